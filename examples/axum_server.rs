@@ -0,0 +1,288 @@
+/*!
+ * HTTP API embedding the library in an axum server
+ *
+ * ```bash
+ * $ cargo run --example axum_server
+ * $ curl -X POST localhost:3000/register -H 'content-type: application/json' \
+ *       -d '{"email": "alice@example.com", "password": "..."}'
+ * $ curl localhost:3000/login/banner
+ * $ curl -X POST localhost:3000/login -H 'content-type: application/json' \
+ *       -d '{"email": "alice@example.com", "password": "..."}'
+ * $ curl -X POST localhost:3000/login/captcha
+ * $ curl -X POST localhost:3000/login/captcha/answer -H 'content-type: application/json' \
+ *       -d '{"email": "alice@example.com", "captcha": "...", "answer": "..."}'
+ * $ curl -X POST localhost:3000/availability/captcha
+ * $ curl -X POST localhost:3000/availability -H 'content-type: application/json' \
+ *       -d '{"email": "bob@example.com", "captcha": "...", "answer": "..."}'
+ * $ curl localhost:3000/health
+ * ```
+ *
+ * The errors are sent as problem details (see `errors/catalog.rs`). The
+ * availability checks rejected by the rate limit are answered with a 429, a
+ * `Retry-After` header & the state of the limit, so the clients can back off.
+ * A login that went through is answered with the user & the message of the
+ * day (see `banner.rs`). The logins are throttled per address of the client: once a CAPTCHA is
+ * required, the login answers `CaptchaRequired` until one is solved. The
+ * server keeps no state, the challenges of the second factor & the CAPTCHAs
+ * stay in the store of the library, only their ids are handed out.
+ * The library is synchronous (hashing, SQLite), so every call runs on the
+ * blocking threads of tokio.
+ *
+ * # Author
+ * Doran Kayoumi <doran.kayoumi@heig-vd.ch>
+ */
+
+use std::net::SocketAddr;
+
+use axum::extract::ConnectInfo;
+use axum::http::{header, HeaderMap, HeaderValue, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use secure_auth::auth::availability::{self, RateLimit};
+use secure_auth::auth::banner::{self, LoginResponse};
+use secure_auth::auth::login;
+use secure_auth::auth::throttle::Captcha;
+use secure_auth::config;
+use secure_auth::db::{self, doctor};
+use secure_auth::errors::catalog::{
+    problem_json, rate_limited_json, Catalogued, PROBLEM_JSON_CONTENT_TYPE,
+};
+use secure_auth::prelude::*;
+
+#[derive(Deserialize)]
+struct Credentials {
+    email: String,
+    password: String,
+}
+
+#[derive(Deserialize)]
+struct AvailabilityCheck {
+    email: String,
+    captcha: String,
+    answer: String,
+}
+
+#[derive(Deserialize)]
+struct CaptchaAnswer {
+    email: String,
+    captcha: String,
+    answer: String,
+}
+
+#[derive(Deserialize)]
+struct SecondFactorCode {
+    challenge: String,
+    factor: String,
+    code: String,
+}
+
+/// Send an error of the library as problem details
+fn problem(e: AuthError) -> Response {
+    (
+        e.status(),
+        [(header::CONTENT_TYPE, PROBLEM_JSON_CONTENT_TYPE)],
+        problem_json(&e, None).to_string(),
+    )
+        .into_response()
+}
+
+/// Send a rejection of the rate limit, with when to try again
+fn rate_limited(limit: &RateLimit) -> Response {
+    let mut response = (
+        StatusCode::TOO_MANY_REQUESTS,
+        [(header::CONTENT_TYPE, PROBLEM_JSON_CONTENT_TYPE)],
+        rate_limited_json(limit, None).to_string(),
+    )
+        .into_response();
+    if let Some(secs) = limit.retry_after() {
+        response
+            .headers_mut()
+            .insert(header::RETRY_AFTER, HeaderValue::from(secs));
+    }
+
+    response
+}
+
+/// What is known about the client of a request, the challenges are bound to it (see `binding.rs`)
+fn client_info(address: &SocketAddr, headers: &HeaderMap) -> ClientInfo {
+    ClientInfo {
+        ip: Some(address.ip()),
+        user_agent: headers
+            .get(header::USER_AGENT)
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string),
+        ..ClientInfo::default()
+    }
+}
+
+/// Run a call of the library on a blocking thread
+async fn blocking<T: Send + 'static>(
+    f: impl FnOnce() -> Result<T, AuthError> + Send + 'static,
+) -> Result<T, Response> {
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR.into_response())?
+        .map_err(problem)
+}
+
+async fn register_handler(Json(c): Json<Credentials>) -> Result<StatusCode, Response> {
+    blocking(move || {
+        let email = Email::parse(&c.email).ok_or(AuthError::InvalidEmail)?;
+        let passwd = Password::parse(&c.password).ok_or(AuthError::InvalidPassword)?;
+        register(&email, &passwd, &ValidatorChain::new())
+    })
+    .await?;
+    Ok(StatusCode::CREATED)
+}
+
+async fn login_handler(
+    ConnectInfo(address): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(c): Json<Credentials>,
+) -> Result<Json<Value>, Response> {
+    let client = client_info(&address, &headers);
+    // Note: credentials with an incorrect format can't be the ones of an account
+    let outcome = blocking(move || {
+        let email = Email::parse(&c.email).ok_or(AuthError::LoginError)?;
+        let passwd = Password::parse(&c.password).ok_or(AuthError::LoginError)?;
+        let signals = Signals::at(chrono::Utc::now(), config::get().locale.timezone);
+        begin_login_with(
+            &FactorRegistry::standard(),
+            &signals,
+            &address.ip().to_string(),
+            &client,
+            &email,
+            &passwd,
+        )
+    })
+    .await?;
+    match outcome {
+        LoginOutcome::Authenticated(login) => {
+            Ok(Json(json!(banner::login_response(login.get_user(), false))))
+        }
+        LoginOutcome::TwoFactorRequired(challenge) => Ok(Json(json!({
+            "challenge": challenge.get_id(),
+            "factors": challenge.get_factors(),
+            "expires_at": challenge.get_expires_at(),
+        }))),
+    }
+}
+
+async fn second_factor_handler(
+    ConnectInfo(address): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(c): Json<SecondFactorCode>,
+) -> Result<Json<LoginResponse>, Response> {
+    let client = client_info(&address, &headers);
+    let completed = blocking(move || {
+        let challenge = find_challenge(&c.challenge)?;
+        complete_2fa_with(
+            &FactorRegistry::standard(),
+            &client,
+            &challenge,
+            &c.factor,
+            &c.code,
+        )
+    })
+    .await?;
+
+    Ok(Json(banner::login_response(completed.get_user(), true)))
+}
+
+/// Banner the login page shows before the form, e.g. the legal notice of the system
+async fn login_banner_handler() -> Json<Value> {
+    Json(json!({ "banner": banner::pre_login_banner() }))
+}
+
+async fn login_captcha_handler() -> Json<Value> {
+    // Note: the answer is kept by the library (in Redis if set up), only the id is handed out
+    let captcha = login::new_captcha();
+
+    Json(json!({ "captcha": captcha.get_id(), "question": captcha.get_question() }))
+}
+
+/// Grants one login attempt to the account once the CAPTCHA is solved
+async fn login_captcha_answer_handler(
+    Json(c): Json<CaptchaAnswer>,
+) -> Result<StatusCode, Response> {
+    blocking(move || login::solve_captcha(&Captcha::from_id(&c.captcha), &c.answer, &c.email))
+        .await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn availability_captcha_handler() -> Json<Value> {
+    // Note: the answer is kept by the library (in Redis if set up), only the id is handed out
+    let captcha = availability::new_captcha();
+
+    Json(json!({ "captcha": captcha.get_id(), "question": captcha.get_question() }))
+}
+
+async fn availability_handler(
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
+    Json(c): Json<AvailabilityCheck>,
+) -> Result<Json<Value>, Response> {
+    // Note: a CAPTCHA can only be answered once, the library forgets it once checked
+    let captcha = Captcha::from_id(&c.captcha);
+
+    let source = client.ip().to_string();
+    let checked_source = source.clone();
+    let available = tokio::task::spawn_blocking(move || {
+        availability::is_email_available(&c.email, &checked_source, &captcha, &c.answer)
+    })
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR.into_response())?;
+
+    match available {
+        Ok(available) => Ok(Json(json!({ "available": available }))),
+        Err(AuthError::TooManyChecks) => Err(rate_limited(&availability::rate_limit(&source))),
+        Err(e) => Err(problem(e)),
+    }
+}
+
+/// State of the database, a 503 if a problem was found (e.g. corrupted password hashes)
+async fn health_handler() -> Response {
+    let health =
+        tokio::task::spawn_blocking(|| db::try_database_url().map(|url| doctor::health(&url)))
+            .await;
+
+    match health {
+        Ok(Some(Ok(health))) if health.healthy => Json(health).into_response(),
+        Ok(Some(Ok(health))) => (StatusCode::SERVICE_UNAVAILABLE, Json(health)).into_response(),
+        _ => StatusCode::SERVICE_UNAVAILABLE.into_response(),
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let url = db::try_database_url().expect("DATABASE_URL isn't set");
+    db::run_migrations(&url).expect("the database can't be migrated");
+    // Note: the corrupted credentials are reported right away, rather than when their users fail to login
+    for f in doctor::scan_credentials(&url).unwrap_or_default() {
+        eprintln!("[!] {} {}", f.problem, f.fix);
+    }
+
+    let app = Router::new()
+        .route("/register", post(register_handler))
+        .route("/login/banner", get(login_banner_handler))
+        .route("/login", post(login_handler))
+        .route("/login/2fa", post(second_factor_handler))
+        .route("/login/captcha", post(login_captcha_handler))
+        .route("/login/captcha/answer", post(login_captcha_answer_handler))
+        .route("/availability/captcha", post(availability_captcha_handler))
+        .route("/availability", post(availability_handler))
+        .route("/health", get(health_handler));
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:3000")
+        .await
+        .expect("the port 3000 is taken");
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await
+    .expect("the server stopped");
+}
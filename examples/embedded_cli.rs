@@ -0,0 +1,90 @@
+/*!
+ * Minimal command line application embedding the library
+ *
+ * ```bash
+ * $ cargo run --example embedded_cli -- register alice@example.com
+ * $ cargo run --example embedded_cli -- login alice@example.com
+ * ```
+ *
+ * The password (& the 2fa code, if asked) are read from the standard input.
+ * The database is the one set by `DATABASE_URL`, it's created if needed.
+ *
+ * # Author
+ * Doran Kayoumi <doran.kayoumi@heig-vd.ch>
+ */
+
+use std::env;
+use std::io::{self, BufRead, Write};
+use std::process;
+
+use secure_auth::db;
+use secure_auth::prelude::*;
+
+/// Ask for a line on the standard input
+fn ask(prompt: &str) -> String {
+    print!("{}: ", prompt);
+    let _ = io::stdout().flush();
+
+    let mut line = String::new();
+    let _ = io::stdin().lock().read_line(&mut line);
+    line.trim_end_matches(&['\r', '\n'][..]).to_string()
+}
+
+/// Ask for a password, the ones outside of the policy are refused with `error`
+fn ask_password(error: AuthError) -> Result<Password, AuthError> {
+    Password::parse(&ask("Password")).ok_or(error)
+}
+
+fn login(email: &Email) -> Result<User, AuthError> {
+    match begin_login(email, &ask_password(AuthError::LoginError)?)? {
+        LoginOutcome::Authenticated(login) => Ok(login.into_user()),
+        LoginOutcome::TwoFactorRequired(challenge) => {
+            let factor = challenge.get_factors()[0];
+            let code = ask(&format!("Code ({})", factor));
+            complete_2fa(&challenge, factor, &code).map(CompletedLogin::into_user)
+        }
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let (command, email) = match args.as_slice() {
+        [command, email] => (command.as_str(), email.as_str()),
+        _ => {
+            eprintln!("usage: embedded_cli <register|login> <email>");
+            process::exit(2);
+        }
+    };
+    let email = Email::parse(email).unwrap_or_else(|| {
+        eprintln!("{}", AuthError::InvalidEmail);
+        process::exit(2);
+    });
+
+    let url = db::try_database_url().unwrap_or_else(|| {
+        eprintln!("DATABASE_URL isn't set");
+        process::exit(1);
+    });
+    if let Err(e) = db::run_migrations(&url) {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+
+    let res = match command {
+        "register" => ask_password(AuthError::InvalidPassword)
+            .and_then(|passwd| register(&email, &passwd, &ValidatorChain::new()))
+            .map(|()| format!("{} is registered", email)),
+        "login" => login(&email).map(|u| format!("welcome {}", u.get_email())),
+        _ => {
+            eprintln!("unknown command: {}", command);
+            process::exit(2);
+        }
+    };
+
+    match res {
+        Ok(msg) => println!("{}", msg),
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    }
+}
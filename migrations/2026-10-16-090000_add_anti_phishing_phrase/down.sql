@@ -0,0 +1,2 @@
+-- This file should undo anything in `up.sql`
+alter table users drop column anti_phishing_phrase
\ No newline at end of file
@@ -0,0 +1,2 @@
+-- Your SQL goes here
+alter table users add column anti_phishing_phrase varchar null
\ No newline at end of file
@@ -0,0 +1,8 @@
+-- Your SQL goes here
+create table audit_log (
+    id integer not null primary key,
+    user_id integer null,
+    event varchar not null,
+    details varchar null,
+    created_at datetime not null
+)
\ No newline at end of file
@@ -0,0 +1,2 @@
+-- This file should undo anything in `up.sql`
+alter table users drop column accepted_tos_version
\ No newline at end of file
@@ -0,0 +1,2 @@
+-- Your SQL goes here
+alter table users add column accepted_tos_version integer null
\ No newline at end of file
@@ -0,0 +1,7 @@
+-- Your SQL goes here
+create table user_attributes (
+    user_id integer not null references users(id),
+    name varchar not null,
+    value varchar not null,
+    primary key (user_id, name)
+)
\ No newline at end of file
@@ -0,0 +1,22 @@
+-- This file should undo anything in `up.sql`
+
+create table user_attributes_old (
+    user_id integer not null references users(id),
+    name varchar not null,
+    value varchar not null,
+    primary key (user_id, name)
+);
+insert into user_attributes_old select user_id, name, value from user_attributes;
+drop table user_attributes;
+alter table user_attributes_old rename to user_attributes;
+
+create table audit_log_old (
+    id integer not null primary key,
+    user_id integer null,
+    event varchar not null,
+    details varchar null,
+    created_at datetime not null
+);
+insert into audit_log_old select id, user_id, event, details, created_at from audit_log;
+drop table audit_log;
+alter table audit_log_old rename to audit_log;
\ No newline at end of file
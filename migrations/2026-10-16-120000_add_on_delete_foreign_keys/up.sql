@@ -0,0 +1,24 @@
+-- Your SQL goes here
+-- SQLite can't alter a constraint, so the tables referencing users are rebuilt
+
+create table user_attributes_new (
+    user_id integer not null references users(id) on delete cascade,
+    name varchar not null,
+    value varchar not null,
+    primary key (user_id, name)
+);
+insert into user_attributes_new select user_id, name, value from user_attributes;
+drop table user_attributes;
+alter table user_attributes_new rename to user_attributes;
+
+-- the audit log outlives the accounts, it's only detached from them
+create table audit_log_new (
+    id integer not null primary key,
+    user_id integer null references users(id) on delete set null,
+    event varchar not null,
+    details varchar null,
+    created_at datetime not null
+);
+insert into audit_log_new select id, user_id, event, details, created_at from audit_log;
+drop table audit_log;
+alter table audit_log_new rename to audit_log;
\ No newline at end of file
@@ -0,0 +1,2 @@
+-- This file should undo anything in `up.sql`
+drop index users_email_unique
\ No newline at end of file
@@ -0,0 +1,3 @@
+-- Your SQL goes here
+-- Note: fails if several accounts already share an email, they must be merged first (see `db doctor`)
+create unique index users_email_unique on users(email)
\ No newline at end of file
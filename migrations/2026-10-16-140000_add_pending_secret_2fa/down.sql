@@ -0,0 +1,2 @@
+-- This file should undo anything in `up.sql`
+alter table users drop column pending_secret_2fa
\ No newline at end of file
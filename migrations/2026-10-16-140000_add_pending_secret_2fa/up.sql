@@ -0,0 +1,2 @@
+-- Your SQL goes here
+alter table users add column pending_secret_2fa text null
\ No newline at end of file
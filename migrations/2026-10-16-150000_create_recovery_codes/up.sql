@@ -0,0 +1,7 @@
+-- Your SQL goes here
+create table recovery_codes (
+    id integer not null primary key,
+    user_id integer not null references users(id) on delete cascade,
+    code_hash varchar not null,
+    used_at datetime null
+)
\ No newline at end of file
@@ -0,0 +1,8 @@
+-- Your SQL goes here
+create table notification_dedupe (
+    recipient varchar not null,
+    fingerprint varchar not null,
+    last_sent_at datetime not null,
+    suppressed integer not null default 0,
+    primary key (recipient, fingerprint)
+)
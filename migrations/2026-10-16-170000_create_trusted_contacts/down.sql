@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+drop table contact_recoveries;
+drop table trusted_contacts
\ No newline at end of file
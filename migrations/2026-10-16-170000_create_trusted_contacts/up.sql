@@ -0,0 +1,16 @@
+-- Your SQL goes here
+create table trusted_contacts (
+    id integer not null primary key,
+    user_id integer not null references users(id) on delete cascade,
+    email varchar not null,
+    unique (user_id, email)
+);
+
+create table contact_recoveries (
+    id integer not null primary key,
+    user_id integer not null references users(id) on delete cascade,
+    contact_email varchar not null,
+    token_hash varchar not null unique,
+    requested_at datetime not null,
+    approved_at datetime null
+)
@@ -0,0 +1,7 @@
+-- Your SQL goes here
+create table password_stats (
+    context varchar not null,
+    score integer not null,
+    count integer not null default 0,
+    primary key (context, score)
+)
\ No newline at end of file
@@ -0,0 +1,3 @@
+-- Your SQL goes here
+-- 'human' or 'service' (see `db::models::AccountKind`)
+alter table users add column kind text not null default 'human'
\ No newline at end of file
@@ -0,0 +1,14 @@
+-- Your SQL goes here
+-- the ids are the sequence numbers of the events (see `audit::stream_since`),
+-- `autoincrement` makes sure they're never reused
+
+create table audit_log_new (
+    id integer not null primary key autoincrement,
+    user_id integer null references users(id) on delete set null,
+    event varchar not null,
+    details varchar null,
+    created_at datetime not null
+);
+insert into audit_log_new select id, user_id, event, details, created_at from audit_log;
+drop table audit_log;
+alter table audit_log_new rename to audit_log;
\ No newline at end of file
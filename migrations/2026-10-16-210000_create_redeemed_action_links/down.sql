@@ -0,0 +1,2 @@
+-- This file should undo anything in `up.sql`
+drop table redeemed_action_links
\ No newline at end of file
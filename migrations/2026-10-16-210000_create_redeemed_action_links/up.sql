@@ -0,0 +1,5 @@
+-- Your SQL goes here
+create table redeemed_action_links (
+    jti varchar not null primary key,
+    expires_at datetime not null
+)
\ No newline at end of file
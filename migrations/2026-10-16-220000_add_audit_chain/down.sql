@@ -0,0 +1,14 @@
+-- This file should undo anything in `up.sql`
+drop trigger audit_log_chained_delete;
+drop trigger audit_log_chained_update;
+
+create table audit_log_old (
+    id integer not null primary key autoincrement,
+    user_id integer null references users(id) on delete set null,
+    event varchar not null,
+    details varchar null,
+    created_at datetime not null
+);
+insert into audit_log_old select id, user_id, event, details, created_at from audit_log;
+drop table audit_log;
+alter table audit_log_old rename to audit_log;
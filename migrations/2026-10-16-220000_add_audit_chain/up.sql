@@ -0,0 +1,26 @@
+-- Your SQL goes here
+-- the hash chaining the entries once the audit log is tamper-evident (see `audit::chain`),
+-- the user is copied in `subject_id`: it's part of the hash & isn't cleared when the user is deleted
+alter table audit_log add column subject_id integer null;
+alter table audit_log add column hash varchar null;
+
+-- the chained entries are append-only, only their user is cleared once she/he's deleted
+create trigger audit_log_chained_update before update on audit_log
+when old.hash is not null and (
+    new.user_id is not null
+    or new.id is not old.id
+    or new.event is not old.event
+    or new.details is not old.details
+    or new.created_at is not old.created_at
+    or new.subject_id is not old.subject_id
+    or new.hash is not old.hash
+)
+begin
+    select raise(abort, 'the chained audit entries are append-only');
+end;
+
+create trigger audit_log_chained_delete before delete on audit_log
+when old.hash is not null
+begin
+    select raise(abort, 'the chained audit entries are append-only');
+end;
@@ -0,0 +1,7 @@
+-- Your SQL goes here
+-- the instance running a job (see `auth::jobs`), until the lease expires
+create table job_leases (
+    job varchar not null primary key,
+    holder varchar not null,
+    expires_at datetime not null
+)
\ No newline at end of file
@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+alter table users add column reset_token varchar null;
+alter table users add column reset_token_created_at datetime null;
+drop table ephemeral_values;
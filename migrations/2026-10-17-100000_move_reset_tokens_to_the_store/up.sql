@@ -0,0 +1,11 @@
+-- Your SQL goes here
+-- the short-lived state that must outlive the process (e.g. the reset tokens, see `db/ephemeral.rs`)
+create table ephemeral_values (
+    key varchar not null primary key,
+    value varchar not null,
+    expires_at datetime not null
+);
+
+-- the reset tokens are kept hashed in it, rather than in the rows of the users
+alter table users drop column reset_token;
+alter table users drop column reset_token_created_at;
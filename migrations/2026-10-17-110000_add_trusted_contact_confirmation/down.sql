@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+alter table trusted_contacts drop column confirmation_hash;
+alter table trusted_contacts drop column confirmed_at;
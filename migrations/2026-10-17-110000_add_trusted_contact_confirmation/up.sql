@@ -0,0 +1,5 @@
+-- Your SQL goes here
+-- a trusted contact only approves recoveries once she/he confirmed she/he accepts the role,
+-- the hash of the token sent to her/him is kept until then
+alter table trusted_contacts add column confirmation_hash varchar null;
+alter table trusted_contacts add column confirmed_at datetime null;
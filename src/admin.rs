@@ -0,0 +1,205 @@
+/*!
+ * Statistics for the administrators
+ *
+ * `stats` gathers the figures a dashboard needs: the number of users, the
+ * adoption of the 2fa, the accounts on security hold & the activity of the last
+ * days taken from the audit log. They can be serialized to JSON or printed by
+ * `secure-auth stats`.
+ *
+ * # Author
+ * Doran Kayoumi <doran.kayoumi@heig-vd.ch>
+ */
+
+use chrono::{DateTime, Duration, NaiveDate, TimeZone, Utc};
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+use crate::audit::AuditEvent;
+use crate::auth::hold;
+use crate::db::repository::{
+    AuditRepository, SQliteAuditRepository, SQliteUserRepository, UserFilter, UserRepository,
+};
+use crate::errors::AuthError;
+
+/// Number of days of activity in the statistics
+pub const STATS_DAYS: i64 = 30;
+
+/// Figures about the users & their activity
+#[derive(Serialize, PartialEq, Debug)]
+pub struct AdminStats {
+    pub total_users: u64,
+    /// `None` as long as the emails of the users aren't verified by the system
+    pub verified_users: Option<u64>,
+    pub users_with_2fa: u64,
+    /// Share (in percent) of the users with the 2fa enabled
+    /// Note: the service accounts can't use the 2fa, they're left out
+    pub twofa_adoption: f64,
+    /// Accounts used by other systems (see `auth/service.rs`), counted in `total_users`
+    pub service_accounts: u64,
+    /// Accounts on security hold (see `auth/hold.rs`)
+    pub locked_accounts: u64,
+    /// Successful logins of each of the last `STATS_DAYS` days
+    pub logins_per_day: BTreeMap<NaiveDate, u64>,
+    /// Reset tokens asked for each of the last `STATS_DAYS` days
+    pub reset_requests_per_day: BTreeMap<NaiveDate, u64>,
+}
+
+/// Public function for the statistics
+/// See `_stats` for more info
+///
+pub fn stats() -> Result<AdminStats, AuthError> {
+    let repository = SQliteUserRepository::new();
+    let audit_repository = SQliteAuditRepository::new();
+    _stats(&repository, &audit_repository, Utc::now())
+}
+
+/// Count the occurrences of an event on each day, the days without any are included
+///
+/// # Arguments
+///
+/// * `audit_repository` - the audit repository to read from
+///
+/// * `event` - the event to count
+///
+/// * `now` - the current date & time
+///
+fn per_day(
+    audit_repository: &dyn AuditRepository,
+    event: AuditEvent,
+    now: DateTime<Utc>,
+) -> Result<BTreeMap<NaiveDate, u64>, AuthError> {
+    let first_day = (now - Duration::days(STATS_DAYS - 1)).date().naive_utc();
+    let mut days: BTreeMap<NaiveDate, u64> = (0..STATS_DAYS)
+        .map(|i| (first_day + Duration::days(i), 0))
+        .collect();
+
+    let occurrences = audit_repository
+        .occurrences_since(
+            &event.to_string(),
+            Utc.from_utc_datetime(&first_day.and_hms(0, 0, 0)),
+        )
+        .map_err(|e| e.to_auth_error(AuthError::StatsError))?;
+    for o in occurrences {
+        if let Ok(dt) = DateTime::parse_from_rfc3339(&o) {
+            if let Some(count) = days.get_mut(&dt.with_timezone(&Utc).date().naive_utc()) {
+                *count += 1;
+            }
+        }
+    }
+
+    Ok(days)
+}
+
+/// Gather the statistics of the users & their activity
+///
+/// # Arguments
+///
+/// * `repository` - the user repository to read from
+///
+/// * `audit_repository` - the audit repository to read from
+///
+/// * `now` - the current date & time
+///
+fn _stats(
+    repository: &dyn UserRepository,
+    audit_repository: &dyn AuditRepository,
+    now: DateTime<Utc>,
+) -> Result<AdminStats, AuthError> {
+    let mut total_users = 0;
+    let mut users_with_2fa = 0;
+    let mut service_accounts = 0;
+    for u in repository.iter_users(&UserFilter::new()) {
+        let u = u.map_err(|_| AuthError::StatsError)?;
+        total_users += 1;
+        if u.is_service() {
+            service_accounts += 1;
+        } else if u.is_2fa_enabled() {
+            users_with_2fa += 1;
+        }
+    }
+
+    let locked_accounts = hold::_list_holds(repository)?.len() as u64;
+
+    Ok(AdminStats {
+        total_users,
+        verified_users: None,
+        users_with_2fa,
+        twofa_adoption: match total_users - service_accounts {
+            0 => 0.0,
+            humans => users_with_2fa as f64 * 100.0 / humans as f64,
+        },
+        service_accounts,
+        locked_accounts,
+        logins_per_day: per_day(audit_repository, AuditEvent::LoginSucceeded, now)?,
+        reset_requests_per_day: per_day(audit_repository, AuditEvent::ResetRequested, now)?,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::db::models::{AccountKind, User};
+    use crate::db::repository::{MockSQliteAuditRepository, MockSQliteUserRepository};
+
+    #[test]
+    fn test_stats() {
+        let mut repository = MockSQliteUserRepository::new();
+        let mut audit_repository = MockSQliteAuditRepository::new();
+        let now = Utc.ymd(2021, 4, 28).and_hms(14, 0, 0);
+
+        repository.expect_iter_users().returning(|_| {
+            let mut with_2fa = User::new("a@email.test", "passwd_hash");
+            with_2fa.set_secret_2fa(Some("secret".to_string()));
+            let mut service = User::new("ci@email.test", "passwd_hash");
+            service.set_kind(AccountKind::Service);
+            Box::new(
+                vec![
+                    Ok(with_2fa),
+                    Ok(User::new("b@email.test", "passwd_hash")),
+                    Ok(User::new("c@email.test", "passwd_hash")),
+                    Ok(User::new("d@email.test", "passwd_hash")),
+                    Ok(service),
+                ]
+                .into_iter(),
+            )
+        });
+        repository
+            .expect_list_users()
+            .returning(|_| Ok(vec![User::new("b@email.test", "passwd_hash")]));
+        repository
+            .expect_get_attributes()
+            .returning(|_| Ok(Default::default()));
+        audit_repository
+            .expect_occurrences_since()
+            .withf(move |e, since| {
+                e == "LoginSucceeded" && *since == Utc.ymd(2021, 3, 30).and_hms(0, 0, 0)
+            })
+            .returning(|_, _| {
+                Ok(vec![
+                    "2021-04-27T09:00:00+00:00".to_string(),
+                    "2021-04-28T08:00:00+00:00".to_string(),
+                    "2021-04-28T13:00:00+00:00".to_string(),
+                ])
+            });
+        audit_repository
+            .expect_occurrences_since()
+            .withf(|e, _| e == "ResetRequested")
+            .returning(|_, _| Ok(vec![]));
+
+        let stats = _stats(&repository, &audit_repository, now).unwrap();
+
+        assert_eq!(stats.total_users, 5);
+        assert_eq!(stats.users_with_2fa, 1);
+        assert_eq!(stats.twofa_adoption, 25.0);
+        assert_eq!(stats.service_accounts, 1);
+        assert_eq!(stats.locked_accounts, 1);
+        assert_eq!(stats.logins_per_day.len(), STATS_DAYS as usize);
+        assert_eq!(stats.logins_per_day[&NaiveDate::from_ymd(2021, 4, 28)], 2);
+        assert_eq!(stats.logins_per_day[&NaiveDate::from_ymd(2021, 4, 27)], 1);
+        assert_eq!(stats.reset_requests_per_day.values().sum::<u64>(), 0);
+
+        let json = serde_json::to_value(&stats).unwrap();
+        assert_eq!(json["logins_per_day"]["2021-04-28"], 2);
+        assert!(json["verified_users"].is_null());
+    }
+}
@@ -0,0 +1,241 @@
+/*!
+ * Audit log of the security relevant events happening in the system
+ *
+ * The log can be made tamper-evident, see `chain.rs`.
+ *
+ * # Author
+ * Doran Kayoumi <doran.kayoumi@heig-vd.ch>
+ */
+
+pub mod chain;
+
+use std::collections::VecDeque;
+use strum_macros::Display;
+
+use crate::db::models::AuditEntry;
+use crate::db::repository::AuditRepository;
+use crate::errors::AuditDBError;
+
+#[derive(PartialEq, Debug, Display)]
+pub enum AuditEvent {
+    /// A user accepted a version of the terms of service & privacy policy
+    TosAccepted,
+    /// A user enabled the two-factor authentication
+    TwoFaEnabled,
+    /// A user disabled the two-factor authentication
+    TwoFaDisabled,
+    /// The failed logins reached a threshold, a CAPTCHA is now required
+    CaptchaRequired,
+    /// A CAPTCHA wasn't solved
+    CaptchaFailed,
+    /// An account was placed on security hold
+    SecurityHoldPlaced,
+    /// The security hold of an account was released
+    SecurityHoldReleased,
+    /// A user added a trusted contact
+    TrustedContactAdded,
+    /// A user removed a trusted contact
+    TrustedContactRemoved,
+    /// A trusted contact confirmed she/he accepts the role
+    TrustedContactConfirmed,
+    /// A recovery through the trusted contacts was started
+    ContactRecoveryStarted,
+    /// A trusted contact approved a recovery
+    ContactRecoveryApproved,
+    /// A user cancelled a recovery through her/his trusted contacts
+    ContactRecoveryCancelled,
+    /// The owner of an account replaced a recovery through the link of her/his alert
+    ContactRecoveryTakenOver,
+    /// An account was recovered through its trusted contacts
+    ContactRecoveryCompleted,
+    /// A user tried to login outside of her/his allowed hours
+    LoginOutsideAllowedHours,
+    /// A user was warned her/his account will be disabled because of her/his inactivity
+    InactivityWarningSent,
+    /// A user logged in
+    LoginSucceeded,
+    /// A user asked for a reset token
+    ResetRequested,
+    /// A service account was created
+    ServiceAccountCreated,
+    /// The account of a directory user was created on her/his first login
+    DirectoryAccountProvisioned,
+    /// A new API key replaced the one of a service account
+    ApiKeyRotated,
+    /// A client certificate was registered for a service account
+    ClientCertificateRegistered,
+    /// A user followed the "this wasn't me" link of an alert
+    NotMeReported,
+    /// The tamper-evident audit log signed the entry before it
+    AuditCheckpoint,
+    /// An administrator resolved the pseudonym of a user
+    PseudonymResolved,
+    /// An account was erased, the details are its erasure certificate
+    AccountErased,
+}
+
+/// Add an event to the audit log
+///
+/// # Arguments
+///
+/// * `repository` - the audit repository to write in
+///
+/// * `user` - id of the user concerned by the event (if any)
+///
+/// * `event` - the event that happened
+///
+/// * `details` - additional information about the event
+///
+pub fn record(
+    repository: &dyn AuditRepository,
+    user: Option<i32>,
+    event: AuditEvent,
+    details: Option<String>,
+) -> Result<(), AuditDBError> {
+    repository.create_entry(user, &event.to_string(), details)
+}
+
+/// Number of entries loaded at once by `stream_since`
+const STREAM_PAGE_SIZE: i64 = 500;
+
+/// Entries of the audit log following a sequence number, loaded page by page
+pub struct AuditStream<'a> {
+    repository: &'a dyn AuditRepository,
+    page: VecDeque<AuditEntry>,
+    last_seq: i32,
+    done: bool,
+}
+
+impl AuditStream<'_> {
+    /// Sequence number of the last entry returned, from which a consumer can resume
+    pub fn last_seq(&self) -> i32 {
+        self.last_seq
+    }
+}
+
+impl Iterator for AuditStream<'_> {
+    type Item = Result<AuditEntry, AuditDBError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.page.is_empty() && !self.done {
+            match self
+                .repository
+                .entries_after(self.last_seq, STREAM_PAGE_SIZE)
+            {
+                Ok(page) => {
+                    // a partial page means there's nothing left after it
+                    self.done = (page.len() as i64) < STREAM_PAGE_SIZE;
+                    self.page.extend(page);
+                }
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+            }
+        }
+
+        let entry = self.page.pop_front()?;
+        self.last_seq = entry.id;
+
+        Some(Ok(entry))
+    }
+}
+
+/// Replay the audit log from a sequence number
+/// The entries come in the order they were added, a consumer (e.g. a SIEM shipper)
+/// keeps the sequence number of the last one it handled & resumes from it after a downtime
+///
+/// # Arguments
+///
+/// * `repository` - the audit repository to read from
+///
+/// * `seq` - sequence number of the last entry already consumed (0 to replay everything)
+///
+pub fn stream_since(repository: &dyn AuditRepository, seq: i32) -> AuditStream<'_> {
+    AuditStream {
+        repository,
+        page: VecDeque::new(),
+        last_seq: seq,
+        done: false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::db::repository::MockSQliteAuditRepository;
+    use mockall::predicate::*;
+
+    #[test]
+    fn test_record_uses_event_name() {
+        let mut mock = MockSQliteAuditRepository::new();
+
+        mock.expect_create_entry()
+            .withf(|u, e, d| *u == Some(1) && e == "TosAccepted" && d.is_none())
+            .times(1)
+            .returning(|_, _, _| Ok(()));
+
+        let res = record(&mock, Some(1), AuditEvent::TosAccepted, None);
+
+        assert_eq!(Ok(()), res);
+    }
+
+    fn entry(id: i32) -> AuditEntry {
+        AuditEntry {
+            id,
+            user_id: None,
+            event: "LoginSucceeded".to_string(),
+            details: None,
+            created_at: "2021-04-28T00:00:00+00:00".to_string(),
+            subject_id: None,
+            hash: None,
+        }
+    }
+
+    #[test]
+    fn test_stream_since_resumes_after_seq() {
+        let mut mock = MockSQliteAuditRepository::new();
+
+        mock.expect_entries_after()
+            .with(eq(2), eq(STREAM_PAGE_SIZE))
+            .times(1)
+            .returning(|_, _| Ok(vec![entry(3), entry(5)]));
+
+        let mut stream = stream_since(&mock, 2);
+        let seqs = stream.by_ref().map(|e| e.map(|e| e.id)).collect::<Vec<_>>();
+
+        assert_eq!(seqs, vec![Ok(3), Ok(5)]);
+        assert_eq!(stream.last_seq(), 5);
+    }
+
+    #[test]
+    fn test_stream_since_loads_pages() {
+        let mut mock = MockSQliteAuditRepository::new();
+
+        mock.expect_entries_after()
+            .with(eq(0), always())
+            .times(1)
+            .returning(|_, limit| Ok((1..=limit as i32).map(entry).collect()));
+        mock.expect_entries_after()
+            .with(eq(STREAM_PAGE_SIZE as i32), always())
+            .times(1)
+            .returning(|_, _| Ok(vec![]));
+
+        assert_eq!(stream_since(&mock, 0).count(), STREAM_PAGE_SIZE as usize);
+    }
+
+    #[test]
+    fn test_stream_since_stops_on_error() {
+        let mut mock = MockSQliteAuditRepository::new();
+
+        mock.expect_entries_after()
+            .times(1)
+            .returning(|_, _| Err(AuditDBError::ReadEntryError));
+
+        let mut stream = stream_since(&mock, 7);
+
+        assert_eq!(stream.next(), Some(Err(AuditDBError::ReadEntryError)));
+        assert_eq!(stream.next(), None);
+        assert_eq!(stream.last_seq(), 7);
+    }
+}
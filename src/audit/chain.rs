@@ -0,0 +1,439 @@
+/*!
+ * Tamper-evident audit log
+ *
+ * Once `tamper_evident` is set in the `[audit]` section of the configuration,
+ * every new entry carries the SHA-256 hash of its content & of the hash of the
+ * entry before it, so changing or removing an entry breaks the chain from there.
+ * The database also refuses to change or remove the chained entries (only their
+ * user is cleared once she/he's deleted, it's kept in `subject_id` for the hash),
+ * dropping its triggers to do it anyway is then revealed by the chain.
+ *
+ * Every `checkpoint_every` entries, an `AuditCheckpoint` entry signs the entry it
+ * follows with HMAC-SHA256 & the key read from `AUDIT_CHECKPOINT_SECRET`, so a
+ * chain rewritten with recomputed hashes doesn't pass the verification without
+ * the key. The checkpoints are skipped while the key isn't set.
+ *
+ * Removing the last entries leaves a valid chain behind: a truncation is found
+ * by comparing the chain with its head noted by a previous verification (see
+ * `verify`). The entries written before the log was tamper-evident aren't
+ * chained, the chain starts at the first entry written afterwards.
+ *
+ * # Author
+ * Doran Kayoumi <doran.kayoumi@heig-vd.ch>
+ */
+
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use std::env;
+use std::fmt;
+use std::str::FromStr;
+
+use super::{stream_since, AuditEvent};
+use crate::db::repository::{AuditRepository, SQliteAuditRepository};
+use crate::errors::AuditDBError;
+
+/// Variable holding the key signing the checkpoints
+pub const SECRET_VARIABLE: &str = "AUDIT_CHECKPOINT_SECRET";
+/// Minimum length of the signing key
+pub const MIN_SECRET_LEN: usize = 32;
+/// Hash the first entry of the chain follows
+pub const GENESIS: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// Last entry of the chain, noted to find a later truncation
+#[derive(Debug, Clone, PartialEq)]
+pub struct Head {
+    pub seq: i32,
+    pub hash: String,
+}
+
+/// `<seq>:<hash>`
+impl fmt::Display for Head {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.seq, self.hash)
+    }
+}
+
+impl FromStr for Head {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("`{}` isn't a head of the audit log (<seq>:<hash>)", s);
+
+        let (seq, hash) = s.split_once(':').ok_or_else(invalid)?;
+        let seq = seq.parse().map_err(|_| invalid())?;
+        if hash.len() != GENESIS.len() || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(invalid());
+        }
+
+        Ok(Self {
+            seq,
+            hash: hash.to_ascii_lowercase(),
+        })
+    }
+}
+
+/// Sign that the audit log was tampered with
+#[derive(Debug, Clone, PartialEq)]
+pub enum Tampering {
+    /// An entry following the start of the chain isn't chained
+    Unchained(i32),
+    /// An entry was changed, or entries were removed before it
+    Broken(i32),
+    /// A checkpoint wasn't signed with the key
+    ForgedCheckpoint(i32),
+    /// The head noted by a previous verification isn't in the chain anymore
+    Truncated(Head),
+}
+
+impl fmt::Display for Tampering {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Unchained(seq) => write!(f, "entry {} isn't chained", seq),
+            Self::Broken(seq) => write!(
+                f,
+                "entry {} was changed, or entries were removed before it",
+                seq
+            ),
+            Self::ForgedCheckpoint(seq) => {
+                write!(f, "checkpoint {} isn't signed with the key", seq)
+            }
+            Self::Truncated(head) => write!(
+                f,
+                "entry {} isn't in the chain anymore, it was truncated or rewritten",
+                head.seq
+            ),
+        }
+    }
+}
+
+/// Outcome of a verification of the chain
+#[derive(Debug, Default, PartialEq)]
+pub struct Verification {
+    /// Number of chained entries checked
+    pub entries: usize,
+    /// Number of checkpoints whose signature was checked
+    pub checkpoints: usize,
+    /// Last entry of the chain, `None` if nothing is chained yet
+    pub head: Option<Head>,
+    pub tampering: Vec<Tampering>,
+}
+
+/// Get the key signing the checkpoints, `None` if it's missing or too short
+pub fn secret() -> Option<String> {
+    dotenv::dotenv().ok();
+    env::var(SECRET_VARIABLE)
+        .ok()
+        .filter(|s| s.len() >= MIN_SECRET_LEN)
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Compute the hash of an entry, in hex
+/// Note: the fields are prefixed with their length, so they can't be shifted into each other
+///
+/// # Arguments
+///
+/// * `previous` - hash of the entry before it, `GENESIS` for the first one
+///
+/// * `subject` - id of the user concerned by the event (if any)
+///
+/// * `event` - name of the event
+///
+/// * `details` - additional information about the event
+///
+/// * `created_at` - when the entry was added, as stored
+///
+pub fn entry_hash(
+    previous: &str,
+    subject: Option<i32>,
+    event: &str,
+    details: Option<&str>,
+    created_at: &str,
+) -> String {
+    let subject = subject.map(|s| s.to_string());
+    let mut hasher = Sha256::new();
+    for field in &[
+        Some(previous),
+        subject.as_deref(),
+        Some(event),
+        details,
+        Some(created_at),
+    ] {
+        match field {
+            Some(value) => hasher.update(format!("{}:{};", value.len(), value)),
+            None => hasher.update("-;"),
+        }
+    }
+
+    to_hex(&hasher.finalize())
+}
+
+/// Sign the entry a checkpoint follows, in hex
+/// `None` is returned if the key can't be used
+///
+/// # Arguments
+///
+/// * `seq` - sequence number of the entry
+///
+/// * `hash` - hash of the entry
+///
+/// * `secret` - the signing key
+///
+pub fn sign_checkpoint(seq: i32, hash: &str, secret: &str) -> Option<String> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).ok()?;
+    mac.update(format!("{}:{}", seq, hash).as_bytes());
+
+    Some(to_hex(&mac.finalize().into_bytes()))
+}
+
+/// Public function for the verification of the audit log
+/// See `_verify` for more info
+///
+pub fn verify(expected: Option<&Head>) -> Result<Verification, AuditDBError> {
+    let repository = SQliteAuditRepository::new();
+    _verify(&repository, secret().as_deref(), expected)
+}
+
+/// Verify the chain of the audit log, every sign of tampering is reported
+/// if the log can't be read, an error is returned
+///
+/// # Arguments
+///
+/// * `repository` - the audit repository to read from
+///
+/// * `secret` - the key signing the checkpoints, they aren't checked without it
+///
+/// * `expected` - the head noted by a previous verification (if any), to find a truncation
+///
+fn _verify(
+    repository: &dyn AuditRepository,
+    secret: Option<&str>,
+    expected: Option<&Head>,
+) -> Result<Verification, AuditDBError> {
+    let checkpoint = AuditEvent::AuditCheckpoint.to_string();
+    let mut report = Verification::default();
+    let mut expected_found = false;
+
+    for entry in stream_since(repository, 0) {
+        let entry = entry?;
+        let seq = entry.id;
+        let hash = match (entry.hash, &report.head) {
+            // the log wasn't tamper-evident yet
+            (None, None) => continue,
+            (None, Some(_)) => {
+                report.tampering.push(Tampering::Unchained(seq));
+                continue;
+            }
+            (Some(hash), _) => hash,
+        };
+
+        let previous = report.head.as_ref().map_or(GENESIS, |h| h.hash.as_str());
+        let computed = entry_hash(
+            previous,
+            entry.subject_id,
+            &entry.event,
+            entry.details.as_deref(),
+            &entry.created_at,
+        );
+        // Note: the user is cleared once she/he's deleted, it can't be changed otherwise
+        let user_kept = entry.user_id.is_none() || entry.user_id == entry.subject_id;
+        if computed != hash || !user_kept {
+            report.tampering.push(Tampering::Broken(seq));
+        }
+
+        if entry.event == checkpoint {
+            if let (Some(secret), Some(head)) = (secret, &report.head) {
+                if sign_checkpoint(head.seq, &head.hash, secret) != entry.details {
+                    report.tampering.push(Tampering::ForgedCheckpoint(seq));
+                }
+                report.checkpoints += 1;
+            }
+        }
+
+        if let Some(expected) = expected.filter(|e| e.seq == seq) {
+            expected_found = expected.hash == hash;
+        }
+        report.entries += 1;
+        report.head = Some(Head { seq, hash });
+    }
+
+    if let Some(expected) = expected.filter(|_| !expected_found) {
+        report
+            .tampering
+            .push(Tampering::Truncated(expected.clone()));
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::AuditConfig;
+    use crate::db::repository::{SQliteUserRepository, UserRepository};
+    use crate::db::test_database;
+    use diesel::prelude::*;
+    use diesel::sql_query;
+
+    const SECRET: &str = "0123456789abcdef0123456789abcdef";
+
+    /// Tamper-evident log signing a checkpoint every 2 entries
+    fn chained_log(url: &str) -> SQliteAuditRepository {
+        let audit = AuditConfig {
+            tamper_evident: true,
+            checkpoint_every: 2,
+        };
+        SQliteAuditRepository::with_database_url(url).with_config(audit, Some(SECRET.to_string()))
+    }
+
+    fn record_events(repository: &SQliteAuditRepository, n: usize) {
+        for i in 0..n {
+            repository
+                .create_entry(None, "LoginSucceeded", Some(i.to_string()))
+                .unwrap();
+        }
+    }
+
+    /// Run statements on the database, once its triggers are dropped
+    fn tamper(url: &str, statements: &[&str]) {
+        let conn = SqliteConnection::establish(url).unwrap();
+        sql_query("drop trigger audit_log_chained_update")
+            .execute(&conn)
+            .unwrap();
+        sql_query("drop trigger audit_log_chained_delete")
+            .execute(&conn)
+            .unwrap();
+        for statement in statements {
+            sql_query(*statement).execute(&conn).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_entry_hash_separates_the_fields() {
+        assert_ne!(
+            entry_hash(GENESIS, None, "ab", Some("c"), "now"),
+            entry_hash(GENESIS, None, "a", Some("bc"), "now")
+        );
+        assert_ne!(
+            entry_hash(GENESIS, None, "a", None, "now"),
+            entry_hash(GENESIS, None, "a", Some(""), "now")
+        );
+        assert_ne!(
+            entry_hash(GENESIS, Some(1), "a", None, "now"),
+            entry_hash(GENESIS, Some(2), "a", None, "now")
+        );
+    }
+
+    #[test]
+    fn test_parse_head() {
+        let head = format!("42:{}", "AB".repeat(32));
+
+        assert_eq!(
+            head.parse(),
+            Ok(Head {
+                seq: 42,
+                hash: "ab".repeat(32),
+            })
+        );
+        assert!("42".parse::<Head>().is_err());
+        assert!("x:00".parse::<Head>().is_err());
+        assert!(format!("42:{}", "zz".repeat(32)).parse::<Head>().is_err());
+    }
+
+    #[test]
+    fn test_intact_log() {
+        let (_dir, url) = test_database();
+        let plain = SQliteAuditRepository::with_database_url(&url);
+        let repository = chained_log(&url);
+
+        // the entries written before the log was tamper-evident aren't checked
+        record_events(&plain, 2);
+        record_events(&repository, 5);
+
+        let report = _verify(&repository, Some(SECRET), None).unwrap();
+        assert_eq!(report.entries, 7);
+        assert_eq!(report.checkpoints, 2);
+        assert!(report.tampering.is_empty());
+
+        let head = report.head.unwrap();
+        record_events(&repository, 1);
+        let report = _verify(&repository, Some(SECRET), Some(&head)).unwrap();
+        assert!(report.tampering.is_empty());
+    }
+
+    #[test]
+    fn test_chained_entries_are_append_only() {
+        let (_dir, url) = test_database();
+        let users = SQliteUserRepository::with_database_url(&url);
+        let repository = chained_log(&url);
+
+        users
+            .create_user("email@email.test", "passwd_hash")
+            .unwrap();
+        let user = users.get_user("email@email.test").unwrap().get_id();
+        repository
+            .create_entry(Some(user), "LoginSucceeded", None)
+            .unwrap();
+
+        let conn = SqliteConnection::establish(&url).unwrap();
+        assert!(sql_query("update audit_log set details = 'forged'")
+            .execute(&conn)
+            .is_err());
+        assert!(sql_query("delete from audit_log").execute(&conn).is_err());
+
+        // deleting the user clears the entry's user, the chain is still intact
+        users.delete_user(user).unwrap();
+        let report = _verify(&repository, Some(SECRET), None).unwrap();
+        assert_eq!(report.entries, 1);
+        assert!(report.tampering.is_empty());
+    }
+
+    #[test]
+    fn test_tampered_log() {
+        let (_dir, url) = test_database();
+        let repository = chained_log(&url);
+        // entries 1, 2, checkpoint 3, 4, 5, checkpoint 6 & 7
+        record_events(&repository, 5);
+        let head = _verify(&repository, Some(SECRET), None)
+            .unwrap()
+            .head
+            .unwrap();
+
+        tamper(
+            &url,
+            &[
+                "update audit_log set details = 'forged' where id = 1",
+                "delete from audit_log where id = 4",
+                "delete from audit_log where id = 7",
+                "insert into audit_log (event, created_at) values ('LoginSucceeded', '2021-04-28')",
+            ],
+        );
+
+        let report = _verify(&repository, Some(SECRET), Some(&head)).unwrap();
+        assert_eq!(
+            report.tampering,
+            vec![
+                Tampering::Broken(1),
+                Tampering::Broken(5),
+                Tampering::Unchained(8),
+                Tampering::Truncated(head),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_forged_checkpoint() {
+        let (_dir, url) = test_database();
+        record_events(&chained_log(&url), 2);
+
+        let report = _verify(&chained_log(&url), Some(&SECRET.replace('0', "1")), None).unwrap();
+
+        assert_eq!(report.tampering, vec![Tampering::ForgedCheckpoint(3)]);
+        assert!(_verify(&chained_log(&url), None, None)
+            .unwrap()
+            .tampering
+            .is_empty());
+    }
+}
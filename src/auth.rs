@@ -5,7 +5,40 @@
  * Doran Kayoumi <doran.kayoumi@heig-vd.ch>
  */
 
+// the library must not panic, the failures are returned as typed errors
+#![cfg_attr(
+    not(test),
+    deny(clippy::unwrap_used, clippy::expect_used, clippy::panic)
+)]
+
+pub mod action;
+pub mod auth_service;
+pub mod availability;
+pub mod banner;
+pub mod binding;
+pub mod contacts;
+pub mod erasure;
+pub mod factor;
+pub mod hold;
+pub mod inactivity;
+pub mod jobs;
+pub mod jwt;
+pub mod ldap;
 pub mod login;
+pub mod maintenance;
+pub mod not_me;
+pub mod pseudonym;
+pub mod recovery;
 pub mod register;
 pub mod reset;
+pub mod risk;
+pub mod schedule;
+pub mod service;
+pub mod simulation;
+pub mod status;
+pub mod step_up;
+pub mod throttle;
+pub mod timing;
+pub mod tos;
 pub mod twofa;
+pub mod validator;
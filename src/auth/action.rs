@@ -0,0 +1,434 @@
+/*!
+ * Short-lived signed links for one-click operations (e.g. verifying an email,
+ * unlocking an account or confirming a subscription)
+ *
+ * A link carries its purpose, the user it was issued to, when it expires & a
+ * unique identifier (jti), signed with HMAC-SHA256. Nothing is stored when it's
+ * issued: the signature proves the system issued it, and only the identifiers
+ * of the redeemed links are kept (until they expire), so a link works once.
+ *
+ * The signing key is read from `ACTION_LINK_SECRET` (in the environment or the
+ * `.env` file), so it isn't stored in the configuration. Replacing it
+ * invalidates every link issued with the previous one.
+ *
+ * The token is URL-safe, the application puts it in the link it sends, e.g.
+ * `https://example.com/actions/<token>`, and redeems it for the purpose the
+ * page handles.
+ *
+ * The flows of the library use them for the reset tokens (see `reset.rs`),
+ * the email verification (see `register.rs`), the unlock of an account & the
+ * confirmation of an unusual login (see `login.rs`) and the "this wasn't me"
+ * reports (see `not_me.rs`). A flow can keep its own record of the links
+ * instead of the redeemed ones (see `_redeem`), e.g. the reset keeps the hash
+ * of the last token issued so a new one revokes it.
+ *
+ * # Author
+ * Doran Kayoumi <doran.kayoumi@heig-vd.ch>
+ */
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::prelude::*;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::env;
+
+use crate::db::repository::{ActionLinkRepository, SQliteActionLinkRepository};
+use crate::errors::AuthError;
+use crate::utils;
+use crate::validation::MAX_TOKEN_BYTES;
+
+/// Variable holding the key signing the links
+pub const SECRET_VARIABLE: &str = "ACTION_LINK_SECRET";
+/// Minimum length of the signing key
+pub const MIN_SECRET_LEN: usize = 32;
+
+/// A verified action link
+#[derive(Debug, Clone, PartialEq)]
+pub struct ActionLink {
+    /// What the link is for (e.g. `verify-email`), it's only redeemed for it
+    pub purpose: String,
+    /// Id of the user the link was issued to
+    pub user: i32,
+    pub expires_at: DateTime<Utc>,
+    /// Unique identifier of the link
+    pub jti: String,
+}
+
+/// Public function for the issuing of an action link
+/// See `_issue` for more info
+///
+pub fn issue(purpose: &str, user: i32, validity: chrono::Duration) -> Result<String, AuthError> {
+    _issue(purpose, user, Utc::now() + validity, &secret()?)
+}
+
+/// Public function for the redemption of an action link
+/// See `_redeem` for more info
+///
+pub fn redeem(token: &str, purpose: &str) -> Result<ActionLink, AuthError> {
+    let repository = SQliteActionLinkRepository::new();
+    _redeem(token, purpose, &secret()?, &repository, Utc::now())
+}
+
+/// Public function for the check of an action link
+/// See `_check` for more info
+///
+pub fn check(token: &str, purpose: &str) -> Result<ActionLink, AuthError> {
+    _check(token, purpose, &secret()?, Utc::now())
+}
+
+/// Check the format of a purpose: lowercase letters, digits & `.`, `_`, `-`
+///
+/// # Arguments
+///
+/// * `purpose` - the purpose to check
+///
+pub fn is_purpose_valid(purpose: &str) -> bool {
+    !purpose.is_empty()
+        && purpose
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || "._-".contains(c))
+}
+
+/// Get the signing key, a missing or too short one is an error
+pub(super) fn secret() -> Result<String, AuthError> {
+    dotenv::dotenv().ok();
+    env::var(SECRET_VARIABLE)
+        .ok()
+        .filter(|s| s.len() >= MIN_SECRET_LEN)
+        .ok_or(AuthError::ActionLinkError)
+}
+
+/// Start the signature of a payload
+///
+/// # Arguments
+///
+/// * `payload` - the encoded payload of the link
+///
+/// * `secret` - the signing key
+///
+fn mac(payload: &str, secret: &str) -> Result<Hmac<Sha256>, AuthError> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+        .map_err(|_| AuthError::ActionLinkError)?;
+    mac.update(payload.as_bytes());
+
+    Ok(mac)
+}
+
+/// Issue an action link
+/// Returns the token of the link, `<payload>.<signature>`
+///
+/// # Arguments
+///
+/// * `purpose` - what the link is for
+///
+/// * `user` - id of the user the link is issued to
+///
+/// * `expires_at` - when the link expires
+///
+/// * `secret` - the signing key
+///
+pub(super) fn _issue(
+    purpose: &str,
+    user: i32,
+    expires_at: DateTime<Utc>,
+    secret: &str,
+) -> Result<String, AuthError> {
+    if !is_purpose_valid(purpose) {
+        return Err(AuthError::ActionLinkError);
+    }
+
+    let payload = URL_SAFE_NO_PAD.encode(format!(
+        "{}:{}:{}:{}",
+        purpose,
+        user,
+        expires_at.timestamp(),
+        utils::gen_token()
+    ));
+    let signature = mac(&payload, secret)?.finalize().into_bytes();
+
+    Ok(format!("{}.{}", payload, URL_SAFE_NO_PAD.encode(signature)))
+}
+
+/// Check the signature of a link & read it
+/// Note: the expiry isn't checked
+///
+/// # Arguments
+///
+/// * `token` - the token of the link
+///
+/// * `secret` - the signing key
+///
+fn verify(token: &str, secret: &str) -> Result<ActionLink, AuthError> {
+    utils::check_length(token, MAX_TOKEN_BYTES)?;
+    let (payload, signature) = token
+        .trim()
+        .split_once('.')
+        .ok_or(AuthError::InvalidActionLink)?;
+    let signature = URL_SAFE_NO_PAD
+        .decode(signature)
+        .map_err(|_| AuthError::InvalidActionLink)?;
+    mac(payload, secret)?
+        .verify_slice(&signature)
+        .map_err(|_| AuthError::InvalidActionLink)?;
+
+    let payload = URL_SAFE_NO_PAD
+        .decode(payload)
+        .ok()
+        .and_then(|p| String::from_utf8(p).ok())
+        .ok_or(AuthError::InvalidActionLink)?;
+    match payload.split(':').collect::<Vec<_>>().as_slice() {
+        [purpose, user, expires_at, jti] => Ok(ActionLink {
+            purpose: purpose.to_string(),
+            user: user.parse().map_err(|_| AuthError::InvalidActionLink)?,
+            expires_at: expires_at
+                .parse()
+                .ok()
+                .and_then(|t| Utc.timestamp_opt(t, 0).single())
+                .ok_or(AuthError::InvalidActionLink)?,
+            jti: jti.to_string(),
+        }),
+        _ => Err(AuthError::InvalidActionLink),
+    }
+}
+
+/// Check an action link without redeeming it (e.g. before asking for the rest of a form)
+/// Returns the link if it was issued for the purpose & didn't expire yet
+///
+/// # Arguments
+///
+/// * `token` - the token of the link
+///
+/// * `purpose` - what the link must be for
+///
+/// * `secret` - the signing key
+///
+/// * `now` - when the link is checked
+///
+pub(super) fn _check(
+    token: &str,
+    purpose: &str,
+    secret: &str,
+    now: DateTime<Utc>,
+) -> Result<ActionLink, AuthError> {
+    let link = verify(token, secret)?;
+    if link.purpose != purpose {
+        return Err(AuthError::InvalidActionLink);
+    }
+    if link.expires_at <= now {
+        return Err(AuthError::ActionLinkExpired);
+    }
+
+    Ok(link)
+}
+
+/// Redeem an action link, a link can only be redeemed once
+/// Returns the link, the caller carries out the operation for its user
+///
+/// # Arguments
+///
+/// * `token` - the token of the link
+///
+/// * `purpose` - what the link must be for
+///
+/// * `secret` - the signing key
+///
+/// * `repository` - where the redeemed links are kept
+///
+/// * `now` - when the link is redeemed
+///
+pub(super) fn _redeem(
+    token: &str,
+    purpose: &str,
+    secret: &str,
+    repository: &dyn ActionLinkRepository,
+    now: DateTime<Utc>,
+) -> Result<ActionLink, AuthError> {
+    let link = _check(token, purpose, secret, now)?;
+
+    let redeemed = repository
+        .redeem(&link.jti, link.expires_at, now)
+        .map_err(|_| AuthError::ActionLinkError)?;
+    if !redeemed {
+        return Err(AuthError::ActionLinkUsed);
+    }
+
+    Ok(link)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::db::repository::MockSQliteActionLinkRepository;
+    use crate::errors::ActionLinkDBError;
+
+    const SECRET: &str = "0123456789abcdef0123456789abcdef";
+
+    fn now() -> DateTime<Utc> {
+        Utc.ymd(2021, 4, 28).and_hms(12, 0, 0)
+    }
+
+    fn repository(redeemed: bool) -> MockSQliteActionLinkRepository {
+        let mut mock = MockSQliteActionLinkRepository::new();
+        mock.expect_redeem().returning(move |_, _, _| Ok(redeemed));
+        mock
+    }
+
+    fn token(purpose: &str) -> String {
+        _issue(purpose, 42, now() + chrono::Duration::hours(1), SECRET).unwrap()
+    }
+
+    #[test]
+    fn test_redeem() {
+        let link = _redeem(
+            &token("verify-email"),
+            "verify-email",
+            SECRET,
+            &repository(true),
+            now(),
+        )
+        .unwrap();
+
+        assert_eq!(link.purpose, "verify-email");
+        assert_eq!(link.user, 42);
+        assert_eq!(link.expires_at, now() + chrono::Duration::hours(1));
+        assert_eq!(link.jti.len(), 30);
+    }
+
+    #[test]
+    fn test_tokens_are_url_safe_and_unique() {
+        let t = token("verify-email");
+
+        assert!(t
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c)));
+        assert_ne!(t, token("verify-email"));
+    }
+
+    #[test]
+    fn test_redeem_for_another_purpose() {
+        assert_eq!(
+            _redeem(
+                &token("unlock"),
+                "verify-email",
+                SECRET,
+                &repository(true),
+                now()
+            ),
+            Err(AuthError::InvalidActionLink)
+        );
+    }
+
+    #[test]
+    fn test_redeem_tampered_link() {
+        let t = token("verify-email");
+        let (_, signature) = t.split_once('.').unwrap();
+        let forged = format!(
+            "{}.{}",
+            URL_SAFE_NO_PAD.encode("verify-email:1:9999999999:jti"),
+            signature
+        );
+
+        assert_eq!(
+            _redeem(&forged, "verify-email", SECRET, &repository(true), now()),
+            Err(AuthError::InvalidActionLink)
+        );
+        assert_eq!(
+            _redeem(
+                &t,
+                "verify-email",
+                "another secret of at least 32 chars",
+                &repository(true),
+                now()
+            ),
+            Err(AuthError::InvalidActionLink)
+        );
+        assert_eq!(
+            _redeem("garbage", "verify-email", SECRET, &repository(true), now()),
+            Err(AuthError::InvalidActionLink)
+        );
+    }
+
+    #[test]
+    fn test_redeem_expired_link() {
+        let mut repository = MockSQliteActionLinkRepository::new();
+        repository.expect_redeem().times(0);
+
+        assert_eq!(
+            _redeem(
+                &token("verify-email"),
+                "verify-email",
+                SECRET,
+                &repository,
+                now() + chrono::Duration::hours(1)
+            ),
+            Err(AuthError::ActionLinkExpired)
+        );
+    }
+
+    #[test]
+    fn test_check_doesnt_redeem() {
+        let t = token("verify-email");
+
+        assert_eq!(
+            _check(&t, "verify-email", SECRET, now()).map(|l| l.user),
+            Ok(42)
+        );
+        assert_eq!(
+            _check(&t, "unlock", SECRET, now()),
+            Err(AuthError::InvalidActionLink)
+        );
+        assert_eq!(
+            _check(
+                &t,
+                "verify-email",
+                SECRET,
+                now() + chrono::Duration::hours(1)
+            ),
+            Err(AuthError::ActionLinkExpired)
+        );
+        assert!(_redeem(&t, "verify-email", SECRET, &repository(true), now()).is_ok());
+    }
+
+    #[test]
+    fn test_redeem_link_twice() {
+        assert_eq!(
+            _redeem(
+                &token("verify-email"),
+                "verify-email",
+                SECRET,
+                &repository(false),
+                now()
+            ),
+            Err(AuthError::ActionLinkUsed)
+        );
+
+        let mut repository = MockSQliteActionLinkRepository::new();
+        repository
+            .expect_redeem()
+            .returning(|_, _, _| Err(ActionLinkDBError::RedeemError));
+        assert_eq!(
+            _redeem(
+                &token("verify-email"),
+                "verify-email",
+                SECRET,
+                &repository,
+                now()
+            ),
+            Err(AuthError::ActionLinkError)
+        );
+    }
+
+    #[test]
+    fn test_is_purpose_valid() {
+        assert!(is_purpose_valid("verify-email"));
+        assert!(is_purpose_valid("newsletter.confirm_v2"));
+        assert!(!is_purpose_valid(""));
+        assert!(!is_purpose_valid("verify:email"));
+        assert!(!is_purpose_valid("Unlock"));
+        assert_eq!(
+            _issue("verify:email", 42, now(), SECRET),
+            Err(AuthError::ActionLinkError)
+        );
+    }
+}
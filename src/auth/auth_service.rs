@@ -0,0 +1,519 @@
+/*!
+ * The login, registration, reset & 2FA flows over a given storage of the users
+ *
+ * The free functions of the flows (e.g. `reset::change_password`) use the
+ * SQLite database set in the `.env` file. An `AuthService` holds the
+ * repository of the users instead, along with the mailer & the clock, so an
+ * application embedding the library picks its own storage once:
+ *
+ * ```no_run
+ * use secure_auth::auth::auth_service::AuthService;
+ * use secure_auth::auth::binding::ClientInfo;
+ * use secure_auth::auth::login::LoginOutcome;
+ * use secure_auth::auth::risk::Signals;
+ * use secure_auth::db::repository::InMemoryUserRepository;
+ * use secure_auth::validation::{Email, Password};
+ *
+ * let service = AuthService::new(InMemoryUserRepository::new());
+ * let email = Email::parse("alice@example.com").unwrap();
+ * let passwd = Password::parse("correct horse battery staple").unwrap();
+ * let signals = Signals::at(chrono::Utc::now(), secure_auth::config::get().locale.timezone);
+ * let client = ClientInfo {
+ *     ip: Some("203.0.113.7".parse().unwrap()),
+ *     ..ClientInfo::default()
+ * };
+ * match service.begin_login(&signals, "203.0.113.7", &client, &email, &passwd)? {
+ *     LoginOutcome::Authenticated(_) => {}
+ *     LoginOutcome::TwoFactorRequired(challenge) => {
+ *         service.complete_2fa(&client, &challenge, "totp", "123456")?;
+ *     }
+ * }
+ * # Ok::<(), secure_auth::errors::AuthError>(())
+ * ```
+ *
+ * # Note
+ * Only the users are in the repository, the other storages (audit log,
+ * notifications...) stay on SQLite.
+ *
+ * # Author
+ * Doran Kayoumi <doran.kayoumi@heig-vd.ch>
+ */
+
+use chrono::{DateTime, Utc};
+
+use super::binding::ClientInfo;
+use super::factor::FactorRegistry;
+use super::login::{CompletedLogin, LoginOutcome, TwoFactorChallenge};
+use super::risk::Signals;
+use super::twofa::{self, Enrollment};
+use super::validator::{RegistrationValidator, ReservedEmailValidator};
+use super::{action, login, register, reset, step_up};
+use crate::config;
+use crate::db::ephemeral::{self, SharedTokenStore};
+use crate::db::models::User;
+use crate::db::repository::UserRepository;
+use crate::errors::{AuthError, Completion, MailError};
+use crate::mail::{self, Mailer};
+use crate::validation::{Email, Password};
+
+/// The flows of the library, over the users of a given storage
+pub struct AuthService<R: UserRepository> {
+    repository: R,
+    /// Mailer of the reset tokens & the alerts, the default ones if not set (see `mail.rs`)
+    mailer: Option<Box<dyn Mailer>>,
+    clock: fn() -> DateTime<Utc>,
+    /// Store of the reset tokens, the one outliving the process if not set (see `ephemeral::durable`)
+    store: Option<SharedTokenStore>,
+    /// Key signing the reset tokens, `ACTION_LINK_SECRET` if not set (see `action.rs`)
+    link_secret: Option<String>,
+    /// Patterns of the reserved emails, the ones of the `[registration]` section if not set
+    reserved: Option<Vec<String>>,
+}
+
+impl<R: UserRepository> AuthService<R> {
+    /// Service sending the emails with the default mailers, at the current date & time
+    ///
+    /// # Arguments
+    ///
+    /// * `repository` - the storage of the users
+    ///
+    pub fn new(repository: R) -> Self {
+        Self {
+            repository,
+            mailer: None,
+            clock: Utc::now,
+            store: None,
+            link_secret: None,
+            reserved: None,
+        }
+    }
+
+    /// Send the reset tokens & the alerts with a given mailer
+    /// Note: the alerts aren't deduplicated like with the default alert mailer
+    pub fn with_mailer(mut self, mailer: Box<dyn Mailer>) -> Self {
+        self.mailer = Some(mailer);
+        self
+    }
+
+    /// Check the expiry of the reset tokens against a given clock (e.g. a fixed date in the tests)
+    pub fn with_clock(mut self, clock: fn() -> DateTime<Utc>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Keep the reset tokens in a given store (e.g. an `InMemoryTokenStore` in the tests)
+    pub fn with_token_store(mut self, store: SharedTokenStore) -> Self {
+        self.store = Some(store);
+        self
+    }
+
+    /// Sign the reset tokens with a given key rather than `ACTION_LINK_SECRET` (e.g. in the tests)
+    ///
+    /// # Arguments
+    ///
+    /// * `secret` - the key, at least 32 characters
+    ///
+    pub fn with_link_secret(mut self, secret: &str) -> Self {
+        self.link_secret = Some(secret.to_string());
+        self
+    }
+
+    /// Refuse the registrations with given reserved emails rather than the ones of the configuration
+    ///
+    /// # Arguments
+    ///
+    /// * `patterns` - the reserved emails, `*` matches anything (e.g. `admin@*.example.com`)
+    ///
+    pub fn with_reserved_emails(mut self, patterns: &[String]) -> Self {
+        self.reserved = Some(patterns.to_vec());
+        self
+    }
+
+    pub fn repository(&self) -> &R {
+        &self.repository
+    }
+
+    /// See `login::begin_login_with`, with the standard second factors
+    ///
+    /// # Arguments
+    ///
+    /// * `signals` - what is known about the login (e.g. a new device)
+    ///
+    /// * `source` - where the login comes from (e.g. the IP address of the client)
+    ///
+    /// * `client` - the client logging in, the challenge is bound to it
+    ///
+    /// * `email` - the email of the user trying to login
+    ///
+    /// * `passwd` - the password of the user trying to login
+    ///
+    pub fn begin_login(
+        &self,
+        signals: &Signals,
+        source: &str,
+        client: &ClientInfo,
+        email: &Email,
+        passwd: &Password,
+    ) -> Result<LoginOutcome, AuthError> {
+        login::begin_login_with_repository(
+            &FactorRegistry::standard(),
+            signals,
+            source,
+            client,
+            email,
+            passwd,
+            &self.repository,
+        )
+    }
+
+    /// See `login::complete_2fa_with`, with the standard second factors
+    pub fn complete_2fa(
+        &self,
+        client: &ClientInfo,
+        challenge: &TwoFactorChallenge,
+        factor: &str,
+        code: &str,
+    ) -> Result<CompletedLogin, AuthError> {
+        login::complete_2fa_with_repository(
+            &FactorRegistry::standard(),
+            client,
+            challenge,
+            factor,
+            code,
+            &self.repository,
+        )
+    }
+
+    /// See `register::register`
+    /// Note: the reserved emails are refused before the given validator is called
+    pub fn register(
+        &self,
+        email: &Email,
+        passwd: &Password,
+        validator: &dyn RegistrationValidator,
+    ) -> Result<(), AuthError> {
+        let reserved = match self.reserved.as_deref() {
+            Some(patterns) => ReservedEmailValidator::new(patterns),
+            None => ReservedEmailValidator::new(&config::get().registration.reserved),
+        };
+        reserved.validate(email, passwd.as_str())?;
+
+        let mailer: &dyn Mailer = match self.mailer.as_deref() {
+            Some(mailer) => mailer,
+            None => mail::default_mailer(),
+        };
+        register::register_with(email, passwd, validator, &self.repository, mailer)
+    }
+
+    /// See `reset::generate_reset_token`
+    pub fn generate_reset_token(&self, email: &Email) -> Result<(), AuthError> {
+        let mailer: &dyn Mailer = match self.mailer.as_deref() {
+            Some(mailer) => mailer,
+            None => mail::default_mailer(),
+        };
+        reset::generate_reset_token_with(
+            email,
+            &self.repository,
+            &*self.token_store(),
+            mailer,
+            &self.link_secret()?,
+        )
+    }
+
+    /// See `reset::send_reset_token`
+    #[deprecated(
+        since = "0.1.0",
+        note = "the token is sent by `generate_reset_token`, only its hash is kept afterwards; this does nothing"
+    )]
+    pub fn send_reset_token(&self, _email: &Email) -> Result<(), MailError> {
+        Ok(())
+    }
+
+    /// See `reset::check_token`
+    pub fn check_token(&self, email: &Email, token: &str) -> Result<(), AuthError> {
+        reset::check_token_with(
+            email,
+            token,
+            &self.repository,
+            &*self.token_store(),
+            &self.link_secret()?,
+            (self.clock)(),
+        )
+    }
+
+    /// See `reset::change_password`
+    pub fn change_password(
+        &self,
+        email: &Email,
+        token: &str,
+        new_passwd: &Password,
+    ) -> Result<Completion, AuthError> {
+        let (store, secret, now) = (self.token_store(), self.link_secret()?, (self.clock)());
+        match self.mailer.as_deref() {
+            Some(mailer) => reset::change_password_with(
+                email,
+                token,
+                new_passwd,
+                &self.repository,
+                &*store,
+                mailer,
+                &secret,
+                now,
+            ),
+            None => reset::change_password_with(
+                email,
+                token,
+                new_passwd,
+                &self.repository,
+                &*store,
+                &mail::alert_mailer(),
+                &secret,
+                now,
+            ),
+        }
+    }
+
+    /// See `step_up::require_step_up_with`, with the standard second factors
+    /// Note: the user is read again from the repository, so the password checked is
+    ///       the current one even if the user was loaded before it changed
+    ///
+    /// # Arguments
+    ///
+    /// * `source` - where the confirmation comes from (e.g. the IP address of the client)
+    ///
+    /// * `client` - the client confirming the action
+    ///
+    /// * `u` - the user confirming the action
+    ///
+    /// * `passwd` - the password entered by the user
+    ///
+    /// * `code` - the code of her/his second factor, ignored if she/he set none up
+    ///
+    pub fn require_step_up(
+        &self,
+        source: &str,
+        client: &ClientInfo,
+        u: &User,
+        passwd: &str,
+        code: Option<&str>,
+    ) -> Result<(), AuthError> {
+        let current = self
+            .repository
+            .get_user(&u.get_email())
+            .map_err(|e| e.to_auth_error(AuthError::IncorrectPassword))?;
+        step_up::require_step_up_with(
+            &FactorRegistry::standard(),
+            source,
+            client,
+            &current,
+            passwd,
+            code,
+        )
+    }
+
+    /// See `twofa::start_enrollment`
+    pub fn start_enrollment(&self, u: &mut User, issuer: &str) -> Result<Enrollment, AuthError> {
+        twofa::start_enrollment_with_repository(u, issuer, &self.repository)
+    }
+
+    /// See `twofa::start_rotation`
+    pub fn start_rotation(&self, u: &mut User, issuer: &str) -> Result<Enrollment, AuthError> {
+        twofa::start_rotation_with_repository(u, issuer, &self.repository)
+    }
+
+    /// See `twofa::confirm_rotation`
+    pub fn confirm_rotation(&self, u: &mut User, code: &str) -> Result<(), AuthError> {
+        twofa::confirm_rotation_with_repository(u, code, &self.repository)
+    }
+
+    fn token_store(&self) -> SharedTokenStore {
+        self.store.clone().unwrap_or_else(ephemeral::durable)
+    }
+
+    fn link_secret(&self) -> Result<String, AuthError> {
+        match &self.link_secret {
+            Some(secret) => Ok(secret.clone()),
+            None => action::secret(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::auth::factor::TOTP;
+    use crate::auth::inactivity;
+    use crate::auth::validator::ValidatorChain;
+    use crate::db::ephemeral::InMemoryTokenStore;
+    use crate::db::repository::InMemoryUserRepository;
+    use crate::mail::capture::CapturingMailer;
+    use crate::mail::MockConsoleMailer;
+    use chrono::TimeZone;
+    use google_authenticator::GoogleAuthenticator;
+    use std::sync::Arc;
+
+    fn email() -> Email {
+        Email::parse("alice@email.test").unwrap()
+    }
+
+    fn passwd() -> Password {
+        Password::parse("correct horse battery staple").unwrap()
+    }
+
+    /// Service with Alice registered, keeping the reset tokens in memory
+    fn service() -> AuthService<InMemoryUserRepository> {
+        let service = AuthService::new(InMemoryUserRepository::new())
+            .with_token_store(Arc::new(InMemoryTokenStore::new()))
+            .with_link_secret("0123456789abcdef0123456789abcdef");
+        service
+            .register(&email(), &passwd(), &ValidatorChain::new())
+            .unwrap();
+        service
+    }
+
+    /// Login of Alice, from a source of its own so the other tests don't throttle it
+    fn login(
+        service: &AuthService<InMemoryUserRepository>,
+        passwd: &Password,
+    ) -> Result<LoginOutcome, AuthError> {
+        service.begin_login(
+            &Signals::default(),
+            "auth-service",
+            &ClientInfo::local(),
+            &email(),
+            passwd,
+        )
+    }
+
+    #[test]
+    fn test_login_with_the_given_repository() {
+        let service = service();
+
+        match login(&service, &passwd()) {
+            Ok(LoginOutcome::Authenticated(login)) => {
+                assert_eq!(login.get_user().get_email(), "alice@email.test")
+            }
+            outcome => panic!("unexpected outcome: {:?}", outcome),
+        }
+
+        // the login is kept for the inactivity policy, in the given repository too
+        let u = UserRepository::get_user(service.repository(), &email()).unwrap();
+        let attributes = service.repository().get_attributes(u.get_id()).unwrap();
+        assert!(attributes.contains_key(inactivity::LAST_LOGIN_ATTRIBUTE));
+    }
+
+    #[test]
+    fn test_2fa_with_the_given_repository() {
+        const SECRET: &str = "I3VFM3JKMNDJCDH5BMBEEQAW6KJ6NOE3";
+        let service = service();
+        let mut u = UserRepository::get_user(service.repository(), &email()).unwrap();
+        u.set_secret_2fa(Some(SECRET.to_string()));
+        service.repository().update_user(&u).unwrap();
+
+        let challenge = match login(&service, &passwd()) {
+            Ok(LoginOutcome::TwoFactorRequired(challenge)) => challenge,
+            outcome => panic!("unexpected outcome: {:?}", outcome),
+        };
+        let code = GoogleAuthenticator::new().get_code(SECRET, 0).unwrap();
+        let completed = service
+            .complete_2fa(&ClientInfo::local(), &challenge, TOTP, &code)
+            .unwrap();
+
+        assert_eq!(completed.user.get_email(), "alice@email.test");
+    }
+
+    #[test]
+    fn test_register_refuses_reserved_emails() {
+        let service = AuthService::new(InMemoryUserRepository::new())
+            .with_reserved_emails(&["admin@*".to_string()]);
+        let admin = Email::parse("Admin+x@email.test").unwrap();
+
+        assert_eq!(
+            service.register(&admin, &passwd(), &ValidatorChain::new()),
+            Err(AuthError::ReservedEmail)
+        );
+        assert!(UserRepository::get_user(service.repository(), &admin).is_err());
+        assert_eq!(
+            service.register(&email(), &passwd(), &ValidatorChain::new()),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn test_reset_with_the_given_mailer() {
+        let mut mailer = MockConsoleMailer::new();
+        // the reset token, sent once it's generated
+        mailer
+            .expect_send()
+            .withf(|e| e.to == "alice@email.test" && e.body.contains("reset token"))
+            .times(1)
+            .returning(|_| Ok(()));
+        let service = service().with_mailer(Box::new(mailer));
+
+        service.generate_reset_token(&email()).unwrap();
+        #[allow(deprecated)]
+        let sent = service.send_reset_token(&email());
+        assert_eq!(sent, Ok(()));
+    }
+
+    #[test]
+    fn test_reset_with_the_token_received() {
+        let mailer = CapturingMailer::new();
+        let service = service().with_mailer(Box::new(mailer.clone()));
+
+        service.generate_reset_token(&email()).unwrap();
+        let token = mailer.last_token_for("alice@email.test").unwrap();
+        let new_passwd = Password::parse("another correct horse battery").unwrap();
+        assert_eq!(
+            service.change_password(&email(), &token, &new_passwd),
+            Ok(Completion::Completed)
+        );
+
+        assert!(login(&service, &new_passwd).is_ok());
+        // the token & the alert of the password change
+        assert_eq!(mailer.emails_to("alice@email.test").len(), 2);
+    }
+
+    #[test]
+    fn test_step_up_with_the_current_password() {
+        let mailer = CapturingMailer::new();
+        let service = service().with_mailer(Box::new(mailer.clone()));
+        let u = UserRepository::get_user(service.repository(), &email()).unwrap();
+        let step_up = |passwd: &Password| {
+            service.require_step_up(
+                "auth-service",
+                &ClientInfo::local(),
+                &u,
+                passwd.as_str(),
+                None,
+            )
+        };
+
+        assert_eq!(step_up(&passwd()), Ok(()));
+
+        service.generate_reset_token(&email()).unwrap();
+        let token = mailer.last_token_for("alice@email.test").unwrap();
+        let new_passwd = Password::parse("another correct horse battery").unwrap();
+        service
+            .change_password(&email(), &token, &new_passwd)
+            .unwrap();
+        // the user was loaded before the change, the new password is checked all the same
+        assert_eq!(step_up(&passwd()), Err(AuthError::IncorrectPassword));
+        assert_eq!(step_up(&new_passwd), Ok(()));
+    }
+
+    #[test]
+    fn test_reset_token_expiry_with_the_given_clock() {
+        let mailer = CapturingMailer::new();
+        let service = service()
+            .with_mailer(Box::new(mailer.clone()))
+            .with_clock(|| Utc.ymd(2100, 1, 1).and_hms(0, 0, 0));
+
+        service.generate_reset_token(&email()).unwrap();
+        let token = mailer.last_token_for("alice@email.test").unwrap();
+        assert_eq!(
+            service.check_token(&email(), &token),
+            Err(AuthError::ExpiredToken)
+        );
+    }
+}
@@ -0,0 +1,433 @@
+/*!
+ * Check whether an email is still available, e.g. to validate a signup form early
+ *
+ * Telling whether an email is used is exactly what an attacker enumerating the
+ * accounts wants to know, so each check:
+ * - is limited per source (see the `[availability]` section of the configuration)
+ * - requires a solved CAPTCHA, so they can't be automated cheaply
+ * - takes at least a minimum duration, so the answer can't be told from the timing
+ *
+ * The registration still rejects used emails by itself (see `register.rs`).
+ *
+ * The checks counted & the CAPTCHAs issued are kept in the store of the flows
+ * (see `ephemeral::shared`), so the instances sharing it limit the same sources
+ * and a CAPTCHA issued by one can be answered on another.
+ *
+ * # Author
+ * Doran Kayoumi <doran.kayoumi@heig-vd.ch>
+ */
+
+use chrono::Utc;
+use lazy_static::lazy_static;
+use serde::Serialize;
+use std::convert::TryFrom;
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::throttle::{ArithmeticCaptcha, Captcha, CaptchaProvider};
+use super::timing;
+use crate::config::{self, AvailabilityConfig};
+use crate::db::ephemeral::{self, InMemoryTokenStore, SharedTokenStore};
+use crate::db::repository::{SQliteUserRepository, UserFilter, UserRepository};
+use crate::errors::AuthError;
+use crate::validation::is_email_valid;
+
+/// Window in which the checks of a source are counted
+const WINDOW: Duration = Duration::from_secs(60 * 60);
+
+/// What the CAPTCHAs of the checks are for (see `ephemeral::captcha_key`)
+pub const CAPTCHA_SCOPE: &str = "availability";
+
+/// Checks of each source in its current window
+/// They're kept in a `TokenStore` which drops them once the window is over, the
+/// memory of an `InMemoryTokenStore` is capped too (see `ephemeral::MAX_ENTRIES`)
+/// Note: the window of a source starts with its first check, it's fixed
+pub struct SourceLimiter {
+    store: SharedTokenStore,
+    window: Duration,
+}
+
+impl Default for SourceLimiter {
+    /// Checks kept in memory, by this limiter only
+    fn default() -> Self {
+        Self::with_store(Arc::new(InMemoryTokenStore::new()))
+    }
+}
+
+/// Key of the checks of a source
+fn count_key(source: &str) -> String {
+    ephemeral::rate_limit_key("availability", source)
+}
+
+/// Key of the start of the window of a source, in seconds since the epoch
+fn start_key(source: &str) -> String {
+    ephemeral::rate_limit_key("availability_window", source)
+}
+
+/// State of the checks of a source, so a rejected client knows when to try again
+#[derive(PartialEq, Debug, Clone, Copy, Serialize)]
+pub struct RateLimit {
+    /// Checks allowed in the window
+    pub limit: u32,
+    /// Checks left in the window
+    pub remaining: u32,
+    /// Seconds until the oldest check counted leaves the window, i.e. until a check is given back
+    pub reset: u64,
+}
+
+impl RateLimit {
+    /// Seconds to wait before checking again (the `Retry-After` of a 429), if no check is left
+    pub fn retry_after(&self) -> Option<u64> {
+        if self.remaining == 0 {
+            Some(self.reset)
+        } else {
+            None
+        }
+    }
+}
+
+impl SourceLimiter {
+    /// # Arguments
+    ///
+    /// * `store` - where the checks are kept
+    ///
+    pub fn with_store(store: SharedTokenStore) -> Self {
+        Self {
+            store,
+            window: WINDOW,
+        }
+    }
+
+    /// Count a check of a source
+    /// Returns whether the check is allowed, i.e. the source didn't use up its checks
+    /// Note: the checks are refused if the store can't be reached, they can't be limited
+    ///
+    /// # Arguments
+    ///
+    /// * `source` - where the check comes from (e.g. an IP address)
+    ///
+    /// * `max` - checks allowed in the window
+    ///
+    pub fn allow(&self, source: &str, max: u32) -> Result<bool, AuthError> {
+        let count = self
+            .store
+            .increment(&count_key(source), self.window)
+            .map_err(|_| AuthError::StateStoreError)?;
+        if count == 1 {
+            // Note: only used to tell when the window is over, see `status`
+            let _ = self.store.put(
+                &start_key(source),
+                &Utc::now().timestamp().to_string(),
+                self.window,
+            );
+        }
+
+        Ok(count <= u64::from(max))
+    }
+
+    /// Get the state of the checks of a source, without counting a check
+    /// Note: a source whose checks can't be read has all of them left
+    ///
+    /// # Arguments
+    ///
+    /// * `source` - where the checks come from (e.g. an IP address)
+    ///
+    /// * `max` - checks allowed in the window
+    ///
+    pub fn status(&self, source: &str, max: u32) -> RateLimit {
+        let read = |key: String| self.store.get(&key).ok().flatten();
+        let count = read(count_key(source))
+            .and_then(|c| c.parse::<u64>().ok())
+            .unwrap_or(0);
+
+        // Note: a window whose start is unknown is taken as a new one, the client waits
+        //       no longer than a window anyway
+        let reset = if count == 0 {
+            0
+        } else {
+            let elapsed = read(start_key(source))
+                .and_then(|s| s.parse::<i64>().ok())
+                .map_or(0, |start| (Utc::now().timestamp() - start).max(0) as u64);
+            self.window.as_secs().saturating_sub(elapsed)
+        };
+
+        RateLimit {
+            limit: max,
+            remaining: max.saturating_sub(u32::try_from(count).unwrap_or(u32::MAX)),
+            reset,
+        }
+    }
+}
+
+lazy_static! {
+    static ref LIMITER: SourceLimiter = SourceLimiter::with_store(ephemeral::shared());
+    static ref CAPTCHAS: ArithmeticCaptcha =
+        ArithmeticCaptcha::with_store(ephemeral::shared(), CAPTCHA_SCOPE);
+}
+
+/// Get a CAPTCHA to solve before checking an email
+pub fn new_captcha() -> Captcha {
+    CAPTCHAS.issue()
+}
+
+/// Get the state of the checks of a source, e.g. to answer a check rejected with
+/// `AuthError::TooManyChecks` with a 429 telling when to try again
+///
+/// # Arguments
+///
+/// * `source` - where the checks come from (e.g. an IP address)
+///
+pub fn rate_limit(source: &str) -> RateLimit {
+    LIMITER.status(source, config::get().availability.checks_per_hour)
+}
+
+/// Public function for the availability check
+/// See `_is_email_available` for more info
+///
+pub fn is_email_available(
+    email: &str,
+    source: &str,
+    captcha: &Captcha,
+    answer: &str,
+) -> Result<bool, AuthError> {
+    let repository = SQliteUserRepository::new();
+    _is_email_available(
+        email,
+        source,
+        captcha,
+        answer,
+        &*CAPTCHAS,
+        &LIMITER,
+        &repository,
+        &config::get().availability,
+    )
+}
+
+/// Check whether an email is still available, it always takes at least the
+/// minimum duration of the policy, whatever the outcome
+///
+/// # Arguments
+///
+/// * `email` - the email to check
+///
+/// * `source` - where the check comes from (e.g. an IP address)
+///
+/// * `captcha` - the CAPTCHA returned by `new_captcha`
+///
+/// * `answer` - the answer of the user to the CAPTCHA
+///
+/// * `captchas` - the provider which issued the CAPTCHA
+///
+/// * `limiter` - the recent checks of the sources
+///
+/// * `repository` - the user repository to read from
+///
+/// * `policy` - the checks allowed & their minimum duration
+///
+#[allow(clippy::too_many_arguments)]
+fn _is_email_available(
+    email: &str,
+    source: &str,
+    captcha: &Captcha,
+    answer: &str,
+    captchas: &dyn CaptchaProvider,
+    limiter: &SourceLimiter,
+    repository: &dyn UserRepository,
+    policy: &AvailabilityConfig,
+) -> Result<bool, AuthError> {
+    let min_duration = Duration::from_millis(policy.min_duration_ms);
+
+    timing::pad(min_duration, || {
+        // Note: counted before the CAPTCHA, so guessing the answers uses up the checks too
+        if !limiter.allow(source, policy.checks_per_hour)? {
+            return Err(AuthError::TooManyChecks);
+        }
+        if !captchas.verify(captcha, answer) {
+            return Err(AuthError::InvalidCaptcha);
+        }
+        if !is_email_valid(email) {
+            return Err(AuthError::InvalidEmail);
+        }
+
+        repository
+            .list_users(&UserFilter::new().with_email(email))
+            .map(|found| found.is_empty())
+            .map_err(|_| AuthError::AvailabilityError)
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::db::models::User;
+    use crate::db::repository::MockSQliteUserRepository;
+    use std::time::Instant;
+
+    fn policy() -> AvailabilityConfig {
+        AvailabilityConfig {
+            checks_per_hour: 2,
+            min_duration_ms: 0,
+        }
+    }
+
+    /// Repository in which only `taken@email.test` is used
+    fn repository() -> MockSQliteUserRepository {
+        let mut mock = MockSQliteUserRepository::new();
+        mock.expect_list_users().returning(|f| {
+            if *f == UserFilter::new().with_email("taken@email.test") {
+                Ok(vec![User::new("taken@email.test", "passwd_hash")])
+            } else {
+                Ok(vec![])
+            }
+        });
+        mock
+    }
+
+    /// Check an email, solving the CAPTCHA right if asked to
+    fn check(
+        email: &str,
+        solved: bool,
+        limiter: &SourceLimiter,
+        policy: &AvailabilityConfig,
+    ) -> Result<bool, AuthError> {
+        let captchas = ArithmeticCaptcha::default();
+        let captcha = captchas.issue();
+        let answer = if solved {
+            // the questions are "How much is a + b?"
+            let terms: Vec<u32> = captcha
+                .get_question()
+                .trim_start_matches("How much is ")
+                .trim_end_matches('?')
+                .split(" + ")
+                .map(|t| t.parse().unwrap())
+                .collect();
+            (terms[0] + terms[1]).to_string()
+        } else {
+            "not a number".to_string()
+        };
+
+        _is_email_available(
+            email,
+            "1",
+            &captcha,
+            &answer,
+            &captchas,
+            limiter,
+            &repository(),
+            policy,
+        )
+    }
+
+    #[test]
+    fn test_availability() {
+        let limiter = SourceLimiter::default();
+
+        assert_eq!(
+            check("taken@email.test", true, &limiter, &policy()),
+            Ok(false)
+        );
+        assert_eq!(
+            check("free@email.test", true, &limiter, &policy()),
+            Ok(true)
+        );
+    }
+
+    #[test]
+    fn test_captcha_is_required() {
+        let limiter = SourceLimiter::default();
+
+        assert_eq!(
+            check("taken@email.test", false, &limiter, &policy()),
+            Err(AuthError::InvalidCaptcha)
+        );
+        assert_eq!(
+            check("email", true, &limiter, &policy()),
+            Err(AuthError::InvalidEmail)
+        );
+    }
+
+    #[test]
+    fn test_checks_are_limited_per_source() {
+        let limiter = SourceLimiter::default();
+
+        // the failed CAPTCHAs are counted too
+        let _ = check("free@email.test", false, &limiter, &policy());
+        let _ = check("free@email.test", true, &limiter, &policy());
+
+        assert_eq!(
+            check("free@email.test", true, &limiter, &policy()),
+            Err(AuthError::TooManyChecks)
+        );
+        assert_eq!(limiter.allow("2", 1), Ok(true));
+    }
+
+    #[test]
+    fn test_rate_limit_status() {
+        let limiter = SourceLimiter::default();
+
+        assert_eq!(
+            limiter.status("1", 2),
+            RateLimit {
+                limit: 2,
+                remaining: 2,
+                reset: 0
+            }
+        );
+        assert_eq!(limiter.status("1", 2).retry_after(), None);
+
+        let _ = check("free@email.test", true, &limiter, &policy());
+        let _ = check("free@email.test", true, &limiter, &policy());
+        let _ = check("free@email.test", true, &limiter, &policy());
+
+        // the rejected check isn't counted
+        let status = limiter.status("1", 2);
+        assert_eq!(status.remaining, 0);
+        assert!(status.reset > WINDOW.as_secs() - 5 && status.reset <= WINDOW.as_secs());
+        assert_eq!(status.retry_after(), Some(status.reset));
+        assert_eq!(limiter.status("2", 2).remaining, 2);
+    }
+
+    #[test]
+    fn test_windows_expire() {
+        let limiter = SourceLimiter {
+            store: Arc::new(InMemoryTokenStore::new()),
+            window: Duration::ZERO,
+        };
+
+        // once its window is over, a source has all of its checks again
+        assert_eq!(limiter.allow("1", 1), Ok(true));
+        assert_eq!(limiter.allow("1", 1), Ok(true));
+        assert_eq!(limiter.status("1", 1).remaining, 1);
+    }
+
+    #[test]
+    fn test_limits_are_shared() {
+        let store: SharedTokenStore = Arc::new(InMemoryTokenStore::new());
+        let first = SourceLimiter::with_store(Arc::clone(&store));
+        let second = SourceLimiter::with_store(store);
+
+        assert_eq!(first.allow("1", 1), Ok(true));
+        // e.g. another instance of the application
+        assert_eq!(second.allow("1", 1), Ok(false));
+        assert_eq!(second.status("1", 1).remaining, 0);
+    }
+
+    #[test]
+    fn test_uniform_duration() {
+        let policy = AvailabilityConfig {
+            checks_per_hour: 10,
+            min_duration_ms: 50,
+        };
+        let limiter = SourceLimiter::default();
+
+        for (email, solved) in &[
+            ("taken@email.test", true),
+            ("free@email.test", true),
+            ("taken@email.test", false),
+        ] {
+            let start = Instant::now();
+            let _ = check(email, *solved, &limiter, &policy);
+            assert!(start.elapsed() >= Duration::from_millis(50));
+        }
+    }
+}
@@ -0,0 +1,160 @@
+/*!
+ * Messages shown around the logins
+ *
+ * The operators can set (in the `[banner]` section of the configuration):
+ * - a banner shown before the logins, e.g. the legal notice of the system
+ * - a message of the day (MOTD), shown once logged in, e.g. a security notice or
+ *   a planned maintenance
+ *
+ * The CLI prints them, the HTTP layers embedding the library show the banner
+ * on their login page (`pre_login_banner`) & send the MOTD with the answer to
+ * a login (see `LoginResponse`).
+ *
+ * # Note
+ * The system has a single tenant, so the messages are the same for every user.
+ *
+ * # Author
+ * Doran Kayoumi <doran.kayoumi@heig-vd.ch>
+ */
+
+use serde::Serialize;
+
+use crate::config::{self, BannerConfig};
+use crate::db::models::User;
+
+/// Body of the answer of an HTTP layer to a login that went through
+#[derive(PartialEq, Debug, Clone, Serialize)]
+pub struct LoginResponse {
+    pub user_id: i32,
+    pub email: String,
+    /// Whether the login was confirmed with a second factor
+    pub two_factor: bool,
+    /// Message of the day, left out if none is set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub motd: Option<String>,
+}
+
+/// Public function for the banner shown before the logins
+/// See `_pre_login_banner` for more info
+///
+pub fn pre_login_banner() -> Option<String> {
+    _pre_login_banner(&config::get().banner)
+}
+
+/// Public function for the message of the day
+/// See `_motd` for more info
+///
+pub fn motd() -> Option<String> {
+    _motd(&config::get().banner)
+}
+
+/// Public function for the answer to a login
+/// See `_login_response` for more info
+///
+pub fn login_response(u: &User, two_factor: bool) -> LoginResponse {
+    _login_response(u, two_factor, &config::get().banner)
+}
+
+/// Get the banner shown before the logins, none if it isn't set or is blank
+///
+/// # Arguments
+///
+/// * `config` - the messages set by the operators
+///
+fn _pre_login_banner(config: &BannerConfig) -> Option<String> {
+    non_blank(&config.pre_login)
+}
+
+/// Get the message of the day, none if it isn't set or is blank
+///
+/// # Arguments
+///
+/// * `config` - the messages set by the operators
+///
+fn _motd(config: &BannerConfig) -> Option<String> {
+    non_blank(&config.motd)
+}
+
+/// Build the answer to a login that went through
+///
+/// # Arguments
+///
+/// * `u` - the user who logged in (of `LoginOutcome::Authenticated` or `CompletedLogin`)
+///
+/// * `two_factor` - whether the login was completed with a second factor (`complete_2fa`)
+///
+/// * `config` - the messages set by the operators
+///
+fn _login_response(u: &User, two_factor: bool, config: &BannerConfig) -> LoginResponse {
+    LoginResponse {
+        user_id: u.get_id(),
+        email: u.get_email(),
+        two_factor,
+        motd: _motd(config),
+    }
+}
+
+/// Trim a message, a blank one is the same as none
+/// Note: e.g. a multi-line string of the configuration ends with a line break
+fn non_blank(msg: &Option<String>) -> Option<String> {
+    msg.as_deref()
+        .map(str::trim)
+        .filter(|m| !m.is_empty())
+        .map(str::to_string)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    fn config() -> BannerConfig {
+        BannerConfig {
+            pre_login: Some("\nAuthorized use only.\n".to_string()),
+            motd: Some("Maintenance on Sunday".to_string()),
+        }
+    }
+
+    #[test]
+    fn test_messages() {
+        assert_eq!(
+            _pre_login_banner(&config()),
+            Some("Authorized use only.".to_string())
+        );
+        assert_eq!(_motd(&config()), Some("Maintenance on Sunday".to_string()));
+
+        let blank = BannerConfig {
+            pre_login: Some(" \n".to_string()),
+            motd: None,
+        };
+        assert_eq!(_pre_login_banner(&blank), None);
+        assert_eq!(_motd(&blank), None);
+    }
+
+    #[test]
+    fn test_login_response() {
+        let u = User::new("alice@email.test", "passwd_hash");
+
+        let response = _login_response(&u, true, &config());
+        assert_eq!(
+            serde_json::to_value(&response).unwrap(),
+            json!({
+                "user_id": u.get_id(),
+                "email": "alice@email.test",
+                "two_factor": true,
+                "motd": "Maintenance on Sunday",
+            })
+        );
+
+        // without a MOTD, the field is left out
+        let response = _login_response(&u, false, &BannerConfig::default());
+        assert_eq!(
+            serde_json::to_value(&response).unwrap(),
+            json!({
+                "user_id": u.get_id(),
+                "email": "alice@email.test",
+                "two_factor": false,
+            })
+        );
+    }
+}
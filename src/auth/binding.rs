@@ -0,0 +1,155 @@
+/*!
+ * Binding of the login challenges to the client which started them
+ *
+ * The only token handed out between two steps of the authentication is the id
+ * of the 2fa challenge (see `login.rs`), there are no sessions yet. When the
+ * binding is enabled (see the `[binding]` section of the configuration), the
+ * challenge remembers a fingerprint of the client & can only be completed by
+ * a client with the same fingerprint, so a stolen challenge id is useless.
+ *
+ * Only the hashes of the fingerprints are kept.
+ *
+ * # Author
+ * Doran Kayoumi <doran.kayoumi@heig-vd.ch>
+ */
+
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+
+use crate::portable::token;
+
+/// How much of the client must stay the same between the steps of a login
+#[derive(Deserialize, Serialize, Debug, PartialEq, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum BindingStrictness {
+    /// The challenges aren't bound
+    #[default]
+    Off,
+    /// Same user agent & same network (i.e. /24 in IPv4, /48 in IPv6), tolerates
+    /// the address changes of the mobile networks
+    Lenient,
+    /// Same TLS fingerprint, user agent & address
+    Strict,
+}
+
+/// What is known about the client of a request
+/// Note: everything is unknown for the CLI (see `ClientInfo::local`)
+#[derive(PartialEq, Debug, Clone, Default)]
+pub struct ClientInfo {
+    pub user_agent: Option<String>,
+    pub ip: Option<IpAddr>,
+    /// Fingerprint of the TLS handshake (e.g. JA3), set by the TLS terminator
+    pub tls_fingerprint: Option<String>,
+}
+
+impl ClientInfo {
+    /// The client of the CLI, on the machine running it
+    pub fn local() -> Self {
+        Self::default()
+    }
+
+    /// Network of the address of the client, i.e. its /24 in IPv4 & its /48 in IPv6
+    fn ip_class(&self) -> Option<String> {
+        self.ip.map(|ip| match ip {
+            IpAddr::V4(v4) => {
+                let o = v4.octets();
+                format!("{}.{}.{}.0/24", o[0], o[1], o[2])
+            }
+            IpAddr::V6(v6) => {
+                let s = v6.segments();
+                format!("{:x}:{:x}:{:x}::/48", s[0], s[1], s[2])
+            }
+        })
+    }
+}
+
+/// Computes the fingerprint a challenge is bound to, `None` if the binding is off
+///
+/// # Arguments
+///
+/// * `client` - the client of the request
+///
+/// * `strictness` - which attributes of the client are part of the fingerprint
+///
+pub fn fingerprint(client: &ClientInfo, strictness: BindingStrictness) -> Option<String> {
+    let unknown = String::new();
+    let user_agent = client.user_agent.as_ref().unwrap_or(&unknown);
+
+    let attributes = match strictness {
+        BindingStrictness::Off => return None,
+        BindingStrictness::Lenient => format!(
+            "ua={}\nnet={}",
+            user_agent,
+            client.ip_class().unwrap_or_default()
+        ),
+        BindingStrictness::Strict => format!(
+            "tls={}\nua={}\nip={}",
+            client.tls_fingerprint.as_ref().unwrap_or(&unknown),
+            user_agent,
+            client.ip.map(|ip| ip.to_string()).unwrap_or_default()
+        ),
+    };
+
+    Some(token::hash(&attributes))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rstest::rstest;
+
+    fn client(user_agent: &str, ip: &str, tls: &str) -> ClientInfo {
+        ClientInfo {
+            user_agent: Some(user_agent.to_string()),
+            ip: ip.parse().ok(),
+            tls_fingerprint: Some(tls.to_string()),
+        }
+    }
+
+    #[test]
+    fn test_off() {
+        assert_eq!(
+            fingerprint(&client("curl", "10.0.0.1", "a"), BindingStrictness::Off),
+            None
+        );
+    }
+
+    #[rstest(
+        other,
+        lenient,
+        strict,
+        case(client("curl", "10.0.0.1", "a"), true, true),
+        // same network
+        case(client("curl", "10.0.0.99", "a"), true, false),
+        case(client("curl", "10.0.1.1", "a"), false, false),
+        case(client("firefox", "10.0.0.1", "a"), false, false),
+        case(client("curl", "10.0.0.1", "b"), true, false),
+        case(ClientInfo::local(), false, false),
+        ::trace
+    )]
+    fn test_fingerprint(other: ClientInfo, lenient: bool, strict: bool) {
+        let c = client("curl", "10.0.0.1", "a");
+
+        for (strictness, same) in &[
+            (BindingStrictness::Lenient, lenient),
+            (BindingStrictness::Strict, strict),
+        ] {
+            assert_eq!(
+                fingerprint(&c, *strictness) == fingerprint(&other, *strictness),
+                *same
+            );
+        }
+    }
+
+    #[test]
+    fn test_ipv6_class() {
+        let c = client("curl", "2001:db8:1:2::1", "a");
+        let same_network = client("curl", "2001:db8:1:ffff::2", "a");
+
+        assert_eq!(c.ip_class().as_deref(), Some("2001:db8:1::/48"));
+        assert_eq!(
+            fingerprint(&c, BindingStrictness::Lenient),
+            fingerprint(&same_network, BindingStrictness::Lenient)
+        );
+    }
+}
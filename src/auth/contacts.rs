@@ -0,0 +1,1338 @@
+/*!
+ * Recovery of the accounts through trusted contacts
+ *
+ * A user can nominate up to `MAX_CONTACTS` trusted contacts, each of them gets
+ * an email with a link to confirm she/he accepts the role. When the user lost
+ * everything else (password, second factor), she/he can start a recovery: each
+ * confirmed contact gets an email with a link to approve it & the user gets an
+ * alert. Once all of them approved it & the waiting period is over (see the
+ * `[contact_recovery]` section of the configuration), the user can set a new
+ * password. The 2fa is disabled & the security hold (if any) released, no
+ * administrator is involved.
+ *
+ * Starting a recovery gives a secret to the person who started it, it's asked
+ * to complete the recovery, so only she/he can set the new password once the
+ * contacts approved it. A recovery in progress can't be replaced by someone
+ * else starting one (or the approvals already given would be lost).
+ *
+ * The waiting period gives the real owner of the account the time to react to
+ * a recovery she/he didn't start: she/he can login & cancel it, or follow the
+ * link of the alert (see `action.rs`) to take it over. The recovery is then
+ * replaced by a new one whose secret only she/he gets, so whoever started the
+ * first one can't keep her/him from recovering the account. The confirmation
+ * & approval tokens & the secret are random, only their hashes are stored, so
+ * the links can't be forged.
+ *
+ * # Author
+ * Doran Kayoumi <doran.kayoumi@heig-vd.ch>
+ */
+
+use chrono::{DateTime, Duration, Utc};
+
+use super::hold::{self, Release};
+use super::{action, maintenance, reset};
+use crate::audit::{self, AuditEvent};
+use crate::config::{self, ContactRecoveryConfig};
+use crate::db::ephemeral;
+use crate::db::models::{User, UserChangeset};
+use crate::db::repository::{
+    AuditRepository, SQliteAuditRepository, SQliteTrustedContactRepository, SQliteUserRepository,
+    TrustedContactRepository, UserFilter, UserRepository,
+};
+use crate::errors::AuthError;
+use crate::mail::templates::{self, Template};
+use crate::mail::{self, Mailer};
+use crate::portable::token;
+use crate::stats::{self, PasswordContext};
+use crate::validation::{Password, MAX_PASSWORD_BYTES, MAX_TOKEN_BYTES};
+use crate::{utils, validation};
+
+/// Maximum number of trusted contacts of a user
+pub const MAX_CONTACTS: usize = 2;
+
+/// Attribute holding the hash of the secret of the recovery in progress
+pub const RECOVERY_SECRET_ATTRIBUTE: &str = "contact_recovery_secret";
+
+/// Purpose of the links taking over a recovery, sent in the alert to the owner of the account
+pub const TAKEOVER_PURPOSE: &str = "recovery-takeover";
+
+/// Progress of a recovery
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct RecoveryStatus {
+    /// Number of contacts who approved the recovery
+    pub approved: usize,
+    /// Number of contacts who must approve the recovery
+    pub required: usize,
+    /// When the waiting period is over
+    pub ready_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// Hashes an approval token or a recovery secret
+fn hash_token(approval: &str) -> String {
+    token::hash(approval.trim())
+}
+
+/// Public function for listing the trusted contacts
+/// See `_list_contacts` for more info
+///
+pub fn list_contacts(u: &User) -> Result<Vec<String>, AuthError> {
+    let repository = SQliteTrustedContactRepository::new();
+    _list_contacts(u, &repository)
+}
+
+/// Public function for adding a trusted contact
+/// See `_add_contact` for more info
+///
+pub fn add_contact(u: &User, contact: &str) -> Result<(), AuthError> {
+    maintenance::check_writable()?;
+    let repository = SQliteTrustedContactRepository::new();
+    let audit_repository = SQliteAuditRepository::new();
+    _add_contact(
+        u,
+        contact,
+        &repository,
+        &audit_repository,
+        mail::default_mailer(),
+    )
+}
+
+/// Public function for listing the trusted contacts who confirmed they accept the role
+/// See `_list_confirmed_contacts` for more info
+///
+pub fn list_confirmed_contacts(u: &User) -> Result<Vec<String>, AuthError> {
+    let repository = SQliteTrustedContactRepository::new();
+    _list_confirmed_contacts(u, &repository)
+}
+
+/// Public function for the confirmation of a trusted contact
+/// See `_confirm_contact` for more info
+///
+pub fn confirm_contact(token: &str) -> Result<(), AuthError> {
+    maintenance::check_writable()?;
+    let repository = SQliteTrustedContactRepository::new();
+    let audit_repository = SQliteAuditRepository::new();
+    _confirm_contact(token, &repository, &audit_repository, Utc::now())
+}
+
+/// Public function for removing a trusted contact
+/// See `_remove_contact` for more info
+///
+pub fn remove_contact(u: &User, contact: &str) -> Result<(), AuthError> {
+    maintenance::check_writable()?;
+    let repository = SQliteTrustedContactRepository::new();
+    let audit_repository = SQliteAuditRepository::new();
+    _remove_contact(u, contact, &repository, &audit_repository)
+}
+
+/// Public function for starting a recovery
+/// See `_start_recovery` for more info
+///
+pub fn start_recovery(email: &str) -> Result<String, AuthError> {
+    maintenance::check_writable()?;
+    let repository = SQliteUserRepository::new();
+    let contacts_repository = SQliteTrustedContactRepository::new();
+    let audit_repository = SQliteAuditRepository::new();
+    _start_recovery(
+        email,
+        &repository,
+        &contacts_repository,
+        &audit_repository,
+        mail::default_mailer(),
+        &config::get().contact_recovery,
+        action::secret().ok().as_deref(),
+        Utc::now(),
+    )
+}
+
+/// Public function for taking over a recovery with the link of the alert
+/// See `_take_over_recovery` for more info
+///
+pub fn take_over_recovery(token: &str) -> Result<String, AuthError> {
+    maintenance::check_writable()?;
+    let link = action::redeem(token, TAKEOVER_PURPOSE)?;
+    let repository = SQliteUserRepository::new();
+    let contacts_repository = SQliteTrustedContactRepository::new();
+    let audit_repository = SQliteAuditRepository::new();
+    _take_over_recovery(
+        link.user,
+        &repository,
+        &contacts_repository,
+        &audit_repository,
+        mail::default_mailer(),
+        &config::get().contact_recovery,
+        Some(&action::secret()?),
+        Utc::now(),
+    )
+}
+
+/// Public function for the approval of a recovery by a trusted contact
+/// See `_approve_recovery` for more info
+///
+pub fn approve_recovery(token: &str) -> Result<(), AuthError> {
+    maintenance::check_writable()?;
+    let contacts_repository = SQliteTrustedContactRepository::new();
+    let audit_repository = SQliteAuditRepository::new();
+    _approve_recovery(token, &contacts_repository, &audit_repository, Utc::now())
+}
+
+/// Public function for the progress of a recovery
+/// See `_recovery_status` for more info
+///
+pub fn recovery_status(u: &User) -> Result<RecoveryStatus, AuthError> {
+    let contacts_repository = SQliteTrustedContactRepository::new();
+    _recovery_status(u, &contacts_repository, &config::get().contact_recovery)
+}
+
+/// Public function for cancelling a recovery
+/// See `_cancel_recovery` for more info
+///
+pub fn cancel_recovery(u: &User) -> Result<(), AuthError> {
+    maintenance::check_writable()?;
+    let contacts_repository = SQliteTrustedContactRepository::new();
+    let audit_repository = SQliteAuditRepository::new();
+    _cancel_recovery(u, &contacts_repository, &audit_repository)
+}
+
+/// Public function for completing a recovery
+/// See `_complete_recovery` for more info
+///
+pub fn complete_recovery(
+    email: &str,
+    secret: &str,
+    new_passwd: &Password,
+) -> Result<(), AuthError> {
+    maintenance::check_writable()?;
+    let repository = SQliteUserRepository::new();
+    let contacts_repository = SQliteTrustedContactRepository::new();
+    let audit_repository = SQliteAuditRepository::new();
+    _complete_recovery(
+        email,
+        secret,
+        new_passwd.as_str(),
+        &repository,
+        &contacts_repository,
+        &audit_repository,
+        &config::get().contact_recovery,
+        Utc::now(),
+    )?;
+    // Note: a reset token requested before can't replace the new password
+    let _ = reset::revoke_reset_token(email, &*ephemeral::durable());
+    stats::record_password_score(PasswordContext::Change, new_passwd.as_str(), email);
+
+    Ok(())
+}
+
+/// Get the emails of the trusted contacts of a user
+///
+/// # Arguments
+///
+/// * `u` - the user
+///
+/// * `repository` - the trusted contact repository to interact with
+///
+fn _list_contacts(
+    u: &User,
+    repository: &dyn TrustedContactRepository,
+) -> Result<Vec<String>, AuthError> {
+    repository
+        .list_contacts(u.get_id())
+        .map_err(|_| AuthError::ContactRecoveryError)
+}
+
+/// Get the emails of the trusted contacts of a user who confirmed they accept the role,
+/// only them are asked to approve a recovery
+///
+/// # Arguments
+///
+/// * `u` - the user
+///
+/// * `repository` - the trusted contact repository to interact with
+///
+fn _list_confirmed_contacts(
+    u: &User,
+    repository: &dyn TrustedContactRepository,
+) -> Result<Vec<String>, AuthError> {
+    repository
+        .list_confirmed_contacts(u.get_id())
+        .map_err(|_| AuthError::ContactRecoveryError)
+}
+
+/// Add a trusted contact to a user & ask her/him to confirm she/he accepts the role
+/// Adding an existing one asks her/him again, if she/he didn't confirm yet (e.g. the
+/// email was lost), the link sent before stops working
+///
+/// # Arguments
+///
+/// * `u` - the user
+///
+/// * `contact` - the email of the contact
+///
+/// * `repository` - the trusted contact repository to interact with
+///
+/// * `audit_repository` - the audit repository to write in
+///
+/// * `mailer` - the mailer used to send the confirmation request
+///
+fn _add_contact(
+    u: &User,
+    contact: &str,
+    repository: &dyn TrustedContactRepository,
+    audit_repository: &dyn AuditRepository,
+    mailer: &dyn Mailer,
+) -> Result<(), AuthError> {
+    if !validation::is_email_valid(contact) {
+        return Err(AuthError::InvalidEmail);
+    }
+    if contact.eq_ignore_ascii_case(&u.get_email()) {
+        return Err(AuthError::InvalidContact);
+    }
+
+    let contacts = _list_contacts(u, repository)?;
+    if !contacts.iter().any(|c| c == contact) {
+        if contacts.len() >= MAX_CONTACTS {
+            return Err(AuthError::TooManyContacts);
+        }
+
+        repository
+            .add_contact(u.get_id(), contact)
+            .map_err(|_| AuthError::ContactRecoveryError)?;
+
+        let _ = audit::record(
+            audit_repository,
+            Some(u.get_id()),
+            AuditEvent::TrustedContactAdded,
+            Some(contact.to_string()),
+        );
+    }
+
+    let token = utils::gen_token();
+    let asked = repository
+        .request_confirmation(u.get_id(), contact, &hash_token(&token))
+        .map_err(|_| AuthError::ContactRecoveryError)?;
+    if asked {
+        let template = Template::ContactConfirmation {
+            account: u.get_email(),
+            token,
+        };
+        mailer
+            .send(&templates::render_to(&template, contact))
+            .map_err(|_| AuthError::ContactRecoveryError)?;
+    }
+
+    Ok(())
+}
+
+/// Confirm a trusted contact with the token she/he received, a token can only be used once
+///
+/// # Arguments
+///
+/// * `token` - the confirmation token
+///
+/// * `repository` - the trusted contact repository to interact with
+///
+/// * `audit_repository` - the audit repository to write in
+///
+/// * `now` - the current date & time
+///
+fn _confirm_contact(
+    token: &str,
+    repository: &dyn TrustedContactRepository,
+    audit_repository: &dyn AuditRepository,
+    now: DateTime<Utc>,
+) -> Result<(), AuthError> {
+    utils::check_length(token, MAX_TOKEN_BYTES)?;
+    let confirmed = repository
+        .confirm_contact(&hash_token(token), now)
+        .map_err(|_| AuthError::ContactRecoveryError)?;
+    if !confirmed {
+        return Err(AuthError::InvalidContactConfirmation);
+    }
+
+    let _ = audit::record(
+        audit_repository,
+        None,
+        AuditEvent::TrustedContactConfirmed,
+        None,
+    );
+
+    Ok(())
+}
+
+/// Remove a trusted contact of a user
+///
+/// # Arguments
+///
+/// * `u` - the user
+///
+/// * `contact` - the email of the contact
+///
+/// * `repository` - the trusted contact repository to interact with
+///
+/// * `audit_repository` - the audit repository to write in
+///
+fn _remove_contact(
+    u: &User,
+    contact: &str,
+    repository: &dyn TrustedContactRepository,
+    audit_repository: &dyn AuditRepository,
+) -> Result<(), AuthError> {
+    repository
+        .remove_contact(u.get_id(), contact)
+        .map_err(|_| AuthError::ContactRecoveryError)?;
+
+    let _ = audit::record(
+        audit_repository,
+        Some(u.get_id()),
+        AuditEvent::TrustedContactRemoved,
+        Some(contact.to_string()),
+    );
+
+    Ok(())
+}
+
+/// Start the recovery of an account & get the secret asked to complete it
+/// Each confirmed trusted contact gets a link to approve it & the user gets an alert
+///
+/// # Note
+/// Nothing happens if the account doesn't exist, has no confirmed trusted contact or a
+/// recovery is already in progress (the approvals already given are kept), a secret is
+/// returned anyway, this is done to not leak the info to the person starting the recovery.
+/// An expired recovery is replaced.
+/// The accounts without a local password (service & directory ones) have nothing to recover.
+///
+/// # Arguments
+///
+/// * `email` - the email of the account to recover
+///
+/// * `repository` - the user repository to interact with
+///
+/// * `contacts_repository` - the trusted contact repository to interact with
+///
+/// * `audit_repository` - the audit repository to write in
+///
+/// * `mailer` - the mailer used to send the emails
+///
+/// * `policy` - the waiting period & the validity of the recoveries
+///
+/// * `secret` - the key signing the link of the alert, it's sent without it if `None`
+///
+/// * `now` - the current date & time
+///
+#[allow(clippy::too_many_arguments)]
+fn _start_recovery(
+    email: &str,
+    repository: &dyn UserRepository,
+    contacts_repository: &dyn TrustedContactRepository,
+    audit_repository: &dyn AuditRepository,
+    mailer: &dyn Mailer,
+    policy: &ContactRecoveryConfig,
+    secret: Option<&str>,
+    now: DateTime<Utc>,
+) -> Result<String, AuthError> {
+    let decoy = utils::gen_token();
+    let u = match repository.get_user(email) {
+        Ok(u) if u.has_local_password() => u,
+        _ => return Ok(decoy),
+    };
+    match _recovery_status(&u, contacts_repository, policy) {
+        Ok(status) if now < status.expires_at => return Ok(decoy),
+        Ok(_) | Err(AuthError::NoRecovery) => {}
+        Err(e) => return Err(e),
+    }
+
+    let opened = open_recovery(
+        &u,
+        repository,
+        contacts_repository,
+        audit_repository,
+        mailer,
+        policy,
+        secret,
+        now,
+    )?;
+
+    Ok(opened.unwrap_or(decoy))
+}
+
+/// Take over the recovery of an account with the link of the alert sent to its owner:
+/// the recovery in progress (if any) is replaced by a new one & its secret returned
+/// The approvals given to the previous one are lost, the contacts are asked again
+///
+/// # Arguments
+///
+/// * `user` - id of the user who followed the link
+///
+/// * `repository` - the user repository to interact with
+///
+/// * `contacts_repository` - the trusted contact repository to interact with
+///
+/// * `audit_repository` - the audit repository to write in
+///
+/// * `mailer` - the mailer used to send the emails
+///
+/// * `policy` - the waiting period & the validity of the recoveries
+///
+/// * `secret` - the key signing the link of the new alert, it's sent without it if `None`
+///
+/// * `now` - the current date & time
+///
+#[allow(clippy::too_many_arguments)]
+fn _take_over_recovery(
+    user: i32,
+    repository: &dyn UserRepository,
+    contacts_repository: &dyn TrustedContactRepository,
+    audit_repository: &dyn AuditRepository,
+    mailer: &dyn Mailer,
+    policy: &ContactRecoveryConfig,
+    secret: Option<&str>,
+    now: DateTime<Utc>,
+) -> Result<String, AuthError> {
+    // Note: the account may have been deleted since the alert was sent
+    let u = repository
+        .list_users(&UserFilter::new().with_id(user))
+        .map_err(|_| AuthError::ContactRecoveryError)?
+        .into_iter()
+        .next()
+        .ok_or(AuthError::InvalidActionLink)?;
+
+    contacts_repository
+        .clear_recovery(u.get_id())
+        .map_err(|_| AuthError::ContactRecoveryError)?;
+    let _ = audit::record(
+        audit_repository,
+        Some(u.get_id()),
+        AuditEvent::ContactRecoveryTakenOver,
+        None,
+    );
+
+    open_recovery(
+        &u,
+        repository,
+        contacts_repository,
+        audit_repository,
+        mailer,
+        policy,
+        secret,
+        now,
+    )?
+    .ok_or(AuthError::NoRecovery)
+}
+
+/// Open a recovery of an account & get its secret, the one in progress (if any) is replaced
+/// Returns `None` if the account has no confirmed trusted contact
+///
+/// # Arguments
+///
+/// * `u` - the user whose account is recovered
+///
+/// * `repository` - the user repository to interact with
+///
+/// * `contacts_repository` - the trusted contact repository to interact with
+///
+/// * `audit_repository` - the audit repository to write in
+///
+/// * `mailer` - the mailer used to send the emails
+///
+/// * `policy` - the waiting period & the validity of the recoveries
+///
+/// * `secret` - the key signing the link of the alert, it's sent without it if `None`
+///
+/// * `now` - the current date & time
+///
+#[allow(clippy::too_many_arguments)]
+fn open_recovery(
+    u: &User,
+    repository: &dyn UserRepository,
+    contacts_repository: &dyn TrustedContactRepository,
+    audit_repository: &dyn AuditRepository,
+    mailer: &dyn Mailer,
+    policy: &ContactRecoveryConfig,
+    secret: Option<&str>,
+    now: DateTime<Utc>,
+) -> Result<Option<String>, AuthError> {
+    let contacts = _list_confirmed_contacts(u, contacts_repository)?;
+    if contacts.is_empty() {
+        return Ok(None);
+    }
+
+    let recovery_secret = utils::gen_token();
+    let tokens: Vec<(String, String)> = contacts
+        .into_iter()
+        .map(|c| (c, utils::gen_token()))
+        .collect();
+    let approvals: Vec<(String, String)> = tokens
+        .iter()
+        .map(|(c, token)| (c.clone(), hash_token(token)))
+        .collect();
+    repository
+        .set_attribute(
+            u.get_id(),
+            RECOVERY_SECRET_ATTRIBUTE,
+            &hash_token(&recovery_secret),
+        )
+        .map_err(|_| AuthError::ContactRecoveryError)?;
+    contacts_repository
+        .start_recovery(u.get_id(), &approvals, now)
+        .map_err(|_| AuthError::ContactRecoveryError)?;
+
+    for (contact, token) in tokens {
+        let template = Template::ContactApproval {
+            account: u.get_email(),
+            token,
+        };
+        mailer
+            .send(&templates::render_to(&template, &contact))
+            .map_err(|_| AuthError::ContactRecoveryError)?;
+    }
+    // Note: the link expires with the recovery, there's nothing left to take over after
+    let takeover = secret.and_then(|secret| {
+        let expires_at = now + Duration::hours(policy.validity_hours);
+        action::_issue(TAKEOVER_PURPOSE, u.get_id(), expires_at, secret).ok()
+    });
+    let alert = Template::RecoveryStarted {
+        waiting_hours: policy.waiting_period_hours,
+        takeover,
+    };
+    mailer
+        .send(&templates::render(&alert, u))
+        .map_err(|_| AuthError::ContactRecoveryError)?;
+
+    let _ = audit::record(
+        audit_repository,
+        Some(u.get_id()),
+        AuditEvent::ContactRecoveryStarted,
+        Some(format!("{} contact(s) asked", approvals.len())),
+    );
+
+    Ok(Some(recovery_secret))
+}
+
+/// Approve a recovery with the token a trusted contact received, a token can only be used once
+///
+/// # Arguments
+///
+/// * `token` - the approval token
+///
+/// * `contacts_repository` - the trusted contact repository to interact with
+///
+/// * `audit_repository` - the audit repository to write in
+///
+/// * `now` - the current date & time
+///
+fn _approve_recovery(
+    token: &str,
+    contacts_repository: &dyn TrustedContactRepository,
+    audit_repository: &dyn AuditRepository,
+    now: DateTime<Utc>,
+) -> Result<(), AuthError> {
+    utils::check_length(token, MAX_TOKEN_BYTES)?;
+    let approved = contacts_repository
+        .approve(&hash_token(token), now)
+        .map_err(|_| AuthError::ContactRecoveryError)?;
+    if !approved {
+        return Err(AuthError::InvalidApproval);
+    }
+
+    let _ = audit::record(
+        audit_repository,
+        None,
+        AuditEvent::ContactRecoveryApproved,
+        None,
+    );
+
+    Ok(())
+}
+
+/// Get the progress of the recovery a user started
+///
+/// # Arguments
+///
+/// * `u` - the user
+///
+/// * `contacts_repository` - the trusted contact repository to interact with
+///
+/// * `policy` - the waiting period & the validity of the recoveries
+///
+fn _recovery_status(
+    u: &User,
+    contacts_repository: &dyn TrustedContactRepository,
+    policy: &ContactRecoveryConfig,
+) -> Result<RecoveryStatus, AuthError> {
+    let approvals = contacts_repository
+        .get_recovery(u.get_id())
+        .map_err(|_| AuthError::ContactRecoveryError)?;
+    let requested_at = approvals
+        .first()
+        .ok_or(AuthError::NoRecovery)
+        .and_then(|a| {
+            DateTime::parse_from_rfc3339(&a.requested_at)
+                .map_err(|_| AuthError::ContactRecoveryError)
+        })?
+        .with_timezone(&Utc);
+
+    Ok(RecoveryStatus {
+        approved: approvals.iter().filter(|a| a.approved_at.is_some()).count(),
+        required: approvals.len(),
+        ready_at: requested_at + Duration::hours(policy.waiting_period_hours),
+        expires_at: requested_at + Duration::hours(policy.validity_hours),
+    })
+}
+
+/// Cancel the recovery a user started (or someone else started for her/him)
+///
+/// # Arguments
+///
+/// * `u` - the user
+///
+/// * `contacts_repository` - the trusted contact repository to interact with
+///
+/// * `audit_repository` - the audit repository to write in
+///
+fn _cancel_recovery(
+    u: &User,
+    contacts_repository: &dyn TrustedContactRepository,
+    audit_repository: &dyn AuditRepository,
+) -> Result<(), AuthError> {
+    contacts_repository
+        .clear_recovery(u.get_id())
+        .map_err(|_| AuthError::ContactRecoveryError)?;
+
+    let _ = audit::record(
+        audit_repository,
+        Some(u.get_id()),
+        AuditEvent::ContactRecoveryCancelled,
+        None,
+    );
+
+    Ok(())
+}
+
+/// Complete a recovery approved by all the trusted contacts once the waiting period is over
+/// The password is replaced, the 2fa disabled & the security hold (if any) released
+///
+/// # Arguments
+///
+/// * `email` - the email of the account to recover
+///
+/// * `secret` - the secret given when the recovery was started
+///
+/// * `new_passwd` - the new password, it must respect the password policy
+///
+/// * `repository` - the user repository to interact with
+///
+/// * `contacts_repository` - the trusted contact repository to interact with
+///
+/// * `audit_repository` - the audit repository to write in
+///
+/// * `policy` - the waiting period & the validity of the recoveries
+///
+/// * `now` - the current date & time
+///
+#[allow(clippy::too_many_arguments)]
+fn _complete_recovery(
+    email: &str,
+    secret: &str,
+    new_passwd: &str,
+    repository: &dyn UserRepository,
+    contacts_repository: &dyn TrustedContactRepository,
+    audit_repository: &dyn AuditRepository,
+    policy: &ContactRecoveryConfig,
+    now: DateTime<Utc>,
+) -> Result<(), AuthError> {
+    utils::check_length(secret, MAX_TOKEN_BYTES)?;
+    utils::check_length(new_passwd, MAX_PASSWORD_BYTES)?;
+    if !validation::is_password_valid(new_passwd) {
+        return Err(AuthError::InvalidPassword);
+    }
+    let u = repository
+        .get_user(email)
+        .map_err(|_| AuthError::NoRecovery)?;
+    let stored = repository
+        .get_attributes(u.get_id())
+        .map_err(|_| AuthError::ContactRecoveryError)?
+        .remove(RECOVERY_SECRET_ATTRIBUTE)
+        .ok_or(AuthError::NoRecovery)?;
+    if stored != hash_token(secret) {
+        return Err(AuthError::InvalidRecoverySecret);
+    }
+    let status = _recovery_status(&u, contacts_repository, policy)?;
+
+    if now >= status.expires_at {
+        let _ = contacts_repository.clear_recovery(u.get_id());
+        return Err(AuthError::RecoveryExpired);
+    }
+    if status.approved < status.required {
+        return Err(AuthError::RecoveryNotApproved);
+    }
+    if now < status.ready_at {
+        return Err(AuthError::RecoveryPending);
+    }
+
+    // Note: hashed first, so a failure doesn't use up the recovery
+    let pwh = utils::hash(new_passwd).ok_or(AuthError::ContactRecoveryError)?;
+
+    // Note: the recovery can only be completed once
+    contacts_repository
+        .clear_recovery(u.get_id())
+        .map_err(|_| AuthError::ContactRecoveryError)?;
+    let _ = repository.remove_attribute(u.get_id(), RECOVERY_SECRET_ATTRIBUTE);
+
+    let changes = UserChangeset::new().password(&pwh).secret_2fa(None);
+    repository
+        .patch_user(u.get_id(), &changes)
+        .map_err(|_| AuthError::ContactRecoveryError)?;
+
+    match hold::_release_hold(
+        email,
+        Release::ContactRecovery,
+        repository,
+        audit_repository,
+    ) {
+        Ok(()) | Err(AuthError::NotOnHold) => {}
+        Err(e) => return Err(e),
+    }
+
+    let _ = audit::record(
+        audit_repository,
+        Some(u.get_id()),
+        AuditEvent::ContactRecoveryCompleted,
+        None,
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::db::models::ContactRecovery;
+    use crate::db::repository::{
+        MockSQliteAuditRepository, MockSQliteTrustedContactRepository, MockSQliteUserRepository,
+    };
+    use crate::mail::capture::CapturingMailer;
+    use crate::mail::MockConsoleMailer;
+    use std::collections::HashMap;
+
+    const SECRET: &str = "0123456789abcdef0123456789abcdef";
+
+    fn user() -> User {
+        User::new("email@email.test", "passwd_hash")
+    }
+
+    fn audit_mock() -> MockSQliteAuditRepository {
+        let mut mock = MockSQliteAuditRepository::new();
+        mock.expect_create_entry().returning(|_, _, _| Ok(()));
+        mock
+    }
+
+    /// Attributes of a user who started a recovery with the secret "secret"
+    fn secret_attributes() -> HashMap<String, String> {
+        vec![(RECOVERY_SECRET_ATTRIBUTE.to_string(), hash_token("secret"))]
+            .into_iter()
+            .collect()
+    }
+
+    /// Repository of the trusted contacts of a user, all of them confirmed
+    fn contacts_mock(contacts: &[&str]) -> MockSQliteTrustedContactRepository {
+        let contacts: Vec<String> = contacts.iter().map(|c| c.to_string()).collect();
+        let confirmed = contacts.clone();
+        let mut mock = MockSQliteTrustedContactRepository::new();
+        mock.expect_list_contacts()
+            .returning(move |_| Ok(contacts.clone()));
+        mock.expect_list_confirmed_contacts()
+            .returning(move |_| Ok(confirmed.clone()));
+        mock
+    }
+
+    /// Approvals of a recovery requested at `requested_at`, the first `approved` ones approved
+    fn recovery(
+        requested_at: DateTime<Utc>,
+        approved: usize,
+        required: usize,
+    ) -> Vec<ContactRecovery> {
+        (0..required)
+            .map(|i| ContactRecovery {
+                id: i as i32,
+                user_id: 1,
+                contact_email: format!("{}@email.test", i),
+                token_hash: i.to_string(),
+                requested_at: requested_at.to_rfc3339(),
+                approved_at: if i < approved {
+                    Some(requested_at.to_rfc3339())
+                } else {
+                    None
+                },
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_hash_token_vector() {
+        // SHA-256 of "abc" (FIPS 180-2), the surrounding blanks are ignored
+        assert_eq!(
+            hash_token(" abc\n"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn test_add_contact() {
+        let mut repository = contacts_mock(&["a@email.test"]);
+        let mailer = CapturingMailer::new();
+        repository
+            .expect_add_contact()
+            .withf(|u, c| *u == 1 && c == "b@email.test")
+            .times(1)
+            .returning(|_, _| Ok(()));
+        repository
+            .expect_request_confirmation()
+            .withf(|u, c, hash| *u == 1 && c == "b@email.test" && hash.len() == 64)
+            .times(1)
+            .returning(|_, _, _| Ok(true));
+        // already a confirmed contact
+        repository
+            .expect_request_confirmation()
+            .withf(|_, c, _| c == "a@email.test")
+            .returning(|_, _, _| Ok(false));
+
+        assert_eq!(
+            _add_contact(&user(), "b@email.test", &repository, &audit_mock(), &mailer),
+            Ok(())
+        );
+        let request = mailer.emails_to("b@email.test");
+        assert_eq!(request.len(), 1);
+        assert!(request[0].body.starts_with("email@email.test chose you"));
+
+        assert_eq!(
+            _add_contact(&user(), "a@email.test", &repository, &audit_mock(), &mailer),
+            Ok(())
+        );
+        assert!(mailer.emails_to("a@email.test").is_empty());
+        assert_eq!(
+            _add_contact(
+                &user(),
+                "EMAIL@email.test",
+                &repository,
+                &audit_mock(),
+                &mailer
+            ),
+            Err(AuthError::InvalidContact)
+        );
+        assert_eq!(
+            _add_contact(&user(), "not an email", &repository, &audit_mock(), &mailer),
+            Err(AuthError::InvalidEmail)
+        );
+    }
+
+    #[test]
+    fn test_add_contact_asks_again_until_confirmed() {
+        let mut repository = contacts_mock(&["a@email.test"]);
+        let mailer = CapturingMailer::new();
+        repository.expect_add_contact().times(0);
+        repository
+            .expect_request_confirmation()
+            .times(1)
+            .returning(|_, _, _| Ok(true));
+
+        assert_eq!(
+            _add_contact(&user(), "a@email.test", &repository, &audit_mock(), &mailer),
+            Ok(())
+        );
+        assert!(mailer.last_token_for("a@email.test").is_some());
+    }
+
+    #[test]
+    fn test_add_too_many_contacts() {
+        let mut repository = contacts_mock(&["a@email.test", "b@email.test"]);
+        let mut mailer = MockConsoleMailer::new();
+        repository.expect_add_contact().times(0);
+        mailer.expect_send().times(0);
+
+        assert_eq!(
+            _add_contact(&user(), "c@email.test", &repository, &audit_mock(), &mailer),
+            Err(AuthError::TooManyContacts)
+        );
+    }
+
+    #[test]
+    fn test_confirm_contact() {
+        let mut repository = MockSQliteTrustedContactRepository::new();
+        let hash = hash_token("token");
+
+        repository
+            .expect_confirm_contact()
+            .returning(move |h, _| Ok(h == hash));
+
+        assert_eq!(
+            _confirm_contact(" token ", &repository, &audit_mock(), Utc::now()),
+            Ok(())
+        );
+        assert_eq!(
+            _confirm_contact("other", &repository, &audit_mock(), Utc::now()),
+            Err(AuthError::InvalidContactConfirmation)
+        );
+    }
+
+    #[test]
+    fn test_start_recovery() {
+        let mut repository = MockSQliteUserRepository::new();
+        let mut contacts_repository = contacts_mock(&["a@email.test", "b@email.test"]);
+        let mut mailer = MockConsoleMailer::new();
+        let now = Utc::now();
+
+        repository.expect_get_user().returning(|_| Ok(user()));
+        repository
+            .expect_set_attribute()
+            .withf(|u, k, v| *u == 1 && k == RECOVERY_SECRET_ATTRIBUTE && v.len() == 64)
+            .times(1)
+            .returning(|_, _, _| Ok(()));
+        contacts_repository
+            .expect_get_recovery()
+            .returning(|_| Ok(vec![]));
+        contacts_repository
+            .expect_start_recovery()
+            .withf(move |u, approvals, at| {
+                *u == 1
+                    && approvals.len() == 2
+                    && approvals[0].0 == "a@email.test"
+                    && approvals[0].1 != approvals[1].1
+                    && *at == now
+            })
+            .times(1)
+            .returning(|_, _, _| Ok(()));
+        // one approval request per contact & an alert to the user
+        mailer
+            .expect_send()
+            .withf(|e| e.to != "email@email.test" && e.body.contains("approve"))
+            .times(2)
+            .returning(|_| Ok(()));
+        mailer
+            .expect_send()
+            .withf(|e| {
+                e.to == "email@email.test"
+                    && e.body.contains("72 hours")
+                    && e.body.contains("secure-auth recovery take-over ")
+            })
+            .times(1)
+            .returning(|_| Ok(()));
+
+        let res = _start_recovery(
+            "email@email.test",
+            &repository,
+            &contacts_repository,
+            &audit_mock(),
+            &mailer,
+            &ContactRecoveryConfig::default(),
+            Some(SECRET),
+            now,
+        );
+
+        assert!(res.is_ok());
+    }
+
+    #[test]
+    fn test_start_recovery_keeps_the_one_in_progress() {
+        let mut repository = MockSQliteUserRepository::new();
+        let mut contacts_repository = contacts_mock(&["a@email.test"]);
+        let mut mailer = MockConsoleMailer::new();
+        let now = Utc::now();
+
+        repository.expect_get_user().returning(|_| Ok(user()));
+        repository.expect_set_attribute().times(0);
+        contacts_repository
+            .expect_get_recovery()
+            .returning(move |_| Ok(recovery(now - Duration::hours(1), 1, 1)));
+        contacts_repository.expect_start_recovery().times(0);
+        mailer.expect_send().times(0);
+
+        // e.g. someone else starting a recovery doesn't reset the approvals
+        let res = _start_recovery(
+            "email@email.test",
+            &repository,
+            &contacts_repository,
+            &audit_mock(),
+            &mailer,
+            &ContactRecoveryConfig::default(),
+            None,
+            now,
+        );
+
+        assert!(res.is_ok());
+    }
+
+    #[test]
+    fn test_start_recovery_without_confirmed_contacts() {
+        let mut repository = MockSQliteUserRepository::new();
+        let mut contacts_repository = MockSQliteTrustedContactRepository::new();
+        let mut mailer = MockConsoleMailer::new();
+
+        repository.expect_get_user().returning(|_| Ok(user()));
+        repository.expect_set_attribute().times(0);
+        contacts_repository
+            .expect_get_recovery()
+            .returning(|_| Ok(vec![]));
+        // e.g. the contacts didn't accept the role yet, they can't approve a recovery
+        contacts_repository
+            .expect_list_confirmed_contacts()
+            .returning(|_| Ok(vec![]));
+        contacts_repository.expect_start_recovery().times(0);
+        mailer.expect_send().times(0);
+
+        // a secret is given anyway, to not leak that the account has no contact
+        let res = _start_recovery(
+            "email@email.test",
+            &repository,
+            &contacts_repository,
+            &audit_mock(),
+            &mailer,
+            &ContactRecoveryConfig::default(),
+            Some(SECRET),
+            Utc::now(),
+        );
+
+        assert!(res.is_ok());
+    }
+
+    #[test]
+    fn test_take_over_recovery() {
+        let mut repository = MockSQliteUserRepository::new();
+        let mut contacts_repository = contacts_mock(&["a@email.test"]);
+        let mailer = CapturingMailer::new();
+        let now = Utc::now();
+
+        repository
+            .expect_list_users()
+            .withf(|f| *f == UserFilter::new().with_id(1))
+            .returning(|_| Ok(vec![user()]));
+        repository
+            .expect_set_attribute()
+            .withf(|u, k, _| *u == 1 && k == RECOVERY_SECRET_ATTRIBUTE)
+            .times(1)
+            .returning(|_, _, _| Ok(()));
+        // the recovery in progress is dropped with its approvals, the contacts are asked again
+        contacts_repository
+            .expect_clear_recovery()
+            .withf(|u| *u == 1)
+            .times(1)
+            .returning(|_| Ok(()));
+        contacts_repository
+            .expect_start_recovery()
+            .withf(|u, approvals, _| *u == 1 && approvals.len() == 1)
+            .times(1)
+            .returning(|_, _, _| Ok(()));
+
+        let secret = _take_over_recovery(
+            1,
+            &repository,
+            &contacts_repository,
+            &audit_mock(),
+            &mailer,
+            &ContactRecoveryConfig::default(),
+            Some(SECRET),
+            now,
+        )
+        .unwrap();
+
+        assert_eq!(secret.len(), token::TOKEN_LEN);
+        assert!(mailer.last_token_for("a@email.test").is_some());
+        // the new alert has a link too, valid as long as the recovery
+        let takeover = mailer.last_token_for("email@email.test").unwrap();
+        let link = action::_check(&takeover, TAKEOVER_PURPOSE, SECRET, now).unwrap();
+        assert_eq!(link.user, 1);
+        assert_eq!(
+            link.expires_at.timestamp(),
+            (now + Duration::hours(ContactRecoveryConfig::default().validity_hours)).timestamp()
+        );
+    }
+
+    #[test]
+    fn test_take_over_recovery_of_a_deleted_account() {
+        let mut repository = MockSQliteUserRepository::new();
+        let mut contacts_repository = MockSQliteTrustedContactRepository::new();
+
+        repository.expect_list_users().returning(|_| Ok(vec![]));
+        contacts_repository.expect_clear_recovery().times(0);
+
+        assert_eq!(
+            _take_over_recovery(
+                1,
+                &repository,
+                &contacts_repository,
+                &audit_mock(),
+                &CapturingMailer::new(),
+                &ContactRecoveryConfig::default(),
+                Some(SECRET),
+                Utc::now(),
+            ),
+            Err(AuthError::InvalidActionLink)
+        );
+    }
+
+    #[test]
+    fn test_approve_recovery() {
+        let mut contacts_repository = MockSQliteTrustedContactRepository::new();
+        let hash = hash_token("token");
+
+        contacts_repository
+            .expect_approve()
+            .returning(move |h, _| Ok(h == hash));
+
+        assert_eq!(
+            _approve_recovery(" token ", &contacts_repository, &audit_mock(), Utc::now()),
+            Ok(())
+        );
+        assert_eq!(
+            _approve_recovery("other", &contacts_repository, &audit_mock(), Utc::now()),
+            Err(AuthError::InvalidApproval)
+        );
+    }
+
+    #[rstest::rstest(
+        hours_ago,
+        approved,
+        expected,
+        case(1, 2, Err(AuthError::RecoveryPending)),
+        case(80, 1, Err(AuthError::RecoveryNotApproved)),
+        case(200, 2, Err(AuthError::RecoveryExpired)),
+        ::trace
+    )]
+    fn test_complete_recovery_is_refused(
+        hours_ago: i64,
+        approved: usize,
+        expected: Result<(), AuthError>,
+    ) {
+        let mut repository = MockSQliteUserRepository::new();
+        let mut contacts_repository = MockSQliteTrustedContactRepository::new();
+        let now = Utc::now();
+
+        repository.expect_get_user().returning(|_| Ok(user()));
+        repository
+            .expect_get_attributes()
+            .returning(|_| Ok(secret_attributes()));
+        repository.expect_patch_user().times(0);
+        contacts_repository
+            .expect_get_recovery()
+            .returning(move |_| Ok(recovery(now - Duration::hours(hours_ago), approved, 2)));
+        contacts_repository
+            .expect_clear_recovery()
+            .returning(|_| Ok(()));
+
+        let res = _complete_recovery(
+            "email@email.test",
+            "secret",
+            "new password",
+            &repository,
+            &contacts_repository,
+            &audit_mock(),
+            &ContactRecoveryConfig::default(),
+            now,
+        );
+
+        assert_eq!(res, expected);
+    }
+
+    #[test]
+    fn test_complete_recovery() {
+        let mut repository = MockSQliteUserRepository::new();
+        let mut contacts_repository = MockSQliteTrustedContactRepository::new();
+        let now = Utc::now();
+
+        repository.expect_get_user().returning(|_| {
+            let mut u = user();
+            u.set_secret_2fa(Some("secret".to_string()));
+            Ok(u)
+        });
+        repository
+            .expect_patch_user()
+            .withf(|u, c| *u == 1 && format!("{:?}", c).contains("secret_2fa: Some(None)"))
+            .times(1)
+            .returning(|_, _| Ok(()));
+        repository
+            .expect_get_attributes()
+            .returning(|_| Ok(secret_attributes()));
+        repository
+            .expect_remove_attribute()
+            .withf(|u, k| *u == 1 && k == RECOVERY_SECRET_ATTRIBUTE)
+            .times(1)
+            .returning(|_, _| Ok(()));
+        contacts_repository
+            .expect_get_recovery()
+            .returning(move |_| Ok(recovery(now - Duration::hours(80), 2, 2)));
+        contacts_repository
+            .expect_clear_recovery()
+            .times(1)
+            .returning(|_| Ok(()));
+
+        let res = _complete_recovery(
+            "email@email.test",
+            "secret",
+            "new password",
+            &repository,
+            &contacts_repository,
+            &audit_mock(),
+            &ContactRecoveryConfig::default(),
+            now,
+        );
+
+        assert_eq!(res, Ok(()));
+    }
+
+    #[rstest::rstest(
+        secret,
+        passwd,
+        expected,
+        case("other", "new password", Err(AuthError::InvalidRecoverySecret)),
+        case("secret", "short", Err(AuthError::InvalidPassword)),
+        ::trace
+    )]
+    fn test_complete_recovery_checks_secret_and_password(
+        secret: &str,
+        passwd: &str,
+        expected: Result<(), AuthError>,
+    ) {
+        let mut repository = MockSQliteUserRepository::new();
+        let mut contacts_repository = MockSQliteTrustedContactRepository::new();
+        let now = Utc::now();
+
+        repository.expect_get_user().returning(|_| Ok(user()));
+        repository
+            .expect_get_attributes()
+            .returning(|_| Ok(secret_attributes()));
+        repository.expect_patch_user().times(0);
+        contacts_repository
+            .expect_get_recovery()
+            .returning(move |_| Ok(recovery(now - Duration::hours(80), 2, 2)));
+        contacts_repository.expect_clear_recovery().times(0);
+
+        let res = _complete_recovery(
+            "email@email.test",
+            secret,
+            passwd,
+            &repository,
+            &contacts_repository,
+            &audit_mock(),
+            &ContactRecoveryConfig::default(),
+            now,
+        );
+
+        assert_eq!(res, expected);
+    }
+
+    #[test]
+    fn test_recovery_status_without_recovery() {
+        let mut contacts_repository = MockSQliteTrustedContactRepository::new();
+        contacts_repository
+            .expect_get_recovery()
+            .returning(|_| Ok(vec![]));
+
+        assert_eq!(
+            _recovery_status(
+                &user(),
+                &contacts_repository,
+                &ContactRecoveryConfig::default()
+            ),
+            Err(AuthError::NoRecovery)
+        );
+    }
+}
@@ -0,0 +1,282 @@
+/*!
+ * Erasure of an account, on the request of its user (right to be forgotten)
+ *
+ * The account is deleted with everything linked to it (attributes, recovery
+ * codes, trusted contacts, contact recoveries) along with the notifications
+ * sent to its email. The audit history is kept, detached from the account
+ * (see `UserRepository::delete_user`).
+ *
+ * The erasure returns a certificate: the id the account had, the SHA-256 of its
+ * email & the date of the erasure. It's recorded in the audit log, so it's
+ * covered by the tamper-evident chain (if enabled, see `audit/chain.rs`), and a
+ * request can be checked against it by hashing the email of the requester, the
+ * email itself isn't kept anywhere.
+ *
+ * # Note
+ * The data isn't encrypted at rest, so there's no key to destroy: the rows are
+ * deleted, the copies of the database made before (e.g. backups) still hold them.
+ *
+ * # Author
+ * Doran Kayoumi <doran.kayoumi@heig-vd.ch>
+ */
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sodiumoxide::crypto::hash::sha256;
+
+use super::maintenance;
+use crate::audit::{self, AuditEvent};
+use crate::db::repository::{
+    AuditRepository, NotificationRepository, SQliteAuditRepository, SQliteNotificationRepository,
+    SQliteUserRepository, UserRepository,
+};
+use crate::errors::{AuthError, Completion, Warning};
+
+/// What's deleted along with an account
+const ERASED: [&str; 6] = [
+    "account",
+    "attributes",
+    "recovery_codes",
+    "trusted_contacts",
+    "contact_recoveries",
+    "notifications",
+];
+
+/// Proof of the erasure of an account
+#[derive(PartialEq, Debug, Clone, Serialize)]
+pub struct ErasureCertificate {
+    /// Id the account had
+    pub user: i32,
+    /// SHA-256 (hex) of the email of the account
+    pub subject: String,
+    pub erased_at: String,
+    /// What was deleted
+    pub erased: Vec<String>,
+}
+
+/// Identify the owner of an account without keeping her/his email
+///
+/// # Arguments
+///
+/// * `email` - the email of the account
+///
+pub fn subject(email: &str) -> String {
+    sodiumoxide::hex::encode(sha256::hash(email.to_lowercase().as_bytes()))
+}
+
+/// Public function for the erasure of an account
+/// See `_erase` for more info
+///
+pub fn erase(email: &str, dry_run: bool) -> Result<(ErasureCertificate, Completion), AuthError> {
+    // Note: a dry run changes nothing, so it's allowed in read-only mode
+    if !dry_run {
+        maintenance::check_writable()?;
+    }
+    _erase(
+        email,
+        &SQliteUserRepository::new(),
+        &SQliteNotificationRepository::new(),
+        &SQliteAuditRepository::new(),
+        Utc::now(),
+        dry_run,
+    )
+}
+
+/// Erase an account & record its certificate in the audit log
+/// Note: the account stays erased if the certificate can't be recorded, it's reported as a warning
+///
+/// # Arguments
+///
+/// * `email` - the email of the account
+///
+/// * `repository` - the user repository to interact with
+///
+/// * `notification_repository` - where the notifications sent to the account are kept
+///
+/// * `audit_repository` - the audit repository to write in
+///
+/// * `now` - the current date & time
+///
+/// * `dry_run` - only return the certificate the erasure would have, without erasing anything
+///
+fn _erase(
+    email: &str,
+    repository: &dyn UserRepository,
+    notification_repository: &dyn NotificationRepository,
+    audit_repository: &dyn AuditRepository,
+    now: DateTime<Utc>,
+    dry_run: bool,
+) -> Result<(ErasureCertificate, Completion), AuthError> {
+    let u = repository
+        .get_user(email)
+        .map_err(|e| e.to_auth_error(AuthError::ErasureError))?;
+
+    let certificate = ErasureCertificate {
+        user: u.get_id(),
+        subject: subject(&u.get_email()),
+        erased_at: now.to_rfc3339(),
+        erased: ERASED.iter().map(|e| e.to_string()).collect(),
+    };
+    if dry_run {
+        return Ok((certificate, Completion::Completed));
+    }
+
+    // Note: forgotten first, so nothing is left behind if it fails
+    notification_repository
+        .forget_recipient(&u.get_email())
+        .map_err(|_| AuthError::ErasureError)?;
+    repository
+        .delete_user(u.get_id())
+        .map_err(|e| e.to_auth_error(AuthError::ErasureError))?;
+
+    let mut warnings = vec![];
+    let recorded = serde_json::to_string(&certificate).ok().and_then(|c| {
+        audit::record(audit_repository, None, AuditEvent::AccountErased, Some(c)).ok()
+    });
+    if recorded.is_none() {
+        warnings.push(Warning::ErasureNotRecorded);
+    }
+
+    Ok((certificate, Completion::with_warnings(warnings)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::db::models::User;
+    use crate::db::repository::{
+        MockSQliteAuditRepository, MockSQliteNotificationRepository, MockSQliteUserRepository,
+    };
+    use crate::errors::{AuditDBError, UserDBError};
+    use chrono::TimeZone;
+
+    fn now() -> DateTime<Utc> {
+        Utc.ymd(2021, 4, 28).and_hms(12, 0, 0)
+    }
+
+    fn users() -> MockSQliteUserRepository {
+        let mut mock = MockSQliteUserRepository::new();
+        mock.expect_get_user()
+            .returning(|e| Ok(User::new(e, "passwd_hash")));
+        mock
+    }
+
+    fn notifications() -> MockSQliteNotificationRepository {
+        let mut mock = MockSQliteNotificationRepository::new();
+        mock.expect_forget_recipient()
+            .withf(|r| r == "alice@email.test")
+            .returning(|_| Ok(()));
+        mock
+    }
+
+    #[test]
+    fn test_subject_ignores_the_case() {
+        assert_eq!(subject("Alice@Email.test"), subject("alice@email.test"));
+        assert_ne!(subject("bob@email.test"), subject("alice@email.test"));
+        assert_eq!(subject("alice@email.test").len(), 64);
+    }
+
+    #[test]
+    fn test_erase() {
+        let mut repository = users();
+        repository
+            .expect_delete_user()
+            .withf(|u| *u == 1)
+            .times(1)
+            .returning(|_| Ok(()));
+        let mut audit_repository = MockSQliteAuditRepository::new();
+        audit_repository
+            .expect_create_entry()
+            .withf(|u, e, d| {
+                u.is_none()
+                    && e == "AccountErased"
+                    && d.as_ref().is_some_and(|d| !d.contains("alice"))
+            })
+            .times(1)
+            .returning(|_, _, _| Ok(()));
+
+        let (certificate, completion) = _erase(
+            "alice@email.test",
+            &repository,
+            &notifications(),
+            &audit_repository,
+            now(),
+            false,
+        )
+        .unwrap();
+
+        assert_eq!(certificate.user, 1);
+        assert_eq!(certificate.subject, subject("alice@email.test"));
+        assert_eq!(certificate.erased_at, now().to_rfc3339());
+        assert_eq!(completion, Completion::Completed);
+    }
+
+    #[test]
+    fn test_erase_dry_run() {
+        let mut repository = users();
+        repository.expect_delete_user().times(0);
+        let mut notification_repository = MockSQliteNotificationRepository::new();
+        notification_repository.expect_forget_recipient().times(0);
+        let mut audit_repository = MockSQliteAuditRepository::new();
+        audit_repository.expect_create_entry().times(0);
+
+        let (certificate, _) = _erase(
+            "alice@email.test",
+            &repository,
+            &notification_repository,
+            &audit_repository,
+            now(),
+            true,
+        )
+        .unwrap();
+
+        assert_eq!(certificate.user, 1);
+    }
+
+    #[test]
+    fn test_erase_unknown_account() {
+        let mut repository = MockSQliteUserRepository::new();
+        repository
+            .expect_get_user()
+            .returning(|_| Err(UserDBError::GetUserError));
+        repository.expect_delete_user().times(0);
+
+        assert_eq!(
+            _erase(
+                "alice@email.test",
+                &repository,
+                &MockSQliteNotificationRepository::new(),
+                &MockSQliteAuditRepository::new(),
+                now(),
+                false,
+            ),
+            Err(AuthError::ErasureError)
+        );
+    }
+
+    #[test]
+    fn test_erase_with_unrecorded_certificate() {
+        let mut repository = users();
+        repository.expect_delete_user().returning(|_| Ok(()));
+        let mut audit_repository = MockSQliteAuditRepository::new();
+        audit_repository
+            .expect_create_entry()
+            .returning(|_, _, _| Err(AuditDBError::CreateEntryError));
+
+        let (_, completion) = _erase(
+            "alice@email.test",
+            &repository,
+            &notifications(),
+            &audit_repository,
+            now(),
+            false,
+        )
+        .unwrap();
+
+        // the account is erased all the same
+        assert_eq!(
+            completion,
+            Completion::CompletedWithWarnings(vec![Warning::ErasureNotRecorded])
+        );
+    }
+}
@@ -0,0 +1,324 @@
+/*!
+ * Second factors the users can complete their login with
+ *
+ * Every second factor implements `SecondFactor` & is registered in a
+ * `FactorRegistry`, the login dispatches to the one chosen by the user.
+ * Adding a factor (e.g. email OTP) only requires implementing the trait &
+ * registering it, the login itself doesn't change.
+ *
+ * # Author
+ * Doran Kayoumi <doran.kayoumi@heig-vd.ch>
+ */
+
+use super::recovery::{self, Consumption};
+use super::twofa;
+use crate::db::models::User;
+use crate::db::repository::{RecoveryCodeRepository, SQliteRecoveryCodeRepository};
+use crate::errors::AuthError;
+
+/// Name of the authentication app factor
+pub const TOTP: &str = "totp";
+/// Name of the recovery code factor
+pub const RECOVERY_CODE: &str = "recovery_code";
+
+/// What happened when a second factor was verified
+#[derive(PartialEq, Debug, Default, Clone, Copy)]
+pub struct Verification {
+    /// Set if a recovery code was used
+    pub recovery: Option<Consumption>,
+}
+
+pub trait SecondFactor {
+    /// Unique name of the factor, used by the users to choose it
+    fn name(&self) -> &'static str;
+
+    /// Check if a user set the factor up
+    ///
+    /// # Arguments
+    ///
+    /// * `u` - the user to check
+    ///
+    fn is_enabled(&self, u: &User) -> bool;
+
+    /// Check the code entered by a user
+    /// if the code is wrong (or something goes wrong), an error is returned
+    ///
+    /// # Arguments
+    ///
+    /// * `u` - the user logging in
+    ///
+    /// * `code` - the code entered by the user
+    ///
+    fn verify(&self, u: &User, code: &str) -> Result<Verification, AuthError>;
+
+    /// How much the factor proves, the sensitive actions are confirmed with the
+    /// strongest factor a user set up (see `step_up.rs`)
+    /// 0 if the factor can't confirm them
+    fn strength(&self) -> u8 {
+        1
+    }
+}
+
+/// Codes generated by an authentication app from the user's 2fa secret
+pub struct TotpFactor;
+
+impl SecondFactor for TotpFactor {
+    fn name(&self) -> &'static str {
+        TOTP
+    }
+
+    fn is_enabled(&self, u: &User) -> bool {
+        u.is_2fa_enabled()
+    }
+
+    fn verify(&self, u: &User, code: &str) -> Result<Verification, AuthError> {
+        let secret = u.get_secret_2fa().ok_or(AuthError::UnknownFactor)?;
+
+        if twofa::check_code(&secret, code) {
+            Ok(Verification::default())
+        } else {
+            Err(AuthError::InvalidAuthenticationCode)
+        }
+    }
+
+    fn strength(&self) -> u8 {
+        2
+    }
+}
+
+/// Single use codes for the users who lost their device (see `recovery.rs`)
+pub struct RecoveryCodeFactor {
+    repository: Box<dyn RecoveryCodeRepository>,
+}
+
+impl RecoveryCodeFactor {
+    /// Factor using the recovery codes stored in the database set in the `.env` file
+    pub fn new() -> Self {
+        Self::with_repository(Box::new(SQliteRecoveryCodeRepository::new()))
+    }
+
+    /// Factor using the recovery codes of a specific repository
+    ///
+    /// # Arguments
+    ///
+    /// * `repository` - the recovery code repository to interact with
+    ///
+    pub fn with_repository(repository: Box<dyn RecoveryCodeRepository>) -> Self {
+        Self { repository }
+    }
+}
+
+impl Default for RecoveryCodeFactor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SecondFactor for RecoveryCodeFactor {
+    fn name(&self) -> &'static str {
+        RECOVERY_CODE
+    }
+
+    fn is_enabled(&self, u: &User) -> bool {
+        // Note: the codes can't be counted, the user can still try one
+        u.is_2fa_enabled()
+            && self
+                .repository
+                .count_unused_codes(u.get_id())
+                .map_or(true, |n| n > 0)
+    }
+
+    fn verify(&self, u: &User, code: &str) -> Result<Verification, AuthError> {
+        let consumption = recovery::_consume_code(u, code, self.repository.as_ref())?;
+
+        Ok(Verification {
+            recovery: Some(consumption),
+        })
+    }
+
+    /// Note: the codes are for the users who lost their device, not to confirm an action
+    fn strength(&self) -> u8 {
+        0
+    }
+}
+
+/// The second factors available in the system
+#[derive(Default)]
+pub struct FactorRegistry {
+    factors: Vec<Box<dyn SecondFactor>>,
+}
+
+impl FactorRegistry {
+    /// Registry without any factor, see `standard` for the ones built in the system
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registry with the factors built in the system (authentication app & recovery codes)
+    pub fn standard() -> Self {
+        Self::new().with(TotpFactor).with(RecoveryCodeFactor::new())
+    }
+
+    /// Add a factor, it replaces the one with the same name (if any)
+    pub fn with(mut self, factor: impl SecondFactor + 'static) -> Self {
+        self.factors.retain(|f| f.name() != factor.name());
+        self.factors.push(Box::new(factor));
+        self
+    }
+
+    /// Get a factor by its name
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - the name of the factor
+    ///
+    pub fn get(&self, name: &str) -> Option<&dyn SecondFactor> {
+        self.factors
+            .iter()
+            .find(|f| f.name() == name)
+            .map(|f| f.as_ref())
+    }
+
+    /// Names of the factors a user set up
+    ///
+    /// # Arguments
+    ///
+    /// * `u` - the user to check
+    ///
+    pub fn enabled_for(&self, u: &User) -> Vec<&'static str> {
+        self.factors
+            .iter()
+            .filter(|f| f.is_enabled(u))
+            .map(|f| f.name())
+            .collect()
+    }
+
+    /// The strongest factor a user set up, the first one registered if several are as strong
+    /// None if the user set up none that can confirm an action (see `SecondFactor::strength`)
+    ///
+    /// # Arguments
+    ///
+    /// * `u` - the user to check
+    ///
+    pub fn strongest_for(&self, u: &User) -> Option<&dyn SecondFactor> {
+        self.factors
+            .iter()
+            .filter(|f| f.strength() > 0 && f.is_enabled(u))
+            // Note: `max_by_key` keeps the last of the strongest, hence the reversed order
+            .rev()
+            .max_by_key(|f| f.strength())
+            .map(|f| f.as_ref())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::db::repository::MockSQliteRecoveryCodeRepository;
+    use google_authenticator::GoogleAuthenticator;
+
+    const SECRET: &str = "I3VFM3JKMNDJCDH5BMBEEQAW6KJ6NOE3";
+
+    /// Factor accepting a fixed code, e.g. an email OTP
+    struct FixedCodeFactor;
+
+    impl SecondFactor for FixedCodeFactor {
+        fn name(&self) -> &'static str {
+            "fixed"
+        }
+
+        fn is_enabled(&self, _u: &User) -> bool {
+            true
+        }
+
+        fn verify(&self, _u: &User, code: &str) -> Result<Verification, AuthError> {
+            if code == "42" {
+                Ok(Verification::default())
+            } else {
+                Err(AuthError::InvalidAuthenticationCode)
+            }
+        }
+    }
+
+    fn codes_repository(unused: i64) -> Box<MockSQliteRecoveryCodeRepository> {
+        let mut mock = MockSQliteRecoveryCodeRepository::new();
+        mock.expect_count_unused_codes()
+            .returning(move |_| Ok(unused));
+        Box::new(mock)
+    }
+
+    #[test]
+    fn test_totp_factor() {
+        let mut u = User::new("email@email.test", "passwd_hash");
+        assert!(!TotpFactor.is_enabled(&u));
+
+        u.set_secret_2fa(Some(SECRET.to_string()));
+        let code = GoogleAuthenticator::new().get_code(SECRET, 0).unwrap();
+
+        assert!(TotpFactor.is_enabled(&u));
+        assert_eq!(TotpFactor.verify(&u, &code), Ok(Verification::default()));
+        assert_eq!(
+            TotpFactor.verify(&u, "000000"),
+            Err(AuthError::InvalidAuthenticationCode)
+        );
+    }
+
+    #[test]
+    fn test_recovery_code_factor_is_enabled() {
+        let mut u = User::new("email@email.test", "passwd_hash");
+        u.set_secret_2fa(Some(SECRET.to_string()));
+
+        assert!(RecoveryCodeFactor::with_repository(codes_repository(2)).is_enabled(&u));
+        assert!(!RecoveryCodeFactor::with_repository(codes_repository(0)).is_enabled(&u));
+
+        u.set_secret_2fa(None);
+        assert!(!RecoveryCodeFactor::with_repository(codes_repository(2)).is_enabled(&u));
+    }
+
+    #[test]
+    fn test_registry() {
+        let registry = FactorRegistry::new()
+            .with(TotpFactor)
+            .with(RecoveryCodeFactor::with_repository(codes_repository(0)))
+            .with(FixedCodeFactor);
+        let mut u = User::new("email@email.test", "passwd_hash");
+
+        assert_eq!(registry.enabled_for(&u), vec!["fixed"]);
+
+        u.set_secret_2fa(Some(SECRET.to_string()));
+        assert_eq!(registry.enabled_for(&u), vec![TOTP, "fixed"]);
+
+        assert!(registry.get(RECOVERY_CODE).is_some());
+        assert!(registry.get("webauthn").is_none());
+        assert_eq!(
+            registry.get("fixed").unwrap().verify(&u, "42"),
+            Ok(Verification::default())
+        );
+    }
+
+    #[test]
+    fn test_strongest_factor() {
+        let registry = FactorRegistry::new()
+            .with(RecoveryCodeFactor::with_repository(codes_repository(10)))
+            .with(FixedCodeFactor)
+            .with(TotpFactor);
+        let mut u = User::new("email@email.test", "passwd_hash");
+
+        assert_eq!(registry.strongest_for(&u).map(|f| f.name()), Some("fixed"));
+
+        u.set_secret_2fa(Some(SECRET.to_string()));
+        assert_eq!(registry.strongest_for(&u).map(|f| f.name()), Some(TOTP));
+
+        // the recovery codes never confirm an action
+        let registry =
+            FactorRegistry::new().with(RecoveryCodeFactor::with_repository(codes_repository(10)));
+        assert!(registry.strongest_for(&u).is_none());
+    }
+
+    #[test]
+    fn test_registry_replaces_factors_with_the_same_name() {
+        let registry = FactorRegistry::new().with(TotpFactor).with(TotpFactor);
+
+        assert_eq!(registry.factors.len(), 1);
+    }
+}
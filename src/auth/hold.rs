@@ -0,0 +1,573 @@
+/*!
+ * Security hold of the accounts
+ *
+ * When the risk-based policy or the failed logins indicate a likely compromise
+ * (see the `[hold]` section of the configuration), the account is placed on
+ * security hold. Unlike the CAPTCHA of `throttle.rs`, a hold doesn't end by
+ * itself: even the right password is refused until an administrator reviews
+ * the account (`release_hold`) or the user recovers it with both her/his reset
+ * token & a second factor (`release_with_recovery`). The users without a second
+ * factor must go through an administrator.
+ *
+ * The hold is kept in the attributes of the user, the placements & the
+ * releases are added to the audit log.
+ *
+ * # Author
+ * Doran Kayoumi <doran.kayoumi@heig-vd.ch>
+ */
+
+use chrono::Utc;
+use std::fmt;
+
+use super::factor::FactorRegistry;
+use super::{action, maintenance, reset};
+use crate::audit::{self, AuditEvent};
+use crate::db::ephemeral::{self, TokenStore};
+use crate::db::repository::{
+    AuditRepository, SQliteAuditRepository, SQliteUserRepository, UserFilter, UserRepository,
+};
+use crate::errors::AuthError;
+use crate::utils;
+use crate::validation::MAX_TOKEN_BYTES;
+
+/// Attribute marking the accounts on hold
+pub const HOLD_ATTRIBUTE: &str = "security_hold";
+/// Attribute explaining why an account is on hold
+pub const HOLD_REASON_ATTRIBUTE: &str = "security_hold_reason";
+
+const ACTIVE: &str = "active";
+
+/// Why an account was placed on hold
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum HoldReason {
+    /// The risk-based policy blocked a login
+    RiskBlocked { score: u32 },
+    /// Too many failed logins
+    FailedLogins { count: u32 },
+    /// No login for too long (see `inactivity.rs`)
+    Inactivity { days: i64 },
+    /// The user reported a change she/he didn't make (see `not_me.rs`)
+    NotMe,
+}
+
+impl fmt::Display for HoldReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HoldReason::RiskBlocked { score } => write!(f, "login blocked (risk score {})", score),
+            HoldReason::FailedLogins { count } => write!(f, "{} failed logins", count),
+            HoldReason::Inactivity { days } => write!(f, "inactive for {} days", days),
+            HoldReason::NotMe => write!(f, "reported by the user (\"this wasn't me\")"),
+        }
+    }
+}
+
+/// How a hold was released
+#[derive(PartialEq, Debug, Clone, Copy, strum_macros::Display)]
+pub enum Release {
+    #[strum(serialize = "admin review")]
+    AdminReview,
+    #[strum(serialize = "recovery with the reset token & a second factor")]
+    Recovery,
+    #[strum(serialize = "recovery approved by the trusted contacts")]
+    ContactRecovery,
+}
+
+/// An account on hold
+#[derive(PartialEq, Debug, Clone)]
+pub struct Hold {
+    pub email: String,
+    pub reason: Option<String>,
+}
+
+/// Check if an account is on hold
+///
+/// # Arguments
+///
+/// * `user` - id of the user to check
+///
+/// * `repository` - the user repository to interact with
+///
+pub fn is_on_hold(user: i32, repository: &dyn UserRepository) -> Result<bool, AuthError> {
+    repository
+        .get_attributes(user)
+        .map(|a| a.get(HOLD_ATTRIBUTE).map(String::as_str) == Some(ACTIVE))
+        .map_err(|_| AuthError::HoldError)
+}
+
+/// Public function for placing an account on hold
+/// See `_place_hold` for more info
+///
+pub fn place_hold(email: &str, reason: HoldReason) -> Result<bool, AuthError> {
+    let repository = SQliteUserRepository::new();
+    let audit_repository = SQliteAuditRepository::new();
+    _place_hold(email, reason, &repository, &audit_repository)
+}
+
+/// Public function for listing the accounts on hold
+/// See `_list_holds` for more info
+///
+pub fn list_holds() -> Result<Vec<Hold>, AuthError> {
+    let repository = SQliteUserRepository::new();
+    _list_holds(&repository)
+}
+
+/// Public function for the release of a hold by an administrator
+/// See `_release_hold` for more info
+///
+pub fn release_hold(email: &str) -> Result<(), AuthError> {
+    maintenance::check_writable()?;
+    let repository = SQliteUserRepository::new();
+    let audit_repository = SQliteAuditRepository::new();
+    _release_hold(email, Release::AdminReview, &repository, &audit_repository)
+}
+
+/// Public function for the release of a hold by the user
+/// See `_release_with_recovery` for more info
+///
+pub fn release_with_recovery(
+    email: &str,
+    token: &str,
+    factor: &str,
+    code: &str,
+) -> Result<(), AuthError> {
+    maintenance::check_writable()?;
+    let repository = SQliteUserRepository::new();
+    let audit_repository = SQliteAuditRepository::new();
+    _release_with_recovery(
+        email,
+        token,
+        factor,
+        code,
+        &repository,
+        &*ephemeral::durable(),
+        &FactorRegistry::standard(),
+        &audit_repository,
+        &action::secret()?,
+    )
+}
+
+/// Place an account on hold
+/// Returns whether the account was placed on hold, i.e. it exists & wasn't already on hold
+///
+/// # Arguments
+///
+/// * `email` - the email of the account
+///
+/// * `reason` - why the account is placed on hold
+///
+/// * `repository` - the user repository to interact with
+///
+/// * `audit_repository` - the audit repository to write in
+///
+pub(super) fn _place_hold(
+    email: &str,
+    reason: HoldReason,
+    repository: &dyn UserRepository,
+    audit_repository: &dyn AuditRepository,
+) -> Result<bool, AuthError> {
+    // Note: the failed logins can target an account that doesn't exist
+    let u = match repository.get_user(email) {
+        Ok(u) => u,
+        Err(_) => return Ok(false),
+    };
+    if is_on_hold(u.get_id(), repository)? {
+        return Ok(false);
+    }
+
+    repository
+        .set_attribute(u.get_id(), HOLD_ATTRIBUTE, ACTIVE)
+        .and_then(|_| {
+            repository.set_attribute(u.get_id(), HOLD_REASON_ATTRIBUTE, &reason.to_string())
+        })
+        .map_err(|_| AuthError::HoldError)?;
+
+    let _ = audit::record(
+        audit_repository,
+        Some(u.get_id()),
+        AuditEvent::SecurityHoldPlaced,
+        Some(reason.to_string()),
+    );
+
+    Ok(true)
+}
+
+/// List the accounts on hold, with the reason of their hold
+///
+/// # Arguments
+///
+/// * `repository` - the user repository to interact with
+///
+pub(crate) fn _list_holds(repository: &dyn UserRepository) -> Result<Vec<Hold>, AuthError> {
+    let users = repository
+        .list_users(&UserFilter::new().with_attribute(HOLD_ATTRIBUTE, ACTIVE))
+        .map_err(|_| AuthError::HoldError)?;
+
+    users
+        .iter()
+        .map(|u| {
+            let mut attributes = repository
+                .get_attributes(u.get_id())
+                .map_err(|_| AuthError::HoldError)?;
+
+            Ok(Hold {
+                email: u.get_email(),
+                reason: attributes.remove(HOLD_REASON_ATTRIBUTE),
+            })
+        })
+        .collect()
+}
+
+/// Release the hold of an account
+///
+/// # Arguments
+///
+/// * `email` - the email of the account
+///
+/// * `release` - how the hold was released
+///
+/// * `repository` - the user repository to interact with
+///
+/// * `audit_repository` - the audit repository to write in
+///
+pub(super) fn _release_hold(
+    email: &str,
+    release: Release,
+    repository: &dyn UserRepository,
+    audit_repository: &dyn AuditRepository,
+) -> Result<(), AuthError> {
+    let u = repository
+        .get_user(email)
+        .map_err(|_| AuthError::NotOnHold)?;
+    if !is_on_hold(u.get_id(), repository)? {
+        return Err(AuthError::NotOnHold);
+    }
+
+    repository
+        .remove_attribute(u.get_id(), HOLD_ATTRIBUTE)
+        .and_then(|_| repository.remove_attribute(u.get_id(), HOLD_REASON_ATTRIBUTE))
+        .map_err(|_| AuthError::HoldError)?;
+
+    let _ = audit::record(
+        audit_repository,
+        Some(u.get_id()),
+        AuditEvent::SecurityHoldReleased,
+        Some(release.to_string()),
+    );
+
+    Ok(())
+}
+
+/// Release the hold of an account once the user proved she/he owns both the
+/// mailbox (reset token) & the second factor of the account
+///
+/// # Arguments
+///
+/// * `email` - the email of the account
+///
+/// * `token` - the reset token sent to the user
+///
+/// * `factor` - the name of the second factor used
+///
+/// * `code` - the code of the second factor
+///
+/// * `repository` - the user repository to interact with
+///
+/// * `store` - the store of the reset tokens
+///
+/// * `registry` - the second factors available
+///
+/// * `audit_repository` - the audit repository to write in
+///
+/// * `secret` - the key signing the reset tokens
+///
+#[allow(clippy::too_many_arguments)]
+fn _release_with_recovery(
+    email: &str,
+    token: &str,
+    factor: &str,
+    code: &str,
+    repository: &dyn UserRepository,
+    store: &dyn TokenStore,
+    registry: &FactorRegistry,
+    audit_repository: &dyn AuditRepository,
+    secret: &str,
+) -> Result<(), AuthError> {
+    utils::check_length(code, MAX_TOKEN_BYTES)?;
+    reset::_check_token(email, token, repository, store, secret, Utc::now())?;
+
+    let u = repository
+        .get_user(email)
+        .map_err(|_| AuthError::ResetError)?;
+    let factor = registry
+        .get(factor)
+        .filter(|f| f.is_enabled(&u))
+        .ok_or(AuthError::UnknownFactor)?;
+    factor.verify(&u, code)?;
+
+    _release_hold(email, Release::Recovery, repository, audit_repository)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::auth::factor::{SecondFactor, Verification};
+    use crate::db::ephemeral::InMemoryTokenStore;
+    use crate::db::models::User;
+    use crate::db::repository::{MockSQliteAuditRepository, MockSQliteUserRepository};
+    use crate::errors::UserDBError;
+    use crate::mail::capture::CapturingMailer;
+    use std::collections::HashMap;
+
+    /// Factor accepting a fixed code
+    struct FixedCodeFactor;
+
+    impl SecondFactor for FixedCodeFactor {
+        fn name(&self) -> &'static str {
+            "fixed"
+        }
+
+        fn is_enabled(&self, _u: &User) -> bool {
+            true
+        }
+
+        fn verify(&self, _u: &User, code: &str) -> Result<Verification, AuthError> {
+            if code == "123456" {
+                Ok(Verification::default())
+            } else {
+                Err(AuthError::InvalidAuthenticationCode)
+            }
+        }
+    }
+
+    fn on_hold() -> HashMap<String, String> {
+        vec![
+            (HOLD_ATTRIBUTE.to_string(), ACTIVE.to_string()),
+            (
+                HOLD_REASON_ATTRIBUTE.to_string(),
+                "20 failed logins".to_string(),
+            ),
+        ]
+        .into_iter()
+        .collect()
+    }
+
+    #[test]
+    fn test_place_hold() {
+        let mut repository = MockSQliteUserRepository::new();
+        let mut audit_repository = MockSQliteAuditRepository::new();
+
+        repository
+            .expect_get_user()
+            .returning(|e| Ok(User::new(e, "passwd_hash")));
+        repository
+            .expect_get_attributes()
+            .returning(|_| Ok(HashMap::new()));
+        repository
+            .expect_set_attribute()
+            .withf(|_, a, v| {
+                (a == HOLD_ATTRIBUTE && v == ACTIVE)
+                    || (a == HOLD_REASON_ATTRIBUTE && v == "login blocked (risk score 90)")
+            })
+            .times(2)
+            .returning(|_, _, _| Ok(()));
+        audit_repository
+            .expect_create_entry()
+            .withf(|u, e, _| *u == Some(1) && e == "SecurityHoldPlaced")
+            .times(1)
+            .returning(|_, _, _| Ok(()));
+
+        assert_eq!(
+            _place_hold(
+                "email@email.test",
+                HoldReason::RiskBlocked { score: 90 },
+                &repository,
+                &audit_repository
+            ),
+            Ok(true)
+        );
+    }
+
+    #[test]
+    fn test_place_hold_is_idempotent() {
+        let mut repository = MockSQliteUserRepository::new();
+        let mut audit_repository = MockSQliteAuditRepository::new();
+
+        repository
+            .expect_get_user()
+            .returning(|e| Ok(User::new(e, "passwd_hash")));
+        repository
+            .expect_get_attributes()
+            .returning(|_| Ok(on_hold()));
+        repository.expect_set_attribute().times(0);
+        audit_repository.expect_create_entry().times(0);
+
+        assert_eq!(
+            _place_hold(
+                "email@email.test",
+                HoldReason::FailedLogins { count: 20 },
+                &repository,
+                &audit_repository
+            ),
+            Ok(false)
+        );
+    }
+
+    #[test]
+    fn test_unknown_account_isnt_placed_on_hold() {
+        let mut repository = MockSQliteUserRepository::new();
+        let audit_repository = MockSQliteAuditRepository::new();
+
+        repository
+            .expect_get_user()
+            .returning(|_| Err(UserDBError::GetUserError));
+
+        assert_eq!(
+            _place_hold(
+                "unknown@email.test",
+                HoldReason::FailedLogins { count: 20 },
+                &repository,
+                &audit_repository
+            ),
+            Ok(false)
+        );
+    }
+
+    #[test]
+    fn test_release_hold() {
+        let mut repository = MockSQliteUserRepository::new();
+        let mut audit_repository = MockSQliteAuditRepository::new();
+
+        repository
+            .expect_get_user()
+            .returning(|e| Ok(User::new(e, "passwd_hash")));
+        repository
+            .expect_get_attributes()
+            .returning(|_| Ok(on_hold()));
+        repository
+            .expect_remove_attribute()
+            .times(2)
+            .returning(|_, _| Ok(()));
+        audit_repository
+            .expect_create_entry()
+            .withf(|_, e, d| e == "SecurityHoldReleased" && d.as_deref() == Some("admin review"))
+            .times(1)
+            .returning(|_, _, _| Ok(()));
+
+        assert_eq!(
+            _release_hold(
+                "email@email.test",
+                Release::AdminReview,
+                &repository,
+                &audit_repository
+            ),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn test_release_without_hold() {
+        let mut repository = MockSQliteUserRepository::new();
+        let audit_repository = MockSQliteAuditRepository::new();
+
+        repository
+            .expect_get_user()
+            .returning(|e| Ok(User::new(e, "passwd_hash")));
+        repository
+            .expect_get_attributes()
+            .returning(|_| Ok(HashMap::new()));
+        repository.expect_remove_attribute().times(0);
+
+        assert_eq!(
+            _release_hold(
+                "email@email.test",
+                Release::AdminReview,
+                &repository,
+                &audit_repository
+            ),
+            Err(AuthError::NotOnHold)
+        );
+    }
+
+    #[test]
+    fn test_release_with_recovery_requires_both_proofs() {
+        let mut repository = MockSQliteUserRepository::new();
+        let mut audit_repository = MockSQliteAuditRepository::new();
+        let registry = FactorRegistry::new().with(FixedCodeFactor);
+
+        repository
+            .expect_get_user()
+            .returning(|e| Ok(User::new(e, "passwd_hash")));
+        repository
+            .expect_get_attributes()
+            .returning(|_| Ok(on_hold()));
+        repository
+            .expect_remove_attribute()
+            .times(2)
+            .returning(|_, _| Ok(()));
+        audit_repository
+            .expect_create_entry()
+            .times(1)
+            .returning(|_, _, _| Ok(()));
+        let (store, mailer) = (InMemoryTokenStore::new(), CapturingMailer::new());
+        let secret = "0123456789abcdef0123456789abcdef";
+        reset::_generate_reset_token(
+            "email@email.test",
+            &repository,
+            &store,
+            &mailer,
+            secret,
+            Utc::now(),
+        )
+        .unwrap();
+        let token = mailer.last_token_for("email@email.test").unwrap();
+
+        let release = |token: &str, factor: &str, code: &str| {
+            _release_with_recovery(
+                "email@email.test",
+                token,
+                factor,
+                code,
+                &repository,
+                &store,
+                &registry,
+                &audit_repository,
+                secret,
+            )
+        };
+
+        assert_eq!(
+            release("wrong", "fixed", "123456"),
+            Err(AuthError::TokenMismatch)
+        );
+        assert_eq!(
+            release(&token, "fixed", "000000"),
+            Err(AuthError::InvalidAuthenticationCode)
+        );
+        assert_eq!(
+            release(&token, "totp", "123456"),
+            Err(AuthError::UnknownFactor)
+        );
+        assert_eq!(release(&token, "fixed", "123456"), Ok(()));
+    }
+
+    #[test]
+    fn test_list_holds() {
+        let mut repository = MockSQliteUserRepository::new();
+
+        repository
+            .expect_list_users()
+            .withf(|f| *f == UserFilter::new().with_attribute(HOLD_ATTRIBUTE, ACTIVE))
+            .returning(|_| Ok(vec![User::new("email@email.test", "passwd_hash")]));
+        repository
+            .expect_get_attributes()
+            .returning(|_| Ok(on_hold()));
+
+        assert_eq!(
+            _list_holds(&repository),
+            Ok(vec![Hold {
+                email: "email@email.test".to_string(),
+                reason: Some("20 failed logins".to_string()),
+            }])
+        );
+    }
+}
@@ -0,0 +1,499 @@
+/*!
+ * Accounts disabled after a long inactivity
+ *
+ * The date of the last login of each user is kept in her/his attributes. The
+ * maintenance job (`secure-auth inactivity`, to run periodically) warns the users
+ * who didn't login for too long by email & disables the ones still inactive
+ * once the grace period is over, see the `[inactivity]` section of the
+ * configuration. A disabled account is placed on security hold (see `hold.rs`),
+ * so it's released the same way.
+ *
+ * The users who never logged in since the policy exists get their clock started
+ * by the first run of the job.
+ *
+ * With `dry_run`, the job only reports the accounts it would warn & disable:
+ * nothing is written, no email is sent and the clocks aren't started.
+ *
+ * When several instances share the database, the job runs on one of them at a
+ * time (see `jobs.rs`), so no user is warned twice.
+ *
+ * # Author
+ * Doran Kayoumi <doran.kayoumi@heig-vd.ch>
+ */
+
+use chrono::{DateTime, Duration, Utc};
+
+use super::hold::{self, HoldReason};
+use super::{jobs, maintenance};
+use crate::audit::{self, AuditEvent};
+use crate::config::{self, InactivityConfig};
+use crate::db::models::User;
+use crate::db::repository::{
+    AuditRepository, SQliteAuditRepository, SQliteUserRepository, UserFilter, UserRepository,
+};
+use crate::errors::AuthError;
+use crate::mail::templates::{self, Template};
+use crate::mail::{self, Mailer};
+
+/// Attribute holding the date & time of the last login of a user
+pub const LAST_LOGIN_ATTRIBUTE: &str = "last_login_at";
+/// Attribute holding when a user was warned about her/his inactivity
+pub const WARNED_ATTRIBUTE: &str = "inactivity_warned_at";
+/// Name of the job, it runs on one instance at a time (see `jobs.rs`)
+const JOB: &str = "inactivity";
+
+/// Accounts warned & disabled by a run of the job (or that would be, on a dry run)
+#[derive(PartialEq, Debug, Default)]
+pub struct InactivityReport {
+    pub warned: Vec<String>,
+    pub disabled: Vec<String>,
+}
+
+fn parse(dt: Option<&String>) -> Option<DateTime<Utc>> {
+    dt.and_then(|dt| DateTime::parse_from_rfc3339(dt).ok())
+        .map(|dt| dt.with_timezone(&Utc))
+}
+
+/// Public function for recording a login
+/// See `_record_login` for more info
+///
+pub fn record_login(u: &User) {
+    record_login_with_repository(u, &SQliteUserRepository::new())
+}
+
+/// Same as `record_login`, with the users of a given storage (e.g. `PostgresUserRepository`)
+pub fn record_login_with_repository(u: &User, repository: &dyn UserRepository) {
+    _record_login(u, repository, Utc::now())
+}
+
+/// Public function for the inactivity job
+/// See `_disable_inactive_accounts` for more info
+///
+pub fn disable_inactive_accounts(dry_run: bool) -> Result<InactivityReport, AuthError> {
+    // Note: a dry run changes nothing, so it's allowed in read-only mode
+    if !dry_run {
+        maintenance::check_writable()?;
+    }
+    let repository = SQliteUserRepository::new();
+    let audit_repository = SQliteAuditRepository::new();
+    let run = || {
+        _disable_inactive_accounts(
+            &repository,
+            &audit_repository,
+            mail::default_mailer(),
+            &config::get().inactivity,
+            Utc::now(),
+            dry_run,
+        )
+    };
+
+    // Note: the other instances would warn & disable the same accounts, a dry run writes nothing
+    if dry_run {
+        run()
+    } else {
+        jobs::run_exclusive(JOB, run)
+    }
+}
+
+/// Keep the date & time of the last login of a user
+///
+/// # Note
+/// The login succeeded anyway, it's not worth failing it if the date can't be kept
+///
+/// # Arguments
+///
+/// * `u` - the user who logged in
+///
+/// * `repository` - the user repository to interact with
+///
+/// * `now` - the current date & time
+///
+fn _record_login(u: &User, repository: &dyn UserRepository, now: DateTime<Utc>) {
+    let _ = repository.set_attribute(u.get_id(), LAST_LOGIN_ATTRIBUTE, &now.to_rfc3339());
+}
+
+/// Warn the users who didn't login for too long & disable the ones still
+/// inactive once the grace period is over
+///
+/// # Arguments
+///
+/// * `repository` - the user repository to interact with
+///
+/// * `audit_repository` - the audit repository to write in
+///
+/// * `mailer` - the mailer used to send the warnings
+///
+/// * `policy` - the inactivity allowed & the grace period
+///
+/// * `now` - the current date & time
+///
+/// * `dry_run` - only report the accounts that would be warned & disabled
+///
+fn _disable_inactive_accounts(
+    repository: &dyn UserRepository,
+    audit_repository: &dyn AuditRepository,
+    mailer: &dyn Mailer,
+    policy: &InactivityConfig,
+    now: DateTime<Utc>,
+    dry_run: bool,
+) -> Result<InactivityReport, AuthError> {
+    let mut report = InactivityReport::default();
+    if policy.after_days == 0 {
+        return Ok(report);
+    }
+
+    for u in repository.iter_users(&UserFilter::new()) {
+        let u = u.map_err(|_| AuthError::InactivityError)?;
+        // Note: the service accounts don't login, they'd all be disabled
+        if u.is_service() {
+            continue;
+        }
+        let attributes = repository
+            .get_attributes(u.get_id())
+            .map_err(|_| AuthError::InactivityError)?;
+        if hold::is_on_hold(u.get_id(), repository)? {
+            continue;
+        }
+
+        let last_login = match parse(attributes.get(LAST_LOGIN_ATTRIBUTE)) {
+            Some(dt) => dt,
+            None => {
+                if !dry_run {
+                    _record_login(&u, repository, now);
+                }
+                continue;
+            }
+        };
+        let inactive_days = (now - last_login).num_days();
+        if inactive_days < i64::from(policy.after_days) {
+            // the user logged in since she/he was warned
+            if attributes.contains_key(WARNED_ATTRIBUTE) && !dry_run {
+                let _ = repository.remove_attribute(u.get_id(), WARNED_ATTRIBUTE);
+            }
+            continue;
+        }
+
+        match parse(attributes.get(WARNED_ATTRIBUTE)) {
+            None if dry_run => report.warned.push(u.get_email()),
+            None => {
+                let warning = Template::InactivityWarning {
+                    inactive_days,
+                    grace_days: policy.grace_days,
+                };
+                // Note: the grace period only starts once the user could be warned
+                if mailer.send(&templates::render(&warning, &u)).is_err() {
+                    continue;
+                }
+                repository
+                    .set_attribute(u.get_id(), WARNED_ATTRIBUTE, &now.to_rfc3339())
+                    .map_err(|_| AuthError::InactivityError)?;
+                let _ = audit::record(
+                    audit_repository,
+                    Some(u.get_id()),
+                    AuditEvent::InactivityWarningSent,
+                    Some(format!("inactive for {} days", inactive_days)),
+                );
+                report.warned.push(u.get_email());
+            }
+            Some(warned_at) if now - warned_at >= Duration::days(i64::from(policy.grace_days)) => {
+                if dry_run {
+                    report.disabled.push(u.get_email());
+                    continue;
+                }
+                let reason = HoldReason::Inactivity {
+                    days: inactive_days,
+                };
+                // Note: not reported if the account was placed on hold meanwhile (e.g. by
+                //       another instance), it wasn't disabled by this run
+                if hold::_place_hold(&u.get_email(), reason, repository, audit_repository)? {
+                    let _ = repository.remove_attribute(u.get_id(), WARNED_ATTRIBUTE);
+                    report.disabled.push(u.get_email());
+                }
+            }
+            Some(_) => {}
+        }
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::db::models::AccountKind;
+    use crate::db::repository::{MockSQliteAuditRepository, MockSQliteUserRepository};
+    use crate::errors::UserDBError;
+    use crate::mail::MockConsoleMailer;
+    use std::collections::HashMap;
+
+    fn policy() -> InactivityConfig {
+        InactivityConfig {
+            after_days: 180,
+            grace_days: 30,
+        }
+    }
+
+    fn audit_mock() -> MockSQliteAuditRepository {
+        let mut mock = MockSQliteAuditRepository::new();
+        mock.expect_create_entry().returning(|_, _, _| Ok(()));
+        mock
+    }
+
+    /// Repository with a single user having the given attributes
+    fn repository_with(attributes: Vec<(&'static str, String)>) -> MockSQliteUserRepository {
+        let mut mock = MockSQliteUserRepository::new();
+        mock.expect_iter_users().returning(|_| {
+            Box::new(vec![Ok(User::new("email@email.test", "passwd_hash"))].into_iter())
+        });
+        mock.expect_get_user()
+            .returning(|_| Ok(User::new("email@email.test", "passwd_hash")));
+        mock.expect_get_attributes().returning(move |_| {
+            Ok(attributes
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.clone()))
+                .collect::<HashMap<_, _>>())
+        });
+        mock
+    }
+
+    #[test]
+    fn test_inactive_user_is_warned() {
+        let now = Utc::now();
+        let mut repository = repository_with(vec![(
+            LAST_LOGIN_ATTRIBUTE,
+            (now - Duration::days(200)).to_rfc3339(),
+        )]);
+        let mut mailer = MockConsoleMailer::new();
+
+        mailer
+            .expect_send()
+            .withf(|e| e.body.contains("200 days") && e.body.contains("30 days"))
+            .times(1)
+            .returning(|_| Ok(()));
+        repository
+            .expect_set_attribute()
+            .withf(|_, a, _| a == WARNED_ATTRIBUTE)
+            .times(1)
+            .returning(|_, _, _| Ok(()));
+
+        let report =
+            _disable_inactive_accounts(&repository, &audit_mock(), &mailer, &policy(), now, false);
+
+        assert_eq!(
+            report,
+            Ok(InactivityReport {
+                warned: vec!["email@email.test".to_string()],
+                disabled: vec![],
+            })
+        );
+    }
+
+    #[test]
+    fn test_inactive_user_is_disabled_after_grace_period() {
+        let now = Utc::now();
+        let mut repository = repository_with(vec![
+            (
+                LAST_LOGIN_ATTRIBUTE,
+                (now - Duration::days(220)).to_rfc3339(),
+            ),
+            (WARNED_ATTRIBUTE, (now - Duration::days(31)).to_rfc3339()),
+        ]);
+        let mut mailer = MockConsoleMailer::new();
+
+        mailer.expect_send().times(0);
+        repository
+            .expect_set_attribute()
+            .withf(|_, a, _| a.starts_with(hold::HOLD_ATTRIBUTE))
+            .times(2)
+            .returning(|_, _, _| Ok(()));
+        repository
+            .expect_remove_attribute()
+            .withf(|_, a| a == WARNED_ATTRIBUTE)
+            .times(1)
+            .returning(|_, _| Ok(()));
+
+        let report =
+            _disable_inactive_accounts(&repository, &audit_mock(), &mailer, &policy(), now, false);
+
+        assert_eq!(
+            report,
+            Ok(InactivityReport {
+                warned: vec![],
+                disabled: vec!["email@email.test".to_string()],
+            })
+        );
+    }
+
+    #[test]
+    fn test_user_gone_meanwhile_is_not_reported() {
+        let now = Utc::now();
+        let attributes: HashMap<String, String> = vec![
+            (
+                LAST_LOGIN_ATTRIBUTE.to_string(),
+                (now - Duration::days(220)).to_rfc3339(),
+            ),
+            (
+                WARNED_ATTRIBUTE.to_string(),
+                (now - Duration::days(31)).to_rfc3339(),
+            ),
+        ]
+        .into_iter()
+        .collect();
+        let mut repository = MockSQliteUserRepository::new();
+        repository.expect_iter_users().returning(|_| {
+            Box::new(vec![Ok(User::new("email@email.test", "passwd_hash"))].into_iter())
+        });
+        repository
+            .expect_get_attributes()
+            .returning(move |_| Ok(attributes.clone()));
+        // e.g. the account was erased since it was listed, no hold is placed
+        repository
+            .expect_get_user()
+            .returning(|_| Err(UserDBError::GetUserError));
+        repository.expect_set_attribute().times(0);
+        repository.expect_remove_attribute().times(0);
+
+        let report = _disable_inactive_accounts(
+            &repository,
+            &audit_mock(),
+            &MockConsoleMailer::new(),
+            &policy(),
+            now,
+            false,
+        );
+
+        assert_eq!(report, Ok(InactivityReport::default()));
+    }
+
+    #[test]
+    fn test_warned_user_who_logged_in_is_kept() {
+        let now = Utc::now();
+        let mut repository = repository_with(vec![
+            (LAST_LOGIN_ATTRIBUTE, (now - Duration::days(2)).to_rfc3339()),
+            (WARNED_ATTRIBUTE, (now - Duration::days(40)).to_rfc3339()),
+        ]);
+        let mut mailer = MockConsoleMailer::new();
+
+        mailer.expect_send().times(0);
+        repository.expect_set_attribute().times(0);
+        repository
+            .expect_remove_attribute()
+            .withf(|_, a| a == WARNED_ATTRIBUTE)
+            .times(1)
+            .returning(|_, _| Ok(()));
+
+        let report =
+            _disable_inactive_accounts(&repository, &audit_mock(), &mailer, &policy(), now, false);
+
+        assert_eq!(report, Ok(InactivityReport::default()));
+    }
+
+    #[test]
+    fn test_clock_starts_for_users_who_never_logged_in() {
+        let now = Utc::now();
+        let mut repository = repository_with(vec![]);
+        let mut mailer = MockConsoleMailer::new();
+
+        mailer.expect_send().times(0);
+        repository
+            .expect_set_attribute()
+            .withf(move |_, a, v| a == LAST_LOGIN_ATTRIBUTE && *v == now.to_rfc3339())
+            .times(1)
+            .returning(|_, _, _| Ok(()));
+
+        let report =
+            _disable_inactive_accounts(&repository, &audit_mock(), &mailer, &policy(), now, false);
+
+        assert_eq!(report, Ok(InactivityReport::default()));
+    }
+
+    #[test]
+    fn test_dry_run_changes_nothing() {
+        let now = Utc::now();
+        let mut mailer = MockConsoleMailer::new();
+        mailer.expect_send().times(0);
+
+        let mut repository = repository_with(vec![(
+            LAST_LOGIN_ATTRIBUTE,
+            (now - Duration::days(200)).to_rfc3339(),
+        )]);
+        repository.expect_set_attribute().times(0);
+        let report =
+            _disable_inactive_accounts(&repository, &audit_mock(), &mailer, &policy(), now, true);
+        assert_eq!(
+            report,
+            Ok(InactivityReport {
+                warned: vec!["email@email.test".to_string()],
+                disabled: vec![],
+            })
+        );
+
+        let mut repository = repository_with(vec![
+            (
+                LAST_LOGIN_ATTRIBUTE,
+                (now - Duration::days(220)).to_rfc3339(),
+            ),
+            (WARNED_ATTRIBUTE, (now - Duration::days(31)).to_rfc3339()),
+        ]);
+        repository.expect_set_attribute().times(0);
+        repository.expect_remove_attribute().times(0);
+        let report =
+            _disable_inactive_accounts(&repository, &audit_mock(), &mailer, &policy(), now, true);
+        assert_eq!(
+            report,
+            Ok(InactivityReport {
+                warned: vec![],
+                disabled: vec!["email@email.test".to_string()],
+            })
+        );
+
+        let mut repository = repository_with(vec![]);
+        repository.expect_set_attribute().times(0);
+        let report =
+            _disable_inactive_accounts(&repository, &audit_mock(), &mailer, &policy(), now, true);
+        assert_eq!(report, Ok(InactivityReport::default()));
+    }
+
+    #[test]
+    fn test_service_accounts_are_left_out() {
+        let mut repository = MockSQliteUserRepository::new();
+        let mut mailer = MockConsoleMailer::new();
+
+        repository.expect_iter_users().returning(|_| {
+            let mut u = User::new("ci@email.test", "passwd_hash");
+            u.set_kind(AccountKind::Service);
+            Box::new(vec![Ok(u)].into_iter())
+        });
+        repository.expect_get_attributes().times(0);
+        repository.expect_set_attribute().times(0);
+        mailer.expect_send().times(0);
+
+        let report = _disable_inactive_accounts(
+            &repository,
+            &audit_mock(),
+            &mailer,
+            &policy(),
+            Utc::now(),
+            false,
+        );
+
+        assert_eq!(report, Ok(InactivityReport::default()));
+    }
+
+    #[test]
+    fn test_disabled_policy() {
+        let mut repository = MockSQliteUserRepository::new();
+        repository.expect_iter_users().times(0);
+
+        let report = _disable_inactive_accounts(
+            &repository,
+            &audit_mock(),
+            &MockConsoleMailer::new(),
+            &InactivityConfig::default(),
+            Utc::now(),
+            false,
+        );
+
+        assert_eq!(report, Ok(InactivityReport::default()));
+    }
+}
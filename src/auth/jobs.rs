@@ -0,0 +1,130 @@
+/*!
+ * Maintenance jobs run by one instance at a time
+ *
+ * When several instances share the database, each one may run the periodic
+ * jobs (e.g. `secure-auth inactivity` scheduled on every host). A job first
+ * acquires its lease in the database (see `LeaseRepository`): only one run
+ * holds it, the others are refused with `AuthError::JobAlreadyRunning`. The
+ * lease is released once the job is over, or expires after `LEASE_TTL_SECS` if its
+ * holder crashed.
+ *
+ * # Note
+ * A run longer than `LEASE_TTL_SECS` can overlap with the next one, the jobs must be
+ * shorter than it.
+ *
+ * # Author
+ * Doran Kayoumi <doran.kayoumi@heig-vd.ch>
+ */
+
+use chrono::{DateTime, Duration, Utc};
+
+use crate::db::repository::{LeaseRepository, SQliteLeaseRepository};
+use crate::errors::AuthError;
+use crate::utils;
+
+/// How long (in seconds) a run holds the lease of its job, at most
+pub const LEASE_TTL_SECS: i64 = 3600;
+
+/// Public function for running a job on one instance at a time
+/// See `_run_exclusive` for more info
+///
+pub fn run_exclusive<T>(
+    job: &str,
+    run: impl FnOnce() -> Result<T, AuthError>,
+) -> Result<T, AuthError> {
+    _run_exclusive(job, &SQliteLeaseRepository::new(), Utc::now(), run)
+}
+
+/// Run a job if no other instance is running it
+/// Note: the job ran anyway, its outcome is returned even if the lease can't be released
+///       (it expires on its own)
+///
+/// # Arguments
+///
+/// * `job` - the name of the job
+///
+/// * `repository` - where the leases are kept
+///
+/// * `now` - the current date & time
+///
+/// * `run` - the job
+///
+fn _run_exclusive<T>(
+    job: &str,
+    repository: &dyn LeaseRepository,
+    now: DateTime<Utc>,
+    run: impl FnOnce() -> Result<T, AuthError>,
+) -> Result<T, AuthError> {
+    let holder = utils::gen_token();
+    let acquired = repository
+        .acquire(job, &holder, now, Duration::seconds(LEASE_TTL_SECS))
+        .map_err(|_| AuthError::JobLeaseError)?;
+    if !acquired {
+        return Err(AuthError::JobAlreadyRunning);
+    }
+
+    let outcome = run();
+    let _ = repository.release(job, &holder);
+
+    outcome
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::db::repository::MockSQliteLeaseRepository;
+    use crate::errors::LeaseDBError;
+
+    #[test]
+    fn test_run_exclusive() {
+        let mut repository = MockSQliteLeaseRepository::new();
+        repository
+            .expect_acquire()
+            .withf(|j, _, _, ttl| j == "job" && *ttl == Duration::seconds(LEASE_TTL_SECS))
+            .times(1)
+            .returning(|_, _, _, _| Ok(true));
+        repository
+            .expect_release()
+            .withf(|j, _| j == "job")
+            .times(1)
+            .returning(|_, _| Ok(()));
+
+        assert_eq!(
+            _run_exclusive("job", &repository, Utc::now(), || Ok(42)),
+            Ok(42)
+        );
+    }
+
+    #[test]
+    fn test_run_exclusive_with_job_running_elsewhere() {
+        let mut repository = MockSQliteLeaseRepository::new();
+        repository
+            .expect_acquire()
+            .returning(|_, _, _, _| Ok(false));
+        repository.expect_release().times(0);
+
+        assert_eq!(
+            _run_exclusive("job", &repository, Utc::now(), || -> Result<(), _> {
+                panic!("the job mustn't run")
+            }),
+            Err(AuthError::JobAlreadyRunning)
+        );
+    }
+
+    #[test]
+    fn test_run_exclusive_releases_a_failed_job() {
+        let mut repository = MockSQliteLeaseRepository::new();
+        repository.expect_acquire().returning(|_, _, _, _| Ok(true));
+        repository
+            .expect_release()
+            .times(1)
+            .returning(|_, _| Err(LeaseDBError::ReleaseError));
+
+        assert_eq!(
+            _run_exclusive("job", &repository, Utc::now(), || -> Result<(), _> {
+                Err(AuthError::InactivityError)
+            }),
+            Err(AuthError::InactivityError)
+        );
+    }
+}
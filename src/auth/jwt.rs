@@ -0,0 +1,569 @@
+/*!
+ * Tokens issued after a login, for the services downstream
+ *
+ * Once a login went through (the password &, if the user set one up, the
+ * second factor), the application can hand out a JWT: the services it calls
+ * check it with `verify_jwt` rather than asking the system who the user is.
+ * A token is only issued for the outcome of a login (`LoginOutcome::Authenticated`
+ * or `CompletedLogin`, see `LoginProof`), its claims are taken from it: the id
+ * & the email of the user and whether the login was confirmed with a second
+ * factor. It's valid for `[jwt] ttl_secs`.
+ *
+ * The tokens are signed with HMAC-SHA256 (`HS256`) keyed with `JWT_SECRET`
+ * (in the environment or the `.env` file), only the services holding the key
+ * can check them. The tokens of any other algorithm (e.g. `none`) are refused.
+ *
+ * # Note
+ * There's no list of the tokens issued, so logging out doesn't revoke one.
+ * Instead, `verify_jwt` reads the account again: the tokens of an account
+ * placed on security hold (see `hold.rs`) or deleted are refused from then
+ * on, & no token is issued to an account on hold. The services checking the
+ * tokens without the database (with the key only) can't tell, keep `ttl_secs`
+ * short for them.
+ *
+ * # Author
+ * Doran Kayoumi <doran.kayoumi@heig-vd.ch>
+ */
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::{DateTime, Duration, Utc};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::env;
+
+use super::hold;
+use super::login::{CompletedLogin, LoginOutcome};
+use crate::config::{self, JwtConfig};
+use crate::db::models::User;
+use crate::db::repository::{SQliteUserRepository, UserRepository};
+use crate::errors::AuthError;
+use crate::utils;
+
+/// Variable holding the signing key
+pub const SECRET_VARIABLE: &str = "JWT_SECRET";
+/// Minimum length of the key
+pub const MIN_SECRET_LEN: usize = 32;
+/// Longest token checked (in bytes), the tokens of the system are far shorter
+const MAX_JWT_BYTES: usize = 2048;
+/// The only algorithm the tokens are signed with
+const ALGORITHM: &str = "HS256";
+
+/// Content of a token
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Claims {
+    /// Id of the user, a string as the standard wants it (see `user_id`)
+    pub sub: String,
+    pub email: String,
+    /// Whether the login was confirmed with a second factor
+    pub two_factor: bool,
+    pub iss: String,
+    /// When the token was issued, in seconds since the epoch
+    pub iat: i64,
+    /// When the token expires, in seconds since the epoch
+    pub exp: i64,
+}
+
+impl Claims {
+    pub fn user_id(&self) -> Option<i32> {
+        self.sub.parse().ok()
+    }
+}
+
+/// Header of a token, only the algorithm is checked
+#[derive(Serialize, Deserialize)]
+struct Header {
+    alg: String,
+    /// Note: optional in the standard
+    #[serde(default)]
+    typ: String,
+}
+
+mod sealed {
+    /// Only the outcomes of the login flows are proofs, see `LoginProof`
+    pub trait Sealed {}
+
+    impl Sealed for super::LoginOutcome {}
+    impl Sealed for super::CompletedLogin {}
+}
+
+/// Outcome of a login a token can be issued for
+///
+/// Only the login flows create one (`begin_login`, `confirm_login` & `complete_2fa`),
+/// so a token can't be issued for a login that didn't happen. A proof can't be
+/// built by hand:
+///
+/// ```compile_fail
+/// use secure_auth::auth::login::CompletedLogin;
+/// use secure_auth::db::models::User;
+///
+/// let proof = CompletedLogin {
+///     user: User::new("alice@example.com", "passwd_hash"),
+///     factor: "totp",
+///     recovery: None,
+/// };
+/// ```
+///
+/// ```compile_fail
+/// use secure_auth::auth::login::{AuthenticatedLogin, LoginOutcome};
+/// use secure_auth::db::models::User;
+///
+/// let proof = LoginOutcome::Authenticated(AuthenticatedLogin {
+///     user: User::new("alice@example.com", "passwd_hash"),
+/// });
+/// ```
+///
+/// nor can another type be one:
+///
+/// ```compile_fail
+/// use secure_auth::auth::jwt::LoginProof;
+/// use secure_auth::db::models::User;
+///
+/// struct Forged(User);
+///
+/// impl LoginProof for Forged {
+///     fn authenticated(&self) -> Option<(&User, bool)> {
+///         Some((&self.0, true))
+///     }
+/// }
+/// ```
+pub trait LoginProof: sealed::Sealed {
+    /// The user who logged in & whether a second factor was used, none if the
+    /// login isn't complete yet
+    fn authenticated(&self) -> Option<(&User, bool)>;
+}
+
+impl LoginProof for LoginOutcome {
+    fn authenticated(&self) -> Option<(&User, bool)> {
+        match self {
+            LoginOutcome::Authenticated(login) => Some((login.get_user(), false)),
+            LoginOutcome::TwoFactorRequired(_) => None,
+        }
+    }
+}
+
+impl LoginProof for CompletedLogin {
+    fn authenticated(&self) -> Option<(&User, bool)> {
+        Some((&self.user, true))
+    }
+}
+
+/// Public function for the issuing of a token
+/// See `_issue_jwt` for more info
+///
+pub fn issue_jwt(login: &dyn LoginProof) -> Result<String, AuthError> {
+    issue_jwt_with_repository(login, &SQliteUserRepository::new())
+}
+
+/// Same as `issue_jwt`, with the users of a given storage (e.g. `PostgresUserRepository`)
+pub fn issue_jwt_with_repository(
+    login: &dyn LoginProof,
+    repository: &dyn UserRepository,
+) -> Result<String, AuthError> {
+    _issue_jwt(
+        login,
+        repository,
+        &secret()?,
+        &config::get().jwt,
+        Utc::now(),
+    )
+}
+
+/// Public function for the check of a token
+/// See `_verify_jwt` for more info
+///
+pub fn verify_jwt(token: &str) -> Result<Claims, AuthError> {
+    verify_jwt_with_repository(token, &SQliteUserRepository::new())
+}
+
+/// Same as `verify_jwt`, with the users of a given storage (e.g. `PostgresUserRepository`)
+pub fn verify_jwt_with_repository(
+    token: &str,
+    repository: &dyn UserRepository,
+) -> Result<Claims, AuthError> {
+    _verify_jwt(
+        token,
+        repository,
+        &secret()?,
+        &config::get().jwt,
+        Utc::now(),
+    )
+}
+
+/// Get the signing key, a missing or too short one is an error
+fn secret() -> Result<String, AuthError> {
+    dotenv::dotenv().ok();
+    env::var(SECRET_VARIABLE)
+        .ok()
+        .filter(|s| s.len() >= MIN_SECRET_LEN)
+        .ok_or(AuthError::JwtError)
+}
+
+/// Start the signature of the signed part of a token
+///
+/// # Arguments
+///
+/// * `signed` - the encoded header & claims, `<header>.<claims>`
+///
+/// * `secret` - the signing key
+///
+fn mac(signed: &str, secret: &str) -> Result<Hmac<Sha256>, AuthError> {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).map_err(|_| AuthError::JwtError)?;
+    mac.update(signed.as_bytes());
+
+    Ok(mac)
+}
+
+/// Issue a token to a user whose login went through, unless her/his account was
+/// placed on hold since
+/// Returns the token, `<header>.<claims>.<signature>`
+///
+/// # Arguments
+///
+/// * `login` - the outcome of the login (`LoginOutcome::Authenticated` or the `CompletedLogin` of `complete_2fa`)
+///
+/// * `repository` - the user repository to interact with
+///
+/// * `secret` - the signing key
+///
+/// * `config` - the validity & the issuer of the tokens
+///
+/// * `now` - when the token is issued
+///
+fn _issue_jwt(
+    login: &dyn LoginProof,
+    repository: &dyn UserRepository,
+    secret: &str,
+    config: &JwtConfig,
+    now: DateTime<Utc>,
+) -> Result<String, AuthError> {
+    let (u, two_factor) = login.authenticated().ok_or(AuthError::LoginIncomplete)?;
+    if hold::is_on_hold(u.get_id(), repository)? {
+        return Err(AuthError::AccountOnHold);
+    }
+
+    let header = Header {
+        alg: ALGORITHM.to_string(),
+        typ: "JWT".to_string(),
+    };
+    let claims = Claims {
+        sub: u.get_id().to_string(),
+        email: u.get_email(),
+        two_factor,
+        iss: config.issuer.clone(),
+        iat: now.timestamp(),
+        exp: (now + Duration::seconds(config.ttl_secs)).timestamp(),
+    };
+
+    let signed = format!("{}.{}", encode(&header)?, encode(&claims)?);
+    let signature = mac(&signed, secret)?.finalize().into_bytes();
+
+    Ok(format!("{}.{}", signed, URL_SAFE_NO_PAD.encode(signature)))
+}
+
+/// Check a token & read its claims
+/// The token of an account deleted or placed on hold since it was issued is refused
+///
+/// # Arguments
+///
+/// * `token` - the token, as issued by `issue_jwt`
+///
+/// * `repository` - the user repository to interact with
+///
+/// * `secret` - the signing key
+///
+/// * `config` - the issuer of the tokens
+///
+/// * `now` - when the token is checked
+///
+fn _verify_jwt(
+    token: &str,
+    repository: &dyn UserRepository,
+    secret: &str,
+    config: &JwtConfig,
+    now: DateTime<Utc>,
+) -> Result<Claims, AuthError> {
+    utils::check_length(token, MAX_JWT_BYTES)?;
+
+    let parts: Vec<&str> = token.trim().split('.').collect();
+    let (header, claims, signature) = match parts.as_slice() {
+        [header, claims, signature] => (*header, *claims, *signature),
+        _ => return Err(AuthError::InvalidJwt),
+    };
+
+    // Note: the algorithm is checked before the signature, so a token can't pick a weaker one
+    if decode::<Header>(header)?.alg != ALGORITHM {
+        return Err(AuthError::InvalidJwt);
+    }
+    let signature = URL_SAFE_NO_PAD
+        .decode(signature)
+        .map_err(|_| AuthError::InvalidJwt)?;
+    mac(&format!("{}.{}", header, claims), secret)?
+        .verify_slice(&signature)
+        .map_err(|_| AuthError::InvalidJwt)?;
+
+    let claims: Claims = decode(claims)?;
+    if claims.iss != config.issuer {
+        return Err(AuthError::InvalidJwt);
+    }
+    if claims.exp <= now.timestamp() {
+        return Err(AuthError::JwtExpired);
+    }
+
+    // Note: an account deleted & another one created with the same email has another id
+    let u = repository
+        .get_user(&claims.email)
+        .map_err(|e| e.to_auth_error(AuthError::InvalidJwt))?;
+    if claims.user_id() != Some(u.get_id()) {
+        return Err(AuthError::InvalidJwt);
+    }
+    if hold::is_on_hold(u.get_id(), repository)? {
+        return Err(AuthError::AccountOnHold);
+    }
+
+    Ok(claims)
+}
+
+/// Encode a part of a token
+///
+/// # Arguments
+///
+/// * `part` - the header or the claims
+///
+fn encode<T: Serialize>(part: &T) -> Result<String, AuthError> {
+    let json = serde_json::to_vec(part).map_err(|_| AuthError::JwtError)?;
+
+    Ok(URL_SAFE_NO_PAD.encode(json))
+}
+
+/// Decode a part of a token
+///
+/// # Arguments
+///
+/// * `part` - the part, JSON encoded in base64
+///
+fn decode<T: for<'de> Deserialize<'de>>(part: &str) -> Result<T, AuthError> {
+    let json = URL_SAFE_NO_PAD
+        .decode(part)
+        .map_err(|_| AuthError::InvalidJwt)?;
+
+    serde_json::from_slice(&json).map_err(|_| AuthError::InvalidJwt)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::auth::login::AuthenticatedLogin;
+    use crate::db::repository::InMemoryUserRepository;
+    use chrono::TimeZone;
+
+    const SECRET: &str = "0123456789abcdef0123456789abcdef";
+
+    fn now() -> DateTime<Utc> {
+        Utc.ymd(2021, 4, 28).and_hms(12, 0, 0)
+    }
+
+    /// Repository holding the user of the tokens
+    fn repository() -> InMemoryUserRepository {
+        let repository = InMemoryUserRepository::new();
+        repository
+            .create_user("alice@email.test", "passwd_hash")
+            .unwrap();
+        repository
+    }
+
+    fn user(repository: &dyn UserRepository) -> User {
+        repository.get_user("alice@email.test").unwrap()
+    }
+
+    fn login(repository: &dyn UserRepository, two_factor: bool) -> Box<dyn LoginProof> {
+        let u = user(repository);
+        if two_factor {
+            Box::new(CompletedLogin {
+                user: u,
+                factor: "totp",
+                recovery: None,
+            })
+        } else {
+            Box::new(LoginOutcome::Authenticated(AuthenticatedLogin { user: u }))
+        }
+    }
+
+    fn token(repository: &dyn UserRepository, two_factor: bool) -> String {
+        _issue_jwt(
+            login(repository, two_factor).as_ref(),
+            repository,
+            SECRET,
+            &JwtConfig::default(),
+            now(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_issue_and_verify() {
+        let repository = repository();
+        let claims = _verify_jwt(
+            &token(&repository, true),
+            &repository,
+            SECRET,
+            &JwtConfig::default(),
+            now(),
+        )
+        .unwrap();
+
+        assert_eq!(claims.user_id(), Some(user(&repository).get_id()));
+        assert_eq!(claims.email, "alice@email.test");
+        assert!(claims.two_factor);
+        assert_eq!(claims.iss, "secure-auth");
+        assert_eq!(claims.exp - claims.iat, JwtConfig::default().ttl_secs);
+
+        let claims = _verify_jwt(
+            &token(&repository, false),
+            &repository,
+            SECRET,
+            &JwtConfig::default(),
+            now(),
+        )
+        .unwrap();
+        assert!(!claims.two_factor);
+    }
+
+    #[test]
+    fn test_held_accounts() {
+        let repository = repository();
+        let token = token(&repository, false);
+        repository
+            .set_attribute(user(&repository).get_id(), hold::HOLD_ATTRIBUTE, "active")
+            .unwrap();
+
+        // no token is issued anymore, & the one issued before is refused
+        assert_eq!(
+            _issue_jwt(
+                login(&repository, true).as_ref(),
+                &repository,
+                SECRET,
+                &JwtConfig::default(),
+                now()
+            ),
+            Err(AuthError::AccountOnHold)
+        );
+        assert_eq!(
+            _verify_jwt(&token, &repository, SECRET, &JwtConfig::default(), now()),
+            Err(AuthError::AccountOnHold)
+        );
+    }
+
+    #[test]
+    fn test_deleted_accounts() {
+        let repository = repository();
+        let token = token(&repository, false);
+
+        repository.delete_user(user(&repository).get_id()).unwrap();
+        assert_eq!(
+            _verify_jwt(&token, &repository, SECRET, &JwtConfig::default(), now()),
+            Err(AuthError::InvalidJwt)
+        );
+
+        // another account with the same email isn't the one of the token
+        repository
+            .create_user("alice@email.test", "passwd_hash")
+            .unwrap();
+        assert_eq!(
+            _verify_jwt(&token, &repository, SECRET, &JwtConfig::default(), now()),
+            Err(AuthError::InvalidJwt)
+        );
+    }
+
+    #[test]
+    fn test_expired_token() {
+        let repository = repository();
+        let later = now() + Duration::seconds(JwtConfig::default().ttl_secs);
+
+        assert_eq!(
+            _verify_jwt(
+                &token(&repository, false),
+                &repository,
+                SECRET,
+                &JwtConfig::default(),
+                later
+            ),
+            Err(AuthError::JwtExpired)
+        );
+    }
+
+    #[test]
+    fn test_tampered_tokens() {
+        let repository = repository();
+        let token = token(&repository, false);
+        let config = JwtConfig::default();
+        let parts: Vec<&str> = token.split('.').collect();
+
+        // claims changed to get the 2fa status
+        let forged = encode(&Claims {
+            two_factor: true,
+            ..decode::<Claims>(parts[1]).unwrap()
+        })
+        .unwrap();
+        assert_eq!(
+            _verify_jwt(
+                &format!("{}.{}.{}", parts[0], forged, parts[2]),
+                &repository,
+                SECRET,
+                &config,
+                now()
+            ),
+            Err(AuthError::InvalidJwt)
+        );
+
+        // unsigned
+        let none = encode(&Header {
+            alg: "none".to_string(),
+            typ: "JWT".to_string(),
+        })
+        .unwrap();
+        assert_eq!(
+            _verify_jwt(
+                &format!("{}.{}.", none, parts[1]),
+                &repository,
+                SECRET,
+                &config,
+                now()
+            ),
+            Err(AuthError::InvalidJwt)
+        );
+
+        // signed with another key
+        assert_eq!(
+            _verify_jwt(
+                &token,
+                &repository,
+                "another key of at least 32 characters",
+                &config,
+                now()
+            ),
+            Err(AuthError::InvalidJwt)
+        );
+
+        // issued by another issuer
+        let other = JwtConfig {
+            issuer: "another-issuer".to_string(),
+            ..JwtConfig::default()
+        };
+        assert_eq!(
+            _verify_jwt(&token, &repository, SECRET, &other, now()),
+            Err(AuthError::InvalidJwt)
+        );
+
+        assert_eq!(
+            _verify_jwt("not.a token", &repository, SECRET, &config, now()),
+            Err(AuthError::InvalidJwt)
+        );
+        assert_eq!(
+            _verify_jwt(&"a".repeat(1 << 20), &repository, SECRET, &config, now()),
+            Err(AuthError::InputTooLong)
+        );
+    }
+}
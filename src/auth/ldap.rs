@@ -0,0 +1,428 @@
+/*!
+ * Login with the password of an LDAP / Active Directory server
+ *
+ * The password of a directory user is checked by binding to the directory as
+ * her/him (see `[ldap]` in the configuration). On her/his first login, a local
+ * account is created for her/him, a "directory" account without a usable local
+ * password: it can't login with `login` nor reset its password, but it gets the
+ * rest of the login (security holds, allowed hours, risk-based policy & second
+ * factors, see `login::continue_login`) like any other account.
+ *
+ * # Note
+ * The failed logins are limited by the directory (e.g. its lockout policy), they
+ * aren't counted by the throttle of the local logins (see `throttle.rs`).
+ * An existing local account isn't taken over by a directory user with the same
+ * email, the login is refused.
+ *
+ * # Author
+ * Doran Kayoumi <doran.kayoumi@heig-vd.ch>
+ */
+
+use super::login::{self, LoginOutcome};
+use super::{maintenance, timing};
+use crate::audit::{self, AuditEvent};
+#[cfg(feature = "ldap")]
+use crate::config::{self, LdapConfig};
+use crate::db::models::{AccountKind, User, UserChangeset};
+use crate::db::repository::{
+    AuditRepository, SQliteAuditRepository, SQliteUserRepository, UserRepository,
+};
+use crate::errors::{AuthError, UserDBError};
+use crate::utils;
+use crate::validation::{Email, Password};
+
+/// Result code of a bind refused because of the credentials (RFC 4511)
+#[cfg(feature = "ldap")]
+const INVALID_CREDENTIALS: u32 = 49;
+
+/// Checks the passwords of the directory users
+pub trait Directory {
+    /// Check the password of a user by binding to the directory as her/him
+    /// Returns whether the directory accepted it, `AuthError::DirectoryUnavailable`
+    /// if it can't be reached
+    ///
+    /// # Arguments
+    ///
+    /// * `email` - the email of the user
+    ///
+    /// * `passwd` - the password of the user
+    ///
+    fn bind(&self, email: &str, passwd: &str) -> Result<bool, AuthError>;
+}
+
+/// `Directory` binding to the LDAP server of the configuration
+#[cfg(feature = "ldap")]
+pub struct LdapDirectory<'a> {
+    config: &'a LdapConfig,
+}
+
+#[cfg(feature = "ldap")]
+impl<'a> LdapDirectory<'a> {
+    pub fn new(config: &'a LdapConfig) -> Self {
+        Self { config }
+    }
+}
+
+#[cfg(feature = "ldap")]
+impl Directory for LdapDirectory<'_> {
+    fn bind(&self, email: &str, passwd: &str) -> Result<bool, AuthError> {
+        use ldap3::{LdapConn, LdapConnSettings};
+        use std::time::Duration;
+
+        let timeout = Duration::from_secs(self.config.timeout_secs);
+        let settings = LdapConnSettings::new()
+            .set_conn_timeout(timeout)
+            .set_starttls(self.config.starttls);
+        let mut conn = LdapConn::with_settings(settings, &self.config.url)
+            .map_err(|_| AuthError::DirectoryUnavailable)?;
+
+        let result = conn
+            .with_timeout(timeout)
+            .simple_bind(&bind_dn(&self.config.bind_dn, email), passwd)
+            .map_err(|_| AuthError::DirectoryUnavailable)?;
+        let _ = conn.unbind();
+
+        match result.rc {
+            0 => Ok(true),
+            INVALID_CREDENTIALS => Ok(false),
+            _ => Err(AuthError::DirectoryUnavailable),
+        }
+    }
+}
+
+/// Build the DN a user binds with
+/// The email is escaped (RFC 4514), so it can't change the DN, unless the DN is only
+/// `{email}` (a user principal name on Active Directory, which isn't parsed as a DN)
+///
+/// # Arguments
+///
+/// * `template` - the bind DN of the configuration, with `{email}` & `{user}`
+///
+/// * `email` - the email of the user
+///
+pub fn bind_dn(template: &str, email: &str) -> String {
+    let user = email.split('@').next().unwrap_or_default();
+    if template.contains('=') {
+        template
+            .replace("{email}", &escape_dn_value(email))
+            .replace("{user}", &escape_dn_value(user))
+    } else {
+        template.replace("{email}", email).replace("{user}", user)
+    }
+}
+
+/// Escape a value of a DN (RFC 4514)
+///
+/// # Arguments
+///
+/// * `value` - the value to escape
+///
+fn escape_dn_value(value: &str) -> String {
+    let last = value.chars().count().saturating_sub(1);
+    value
+        .chars()
+        .enumerate()
+        .map(|(i, c)| match c {
+            '"' | '+' | ',' | ';' | '<' | '>' | '\\' | '=' => format!("\\{}", c),
+            '#' | ' ' if i == 0 => format!("\\{}", c),
+            ' ' if i == last => "\\ ".to_string(),
+            '\0' => "\\00".to_string(),
+            c => c.to_string(),
+        })
+        .collect()
+}
+
+/// Public function for the login of a directory user
+/// See `login_ldap_with` for more info
+///
+/// # Note
+/// `AuthError::DirectoryUnavailable` is returned if no LDAP server is set
+///
+#[cfg(feature = "ldap")]
+pub fn login_ldap(email: &Email, passwd: &Password) -> Result<LoginOutcome, AuthError> {
+    let config = config::get()
+        .ldap
+        .as_ref()
+        .ok_or(AuthError::DirectoryUnavailable)?;
+    login_ldap_with(&LdapDirectory::new(config), email, passwd)
+}
+
+/// Same as `login_ldap`, with the passwords checked by a given directory
+/// The users who set up a second factor get a challenge to complete with `login::complete_2fa`
+/// Note: whatever the outcome, it lasts at least the minimum response time (see `timing.rs`)
+pub fn login_ldap_with(
+    directory: &dyn Directory,
+    email: &Email,
+    passwd: &Password,
+) -> Result<LoginOutcome, AuthError> {
+    timing::padded(|| {
+        let repository = SQliteUserRepository::new();
+        let u = _login_ldap(
+            email,
+            passwd.as_str(),
+            directory,
+            &repository,
+            &SQliteAuditRepository::new(),
+        )?;
+        login::continue_login(u, &repository)
+    })
+}
+
+/// Check the password of a directory user & get her/his account, created on her/his first login
+///
+/// # Arguments
+///
+/// * `email` - the email of the user trying to login
+///
+/// * `passwd` - the password of the user trying to login
+///
+/// * `directory` - the directory checking the password
+///
+/// * `repository` - the user repository to interact with
+///
+/// * `audit_repository` - the audit repository to write in
+///
+fn _login_ldap(
+    email: &str,
+    passwd: &str,
+    directory: &dyn Directory,
+    repository: &dyn UserRepository,
+    audit_repository: &dyn AuditRepository,
+) -> Result<User, AuthError> {
+    // Note: a bind without a password is an anonymous one, most directories accept it
+    //       whatever the DN, so it's never sent
+    if passwd.is_empty() || !directory.bind(email, passwd)? {
+        return Err(AuthError::LoginError);
+    }
+
+    match repository.get_user(email) {
+        Ok(u) if u.get_kind() == AccountKind::Directory => Ok(u),
+        Ok(_) => Err(AuthError::LoginError),
+        Err(UserDBError::Timeout) => Err(AuthError::Timeout),
+        Err(_) => provision(email, repository, audit_repository),
+    }
+}
+
+/// Create the account of a directory user
+/// Note: its local password is random & never known, it can't be used anyway
+///
+/// # Arguments
+///
+/// * `email` - the email of the user
+///
+/// * `repository` - the user repository to interact with
+///
+/// * `audit_repository` - the audit repository to write in
+///
+fn provision(
+    email: &str,
+    repository: &dyn UserRepository,
+    audit_repository: &dyn AuditRepository,
+) -> Result<User, AuthError> {
+    maintenance::check_writable()?;
+
+    let pwh = utils::hash(&utils::gen_token()).ok_or(AuthError::DirectoryProvisioningError)?;
+    repository
+        .create_user(email, &pwh)
+        .map_err(|e| e.to_auth_error(AuthError::DirectoryProvisioningError))?;
+    let mut u = repository
+        .get_user(email)
+        .map_err(|e| e.to_auth_error(AuthError::DirectoryProvisioningError))?;
+
+    let changes = UserChangeset::new().kind(AccountKind::Directory);
+    if let Err(e) = repository.patch_user(u.get_id(), &changes) {
+        // Note: removed, a local account left behind would refuse the next logins
+        let _ = repository.delete_user(u.get_id());
+        return Err(e.to_auth_error(AuthError::DirectoryProvisioningError));
+    }
+    u.set_kind(AccountKind::Directory);
+
+    let _ = audit::record(
+        audit_repository,
+        Some(u.get_id()),
+        AuditEvent::DirectoryAccountProvisioned,
+        None,
+    );
+
+    Ok(u)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::auth::reset;
+    use crate::db::ephemeral::InMemoryTokenStore;
+    use crate::db::repository::{InMemoryUserRepository, MockSQliteAuditRepository};
+    use crate::mail::capture::CapturingMailer;
+
+    /// Directory accepting a single password
+    struct StaticDirectory {
+        passwd: &'static str,
+        reachable: bool,
+    }
+
+    impl Directory for StaticDirectory {
+        fn bind(&self, _: &str, passwd: &str) -> Result<bool, AuthError> {
+            if !self.reachable {
+                return Err(AuthError::DirectoryUnavailable);
+            }
+            Ok(passwd == self.passwd)
+        }
+    }
+
+    fn directory() -> StaticDirectory {
+        StaticDirectory {
+            passwd: "directory passwd",
+            reachable: true,
+        }
+    }
+
+    fn audit(times: usize) -> MockSQliteAuditRepository {
+        let mut mock = MockSQliteAuditRepository::new();
+        mock.expect_create_entry()
+            .withf(|u, e, _| *u == Some(1) && e == "DirectoryAccountProvisioned")
+            .times(times)
+            .returning(|_, _, _| Ok(()));
+        mock
+    }
+
+    #[test]
+    fn test_bind_dn() {
+        assert_eq!(
+            bind_dn("uid={user},ou=people,dc=example,dc=com", "alice@email.test"),
+            "uid=alice,ou=people,dc=example,dc=com"
+        );
+        assert_eq!(
+            bind_dn("{email}", "alice+ad@email.test"),
+            "alice+ad@email.test"
+        );
+        // the email can't add a component to the DN
+        assert_eq!(
+            bind_dn("mail={email},dc=example,dc=com", "a,dc=evil@email.test"),
+            "mail=a\\,dc\\=evil@email.test,dc=example,dc=com"
+        );
+        assert_eq!(escape_dn_value(" #a b "), "\\ #a b\\ ");
+        assert_eq!(escape_dn_value("#a"), "\\#a");
+    }
+
+    #[test]
+    fn test_first_login_provisions_the_account() {
+        let repository = InMemoryUserRepository::new();
+
+        let u = _login_ldap(
+            "alice@email.test",
+            "directory passwd",
+            &directory(),
+            &repository,
+            &audit(1),
+        )
+        .unwrap();
+        assert_eq!(u.get_kind(), AccountKind::Directory);
+        assert_eq!(
+            UserRepository::get_user(&repository, "alice@email.test").map(|u| u.get_kind()),
+            Ok(AccountKind::Directory)
+        );
+
+        // the account is reused by the next logins
+        assert_eq!(
+            _login_ldap(
+                "alice@email.test",
+                "directory passwd",
+                &directory(),
+                &repository,
+                &audit(0),
+            )
+            .map(|u| u.get_id()),
+            Ok(u.get_id())
+        );
+    }
+
+    #[test]
+    fn test_login_with_wrong_password() {
+        let repository = InMemoryUserRepository::new();
+
+        for passwd in ["wrong passwd", ""] {
+            assert_eq!(
+                _login_ldap(
+                    "alice@email.test",
+                    passwd,
+                    &directory(),
+                    &repository,
+                    &audit(0),
+                ),
+                Err(AuthError::LoginError)
+            );
+        }
+        // nothing is provisioned
+        assert!(UserRepository::get_user(&repository, "alice@email.test").is_err());
+    }
+
+    #[test]
+    fn test_login_with_unreachable_directory() {
+        let directory = StaticDirectory {
+            reachable: false,
+            ..directory()
+        };
+
+        assert_eq!(
+            _login_ldap(
+                "alice@email.test",
+                "directory passwd",
+                &directory,
+                &InMemoryUserRepository::new(),
+                &audit(0),
+            ),
+            Err(AuthError::DirectoryUnavailable)
+        );
+    }
+
+    #[test]
+    fn test_local_account_is_not_taken_over() {
+        let repository = InMemoryUserRepository::new();
+        UserRepository::create_user(&repository, "alice@email.test", "passwd_hash").unwrap();
+
+        assert_eq!(
+            _login_ldap(
+                "alice@email.test",
+                "directory passwd",
+                &directory(),
+                &repository,
+                &audit(0),
+            ),
+            Err(AuthError::LoginError)
+        );
+    }
+
+    #[test]
+    fn test_directory_account_has_no_local_password() {
+        let repository = InMemoryUserRepository::new();
+        _login_ldap(
+            "alice@email.test",
+            "directory passwd",
+            &directory(),
+            &repository,
+            &audit(1),
+        )
+        .unwrap();
+        let email = Email::parse("alice@email.test").unwrap();
+
+        assert_eq!(
+            login::login_with_repository(
+                &email,
+                &Password::parse("directory passwd").unwrap(),
+                &repository
+            ),
+            Err(AuthError::LoginError)
+        );
+        assert_eq!(
+            reset::generate_reset_token_with(
+                &email,
+                &repository,
+                &InMemoryTokenStore::new(),
+                &CapturingMailer::new(),
+                "0123456789abcdef0123456789abcdef"
+            ),
+            Err(AuthError::ResetError)
+        );
+    }
+}
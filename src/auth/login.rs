@@ -1,24 +1,448 @@
 /*!
  * Functions related to login
  *
+ * The users with 2fa enabled login in two phases: `begin_login` checks the
+ * password & returns a `TwoFactorChallenge`, `complete_2fa` checks the code
+ * of the second factor chosen by the user (see `factor.rs`) & completes the
+ * login. The challenges are kept server side, they
+ * expire after `CHALLENGE_VALIDITY_SECS` & can only be completed once, so a
+ * checked password can't be held open indefinitely or replayed.
+ *
+ * Every login is assessed by the risk-based policy (see `risk.rs`) first, a
+ * risky login can require a second factor, an email confirmation or be blocked.
+ * After too many failed logins, a CAPTCHA is required (see `throttle.rs`).
+ * A blocked login or far too many failed logins place the account on security
+ * hold (see `hold.rs`), the account can't be used until the hold is released.
+ * The users restricted to some hours can only login in them (see `schedule.rs`).
+ *
  * # Author
  * Doran Kayoumi <doran.kayoumi@heig-vd.ch>
  */
 
+use chrono::{DateTime, Duration, Utc};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::sync::Arc;
+
+use super::action;
+use super::binding::{self, ClientInfo};
+use super::factor::FactorRegistry;
+use super::hold::{self, HoldReason};
+use super::inactivity;
+use super::recovery::Consumption;
+use super::risk::{self, Decision, Signals};
+use super::schedule;
+use super::throttle::{
+    self, ArithmeticCaptcha, Captcha, CaptchaProvider, Escalation, LoginThrottle,
+};
+use super::timing;
+use crate::audit::{self, AuditEvent};
+use crate::config::{self, AccessHoursConfig, CaptchaConfig, HoldConfig};
+use crate::db::ephemeral::{self, InMemoryTokenStore, SharedTokenStore};
 use crate::db::models::User;
-use crate::db::repository::{SQliteUserRepository, UserRepository};
-use crate::errors::AuthError;
-use crate::utils;
+#[cfg(feature = "async")]
+use crate::db::repository::{run_blocking, AsyncUserRepository};
+use crate::db::repository::{
+    AuditRepository, SQliteAuditRepository, SQliteUserRepository, UserFilter, UserRepository,
+};
+use crate::errors::{AuthError, UserDBError};
+use crate::mail::templates::{self, Template};
+use crate::mail::{self, Mailer};
+use crate::utils::{self, Redacted};
+use crate::validation::{Email, Password, MAX_TOKEN_BYTES};
+
+/// How long (in seconds) a user has to enter her/his 2fa code once her/his password was checked
+pub const CHALLENGE_VALIDITY_SECS: i64 = 300;
+/// Number of wrong 2fa codes after which a challenge is revoked
+pub const CHALLENGE_MAX_ATTEMPTS: u32 = 5;
+/// Purpose of the links confirming an unusual login (see `action.rs`)
+pub const CONFIRMATION_PURPOSE: &str = "login-confirmation";
+/// What the CAPTCHAs of the logins are for (see `ephemeral::captcha_key`)
+pub const CAPTCHA_SCOPE: &str = "login";
+/// How long (in minutes) a user has to confirm an unusual login
+pub const CONFIRMATION_VALIDITY_MINS: i64 = 15;
+/// Purpose of the links unlocking an account after too many failed logins (see `action.rs`)
+pub const UNLOCK_PURPOSE: &str = "unlock";
+/// How long (in minutes) an unlock link is valid, the failures are forgotten afterwards anyway
+pub const UNLOCK_VALIDITY_MINS: i64 = 60;
+
+/// Second phase of the login of a user with 2fa enabled
+/// Note: only a random id is handed out, the state stays server side
+/// The id is hidden in the debug output
+#[derive(PartialEq, Clone)]
+pub struct TwoFactorChallenge {
+    id: String,
+    expires_at: DateTime<Utc>,
+    factors: Vec<&'static str>,
+}
+
+impl TwoFactorChallenge {
+    pub fn get_id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn get_expires_at(&self) -> DateTime<Utc> {
+        self.expires_at
+    }
+
+    /// Names of the second factors the user can complete the challenge with
+    pub fn get_factors(&self) -> &[&'static str] {
+        &self.factors
+    }
+}
+
+impl fmt::Debug for TwoFactorChallenge {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TwoFactorChallenge")
+            .field("id", &Redacted)
+            .field("expires_at", &self.expires_at)
+            .field("factors", &self.factors)
+            .finish()
+    }
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub enum LoginOutcome {
+    /// The user is logged in
+    Authenticated(AuthenticatedLogin),
+    /// The user must enter her/his 2fa code (see `complete_2fa`)
+    TwoFactorRequired(TwoFactorChallenge),
+}
+
+/// A login completed without a second factor, the user didn't set one up
+/// Note: only the login flows create it, it proves the login went through (see `jwt::LoginProof`)
+#[derive(PartialEq, Debug, Clone)]
+pub struct AuthenticatedLogin {
+    pub(crate) user: User,
+}
+
+impl AuthenticatedLogin {
+    pub fn get_user(&self) -> &User {
+        &self.user
+    }
+
+    pub fn into_user(self) -> User {
+        self.user
+    }
+}
+
+/// A login completed with a second factor
+/// Note: only `complete_2fa` creates it, it proves the login went through (see `jwt::LoginProof`)
+#[derive(PartialEq, Debug, Clone)]
+pub struct CompletedLogin {
+    pub(crate) user: User,
+    /// Name of the second factor used
+    pub(crate) factor: &'static str,
+    /// Set if a recovery code was used
+    pub(crate) recovery: Option<Consumption>,
+}
+
+impl CompletedLogin {
+    pub fn get_user(&self) -> &User {
+        &self.user
+    }
+
+    pub fn into_user(self) -> User {
+        self.user
+    }
+
+    /// Name of the second factor used
+    pub fn get_factor(&self) -> &'static str {
+        self.factor
+    }
+
+    /// Set if a recovery code was used
+    pub fn get_recovery(&self) -> Option<Consumption> {
+        self.recovery
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct PendingChallenge {
+    email: String,
+    expires_at: DateTime<Utc>,
+    attempts: u32,
+    /// Fingerprint of the client which started the login (see `binding.rs`)
+    binding: Option<String>,
+    /// Names of the second factors the user can complete the challenge with
+    #[serde(default)]
+    factors: Vec<String>,
+}
+
+/// The challenges that weren't completed yet
+/// They're kept in a `TokenStore`, so a challenge issued by an instance can be
+/// completed on another one sharing the store (e.g. in Redis, see `ephemeral::shared`)
+struct ChallengeStore {
+    store: SharedTokenStore,
+}
+
+impl Default for ChallengeStore {
+    /// Challenges kept in memory, by this store only
+    fn default() -> Self {
+        Self::with_store(Arc::new(InMemoryTokenStore::new()))
+    }
+}
+
+impl ChallengeStore {
+    fn with_store(store: SharedTokenStore) -> Self {
+        Self { store }
+    }
+
+    /// Keeps a challenge, the store drops it after `CHALLENGE_VALIDITY_SECS`
+    /// Note: `redeem` checks its expiry all the same, with the clock of the login
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - the id of the challenge
+    ///
+    /// * `challenge` - the state of the challenge
+    ///
+    fn save(&self, id: &str, challenge: &PendingChallenge) -> Result<(), AuthError> {
+        let value = serde_json::to_string(challenge).map_err(|_| AuthError::StateStoreError)?;
+        let ttl = Duration::seconds(CHALLENGE_VALIDITY_SECS)
+            .to_std()
+            .unwrap_or_default();
+
+        self.store
+            .put(&ephemeral::challenge_key(id), &value, ttl)
+            .map_err(|_| AuthError::StateStoreError)
+    }
+
+    /// Reads a challenge, `None` if it doesn't exist (anymore)
+    ///
+    /// # Arguments
+    ///
+    /// * `value` - the value kept in the store
+    ///
+    fn parse(value: Option<String>) -> Option<PendingChallenge> {
+        value.and_then(|v| serde_json::from_str(&v).ok())
+    }
+
+    /// Creates a challenge for a user whose password was checked
+    ///
+    /// # Arguments
+    ///
+    /// * `email` - the email of the user
+    ///
+    /// * `factors` - the second factors the user set up
+    ///
+    /// * `now` - the current date & time
+    ///
+    fn issue(
+        &self,
+        email: &str,
+        factors: Vec<&'static str>,
+        now: DateTime<Utc>,
+    ) -> Result<TwoFactorChallenge, AuthError> {
+        let challenge = TwoFactorChallenge {
+            id: utils::gen_token(),
+            expires_at: now + Duration::seconds(CHALLENGE_VALIDITY_SECS),
+            factors,
+        };
+        self.save(
+            &challenge.id,
+            &PendingChallenge {
+                email: email.to_string(),
+                expires_at: challenge.expires_at,
+                attempts: 0,
+                binding: None,
+                factors: challenge.factors.iter().map(|f| f.to_string()).collect(),
+            },
+        )?;
+
+        Ok(challenge)
+    }
+
+    /// Gets a challenge that wasn't completed yet, e.g. from the id a client sent back
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - the id of the challenge
+    ///
+    /// * `registry` - the second factors available
+    ///
+    /// * `now` - the current date & time
+    ///
+    fn find(
+        &self,
+        id: &str,
+        registry: &FactorRegistry,
+        now: DateTime<Utc>,
+    ) -> Result<TwoFactorChallenge, AuthError> {
+        let value = self
+            .store
+            .get(&ephemeral::challenge_key(id))
+            .map_err(|_| AuthError::StateStoreError)?;
+        let challenge = Self::parse(value).ok_or(AuthError::InvalidChallenge)?;
+        if challenge.expires_at <= now {
+            return Err(AuthError::ChallengeExpired);
+        }
+
+        Ok(TwoFactorChallenge {
+            id: id.to_string(),
+            expires_at: challenge.expires_at,
+            factors: challenge
+                .factors
+                .iter()
+                .filter_map(|f| registry.get(f).map(|f| f.name()))
+                .collect(),
+        })
+    }
+
+    /// Binds a challenge to the client which started the login
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - the id of the challenge
+    ///
+    /// * `fingerprint` - the fingerprint of the client, `None` if the binding is off
+    ///
+    fn bind(&self, id: &str, fingerprint: Option<String>) -> Result<(), AuthError> {
+        if fingerprint.is_none() {
+            return Ok(());
+        }
+
+        let value = self
+            .store
+            .get(&ephemeral::challenge_key(id))
+            .map_err(|_| AuthError::StateStoreError)?;
+        match Self::parse(value) {
+            Some(mut challenge) => {
+                challenge.binding = fingerprint;
+                self.save(id, &challenge)
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Checks that a challenge is presented by the client it's bound to
+    /// A challenge presented by another client is revoked, its id was likely stolen
+    /// Note: the unknown challenges are left to `redeem`
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - the id of the challenge
+    ///
+    /// * `fingerprint` - the fingerprint of the client presenting it
+    ///
+    fn check_client(&self, id: &str, fingerprint: Option<&str>) -> Result<(), AuthError> {
+        let key = ephemeral::challenge_key(id);
+        let value = self
+            .store
+            .get(&key)
+            .map_err(|_| AuthError::StateStoreError)?;
+        let bound_elsewhere = Self::parse(value)
+            .and_then(|c| c.binding)
+            .is_some_and(|binding| Some(binding.as_str()) != fingerprint);
+
+        if bound_elsewhere {
+            // Note: revoked even if it can't be removed, `redeem` isn't reached
+            let _ = self.store.remove(&key);
+            return Err(AuthError::ClientMismatch);
+        }
+
+        Ok(())
+    }
+
+    /// Completes a challenge if `check` accepts the code entered by the user
+    /// A completed challenge is removed, so it can't be completed again
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - the id of the challenge
+    ///
+    /// * `now` - the current date & time
+    ///
+    /// * `check` - checks the code of the user, given her/his email
+    ///
+    fn redeem<T>(
+        &self,
+        id: &str,
+        now: DateTime<Utc>,
+        check: impl FnOnce(&str) -> Result<T, AuthError>,
+    ) -> Result<T, AuthError> {
+        // Note: taken out of the store while the code is checked, so two attempts (even on
+        //       two instances) can't complete it at once
+        let value = self
+            .store
+            .take(&ephemeral::challenge_key(id))
+            .map_err(|_| AuthError::StateStoreError)?;
+        let mut challenge = Self::parse(value).ok_or(AuthError::InvalidChallenge)?;
+
+        if challenge.expires_at <= now {
+            return Err(AuthError::ChallengeExpired);
+        }
+
+        check(&challenge.email).inspect_err(|_| {
+            challenge.attempts += 1;
+            if challenge.attempts < CHALLENGE_MAX_ATTEMPTS {
+                // Note: a challenge that can't be put back is revoked, the user logs in again
+                let _ = self.save(id, &challenge);
+            }
+        })
+    }
+}
+
+lazy_static! {
+    static ref CHALLENGES: ChallengeStore = ChallengeStore::with_store(ephemeral::shared());
+    static ref THROTTLE: LoginThrottle = LoginThrottle::with_store(ephemeral::shared());
+    static ref CAPTCHAS: ArithmeticCaptcha =
+        ArithmeticCaptcha::with_store(ephemeral::shared(), CAPTCHA_SCOPE);
+}
+
+/// The failed logins counted by the login flows, the step-ups count theirs too (see `step_up.rs`)
+pub(super) fn throttle() -> &'static LoginThrottle {
+    &THROTTLE
+}
 
 /// Public function for the login
 /// See `_login` for more info
 ///
-pub fn login(email: &str, passwd: &str) -> Result<User, AuthError> {
-    let repository = SQliteUserRepository {};
-    _login(email, passwd, &repository)
+/// # Note
+/// The 2fa isn't checked, see `begin_login` for that
+///
+pub fn login(email: &Email, passwd: &Password) -> Result<User, AuthError> {
+    login_with_repository(email, passwd, &SQliteUserRepository::new())
+}
+
+/// Same as `login`, with the users of a given storage (e.g. `PostgresUserRepository`)
+pub fn login_with_repository(
+    email: &Email,
+    passwd: &Password,
+    repository: &dyn UserRepository,
+) -> Result<User, AuthError> {
+    let u = timing::padded(|| {
+        _login(
+            email,
+            passwd.as_str(),
+            repository,
+            &config::get().access_hours,
+            Utc::now(),
+        )
+    })?;
+    record_login(&u, repository);
+
+    Ok(u)
+}
+
+/// Same as `login_with_repository`, run on a blocking thread of tokio (see `run_blocking`)
+#[cfg(feature = "async")]
+pub async fn login_async(
+    email: &Email,
+    passwd: &Password,
+    repository: Arc<dyn AsyncUserRepository>,
+) -> Result<User, AuthError> {
+    let (email, passwd) = (email.clone(), passwd.clone());
+    run_blocking(repository, AuthError::LoginError, move |r| {
+        login_with_repository(&email, &passwd, r)
+    })
+    .await
 }
 
 /// User login
+/// An account on hold or outside of its allowed hours is refused, whatever flow
+/// (e.g. `login`, `begin_login`) checks the password
 ///
 /// # Arguments
 ///
@@ -28,30 +452,842 @@ pub fn login(email: &str, passwd: &str) -> Result<User, AuthError> {
 ///
 /// * `repository` - the user repository to interact with
 ///
-fn _login(email: &str, passwd: &str, repository: &dyn UserRepository) -> Result<User, AuthError> {
+/// * `access` - the hours in which the users are allowed to login
+///
+/// * `now` - the current date & time
+///
+fn _login(
+    email: &str,
+    passwd: &str,
+    repository: &dyn UserRepository,
+    access: &AccessHoursConfig,
+    now: DateTime<Utc>,
+) -> Result<User, AuthError> {
+    let u = check_password(email, passwd, repository)?;
+
+    // Note: only checked once the password is, so they don't tell whether an account exists
+    check_account(&u, repository, access, now)?;
+
+    Ok(u)
+}
+
+/// Checks the password of a user, whatever the state of her/his account
+///
+/// # Arguments
+///
+/// * `email` - the email of the user trying to login
+///
+/// * `passwd` - the password of the user trying to login
+///
+/// * `repository` - the user repository to interact with
+///
+fn check_password(
+    email: &str,
+    passwd: &str,
+    repository: &dyn UserRepository,
+) -> Result<User, AuthError> {
     // get all the user info we need from the database
-    let u = repository.get_user(email);
+    // Note: the service & directory accounts can't login with a local password
+    //       (see `service.rs` & `ldap.rs`), they're refused like an unknown user
+    let u = match repository.get_user(email) {
+        Ok(u) if u.has_local_password() => u,
+        // Note: a missing user isn't a timeout, so it doesn't leak whether the user exists
+        Err(UserDBError::Timeout) => return Err(AuthError::Timeout),
+        _ => {
+            // to avoid timing attacks, perform a argon2 hash to "waste" time
+            let _ = utils::hash(passwd);
+            return Err(AuthError::LoginError);
+        }
+    };
 
-    if let Err(_) = u {
-        // to avoid timing attacks, perform a argon2 hash to "waste" time
-        utils::hash(passwd);
+    // check the password
+    if !utils::verify_hash(passwd, &u.get_password()) {
         return Err(AuthError::LoginError);
     }
 
-    let u = u.unwrap();
-    // check the password
-    if utils::verify_hash(passwd, &u.get_password()) {
-        Ok(u)
+    Ok(u)
+}
+
+/// Checks that a user whose credentials were checked can login: her/his account
+/// isn't on hold & it's within her/his allowed hours
+///
+/// # Arguments
+///
+/// * `u` - the user whose credentials were checked
+///
+/// * `repository` - the user repository to interact with
+///
+/// * `access` - the hours in which the users are allowed to login
+///
+/// * `now` - the current date & time
+///
+fn check_account(
+    u: &User,
+    repository: &dyn UserRepository,
+    access: &AccessHoursConfig,
+    now: DateTime<Utc>,
+) -> Result<(), AuthError> {
+    if hold::is_on_hold(u.get_id(), repository)? {
+        return Err(AuthError::AccountOnHold);
+    }
+
+    schedule::check_access(u, repository, access, now)
+}
+
+/// Public function for the first phase of the login
+/// See `_begin_login` for more info
+///
+pub fn begin_login(email: &Email, passwd: &Password) -> Result<LoginOutcome, AuthError> {
+    let signals = Signals::at(Utc::now(), config::get().locale.timezone);
+    begin_login_with(
+        &FactorRegistry::standard(),
+        &signals,
+        throttle::LOCAL_SOURCE,
+        &ClientInfo::local(),
+        email,
+        passwd,
+    )
+}
+
+/// Same as `begin_login`, with the second factors of a given registry & what
+/// is known about the login
+/// Once a CAPTCHA is required, the login fails with `AuthError::CaptchaRequired`
+/// until one is solved with `solve_captcha`
+/// The challenge is bound to the client, if the binding is enabled (see `binding.rs`)
+/// Note: whatever the outcome, it lasts at least the minimum response time (see `timing.rs`)
+pub fn begin_login_with(
+    registry: &FactorRegistry,
+    signals: &Signals,
+    source: &str,
+    client: &ClientInfo,
+    email: &Email,
+    passwd: &Password,
+) -> Result<LoginOutcome, AuthError> {
+    let repository = SQliteUserRepository::new();
+    begin_login_with_repository(
+        registry,
+        signals,
+        source,
+        client,
+        email,
+        passwd,
+        &repository,
+    )
+}
+
+/// Same as `begin_login_with`, with the users of a given repository (see `AuthService`)
+/// Note: the other storages (audit log, notifications...) stay on SQLite
+pub fn begin_login_with_repository(
+    registry: &FactorRegistry,
+    signals: &Signals,
+    source: &str,
+    client: &ClientInfo,
+    email: &Email,
+    passwd: &Password,
+    repository: &dyn UserRepository,
+) -> Result<LoginOutcome, AuthError> {
+    timing::padded(|| {
+        let policy = &config::get().captcha;
+        THROTTLE.check(email, source, policy)?;
+
+        // the failed logins are a signal of the risk-based policy too
+        let failures = THROTTLE.failures(email, source).account_failures;
+        let mut signals = *signals;
+        signals.failed_attempts = signals.failed_attempts.max(failures);
+        let assessment = risk::assess(&signals, &config::get().risk);
+        let access = &config::get().access_hours;
+        let now = Utc::now();
+
+        let mut outcome = _begin_login(
+            email,
+            passwd.as_str(),
+            repository,
+            registry,
+            &CHALLENGES,
+            assessment.decision,
+            access,
+            now,
+        )
+        .and_then(|outcome| bind_challenge(outcome, client));
+
+        // Note: only the right password can place the account on hold, so the failed logins
+        //       of someone who doesn't know it can't lock its owner out
+        let verified = match outcome {
+            // the login is refused whatever the password, it's only checked for the hold
+            Err(AuthError::LoginBlocked) => {
+                check_password(email, passwd.as_str(), repository).is_ok()
+            }
+            Err(AuthError::LoginError) | Err(AuthError::Timeout) => false,
+            // every other outcome comes once the password is checked
+            _ => true,
+        };
+        if let Some(reason) = hold_reason(
+            &outcome,
+            verified,
+            assessment.score,
+            failures,
+            &config::get().hold,
+        ) {
+            // Note: a hold that can't be placed doesn't change the outcome of the login
+            let placed =
+                hold::_place_hold(email, reason, repository, &SQliteAuditRepository::new());
+            if placed == Ok(true) && outcome.is_ok() {
+                outcome = Err(AuthError::AccountOnHold);
+            }
+        }
+
+        match outcome {
+            Ok(LoginOutcome::Authenticated(ref login)) => {
+                THROTTLE.record_success(email);
+                record_login(&login.user, repository);
+            }
+            Ok(_) => THROTTLE.record_success(email),
+            Err(AuthError::LoginError) => {
+                if let Some(escalation) = THROTTLE.record_failure(email, source, policy) {
+                    if escalation.account_failures >= policy.after_account_failures {
+                        if let Ok(u) = repository.get_user(email) {
+                            send_unlock_link(&u, mail::default_mailer());
+                        }
+                    }
+                    record_escalation(
+                        email,
+                        source,
+                        &escalation,
+                        policy,
+                        repository,
+                        &SQliteAuditRepository::new(),
+                    );
+                }
+            }
+            Err(AuthError::EmailConfirmationRequired) => {
+                if let Ok(u) = repository.get_user(email) {
+                    send_confirmation(&u, mail::default_mailer());
+                }
+            }
+            Err(AuthError::OutsideAllowedHours) => {
+                let user = repository.get_user(email).ok().map(|u| u.get_id());
+                let _ = audit::record(
+                    &SQliteAuditRepository::new(),
+                    user,
+                    AuditEvent::LoginOutsideAllowedHours,
+                    Some(format!(
+                        "login on {}",
+                        now.with_timezone(&access.timezone).format("%a %H:%M (%Z)")
+                    )),
+                );
+            }
+            Err(_) => {}
+        }
+
+        outcome
+    })
+}
+
+/// Tell if a login indicates a likely compromise of the account, i.e. it must be placed on hold
+/// Only a login made with the right password does: anyone can fail to login to an account,
+/// the failures alone would let them lock its owner out
+///
+/// # Arguments
+///
+/// * `outcome` - the outcome of the login
+///
+/// * `verified` - the password of the login is the right one
+///
+/// * `score` - the risk score of the login
+///
+/// * `failures` - the failed logins of the account before this one
+///
+/// * `policy` - when an account is placed on hold
+///
+pub(super) fn hold_reason<T>(
+    outcome: &Result<T, AuthError>,
+    verified: bool,
+    score: u32,
+    failures: u32,
+    policy: &HoldConfig,
+) -> Option<HoldReason> {
+    if !verified {
+        return None;
+    }
+
+    match outcome {
+        Err(AuthError::LoginBlocked) if policy.on_risk_block => {
+            Some(HoldReason::RiskBlocked { score })
+        }
+        Err(AuthError::LoginBlocked) | Err(AuthError::AccountOnHold) => None,
+        _ if policy.after_account_failures > 0 && failures >= policy.after_account_failures => {
+            Some(HoldReason::FailedLogins { count: failures })
+        }
+        _ => None,
+    }
+}
+
+/// Keeps track of a successful login, for the inactivity policy & the audit log
+/// Note: the login succeeded anyway, it doesn't fail if it can't be kept
+///
+/// # Arguments
+///
+/// * `u` - the user who logged in
+///
+/// * `repository` - the user repository to interact with
+///
+fn record_login(u: &User, repository: &dyn UserRepository) {
+    inactivity::record_login_with_repository(u, repository);
+    let _ = audit::record(
+        &SQliteAuditRepository::new(),
+        Some(u.get_id()),
+        AuditEvent::LoginSucceeded,
+        None,
+    );
+}
+
+/// Adds the escalation of the failed logins to the audit log, with the thresholds reached
+///
+/// # Arguments
+///
+/// * `email` - the email of the account
+///
+/// * `source` - where the logins came from
+///
+/// * `escalation` - the failed logins counted
+///
+/// * `policy` - the thresholds in use
+///
+/// * `repository` - the user repository to interact with
+///
+/// * `audit_repository` - the audit repository to write in
+///
+fn record_escalation(
+    email: &str,
+    source: &str,
+    escalation: &Escalation,
+    policy: &CaptchaConfig,
+    repository: &dyn UserRepository,
+    audit_repository: &dyn AuditRepository,
+) {
+    // Note: the account may not exist
+    let user = repository.get_user(email).ok().map(|u| u.get_id());
+
+    let _ = audit::record(
+        audit_repository,
+        user,
+        AuditEvent::CaptchaRequired,
+        Some(format!(
+            "{} failed login(s) of {} (threshold {}), {} from {} (threshold {})",
+            escalation.account_failures,
+            email,
+            policy.after_account_failures,
+            escalation.source_failures,
+            source,
+            policy.after_source_failures
+        )),
+    );
+}
+
+/// Get a CAPTCHA to solve before the next login attempt
+pub fn new_captcha() -> Captcha {
+    CAPTCHAS.issue()
+}
+
+/// Check the answer to a CAPTCHA, if it's right, one login attempt is granted to the account
+///
+/// # Arguments
+///
+/// * `captcha` - the CAPTCHA returned by `new_captcha`
+///
+/// * `answer` - the answer of the user
+///
+/// * `email` - the email of the account the user tries to login to
+///
+pub fn solve_captcha(captcha: &Captcha, answer: &str, email: &str) -> Result<(), AuthError> {
+    if CAPTCHAS.verify(captcha, answer) {
+        THROTTLE.grant_attempt(email);
+        return Ok(());
+    }
+
+    let _ = audit::record(
+        &SQliteAuditRepository::new(),
+        None,
+        AuditEvent::CaptchaFailed,
+        Some(email.to_string()),
+    );
+    Err(AuthError::InvalidCaptcha)
+}
+
+/// Checks the password of a user, the users who set up a second factor get
+/// a challenge to complete with `complete_2fa`
+/// The login is refused if the risk-based policy decided so, a required second
+/// factor falls back to an email confirmation for the users who didn't set one up
+///
+/// # Arguments
+///
+/// * `email` - the email of the user trying to login
+///
+/// * `passwd` - the password of the user trying to login
+///
+/// * `repository` - the user repository to interact with
+///
+/// * `registry` - the second factors available
+///
+/// * `store` - where the challenges are kept
+///
+/// * `decision` - the decision of the risk-based policy
+///
+/// * `access` - the hours in which the users are allowed to login
+///
+/// * `now` - the current date & time
+///
+#[allow(clippy::too_many_arguments)]
+fn _begin_login(
+    email: &str,
+    passwd: &str,
+    repository: &dyn UserRepository,
+    registry: &FactorRegistry,
+    store: &ChallengeStore,
+    decision: Decision,
+    access: &AccessHoursConfig,
+    now: DateTime<Utc>,
+) -> Result<LoginOutcome, AuthError> {
+    // Note: the password isn't even checked, so a blocked login can't be used to guess it
+    //       (`begin_login_with` only checks it afterwards, to tell whether to place a hold)
+    if decision == Decision::Block {
+        return Err(AuthError::LoginBlocked);
+    }
+
+    let u = _login(email, passwd, repository, access, now)?;
+    _continue_login(u, registry, store, decision, now)
+}
+
+/// Public function for the rest of the login of a user whose credentials were checked elsewhere
+/// (e.g. by a directory, see `ldap.rs`), with what the system knows locally
+/// See `_continue_login` for more info
+///
+/// # Note
+/// The failed logins aren't counted, the checker of the credentials limits them
+///
+/// # Arguments
+///
+/// * `u` - the user whose credentials were checked
+///
+/// * `repository` - the user repository to interact with
+///
+pub(super) fn continue_login(
+    u: User,
+    repository: &dyn UserRepository,
+) -> Result<LoginOutcome, AuthError> {
+    let now = Utc::now();
+    let signals = Signals::at(now, config::get().locale.timezone);
+    let decision = risk::assess(&signals, &config::get().risk).decision;
+    if decision == Decision::Block {
+        return Err(AuthError::LoginBlocked);
+    }
+    check_account(&u, repository, &config::get().access_hours, now)?;
+
+    let outcome = _continue_login(
+        u.clone(),
+        &FactorRegistry::standard(),
+        &CHALLENGES,
+        decision,
+        now,
+    )
+    .and_then(|outcome| bind_challenge(outcome, &ClientInfo::local()));
+    match outcome {
+        Ok(LoginOutcome::Authenticated(ref login)) => record_login(&login.user, repository),
+        Err(AuthError::EmailConfirmationRequired) => send_confirmation(&u, mail::default_mailer()),
+        _ => {}
+    }
+
+    outcome
+}
+
+/// Binds the challenge of a login to the client which started it, if the binding is
+/// enabled (see `binding.rs`)
+/// Note: a challenge that can't be bound fails the login, rather than being usable anywhere
+///
+/// # Arguments
+///
+/// * `outcome` - the outcome of the first phase of the login
+///
+/// * `client` - the client which started the login
+///
+fn bind_challenge(outcome: LoginOutcome, client: &ClientInfo) -> Result<LoginOutcome, AuthError> {
+    if let LoginOutcome::TwoFactorRequired(ref challenge) = outcome {
+        let strictness = config::get().binding.strictness;
+        CHALLENGES.bind(challenge.get_id(), binding::fingerprint(client, strictness))?;
+    }
+
+    Ok(outcome)
+}
+
+/// Checks what's left of the login once the credentials of a user & her/his
+/// account are (see `check_account`): the second factor
+///
+/// # Arguments
+///
+/// * `u` - the user whose credentials were checked
+///
+/// * `registry` - the second factors available
+///
+/// * `store` - where the challenges are kept
+///
+/// * `decision` - the decision of the risk-based policy
+///
+/// * `now` - the current date & time
+///
+fn _continue_login(
+    u: User,
+    registry: &FactorRegistry,
+    store: &ChallengeStore,
+    decision: Decision,
+    now: DateTime<Utc>,
+) -> Result<LoginOutcome, AuthError> {
+    let factors = registry.enabled_for(&u);
+    if decision == Decision::RequireEmailConfirmation
+        || (decision == Decision::Require2fa && factors.is_empty())
+    {
+        Err(AuthError::EmailConfirmationRequired)
+    } else if factors.is_empty() {
+        Ok(LoginOutcome::Authenticated(AuthenticatedLogin { user: u }))
     } else {
-        Err(AuthError::LoginError)
+        Ok(LoginOutcome::TwoFactorRequired(store.issue(
+            &u.get_email(),
+            factors,
+            now,
+        )?))
+    }
+}
+
+/// Sends the user the token confirming her/his login, the risk-based policy found it unusual
+/// Note: the login is refused anyway, it doesn't change if the token can't be sent
+/// (e.g. the action links aren't set up)
+///
+/// # Arguments
+///
+/// * `u` - the user whose login must be confirmed
+///
+/// * `mailer` - the mailer used to send the token
+///
+fn send_confirmation(u: &User, mailer: &dyn Mailer) {
+    let validity = Duration::minutes(CONFIRMATION_VALIDITY_MINS);
+    if let Ok(token) = action::issue(CONFIRMATION_PURPOSE, u.get_id(), validity) {
+        let _ = mailer.send(&templates::render(
+            &Template::LoginConfirmation { token },
+            u,
+        ));
+    }
+}
+
+/// Public function for the confirmation of an unusual login
+/// See `_confirm_login` for more info
+///
+pub fn confirm_login(token: &str) -> Result<LoginOutcome, AuthError> {
+    confirm_login_with(&FactorRegistry::standard(), &ClientInfo::local(), token)
+}
+
+/// Same as `confirm_login`, with the second factors of a given registry & the
+/// client confirming the login
+/// Note: whatever the outcome, it lasts at least the minimum response time (see `timing.rs`)
+pub fn confirm_login_with(
+    registry: &FactorRegistry,
+    client: &ClientInfo,
+    token: &str,
+) -> Result<LoginOutcome, AuthError> {
+    timing::padded(|| {
+        let link = action::redeem(token, CONFIRMATION_PURPOSE)?;
+        let repository = SQliteUserRepository::new();
+
+        let outcome = _confirm_login(
+            link.user,
+            &repository,
+            registry,
+            &CHALLENGES,
+            &config::get().access_hours,
+            Utc::now(),
+        )
+        .and_then(|outcome| bind_challenge(outcome, client));
+        if let Ok(LoginOutcome::Authenticated(ref login)) = outcome {
+            record_login(&login.user, &repository);
+        }
+
+        outcome
+    })
+}
+
+/// Continues a login the user confirmed with the token sent to her/his mailbox
+/// The hold & the allowed hours are checked again, and the users who set up a
+/// second factor still get a challenge to complete with `complete_2fa`
+///
+/// # Arguments
+///
+/// * `user` - id of the user the token was sent to
+///
+/// * `repository` - the user repository to interact with
+///
+/// * `registry` - the second factors available
+///
+/// * `store` - where the challenges are kept
+///
+/// * `access` - the hours in which the users are allowed to login
+///
+/// * `now` - the current date & time
+///
+fn _confirm_login(
+    user: i32,
+    repository: &dyn UserRepository,
+    registry: &FactorRegistry,
+    store: &ChallengeStore,
+    access: &AccessHoursConfig,
+    now: DateTime<Utc>,
+) -> Result<LoginOutcome, AuthError> {
+    // Note: the account may have been deleted since the token was sent
+    let u = repository
+        .list_users(&UserFilter::new().with_id(user))
+        .map_err(|_| AuthError::LoginError)?
+        .into_iter()
+        .next()
+        .ok_or(AuthError::LoginError)?;
+    check_account(&u, repository, access, now)?;
+
+    // the confirmation stands for the step the risk-based policy required
+    _continue_login(u, registry, store, Decision::Allow, now)
+}
+
+/// Sends the user the link lifting the CAPTCHA asked after too many failed logins to her/his account
+/// Note: the login failed anyway, it doesn't change if the link can't be sent
+/// (e.g. the action links aren't set up)
+///
+/// # Arguments
+///
+/// * `u` - the user whose account was locked
+///
+/// * `mailer` - the mailer used to send the link
+///
+fn send_unlock_link(u: &User, mailer: &dyn Mailer) {
+    let validity = Duration::minutes(UNLOCK_VALIDITY_MINS);
+    if let Ok(token) = action::issue(UNLOCK_PURPOSE, u.get_id(), validity) {
+        let _ = mailer.send(&templates::render(&Template::UnlockAccount { token }, u));
     }
 }
 
+/// Public function for the unlock of an account
+/// See `_unlock` for more info
+///
+pub fn unlock(token: &str) -> Result<(), AuthError> {
+    let link = action::redeem(token, UNLOCK_PURPOSE)?;
+    _unlock(link.user, &SQliteUserRepository::new(), &THROTTLE)
+}
+
+/// Forgets the failed logins of an account, its owner followed the link sent once it was locked
+/// The failures of the sources are kept, so does a security hold (see `hold.rs`)
+///
+/// # Arguments
+///
+/// * `user` - id of the user the link was sent to
+///
+/// * `repository` - the user repository to interact with
+///
+/// * `throttle` - the failed logins counted
+///
+fn _unlock(
+    user: i32,
+    repository: &dyn UserRepository,
+    throttle: &LoginThrottle,
+) -> Result<(), AuthError> {
+    // Note: the account may have been deleted since the link was sent
+    let u = repository
+        .list_users(&UserFilter::new().with_id(user))
+        .map_err(|_| AuthError::ActionLinkError)?
+        .into_iter()
+        .next()
+        .ok_or(AuthError::InvalidActionLink)?;
+    throttle.record_success(&u.get_email());
+
+    Ok(())
+}
+
+/// Get a challenge handed out by `begin_login` from its id, e.g. the one a client sent back
+/// Only the id needs to be kept between the two phases, the challenge stays in the store
+/// of the library (shared by the instances if Redis is set up, see `ephemeral::shared`)
+///
+/// # Arguments
+///
+/// * `id` - the id of the challenge
+///
+pub fn find_challenge(id: &str) -> Result<TwoFactorChallenge, AuthError> {
+    find_challenge_with(&FactorRegistry::standard(), id)
+}
+
+/// Same as `find_challenge`, with the second factors of a given registry
+pub fn find_challenge_with(
+    registry: &FactorRegistry,
+    id: &str,
+) -> Result<TwoFactorChallenge, AuthError> {
+    utils::check_length(id, MAX_TOKEN_BYTES)?;
+    CHALLENGES.find(id, registry, Utc::now())
+}
+
+/// Public function for the second phase of the login
+/// See `_complete_2fa` for more info
+///
+pub fn complete_2fa(
+    challenge: &TwoFactorChallenge,
+    factor: &str,
+    code: &str,
+) -> Result<CompletedLogin, AuthError> {
+    complete_2fa_with(
+        &FactorRegistry::standard(),
+        &ClientInfo::local(),
+        challenge,
+        factor,
+        code,
+    )
+}
+
+/// Same as `complete_2fa`, with the second factors of a given registry & the
+/// client completing the login
+/// A challenge bound to another client is revoked (see `binding.rs`)
+pub fn complete_2fa_with(
+    registry: &FactorRegistry,
+    client: &ClientInfo,
+    challenge: &TwoFactorChallenge,
+    factor: &str,
+    code: &str,
+) -> Result<CompletedLogin, AuthError> {
+    let repository = SQliteUserRepository::new();
+    complete_2fa_with_repository(registry, client, challenge, factor, code, &repository)
+}
+
+/// Same as `complete_2fa_with`, with the users of a given repository (see `AuthService`)
+pub fn complete_2fa_with_repository(
+    registry: &FactorRegistry,
+    client: &ClientInfo,
+    challenge: &TwoFactorChallenge,
+    factor: &str,
+    code: &str,
+    repository: &dyn UserRepository,
+) -> Result<CompletedLogin, AuthError> {
+    let completed = timing::padded(|| {
+        let fingerprint = binding::fingerprint(client, config::get().binding.strictness);
+        CHALLENGES.check_client(challenge.get_id(), fingerprint.as_deref())?;
+
+        _complete_2fa(
+            challenge,
+            factor,
+            code,
+            repository,
+            registry,
+            &CHALLENGES,
+            Utc::now(),
+        )
+    })?;
+    record_login(&completed.user, repository);
+
+    Ok(completed)
+}
+
+/// Completes the login of a user with the second factor she/he chose
+/// After `CHALLENGE_MAX_ATTEMPTS` wrong codes, the challenge is revoked
+/// Note: an account placed on hold since the challenge was handed out is refused
+///
+/// # Arguments
+///
+/// * `challenge` - the challenge returned by `begin_login`
+///
+/// * `factor` - the name of the second factor chosen by the user
+///
+/// * `code` - the code entered by the user
+///
+/// * `repository` - the user repository to interact with
+///
+/// * `registry` - the second factors available
+///
+/// * `store` - where the challenges are kept
+///
+/// * `now` - the current date & time
+///
+fn _complete_2fa(
+    challenge: &TwoFactorChallenge,
+    factor: &str,
+    code: &str,
+    repository: &dyn UserRepository,
+    registry: &FactorRegistry,
+    store: &ChallengeStore,
+    now: DateTime<Utc>,
+) -> Result<CompletedLogin, AuthError> {
+    // Note: refused before the challenge is redeemed, it isn't counted as an attempt
+    utils::check_length(code, MAX_TOKEN_BYTES)?;
+    store.redeem(&challenge.id, now, |email| {
+        let u = repository
+            .get_user(email)
+            .map_err(|_| AuthError::LoginError)?;
+        if hold::is_on_hold(u.get_id(), repository)? {
+            return Err(AuthError::AccountOnHold);
+        }
+
+        // the user can only use the factors she/he set up
+        let factor = registry
+            .get(factor)
+            .filter(|f| f.is_enabled(&u))
+            .ok_or(AuthError::UnknownFactor)?;
+        let verification = factor.verify(&u, code)?;
+
+        Ok(CompletedLogin {
+            user: u,
+            factor: factor.name(),
+            recovery: verification.recovery,
+        })
+    })
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::db::repository::MockSQliteUserRepository;
-    use crate::errors::UserDBError;
+    use crate::auth::factor::{RecoveryCodeFactor, TotpFactor, RECOVERY_CODE, TOTP};
+    use crate::db::flaky::{Faults, FlakyRepository};
+    use crate::db::models::AccountKind;
+    use crate::db::repository::{
+        MockSQliteAuditRepository, MockSQliteRecoveryCodeRepository, MockSQliteUserRepository,
+    };
+    use chrono::TimeZone;
+    use google_authenticator::GoogleAuthenticator;
+    use rstest::rstest;
+    use std::collections::HashMap;
+
+    const SECRET: &str = "I3VFM3JKMNDJCDH5BMBEEQAW6KJ6NOE3";
+
+    fn user_with_2fa() -> User {
+        let mut u = User::new("email@email.test", "passwd_hash");
+        u.set_secret_2fa(Some(SECRET.to_string()));
+        u
+    }
+
+    fn repository_with_2fa() -> MockSQliteUserRepository {
+        let mut mock = MockSQliteUserRepository::new();
+        mock.expect_get_user().returning(|_| Ok(user_with_2fa()));
+        mock.expect_get_attributes()
+            .returning(|_| Ok(HashMap::new()));
+        mock
+    }
+
+    fn held_attributes() -> HashMap<String, String> {
+        let mut attributes = HashMap::new();
+        attributes.insert(hold::HOLD_ATTRIBUTE.to_string(), "active".to_string());
+        attributes
+    }
+
+    /// Standard factors, the user has one recovery code left
+    fn registry(codes_mock: MockSQliteRecoveryCodeRepository) -> FactorRegistry {
+        let mut codes_mock = codes_mock;
+        codes_mock.expect_count_unused_codes().returning(|_| Ok(1));
+
+        FactorRegistry::new()
+            .with(TotpFactor)
+            .with(RecoveryCodeFactor::with_repository(Box::new(codes_mock)))
+    }
+
+    fn valid_code() -> String {
+        GoogleAuthenticator::new().get_code(SECRET, 0).unwrap()
+    }
 
     #[test]
     fn test_login_with_unknown_user() {
@@ -60,8 +1296,724 @@ mod test {
         mock.expect_get_user()
             .returning(|_| Err(UserDBError::GetUserError));
 
-        let res = _login("email@email.test", "password", &mock);
+        let res = _login(
+            "email@email.test",
+            "password",
+            &mock,
+            &AccessHoursConfig::default(),
+            Utc::now(),
+        );
 
         assert_eq!(Err(AuthError::LoginError), res);
     }
+
+    #[test]
+    fn test_login_with_locked_database() {
+        let mut mock = MockSQliteUserRepository::new();
+
+        mock.expect_get_user()
+            .returning(|_| Err(UserDBError::Timeout));
+
+        let res = _login(
+            "email@email.test",
+            "password",
+            &mock,
+            &AccessHoursConfig::default(),
+            Utc::now(),
+        );
+
+        assert_eq!(Err(AuthError::Timeout), res);
+    }
+
+    #[test]
+    fn test_login_with_flaky_storage() {
+        let mut mock = MockSQliteUserRepository::new();
+        mock.expect_get_user().times(0);
+        let faults = Faults {
+            failure_rate: 1.0,
+            error: Some(UserDBError::Timeout),
+            ..Faults::default()
+        };
+        let flaky = FlakyRepository::new(mock, faults);
+
+        assert_eq!(
+            _login(
+                "email@email.test",
+                "password",
+                &flaky,
+                &AccessHoursConfig::default(),
+                Utc::now()
+            ),
+            Err(AuthError::Timeout)
+        );
+    }
+
+    #[test]
+    fn test_service_account_cant_login() {
+        let mut mock = MockSQliteUserRepository::new();
+        mock.expect_get_user().returning(|_| {
+            let mut u = User::new("ci@email.test", &utils::hash("password").unwrap());
+            u.set_kind(AccountKind::Service);
+            Ok(u)
+        });
+
+        let res = _login(
+            "ci@email.test",
+            "password",
+            &mock,
+            &AccessHoursConfig::default(),
+            Utc::now(),
+        );
+
+        assert_eq!(Err(AuthError::LoginError), res);
+    }
+
+    #[test]
+    fn test_begin_login_with_2fa() {
+        let mut mock = MockSQliteUserRepository::new();
+        mock.expect_get_user().returning(|_| {
+            let mut u = User::new("email@email.test", &utils::hash("password").unwrap());
+            u.set_secret_2fa(Some(SECRET.to_string()));
+            Ok(u)
+        });
+        mock.expect_get_attributes()
+            .returning(|_| Ok(HashMap::new()));
+        let registry = registry(MockSQliteRecoveryCodeRepository::new());
+        let store = ChallengeStore::default();
+        let now = Utc::now();
+
+        let outcome = _begin_login(
+            "email@email.test",
+            "password",
+            &mock,
+            &registry,
+            &store,
+            Decision::Allow,
+            &AccessHoursConfig::default(),
+            now,
+        );
+
+        match outcome {
+            Ok(LoginOutcome::TwoFactorRequired(c)) => {
+                assert_eq!(
+                    c.get_expires_at(),
+                    now + Duration::seconds(CHALLENGE_VALIDITY_SECS)
+                );
+                assert_eq!(c.get_factors(), &[TOTP, RECOVERY_CODE]);
+            }
+            _ => panic!("a challenge was expected"),
+        }
+    }
+
+    #[rstest(
+        decision,
+        authenticated,
+        case(Decision::Allow, true),
+        case(Decision::Require2fa, false),
+        case(Decision::RequireEmailConfirmation, false),
+        ::trace
+    )]
+    fn test_begin_login_without_2fa(decision: Decision, authenticated: bool) {
+        let mut mock = MockSQliteUserRepository::new();
+        mock.expect_get_user().returning(|_| {
+            Ok(User::new(
+                "email@email.test",
+                &utils::hash("password").unwrap(),
+            ))
+        });
+        mock.expect_get_attributes()
+            .returning(|_| Ok(HashMap::new()));
+        let registry = registry(MockSQliteRecoveryCodeRepository::new());
+        let store = ChallengeStore::default();
+
+        let outcome = _begin_login(
+            "email@email.test",
+            "password",
+            &mock,
+            &registry,
+            &store,
+            decision,
+            &AccessHoursConfig::default(),
+            Utc::now(),
+        );
+
+        match outcome {
+            Ok(LoginOutcome::Authenticated(_)) => assert!(authenticated),
+            Err(AuthError::EmailConfirmationRequired) => assert!(!authenticated),
+            _ => panic!("unexpected outcome"),
+        }
+    }
+
+    #[test]
+    fn test_record_escalation() {
+        let mut mock = MockSQliteUserRepository::new();
+        mock.expect_get_user()
+            .returning(|_| Err(UserDBError::GetUserError));
+        let mut audit_mock = MockSQliteAuditRepository::new();
+        audit_mock
+            .expect_create_entry()
+            .withf(|u, e, d| {
+                u.is_none()
+                    && e == "CaptchaRequired"
+                    && d.as_deref().is_some_and(|d| d.contains("(threshold 3)"))
+            })
+            .times(1)
+            .returning(|_, _, _| Ok(()));
+
+        record_escalation(
+            "email@email.test",
+            "local",
+            &Escalation {
+                account_failures: 3,
+                source_failures: 3,
+            },
+            &CaptchaConfig::default(),
+            &mock,
+            &audit_mock,
+        );
+    }
+
+    #[test]
+    fn test_blocked_login_does_not_check_password() {
+        let mut mock = MockSQliteUserRepository::new();
+        mock.expect_get_user().times(0);
+        let registry = FactorRegistry::new();
+        let store = ChallengeStore::default();
+
+        let outcome = _begin_login(
+            "email@email.test",
+            "password",
+            &mock,
+            &registry,
+            &store,
+            Decision::Block,
+            &AccessHoursConfig::default(),
+            Utc::now(),
+        );
+
+        assert_eq!(outcome, Err(AuthError::LoginBlocked));
+    }
+
+    #[test]
+    fn test_account_on_hold_is_refused() {
+        let mut mock = MockSQliteUserRepository::new();
+        mock.expect_get_user().returning(|_| {
+            Ok(User::new(
+                "email@email.test",
+                &utils::hash("password").unwrap(),
+            ))
+        });
+        mock.expect_get_attributes()
+            .returning(|_| Ok(held_attributes()));
+        let registry = FactorRegistry::new();
+        let store = ChallengeStore::default();
+
+        let login = |passwd: &str| {
+            _begin_login(
+                "email@email.test",
+                passwd,
+                &mock,
+                &registry,
+                &store,
+                Decision::Allow,
+                &AccessHoursConfig::default(),
+                Utc::now(),
+            )
+        };
+
+        assert_eq!(login("password"), Err(AuthError::AccountOnHold));
+        // a wrong password doesn't tell the account is on hold
+        assert_eq!(login("wrong"), Err(AuthError::LoginError));
+    }
+
+    #[test]
+    fn test_account_on_hold_is_refused_by_every_login() {
+        let mut mock = MockSQliteUserRepository::new();
+        mock.expect_get_user().returning(|_| {
+            let mut u = User::new("email@email.test", &utils::hash("password").unwrap());
+            u.set_secret_2fa(Some(SECRET.to_string()));
+            Ok(u)
+        });
+        mock.expect_get_attributes()
+            .returning(|_| Ok(held_attributes()));
+        let email = Email::parse("email@email.test").unwrap();
+        let passwd = Password::parse("password").unwrap();
+
+        // the login without the 2fa (e.g. `login_async`)
+        assert_eq!(
+            login_with_repository(&email, &passwd, &mock),
+            Err(AuthError::AccountOnHold)
+        );
+
+        // the challenges handed out before the hold was placed
+        let registry = registry(MockSQliteRecoveryCodeRepository::new());
+        let store = ChallengeStore::default();
+        let now = Utc::now();
+        let challenge = store.issue("email@email.test", vec![TOTP], now).unwrap();
+        assert_eq!(
+            _complete_2fa(
+                &challenge,
+                TOTP,
+                &valid_code(),
+                &mock,
+                &registry,
+                &store,
+                now
+            ),
+            Err(AuthError::AccountOnHold)
+        );
+    }
+
+    #[test]
+    fn test_login_outside_allowed_hours_is_refused() {
+        use crate::config::AccessWindow;
+
+        let mut mock = MockSQliteUserRepository::new();
+        mock.expect_get_user().returning(|_| {
+            Ok(User::new(
+                "email@email.test",
+                &utils::hash("password").unwrap(),
+            ))
+        });
+        mock.expect_get_attributes()
+            .returning(|_| Ok(HashMap::new()));
+        let registry = FactorRegistry::new();
+        let store = ChallengeStore::default();
+        let mut access = AccessHoursConfig::default();
+        access.users.insert(
+            "email@email.test".to_string(),
+            vec![AccessWindow {
+                days: vec![],
+                start_hour: 8,
+                end_hour: 18,
+            }],
+        );
+
+        let login = |hour: u32| {
+            _begin_login(
+                "email@email.test",
+                "password",
+                &mock,
+                &registry,
+                &store,
+                Decision::Allow,
+                &access,
+                Utc.ymd(2021, 4, 28).and_hms(hour, 0, 0),
+            )
+        };
+
+        assert_eq!(login(20), Err(AuthError::OutsideAllowedHours));
+        assert!(matches!(login(10), Ok(LoginOutcome::Authenticated(_))));
+        // the login without the 2fa too (e.g. `login_async`)
+        assert_eq!(
+            _login(
+                "email@email.test",
+                "password",
+                &mock,
+                &access,
+                Utc.ymd(2021, 4, 28).and_hms(20, 0, 0)
+            ),
+            Err(AuthError::OutsideAllowedHours)
+        );
+    }
+
+    #[test]
+    fn test_confirm_login() {
+        let mut mock = MockSQliteUserRepository::new();
+        mock.expect_list_users()
+            .returning(|_| Ok(vec![User::new("email@email.test", "passwd_hash")]));
+        mock.expect_get_attributes()
+            .returning(|_| Ok(HashMap::new()));
+        let registry = registry(MockSQliteRecoveryCodeRepository::new());
+        let store = ChallengeStore::default();
+        let access = AccessHoursConfig::default();
+
+        let outcome = _confirm_login(1, &mock, &registry, &store, &access, Utc::now());
+        assert!(matches!(outcome, Ok(LoginOutcome::Authenticated(_))));
+
+        // the users who set up a second factor still complete the login with it
+        let mut mock = MockSQliteUserRepository::new();
+        mock.expect_list_users()
+            .returning(|_| Ok(vec![user_with_2fa()]));
+        mock.expect_get_attributes()
+            .returning(|_| Ok(HashMap::new()));
+
+        let outcome = _confirm_login(1, &mock, &registry, &store, &access, Utc::now());
+        assert!(matches!(outcome, Ok(LoginOutcome::TwoFactorRequired(_))));
+    }
+
+    #[test]
+    fn test_confirm_login_of_held_or_deleted_user() {
+        let mut mock = MockSQliteUserRepository::new();
+        mock.expect_list_users()
+            .returning(|_| Ok(vec![User::new("email@email.test", "passwd_hash")]));
+        mock.expect_get_attributes()
+            .returning(|_| Ok(held_attributes()));
+        let registry = FactorRegistry::new();
+        let store = ChallengeStore::default();
+        let access = AccessHoursConfig::default();
+
+        assert_eq!(
+            _confirm_login(1, &mock, &registry, &store, &access, Utc::now()),
+            Err(AuthError::AccountOnHold)
+        );
+
+        let mut mock = MockSQliteUserRepository::new();
+        mock.expect_list_users().returning(|_| Ok(vec![]));
+        assert_eq!(
+            _confirm_login(1, &mock, &registry, &store, &access, Utc::now()),
+            Err(AuthError::LoginError)
+        );
+    }
+
+    fn hold_policy() -> HoldConfig {
+        HoldConfig {
+            on_risk_block: true,
+            after_account_failures: 20,
+            on_not_me: true,
+        }
+    }
+
+    #[rstest(
+        outcome,
+        failures,
+        reason,
+        case(Err(AuthError::LoginBlocked), 0, Some(HoldReason::RiskBlocked { score: 90 })),
+        case(Ok(()), 19, None),
+        case(Ok(()), 20, Some(HoldReason::FailedLogins { count: 20 })),
+        case(Err(AuthError::EmailConfirmationRequired), 20, Some(HoldReason::FailedLogins { count: 20 })),
+        case(Err(AuthError::AccountOnHold), 20, None),
+        ::trace
+    )]
+    fn test_hold_reason(outcome: Result<(), AuthError>, failures: u32, reason: Option<HoldReason>) {
+        assert_eq!(
+            hold_reason(&outcome, true, 90, failures, &hold_policy()),
+            reason
+        );
+    }
+
+    #[test]
+    fn test_hold_reason_without_the_password() {
+        // an attacker who doesn't know the password gets the login blocked or fails it
+        // as many times as she/he likes, the account isn't placed on hold
+        for outcome in [Err(AuthError::LoginBlocked), Err(AuthError::LoginError)] {
+            assert_eq!(
+                hold_reason::<()>(&outcome, false, 1000, 1000, &hold_policy()),
+                None
+            );
+        }
+    }
+
+    #[test]
+    fn test_hold_reason_disabled() {
+        let policy = HoldConfig::default();
+
+        assert_eq!(
+            hold_reason::<()>(&Err(AuthError::LoginBlocked), true, 90, 0, &policy),
+            None
+        );
+        assert_eq!(hold_reason::<()>(&Ok(()), true, 90, 1000, &policy), None);
+    }
+
+    #[test]
+    fn test_complete_2fa_only_once() {
+        let mock = repository_with_2fa();
+        let registry = registry(MockSQliteRecoveryCodeRepository::new());
+        let store = ChallengeStore::default();
+        let now = Utc::now();
+        let challenge = store.issue("email@email.test", vec![TOTP], now).unwrap();
+
+        let res = _complete_2fa(
+            &challenge,
+            TOTP,
+            &valid_code(),
+            &mock,
+            &registry,
+            &store,
+            now,
+        );
+        assert_eq!(res.unwrap().factor, TOTP);
+
+        // the challenge can't be replayed
+        let res = _complete_2fa(
+            &challenge,
+            TOTP,
+            &valid_code(),
+            &mock,
+            &registry,
+            &store,
+            now,
+        );
+        assert_eq!(res, Err(AuthError::InvalidChallenge));
+    }
+
+    #[test]
+    fn test_challenge_bound_to_client() {
+        let mock = repository_with_2fa();
+        let registry = registry(MockSQliteRecoveryCodeRepository::new());
+        let store = ChallengeStore::default();
+        let now = Utc::now();
+        let challenge = store.issue("email@email.test", vec![TOTP], now).unwrap();
+        store
+            .bind(challenge.get_id(), Some("client".to_string()))
+            .unwrap();
+
+        assert_eq!(
+            store.check_client(challenge.get_id(), Some("client")),
+            Ok(())
+        );
+        // another client revokes it, even for the right client afterwards
+        assert_eq!(
+            store.check_client(challenge.get_id(), Some("thief")),
+            Err(AuthError::ClientMismatch)
+        );
+        let res = _complete_2fa(
+            &challenge,
+            TOTP,
+            &valid_code(),
+            &mock,
+            &registry,
+            &store,
+            now,
+        );
+        assert_eq!(res, Err(AuthError::InvalidChallenge));
+    }
+
+    #[test]
+    fn test_unbound_challenge_accepts_any_client() {
+        let store = ChallengeStore::default();
+        let challenge = store
+            .issue("email@email.test", vec![TOTP], Utc::now())
+            .unwrap();
+        store.bind(challenge.get_id(), None).unwrap();
+
+        assert_eq!(
+            store.check_client(challenge.get_id(), Some("client")),
+            Ok(())
+        );
+        assert_eq!(store.check_client(challenge.get_id(), None), Ok(()));
+        assert_eq!(store.check_client("unknown", Some("client")), Ok(()));
+    }
+
+    #[test]
+    fn test_complete_2fa_after_expiry() {
+        let mock = repository_with_2fa();
+        let registry = registry(MockSQliteRecoveryCodeRepository::new());
+        let store = ChallengeStore::default();
+        let now = Utc::now();
+        let challenge = store.issue("email@email.test", vec![TOTP], now).unwrap();
+
+        let later = now + Duration::seconds(CHALLENGE_VALIDITY_SECS);
+        let res = _complete_2fa(
+            &challenge,
+            TOTP,
+            &valid_code(),
+            &mock,
+            &registry,
+            &store,
+            later,
+        );
+
+        assert_eq!(res, Err(AuthError::ChallengeExpired));
+        assert_eq!(
+            store
+                .store
+                .get(&ephemeral::challenge_key(challenge.get_id())),
+            Ok(None)
+        );
+    }
+
+    #[test]
+    fn test_complete_2fa_with_too_many_wrong_codes() {
+        let mock = repository_with_2fa();
+        let registry = registry(MockSQliteRecoveryCodeRepository::new());
+        let store = ChallengeStore::default();
+        let now = Utc::now();
+        let challenge = store.issue("email@email.test", vec![TOTP], now).unwrap();
+
+        for _ in 0..CHALLENGE_MAX_ATTEMPTS {
+            let res = _complete_2fa(&challenge, TOTP, "000000", &mock, &registry, &store, now);
+            assert_eq!(res, Err(AuthError::InvalidAuthenticationCode));
+        }
+
+        let res = _complete_2fa(
+            &challenge,
+            TOTP,
+            &valid_code(),
+            &mock,
+            &registry,
+            &store,
+            now,
+        );
+        assert_eq!(res, Err(AuthError::InvalidChallenge));
+    }
+
+    #[test]
+    fn test_complete_2fa_with_oversized_code() {
+        let mock = repository_with_2fa();
+        let registry = registry(MockSQliteRecoveryCodeRepository::new());
+        let store = ChallengeStore::default();
+        let now = Utc::now();
+        let challenge = store.issue("email@email.test", vec![TOTP], now).unwrap();
+
+        let huge = "0".repeat(1 << 20);
+        let res = _complete_2fa(&challenge, TOTP, &huge, &mock, &registry, &store, now);
+        assert_eq!(res, Err(AuthError::InputTooLong));
+
+        // it wasn't counted as an attempt
+        let res = _complete_2fa(
+            &challenge,
+            TOTP,
+            &valid_code(),
+            &mock,
+            &registry,
+            &store,
+            now,
+        );
+        assert_eq!(res.unwrap().factor, TOTP);
+    }
+
+    #[test]
+    fn test_complete_2fa_with_recovery_code() {
+        let mock = repository_with_2fa();
+        let mut codes_mock = MockSQliteRecoveryCodeRepository::new();
+        codes_mock.expect_use_code().returning(|_, _| Ok(true));
+        let registry = registry(codes_mock);
+        let store = ChallengeStore::default();
+        let now = Utc::now();
+        let challenge = store
+            .issue("email@email.test", vec![TOTP, RECOVERY_CODE], now)
+            .unwrap();
+
+        let res = _complete_2fa(
+            &challenge,
+            RECOVERY_CODE,
+            "ABCDE-FGHJK",
+            &mock,
+            &registry,
+            &store,
+            now,
+        )
+        .unwrap();
+
+        assert_eq!(res.factor, RECOVERY_CODE);
+        assert_eq!(
+            res.recovery,
+            Some(Consumption {
+                remaining: 1,
+                should_regenerate: true
+            })
+        );
+    }
+
+    #[test]
+    fn test_complete_2fa_with_unknown_factor() {
+        let mock = repository_with_2fa();
+        let registry = registry(MockSQliteRecoveryCodeRepository::new());
+        let store = ChallengeStore::default();
+        let now = Utc::now();
+        let challenge = store.issue("email@email.test", vec![TOTP], now).unwrap();
+
+        let res = _complete_2fa(&challenge, "webauthn", "42", &mock, &registry, &store, now);
+
+        assert_eq!(res, Err(AuthError::UnknownFactor));
+    }
+
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    async fn test_login_async() {
+        use crate::db::repository::InMemoryUserRepository;
+
+        let repository = Arc::new(InMemoryUserRepository::new());
+        UserRepository::create_user(
+            repository.as_ref(),
+            "email@email.test",
+            &utils::hash("password").unwrap(),
+        )
+        .unwrap();
+        let email = Email::parse("email@email.test").unwrap();
+
+        let u = login_async(
+            &email,
+            &Password::parse("password").unwrap(),
+            repository.clone(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(u.get_email(), "email@email.test");
+
+        assert_eq!(
+            login_async(
+                &email,
+                &Password::parse("wrong password").unwrap(),
+                repository
+            )
+            .await,
+            Err(AuthError::LoginError)
+        );
+    }
+
+    #[test]
+    fn test_unlock_forgets_the_failed_logins() {
+        let mut mock = MockSQliteUserRepository::new();
+        mock.expect_list_users()
+            .withf(|f| *f == UserFilter::new().with_id(1))
+            .returning(|_| Ok(vec![User::new("email@email.test", "passwd_hash")]));
+        let throttle = LoginThrottle::default();
+        let policy = CaptchaConfig {
+            after_account_failures: 1,
+            ..CaptchaConfig::default()
+        };
+        throttle.record_failure("email@email.test", "source", &policy);
+        assert_eq!(
+            throttle.check("email@email.test", "other source", &policy),
+            Err(AuthError::CaptchaRequired)
+        );
+
+        assert_eq!(_unlock(1, &mock, &throttle), Ok(()));
+        assert_eq!(
+            throttle.check("email@email.test", "other source", &policy),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn test_unlock_of_a_deleted_account() {
+        let mut mock = MockSQliteUserRepository::new();
+        mock.expect_list_users().returning(|_| Ok(vec![]));
+
+        assert_eq!(
+            _unlock(1, &mock, &LoginThrottle::default()),
+            Err(AuthError::InvalidActionLink)
+        );
+    }
+
+    #[test]
+    fn test_find_challenge() {
+        let store = ChallengeStore::default();
+        let registry = FactorRegistry::new().with(TotpFactor);
+        let now = Utc::now();
+        let challenge = store
+            .issue("email@email.test", vec![TOTP, RECOVERY_CODE], now)
+            .unwrap();
+
+        // only the factors of the registry are listed
+        let found = store.find(challenge.get_id(), &registry, now).unwrap();
+        assert_eq!(found.get_id(), challenge.get_id());
+        assert_eq!(found.get_expires_at(), challenge.get_expires_at());
+        assert_eq!(found.get_factors(), &[TOTP]);
+
+        assert_eq!(
+            store.find("unknown", &registry, now),
+            Err(AuthError::InvalidChallenge)
+        );
+        assert_eq!(
+            store.find(
+                challenge.get_id(),
+                &registry,
+                now + Duration::seconds(CHALLENGE_VALIDITY_SECS)
+            ),
+            Err(AuthError::ChallengeExpired)
+        );
+    }
 }
@@ -0,0 +1,54 @@
+/*!
+ * Read-only mode of the system, e.g. during a migration or a backup
+ *
+ * Once `read_only` is set in the `[maintenance]` section of the configuration,
+ * the users can still login (password, second factors, recovery codes, API keys
+ * & certificates), but every change refuses with `AuthError::MaintenanceMode`:
+ * the registrations, the resets & the recoveries, the 2FA & profile changes and
+ * the administration of the accounts. Only what a login records itself is still
+ * written (last login, used recovery codes, accepted terms & security holds).
+ *
+ * The configuration is read once per process, so the commands pick the mode up
+ * by themselves and the applications embedding the library by restarting.
+ *
+ * # Author
+ * Doran Kayoumi <doran.kayoumi@heig-vd.ch>
+ */
+
+use crate::config::{self, MaintenanceConfig};
+use crate::errors::AuthError;
+
+/// Public function for the read-only check
+/// See `_check_writable` for more info
+///
+pub fn check_writable() -> Result<(), AuthError> {
+    _check_writable(&config::get().maintenance)
+}
+
+/// Check that changes are allowed, to call before a flow changes anything
+///
+/// # Arguments
+///
+/// * `policy` - whether the system is in read-only mode
+///
+fn _check_writable(policy: &MaintenanceConfig) -> Result<(), AuthError> {
+    if policy.read_only {
+        Err(AuthError::MaintenanceMode)
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_check_writable() {
+        assert_eq!(_check_writable(&MaintenanceConfig::default()), Ok(()));
+        assert_eq!(
+            _check_writable(&MaintenanceConfig { read_only: true }),
+            Err(AuthError::MaintenanceMode)
+        );
+    }
+}
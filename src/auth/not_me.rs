@@ -0,0 +1,224 @@
+/*!
+ * "This wasn't me" links of the security alerts
+ *
+ * The alert sent once a password was changed has a link (see `action.rs`) the
+ * user follows if she/he didn't change it. The account is then placed on
+ * security hold (unless `on_not_me` is disabled in the `[hold]` section of the
+ * configuration), so even the new password is refused, & a reset token is sent
+ * to the user. With it & a second factor, she/he releases the hold & chooses
+ * a new password (see `hold.rs`).
+ *
+ * The system doesn't keep sessions, so there is none to revoke: the hold stops
+ * every login, whatever flow checks the password (see `login::_login`), the
+ * 2FA challenges already handed out & the JWTs issued after a login (see
+ * `jwt::verify_jwt`).
+ *
+ * # Author
+ * Doran Kayoumi <doran.kayoumi@heig-vd.ch>
+ */
+
+use chrono::{Duration, Utc};
+
+use super::hold::{self, HoldReason};
+use super::{action, maintenance, reset};
+use crate::audit::{self, AuditEvent};
+use crate::config::{self, HoldConfig};
+use crate::db::ephemeral::{self, TokenStore};
+use crate::db::repository::{
+    AuditRepository, SQliteAuditRepository, SQliteUserRepository, UserFilter, UserRepository,
+};
+use crate::errors::AuthError;
+use crate::mail::{self, Mailer};
+
+/// Purpose of the "this wasn't me" links
+pub const PURPOSE: &str = "not-me";
+/// How long (in hours) a "this wasn't me" link is valid
+pub const LINK_VALIDITY_HOURS: i64 = 72;
+
+/// Issue the "this wasn't me" link of an alert
+/// Returns `None` if the action links aren't set up, the alert is sent without it
+///
+/// # Arguments
+///
+/// * `user` - id of the user the alert is sent to
+///
+pub fn issue_link(user: i32) -> Option<String> {
+    action::issue(PURPOSE, user, Duration::hours(LINK_VALIDITY_HOURS)).ok()
+}
+
+/// Public function for the report of a change the user didn't make
+/// See `_report` for more info
+///
+pub fn report(token: &str) -> Result<(), AuthError> {
+    maintenance::check_writable()?;
+    let link = action::redeem(token, PURPOSE)?;
+    let repository = SQliteUserRepository::new();
+    let audit_repository = SQliteAuditRepository::new();
+    _report(
+        link.user,
+        &repository,
+        &audit_repository,
+        &*ephemeral::durable(),
+        mail::default_mailer(),
+        &config::get().hold,
+        &action::secret()?,
+    )
+}
+
+/// Secure an account whose owner reported a change she/he didn't make:
+/// place it on hold (if the policy says so) & send a reset token to the owner
+///
+/// # Arguments
+///
+/// * `user` - id of the user who followed the link
+///
+/// * `repository` - the user repository to interact with
+///
+/// * `audit_repository` - the audit repository to write in
+///
+/// * `store` - the store of the reset tokens
+///
+/// * `mailer` - the mailer used to send the reset token
+///
+/// * `policy` - whether the account is placed on hold
+///
+/// * `secret` - the key signing the reset token
+///
+fn _report(
+    user: i32,
+    repository: &dyn UserRepository,
+    audit_repository: &dyn AuditRepository,
+    store: &dyn TokenStore,
+    mailer: &dyn Mailer,
+    policy: &HoldConfig,
+    secret: &str,
+) -> Result<(), AuthError> {
+    // Note: the account may have been deleted since the alert was sent
+    let u = repository
+        .list_users(&UserFilter::new().with_id(user))
+        .map_err(|_| AuthError::ActionLinkError)?
+        .into_iter()
+        .next()
+        .ok_or(AuthError::InvalidActionLink)?;
+    let email = u.get_email();
+
+    let _ = audit::record(
+        audit_repository,
+        Some(user),
+        AuditEvent::NotMeReported,
+        None,
+    );
+    if policy.on_not_me {
+        hold::_place_hold(&email, HoldReason::NotMe, repository, audit_repository)?;
+    }
+
+    reset::_generate_reset_token(&email, repository, store, mailer, secret, Utc::now())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::db::ephemeral::InMemoryTokenStore;
+    use crate::db::models::User;
+    use crate::db::repository::{MockSQliteAuditRepository, MockSQliteUserRepository};
+    use crate::mail::MockConsoleMailer;
+    use std::collections::HashMap;
+
+    const SECRET: &str = "0123456789abcdef0123456789abcdef";
+
+    fn audit_mock() -> MockSQliteAuditRepository {
+        let mut mock = MockSQliteAuditRepository::new();
+        mock.expect_create_entry().returning(|_, _, _| Ok(()));
+        mock
+    }
+
+    /// Repository of a user, not on hold
+    fn repository() -> MockSQliteUserRepository {
+        let mut mock = MockSQliteUserRepository::new();
+        mock.expect_list_users()
+            .withf(|f| *f == UserFilter::new().with_id(42))
+            .returning(|_| Ok(vec![User::new("email@email.test", "passwd_hash")]));
+        mock.expect_get_user()
+            .returning(|e| Ok(User::new(e, "passwd_hash")));
+        mock.expect_get_attributes()
+            .returning(|_| Ok(HashMap::new()));
+        mock
+    }
+
+    fn mailer() -> MockConsoleMailer {
+        let mut mailer = MockConsoleMailer::new();
+        mailer
+            .expect_send()
+            .withf(|e| e.to == "email@email.test" && e.body.contains("reset token"))
+            .times(1)
+            .returning(|_| Ok(()));
+        mailer
+    }
+
+    #[test]
+    fn test_report_places_the_account_on_hold() {
+        let mut repository = repository();
+        repository
+            .expect_set_attribute()
+            .withf(|_, attr, _| attr == hold::HOLD_ATTRIBUTE || attr == hold::HOLD_REASON_ATTRIBUTE)
+            .times(2)
+            .returning(|_, _, _| Ok(()));
+
+        assert_eq!(
+            _report(
+                42,
+                &repository,
+                &audit_mock(),
+                &InMemoryTokenStore::new(),
+                &mailer(),
+                &HoldConfig::default(),
+                SECRET
+            ),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn test_report_without_hold() {
+        let mut repository = repository();
+        repository.expect_set_attribute().times(0);
+        let policy = HoldConfig {
+            on_not_me: false,
+            ..HoldConfig::default()
+        };
+
+        assert_eq!(
+            _report(
+                42,
+                &repository,
+                &audit_mock(),
+                &InMemoryTokenStore::new(),
+                &mailer(),
+                &policy,
+                SECRET
+            ),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn test_report_of_a_deleted_account() {
+        let mut repository = MockSQliteUserRepository::new();
+        repository.expect_list_users().returning(|_| Ok(vec![]));
+        let mut mailer = MockConsoleMailer::new();
+        mailer.expect_send().times(0);
+
+        assert_eq!(
+            _report(
+                42,
+                &repository,
+                &audit_mock(),
+                &InMemoryTokenStore::new(),
+                &mailer,
+                &HoldConfig::default(),
+                SECRET
+            ),
+            Err(AuthError::InvalidActionLink)
+        );
+    }
+}
@@ -0,0 +1,194 @@
+/*!
+ * Pseudonymous identifiers of the users, for the metrics, webhooks & logs
+ *
+ * The pseudonym of a user is the HMAC-SHA256 of her/his id, keyed with
+ * `PSEUDONYM_SECRET` (in the environment or the `.env` file). It's stable (the
+ * email can change, the id doesn't) and reveals nothing about the user, so the
+ * integrations get it instead of the email. Only who holds the key can map a
+ * pseudonym back to its user, by computing the pseudonyms of the users until one
+ * matches, every resolution is recorded in the audit log.
+ *
+ * Replacing the key gives every user a new pseudonym, the old ones can't be
+ * resolved anymore.
+ *
+ * # Author
+ * Doran Kayoumi <doran.kayoumi@heig-vd.ch>
+ */
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::env;
+
+use crate::audit::{self, AuditEvent};
+use crate::db::models::User;
+use crate::db::repository::{
+    AuditRepository, SQliteAuditRepository, SQliteUserRepository, UserFilter, UserRepository,
+};
+use crate::errors::AuthError;
+
+/// Variable holding the key of the pseudonyms
+pub const SECRET_VARIABLE: &str = "PSEUDONYM_SECRET";
+/// Minimum length of the key
+pub const MIN_SECRET_LEN: usize = 32;
+/// Prefix of the pseudonyms, so they're recognized in the logs
+const PREFIX: &str = "psn_";
+/// Bytes of the HMAC kept in a pseudonym
+const LEN: usize = 16;
+
+/// Public function for the pseudonym of a user
+/// See `_pseudonym` for more info
+///
+pub fn pseudonym(u: &User) -> Result<String, AuthError> {
+    pseudonym_of(u.get_id())
+}
+
+/// Same as `pseudonym`, with the id of the user (e.g. of a `db doctor` finding)
+pub fn pseudonym_of(user: i32) -> Result<String, AuthError> {
+    _pseudonym(user, &secret()?)
+}
+
+/// Public function for the resolution of a pseudonym
+/// See `_resolve` for more info
+///
+pub fn resolve(pseudonym: &str) -> Result<Option<User>, AuthError> {
+    let repository = SQliteUserRepository::new();
+    let audit_repository = SQliteAuditRepository::new();
+    _resolve(pseudonym, &secret()?, &repository, &audit_repository)
+}
+
+/// Get the key, a missing or too short one is an error
+fn secret() -> Result<String, AuthError> {
+    dotenv::dotenv().ok();
+    env::var(SECRET_VARIABLE)
+        .ok()
+        .filter(|s| s.len() >= MIN_SECRET_LEN)
+        .ok_or(AuthError::PseudonymError)
+}
+
+/// Compute the pseudonym of a user, `psn_` followed by 32 hex digits
+///
+/// # Arguments
+///
+/// * `user` - id of the user
+///
+/// * `secret` - the key of the pseudonyms
+///
+fn _pseudonym(user: i32, secret: &str) -> Result<String, AuthError> {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).map_err(|_| AuthError::PseudonymError)?;
+    mac.update(format!("user:{}", user).as_bytes());
+    let digest = mac.finalize().into_bytes();
+
+    Ok(digest
+        .iter()
+        .take(LEN)
+        .fold(PREFIX.to_string(), |p, b| p + &format!("{:02x}", b)))
+}
+
+/// Find the user behind a pseudonym, `None` is returned if it's no one's
+/// (e.g. the user was deleted or the key replaced)
+///
+/// # Arguments
+///
+/// * `pseudonym` - the pseudonym to resolve
+///
+/// * `secret` - the key of the pseudonyms
+///
+/// * `repository` - the user repository to interact with
+///
+/// * `audit_repository` - the audit repository to write in
+///
+fn _resolve(
+    pseudonym: &str,
+    secret: &str,
+    repository: &dyn UserRepository,
+    audit_repository: &dyn AuditRepository,
+) -> Result<Option<User>, AuthError> {
+    let well_formed = pseudonym
+        .strip_prefix(PREFIX)
+        .is_some_and(|h| h.len() == LEN * 2 && h.chars().all(|c| c.is_ascii_hexdigit()));
+    if !well_formed {
+        return Ok(None);
+    }
+
+    for u in repository.iter_users(&UserFilter::new()) {
+        let u = u.map_err(|_| AuthError::PseudonymError)?;
+        if _pseudonym(u.get_id(), secret)? == pseudonym.to_ascii_lowercase() {
+            let _ = audit::record(
+                audit_repository,
+                Some(u.get_id()),
+                AuditEvent::PseudonymResolved,
+                Some(pseudonym.to_string()),
+            );
+            return Ok(Some(u));
+        }
+    }
+
+    Ok(None)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::db::repository::{InMemoryUserRepository, MockSQliteAuditRepository};
+
+    const SECRET: &str = "0123456789abcdef0123456789abcdef";
+
+    /// Alice (1) & Bob (2)
+    fn users() -> InMemoryUserRepository {
+        let repository = InMemoryUserRepository::new();
+        for e in &["alice@email.test", "bob@email.test"] {
+            repository.create_user(e, "passwd_hash").unwrap();
+        }
+        repository
+    }
+
+    #[test]
+    fn test_pseudonym_is_stable_and_keyed() {
+        let p = _pseudonym(1, SECRET).unwrap();
+
+        assert!(p.starts_with(PREFIX));
+        assert_eq!(p.len(), PREFIX.len() + LEN * 2);
+        assert_eq!(_pseudonym(1, SECRET).unwrap(), p);
+        assert_ne!(_pseudonym(2, SECRET).unwrap(), p);
+        assert_ne!(_pseudonym(1, &SECRET.replace('0', "1")).unwrap(), p);
+    }
+
+    #[test]
+    fn test_resolve() {
+        let mut audit_repository = MockSQliteAuditRepository::new();
+        audit_repository
+            .expect_create_entry()
+            .withf(|u, e, _| *u == Some(2) && e == "PseudonymResolved")
+            .times(1)
+            .returning(|_, _, _| Ok(()));
+        // Note: the case of the hex digits doesn't matter
+        let bob = _pseudonym(2, SECRET).unwrap()[PREFIX.len()..].to_uppercase();
+        let found = _resolve(
+            &format!("{}{}", PREFIX, bob),
+            SECRET,
+            &users(),
+            &audit_repository,
+        );
+        assert_eq!(
+            found.unwrap().map(|u| u.get_email()),
+            Some("bob@email.test".to_string())
+        );
+    }
+
+    #[test]
+    fn test_resolve_unknown_pseudonym() {
+        let mut audit_repository = MockSQliteAuditRepository::new();
+        audit_repository.expect_create_entry().times(0);
+
+        let unknown = _pseudonym(3, SECRET).unwrap();
+        assert_eq!(
+            _resolve(&unknown, SECRET, &users(), &audit_repository),
+            Ok(None)
+        );
+        assert_eq!(
+            _resolve("alice@email.test", SECRET, &users(), &audit_repository),
+            Ok(None)
+        );
+    }
+}
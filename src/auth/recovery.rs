@@ -0,0 +1,268 @@
+/*!
+ * Functions related to the 2fa recovery codes
+ *
+ * The recovery codes let a user login when she/he lost the device generating
+ * her/his 2fa codes. Each code can only be used once, when only a few are left
+ * the user should generate a new set (see `Consumption::should_regenerate`).
+ *
+ * # Author
+ * Doran Kayoumi <doran.kayoumi@heig-vd.ch>
+ */
+
+use rand::{thread_rng, Rng};
+
+use super::maintenance;
+use crate::db::models::User;
+use crate::db::repository::{RecoveryCodeRepository, SQliteRecoveryCodeRepository};
+use crate::errors::AuthError;
+use crate::portable::token::{self, CODE_ALPHABET, CODE_LEN};
+use crate::utils;
+use crate::validation::MAX_TOKEN_BYTES;
+
+/// Number of codes in a set
+pub const CODE_COUNT: usize = 10;
+/// Below this number of unused codes, the user should generate a new set
+pub const LOW_CODE_THRESHOLD: i64 = 3;
+
+/// Result of the use of a recovery code
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct Consumption {
+    /// Number of unused codes left
+    pub remaining: i64,
+    /// Only a few codes are left, a new set should be generated
+    pub should_regenerate: bool,
+}
+
+/// Generates a code, e.g. `ABCDE-FGHJK`
+fn generate_code() -> String {
+    let mut rng = thread_rng();
+    let chars: String = (0..CODE_LEN)
+        .map(|_| CODE_ALPHABET[rng.gen_range(0..CODE_ALPHABET.len())] as char)
+        .collect();
+
+    token::format_code(&chars)
+}
+
+/// Hashes a code as entered by the user (the case & separators don't matter)
+///
+/// # Arguments
+///
+/// * `code` - the code to hash
+///
+fn hash_code(code: &str) -> String {
+    token::hash(&token::normalize_code(code))
+}
+
+/// Public function for the generation of the recovery codes
+/// See `_regenerate_codes` for more info
+///
+pub fn regenerate_codes(u: &User) -> Result<Vec<String>, AuthError> {
+    maintenance::check_writable()?;
+    let repository = SQliteRecoveryCodeRepository::new();
+    _regenerate_codes(u, &repository)
+}
+
+/// Generates a new set of recovery codes for a user
+/// The previous codes stop working at the same time
+/// Returns the codes so they can be shown to the user, only their hashes are stored
+///
+/// # Arguments
+///
+/// * `u` - the user owning the codes
+///
+/// * `repository` - the recovery code repository to interact with
+///
+fn _regenerate_codes(
+    u: &User,
+    repository: &dyn RecoveryCodeRepository,
+) -> Result<Vec<String>, AuthError> {
+    let codes: Vec<String> = (0..CODE_COUNT).map(|_| generate_code()).collect();
+    let hashes: Vec<String> = codes.iter().map(|c| hash_code(c)).collect();
+
+    repository
+        .replace_codes(u.get_id(), &hashes)
+        .map_err(|e| e.to_auth_error(AuthError::RecoveryCodesError))?;
+
+    Ok(codes)
+}
+
+/// Public function for the use of a recovery code
+/// See `_consume_code` for more info
+///
+pub fn consume_code(u: &User, code: &str) -> Result<Consumption, AuthError> {
+    let repository = SQliteRecoveryCodeRepository::new();
+    _consume_code(u, code, &repository)
+}
+
+/// Uses one of the recovery codes of a user, it can't be used again afterwards
+///
+/// # Arguments
+///
+/// * `u` - the user owning the code
+///
+/// * `code` - the code entered by the user
+///
+/// * `repository` - the recovery code repository to interact with
+///
+pub(crate) fn _consume_code(
+    u: &User,
+    code: &str,
+    repository: &dyn RecoveryCodeRepository,
+) -> Result<Consumption, AuthError> {
+    utils::check_length(code, MAX_TOKEN_BYTES)?;
+    match repository.use_code(u.get_id(), &hash_code(code)) {
+        Ok(true) => {}
+        Ok(false) => return Err(AuthError::InvalidRecoveryCode),
+        Err(e) => return Err(e.to_auth_error(AuthError::RecoveryCodesError)),
+    }
+
+    // Note: the code was used, if the count fails the user is simply not warned
+    let remaining = repository.count_unused_codes(u.get_id()).unwrap_or(0);
+
+    Ok(Consumption {
+        remaining,
+        should_regenerate: remaining < LOW_CODE_THRESHOLD,
+    })
+}
+
+/// Public function for the revocation of the recovery codes
+/// See `_revoke_codes` for more info
+///
+pub fn revoke_codes(u: &User) -> Result<(), AuthError> {
+    maintenance::check_writable()?;
+    let repository = SQliteRecoveryCodeRepository::new();
+    _revoke_codes(u, &repository)
+}
+
+/// Removes every recovery code of a user (e.g. when she/he disables the 2fa)
+///
+/// # Arguments
+///
+/// * `u` - the user owning the codes
+///
+/// * `repository` - the recovery code repository to interact with
+///
+fn _revoke_codes(u: &User, repository: &dyn RecoveryCodeRepository) -> Result<(), AuthError> {
+    repository
+        .replace_codes(u.get_id(), &[])
+        .map_err(|e| e.to_auth_error(AuthError::RecoveryCodesError))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::db::repository::MockSQliteRecoveryCodeRepository;
+    use crate::errors::RecoveryCodeDBError;
+    use rstest::rstest;
+
+    #[test]
+    fn test_generate_code() {
+        let code = generate_code();
+
+        assert_eq!(code.len(), CODE_LEN + 1);
+        assert_eq!(code.chars().nth(CODE_LEN / 2), Some('-'));
+        assert!(code
+            .chars()
+            .filter(|c| *c != '-')
+            .all(|c| CODE_ALPHABET.contains(&(c as u8))));
+    }
+
+    #[test]
+    fn test_hash_code_ignores_case_and_separators() {
+        assert_eq!(hash_code("ABCDE-FGHJK"), hash_code("abcde fghjk"));
+        assert_ne!(hash_code("ABCDE-FGHJK"), hash_code("ABCDE-FGHJL"));
+    }
+
+    #[test]
+    fn test_hash_code_vector() {
+        // SHA-256 of "ABC" (the stored codes must keep matching)
+        assert_eq!(
+            hash_code("a-bc"),
+            "b5d4045c3f466fa91fe2cc6abe79232a1a57cdf104f7a26e716e0a1e2789df78"
+        );
+    }
+
+    #[test]
+    fn test_regenerate_codes() {
+        let mut mock = MockSQliteRecoveryCodeRepository::new();
+        mock.expect_replace_codes()
+            .withf(|_, h| h.len() == CODE_COUNT)
+            .times(1)
+            .returning(|_, _| Ok(()));
+
+        let u = User::new("email@email.test", "passwd_hash");
+        let codes = _regenerate_codes(&u, &mock).unwrap();
+
+        assert_eq!(codes.len(), CODE_COUNT);
+    }
+
+    #[rstest(
+        remaining,
+        should_regenerate,
+        case(LOW_CODE_THRESHOLD + 1, false),
+        case(LOW_CODE_THRESHOLD, false),
+        case(LOW_CODE_THRESHOLD - 1, true),
+        case(0, true),
+        ::trace
+    )]
+    fn test_consume_code(remaining: i64, should_regenerate: bool) {
+        let mut mock = MockSQliteRecoveryCodeRepository::new();
+        mock.expect_use_code()
+            .withf(|_, h| h == hash_code("ABCDE-FGHJK"))
+            .times(1)
+            .returning(|_, _| Ok(true));
+        mock.expect_count_unused_codes()
+            .returning(move |_| Ok(remaining));
+
+        let u = User::new("email@email.test", "passwd_hash");
+
+        assert_eq!(
+            _consume_code(&u, "abcde-fghjk", &mock),
+            Ok(Consumption {
+                remaining,
+                should_regenerate
+            })
+        );
+    }
+
+    #[test]
+    fn test_consume_invalid_code() {
+        let mut mock = MockSQliteRecoveryCodeRepository::new();
+        mock.expect_use_code().returning(|_, _| Ok(false));
+        mock.expect_count_unused_codes().times(0);
+
+        let u = User::new("email@email.test", "passwd_hash");
+
+        assert_eq!(
+            _consume_code(&u, "123456", &mock),
+            Err(AuthError::InvalidRecoveryCode)
+        );
+    }
+
+    #[test]
+    fn test_consume_code_with_db_failure() {
+        let mut mock = MockSQliteRecoveryCodeRepository::new();
+        mock.expect_use_code()
+            .returning(|_, _| Err(RecoveryCodeDBError::UseCodeError));
+
+        let u = User::new("email@email.test", "passwd_hash");
+
+        assert_eq!(
+            _consume_code(&u, "ABCDE-FGHJK", &mock),
+            Err(AuthError::RecoveryCodesError)
+        );
+    }
+
+    #[test]
+    fn test_revoke_codes() {
+        let mut mock = MockSQliteRecoveryCodeRepository::new();
+        mock.expect_replace_codes()
+            .withf(|_, h| h.is_empty())
+            .times(1)
+            .returning(|_, _| Ok(()));
+
+        let u = User::new("email@email.test", "passwd_hash");
+
+        assert_eq!(_revoke_codes(&u, &mock), Ok(()));
+    }
+}
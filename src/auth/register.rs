@@ -1,21 +1,91 @@
 /*!
  * Functions related to registrations
  *
+ * A new user is sent an action link (see `action.rs`) verifying her/his email.
+ * Following it sets the `email_verified` attribute of the account, the
+ * registration doesn't wait for it.
+ *
  * # Author
  * Doran Kayoumi <doran.kayoumi@heig-vd.ch>
  */
 
-use crate::db::repository::{SQliteUserRepository, UserRepository};
-use crate::errors::AuthError;
+use chrono::Duration;
+
+use super::validator::RegistrationValidator;
+use super::{action, maintenance};
+use crate::db::models::User;
+#[cfg(feature = "async")]
+use crate::db::repository::{run_blocking, AsyncUserRepository};
+use crate::db::repository::{SQliteUserRepository, UserFilter, UserRepository};
+use crate::errors::{AuthError, UserDBError};
+use crate::mail::templates::{self, Template};
+use crate::mail::{self, Mailer};
+use crate::stats::{self, PasswordContext};
 use crate::utils;
-use crate::validation::{is_email_valid, is_password_valid};
+use crate::validation::{Email, Password};
+#[cfg(feature = "async")]
+use std::sync::Arc;
+
+/// Purpose of the links verifying the emails
+pub const VERIFICATION_PURPOSE: &str = "verify-email";
+/// How long (in hours) a verification link is valid
+pub const VERIFICATION_VALIDITY_HOURS: i64 = 24;
+/// Attribute set once the user followed the link verifying her/his email
+pub const EMAIL_VERIFIED_ATTRIBUTE: &str = "email_verified";
 
 /// Public function for the registration
 /// See `_register` for more info
 ///
-pub fn register(email: &str, passwd: &str) -> Result<(), AuthError> {
-    let repository = SQliteUserRepository {};
-    _register(email, passwd, &repository)
+pub fn register(
+    email: &Email,
+    passwd: &Password,
+    validator: &dyn RegistrationValidator,
+) -> Result<(), AuthError> {
+    register_with_repository(email, passwd, validator, &SQliteUserRepository::new())
+}
+
+/// Same as `register`, with the users of a given storage (e.g. `PostgresUserRepository`)
+pub fn register_with_repository(
+    email: &Email,
+    passwd: &Password,
+    validator: &dyn RegistrationValidator,
+    repository: &dyn UserRepository,
+) -> Result<(), AuthError> {
+    register_with(email, passwd, validator, repository, mail::default_mailer())
+}
+
+/// Same as `register_with_repository`, with the mailer of the verification link (see `AuthService`)
+pub(super) fn register_with(
+    email: &Email,
+    passwd: &Password,
+    validator: &dyn RegistrationValidator,
+    repository: &dyn UserRepository,
+    mailer: &dyn Mailer,
+) -> Result<(), AuthError> {
+    maintenance::check_writable()?;
+    _register(email, passwd, validator, repository)?;
+    stats::record_password_score(PasswordContext::Registration, passwd.as_str(), email);
+
+    if let Ok(u) = repository.get_user(email) {
+        send_verification_link(&u, mailer);
+    }
+
+    Ok(())
+}
+
+/// Same as `register_with_repository`, run on a blocking thread of tokio (see `run_blocking`)
+#[cfg(feature = "async")]
+pub async fn register_async(
+    email: &Email,
+    passwd: &Password,
+    validator: Arc<dyn RegistrationValidator + Send + Sync>,
+    repository: Arc<dyn AsyncUserRepository>,
+) -> Result<(), AuthError> {
+    let (email, passwd) = (email.clone(), passwd.clone());
+    run_blocking(repository, AuthError::RegistrationError, move |r| {
+        register_with_repository(&email, &passwd, validator.as_ref(), r)
+    })
+    .await
 }
 
 /// User registration
@@ -24,87 +94,155 @@ pub fn register(email: &str, passwd: &str) -> Result<(), AuthError> {
 ///
 /// * `email` - email for the new user
 ///
-/// * `password` - password for the new user
+/// * `passwd` - password for the new user
+///
+/// * `validator` - additional checks the registration must pass (see `validator.rs`)
 ///
 /// * `repository` - the user repository to interact with
 ///
-fn _register(email: &str, passwd: &str, repository: &dyn UserRepository) -> Result<(), AuthError> {
-    if !is_email_valid(email) {
-        return Err(AuthError::InvalidEmail);
-    }
-
-    if let Ok(_) = repository.get_user(email) {
-        return Err(AuthError::EmailUsed);
+fn _register(
+    email: &Email,
+    passwd: &Password,
+    validator: &dyn RegistrationValidator,
+    repository: &dyn UserRepository,
+) -> Result<(), AuthError> {
+    // Note: the format of the email & the password policy were checked when they were created
+    validator.validate(email, passwd.as_str())?;
+
+    let pwh = utils::hash(passwd.as_str()).ok_or(AuthError::RegistrationError)?;
+
+    // Note: the storage rejects used emails, checking beforehand would let
+    //       two concurrent registrations with the same email through
+    match repository.create_user(email, &pwh) {
+        Ok(()) => Ok(()),
+        Err(UserDBError::EmailUsedError) => Err(AuthError::EmailUsed),
+        Err(_) => Err(AuthError::RegistrationError),
     }
+}
 
-    if !is_password_valid(passwd) {
-        return Err(AuthError::InvalidPassword);
+/// Sends a user the link verifying her/his email
+/// Note: the registration succeeded anyway, it doesn't fail if the link can't be sent
+/// (e.g. the action links aren't set up)
+///
+/// # Arguments
+///
+/// * `u` - the user whose email is verified
+///
+/// * `mailer` - the mailer used to send the link
+///
+fn send_verification_link(u: &User, mailer: &dyn Mailer) {
+    let validity = Duration::hours(VERIFICATION_VALIDITY_HOURS);
+    if let Ok(token) = action::issue(VERIFICATION_PURPOSE, u.get_id(), validity) {
+        let _ = mailer.send(&templates::render(&Template::VerifyEmail { token }, u));
     }
+}
 
-    let pwh = utils::hash(passwd);
-
-    let res = repository.create_user(email, &pwh);
-    if let Err(_) = res {
-        return Err(AuthError::RegistrationError);
-    }
+/// Public function for the verification of an email
+/// See `_verify_email` for more info
+///
+pub fn verify_email(token: &str) -> Result<(), AuthError> {
+    maintenance::check_writable()?;
+    let link = action::redeem(token, VERIFICATION_PURPOSE)?;
+    _verify_email(link.user, &SQliteUserRepository::new())
+}
 
-    Ok(())
+/// Marks the email of a user as verified, she/he followed the link sent to it
+///
+/// # Arguments
+///
+/// * `user` - id of the user the link was sent to
+///
+/// * `repository` - the user repository to interact with
+///
+fn _verify_email(user: i32, repository: &dyn UserRepository) -> Result<(), AuthError> {
+    // Note: the account may have been deleted since the link was sent
+    let u = repository
+        .list_users(&UserFilter::new().with_id(user))
+        .map_err(|_| AuthError::ActionLinkError)?
+        .into_iter()
+        .next()
+        .ok_or(AuthError::InvalidActionLink)?;
+
+    repository
+        .set_attribute(u.get_id(), EMAIL_VERIFIED_ATTRIBUTE, "true")
+        .map_err(|_| AuthError::ActionLinkError)
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::db::models::User;
+    use crate::auth::validator::{ConsentValidator, ValidatorChain};
     use crate::db::repository::MockSQliteUserRepository;
-    use crate::errors::UserDBError;
+
+    fn credentials() -> (Email, Password) {
+        (
+            Email::parse("email@test.mock").unwrap(),
+            Password::parse("password").unwrap(),
+        )
+    }
 
     #[test]
-    fn test_register_with_invalid_email() {
+    fn test_register_with_valid_info() {
         let mut mock = MockSQliteUserRepository::new();
 
-        mock.expect_get_user()
-            .returning(|_| Err(UserDBError::GetUserError));
+        mock.expect_create_user().returning(|_, _| Ok(()));
 
-        let res = _register("email", "password", &mock);
+        let (email, passwd) = credentials();
+        let res = _register(&email, &passwd, &ValidatorChain::new(), &mock);
 
-        assert_eq!(Err(AuthError::InvalidEmail), res);
+        assert_eq!(Ok(()), res);
     }
 
     #[test]
-    fn test_register_with_invalid_password() {
+    fn test_register_with_existing_user_info() {
         let mut mock = MockSQliteUserRepository::new();
 
-        mock.expect_get_user()
-            .returning(|_| Err(UserDBError::GetUserError));
+        mock.expect_create_user()
+            .returning(|_, _| Err(UserDBError::EmailUsedError));
 
-        let res = _register("email@test.mock", "p", &mock);
+        let (email, passwd) = credentials();
+        let res = _register(&email, &passwd, &ValidatorChain::new(), &mock);
 
-        assert_eq!(Err(AuthError::InvalidPassword), res);
+        assert_eq!(Err(AuthError::EmailUsed), res);
     }
 
     #[test]
-    fn test_register_with_valid_info() {
+    fn test_register_rejected_by_validator() {
         let mut mock = MockSQliteUserRepository::new();
 
-        mock.expect_get_user()
-            .returning(|_| Err(UserDBError::GetUserError));
+        mock.expect_create_user().times(0);
 
-        mock.expect_create_user().returning(|_, _| Ok(()));
-
-        let res = _register("email@test.mock", "password", &mock);
+        let validator = ConsentValidator::new(|_| false);
+        let (email, passwd) = credentials();
+        let res = _register(&email, &passwd, &validator, &mock);
 
-        assert_eq!(Ok(()), res);
+        assert_eq!(Err(AuthError::ConsentRequired), res);
     }
 
     #[test]
-    fn test_register_with_existing_user_info() {
+    fn test_verify_email() {
         let mut mock = MockSQliteUserRepository::new();
 
-        mock.expect_get_user()
-            .returning(|e| Ok(User::new(e, "passwd_hash")));
+        mock.expect_list_users()
+            .withf(|f| *f == UserFilter::new().with_id(1))
+            .returning(|_| Ok(vec![User::new("email@test.mock", "passwd_hash")]));
+        mock.expect_set_attribute()
+            .withf(|user, attr, val| {
+                *user == 1 && attr == EMAIL_VERIFIED_ATTRIBUTE && val == "true"
+            })
+            .times(1)
+            .returning(|_, _, _| Ok(()));
+
+        assert_eq!(_verify_email(1, &mock), Ok(()));
+    }
+
+    #[test]
+    fn test_verify_email_of_a_deleted_account() {
+        let mut mock = MockSQliteUserRepository::new();
 
-        let res = _register("email@test.mock", "password", &mock);
+        mock.expect_list_users().returning(|_| Ok(vec![]));
+        mock.expect_set_attribute().times(0);
 
-        assert_eq!(Err(AuthError::EmailUsed), res);
+        assert_eq!(_verify_email(1, &mock), Err(AuthError::InvalidActionLink));
     }
 }
@@ -1,109 +1,464 @@
 /*!
  * Functions related to the password reset
  *
+ * The reset token mailed to the user is an action link (see `action.rs`): it's
+ * signed, issued to the user for `PURPOSE` & expires after `CODE_VALIDITY_MIN`.
+ * Only the hash of the last one is kept, in the store outliving the process
+ * (see `db/ephemeral.rs`), so a new token replaces it & changing the password
+ * redeems it, once.
+ *
  * # Author
  * Doran Kayoumi <doran.kayoumi@heig-vd.ch>
  */
 
 use chrono::prelude::*;
+use chrono::Duration;
 
-use crate::db::repository::{SQliteUserRepository, UserRepository};
-use crate::errors::AuthError;
+use super::{action, maintenance, not_me, timing};
+use crate::audit::{self, AuditEvent};
+use crate::db::ephemeral::{self, TokenStore};
+use crate::db::models::{User, UserChangeset};
+#[cfg(feature = "async")]
+use crate::db::repository::{run_blocking, AsyncUserRepository};
+use crate::db::repository::{
+    ActionLinkRepository, SQliteAuditRepository, SQliteUserRepository, UserRepository,
+};
+use crate::errors::{ActionLinkDBError, AuthError, Completion, MailError, Warning};
+use crate::mail::templates::{self, Template};
+use crate::mail::{self, Mailer};
+use crate::portable::token;
+use crate::stats::{self, PasswordContext};
 use crate::utils;
+use crate::validation::{Email, Password, MAX_PASSWORD_BYTES, MAX_TOKEN_BYTES};
+#[cfg(feature = "async")]
+use std::sync::Arc;
 
-const CODE_VALIDITY_MIN: i64 = 15;
+/// How long (in minutes) a reset token is valid
+pub const CODE_VALIDITY_MIN: i64 = 15;
+/// Purpose of the reset tokens (see `action.rs`)
+pub const PURPOSE: &str = "reset-password";
 
 /// Public function for the reset token generation
 /// See `_generate_reset_token` for more info
 ///
-pub fn generate_reset_token(email: &str) -> Result<(), AuthError> {
-    let repository = SQliteUserRepository {};
-    _generate_reset_token(email, &repository)
+pub fn generate_reset_token(email: &Email) -> Result<(), AuthError> {
+    generate_reset_token_with_repository(email, &SQliteUserRepository::new())
+}
+
+/// Same as `generate_reset_token`, with the users of a given storage (e.g. `PostgresUserRepository`)
+pub fn generate_reset_token_with_repository(
+    email: &Email,
+    repository: &dyn UserRepository,
+) -> Result<(), AuthError> {
+    generate_reset_token_with(
+        email,
+        repository,
+        &*ephemeral::durable(),
+        mail::default_mailer(),
+        &action::secret()?,
+    )
+}
+
+/// Same as `generate_reset_token_with_repository`, with the store of the tokens, the
+/// mailer & the key signing the tokens (see `AuthService`)
+pub(super) fn generate_reset_token_with(
+    email: &Email,
+    repository: &dyn UserRepository,
+    store: &dyn TokenStore,
+    mailer: &dyn Mailer,
+    secret: &str,
+) -> Result<(), AuthError> {
+    maintenance::check_writable()?;
+    timing::padded(|| _generate_reset_token(email, repository, store, mailer, secret, Utc::now()))?;
+
+    let user = repository.get_user(email).ok().map(|u| u.get_id());
+    let _ = audit::record(
+        &SQliteAuditRepository::new(),
+        user,
+        AuditEvent::ResetRequested,
+        None,
+    );
+
+    Ok(())
+}
+
+/// Same as `generate_reset_token_with_repository`, run on a blocking thread of tokio (see `run_blocking`)
+#[cfg(feature = "async")]
+pub async fn generate_reset_token_async(
+    email: &Email,
+    repository: Arc<dyn AsyncUserRepository>,
+) -> Result<(), AuthError> {
+    let email = email.clone();
+    run_blocking(repository, AuthError::ResetError, move |r| {
+        generate_reset_token_with_repository(&email, r)
+    })
+    .await
 }
 
 /// Public function for changing the password
 /// See `_change_password` for more info
 ///
-pub fn change_password(email: &str, new_passwd: &str) -> Result<(), AuthError> {
-    let repository = SQliteUserRepository {};
-    _change_password(email, new_passwd, &repository)
+pub fn change_password(
+    email: &Email,
+    token: &str,
+    new_passwd: &Password,
+) -> Result<Completion, AuthError> {
+    change_password_with_repository(email, token, new_passwd, &SQliteUserRepository::new())
+}
+
+/// Same as `change_password`, with the users of a given storage (e.g. `PostgresUserRepository`)
+pub fn change_password_with_repository(
+    email: &Email,
+    token: &str,
+    new_passwd: &Password,
+    repository: &dyn UserRepository,
+) -> Result<Completion, AuthError> {
+    change_password_with(
+        email,
+        token,
+        new_passwd,
+        repository,
+        &*ephemeral::durable(),
+        &mail::alert_mailer(),
+        &action::secret()?,
+        Utc::now(),
+    )
+}
+
+/// Same as `change_password_with_repository`, with the store of the tokens, the mailer of
+/// the alert, the key signing the tokens & the current date & time (see `AuthService`)
+#[allow(clippy::too_many_arguments)]
+pub(super) fn change_password_with(
+    email: &Email,
+    token: &str,
+    new_passwd: &Password,
+    repository: &dyn UserRepository,
+    store: &dyn TokenStore,
+    mailer: &dyn Mailer,
+    secret: &str,
+    now: DateTime<Utc>,
+) -> Result<Completion, AuthError> {
+    maintenance::check_writable()?;
+    let completion = _change_password(
+        email,
+        token,
+        new_passwd.as_str(),
+        repository,
+        store,
+        mailer,
+        secret,
+        now,
+    )?;
+    stats::record_password_score(PasswordContext::Change, new_passwd.as_str(), email);
+
+    Ok(completion)
+}
+
+/// Same as `change_password_with_repository`, run on a blocking thread of tokio (see `run_blocking`)
+#[cfg(feature = "async")]
+pub async fn change_password_async(
+    email: &Email,
+    token: &str,
+    new_passwd: &Password,
+    repository: Arc<dyn AsyncUserRepository>,
+) -> Result<Completion, AuthError> {
+    let (email, token, new_passwd) = (email.clone(), token.to_string(), new_passwd.clone());
+    run_blocking(repository, AuthError::ResetError, move |r| {
+        change_password_with_repository(&email, &token, &new_passwd, r)
+    })
+    .await
 }
 
 /// Public function for the reset token check
 /// See `_check_token` for more info
 ///
-pub fn check_token(email: &str, token: &str) -> Result<(), AuthError> {
-    let repository = SQliteUserRepository {};
-    _check_token(email, token, &repository)
+pub fn check_token(email: &Email, token: &str) -> Result<(), AuthError> {
+    check_token_with_repository(email, token, &SQliteUserRepository::new())
+}
+
+/// Same as `check_token`, with the users of a given storage (e.g. `PostgresUserRepository`)
+pub fn check_token_with_repository(
+    email: &Email,
+    token: &str,
+    repository: &dyn UserRepository,
+) -> Result<(), AuthError> {
+    check_token_with(
+        email,
+        token,
+        repository,
+        &*ephemeral::durable(),
+        &action::secret()?,
+        Utc::now(),
+    )
+}
+
+/// Same as `check_token_with_repository`, with the store of the tokens, the key signing
+/// them & at a given date & time (see `AuthService`)
+pub(super) fn check_token_with(
+    email: &Email,
+    token: &str,
+    repository: &dyn UserRepository,
+    store: &dyn TokenStore,
+    secret: &str,
+    now: DateTime<Utc>,
+) -> Result<(), AuthError> {
+    timing::padded(|| _check_token(email, token, repository, store, secret, now))
+}
+
+/// Same as `check_token_with_repository`, run on a blocking thread of tokio (see `run_blocking`)
+#[cfg(feature = "async")]
+pub async fn check_token_async(
+    email: &Email,
+    token: &str,
+    repository: Arc<dyn AsyncUserRepository>,
+) -> Result<(), AuthError> {
+    let (email, token) = (email.clone(), token.to_string());
+    run_blocking(repository, AuthError::ResetError, move |r| {
+        check_token_with_repository(&email, &token, r)
+    })
+    .await
 }
 
 /// Public function for the sending of the reset token
-/// See `_send_reset_token` for more info
+#[deprecated(
+    since = "0.1.0",
+    note = "the token is sent by `generate_reset_token`, only its hash is kept afterwards; this does nothing"
+)]
+pub fn send_reset_token(_email: &Email) -> Result<(), MailError> {
+    Ok(())
+}
+
+/// Same as `send_reset_token`, with the users of a given storage (e.g. `PostgresUserRepository`)
+#[deprecated(
+    since = "0.1.0",
+    note = "the token is sent by `generate_reset_token_with_repository`, only its hash is kept afterwards; this does nothing"
+)]
+pub fn send_reset_token_with_repository(
+    _email: &Email,
+    _repository: &dyn UserRepository,
+) -> Result<(), MailError> {
+    Ok(())
+}
+
+/// Same as `send_reset_token_with_repository`, run on a blocking thread of tokio
+#[cfg(feature = "async")]
+#[deprecated(
+    since = "0.1.0",
+    note = "the token is sent by `generate_reset_token_async`, only its hash is kept afterwards; this does nothing"
+)]
+pub async fn send_reset_token_async(
+    _email: &Email,
+    _repository: Arc<dyn AsyncUserRepository>,
+) -> Result<(), MailError> {
+    Ok(())
+}
+
+/// Public function for the sending of the password changed alert
+/// See `_send_password_changed_alert` for more info
 ///
-pub fn send_reset_token(email: &str) {
-    let repository = SQliteUserRepository {};
-    _send_reset_token(email, &repository)
+#[deprecated(
+    since = "0.1.0",
+    note = "the alert is sent by `change_password`, which reports a failure as `Warning::NotificationNotSent`"
+)]
+pub fn send_password_changed_alert(email: &str) -> Result<(), MailError> {
+    let repository = SQliteUserRepository::new();
+    _send_password_changed_alert(email, &repository, &mail::alert_mailer())
 }
 
-/// Generate a new reset token
+/// Revoke the reset token of a user, if she/he has one (e.g. her/his account was recovered otherwise)
 ///
 /// # Arguments
 ///
-/// * `email` - the email of the user that needs a reset token
+/// * `email` - the email of the user
+///
+/// * `store` - the store of the reset tokens
+///
+pub(super) fn revoke_reset_token(email: &str, store: &dyn TokenStore) -> Result<(), AuthError> {
+    store
+        .remove(&ephemeral::reset_token_key(email))
+        .map_err(|_| AuthError::StateStoreError)
+}
+
+/// Get a user whose password can be reset
+/// Note: the service & directory accounts have no local password to reset (see `service.rs` & `ldap.rs`)
+///       a storage timing out is kept apart (`AuthError::Timeout`), trying again later may work
+///
+/// # Arguments
+///
+/// * `email` - the email of the user
 ///
 /// * `repository` - the user repository to interact with
 ///
-fn _generate_reset_token(email: &str, repository: &dyn UserRepository) -> Result<(), AuthError> {
-    // generate the reset token
-    // note: A token is generated even though the user doesn't exists
-    //       this is done to not leak the info that the user doesn't exist.
-    let token = utils::gen_token();
+fn resettable_user(email: &str, repository: &dyn UserRepository) -> Result<User, AuthError> {
+    repository
+        .get_user(email)
+        .map_err(|e| e.to_auth_error(AuthError::ResetError))
+        .and_then(|u| {
+            if u.has_local_password() {
+                Ok(u)
+            } else {
+                Err(AuthError::ResetError)
+            }
+        })
+}
 
+/// Generate a new reset token & send it to the user, the previous one (if any) is replaced
+/// Only the hash of the token is kept, until it expires
+///
+/// # Arguments
+///
+/// * `email` - the email of the user that needs a reset token
+///
+/// * `repository` - the user repository to interact with
+///
+/// * `store` - the store of the reset tokens
+///
+/// * `mailer` - the mailer used to send the token
+///
+/// * `secret` - the key signing the tokens
+///
+/// * `now` - the current date & time
+///
+pub(super) fn _generate_reset_token(
+    email: &str,
+    repository: &dyn UserRepository,
+    store: &dyn TokenStore,
+    mailer: &dyn Mailer,
+    secret: &str,
+    now: DateTime<Utc>,
+) -> Result<(), AuthError> {
     // try and find the user in the db
-    let u = repository.get_user(email);
-    if let Err(_) = u {
-        return Err(AuthError::ResetError);
-    }
+    // note: whether she/he exists doesn't show in the response time (see `generate_reset_token_with`)
+    let u = resettable_user(email, repository)?;
 
-    // update the user with the reset token
-    let mut u = u.unwrap();
-    u.set_reset_token(&token);
-    if let Err(_) = repository.update_user(&u) {
-        return Err(AuthError::ResetError);
+    let expires_at = now + Duration::minutes(CODE_VALIDITY_MIN);
+    let token = action::_issue(PURPOSE, u.get_id(), expires_at, secret)?;
+    keep_token(email, &token, store)?;
+
+    let template = Template::ResetToken { token };
+    mailer
+        .send(&templates::render(&template, &u))
+        .map_err(|_| AuthError::ResetError)
+}
+
+/// Keep the hash of the last reset token of a user, until it expires
+///
+/// # Arguments
+///
+/// * `email` - the email of the user
+///
+/// * `token` - the reset token sent to the user
+///
+/// * `store` - the store of the reset tokens
+///
+fn keep_token(email: &str, token: &str, store: &dyn TokenStore) -> Result<(), AuthError> {
+    let ttl = Duration::minutes(CODE_VALIDITY_MIN)
+        .to_std()
+        .unwrap_or_default();
+
+    store
+        .put(&ephemeral::reset_token_key(email), &token::hash(token), ttl)
+        .map_err(|_| AuthError::StateStoreError)
+}
+
+/// The reset token a user can redeem: the last one issued to her/him, once
+/// It's taken from the store when it's redeemed, so a token used twice at the same time
+/// (or replaced in between) changes the password once
+struct LastIssuedToken<'a> {
+    email: &'a str,
+    token: &'a str,
+    store: &'a dyn TokenStore,
+}
+
+impl ActionLinkRepository for LastIssuedToken<'_> {
+    fn redeem(
+        &self,
+        _jti: &str,
+        _expires_at: DateTime<Utc>,
+        _now: DateTime<Utc>,
+    ) -> Result<bool, ActionLinkDBError> {
+        let kept = self
+            .store
+            .take(&ephemeral::reset_token_key(self.email))
+            .map_err(|_| ActionLinkDBError::RedeemError)?;
+
+        Ok(kept.is_some_and(|hash| token::hashes_match(&hash, &token::hash(self.token))))
     }
+}
 
-    Ok(())
+/// Report the errors of the action links as the ones of the reset
+fn reset_error(e: AuthError) -> AuthError {
+    match e {
+        AuthError::InvalidActionLink => AuthError::TokenMismatch,
+        AuthError::ActionLinkExpired => AuthError::ExpiredToken,
+        AuthError::ActionLinkUsed => AuthError::ResetError,
+        e => e,
+    }
 }
 
-/// Change the users password
+/// Change the users password, redeem her/his reset token & warn her/him that it was changed
+/// Note: the password stays changed if the alert can't be sent, it's reported as a warning
 ///
 /// # Arguments
 ///
 /// * `email` - the email of the user that needs a password change
 ///
+/// * `token` - the reset token received by the user
+///
 /// * `new_passwd` - the new password
 ///
 /// * `repository` - the user repository to interact with
 ///
+/// * `store` - the store of the reset tokens
+///
+/// * `mailer` - the mailer used to send the alert
+///
+/// * `secret` - the key signing the tokens
+///
+/// * `now` - the current date & time
+///
+#[allow(clippy::too_many_arguments)]
 fn _change_password(
     email: &str,
+    token: &str,
     new_passwd: &str,
     repository: &dyn UserRepository,
-) -> Result<(), AuthError> {
-    let u = repository.get_user(email);
-    if let Err(_) = u {
-        return Err(AuthError::ResetError);
-    }
-    let mut u = u.unwrap();
+    store: &dyn TokenStore,
+    mailer: &dyn Mailer,
+    secret: &str,
+    now: DateTime<Utc>,
+) -> Result<Completion, AuthError> {
+    utils::check_length(token, MAX_TOKEN_BYTES)?;
+    utils::check_length(new_passwd, MAX_PASSWORD_BYTES)?;
+    let u = resettable_user(email, repository)?;
+    check_user_token(&u, email, token, store, secret, now)?;
 
-    // update the users password
-    u.set_password(&utils::hash(new_passwd));
+    // Note: hashed first, so a failure doesn't use up the token
+    let pwh = utils::hash(new_passwd).ok_or(AuthError::ResetError)?;
+
+    let last_issued = LastIssuedToken {
+        email,
+        token,
+        store,
+    };
+    action::_redeem(token, PURPOSE, secret, &last_issued, now).map_err(reset_error)?;
 
-    if let Err(_) = repository.update_user(&u) {
+    // update the users password
+    let changes = UserChangeset::new().password(&pwh);
+    let changed = repository
+        .update_user_atomic(&u, &changes)
+        .map_err(|e| e.to_auth_error(AuthError::ResetError))?;
+    // Note: the user changed since she/he was read (e.g. her/his password was changed otherwise)
+    if !changed {
         return Err(AuthError::ResetError);
     }
 
-    Ok(())
+    let mut warnings = vec![];
+    if _send_password_changed_alert(email, repository, mailer).is_err() {
+        warnings.push(Warning::NotificationNotSent);
+    }
+
+    Ok(Completion::with_warnings(warnings))
 }
 
 /// Check if an inputed reset token is valid
@@ -116,85 +471,303 @@ fn _change_password(
 ///
 /// * `repository` - the user repository to interact with
 ///
-fn _check_token(
+/// * `store` - the store of the reset tokens
+///
+/// * `secret` - the key signing the tokens
+///
+/// * `now` - the current date & time
+///
+pub(super) fn _check_token(
     email: &str,
     token: &str,
     repository: &dyn UserRepository,
+    store: &dyn TokenStore,
+    secret: &str,
+    now: DateTime<Utc>,
 ) -> Result<(), AuthError> {
-    let u = repository.get_user(email);
-    if let Err(_) = u {
-        return Err(AuthError::ResetError);
-    }
-    let u = u.unwrap();
+    utils::check_length(token, MAX_TOKEN_BYTES)?;
+    let u = resettable_user(email, repository)?;
+    check_user_token(&u, email, token, store, secret, now)
+}
 
-    // check if the user has a reset token set
-    // this should never happen but you never know
-    if u.get_reset_token() == None {
-        return Err(AuthError::ResetError);
+/// Check an inputed reset token: it must be the last one issued to the user & not be expired
+///
+/// # Arguments
+///
+/// * `u` - the user that needs a password change
+///
+/// * `email` - the email the user entered
+///
+/// * `token` - the token to validate
+///
+/// * `store` - the store of the reset tokens
+///
+/// * `secret` - the key signing the tokens
+///
+/// * `now` - the current date & time
+///
+fn check_user_token(
+    u: &User,
+    email: &str,
+    token: &str,
+    store: &dyn TokenStore,
+    secret: &str,
+    now: DateTime<Utc>,
+) -> Result<(), AuthError> {
+    let link = action::_check(token, PURPOSE, secret, now).map_err(reset_error)?;
+    if link.user != u.get_id() {
+        return Err(AuthError::TokenMismatch);
     }
 
-    let token_created_at =
-        DateTime::parse_from_rfc3339(u.get_reset_token_created_at().unwrap().as_str()).unwrap();
-    let now = DateTime::parse_from_rfc3339(Utc::now().to_rfc3339().as_str()).unwrap();
-
-    if (now - token_created_at).num_minutes() > CODE_VALIDITY_MIN {
-        Err(AuthError::ExpiredToken)
-    } else if u.get_reset_token().unwrap() != token {
-        Err(AuthError::TokenMismatch)
-    } else {
+    // Note: the store dropped the token once it expired (if one was ever issued)
+    let kept = store
+        .get(&ephemeral::reset_token_key(email))
+        .map_err(|_| AuthError::StateStoreError)?
+        .ok_or(AuthError::ExpiredToken)?;
+    if token::hashes_match(&kept, &token::hash(token)) {
         Ok(())
+    } else {
+        // Note: a newer token was issued, or the token was revoked
+        Err(AuthError::TokenMismatch)
     }
 }
 
-/// Send the reset token to the user
+/// Warn the user that her/his password was changed
+/// The alert has a "this wasn't me" link if the action links are set up (see `not_me.rs`)
 ///
 /// # Arguments
 ///
-/// * `email` - the email of the user that needs a password change
+/// * `email` - the email of the user whose password was changed
 ///
 /// * `repository` - the user repository to interact with
 ///
-fn _send_reset_token(email: &str, repository: &dyn UserRepository) {
-    let u = repository.get_user(email).unwrap();
-
-    println!();
-    println!("from: lab02.auth@heig-vd.lo");
-    println!("to: {}", email);
-    println!("subject: Lab 02 - Auth Reset token");
-    println!("message:");
-    println!("Here is your reset token: {}", u.get_reset_token().unwrap());
-    println!("Kind regards");
-    println!();
+/// * `mailer` - the mailer used to send the email
+///
+fn _send_password_changed_alert(
+    email: &str,
+    repository: &dyn UserRepository,
+    mailer: &dyn Mailer,
+) -> Result<(), MailError> {
+    let u = repository
+        .get_user(email)
+        .map_err(|_| MailError::SendError)?;
+
+    let template = Template::PasswordChanged {
+        not_me: not_me::issue_link(u.get_id()),
+    };
+    mailer.send(&templates::render(&template, &u))
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::db::models::User;
-    use crate::db::repository::MockSQliteUserRepository;
+    use crate::db::ephemeral::InMemoryTokenStore;
+    use crate::db::models::{AccountKind, User};
+    use crate::db::repository::{InMemoryUserRepository, MockSQliteUserRepository};
     use crate::errors::UserDBError;
+    use crate::mail::capture::CapturingMailer;
+    use crate::mail::MockConsoleMailer;
+
+    const SECRET: &str = "0123456789abcdef0123456789abcdef";
+
+    /// Reset token issued to a user at a given date & time, kept as her/his last one
+    fn issue_token(user: i32, store: &InMemoryTokenStore, issued_at: DateTime<Utc>) -> String {
+        let expires_at = issued_at + Duration::minutes(CODE_VALIDITY_MIN);
+        let token = action::_issue(PURPOSE, user, expires_at, SECRET).unwrap();
+        keep_token("email@email.test", &token, store).unwrap();
+        token
+    }
+
+    /// Store with a reset token issued to the user of `User::new`
+    fn store_with_token() -> (InMemoryTokenStore, String) {
+        let store = InMemoryTokenStore::new();
+        let token = issue_token(1, &store, Utc::now());
+        (store, token)
+    }
+
+    fn known_user() -> MockSQliteUserRepository {
+        let mut mock = MockSQliteUserRepository::new();
+        mock.expect_get_user()
+            .returning(|e| Ok(User::new(e, "passwd_hash")));
+        mock
+    }
 
     #[test]
     fn test_token_generation_with_unknown_user() {
         let mut mock = MockSQliteUserRepository::new();
+        let mut mailer = MockConsoleMailer::new();
+        let store = InMemoryTokenStore::new();
 
         mock.expect_get_user()
             .returning(|_| Err(UserDBError::GetUserError));
+        mailer.expect_send().times(0);
 
-        let res = _generate_reset_token("email@email.test", &mock);
+        let res = _generate_reset_token(
+            "email@email.test",
+            &mock,
+            &store,
+            &mailer,
+            SECRET,
+            Utc::now(),
+        );
 
         assert_eq!(Err(AuthError::ResetError), res);
+        assert!(store.is_empty());
     }
 
     #[test]
-    fn test_token_generation_with_known_user() {
+    fn test_service_account_cant_reset() {
         let mut mock = MockSQliteUserRepository::new();
+        let mut mailer = MockConsoleMailer::new();
+        let (store, token) = store_with_token();
 
-        mock.expect_get_user()
-            .returning(|e| Ok(User::new(e, "passwd_hash")));
-        mock.expect_update_user().returning(|_| Ok(()));
+        mock.expect_get_user().returning(|_| {
+            let mut u = User::new("ci@email.test", "passwd_hash");
+            u.set_kind(AccountKind::Service);
+            Ok(u)
+        });
+        mock.expect_update_user_atomic().times(0);
+        mailer.expect_send().times(0);
+
+        assert_eq!(
+            _generate_reset_token("ci@email.test", &mock, &store, &mailer, SECRET, Utc::now()),
+            Err(AuthError::ResetError)
+        );
+        assert_eq!(
+            _check_token(
+                "email@email.test",
+                &token,
+                &mock,
+                &store,
+                SECRET,
+                Utc::now()
+            ),
+            Err(AuthError::ResetError)
+        );
+        assert_eq!(
+            _change_password(
+                "email@email.test",
+                &token,
+                "new password",
+                &mock,
+                &store,
+                &mailer,
+                SECRET,
+                Utc::now()
+            ),
+            Err(AuthError::ResetError)
+        );
+    }
 
-        let res = _generate_reset_token("email@email.test", &mock);
+    #[test]
+    fn test_token_generation_keeps_only_the_hash() {
+        let mock = known_user();
+        let mailer = CapturingMailer::new();
+        let store = InMemoryTokenStore::new();
+
+        let res = _generate_reset_token(
+            "email@email.test",
+            &mock,
+            &store,
+            &mailer,
+            SECRET,
+            Utc::now(),
+        );
+        assert_eq!(Ok(()), res);
+
+        let token = mailer.last_token_for("email@email.test").unwrap();
+        let kept = store
+            .get(&ephemeral::reset_token_key("email@email.test"))
+            .unwrap();
+        assert_eq!(kept, Some(token::hash(&token)));
+        assert_eq!(
+            _check_token(
+                "email@email.test",
+                &token,
+                &mock,
+                &store,
+                SECRET,
+                Utc::now()
+            ),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn test_token_generation_replaces_the_previous_token() {
+        let mock = known_user();
+        let mailer = CapturingMailer::new();
+        let store = InMemoryTokenStore::new();
+        let generate = || {
+            _generate_reset_token(
+                "email@email.test",
+                &mock,
+                &store,
+                &mailer,
+                SECRET,
+                Utc::now(),
+            )
+        };
+
+        generate().unwrap();
+        let previous = mailer.last_token_for("email@email.test").unwrap();
+        generate().unwrap();
+
+        assert_eq!(
+            _check_token(
+                "email@email.test",
+                &previous,
+                &mock,
+                &store,
+                SECRET,
+                Utc::now()
+            ),
+            Err(AuthError::TokenMismatch)
+        );
+    }
+
+    #[test]
+    fn test_token_generation_with_unreachable_store() {
+        let mock = known_user();
+        let mut mailer = MockConsoleMailer::new();
+        mailer.expect_send().times(0);
+
+        let res = _generate_reset_token(
+            "email@email.test",
+            &mock,
+            &ephemeral::SQliteTokenStore::with_database_url(""),
+            &mailer,
+            SECRET,
+            Utc::now(),
+        );
+
+        assert_eq!(Err(AuthError::StateStoreError), res);
+    }
+
+    #[test]
+    fn test_token_generation_includes_anti_phishing_phrase() {
+        let mut mock = MockSQliteUserRepository::new();
+        let mut mailer = MockConsoleMailer::new();
+
+        mock.expect_get_user().returning(|e| {
+            let mut u = User::new(e, "passwd_hash");
+            u.set_anti_phishing_phrase(Some("purple elephant".to_string()));
+            Ok(u)
+        });
+        mailer
+            .expect_send()
+            .withf(|m| m.body.contains("reset token") && m.body.contains("purple elephant"))
+            .times(1)
+            .returning(|_| Ok(()));
+
+        let res = _generate_reset_token(
+            "email@email.test",
+            &mock,
+            &InMemoryTokenStore::new(),
+            &mailer,
+            SECRET,
+            Utc::now(),
+        );
 
         assert_eq!(Ok(()), res);
     }
@@ -202,79 +775,399 @@ mod test {
     #[test]
     fn test_password_change_with_unknown_user() {
         let mut mock = MockSQliteUserRepository::new();
+        let (store, token) = store_with_token();
 
         mock.expect_get_user()
             .returning(|_| Err(UserDBError::GetUserError));
 
-        let res = _change_password("email@email.test", "password", &mock);
+        let res = _change_password(
+            "email@email.test",
+            &token,
+            "password",
+            &mock,
+            &store,
+            &MockConsoleMailer::new(),
+            SECRET,
+            Utc::now(),
+        );
 
         assert_eq!(Err(AuthError::ResetError), res);
     }
 
     #[test]
     fn test_password_change_with_known_user() {
-        let mut mock = MockSQliteUserRepository::new();
+        let mut mock = known_user();
+        let (store, token) = store_with_token();
 
-        mock.expect_get_user()
-            .returning(|e| Ok(User::new(e, "passwd_hash")));
-        mock.expect_update_user().returning(|_| Ok(()));
+        mock.expect_update_user_atomic()
+            .times(1)
+            .returning(|_, _| Ok(true));
+        let mut mailer = MockConsoleMailer::new();
+        mailer.expect_send().times(1).returning(|_| Ok(()));
 
-        let res = _change_password("email@email.test", "password", &mock);
+        let res = _change_password(
+            "email@email.test",
+            &token,
+            "password",
+            &mock,
+            &store,
+            &mailer,
+            SECRET,
+            Utc::now(),
+        );
 
-        assert_eq!(Ok(()), res);
+        assert_eq!(Ok(Completion::Completed), res);
+    }
+
+    #[test]
+    fn test_password_change_with_wrong_token() {
+        let mut mock = known_user();
+        let (store, token) = store_with_token();
+        mock.expect_update_user_atomic().times(0);
+
+        let change = |token: &str| {
+            _change_password(
+                "email@email.test",
+                token,
+                "password",
+                &mock,
+                &store,
+                &MockConsoleMailer::new(),
+                SECRET,
+                Utc::now(),
+            )
+        };
+
+        assert_eq!(change("wrong token"), Err(AuthError::TokenMismatch));
+        // a token signed with another key
+        let forged = action::_issue(
+            PURPOSE,
+            1,
+            Utc::now() + Duration::minutes(1),
+            "another secret of at least 32 chars",
+        )
+        .unwrap();
+        assert_eq!(change(&forged), Err(AuthError::TokenMismatch));
+        // a token of another user
+        let other = issue_token(2, &InMemoryTokenStore::new(), Utc::now());
+        assert_eq!(change(&other), Err(AuthError::TokenMismatch));
+        // a link issued for another purpose
+        let not_me = action::_issue(
+            not_me::PURPOSE,
+            1,
+            Utc::now() + Duration::minutes(1),
+            SECRET,
+        )
+        .unwrap();
+        assert_eq!(change(&not_me), Err(AuthError::TokenMismatch));
+
+        // the token isn't used up
+        assert_eq!(
+            _check_token(
+                "email@email.test",
+                &token,
+                &mock,
+                &store,
+                SECRET,
+                Utc::now()
+            ),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn test_password_change_with_expired_token() {
+        let mut mock = known_user();
+        let store = InMemoryTokenStore::new();
+        let token = issue_token(
+            1,
+            &store,
+            Utc::now() - Duration::minutes(CODE_VALIDITY_MIN + 1),
+        );
+        mock.expect_update_user_atomic().times(0);
+
+        let res = _change_password(
+            "email@email.test",
+            &token,
+            "password",
+            &mock,
+            &store,
+            &MockConsoleMailer::new(),
+            SECRET,
+            Utc::now(),
+        );
+
+        assert_eq!(Err(AuthError::ExpiredToken), res);
+    }
+
+    #[test]
+    fn test_password_change_with_user_changed_in_between() {
+        let mut mock = known_user();
+        let (store, token) = store_with_token();
+
+        mock.expect_update_user_atomic()
+            .times(1)
+            .returning(|_, _| Ok(false));
+        let mut mailer = MockConsoleMailer::new();
+        mailer.expect_send().times(0);
+
+        let res = _change_password(
+            "email@email.test",
+            &token,
+            "password",
+            &mock,
+            &store,
+            &mailer,
+            SECRET,
+            Utc::now(),
+        );
+
+        assert_eq!(Err(AuthError::ResetError), res);
+    }
+
+    #[test]
+    fn test_password_change_redeems_the_token() {
+        let users = InMemoryUserRepository::new();
+        let repository: &dyn UserRepository = &users;
+        repository
+            .create_user("email@email.test", "passwd_hash")
+            .unwrap();
+        let u = repository.get_user("email@email.test").unwrap();
+        let store = InMemoryTokenStore::new();
+        let token = issue_token(u.get_id(), &store, Utc::now());
+        let mut mailer = MockConsoleMailer::new();
+        mailer.expect_send().times(1).returning(|_| Ok(()));
+        let change = |passwd: &str| {
+            _change_password(
+                "email@email.test",
+                &token,
+                passwd,
+                repository,
+                &store,
+                &mailer,
+                SECRET,
+                Utc::now(),
+            )
+        };
+
+        assert_eq!(change("password"), Ok(Completion::Completed));
+        assert!(store.is_empty());
+        assert_eq!(change("other password"), Err(AuthError::ExpiredToken));
+    }
+
+    #[test]
+    fn test_password_change_with_failed_alert() {
+        let mut mock = known_user();
+        let (store, token) = store_with_token();
+
+        mock.expect_update_user_atomic()
+            .times(1)
+            .returning(|_, _| Ok(true));
+        let mut mailer = MockConsoleMailer::new();
+        mailer
+            .expect_send()
+            .times(1)
+            .returning(|_| Err(MailError::SendError));
+
+        let res = _change_password(
+            "email@email.test",
+            &token,
+            "password",
+            &mock,
+            &store,
+            &mailer,
+            SECRET,
+            Utc::now(),
+        );
+
+        // the password is changed all the same
+        assert_eq!(
+            Ok(Completion::CompletedWithWarnings(vec![
+                Warning::NotificationNotSent
+            ])),
+            res
+        );
+    }
+
+    #[test]
+    fn test_oversized_inputs_are_refused() {
+        let mut mock = MockSQliteUserRepository::new();
+        mock.expect_get_user().times(0);
+        mock.expect_update_user_atomic().times(0);
+        let mut mailer = MockConsoleMailer::new();
+        mailer.expect_send().times(0);
+        let (store, token) = store_with_token();
+        let huge = "a".repeat(1 << 20);
+
+        assert_eq!(
+            _change_password(
+                "email@email.test",
+                &token,
+                &huge,
+                &mock,
+                &store,
+                &mailer,
+                SECRET,
+                Utc::now()
+            ),
+            Err(AuthError::InputTooLong)
+        );
+        assert_eq!(
+            _change_password(
+                "email@email.test",
+                &huge,
+                "password",
+                &mock,
+                &store,
+                &mailer,
+                SECRET,
+                Utc::now()
+            ),
+            Err(AuthError::InputTooLong)
+        );
+        assert_eq!(
+            _check_token("email@email.test", &huge, &mock, &store, SECRET, Utc::now()),
+            Err(AuthError::InputTooLong)
+        );
     }
 
     #[test]
     fn test_check_token_with_unknown_user() {
         let mut mock = MockSQliteUserRepository::new();
+        let (store, token) = store_with_token();
 
         mock.expect_get_user()
             .returning(|_| Err(UserDBError::GetUserError));
 
-        let res = _check_token("email@email.test", "token", &mock);
+        let res = _check_token(
+            "email@email.test",
+            &token,
+            &mock,
+            &store,
+            SECRET,
+            Utc::now(),
+        );
 
         assert_eq!(Err(AuthError::ResetError), res);
     }
 
     #[test]
-    fn test_check_token_with_known_user_and_no_reset_token() {
+    fn test_check_token_with_storage_timing_out() {
         let mut mock = MockSQliteUserRepository::new();
+        let (store, token) = store_with_token();
 
         mock.expect_get_user()
-            .returning(|e| Ok(User::new(e, "passwd_hash")));
+            .returning(|_| Err(UserDBError::Timeout));
 
-        let res = _check_token("email@email.test", "token", &mock);
+        let res = _check_token(
+            "email@email.test",
+            &token,
+            &mock,
+            &store,
+            SECRET,
+            Utc::now(),
+        );
 
-        assert_eq!(Err(AuthError::ResetError), res);
+        assert_eq!(Err(AuthError::Timeout), res);
     }
 
     #[test]
-    fn test_check_token_with_known_user_and_reset_token() {
-        let mut mock = MockSQliteUserRepository::new();
+    fn test_check_token_with_known_user_and_no_reset_token() {
+        let mock = known_user();
+        let (_, token) = store_with_token();
 
-        mock.expect_get_user().returning(|e| {
-            let mut u = User::new(e, "passwd_hash");
-            u.set_reset_token("token");
-            Ok(u)
-        });
+        let res = _check_token(
+            "email@email.test",
+            &token,
+            &mock,
+            &InMemoryTokenStore::new(),
+            SECRET,
+            Utc::now(),
+        );
+
+        assert_eq!(Err(AuthError::ExpiredToken), res);
+    }
+
+    #[test]
+    fn test_check_token_with_known_user_and_reset_token() {
+        let mock = known_user();
+        let (store, token) = store_with_token();
 
-        let res = _check_token("email@email.test", "token", &mock);
+        let res = _check_token(
+            "Email@email.test",
+            &token,
+            &mock,
+            &store,
+            SECRET,
+            Utc::now(),
+        );
 
         assert_eq!(Ok(()), res);
     }
 
     #[test]
     fn test_check_token_with_known_user_and_wrong_reset_token() {
+        let mock = known_user();
+        let (store, _) = store_with_token();
+
+        let res = _check_token(
+            "email@email.test",
+            "wrongtoken",
+            &mock,
+            &store,
+            SECRET,
+            Utc::now(),
+        );
+
+        assert_eq!(Err(AuthError::TokenMismatch), res);
+    }
+
+    #[test]
+    fn test_check_token_expiry() {
+        let mock = known_user();
+        let (store, token) = store_with_token();
+        let later = |minutes| Utc::now() + Duration::minutes(minutes);
+
+        assert_eq!(
+            _check_token("email@email.test", &token, &mock, &store, SECRET, later(14)),
+            Ok(())
+        );
+        assert_eq!(
+            _check_token("email@email.test", &token, &mock, &store, SECRET, later(16)),
+            Err(AuthError::ExpiredToken)
+        );
+    }
+
+    #[test]
+    fn test_revoke_reset_token() {
+        let mock = known_user();
+        let (store, token) = store_with_token();
+
+        assert_eq!(revoke_reset_token("email@email.test", &store), Ok(()));
+        assert_eq!(
+            _check_token(
+                "email@email.test",
+                &token,
+                &mock,
+                &store,
+                SECRET,
+                Utc::now()
+            ),
+            Err(AuthError::ExpiredToken)
+        );
+    }
+
+    #[test]
+    fn test_password_changed_alert_with_unknown_user() {
         let mut mock = MockSQliteUserRepository::new();
+        let mut mailer = MockConsoleMailer::new();
 
-        mock.expect_get_user().returning(|e| {
-            let mut u = User::new(e, "passwd_hash");
-            u.set_reset_token("token");
-            Ok(u)
-        });
+        mock.expect_get_user()
+            .returning(|_| Err(UserDBError::GetUserError));
+        mailer.expect_send().times(0);
 
-        let res = _check_token("email@email.test", "wrongtoken", &mock);
+        let res = _send_password_changed_alert("email@email.test", &mock, &mailer);
 
-        assert_eq!(Err(AuthError::TokenMismatch), res);
+        assert_eq!(Err(MailError::SendError), res);
     }
 }
@@ -0,0 +1,173 @@
+/*!
+ * Risk-based authentication
+ *
+ * Every login is given a risk score computed from what is known about it
+ * (new device, failed attempts, geo anomaly, time of day). The score decides
+ * whether the login is allowed, needs a second factor, needs the user to
+ * confirm her/his identity by email or is blocked. The weights & thresholds
+ * are set in the `[risk]` section of the configuration.
+ *
+ * # Note
+ * The system doesn't track devices or locations yet, the callers set the
+ * signals they know about (see `Signals`).
+ *
+ * # Author
+ * Doran Kayoumi <doran.kayoumi@heig-vd.ch>
+ */
+
+use chrono::{DateTime, Timelike, Utc};
+use chrono_tz::Tz;
+
+use crate::config::RiskConfig;
+
+/// What is known about a login attempt
+#[derive(PartialEq, Debug, Default, Clone, Copy)]
+pub struct Signals {
+    /// The login comes from a device the user never used
+    pub new_device: bool,
+    /// Number of failed attempts since the last successful login
+    pub failed_attempts: u32,
+    /// The login comes from an unusual location
+    pub geo_anomaly: bool,
+    /// Local hour (0 to 23) of the login, if known
+    pub hour: Option<u32>,
+}
+
+impl Signals {
+    /// Signals of a login happening at a given date & time, nothing else is known
+    ///
+    /// # Arguments
+    ///
+    /// * `at` - when the login happens
+    ///
+    /// * `timezone` - the timezone in which the hour is taken
+    ///
+    pub fn at(at: DateTime<Utc>, timezone: Tz) -> Self {
+        Self {
+            hour: Some(at.with_timezone(&timezone).hour()),
+            ..Self::default()
+        }
+    }
+}
+
+/// What to do with a login, from the least to the most restrictive
+#[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Clone, Copy)]
+pub enum Decision {
+    Allow,
+    /// The user must complete the login with a second factor
+    Require2fa,
+    /// The user must confirm her/his identity from her/his mailbox
+    RequireEmailConfirmation,
+    Block,
+}
+
+/// Risk score of a login & the decision taken
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct Assessment {
+    pub score: u32,
+    pub decision: Decision,
+}
+
+/// Check if an hour falls in the unusual hours of a policy
+/// The range can wrap around midnight (e.g. 22 to 6)
+fn is_unusual_hour(hour: u32, policy: &RiskConfig) -> bool {
+    let (start, end) = (policy.unusual_hours_start, policy.unusual_hours_end);
+
+    if start <= end {
+        (start..end).contains(&hour)
+    } else {
+        hour >= start || hour < end
+    }
+}
+
+/// Compute the risk score of a login & decide what to do with it
+///
+/// # Arguments
+///
+/// * `signals` - what is known about the login
+///
+/// * `policy` - the weights & thresholds to use
+///
+pub fn assess(signals: &Signals, policy: &RiskConfig) -> Assessment {
+    let mut score = signals
+        .failed_attempts
+        .saturating_mul(policy.failed_attempt_weight);
+    if signals.new_device {
+        score = score.saturating_add(policy.new_device_weight);
+    }
+    if signals.geo_anomaly {
+        score = score.saturating_add(policy.geo_anomaly_weight);
+    }
+    if signals.hour.is_some_and(|h| is_unusual_hour(h, policy)) {
+        score = score.saturating_add(policy.unusual_hour_weight);
+    }
+
+    let decision = if score >= policy.block_at {
+        Decision::Block
+    } else if score >= policy.require_email_confirmation_at {
+        Decision::RequireEmailConfirmation
+    } else if score >= policy.require_2fa_at {
+        Decision::Require2fa
+    } else {
+        Decision::Allow
+    };
+
+    Assessment { score, decision }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::TimeZone;
+    use rstest::rstest;
+
+    fn signals(new_device: bool, failed_attempts: u32, geo_anomaly: bool) -> Signals {
+        Signals {
+            new_device,
+            failed_attempts,
+            geo_anomaly,
+            hour: Some(12),
+        }
+    }
+
+    #[rstest(
+        s,
+        decision,
+        case(signals(false, 0, false), Decision::Allow),
+        case(signals(true, 0, false), Decision::Require2fa),
+        case(signals(true, 0, true), Decision::RequireEmailConfirmation),
+        case(signals(true, 5, true), Decision::Block),
+        case(signals(false, 1000, false), Decision::Block),
+        ::trace
+    )]
+    fn test_default_policy(s: Signals, decision: Decision) {
+        assert_eq!(assess(&s, &RiskConfig::default()).decision, decision);
+    }
+
+    #[test]
+    fn test_unusual_hours() {
+        let policy = RiskConfig {
+            unusual_hours_start: 22,
+            unusual_hours_end: 6,
+            ..RiskConfig::default()
+        };
+
+        assert!(is_unusual_hour(23, &policy));
+        assert!(is_unusual_hour(0, &policy));
+        assert!(!is_unusual_hour(6, &policy));
+        assert!(!is_unusual_hour(12, &policy));
+
+        let night = Signals::at(Utc.ymd(2021, 4, 28).and_hms(3, 0, 0), Tz::UTC);
+        assert_eq!(
+            assess(&night, &policy).score,
+            RiskConfig::default().unusual_hour_weight
+        );
+    }
+
+    #[test]
+    fn test_signals_at_uses_timezone() {
+        let at = Utc.ymd(2021, 4, 28).and_hms(22, 30, 0);
+
+        assert_eq!(Signals::at(at, Tz::Europe__Zurich).hour, Some(0));
+    }
+}
@@ -0,0 +1,246 @@
+/*!
+ * Hours in which the users are allowed to login
+ *
+ * The administrators can restrict the logins of a role (e.g. the contractors
+ * during the office hours) or of given users to some windows, see the
+ * `[access_hours]` section of the configuration. The windows of a user replace
+ * the ones of her/his role, the users without any window can login at any time.
+ *
+ * # Author
+ * Doran Kayoumi <doran.kayoumi@heig-vd.ch>
+ */
+
+use chrono::{DateTime, Datelike, Timelike, Utc};
+use std::collections::HashMap;
+
+use crate::config::{AccessHoursConfig, AccessWindow};
+use crate::db::models::User;
+use crate::db::repository::UserRepository;
+use crate::errors::AuthError;
+
+/// Name of the attribute holding the role of a user
+pub const ROLE_ATTRIBUTE: &str = "role";
+
+/// Get the windows in which a user is allowed to login, if she/he is restricted
+///
+/// # Arguments
+///
+/// * `email` - the email of the user
+///
+/// * `attributes` - the attributes of the user
+///
+/// * `policy` - the windows of the roles & users
+///
+pub fn windows_for<'a>(
+    email: &str,
+    attributes: &HashMap<String, String>,
+    policy: &'a AccessHoursConfig,
+) -> Option<&'a [AccessWindow]> {
+    policy
+        .users
+        .get(email)
+        .or_else(|| {
+            attributes
+                .get(ROLE_ATTRIBUTE)
+                .and_then(|role| policy.roles.get(role))
+        })
+        .map(Vec::as_slice)
+}
+
+/// Tell if a login at a given date & time falls in one of the windows
+///
+/// # Note
+/// For a window wrapping around midnight, the days are the ones on which the login happens
+///
+/// # Arguments
+///
+/// * `windows` - the windows in which the login is allowed
+///
+/// * `at` - when the login happens
+///
+/// * `policy` - the timezone of the windows
+///
+pub fn is_allowed(windows: &[AccessWindow], at: DateTime<Utc>, policy: &AccessHoursConfig) -> bool {
+    let local = at.with_timezone(&policy.timezone);
+    let hour = local.hour();
+
+    windows.iter().any(|w| {
+        let in_hours = if w.start_hour <= w.end_hour {
+            w.start_hour <= hour && hour < w.end_hour
+        } else {
+            hour >= w.start_hour || hour < w.end_hour
+        };
+
+        in_hours && (w.days.is_empty() || w.days.contains(&local.weekday()))
+    })
+}
+
+/// Check if a user is allowed to login at a given date & time
+///
+/// # Arguments
+///
+/// * `u` - the user trying to login
+///
+/// * `repository` - the user repository to interact with
+///
+/// * `policy` - the windows of the roles & users
+///
+/// * `now` - the current date & time
+///
+pub fn check_access(
+    u: &User,
+    repository: &dyn UserRepository,
+    policy: &AccessHoursConfig,
+    now: DateTime<Utc>,
+) -> Result<(), AuthError> {
+    // Note: most deployments don't restrict anybody, no need to read the attributes then
+    if policy.roles.is_empty() && policy.users.is_empty() {
+        return Ok(());
+    }
+
+    let attributes = repository
+        .get_attributes(u.get_id())
+        .map_err(|_| AuthError::AccessHoursError)?;
+
+    match windows_for(&u.get_email(), &attributes, policy) {
+        Some(windows) if !is_allowed(windows, now, policy) => Err(AuthError::OutsideAllowedHours),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::db::repository::MockSQliteUserRepository;
+    use chrono::{TimeZone, Weekday};
+    use chrono_tz::Tz;
+    use rstest::rstest;
+
+    fn office_hours() -> AccessWindow {
+        AccessWindow {
+            days: vec![
+                Weekday::Mon,
+                Weekday::Tue,
+                Weekday::Wed,
+                Weekday::Thu,
+                Weekday::Fri,
+            ],
+            start_hour: 8,
+            end_hour: 18,
+        }
+    }
+
+    fn policy() -> AccessHoursConfig {
+        let mut policy = AccessHoursConfig {
+            timezone: Tz::Europe__Zurich,
+            ..AccessHoursConfig::default()
+        };
+        policy
+            .roles
+            .insert("contractor".to_string(), vec![office_hours()]);
+        policy.users.insert(
+            "night@email.test".to_string(),
+            vec![AccessWindow {
+                days: vec![],
+                start_hour: 22,
+                end_hour: 6,
+            }],
+        );
+        policy
+    }
+
+    fn repository_with_role(role: &'static str) -> MockSQliteUserRepository {
+        let mut mock = MockSQliteUserRepository::new();
+        mock.expect_get_attributes().returning(move |_| {
+            let mut attributes = HashMap::new();
+            attributes.insert(ROLE_ATTRIBUTE.to_string(), role.to_string());
+            Ok(attributes)
+        });
+        mock
+    }
+
+    // 2021-04-28 is a wednesday, Zurich is at UTC+2
+    #[rstest(
+        day,
+        hour,
+        expected,
+        case(28, 6, true),
+        case(28, 15, true),
+        case(28, 16, false),
+        case(28, 5, false),
+        // saturday
+        case(1, 10, false),
+        ::trace
+    )]
+    fn test_office_hours(day: u32, hour: u32, expected: bool) {
+        let month = if day == 1 { 5 } else { 4 };
+        let at = Utc.ymd(2021, month, day).and_hms(hour, 0, 0);
+
+        assert_eq!(is_allowed(&[office_hours()], at, &policy()), expected);
+    }
+
+    #[rstest(
+        hour,
+        expected,
+        case(20, true),
+        case(2, true),
+        case(4, false),
+        case(12, false),
+        ::trace
+    )]
+    fn test_window_around_midnight(hour: u32, expected: bool) {
+        let at = Utc.ymd(2021, 4, 28).and_hms(hour, 0, 0);
+
+        assert_eq!(
+            is_allowed(&policy().users["night@email.test"], at, &policy()),
+            expected
+        );
+    }
+
+    #[test]
+    fn test_user_windows_replace_role_ones() {
+        let policy = policy();
+        let mut attributes = HashMap::new();
+        attributes.insert(ROLE_ATTRIBUTE.to_string(), "contractor".to_string());
+
+        assert_eq!(
+            windows_for("night@email.test", &attributes, &policy),
+            Some(policy.users["night@email.test"].as_slice())
+        );
+        assert_eq!(
+            windows_for("email@email.test", &attributes, &policy),
+            Some(policy.roles["contractor"].as_slice())
+        );
+        assert_eq!(
+            windows_for("email@email.test", &HashMap::new(), &policy),
+            None
+        );
+    }
+
+    #[test]
+    fn test_check_access() {
+        let u = User::new("email@email.test", "passwd_hash");
+        let saturday = Utc.ymd(2021, 5, 1).and_hms(10, 0, 0);
+
+        assert_eq!(
+            check_access(&u, &repository_with_role("contractor"), &policy(), saturday),
+            Err(AuthError::OutsideAllowedHours)
+        );
+        assert_eq!(
+            check_access(&u, &repository_with_role("admin"), &policy(), saturday),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn test_check_access_without_policy() {
+        let u = User::new("email@email.test", "passwd_hash");
+        let mut repository = MockSQliteUserRepository::new();
+        repository.expect_get_attributes().times(0);
+
+        assert_eq!(
+            check_access(&u, &repository, &AccessHoursConfig::default(), Utc::now()),
+            Ok(())
+        );
+    }
+}
@@ -0,0 +1,627 @@
+/*!
+ * Service accounts, used by other systems (e.g. a CI job or another backend)
+ *
+ * A service account has no usable password: it can't login, reset its password
+ * or be recovered through trusted contacts, & the inactivity & 2fa policies
+ * leave it out. It authenticates with either:
+ * - an API key, only shown once when it's issued (only its hash is kept)
+ * - a client certificate (mTLS), the TLS termination verifies the certificate
+ *   & passes on its SHA-256 fingerprint, which must be registered for the account
+ *
+ * Both are kept in the attributes of the account. An account on security hold
+ * (see `hold.rs`) is refused whatever its credentials.
+ *
+ * The scopes of the account (e.g. `profile:read`, `admin:users`) are chosen when
+ * it's created, the services it calls check them with `require_scope` once it's
+ * authenticated. An account without scopes is granted nothing.
+ *
+ * # Author
+ * Doran Kayoumi <doran.kayoumi@heig-vd.ch>
+ */
+
+use super::{hold, maintenance, timing};
+use crate::audit::{self, AuditEvent};
+use crate::db::models::{AccountKind, User, UserChangeset};
+use crate::db::repository::{
+    AuditRepository, SQliteAuditRepository, SQliteUserRepository, UserFilter, UserRepository,
+};
+use crate::errors::{AuthError, UserDBError};
+use crate::portable::token;
+use crate::utils;
+use crate::validation::is_email_valid;
+
+/// Attribute holding the hash of the API key of a service account
+pub const API_KEY_ATTRIBUTE: &str = "api_key_sha256";
+/// Attribute holding the fingerprint of the client certificate of a service account
+pub const CERTIFICATE_ATTRIBUTE: &str = "client_certificate_sha256";
+/// Attribute holding the scopes of a service account, separated by spaces (like OAuth scopes)
+pub const SCOPES_ATTRIBUTE: &str = "service_scopes";
+
+/// Public function for the creation of a service account
+/// See `_create_service_account` for more info
+///
+pub fn create_service_account(email: &str, scopes: &[String]) -> Result<String, AuthError> {
+    maintenance::check_writable()?;
+    let repository = SQliteUserRepository::new();
+    let audit_repository = SQliteAuditRepository::new();
+    _create_service_account(email, scopes, &repository, &audit_repository)
+}
+
+/// Public function for the rotation of an API key
+/// See `_rotate_api_key` for more info
+///
+pub fn rotate_api_key(email: &str) -> Result<String, AuthError> {
+    maintenance::check_writable()?;
+    let repository = SQliteUserRepository::new();
+    let audit_repository = SQliteAuditRepository::new();
+    _rotate_api_key(email, &repository, &audit_repository)
+}
+
+/// Public function for the registration of a client certificate
+/// See `_register_certificate` for more info
+///
+pub fn register_certificate(email: &str, fingerprint: &str) -> Result<(), AuthError> {
+    maintenance::check_writable()?;
+    let repository = SQliteUserRepository::new();
+    let audit_repository = SQliteAuditRepository::new();
+    _register_certificate(email, fingerprint, &repository, &audit_repository)
+}
+
+/// Authenticate a service account with its API key
+/// Note: whatever the outcome, it lasts at least the minimum response time (see `timing.rs`)
+///
+/// # Arguments
+///
+/// * `key` - the API key
+///
+pub fn authenticate_api_key(key: &str) -> Result<User, AuthError> {
+    let repository = SQliteUserRepository::new();
+    timing::padded(|| _authenticate_api_key(key, &repository))
+}
+
+/// Public function for the check of the scopes of a service account
+/// See `_require_scope` for more info
+///
+pub fn require_scope(service: &User, scope: &str) -> Result<(), AuthError> {
+    let repository = SQliteUserRepository::new();
+    _require_scope(service, scope, &repository)
+}
+
+/// Authenticate a service account with its client certificate
+/// Note: whatever the outcome, it lasts at least the minimum response time (see `timing.rs`)
+///
+/// # Arguments
+///
+/// * `fingerprint` - the SHA-256 fingerprint of the certificate, as verified by the TLS termination
+///
+pub fn authenticate_certificate(fingerprint: &str) -> Result<User, AuthError> {
+    let repository = SQliteUserRepository::new();
+    timing::padded(|| _authenticate_certificate(fingerprint, &repository))
+}
+
+/// Normalize the SHA-256 fingerprint of a certificate, e.g. `AB:CD:...` to `abcd...`
+/// Returns `None` if it isn't a SHA-256 fingerprint
+///
+/// # Arguments
+///
+/// * `fingerprint` - the fingerprint to normalize
+///
+pub fn normalize_fingerprint(fingerprint: &str) -> Option<String> {
+    let normalized: String = fingerprint
+        .trim()
+        .chars()
+        .filter(|c| *c != ':')
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+
+    if normalized.len() == 64 && normalized.chars().all(|c| c.is_ascii_hexdigit()) {
+        Some(normalized)
+    } else {
+        None
+    }
+}
+
+/// Check the format of a scope: lowercase letters, digits & `:`, `.`, `_`, `-`
+///
+/// # Arguments
+///
+/// * `scope` - the scope to check
+///
+pub fn is_scope_valid(scope: &str) -> bool {
+    !scope.is_empty()
+        && scope
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || ":._-".contains(c))
+}
+
+/// Get a service account
+///
+/// # Arguments
+///
+/// * `email` - the email of the account
+///
+/// * `repository` - the user repository to interact with
+///
+fn service_account(email: &str, repository: &dyn UserRepository) -> Result<User, AuthError> {
+    repository
+        .get_user(email)
+        .ok()
+        .filter(User::is_service)
+        .ok_or(AuthError::NotAServiceAccount)
+}
+
+/// Issue a new API key to a service account, it replaces the previous one
+/// Returns the key, it can't be read back later
+///
+/// # Arguments
+///
+/// * `u` - the service account
+///
+/// * `repository` - the user repository to interact with
+///
+fn issue_api_key(u: &User, repository: &dyn UserRepository) -> Result<String, AuthError> {
+    let key = utils::gen_token();
+    repository
+        .set_attribute(u.get_id(), API_KEY_ATTRIBUTE, &token::hash(&key))
+        .map_err(|_| AuthError::ServiceAccountError)?;
+
+    Ok(key)
+}
+
+/// Create a service account & issue its first API key
+/// Returns the API key
+///
+/// # Note
+/// A password is still stored, since every user has one, but it's a random one no one knows
+///
+/// # Arguments
+///
+/// * `email` - the email identifying the account
+///
+/// * `scopes` - the scopes granted to the account
+///
+/// * `repository` - the user repository to interact with
+///
+/// * `audit_repository` - the audit repository to write in
+///
+fn _create_service_account(
+    email: &str,
+    scopes: &[String],
+    repository: &dyn UserRepository,
+    audit_repository: &dyn AuditRepository,
+) -> Result<String, AuthError> {
+    if !is_email_valid(email) {
+        return Err(AuthError::InvalidEmail);
+    }
+    if !scopes.iter().all(|s| is_scope_valid(s)) {
+        return Err(AuthError::InvalidScope);
+    }
+
+    let pwh = utils::hash(&utils::gen_token()).ok_or(AuthError::ServiceAccountError)?;
+    repository.create_user(email, &pwh).map_err(|e| match e {
+        UserDBError::EmailUsedError => AuthError::EmailUsed,
+        _ => AuthError::ServiceAccountError,
+    })?;
+
+    let u = repository
+        .get_user(email)
+        .map_err(|_| AuthError::ServiceAccountError)?;
+    repository
+        .patch_user(u.get_id(), &UserChangeset::new().kind(AccountKind::Service))
+        .map_err(|_| AuthError::ServiceAccountError)?;
+    let scopes = if scopes.is_empty() {
+        None
+    } else {
+        Some(scopes.join(" "))
+    };
+    if let Some(scopes) = &scopes {
+        repository
+            .set_attribute(u.get_id(), SCOPES_ATTRIBUTE, scopes)
+            .map_err(|_| AuthError::ServiceAccountError)?;
+    }
+    let _ = audit::record(
+        audit_repository,
+        Some(u.get_id()),
+        AuditEvent::ServiceAccountCreated,
+        scopes,
+    );
+
+    issue_api_key(&u, repository)
+}
+
+/// Replace the API key of a service account, e.g. once it leaked
+/// Returns the new API key
+///
+/// # Arguments
+///
+/// * `email` - the email of the account
+///
+/// * `repository` - the user repository to interact with
+///
+/// * `audit_repository` - the audit repository to write in
+///
+fn _rotate_api_key(
+    email: &str,
+    repository: &dyn UserRepository,
+    audit_repository: &dyn AuditRepository,
+) -> Result<String, AuthError> {
+    let u = service_account(email, repository)?;
+    let key = issue_api_key(&u, repository)?;
+    let _ = audit::record(
+        audit_repository,
+        Some(u.get_id()),
+        AuditEvent::ApiKeyRotated,
+        None,
+    );
+
+    Ok(key)
+}
+
+/// Register the client certificate of a service account, it replaces the previous one
+///
+/// # Arguments
+///
+/// * `email` - the email of the account
+///
+/// * `fingerprint` - the SHA-256 fingerprint of the certificate
+///
+/// * `repository` - the user repository to interact with
+///
+/// * `audit_repository` - the audit repository to write in
+///
+fn _register_certificate(
+    email: &str,
+    fingerprint: &str,
+    repository: &dyn UserRepository,
+    audit_repository: &dyn AuditRepository,
+) -> Result<(), AuthError> {
+    let fingerprint = normalize_fingerprint(fingerprint).ok_or(AuthError::InvalidCertificate)?;
+    let u = service_account(email, repository)?;
+    repository
+        .set_attribute(u.get_id(), CERTIFICATE_ATTRIBUTE, &fingerprint)
+        .map_err(|_| AuthError::ServiceAccountError)?;
+    let _ = audit::record(
+        audit_repository,
+        Some(u.get_id()),
+        AuditEvent::ClientCertificateRegistered,
+        Some(fingerprint),
+    );
+
+    Ok(())
+}
+
+/// Find the service account whose credential (API key hash or certificate
+/// fingerprint) is set to the given value
+///
+/// # Arguments
+///
+/// * `attribute` - the attribute holding the credential
+///
+/// * `value` - the value of the credential
+///
+/// * `repository` - the user repository to interact with
+///
+fn authenticate(
+    attribute: &str,
+    value: &str,
+    repository: &dyn UserRepository,
+) -> Result<User, AuthError> {
+    let u = repository
+        .list_users(&UserFilter::new().with_attribute(attribute, value))
+        .map_err(|_| AuthError::ServiceAccountError)?
+        .into_iter()
+        .find(User::is_service)
+        .ok_or(AuthError::InvalidApiKey)?;
+
+    if hold::is_on_hold(u.get_id(), repository)? {
+        return Err(AuthError::AccountOnHold);
+    }
+
+    Ok(u)
+}
+
+/// Authenticate a service account with its API key
+///
+/// # Arguments
+///
+/// * `key` - the API key
+///
+/// * `repository` - the user repository to interact with
+///
+fn _authenticate_api_key(key: &str, repository: &dyn UserRepository) -> Result<User, AuthError> {
+    if !token::is_token_well_formed(key) {
+        return Err(AuthError::InvalidApiKey);
+    }
+
+    authenticate(API_KEY_ATTRIBUTE, &token::hash(key), repository)
+}
+
+/// Authenticate a service account with its client certificate
+///
+/// # Arguments
+///
+/// * `fingerprint` - the SHA-256 fingerprint of the certificate
+///
+/// * `repository` - the user repository to interact with
+///
+fn _authenticate_certificate(
+    fingerprint: &str,
+    repository: &dyn UserRepository,
+) -> Result<User, AuthError> {
+    let fingerprint = normalize_fingerprint(fingerprint).ok_or(AuthError::InvalidApiKey)?;
+
+    authenticate(CERTIFICATE_ATTRIBUTE, &fingerprint, repository)
+}
+
+/// Check that a service account was granted a scope
+///
+/// # Arguments
+///
+/// * `service` - the authenticated service account
+///
+/// * `scope` - the scope required
+///
+/// * `repository` - the user repository to interact with
+///
+fn _require_scope(
+    service: &User,
+    scope: &str,
+    repository: &dyn UserRepository,
+) -> Result<(), AuthError> {
+    if !service.is_service() {
+        return Err(AuthError::NotAServiceAccount);
+    }
+
+    let attrs = repository
+        .get_attributes(service.get_id())
+        .map_err(|_| AuthError::ServiceAccountError)?;
+    let granted = attrs
+        .get(SCOPES_ATTRIBUTE)
+        .is_some_and(|scopes| scopes.split(' ').any(|s| s == scope));
+
+    if granted {
+        Ok(())
+    } else {
+        Err(AuthError::MissingScope)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::db::repository::{MockSQliteAuditRepository, MockSQliteUserRepository};
+    use std::collections::HashMap;
+
+    const FINGERPRINT: &str =
+        "6E:34:0B:9C:FF:B3:7A:98:9C:A5:44:E6:BB:78:0A:2C:78:90:1D:3F:B3:37:38:76:85:11:A3:06:17:AF:A0:1D";
+
+    fn service() -> User {
+        let mut u = User::new("ci@email.test", "passwd_hash");
+        u.set_kind(AccountKind::Service);
+        u
+    }
+
+    fn audit_mock() -> MockSQliteAuditRepository {
+        let mut mock = MockSQliteAuditRepository::new();
+        mock.expect_create_entry().returning(|_, _, _| Ok(()));
+        mock
+    }
+
+    /// Repository finding the given user by any credential, not on hold
+    fn repository_with(u: User) -> MockSQliteUserRepository {
+        let mut mock = MockSQliteUserRepository::new();
+        mock.expect_list_users()
+            .returning(move |_| Ok(vec![u.clone()]));
+        mock.expect_get_attributes()
+            .returning(|_| Ok(HashMap::new()));
+        mock
+    }
+
+    #[test]
+    fn test_create_service_account() {
+        let mut repository = MockSQliteUserRepository::new();
+        repository
+            .expect_create_user()
+            .withf(|e, _| e == "ci@email.test")
+            .times(1)
+            .returning(|_, _| Ok(()));
+        repository.expect_get_user().returning(|_| Ok(service()));
+        repository
+            .expect_patch_user()
+            .withf(|_, changes| *changes == UserChangeset::new().kind(AccountKind::Service))
+            .times(1)
+            .returning(|_, _| Ok(()));
+        repository
+            .expect_set_attribute()
+            .withf(|_, attr, hash| attr == API_KEY_ATTRIBUTE && hash.len() == 64)
+            .times(1)
+            .returning(|_, _, _| Ok(()));
+        repository
+            .expect_set_attribute()
+            .withf(|_, attr, scopes| attr == SCOPES_ATTRIBUTE && scopes == "deploy:write logs:read")
+            .times(1)
+            .returning(|_, _, _| Ok(()));
+
+        let scopes = vec!["deploy:write".to_string(), "logs:read".to_string()];
+        let key =
+            _create_service_account("ci@email.test", &scopes, &repository, &audit_mock()).unwrap();
+        assert!(token::is_token_well_formed(&key));
+    }
+
+    #[test]
+    fn test_create_service_account_with_invalid_scope() {
+        let mut repository = MockSQliteUserRepository::new();
+        repository.expect_create_user().times(0);
+
+        assert_eq!(
+            _create_service_account(
+                "ci@email.test",
+                &["admin users".to_string()],
+                &repository,
+                &audit_mock()
+            ),
+            Err(AuthError::InvalidScope)
+        );
+    }
+
+    #[test]
+    fn test_require_scope() {
+        let mut repository = MockSQliteUserRepository::new();
+        repository.expect_get_attributes().returning(|_| {
+            let mut attrs = HashMap::new();
+            attrs.insert(
+                SCOPES_ATTRIBUTE.to_string(),
+                "profile:read admin:users".to_string(),
+            );
+            Ok(attrs)
+        });
+
+        assert_eq!(
+            _require_scope(&service(), "admin:users", &repository),
+            Ok(())
+        );
+        assert_eq!(
+            _require_scope(&service(), "admin", &repository),
+            Err(AuthError::MissingScope)
+        );
+        assert_eq!(
+            _require_scope(
+                &User::new("alice@email.test", "passwd_hash"),
+                "admin:users",
+                &repository
+            ),
+            Err(AuthError::NotAServiceAccount)
+        );
+    }
+
+    #[test]
+    fn test_accounts_without_scopes_are_granted_nothing() {
+        let repository = repository_with(service());
+
+        assert_eq!(
+            _require_scope(&service(), "profile:read", &repository),
+            Err(AuthError::MissingScope)
+        );
+    }
+
+    #[test]
+    fn test_is_scope_valid() {
+        assert!(is_scope_valid("profile:read"));
+        assert!(is_scope_valid("admin.users_v2-beta"));
+        assert!(!is_scope_valid(""));
+        assert!(!is_scope_valid("Admin"));
+        assert!(!is_scope_valid("admin users"));
+    }
+
+    #[test]
+    fn test_create_service_account_with_used_email() {
+        let mut repository = MockSQliteUserRepository::new();
+        repository
+            .expect_create_user()
+            .returning(|_, _| Err(UserDBError::EmailUsedError));
+
+        assert_eq!(
+            _create_service_account("ci@email.test", &[], &repository, &audit_mock()),
+            Err(AuthError::EmailUsed)
+        );
+        assert_eq!(
+            _create_service_account("ci", &[], &repository, &audit_mock()),
+            Err(AuthError::InvalidEmail)
+        );
+    }
+
+    #[test]
+    fn test_rotate_api_key_of_a_human() {
+        let mut repository = MockSQliteUserRepository::new();
+        repository
+            .expect_get_user()
+            .returning(|_| Ok(User::new("alice@email.test", "passwd_hash")));
+        repository.expect_set_attribute().times(0);
+
+        assert_eq!(
+            _rotate_api_key("alice@email.test", &repository, &audit_mock()),
+            Err(AuthError::NotAServiceAccount)
+        );
+    }
+
+    #[test]
+    fn test_authenticate_api_key() {
+        let key = "a".repeat(token::TOKEN_LEN);
+        let filter = UserFilter::new().with_attribute(API_KEY_ATTRIBUTE, &token::hash(&key));
+        let mut repository = MockSQliteUserRepository::new();
+        repository
+            .expect_list_users()
+            .withf(move |f| *f == filter)
+            .returning(|_| Ok(vec![service()]));
+        repository
+            .expect_get_attributes()
+            .returning(|_| Ok(HashMap::new()));
+
+        assert_eq!(_authenticate_api_key(&key, &repository), Ok(service()));
+        assert_eq!(
+            _authenticate_api_key("too short", &repository),
+            Err(AuthError::InvalidApiKey)
+        );
+    }
+
+    #[test]
+    fn test_humans_cant_use_api_keys() {
+        let key = "a".repeat(token::TOKEN_LEN);
+        let repository = repository_with(User::new("alice@email.test", "passwd_hash"));
+
+        assert_eq!(
+            _authenticate_api_key(&key, &repository),
+            Err(AuthError::InvalidApiKey)
+        );
+    }
+
+    #[test]
+    fn test_service_account_on_hold_is_refused() {
+        let mut repository = MockSQliteUserRepository::new();
+        repository
+            .expect_list_users()
+            .returning(|_| Ok(vec![service()]));
+        repository.expect_get_attributes().returning(|_| {
+            let mut attributes = HashMap::new();
+            attributes.insert(hold::HOLD_ATTRIBUTE.to_string(), "active".to_string());
+            Ok(attributes)
+        });
+
+        assert_eq!(
+            _authenticate_certificate(FINGERPRINT, &repository),
+            Err(AuthError::AccountOnHold)
+        );
+    }
+
+    #[test]
+    fn test_register_certificate() {
+        let mut repository = MockSQliteUserRepository::new();
+        repository.expect_get_user().returning(|_| Ok(service()));
+        repository
+            .expect_set_attribute()
+            .withf(|_, attr, val| {
+                attr == CERTIFICATE_ATTRIBUTE && *val == FINGERPRINT.replace(':', "").to_lowercase()
+            })
+            .times(1)
+            .returning(|_, _, _| Ok(()));
+
+        assert_eq!(
+            _register_certificate("ci@email.test", FINGERPRINT, &repository, &audit_mock()),
+            Ok(())
+        );
+        assert_eq!(
+            _register_certificate("ci@email.test", "AB:CD", &repository, &audit_mock()),
+            Err(AuthError::InvalidCertificate)
+        );
+    }
+
+    #[test]
+    fn test_normalize_fingerprint() {
+        let normalized = normalize_fingerprint(FINGERPRINT).unwrap();
+
+        assert_eq!(normalized.len(), 64);
+        assert_eq!(normalize_fingerprint(&normalized), Some(normalized.clone()));
+        assert_eq!(normalize_fingerprint(&normalized[..62]), None);
+        assert_eq!(normalize_fingerprint(&"g".repeat(64)), None);
+    }
+}
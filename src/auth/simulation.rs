@@ -0,0 +1,385 @@
+/*!
+ * Simulation of the login policies, to validate a configuration before rolling it out
+ *
+ * A scenario describes a sequence of login attempts (who, from where, when,
+ * with the right password or not & what is known about them). Each one goes
+ * through the same engines as a real login: the CAPTCHA escalation of
+ * `throttle.rs`, the risk-based policy of `risk.rs`, the allowed hours of
+ * `schedule.rs` & the security holds of `hold.rs`. Nothing is read from or
+ * written to the database, the accounts of the scenario don't need to exist.
+ *
+ * ```toml
+ * [[attempts]]
+ * at = "2021-04-28T09:00:00Z"
+ * email = "alice@example.com"
+ * source = "203.0.113.7"
+ * password = "wrong"        # or "right" (the default)
+ * new_device = true
+ * geo_anomaly = false
+ * twofa = false             # the account has a second factor
+ * role = "contractor"       # see the `[access_hours]` section of the configuration
+ *
+ * [[attempts]]
+ * captcha_solved = true     # the user solved a CAPTCHA right before this attempt
+ * ...
+ * ```
+ *
+ * # Author
+ * Doran Kayoumi <doran.kayoumi@heig-vd.ch>
+ */
+
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use super::hold::HoldReason;
+use super::login::hold_reason;
+use super::risk::{self, Decision, Signals};
+use super::schedule::{self, ROLE_ATTRIBUTE};
+use super::throttle::LoginThrottle;
+use crate::config::Config;
+use crate::errors::{AuthError, ConfigError};
+
+/// Whether the password of an attempt is the right one
+#[derive(Deserialize, PartialEq, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Password {
+    #[default]
+    Right,
+    Wrong,
+}
+
+/// A login attempt of a scenario
+#[derive(Deserialize, PartialEq, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Attempt {
+    pub at: DateTime<Utc>,
+    pub email: String,
+    /// Where the attempt comes from (e.g. an IP address)
+    #[serde(default = "default_source")]
+    pub source: String,
+    #[serde(default)]
+    pub password: Password,
+    #[serde(default)]
+    pub new_device: bool,
+    #[serde(default)]
+    pub geo_anomaly: bool,
+    /// The account has a second factor (e.g. the 2fa enabled)
+    #[serde(default)]
+    pub twofa: bool,
+    /// Role of the account, for the allowed hours
+    #[serde(default)]
+    pub role: Option<String>,
+    /// A CAPTCHA was solved right before the attempt
+    #[serde(default)]
+    pub captcha_solved: bool,
+}
+
+fn default_source() -> String {
+    super::throttle::LOCAL_SOURCE.to_string()
+}
+
+/// Sequence of login attempts, in the order they happen
+#[derive(Deserialize, PartialEq, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Scenario {
+    #[serde(default)]
+    pub attempts: Vec<Attempt>,
+}
+
+impl Scenario {
+    pub fn from_toml(s: &str) -> Result<Self, ConfigError> {
+        toml::from_str(s).map_err(|_| ConfigError::InvalidScenario)
+    }
+}
+
+/// What the policies decided for an attempt
+#[derive(PartialEq, Debug, Clone)]
+pub struct Outcome {
+    pub attempt: Attempt,
+    /// Risk score of the attempt, `None` if it was stopped before being assessed
+    pub score: Option<u32>,
+    /// The attempt succeeded (`true`: the second factor is still required) or why it didn't
+    pub result: Result<bool, AuthError>,
+    /// This attempt made a CAPTCHA required for the next ones
+    pub captcha_escalation: bool,
+    /// This attempt placed the account on security hold
+    pub hold: Option<HoldReason>,
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {} from {}",
+            self.attempt.at.format("%Y-%m-%d %H:%M"),
+            self.attempt.email,
+            self.attempt.source
+        )?;
+        if let Some(score) = self.score {
+            write!(f, " (risk {})", score)?;
+        }
+        match &self.result {
+            Ok(false) => write!(f, ": authenticated")?,
+            Ok(true) => write!(f, ": second factor required")?,
+            Err(e) => write!(f, ": refused, {}", e)?,
+        }
+        if self.captcha_escalation {
+            write!(f, " [CAPTCHA required from now on]")?;
+        }
+        if let Some(reason) = self.hold {
+            write!(f, " [placed on hold: {}]", reason)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Run the attempts of a scenario through the login policies of a configuration
+/// Note: mirrors `login::begin_login_with`, without touching the database
+///
+/// # Arguments
+///
+/// * `scenario` - the attempts to simulate
+///
+/// * `config` - the configuration holding the policies
+///
+pub fn simulate(scenario: &Scenario, config: &Config) -> Vec<Outcome> {
+    let throttle = LoginThrottle::default();
+    let mut holds: HashSet<String> = HashSet::new();
+
+    scenario
+        .attempts
+        .iter()
+        .map(|attempt| {
+            let email = attempt.email.to_lowercase();
+            let mut outcome = Outcome {
+                attempt: attempt.clone(),
+                score: None,
+                result: Ok(false),
+                captcha_escalation: false,
+                hold: None,
+            };
+            if attempt.captcha_solved {
+                throttle.grant_attempt(&email);
+            }
+            if let Err(e) = throttle.check(&email, &attempt.source, &config.captcha) {
+                outcome.result = Err(e);
+                return outcome;
+            }
+
+            let mut signals = Signals::at(attempt.at, config.locale.timezone);
+            signals.new_device = attempt.new_device;
+            signals.geo_anomaly = attempt.geo_anomaly;
+            let failures = throttle.failures(&email, &attempt.source).account_failures;
+            signals.failed_attempts = failures;
+            let assessment = risk::assess(&signals, &config.risk);
+            outcome.score = Some(assessment.score);
+
+            outcome.result = decide(attempt, &email, assessment.decision, &holds, config);
+            match outcome.result {
+                Ok(_) => throttle.record_success(&email),
+                Err(AuthError::LoginError) => {
+                    outcome.captcha_escalation = throttle
+                        .record_failure(&email, &attempt.source, &config.captcha)
+                        .is_some();
+                }
+                Err(_) => {}
+            }
+
+            // Note: the result of a real login is only needed to tell why it failed
+            let result = outcome.result.map(|_| ());
+            let verified = attempt.password == Password::Right;
+            outcome.hold = hold_reason(&result, verified, assessment.score, failures, &config.hold)
+                .filter(|_| holds.insert(email.clone()));
+            if outcome.hold.is_some() && outcome.result.is_ok() {
+                outcome.result = Err(AuthError::AccountOnHold);
+            }
+
+            outcome
+        })
+        .collect()
+}
+
+/// Decide the result of an attempt once its risk is assessed, like `login::_begin_login`
+fn decide(
+    attempt: &Attempt,
+    email: &str,
+    decision: Decision,
+    holds: &HashSet<String>,
+    config: &Config,
+) -> Result<bool, AuthError> {
+    if decision == Decision::Block {
+        return Err(AuthError::LoginBlocked);
+    }
+    if attempt.password == Password::Wrong {
+        return Err(AuthError::LoginError);
+    }
+    if holds.contains(email) {
+        return Err(AuthError::AccountOnHold);
+    }
+
+    let attributes: HashMap<String, String> = attempt
+        .role
+        .iter()
+        .map(|r| (ROLE_ATTRIBUTE.to_string(), r.clone()))
+        .collect();
+    if let Some(windows) = schedule::windows_for(email, &attributes, &config.access_hours) {
+        if !schedule::is_allowed(windows, attempt.at, &config.access_hours) {
+            return Err(AuthError::OutsideAllowedHours);
+        }
+    }
+
+    if decision == Decision::RequireEmailConfirmation
+        || (decision == Decision::Require2fa && !attempt.twofa)
+    {
+        Err(AuthError::EmailConfirmationRequired)
+    } else {
+        Ok(attempt.twofa)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn attempt(at: &str, password: Password) -> String {
+        format!(
+            "[[attempts]]\nat = \"{}\"\nemail = \"alice@example.com\"\npassword = \"{}\"\n",
+            at,
+            match password {
+                Password::Right => "right",
+                Password::Wrong => "wrong",
+            }
+        )
+    }
+
+    #[test]
+    fn test_parse_scenario() {
+        let scenario =
+            Scenario::from_toml(&attempt("2021-04-28T09:00:00Z", Password::Wrong)).unwrap();
+
+        assert_eq!(scenario.attempts.len(), 1);
+        assert_eq!(scenario.attempts[0].password, Password::Wrong);
+        assert_eq!(scenario.attempts[0].source, "local");
+        assert!(!scenario.attempts[0].twofa);
+
+        assert_eq!(
+            Scenario::from_toml("[[attempts]]\nemail = \"alice@example.com\""),
+            Err(ConfigError::InvalidScenario)
+        );
+        assert_eq!(
+            Scenario::from_toml(
+                "[[attempts]]\nat = \"2021-04-28T09:00:00Z\"\nemail = \"a\"\nfoo = 1"
+            ),
+            Err(ConfigError::InvalidScenario)
+        );
+    }
+
+    #[test]
+    fn test_failed_logins_escalate() {
+        let mut toml = String::new();
+        for _ in 0..3 {
+            toml.push_str(&attempt("2021-04-28T09:00:00Z", Password::Wrong));
+        }
+        toml.push_str(&attempt("2021-04-28T09:01:00Z", Password::Right));
+
+        let outcomes = simulate(&Scenario::from_toml(&toml).unwrap(), &Config::default());
+
+        assert_eq!(outcomes[0].result, Err(AuthError::LoginError));
+        assert!(!outcomes[1].captcha_escalation);
+        // 3 failures by default before a CAPTCHA is required
+        assert!(outcomes[2].captcha_escalation);
+        assert_eq!(outcomes[3].result, Err(AuthError::CaptchaRequired));
+        assert_eq!(outcomes[3].score, None);
+    }
+
+    #[test]
+    fn test_risky_login() {
+        let toml = format!(
+            "{}new_device = true\n{}new_device = true\ntwofa = true\n{}new_device = true\ngeo_anomaly = true\ntwofa = true\n{}",
+            attempt("2021-04-28T09:00:00Z", Password::Right),
+            attempt("2021-04-28T10:00:00Z", Password::Right),
+            attempt("2021-04-28T11:00:00Z", Password::Right),
+            attempt("2021-04-28T12:00:00Z", Password::Right),
+        );
+
+        let outcomes = simulate(&Scenario::from_toml(&toml).unwrap(), &Config::default());
+
+        // a new device, without & with a second factor
+        assert_eq!(outcomes[0].score, Some(20));
+        assert_eq!(
+            outcomes[0].result,
+            Err(AuthError::EmailConfirmationRequired)
+        );
+        assert_eq!(outcomes[1].result, Ok(true));
+        assert_eq!(outcomes[2].score, Some(60));
+        assert_eq!(
+            outcomes[2].result,
+            Err(AuthError::EmailConfirmationRequired)
+        );
+        assert_eq!(outcomes[3].score, Some(0));
+        assert_eq!(outcomes[3].result, Ok(false));
+    }
+
+    #[test]
+    fn test_blocked_login_places_a_hold() {
+        let toml = format!(
+            "{}new_device = true\ngeo_anomaly = true\nsource = \"1\"\n{}new_device = true\ngeo_anomaly = true\nsource = \"1\"\n{}",
+            attempt("2021-04-28T03:00:00Z", Password::Wrong),
+            attempt("2021-04-28T03:05:00Z", Password::Right),
+            attempt("2021-04-28T12:00:00Z", Password::Right),
+        );
+        let mut config = Config::default();
+        config.risk.block_at = 70;
+        config.hold.on_risk_block = true;
+
+        let outcomes = simulate(&Scenario::from_toml(&toml).unwrap(), &config);
+
+        // without the password, a blocked login doesn't place a hold
+        assert_eq!(outcomes[0].result, Err(AuthError::LoginBlocked));
+        assert_eq!(outcomes[0].hold, None);
+        assert_eq!(outcomes[1].result, Err(AuthError::LoginBlocked));
+        assert_eq!(
+            outcomes[1].hold,
+            Some(HoldReason::RiskBlocked { score: 70 })
+        );
+        assert_eq!(outcomes[2].result, Err(AuthError::AccountOnHold));
+        assert_eq!(outcomes[2].hold, None);
+        assert!(outcomes[1].to_string().contains("placed on hold"));
+    }
+
+    #[test]
+    fn test_failed_logins_dont_place_a_hold() {
+        // an attacker without the password, solving every CAPTCHA
+        let mut toml = String::new();
+        for minute in 0..30 {
+            toml.push_str(&attempt(
+                &format!("2021-04-28T10:{:02}:00Z", minute),
+                Password::Wrong,
+            ));
+            toml.push_str("captcha_solved = true\n");
+        }
+        let mut config = Config::default();
+        config.hold.on_risk_block = true;
+        config.hold.after_account_failures = 5;
+
+        let outcomes = simulate(&Scenario::from_toml(&toml).unwrap(), &config);
+
+        assert!(outcomes
+            .iter()
+            .any(|o| o.result == Err(AuthError::LoginBlocked)));
+        assert!(outcomes.iter().all(|o| o.hold.is_none()));
+
+        // the right password after them does
+        toml.push_str(&attempt("2021-04-28T11:00:00Z", Password::Right));
+        toml.push_str("captcha_solved = true\n");
+        let outcomes = simulate(&Scenario::from_toml(&toml).unwrap(), &config);
+        assert!(outcomes[30].hold.is_some());
+        assert_eq!(
+            outcomes[30].result.map(|_| ()),
+            Err(AuthError::LoginBlocked)
+        );
+    }
+}
@@ -0,0 +1,290 @@
+/*!
+ * Overview of the security settings of a user & of what the system knows about her/him
+ *
+ * # Author
+ * Doran Kayoumi <doran.kayoumi@heig-vd.ch>
+ */
+
+use chrono::{DateTime, Utc};
+
+use super::hold::{self, HOLD_REASON_ATTRIBUTE};
+use super::inactivity::LAST_LOGIN_ATTRIBUTE;
+use super::register::EMAIL_VERIFIED_ATTRIBUTE;
+use super::schedule::{self, ROLE_ATTRIBUTE};
+use crate::audit::AuditEvent;
+use crate::config::{self, AccessHoursConfig};
+use crate::db::models::{AccountKind, User};
+use crate::db::repository::{
+    AuditRepository, RecoveryCodeRepository, SQliteAuditRepository, SQliteRecoveryCodeRepository,
+    SQliteUserRepository, UserRepository,
+};
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct SecurityStatus {
+    pub twofa_enabled: bool,
+    /// When the 2fa was enabled, `None` if it's disabled or if the date is unknown
+    pub twofa_enabled_at: Option<DateTime<Utc>>,
+    /// A new 2fa secret waits to replace the current one (see `twofa::start_rotation`)
+    pub twofa_rotation_pending: bool,
+    /// Number of unused recovery codes, `None` if the 2fa is disabled or if it's unknown
+    pub recovery_codes_left: Option<i64>,
+}
+
+/// What the system knows about a user, e.g. to check how the policies apply to her/him
+///
+/// # Note
+/// There are no sessions nor permissions: a login lasts until the user logs out,
+/// the role only restricts the hours in which she/he can login
+#[derive(PartialEq, Debug, Clone)]
+pub struct Identity {
+    pub id: i32,
+    pub email: String,
+    pub kind: AccountKind,
+    /// Role given to the user (see `schedule.rs`), `None` if she/he has none
+    pub role: Option<String>,
+    /// The user followed the link verifying her/his email (see `register.rs`)
+    pub email_verified: bool,
+    pub twofa_enabled: bool,
+    /// Why the account is on security hold, `None` if it isn't (see `hold.rs`)
+    pub hold: Option<String>,
+    /// The user can only login in some windows (see the `[access_hours]` section of the configuration)
+    pub restricted_hours: bool,
+    pub last_login_at: Option<DateTime<Utc>>,
+}
+
+/// Public function for the identity of a user
+/// See `_identity` for more info
+///
+pub fn identity(u: &User) -> Identity {
+    let repository = SQliteUserRepository::new();
+    _identity(u, &repository, &config::get().access_hours)
+}
+
+/// Get what the system knows about a user
+/// Note: the attributes that can't be read are left out
+///
+/// # Arguments
+///
+/// * `u` - the user to describe
+///
+/// * `repository` - the user repository to read the attributes from
+///
+/// * `access` - the hours in which the users are allowed to login
+///
+fn _identity(u: &User, repository: &dyn UserRepository, access: &AccessHoursConfig) -> Identity {
+    let attributes = repository.get_attributes(u.get_id()).unwrap_or_default();
+    let hold = match hold::is_on_hold(u.get_id(), repository) {
+        Ok(true) => Some(
+            attributes
+                .get(HOLD_REASON_ATTRIBUTE)
+                .cloned()
+                .unwrap_or_else(|| "unknown reason".to_string()),
+        ),
+        _ => None,
+    };
+
+    Identity {
+        id: u.get_id(),
+        email: u.get_email(),
+        kind: u.get_kind(),
+        role: attributes.get(ROLE_ATTRIBUTE).cloned(),
+        email_verified: attributes
+            .get(EMAIL_VERIFIED_ATTRIBUTE)
+            .is_some_and(|v| v == "true"),
+        twofa_enabled: u.is_2fa_enabled(),
+        hold,
+        restricted_hours: schedule::windows_for(&u.get_email(), &attributes, access).is_some(),
+        last_login_at: attributes
+            .get(LAST_LOGIN_ATTRIBUTE)
+            .and_then(|d| DateTime::parse_from_rfc3339(d).ok())
+            .map(|d| d.with_timezone(&Utc)),
+    }
+}
+
+/// Public function for the security status
+/// See `_security_status` for more info
+///
+pub fn security_status(u: &User) -> SecurityStatus {
+    let audit_repository = SQliteAuditRepository::new();
+    let codes_repository = SQliteRecoveryCodeRepository::new();
+    _security_status(u, &audit_repository, &codes_repository)
+}
+
+/// Get the overview of the security settings of a user
+/// Note: the information that can't be read is left out
+///
+/// # Arguments
+///
+/// * `u` - the user to get the overview of
+///
+/// * `audit_repository` - the audit repository where the events are logged
+///
+/// * `codes_repository` - the recovery code repository to interact with
+///
+fn _security_status(
+    u: &User,
+    audit_repository: &dyn AuditRepository,
+    codes_repository: &dyn RecoveryCodeRepository,
+) -> SecurityStatus {
+    if !u.is_2fa_enabled() {
+        return SecurityStatus {
+            twofa_enabled: false,
+            twofa_enabled_at: None,
+            twofa_rotation_pending: false,
+            recovery_codes_left: None,
+        };
+    }
+
+    let twofa_enabled_at = audit_repository
+        .last_occurrence(u.get_id(), &AuditEvent::TwoFaEnabled.to_string())
+        .ok()
+        .flatten()
+        .and_then(|d| DateTime::parse_from_rfc3339(&d).ok())
+        .map(|d| d.with_timezone(&Utc));
+
+    SecurityStatus {
+        twofa_enabled: true,
+        twofa_enabled_at,
+        twofa_rotation_pending: u.get_pending_secret_2fa().is_some(),
+        recovery_codes_left: codes_repository.count_unused_codes(u.get_id()).ok(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::AccessWindow;
+    use crate::db::repository::{
+        MockSQliteAuditRepository, MockSQliteRecoveryCodeRepository, MockSQliteUserRepository,
+    };
+    use crate::errors::{AuditDBError, RecoveryCodeDBError, UserDBError};
+    use chrono::TimeZone;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_security_status_without_2fa() {
+        let mut audit_mock = MockSQliteAuditRepository::new();
+        let mut codes_mock = MockSQliteRecoveryCodeRepository::new();
+        audit_mock.expect_last_occurrence().times(0);
+        codes_mock.expect_count_unused_codes().times(0);
+
+        let u = User::new("email@email.test", "passwd_hash");
+
+        assert_eq!(
+            _security_status(&u, &audit_mock, &codes_mock),
+            SecurityStatus {
+                twofa_enabled: false,
+                twofa_enabled_at: None,
+                twofa_rotation_pending: false,
+                recovery_codes_left: None,
+            }
+        );
+    }
+
+    #[test]
+    fn test_security_status_with_2fa() {
+        let mut audit_mock = MockSQliteAuditRepository::new();
+        audit_mock
+            .expect_last_occurrence()
+            .withf(|_, e| e == "TwoFaEnabled")
+            .returning(|_, _| Ok(Some("2021-04-28T14:05:00+02:00".to_string())));
+        let mut codes_mock = MockSQliteRecoveryCodeRepository::new();
+        codes_mock.expect_count_unused_codes().returning(|_| Ok(7));
+
+        let mut u = User::new("email@email.test", "passwd_hash");
+        u.set_secret_2fa(Some("secret".to_string()));
+        u.set_pending_secret_2fa(Some("new secret".to_string()));
+
+        assert_eq!(
+            _security_status(&u, &audit_mock, &codes_mock),
+            SecurityStatus {
+                twofa_enabled: true,
+                twofa_enabled_at: Some(Utc.ymd(2021, 4, 28).and_hms(12, 5, 0)),
+                twofa_rotation_pending: true,
+                recovery_codes_left: Some(7),
+            }
+        );
+    }
+
+    #[test]
+    fn test_security_status_with_unreadable_storage() {
+        let mut audit_mock = MockSQliteAuditRepository::new();
+        audit_mock
+            .expect_last_occurrence()
+            .returning(|_, _| Err(AuditDBError::ReadEntryError));
+        let mut codes_mock = MockSQliteRecoveryCodeRepository::new();
+        codes_mock
+            .expect_count_unused_codes()
+            .returning(|_| Err(RecoveryCodeDBError::CountCodesError));
+
+        let mut u = User::new("email@email.test", "passwd_hash");
+        u.set_secret_2fa(Some("secret".to_string()));
+
+        let status = _security_status(&u, &audit_mock, &codes_mock);
+
+        assert!(status.twofa_enabled);
+        assert_eq!(status.twofa_enabled_at, None);
+        assert_eq!(status.recovery_codes_left, None);
+    }
+
+    #[test]
+    fn test_identity() {
+        let mut repository = MockSQliteUserRepository::new();
+        repository.expect_get_attributes().returning(|_| {
+            let mut attributes = HashMap::new();
+            attributes.insert(ROLE_ATTRIBUTE.to_string(), "contractor".to_string());
+            attributes.insert(EMAIL_VERIFIED_ATTRIBUTE.to_string(), "true".to_string());
+            attributes.insert(
+                LAST_LOGIN_ATTRIBUTE.to_string(),
+                "2021-04-28T14:05:00+02:00".to_string(),
+            );
+            Ok(attributes)
+        });
+        let mut access = AccessHoursConfig::default();
+        access.roles.insert(
+            "contractor".to_string(),
+            vec![AccessWindow {
+                days: vec![],
+                start_hour: 8,
+                end_hour: 18,
+            }],
+        );
+
+        let mut u = User::new("email@email.test", "passwd_hash");
+        u.set_secret_2fa(Some("secret".to_string()));
+
+        assert_eq!(
+            _identity(&u, &repository, &access),
+            Identity {
+                id: 1,
+                email: "email@email.test".to_string(),
+                kind: AccountKind::Human,
+                role: Some("contractor".to_string()),
+                email_verified: true,
+                twofa_enabled: true,
+                hold: None,
+                restricted_hours: true,
+                last_login_at: Some(Utc.ymd(2021, 4, 28).and_hms(12, 5, 0)),
+            }
+        );
+    }
+
+    #[test]
+    fn test_identity_with_unreadable_attributes() {
+        let mut repository = MockSQliteUserRepository::new();
+        repository
+            .expect_get_attributes()
+            .returning(|_| Err(UserDBError::GetAttributesError));
+
+        let identity = _identity(
+            &User::new("email@email.test", "passwd_hash"),
+            &repository,
+            &AccessHoursConfig::default(),
+        );
+
+        assert_eq!(identity.role, None);
+        assert_eq!(identity.hold, None);
+        assert!(!identity.email_verified);
+        assert!(!identity.restricted_hours);
+    }
+}
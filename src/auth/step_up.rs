@@ -0,0 +1,418 @@
+/*!
+ * Confirmation of the sensitive actions (step-up)
+ *
+ * Before a sensitive action (e.g. disabling the 2FA or setting the anti-phishing
+ * phrase), the user confirms her/his identity again: with her/his password &,
+ * if she/he set one up, a code of the strongest of her/his second factors (see
+ * `FactorRegistry::strongest_for`). The CLI & the applications embedding the
+ * library (see `AuthService::require_step_up`) both go through
+ * `require_step_up`, so the same proof is asked for everywhere.
+ *
+ * The failed confirmations are counted with the failed logins of the account
+ * & of where they come from (see `throttle.rs`), so a CAPTCHA is required once
+ * there are too many, and each confirmation lasts at least the minimum response
+ * time (see `timing.rs`). Otherwise an open session would be enough to guess
+ * the password. An application passes the address of its client as the source,
+ * like for the logins (see `login::begin_login_with`), the CLI is one local source.
+ *
+ * # Note
+ * The recovery codes are for the users who lost their device, they never
+ * confirm an action.
+ *
+ * # Author
+ * Doran Kayoumi <doran.kayoumi@heig-vd.ch>
+ */
+
+use super::binding::ClientInfo;
+use super::factor::FactorRegistry;
+use super::throttle::{self, Escalation, LoginThrottle};
+use super::{login, timing};
+use crate::audit::{self, AuditEvent};
+use crate::config::{self, CaptchaConfig};
+use crate::db::models::User;
+use crate::db::repository::{AuditRepository, SQliteAuditRepository};
+use crate::errors::AuthError;
+use crate::utils;
+use crate::validation::{MAX_PASSWORD_BYTES, MAX_TOKEN_BYTES};
+
+/// What a user must give to confirm a sensitive action
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct StepUp {
+    /// The second factor whose code is asked along with the password, none if the
+    /// user didn't set one up
+    pub factor: Option<&'static str>,
+}
+
+/// Public function for what a user must give to confirm a sensitive action
+/// See `step_up_with` for more info
+///
+pub fn step_up(u: &User) -> StepUp {
+    step_up_with(&FactorRegistry::standard(), u)
+}
+
+/// What a user must give to confirm a sensitive action, with the second factors
+/// of a given registry
+///
+/// # Arguments
+///
+/// * `factors` - the second factors available
+///
+/// * `u` - the user confirming the action
+///
+pub fn step_up_with(factors: &FactorRegistry, u: &User) -> StepUp {
+    StepUp {
+        factor: factors.strongest_for(u).map(|f| f.name()),
+    }
+}
+
+/// Public function for the confirmation of a sensitive action
+/// See `require_step_up_with` for more info
+///
+pub fn require_step_up(u: &User, passwd: &str, code: Option<&str>) -> Result<(), AuthError> {
+    require_step_up_with(
+        &FactorRegistry::standard(),
+        throttle::LOCAL_SOURCE,
+        &ClientInfo::local(),
+        u,
+        passwd,
+        code,
+    )
+}
+
+/// Confirm the identity of a user before a sensitive action, with the second
+/// factors of a given registry & where the confirmation comes from
+/// Note: whatever the outcome, it lasts at least the minimum response time (see `timing.rs`)
+///
+/// # Arguments
+///
+/// * `factors` - the second factors available
+///
+/// * `source` - where the confirmation comes from (e.g. the IP address of the client)
+///
+/// * `client` - the client confirming the action
+///
+/// * `u` - the user confirming the action
+///
+/// * `passwd` - the password entered by the user
+///
+/// * `code` - the code of her/his second factor, ignored if she/he set none up
+///
+pub fn require_step_up_with(
+    factors: &FactorRegistry,
+    source: &str,
+    client: &ClientInfo,
+    u: &User,
+    passwd: &str,
+    code: Option<&str>,
+) -> Result<(), AuthError> {
+    timing::padded(|| {
+        _require_step_up(
+            factors,
+            login::throttle(),
+            &config::get().captcha,
+            &SQliteAuditRepository::new(),
+            source,
+            client,
+            u,
+            passwd,
+            code,
+        )
+    })
+}
+
+/// Confirm the identity of a user before a sensitive action
+/// The password is checked first, then the code of the factor given by `step_up_with`
+/// Once a CAPTCHA is required, the confirmation fails with `AuthError::CaptchaRequired`
+/// until one is solved (see `login::solve_captcha`)
+///
+/// # Arguments
+///
+/// * `factors` - the second factors available
+///
+/// * `throttle` - the failed logins & confirmations
+///
+/// * `policy` - the thresholds from which a CAPTCHA is required
+///
+/// * `audit_repository` - the audit repository the escalations are written in
+///
+/// * `source` - where the confirmation comes from
+///
+/// * `client` - the client confirming the action
+///
+/// * `u` - the user confirming the action
+///
+/// * `passwd` - the password entered by the user
+///
+/// * `code` - the code of her/his second factor, ignored if she/he set none up
+///
+#[allow(clippy::too_many_arguments)]
+fn _require_step_up(
+    factors: &FactorRegistry,
+    throttle: &LoginThrottle,
+    policy: &CaptchaConfig,
+    audit_repository: &dyn AuditRepository,
+    source: &str,
+    client: &ClientInfo,
+    u: &User,
+    passwd: &str,
+    code: Option<&str>,
+) -> Result<(), AuthError> {
+    utils::check_length(passwd, MAX_PASSWORD_BYTES)?;
+    if let Some(code) = code {
+        utils::check_length(code, MAX_TOKEN_BYTES)?;
+    }
+
+    let email = u.get_email();
+    throttle.check(&email, source, policy)?;
+
+    let res = check_proof(factors, u, passwd, code);
+    match res {
+        Ok(()) => throttle.record_success(&email),
+        // Note: nothing was guessed, the code just wasn't entered yet
+        Err(AuthError::StepUpRequired) => {}
+        Err(_) => {
+            if let Some(escalation) = throttle.record_failure(&email, source, policy) {
+                record_escalation(u, source, client, &escalation, audit_repository);
+            }
+        }
+    }
+
+    res
+}
+
+/// Adds the escalation of the failed confirmations to the audit log, with the client which made them
+///
+/// # Arguments
+///
+/// * `u` - the user confirming the action
+///
+/// * `source` - where the confirmations came from
+///
+/// * `client` - the client confirming the action
+///
+/// * `escalation` - the failed logins & confirmations counted
+///
+/// * `audit_repository` - the audit repository to write in
+///
+fn record_escalation(
+    u: &User,
+    source: &str,
+    client: &ClientInfo,
+    escalation: &Escalation,
+    audit_repository: &dyn AuditRepository,
+) {
+    let _ = audit::record(
+        audit_repository,
+        Some(u.get_id()),
+        AuditEvent::CaptchaRequired,
+        Some(format!(
+            "{} failed login(s) & confirmation(s) of {}, {} from {} (user agent: {})",
+            escalation.account_failures,
+            u.get_email(),
+            escalation.source_failures,
+            source,
+            client.user_agent.as_deref().unwrap_or("unknown")
+        )),
+    );
+}
+
+/// Check the password of a user, then the code of the factor given by `step_up_with`
+///
+/// # Arguments
+///
+/// * `factors` - the second factors available
+///
+/// * `u` - the user confirming the action
+///
+/// * `passwd` - the password entered by the user
+///
+/// * `code` - the code of her/his second factor, ignored if she/he set none up
+///
+fn check_proof(
+    factors: &FactorRegistry,
+    u: &User,
+    passwd: &str,
+    code: Option<&str>,
+) -> Result<(), AuthError> {
+    // Note: the service & directory accounts have no usable password, they can't confirm
+    if !u.has_local_password() || !utils::verify_hash(passwd, &u.get_password()) {
+        return Err(AuthError::IncorrectPassword);
+    }
+
+    match factors.strongest_for(u) {
+        Some(factor) => {
+            factor.verify(u, code.ok_or(AuthError::StepUpRequired)?)?;
+            Ok(())
+        }
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::auth::factor::{RecoveryCodeFactor, TotpFactor, TOTP};
+    use crate::db::models::AccountKind;
+    use crate::db::repository::{MockSQliteAuditRepository, MockSQliteRecoveryCodeRepository};
+    use google_authenticator::GoogleAuthenticator;
+
+    const SECRET: &str = "I3VFM3JKMNDJCDH5BMBEEQAW6KJ6NOE3";
+
+    fn user() -> User {
+        User::new("email@email.test", &utils::hash("password").unwrap())
+    }
+
+    /// Standard factors, the recovery codes can't be used
+    fn registry() -> FactorRegistry {
+        let mut codes_mock = MockSQliteRecoveryCodeRepository::new();
+        codes_mock.expect_count_unused_codes().returning(|_| Ok(10));
+        codes_mock.expect_use_code().times(0);
+
+        FactorRegistry::new()
+            .with(TotpFactor)
+            .with(RecoveryCodeFactor::with_repository(Box::new(codes_mock)))
+    }
+
+    /// Confirmation with a throttle of its own, so the tests don't share the failures
+    fn check(u: &User, passwd: &str, code: Option<&str>) -> Result<(), AuthError> {
+        let mut audit_repository = MockSQliteAuditRepository::new();
+        audit_repository
+            .expect_create_entry()
+            .returning(|_, _, _| Ok(()));
+
+        _require_step_up(
+            &registry(),
+            &LoginThrottle::default(),
+            &CaptchaConfig::default(),
+            &audit_repository,
+            throttle::LOCAL_SOURCE,
+            &ClientInfo::local(),
+            u,
+            passwd,
+            code,
+        )
+    }
+
+    fn valid_code() -> String {
+        GoogleAuthenticator::new().get_code(SECRET, 0).unwrap()
+    }
+
+    #[test]
+    fn test_step_up_without_2fa() {
+        let u = user();
+
+        assert_eq!(step_up_with(&registry(), &u), StepUp { factor: None });
+        assert_eq!(check(&u, "password", None), Ok(()));
+        assert_eq!(
+            check(&u, "wrong password", None),
+            Err(AuthError::IncorrectPassword)
+        );
+    }
+
+    #[test]
+    fn test_step_up_with_2fa() {
+        let mut u = user();
+        u.set_secret_2fa(Some(SECRET.to_string()));
+
+        assert_eq!(step_up_with(&registry(), &u), StepUp { factor: Some(TOTP) });
+        assert_eq!(check(&u, "password", Some(&valid_code())), Ok(()));
+        // the password alone isn't enough anymore
+        assert_eq!(check(&u, "password", None), Err(AuthError::StepUpRequired));
+        assert_eq!(
+            check(&u, "password", Some("000000")),
+            Err(AuthError::InvalidAuthenticationCode)
+        );
+        assert_eq!(
+            check(&u, "wrong password", Some(&valid_code())),
+            Err(AuthError::IncorrectPassword)
+        );
+        // a recovery code isn't a code of the strongest factor
+        assert_eq!(
+            check(&u, "password", Some("ABCD-EFGH")),
+            Err(AuthError::InvalidAuthenticationCode)
+        );
+    }
+
+    #[test]
+    fn test_step_up_of_accounts_without_local_password() {
+        let mut u = user();
+        u.set_kind(AccountKind::Directory);
+
+        assert_eq!(
+            check(&u, "password", None),
+            Err(AuthError::IncorrectPassword)
+        );
+    }
+
+    #[test]
+    fn test_step_up_with_oversized_inputs() {
+        let u = user();
+        let huge = "a".repeat(1 << 20);
+
+        assert_eq!(check(&u, &huge, None), Err(AuthError::InputTooLong));
+        assert_eq!(
+            check(&u, "password", Some(&huge)),
+            Err(AuthError::InputTooLong)
+        );
+    }
+
+    #[test]
+    fn test_step_up_is_throttled() {
+        let u = user();
+        let throttle = LoginThrottle::default();
+        let policy = CaptchaConfig {
+            after_account_failures: 2,
+            after_source_failures: 10,
+        };
+        let mut audit_repository = MockSQliteAuditRepository::new();
+        audit_repository
+            .expect_create_entry()
+            .withf(|_, event, details| {
+                event == "CaptchaRequired"
+                    && details
+                        .as_deref()
+                        .is_some_and(|d| d.contains("from 203.0.113.7"))
+            })
+            .times(1)
+            .returning(|_, _, _| Ok(()));
+        let client = ClientInfo {
+            ip: Some("203.0.113.7".parse().unwrap()),
+            ..ClientInfo::default()
+        };
+        let confirm = |passwd: &str| {
+            _require_step_up(
+                &registry(),
+                &throttle,
+                &policy,
+                &audit_repository,
+                "203.0.113.7",
+                &client,
+                &u,
+                passwd,
+                None,
+            )
+        };
+
+        for _ in 0..2 {
+            assert_eq!(confirm("wrong password"), Err(AuthError::IncorrectPassword));
+        }
+        assert_eq!(
+            throttle.failures(&u.get_email(), "203.0.113.7"),
+            throttle::Escalation {
+                account_failures: 2,
+                source_failures: 2,
+            }
+        );
+        // even the right password is refused until a CAPTCHA is solved
+        assert_eq!(confirm("password"), Err(AuthError::CaptchaRequired));
+
+        throttle.grant_attempt(&u.get_email());
+        assert_eq!(confirm("password"), Ok(()));
+        assert_eq!(
+            throttle
+                .failures(&u.get_email(), "203.0.113.7")
+                .account_failures,
+            0
+        );
+    }
+}
@@ -0,0 +1,516 @@
+/*!
+ * Throttling of the login attempts
+ *
+ * The failed logins are counted per account & per source (e.g. an IP address).
+ * Once one of them reaches its threshold (see the `[captcha]` section of the
+ * configuration), a CAPTCHA must be solved before each further attempt, the
+ * password isn't even checked otherwise. A successful login resets the counter
+ * of its account only: the failures of a source keep counting, so an attacker
+ * can't reset them by logging in to an account she/he controls. The counters
+ * are forgotten `FAILURE_WINDOW_SECS` after their first failure.
+ *
+ * The CAPTCHAs are issued by a `CaptchaProvider`, `ArithmeticCaptcha` is the
+ * one built in the system (it works in a terminal). Like the failures, its
+ * answers are kept in a `TokenStore`, so a CAPTCHA issued by an instance can be
+ * answered on another one & is forgotten after `CAPTCHA_VALIDITY_SECS`.
+ *
+ * # Author
+ * Doran Kayoumi <doran.kayoumi@heig-vd.ch>
+ */
+
+use rand::{thread_rng, Rng};
+use std::convert::TryFrom;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::config::CaptchaConfig;
+use crate::db::ephemeral::{self, InMemoryTokenStore, SharedTokenStore};
+use crate::errors::{AuthError, EphemeralStoreError};
+use crate::utils;
+use crate::validation::MAX_TOKEN_BYTES;
+
+/// Source of the logins made from the terminal
+pub const LOCAL_SOURCE: &str = "local";
+/// How long (in seconds) the failed logins are counted, from the first one
+pub const FAILURE_WINDOW_SECS: u64 = 24 * 60 * 60;
+/// How long (in seconds) the attempt granted by a solved CAPTCHA can be used
+pub const PASS_VALIDITY_SECS: u64 = 15 * 60;
+/// How long (in seconds) a CAPTCHA can be answered
+pub const CAPTCHA_VALIDITY_SECS: u64 = 10 * 60;
+/// What the CAPTCHAs of an `ArithmeticCaptcha` are for, unless another scope is set
+pub const DEFAULT_CAPTCHA_SCOPE: &str = "captcha";
+
+/// A challenge to solve to prove the user is a human
+/// Note: only a random id & the question are handed out, the answer stays server side
+#[derive(PartialEq, Debug, Clone)]
+pub struct Captcha {
+    id: String,
+    question: String,
+}
+
+impl Captcha {
+    /// The CAPTCHA a client answered, from the id it sent back (e.g. with a form)
+    /// Note: the answers are kept by the provider, the question isn't needed to check one
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - the id of the CAPTCHA
+    ///
+    pub fn from_id(id: &str) -> Self {
+        Self {
+            id: id.to_string(),
+            question: String::new(),
+        }
+    }
+
+    pub fn get_id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn get_question(&self) -> &str {
+        &self.question
+    }
+}
+
+pub trait CaptchaProvider {
+    /// Create a new challenge
+    fn issue(&self) -> Captcha;
+
+    /// Check the answer to a challenge, a challenge can only be answered once
+    ///
+    /// # Arguments
+    ///
+    /// * `captcha` - the challenge answered
+    ///
+    /// * `answer` - the answer of the user
+    ///
+    fn verify(&self, captcha: &Captcha, answer: &str) -> bool;
+}
+
+/// Simple additions to compute
+pub struct ArithmeticCaptcha {
+    store: SharedTokenStore,
+    /// Note: the CAPTCHAs of a scope can't be answered in another one
+    scope: &'static str,
+}
+
+impl Default for ArithmeticCaptcha {
+    /// Answers kept in memory, by this provider only
+    fn default() -> Self {
+        Self::with_store(Arc::new(InMemoryTokenStore::new()), DEFAULT_CAPTCHA_SCOPE)
+    }
+}
+
+impl ArithmeticCaptcha {
+    /// # Arguments
+    ///
+    /// * `store` - where the answers are kept
+    ///
+    /// * `scope` - what the CAPTCHAs are for (e.g. `login`)
+    ///
+    pub fn with_store(store: SharedTokenStore, scope: &'static str) -> Self {
+        Self { store, scope }
+    }
+}
+
+impl CaptchaProvider for ArithmeticCaptcha {
+    fn issue(&self) -> Captcha {
+        let mut rng = thread_rng();
+        let (a, b): (u32, u32) = (rng.gen_range(1..20), rng.gen_range(1..20));
+
+        let captcha = Captcha {
+            id: utils::gen_token(),
+            question: format!("How much is {} + {}?", a, b),
+        };
+        // Note: a CAPTCHA whose answer can't be kept is refused by `verify`, the user gets another one
+        let _ = self.store.put(
+            &ephemeral::captcha_key(self.scope, &captcha.id),
+            &(a + b).to_string(),
+            Duration::from_secs(CAPTCHA_VALIDITY_SECS),
+        );
+
+        captcha
+    }
+
+    fn verify(&self, captcha: &Captcha, answer: &str) -> bool {
+        // Note: the id may come from a client (see `Captcha::from_id`)
+        if utils::check_length(&captcha.id, MAX_TOKEN_BYTES).is_err() {
+            return false;
+        }
+
+        let expected: Option<u32> = self
+            .store
+            .take(&ephemeral::captcha_key(self.scope, &captcha.id))
+            .ok()
+            .flatten()
+            .and_then(|a| a.parse().ok());
+
+        expected.is_some() && answer.trim().parse().ok() == expected
+    }
+}
+
+/// Why a CAPTCHA became required
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct Escalation {
+    pub account_failures: u32,
+    pub source_failures: u32,
+}
+
+/// Failed logins of the accounts & the sources
+/// They're kept in a `TokenStore`, so the instances sharing it (e.g. in Redis, see
+/// `ephemeral::shared`) count the same failures
+pub struct LoginThrottle {
+    store: SharedTokenStore,
+}
+
+impl Default for LoginThrottle {
+    /// Failures kept in memory, by this throttle only
+    fn default() -> Self {
+        Self::with_store(Arc::new(InMemoryTokenStore::new()))
+    }
+}
+
+fn account_key(email: &str) -> String {
+    ephemeral::rate_limit_key("login", &format!("account:{}", email.to_lowercase()))
+}
+
+fn source_key(source: &str) -> String {
+    ephemeral::rate_limit_key("login", &format!("source:{}", source))
+}
+
+/// Key of the attempt granted to an account by a solved CAPTCHA
+fn pass_key(email: &str) -> String {
+    ephemeral::rate_limit_key("captcha_pass", &email.to_lowercase())
+}
+
+impl LoginThrottle {
+    /// # Arguments
+    ///
+    /// * `store` - where the failures & the passes are kept
+    ///
+    pub fn with_store(store: SharedTokenStore) -> Self {
+        Self { store }
+    }
+
+    /// Number of failed logins of an account & of a source
+    /// Note: none are counted if the store can't be read, `check` refuses the logins then
+    ///
+    /// # Arguments
+    ///
+    /// * `email` - the email of the account
+    ///
+    /// * `source` - where the logins come from
+    ///
+    pub fn failures(&self, email: &str, source: &str) -> Escalation {
+        self.try_failures(email, source).unwrap_or(Escalation {
+            account_failures: 0,
+            source_failures: 0,
+        })
+    }
+
+    fn try_failures(&self, email: &str, source: &str) -> Result<Escalation, EphemeralStoreError> {
+        let count = |key: String| -> Result<u32, EphemeralStoreError> {
+            Ok(self
+                .store
+                .get(&key)?
+                .and_then(|c| c.parse().ok())
+                .unwrap_or(0))
+        };
+
+        Ok(Escalation {
+            account_failures: count(account_key(email))?,
+            source_failures: count(source_key(source))?,
+        })
+    }
+
+    fn is_escalated(
+        &self,
+        email: &str,
+        source: &str,
+        policy: &CaptchaConfig,
+    ) -> Result<bool, EphemeralStoreError> {
+        let f = self.try_failures(email, source)?;
+
+        Ok(f.account_failures >= policy.after_account_failures
+            || f.source_failures >= policy.after_source_failures)
+    }
+
+    /// Check if a login attempt can be evaluated
+    /// Once the attempts are escalated, each one uses up the attempt granted by a solved CAPTCHA
+    /// Note: the logins are refused if the store can't be reached, they can't be throttled
+    ///
+    /// # Arguments
+    ///
+    /// * `email` - the email of the account
+    ///
+    /// * `source` - where the login comes from
+    ///
+    /// * `policy` - the thresholds to use
+    ///
+    pub fn check(
+        &self,
+        email: &str,
+        source: &str,
+        policy: &CaptchaConfig,
+    ) -> Result<(), AuthError> {
+        let unavailable = |_| AuthError::StateStoreError;
+        if !self
+            .is_escalated(email, source, policy)
+            .map_err(unavailable)?
+            || self
+                .store
+                .take(&pass_key(email))
+                .map_err(unavailable)?
+                .is_some()
+        {
+            Ok(())
+        } else {
+            Err(AuthError::CaptchaRequired)
+        }
+    }
+
+    /// Count a failed login
+    /// Returns why a CAPTCHA is now required if this failure escalated the attempts
+    /// Note: the failures are forgotten `FAILURE_WINDOW_SECS` after the first one
+    ///
+    /// # Arguments
+    ///
+    /// * `email` - the email of the account
+    ///
+    /// * `source` - where the login came from
+    ///
+    /// * `policy` - the thresholds to use
+    ///
+    pub fn record_failure(
+        &self,
+        email: &str,
+        source: &str,
+        policy: &CaptchaConfig,
+    ) -> Option<Escalation> {
+        let window = Duration::from_secs(FAILURE_WINDOW_SECS);
+        let account_failures = self.store.increment(&account_key(email), window).ok()?;
+        let source_failures = self.store.increment(&source_key(source), window).ok()?;
+        let f = Escalation {
+            account_failures: u32::try_from(account_failures).unwrap_or(u32::MAX),
+            source_failures: u32::try_from(source_failures).unwrap_or(u32::MAX),
+        };
+
+        // Note: from the counts returned for this failure, so a concurrent one (e.g. on
+        //       another instance) doesn't report the escalation a second time
+        let escalated = |account: u32, source: u32| {
+            account >= policy.after_account_failures || source >= policy.after_source_failures
+        };
+        let was_escalated = escalated(f.account_failures - 1, f.source_failures - 1);
+        if !was_escalated && escalated(f.account_failures, f.source_failures) {
+            Some(f)
+        } else {
+            None
+        }
+    }
+
+    /// Forget the failed logins of an account after a successful login
+    /// The failures of the source are kept, a login to another account doesn't excuse them
+    /// Note: the login succeeded anyway, failures that can't be forgotten expire on their own
+    ///
+    /// # Arguments
+    ///
+    /// * `email` - the email of the account
+    ///
+    pub fn record_success(&self, email: &str) {
+        let _ = self.store.remove(&account_key(email));
+        let _ = self.store.remove(&pass_key(email));
+    }
+
+    /// Grant one login attempt to an account after a CAPTCHA was solved
+    /// The attempt is lost if it isn't used within `PASS_VALIDITY_SECS`
+    ///
+    /// # Arguments
+    ///
+    /// * `email` - the email of the account
+    ///
+    pub fn grant_attempt(&self, email: &str) {
+        // Note: an attempt that can't be granted is refused by `check`, which asks for a new CAPTCHA
+        let _ = self.store.put(
+            &pass_key(email),
+            "1",
+            Duration::from_secs(PASS_VALIDITY_SECS),
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::db::ephemeral::TokenStore;
+
+    fn policy() -> CaptchaConfig {
+        CaptchaConfig {
+            after_account_failures: 2,
+            after_source_failures: 3,
+        }
+    }
+
+    #[test]
+    fn test_arithmetic_captcha() {
+        let store: SharedTokenStore = Arc::new(InMemoryTokenStore::new());
+        let provider = ArithmeticCaptcha::with_store(Arc::clone(&store), "login");
+        let captcha = provider.issue();
+        let answer = store
+            .get(&ephemeral::captcha_key("login", captcha.get_id()))
+            .unwrap()
+            .unwrap();
+
+        // the id sent back by the client is enough
+        assert_eq!(
+            Captcha::from_id(captcha.get_id()).get_id(),
+            captcha.get_id()
+        );
+
+        // the CAPTCHAs of another scope aren't answered here
+        let availability = ArithmeticCaptcha::with_store(Arc::clone(&store), "availability");
+        assert!(!availability.verify(&captcha, &answer));
+
+        let other = provider.issue();
+        assert!(!provider.verify(&other, "not a number"));
+        assert!(provider.verify(&captcha, &format!(" {} ", answer)));
+        // a challenge can only be answered once
+        assert!(!provider.verify(&captcha, &answer));
+    }
+
+    #[test]
+    fn test_escalation_per_account() {
+        let throttle = LoginThrottle::default();
+
+        assert_eq!(
+            throttle.record_failure("a@email.test", "1", &policy()),
+            None
+        );
+        assert_eq!(
+            throttle.record_failure("A@email.test", "2", &policy()),
+            Some(Escalation {
+                account_failures: 2,
+                source_failures: 1
+            })
+        );
+        // only reported once
+        assert_eq!(
+            throttle.record_failure("a@email.test", "3", &policy()),
+            None
+        );
+
+        assert_eq!(
+            throttle.check("a@email.test", "4", &policy()),
+            Err(AuthError::CaptchaRequired)
+        );
+        assert_eq!(throttle.check("b@email.test", "4", &policy()), Ok(()));
+    }
+
+    #[test]
+    fn test_escalation_per_source() {
+        let throttle = LoginThrottle::default();
+
+        for email in &["a@email.test", "b@email.test", "c@email.test"] {
+            throttle.record_failure(email, "1", &policy());
+        }
+
+        assert_eq!(
+            throttle.check("d@email.test", "1", &policy()),
+            Err(AuthError::CaptchaRequired)
+        );
+        assert_eq!(throttle.check("d@email.test", "2", &policy()), Ok(()));
+    }
+
+    #[test]
+    fn test_solved_captcha_grants_one_attempt() {
+        let throttle = LoginThrottle::default();
+        throttle.record_failure("a@email.test", "1", &policy());
+        throttle.record_failure("a@email.test", "1", &policy());
+
+        throttle.grant_attempt("a@email.test");
+
+        assert_eq!(throttle.check("a@email.test", "1", &policy()), Ok(()));
+        assert_eq!(
+            throttle.check("a@email.test", "1", &policy()),
+            Err(AuthError::CaptchaRequired)
+        );
+
+        throttle.record_success("a@email.test");
+        assert_eq!(throttle.check("a@email.test", "1", &policy()), Ok(()));
+    }
+
+    #[test]
+    fn test_success_keeps_source_failures() {
+        let throttle = LoginThrottle::default();
+        for email in &["a@email.test", "b@email.test", "c@email.test"] {
+            throttle.record_failure(email, "1", &policy());
+        }
+
+        // e.g. the attacker logs in to her/his own account from the same source
+        throttle.record_success("d@email.test");
+
+        assert_eq!(throttle.failures("d@email.test", "1").source_failures, 3);
+        assert_eq!(
+            throttle.check("e@email.test", "1", &policy()),
+            Err(AuthError::CaptchaRequired)
+        );
+    }
+
+    #[test]
+    fn test_throttles_sharing_a_store() {
+        // e.g. two instances behind a load balancer, with the same Redis server
+        let store: SharedTokenStore = Arc::new(InMemoryTokenStore::new());
+        let first = LoginThrottle::with_store(Arc::clone(&store));
+        let second = LoginThrottle::with_store(store);
+
+        first.record_failure("a@email.test", "1", &policy());
+        assert!(second
+            .record_failure("a@email.test", "2", &policy())
+            .is_some());
+        assert_eq!(
+            first.check("a@email.test", "3", &policy()),
+            Err(AuthError::CaptchaRequired)
+        );
+
+        // the attempt granted by one is used up by the other
+        first.grant_attempt("a@email.test");
+        assert_eq!(second.check("a@email.test", "3", &policy()), Ok(()));
+        assert_eq!(
+            first.check("a@email.test", "3", &policy()),
+            Err(AuthError::CaptchaRequired)
+        );
+    }
+
+    #[test]
+    fn test_unreachable_store() {
+        struct Unreachable;
+        impl TokenStore for Unreachable {
+            fn put(&self, _: &str, _: &str, _: Duration) -> Result<(), EphemeralStoreError> {
+                Err(EphemeralStoreError::ConnectionError)
+            }
+            fn get(&self, _: &str) -> Result<Option<String>, EphemeralStoreError> {
+                Err(EphemeralStoreError::ConnectionError)
+            }
+            fn take(&self, _: &str) -> Result<Option<String>, EphemeralStoreError> {
+                Err(EphemeralStoreError::ConnectionError)
+            }
+            fn remove(&self, _: &str) -> Result<(), EphemeralStoreError> {
+                Err(EphemeralStoreError::ConnectionError)
+            }
+            fn increment(&self, _: &str, _: Duration) -> Result<u64, EphemeralStoreError> {
+                Err(EphemeralStoreError::ConnectionError)
+            }
+        }
+        let throttle = LoginThrottle::with_store(Arc::new(Unreachable));
+
+        // the logins can't be throttled, so they're refused
+        assert_eq!(
+            throttle.check("a@email.test", "1", &policy()),
+            Err(AuthError::StateStoreError)
+        );
+        assert_eq!(
+            throttle.record_failure("a@email.test", "1", &policy()),
+            None
+        );
+        assert_eq!(throttle.failures("a@email.test", "1").account_failures, 0);
+    }
+}
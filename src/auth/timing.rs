@@ -0,0 +1,83 @@
+/*!
+ * Minimum response time of the sensitive operations (login, reset, 2FA verification)
+ *
+ * Whichever branch an operation takes (unknown email, wrong password, wrong
+ * code...), it lasts at least the floor set in the `[timing]` section of the
+ * configuration, so the outcome can't be told from the timing. It complements
+ * the dummy hash of `login::_login`, which only covers the unknown emails.
+ *
+ * # Author
+ * Doran Kayoumi <doran.kayoumi@heig-vd.ch>
+ */
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::config;
+
+/// Run an operation with the minimum response time of the configuration
+/// See `pad` for more info
+///
+pub fn padded<T>(op: impl FnOnce() -> T) -> T {
+    pad(config::get().timing.min_response(), op)
+}
+
+/// Run an operation, then wait until it lasted at least a given duration
+/// Note: an operation lasting longer than the floor isn't delayed any further
+///
+/// # Arguments
+///
+/// * `floor` - the minimum duration of the operation
+///
+/// * `op` - the operation to run
+///
+pub fn pad<T>(floor: Duration, op: impl FnOnce() -> T) -> T {
+    let start = Instant::now();
+    let res = op();
+
+    if let Some(left) = floor.checked_sub(start.elapsed()) {
+        thread::sleep(left);
+    }
+
+    res
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_pad_to_floor() {
+        let start = Instant::now();
+        let res = pad(Duration::from_millis(50), || 42);
+
+        assert_eq!(res, 42);
+        assert!(start.elapsed() >= Duration::from_millis(50));
+    }
+
+    #[test]
+    fn test_slow_operation_isnt_delayed() {
+        let start = Instant::now();
+        pad(Duration::from_millis(10), || {
+            thread::sleep(Duration::from_millis(50))
+        });
+
+        assert!(start.elapsed() < Duration::from_millis(100));
+    }
+
+    #[test]
+    fn test_every_branch_takes_the_floor() {
+        for fail in &[true, false] {
+            let start = Instant::now();
+            let _ = pad(Duration::from_millis(30), || {
+                if *fail {
+                    Err(())
+                } else {
+                    Ok(())
+                }
+            });
+
+            assert!(start.elapsed() >= Duration::from_millis(30));
+        }
+    }
+}
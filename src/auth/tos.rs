@@ -0,0 +1,151 @@
+/*!
+ * Functions related to the terms of service & privacy policy acceptance
+ *
+ * # Author
+ * Doran Kayoumi <doran.kayoumi@heig-vd.ch>
+ */
+
+use crate::audit::{self, AuditEvent};
+use crate::db::models::{User, UserChangeset};
+use crate::db::repository::{
+    AuditRepository, SQliteAuditRepository, SQliteUserRepository, UserRepository,
+};
+use crate::errors::AuthError;
+
+/// Current version of the terms of service & privacy policy
+/// Bumping it forces every user to accept the new version on their next login
+pub const TOS_VERSION: i32 = 1;
+
+/// Check if a user needs to accept the current terms of service
+///
+/// # Arguments
+///
+/// * `u` - the user to check
+///
+pub fn requires_acceptance(u: &User) -> bool {
+    match u.get_accepted_tos_version() {
+        Some(v) => v < TOS_VERSION,
+        None => true,
+    }
+}
+
+/// Public function for the acceptance of the terms of service
+/// See `_accept_terms` for more info
+///
+pub fn accept_terms(u: &mut User) -> Result<(), AuthError> {
+    let repository = SQliteUserRepository::new();
+    let audit_repository = SQliteAuditRepository::new();
+    _accept_terms(u, TOS_VERSION, &repository, &audit_repository)
+}
+
+/// Record that a user accepted a version of the terms of service
+///
+/// # Arguments
+///
+/// * `u` - the user accepting the terms
+///
+/// * `version` - the version of the terms accepted
+///
+/// * `repository` - the user repository to interact with
+///
+/// * `audit_repository` - the audit repository where the acceptance is logged
+///
+fn _accept_terms(
+    u: &mut User,
+    version: i32,
+    repository: &dyn UserRepository,
+    audit_repository: &dyn AuditRepository,
+) -> Result<(), AuthError> {
+    let changes = UserChangeset::new().accepted_tos_version(Some(version));
+    if repository.patch_user(u.get_id(), &changes).is_err() {
+        return Err(AuthError::TosAcceptanceError);
+    }
+    u.set_accepted_tos_version(Some(version));
+
+    // the acceptance history is kept in the audit log
+    if audit::record(
+        audit_repository,
+        Some(u.get_id()),
+        AuditEvent::TosAccepted,
+        Some(format!("version {}", version)),
+    )
+    .is_err()
+    {
+        return Err(AuthError::TosAcceptanceError);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::db::repository::{MockSQliteAuditRepository, MockSQliteUserRepository};
+    use crate::errors::{AuditDBError, UserDBError};
+
+    #[test]
+    fn test_requires_acceptance() {
+        let mut u = User::new("email@email.test", "passwd_hash");
+        assert!(requires_acceptance(&u));
+
+        u.set_accepted_tos_version(Some(TOS_VERSION - 1));
+        assert!(requires_acceptance(&u));
+
+        u.set_accepted_tos_version(Some(TOS_VERSION));
+        assert!(!requires_acceptance(&u));
+    }
+
+    #[test]
+    fn test_accept_terms() {
+        let mut mock = MockSQliteUserRepository::new();
+        let mut audit_mock = MockSQliteAuditRepository::new();
+
+        mock.expect_patch_user()
+            .withf(|_, c| *c == UserChangeset::new().accepted_tos_version(Some(2)))
+            .times(1)
+            .returning(|_, _| Ok(()));
+        audit_mock
+            .expect_create_entry()
+            .withf(|_, e, d| e == "TosAccepted" && d.as_deref() == Some("version 2"))
+            .times(1)
+            .returning(|_, _, _| Ok(()));
+
+        let mut u = User::new("email@email.test", "passwd_hash");
+        let res = _accept_terms(&mut u, 2, &mock, &audit_mock);
+
+        assert_eq!(Ok(()), res);
+        assert_eq!(u.get_accepted_tos_version(), Some(2));
+    }
+
+    #[test]
+    fn test_accept_terms_with_db_failure() {
+        let mut mock = MockSQliteUserRepository::new();
+        let mut audit_mock = MockSQliteAuditRepository::new();
+
+        mock.expect_patch_user()
+            .returning(|_, _| Err(UserDBError::UpdateUserError));
+        audit_mock.expect_create_entry().times(0);
+
+        let mut u = User::new("email@email.test", "passwd_hash");
+        let res = _accept_terms(&mut u, 2, &mock, &audit_mock);
+
+        assert_eq!(Err(AuthError::TosAcceptanceError), res);
+        assert_eq!(u.get_accepted_tos_version(), None);
+    }
+
+    #[test]
+    fn test_accept_terms_with_audit_failure() {
+        let mut mock = MockSQliteUserRepository::new();
+        let mut audit_mock = MockSQliteAuditRepository::new();
+
+        mock.expect_patch_user().returning(|_, _| Ok(()));
+        audit_mock
+            .expect_create_entry()
+            .returning(|_, _, _| Err(AuditDBError::CreateEntryError));
+
+        let mut u = User::new("email@email.test", "passwd_hash");
+        let res = _accept_terms(&mut u, 2, &mock, &audit_mock);
+
+        assert_eq!(Err(AuthError::TosAcceptanceError), res);
+    }
+}
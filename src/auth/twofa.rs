@@ -5,7 +5,219 @@
  * Doran Kayoumi <doran.kayoumi@heig-vd.ch>
  */
 
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
 use google_authenticator::{ErrorCorrectionLevel, GoogleAuthenticator};
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use qrcode::render::svg;
+use qrcode::{Color, EcLevel, QrCode};
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::{maintenance, timing};
+use crate::db::models::{User, UserChangeset};
+#[cfg(feature = "async")]
+use crate::db::repository::{run_blocking, AsyncUserRepository};
+use crate::db::repository::{SQliteUserRepository, UserRepository};
+use crate::errors::AuthError;
+use crate::portable::otp;
+use crate::utils::{self, Redacted};
+use crate::validation::MAX_TOKEN_BYTES;
+#[cfg(feature = "async")]
+use std::sync::Arc;
+
+/// Number of digits of the 2fa codes
+pub const DIGITS: usize = 6;
+/// Number of seconds during which a 2fa code is valid
+/// Note: the secrets are generated by the `google_authenticator` crate, which only supports 30s
+pub const PERIOD_SECS: u64 = 30;
+/// Number of periods before & after the current one in which a code is still accepted
+/// Note: one period covers a clock drift of 30s & a code typed as it changed,
+///       more would leave a stolen code usable for minutes
+const TOLERANCE_PERIODS: u64 = 1;
+
+/// Size of a module (i.e. a black or white square) of the PNG QR codes, in pixels
+const QR_MODULE_PX: usize = 8;
+/// Width of the blank border around the PNG QR codes, in modules
+const QR_QUIET_ZONE: usize = 4;
+
+/// Android package of Google Authenticator
+const GOOGLE_AUTHENTICATOR_PACKAGE: &str = "com.google.android.apps.authenticator2";
+/// Android package of Aegis
+const AEGIS_PACKAGE: &str = "com.beemdevelopment.aegis";
+
+/// Authenticator apps a secret can be added to with a link, e.g. from a web frontend opened on a phone
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum AuthenticatorApp {
+    GoogleAuthenticator,
+    /// Only available on Android
+    Aegis,
+}
+
+/// Platform of the phone opening a link
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum Platform {
+    /// The links target the app, through an intent
+    Android,
+    /// The links can't target an app, the system opens the one handling their scheme
+    Ios,
+}
+
+/// Everything a user needs to add her/his 2fa secret to an authenticator app,
+/// by scanning a QR code or by entering it manually
+/// Note: the secret is hidden in the debug output
+#[derive(PartialEq, Clone)]
+pub struct Enrollment {
+    /// base32 encoded secret
+    pub secret: String,
+    /// name of the account in the app (e.g. the email of the user)
+    pub account: String,
+    /// name of the service in the app
+    pub issuer: String,
+    pub digits: usize,
+    pub period_secs: u64,
+}
+
+impl fmt::Debug for Enrollment {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Enrollment")
+            .field("secret", &Redacted)
+            .field("account", &self.account)
+            .field("issuer", &self.issuer)
+            .field("digits", &self.digits)
+            .field("period_secs", &self.period_secs)
+            .finish()
+    }
+}
+
+impl Enrollment {
+    /// The secret split in groups of four characters, easier to type by hand
+    pub fn formatted_secret(&self) -> String {
+        self.secret
+            .as_bytes()
+            .chunks(4)
+            .map(|c| String::from_utf8_lossy(c))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    /// The `otpauth://` URI understood by the authenticator apps (i.e. the content of the QR code)
+    pub fn uri(&self) -> String {
+        let issuer = utf8_percent_encode(&self.issuer, NON_ALPHANUMERIC).to_string();
+        let account = utf8_percent_encode(&self.account, NON_ALPHANUMERIC);
+
+        format!(
+            "otpauth://totp/{}:{}?secret={}&issuer={}&digits={}&period={}",
+            issuer, account, self.secret, issuer, self.digits, self.period_secs
+        )
+    }
+
+    /// The `otpauth-migration://` URI of the accounts transfer of Google Authenticator
+    /// (also imported by Aegis), holding the secret as a protobuf `MigrationPayload`
+    /// Returns `None` if the format can't represent the enrollment, i.e. its secret
+    /// isn't base32, it has neither 6 nor 8 digits or a period other than 30 seconds
+    pub fn migration_uri(&self) -> Option<String> {
+        let digits = match self.digits {
+            6 => 1,
+            8 => 2,
+            _ => return None,
+        };
+        if self.period_secs != 30 {
+            return None;
+        }
+
+        // OtpParameters: secret, name, issuer, algorithm (SHA1), digits, type (TOTP)
+        let mut otp = Vec::new();
+        put_bytes(&mut otp, 1, &otp::decode_secret(&self.secret)?);
+        put_bytes(&mut otp, 2, self.account.as_bytes());
+        put_bytes(&mut otp, 3, self.issuer.as_bytes());
+        put_varint(&mut otp, 4, 1);
+        put_varint(&mut otp, 5, digits);
+        put_varint(&mut otp, 6, 2);
+
+        // MigrationPayload: otp parameters, version, batch size (the batch index is 0)
+        let mut payload = Vec::new();
+        put_bytes(&mut payload, 1, &otp);
+        put_varint(&mut payload, 2, 1);
+        put_varint(&mut payload, 3, 1);
+
+        let data = BASE64.encode(payload);
+        Some(format!(
+            "otpauth-migration://offline?data={}",
+            utf8_percent_encode(&data, NON_ALPHANUMERIC)
+        ))
+    }
+
+    /// Link opening an authenticator app on a phone to add the secret to it
+    /// Google Authenticator imports the `migration_uri`, Aegis the `uri`
+    /// Returns `None` if the app doesn't exist on the platform or can't import the enrollment
+    ///
+    /// # Arguments
+    ///
+    /// * `app` - the app to open
+    ///
+    /// * `platform` - the platform of the phone
+    ///
+    pub fn deep_link(&self, app: AuthenticatorApp, platform: Platform) -> Option<String> {
+        let (uri, package) = match app {
+            AuthenticatorApp::GoogleAuthenticator => {
+                (self.migration_uri()?, GOOGLE_AUTHENTICATOR_PACKAGE)
+            }
+            AuthenticatorApp::Aegis if platform == Platform::Android => (self.uri(), AEGIS_PACKAGE),
+            AuthenticatorApp::Aegis => return None,
+        };
+
+        match platform {
+            Platform::Ios => Some(uri),
+            Platform::Android => {
+                let (scheme, rest) = uri.split_once("://")?;
+                Some(format!(
+                    "intent://{}#Intent;scheme={};package={};end",
+                    rest, scheme, package
+                ))
+            }
+        }
+    }
+}
+
+/// Appends a varint field to a protobuf message
+///
+/// # Arguments
+///
+/// * `buf` - the message
+///
+/// * `field` - the number of the field
+///
+/// * `value` - the value of the field
+///
+fn put_varint(buf: &mut Vec<u8>, field: u8, value: u64) {
+    buf.push(field << 3);
+    write_varint(buf, value);
+}
+
+/// Appends a length-delimited field (bytes, string or message) to a protobuf message
+///
+/// # Arguments
+///
+/// * `buf` - the message
+///
+/// * `field` - the number of the field
+///
+/// * `value` - the value of the field
+///
+fn put_bytes(buf: &mut Vec<u8>, field: u8, value: &[u8]) {
+    buf.push(field << 3 | 2);
+    write_varint(buf, value.len() as u64);
+    buf.extend_from_slice(value);
+}
+
+fn write_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
 
 /// Checks that a 2fa code entered by a user is valid
 ///
@@ -16,8 +228,32 @@ use google_authenticator::{ErrorCorrectionLevel, GoogleAuthenticator};
 /// * `code` - the code to check
 ///
 pub fn check_code(secret: &str, code: &str) -> bool {
-    let auth = GoogleAuthenticator::new();
-    auth.verify_code(secret, &code, 30, 0)
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    check_code_at(secret, code, now)
+}
+
+/// Checks that a 2fa code is valid at a given time
+///
+/// # Arguments
+///
+/// * `secret` - the secret under which the code was genereated
+///
+/// * `code` - the code to check
+///
+/// * `at` - the unix timestamp at which the code is checked
+///
+fn check_code_at(secret: &str, code: &str, at: u64) -> bool {
+    otp::verify_totp(
+        secret,
+        code,
+        at,
+        PERIOD_SECS,
+        DIGITS as u32,
+        TOLERANCE_PERIODS,
+    )
 }
 
 /// Generates a secret for the 2fa
@@ -26,6 +262,24 @@ pub fn generate_secret() -> String {
     auth.create_secret(32)
 }
 
+/// Generates a new secret & everything needed to add it to an authenticator app
+///
+/// # Arguments
+///
+/// * `account` - the name of the account (e.g. the email of the user)
+///
+/// * `issuer` - the name of the service
+///
+pub fn enroll(account: &str, issuer: &str) -> Enrollment {
+    Enrollment {
+        secret: generate_secret(),
+        account: account.to_string(),
+        issuer: issuer.to_string(),
+        digits: DIGITS,
+        period_secs: PERIOD_SECS,
+    }
+}
+
 /// Generates the url of QR code for a given secret
 /// With the qr code, the user will be able to add to an authenticator app
 /// e.g. Google Authenticator
@@ -43,9 +297,299 @@ pub fn generate_qr(secret: &str, name: &str, title: &str) -> String {
     auth.qr_code_url(secret, name, title, 400, 400, ErrorCorrectionLevel::High)
 }
 
+/// Public function to start the enrollment of a 2fa secret
+/// See `_start_enrollment` for more info
+///
+pub fn start_enrollment(u: &mut User, issuer: &str) -> Result<Enrollment, AuthError> {
+    start_enrollment_with_repository(u, issuer, &SQliteUserRepository::new())
+}
+
+/// Same as `start_enrollment`, with the users of a given storage (e.g. `PostgresUserRepository`)
+pub fn start_enrollment_with_repository(
+    u: &mut User,
+    issuer: &str,
+    repository: &dyn UserRepository,
+) -> Result<Enrollment, AuthError> {
+    maintenance::check_writable()?;
+    _start_enrollment(u, issuer, repository)
+}
+
+/// Same as `start_enrollment_with_repository`, run on a blocking thread of tokio (see `run_blocking`)
+/// Note: the user is only updated once the flow succeeded
+#[cfg(feature = "async")]
+pub async fn start_enrollment_async(
+    u: &mut User,
+    issuer: &str,
+    repository: Arc<dyn AsyncUserRepository>,
+) -> Result<Enrollment, AuthError> {
+    let (mut user, issuer) = (u.clone(), issuer.to_string());
+    let (user, res) = run_blocking(repository, AuthError::SecretRotationError, move |r| {
+        let res = start_enrollment_with_repository(&mut user, &issuer, r)?;
+        Ok((user, res))
+    })
+    .await?;
+    *u = user;
+
+    Ok(res)
+}
+
+/// Keeps a new secret pending until the user confirms it (see `confirm_rotation`)
+///
+/// # Arguments
+///
+/// * `u` - the user getting the secret
+///
+/// * `issuer` - the name of the service
+///
+/// * `repository` - the user repository to interact with
+///
+fn store_pending_secret(
+    u: &mut User,
+    issuer: &str,
+    repository: &dyn UserRepository,
+) -> Result<Enrollment, AuthError> {
+    let enrollment = enroll(&u.get_email(), issuer);
+
+    let changes = UserChangeset::new().pending_secret_2fa(Some(enrollment.secret.clone()));
+    if repository.patch_user(u.get_id(), &changes).is_err() {
+        return Err(AuthError::SecretRotationError);
+    }
+    u.set_pending_secret_2fa(Some(enrollment.secret.clone()));
+
+    Ok(enrollment)
+}
+
+/// Generates the first 2fa secret of a user
+/// As for a rotation, the secret stays pending until the user confirms she/he
+/// added it to her/his app (see `confirm_rotation`), the 2fa is only enabled then
+///
+/// # Arguments
+///
+/// * `u` - the user enabling the 2fa
+///
+/// * `issuer` - the name of the service
+///
+/// * `repository` - the user repository to interact with
+///
+fn _start_enrollment(
+    u: &mut User,
+    issuer: &str,
+    repository: &dyn UserRepository,
+) -> Result<Enrollment, AuthError> {
+    if u.is_2fa_enabled() {
+        return Err(AuthError::TwoFaAlreadyEnabled);
+    }
+
+    store_pending_secret(u, issuer, repository)
+}
+
+/// Public function to start the rotation of a 2fa secret
+/// See `_start_rotation` for more info
+///
+pub fn start_rotation(u: &mut User, issuer: &str) -> Result<Enrollment, AuthError> {
+    start_rotation_with_repository(u, issuer, &SQliteUserRepository::new())
+}
+
+/// Same as `start_rotation`, with the users of a given storage (e.g. `PostgresUserRepository`)
+pub fn start_rotation_with_repository(
+    u: &mut User,
+    issuer: &str,
+    repository: &dyn UserRepository,
+) -> Result<Enrollment, AuthError> {
+    maintenance::check_writable()?;
+    _start_rotation(u, issuer, repository)
+}
+
+/// Same as `start_rotation_with_repository`, run on a blocking thread of tokio (see `run_blocking`)
+/// Note: the user is only updated once the flow succeeded
+#[cfg(feature = "async")]
+pub async fn start_rotation_async(
+    u: &mut User,
+    issuer: &str,
+    repository: Arc<dyn AsyncUserRepository>,
+) -> Result<Enrollment, AuthError> {
+    let (mut user, issuer) = (u.clone(), issuer.to_string());
+    let (user, res) = run_blocking(repository, AuthError::SecretRotationError, move |r| {
+        let res = start_rotation_with_repository(&mut user, &issuer, r)?;
+        Ok((user, res))
+    })
+    .await?;
+    *u = user;
+
+    Ok(res)
+}
+
+/// Generates a new 2fa secret for a user who already enabled 2fa
+/// The new secret stays pending (and the current one valid) until the user
+/// confirms she/he added it to her/his app (see `confirm_rotation`)
+/// Starting again replaces the pending secret
+///
+/// # Arguments
+///
+/// * `u` - the user changing her/his secret
+///
+/// * `issuer` - the name of the service
+///
+/// * `repository` - the user repository to interact with
+///
+fn _start_rotation(
+    u: &mut User,
+    issuer: &str,
+    repository: &dyn UserRepository,
+) -> Result<Enrollment, AuthError> {
+    if !u.is_2fa_enabled() {
+        return Err(AuthError::TwoFaNotEnabled);
+    }
+
+    store_pending_secret(u, issuer, repository)
+}
+
+/// Public function to confirm the rotation of a 2fa secret
+/// See `_confirm_rotation` for more info
+///
+pub fn confirm_rotation(u: &mut User, code: &str) -> Result<(), AuthError> {
+    confirm_rotation_with_repository(u, code, &SQliteUserRepository::new())
+}
+
+/// Same as `confirm_rotation`, with the users of a given storage (e.g. `PostgresUserRepository`)
+pub fn confirm_rotation_with_repository(
+    u: &mut User,
+    code: &str,
+    repository: &dyn UserRepository,
+) -> Result<(), AuthError> {
+    maintenance::check_writable()?;
+    timing::padded(|| _confirm_rotation(u, code, repository))
+}
+
+/// Same as `confirm_rotation_with_repository`, run on a blocking thread of tokio (see `run_blocking`)
+/// Note: the user is only updated once the flow succeeded
+#[cfg(feature = "async")]
+pub async fn confirm_rotation_async(
+    u: &mut User,
+    code: &str,
+    repository: Arc<dyn AsyncUserRepository>,
+) -> Result<(), AuthError> {
+    let (mut user, code) = (u.clone(), code.to_string());
+    *u = run_blocking(repository, AuthError::SecretRotationError, move |r| {
+        confirm_rotation_with_repository(&mut user, &code, r)?;
+        Ok(user)
+    })
+    .await?;
+
+    Ok(())
+}
+
+/// Replaces the 2fa secret of a user by her/his pending one
+/// The code must have been generated with the pending secret, which proves
+/// the user's app is set up correctly
+///
+/// # Arguments
+///
+/// * `u` - the user changing her/his secret
+///
+/// * `code` - a code generated with the pending secret
+///
+/// * `repository` - the user repository to interact with
+///
+fn _confirm_rotation(
+    u: &mut User,
+    code: &str,
+    repository: &dyn UserRepository,
+) -> Result<(), AuthError> {
+    utils::check_length(code, MAX_TOKEN_BYTES)?;
+    let pending = u
+        .get_pending_secret_2fa()
+        .ok_or(AuthError::NoPendingSecret)?;
+
+    if !check_code(&pending, code) {
+        return Err(AuthError::InvalidAuthenticationCode);
+    }
+
+    let changes = UserChangeset::new()
+        .secret_2fa(Some(pending.clone()))
+        .pending_secret_2fa(None);
+    if repository.patch_user(u.get_id(), &changes).is_err() {
+        return Err(AuthError::SecretRotationError);
+    }
+    u.set_secret_2fa(Some(pending));
+    u.set_pending_secret_2fa(None);
+
+    Ok(())
+}
+
+/// Encodes the otpauth URI of an enrollment in a QR code
+/// Returns `None` if the URI is too long for a QR code, i.e. the account or
+/// issuer names are thousands of characters long
+///
+fn enrollment_qr(enrollment: &Enrollment) -> Option<QrCode> {
+    QrCode::with_error_correction_level(enrollment.uri(), EcLevel::M).ok()
+}
+
+/// Generates the QR code of an enrollment as a PNG image
+/// Unlike `generate_qr`, the image is generated locally, the secret isn't sent anywhere
+/// Returns `None` if the enrollment doesn't fit in a QR code (see `enrollment_qr`)
+///
+/// # Arguments
+///
+/// * `enrollment` - the enrollment to generate the QR code from
+///
+pub fn enrollment_qr_png(enrollment: &Enrollment) -> Option<Vec<u8>> {
+    let code = enrollment_qr(enrollment)?;
+    let size = (code.width() + 2 * QR_QUIET_ZONE) * QR_MODULE_PX;
+
+    // grayscale pixels, white by default
+    let mut pixels = vec![0xff_u8; size * size];
+    for (i, color) in code.to_colors().iter().enumerate() {
+        if *color != Color::Dark {
+            continue;
+        }
+
+        let x = (i % code.width() + QR_QUIET_ZONE) * QR_MODULE_PX;
+        let y = (i / code.width() + QR_QUIET_ZONE) * QR_MODULE_PX;
+        for row in y..y + QR_MODULE_PX {
+            pixels[row * size + x..row * size + x + QR_MODULE_PX].fill(0);
+        }
+    }
+
+    let mut image = Vec::new();
+    let mut encoder = png::Encoder::new(&mut image, size as u32, size as u32);
+    encoder.set_color(png::ColorType::Grayscale);
+    encoder.set_depth(png::BitDepth::Eight);
+
+    // Note: the image is written in memory, which can't fail
+    let mut writer = encoder.write_header().ok()?;
+    writer.write_image_data(&pixels).ok()?;
+    writer.finish().ok()?;
+
+    Some(image)
+}
+
+/// Generates the QR code of an enrollment as an SVG image
+/// Unlike `generate_qr`, the image is generated locally, the secret isn't sent anywhere
+/// Returns `None` if the enrollment doesn't fit in a QR code (see `enrollment_qr`)
+///
+/// # Arguments
+///
+/// * `enrollment` - the enrollment to generate the QR code from
+///
+pub fn enrollment_qr_svg(enrollment: &Enrollment) -> Option<String> {
+    let svg = enrollment_qr(enrollment)?
+        .render()
+        .min_dimensions(200, 200)
+        .dark_color(svg::Color("#000000"))
+        .light_color(svg::Color("#ffffff"))
+        .build();
+
+    Some(svg)
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::db::repository::MockSQliteUserRepository;
+    use rstest::rstest;
+
+    const SECRET: &str = "I3VFM3JKMNDJCDH5BMBEEQAW6KJ6NOE3";
 
     #[test]
     fn test_check_code() {
@@ -53,8 +597,66 @@ mod test {
         let auth = GoogleAuthenticator::new();
         let code = auth.get_code(secret, 0).unwrap();
 
-        assert_eq!(check_code(secret, &code), true);
-        assert_eq!(check_code(secret, "000000"), false);
+        assert!(check_code(secret, &code));
+        assert!(!check_code(secret, "000000"));
+    }
+
+    /// Secret of the test vectors of the RFCs, "12345678901234567890" in base32
+    const RFC_SECRET: &str = "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ";
+
+    // RFC 4226, appendix D, the codes of the crate must match the ones checked (see `portable/otp.rs`)
+    // Note: the counter 0 can't be given to the crate, it stands for the current time
+    #[rstest(
+        counter,
+        expected,
+        case(1, "287082"),
+        case(2, "359152"),
+        case(3, "969429"),
+        case(4, "338314"),
+        case(5, "254676"),
+        case(6, "287922"),
+        case(7, "162583"),
+        case(8, "399871"),
+        case(9, "520489"),
+        ::trace
+    )]
+    fn test_hotp_vectors(counter: u64, expected: &str) {
+        let auth = GoogleAuthenticator::new().with_code_length(DIGITS);
+
+        assert_eq!(auth.get_code(RFC_SECRET, counter).unwrap(), expected);
+    }
+
+    // RFC 6238, appendix B (SHA-1), the codes are truncated to their last `DIGITS` digits
+    #[rstest(
+        at,
+        expected,
+        case(59, "287082"),
+        case(1111111109, "081804"),
+        case(1111111111, "050471"),
+        case(1234567890, "005924"),
+        case(2000000000, "279037"),
+        case(20000000000, "353130"),
+        ::trace
+    )]
+    fn test_totp_vectors(at: u64, expected: &str) {
+        assert!(check_code_at(RFC_SECRET, expected, at));
+        assert!(!check_code_at(RFC_SECRET, "000000", at));
+    }
+
+    #[test]
+    fn test_code_tolerance() {
+        let auth = GoogleAuthenticator::new().with_code_length(DIGITS);
+        let at = 1234567890;
+        let period = at / PERIOD_SECS;
+        let code_of = |p: u64| auth.get_code(RFC_SECRET, p).unwrap();
+
+        assert!(check_code_at(RFC_SECRET, &code_of(period), at));
+        // the code of the previous period is accepted (e.g. typed as it changed), not the one before
+        assert!(check_code_at(RFC_SECRET, &code_of(period - 1), at));
+        assert!(!check_code_at(RFC_SECRET, &code_of(period - 2), at));
+        // same for a clock running late
+        assert!(check_code_at(RFC_SECRET, &code_of(period + 1), at));
+        assert!(!check_code_at(RFC_SECRET, &code_of(period + 2), at));
     }
 
     #[test]
@@ -64,6 +666,242 @@ mod test {
         assert_eq!(secret.len(), 32);
     }
 
+    #[test]
+    fn test_enroll() {
+        let enrollment = enroll("user@email.test", "Secure Auth");
+
+        assert_eq!(enrollment.secret.len(), 32);
+        assert_eq!(enrollment.digits, DIGITS);
+        assert_eq!(enrollment.period_secs, PERIOD_SECS);
+    }
+
+    fn enrollment() -> Enrollment {
+        Enrollment {
+            secret: "I3VFM3JKMNDJCDH5BMBEEQAW6KJ6NOE3".to_string(),
+            account: "user@email.test".to_string(),
+            issuer: "Secure Auth".to_string(),
+            digits: 6,
+            period_secs: 30,
+        }
+    }
+
+    #[test]
+    fn test_enrollment_manual_entry() {
+        let enrollment = enrollment();
+
+        assert_eq!(
+            enrollment.formatted_secret(),
+            "I3VF M3JK MNDJ CDH5 BMBE EQAW 6KJ6 NOE3"
+        );
+        assert_eq!(
+            enrollment.uri(),
+            "otpauth://totp/Secure%20Auth:user%40email%2Etest?secret=I3VFM3JKMNDJCDH5BMBEEQAW6KJ6NOE3&issuer=Secure%20Auth&digits=6&period=30"
+        );
+    }
+
+    #[test]
+    fn test_enrollment_migration_uri() {
+        let uri = enrollment().migration_uri().unwrap();
+        let data = uri
+            .strip_prefix("otpauth-migration://offline?data=")
+            .unwrap();
+        let data = percent_encoding::percent_decode_str(data)
+            .decode_utf8()
+            .unwrap();
+        let payload = BASE64.decode(data.as_bytes()).unwrap();
+
+        let mut otp = vec![0x0a, 20];
+        otp.extend(otp::decode_secret(SECRET).unwrap());
+        otp.extend([0x12, 15]);
+        otp.extend(b"user@email.test");
+        otp.extend([0x1a, 11]);
+        otp.extend(b"Secure Auth");
+        otp.extend([0x20, 1, 0x28, 1, 0x30, 2]);
+        let mut expected = vec![0x0a, otp.len() as u8];
+        expected.extend(otp);
+        expected.extend([0x10, 1, 0x18, 1]);
+
+        assert_eq!(payload, expected);
+    }
+
+    #[rstest(
+        digits,
+        period_secs,
+        expected,
+        case(6, 30, true),
+        case(8, 30, true),
+        case(7, 30, false),
+        case(6, 60, false),
+        ::trace
+    )]
+    fn test_enrollment_migration_uri_support(digits: usize, period_secs: u64, expected: bool) {
+        let enrollment = Enrollment {
+            digits,
+            period_secs,
+            ..enrollment()
+        };
+
+        assert_eq!(enrollment.migration_uri().is_some(), expected);
+    }
+
+    #[test]
+    fn test_enrollment_deep_links() {
+        let enrollment = enrollment();
+        let migration = enrollment.migration_uri().unwrap();
+
+        assert_eq!(
+            enrollment.deep_link(AuthenticatorApp::GoogleAuthenticator, Platform::Ios),
+            Some(migration.clone())
+        );
+        assert_eq!(
+            enrollment.deep_link(AuthenticatorApp::GoogleAuthenticator, Platform::Android),
+            Some(format!(
+                "intent://{}#Intent;scheme=otpauth-migration;package={};end",
+                migration.trim_start_matches("otpauth-migration://"),
+                GOOGLE_AUTHENTICATOR_PACKAGE
+            ))
+        );
+        assert_eq!(
+            enrollment.deep_link(AuthenticatorApp::Aegis, Platform::Android),
+            Some(format!(
+                "intent://{}#Intent;scheme=otpauth;package={};end",
+                enrollment.uri().trim_start_matches("otpauth://"),
+                AEGIS_PACKAGE
+            ))
+        );
+        assert_eq!(
+            enrollment.deep_link(AuthenticatorApp::Aegis, Platform::Ios),
+            None
+        );
+    }
+
+    #[test]
+    fn test_enrollment_qr_png() {
+        let image = enrollment_qr_png(&enrollment()).unwrap();
+
+        let decoder = png::Decoder::new(image.as_slice());
+        let mut reader = decoder.read_info().unwrap();
+        let mut pixels = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut pixels).unwrap();
+
+        assert_eq!(info.width, info.height);
+        assert_eq!(info.width as usize % QR_MODULE_PX, 0);
+        // blank border, then the top left finder pattern
+        assert_eq!(pixels[0], 0xff);
+        let corner = QR_QUIET_ZONE * QR_MODULE_PX;
+        assert_eq!(pixels[corner * info.width as usize + corner], 0);
+    }
+
+    #[test]
+    fn test_enrollment_qr_svg() {
+        let image = enrollment_qr_svg(&enrollment()).unwrap();
+
+        assert!(image.contains("<svg"));
+        assert!(!image.contains(&enrollment().secret));
+    }
+
+    #[test]
+    fn test_enrollment_qr_too_long() {
+        let enrollment = Enrollment {
+            account: "a".repeat(5000),
+            ..enrollment()
+        };
+
+        assert_eq!(enrollment_qr_png(&enrollment), None);
+        assert_eq!(enrollment_qr_svg(&enrollment), None);
+    }
+
+    #[test]
+    fn test_start_rotation() {
+        let mut mock = MockSQliteUserRepository::new();
+        mock.expect_patch_user()
+            .withf(|_, c| *c != UserChangeset::new().pending_secret_2fa(None))
+            .times(1)
+            .returning(|_, _| Ok(()));
+
+        let mut u = User::new("email@email.test", "passwd_hash");
+        u.set_secret_2fa(Some(SECRET.to_string()));
+        let enrollment = _start_rotation(&mut u, "test", &mock).unwrap();
+
+        // the current secret stays valid until the new one is confirmed
+        assert_eq!(u.get_secret_2fa().as_deref(), Some(SECRET));
+        assert_eq!(u.get_pending_secret_2fa(), Some(enrollment.secret));
+    }
+
+    #[test]
+    fn test_start_enrollment() {
+        let mut mock = MockSQliteUserRepository::new();
+        mock.expect_patch_user().times(1).returning(|_, _| Ok(()));
+
+        let mut u = User::new("email@email.test", "passwd_hash");
+        let enrollment = _start_enrollment(&mut u, "test", &mock).unwrap();
+
+        // only enabled once confirmed
+        assert!(!u.is_2fa_enabled());
+        assert_eq!(u.get_pending_secret_2fa(), Some(enrollment.secret));
+
+        u.set_secret_2fa(Some(SECRET.to_string()));
+        assert_eq!(
+            _start_enrollment(&mut u, "test", &mock),
+            Err(AuthError::TwoFaAlreadyEnabled)
+        );
+    }
+
+    #[test]
+    fn test_start_rotation_without_2fa() {
+        let mut mock = MockSQliteUserRepository::new();
+        mock.expect_patch_user().times(0);
+
+        let mut u = User::new("email@email.test", "passwd_hash");
+
+        assert_eq!(
+            _start_rotation(&mut u, "test", &mock),
+            Err(AuthError::TwoFaNotEnabled)
+        );
+    }
+
+    #[test]
+    fn test_confirm_rotation() {
+        let mut mock = MockSQliteUserRepository::new();
+        mock.expect_patch_user()
+            .withf(|_, c| {
+                *c == UserChangeset::new()
+                    .secret_2fa(Some(SECRET.to_string()))
+                    .pending_secret_2fa(None)
+            })
+            .times(1)
+            .returning(|_, _| Ok(()));
+
+        let mut u = User::new("email@email.test", "passwd_hash");
+        u.set_secret_2fa(Some("OLD2FASECRET".to_string()));
+        u.set_pending_secret_2fa(Some(SECRET.to_string()));
+
+        // a code from the old secret isn't enough
+        assert_eq!(
+            _confirm_rotation(&mut u, "000000", &mock),
+            Err(AuthError::InvalidAuthenticationCode)
+        );
+        assert_eq!(u.get_secret_2fa().as_deref(), Some("OLD2FASECRET"));
+
+        let code = GoogleAuthenticator::new().get_code(SECRET, 0).unwrap();
+        assert_eq!(_confirm_rotation(&mut u, &code, &mock), Ok(()));
+        assert_eq!(u.get_secret_2fa().as_deref(), Some(SECRET));
+        assert_eq!(u.get_pending_secret_2fa(), None);
+    }
+
+    #[test]
+    fn test_confirm_rotation_without_pending_secret() {
+        let mock = MockSQliteUserRepository::new();
+
+        let mut u = User::new("email@email.test", "passwd_hash");
+        u.set_secret_2fa(Some(SECRET.to_string()));
+
+        assert_eq!(
+            _confirm_rotation(&mut u, "000000", &mock),
+            Err(AuthError::NoPendingSecret)
+        );
+    }
+
     #[test]
     fn test_generate_qr() {
         let secret = "I3VFM3JKMNDJCDH5BMBEEQAW6KJ6NOE3";
@@ -71,9 +909,37 @@ mod test {
         let title = "test";
         let qr_url = generate_qr(secret, name, title);
 
-        assert_eq!(qr_url.contains(secret), true);
-        assert_eq!(qr_url.contains(name), true);
-        assert_eq!(qr_url.contains(title), true);
-        assert_eq!(qr_url.contains("http"), true);
+        assert!(qr_url.contains(secret));
+        assert!(qr_url.contains(name));
+        assert!(qr_url.contains(title));
+        assert!(qr_url.contains("http"));
+    }
+
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    async fn test_enrollment_async() {
+        use crate::db::repository::InMemoryUserRepository;
+
+        let repository = Arc::new(InMemoryUserRepository::new());
+        UserRepository::create_user(repository.as_ref(), "email@email.test", "passwd_hash")
+            .unwrap();
+        let mut u = UserRepository::get_user(repository.as_ref(), "email@email.test").unwrap();
+
+        let enrollment = start_enrollment_async(&mut u, "test", repository.clone())
+            .await
+            .unwrap();
+        assert_eq!(u.get_pending_secret_2fa(), Some(enrollment.secret.clone()));
+
+        let code = GoogleAuthenticator::new()
+            .get_code(&enrollment.secret, 0)
+            .unwrap();
+        confirm_rotation_async(&mut u, &code, repository.clone())
+            .await
+            .unwrap();
+        assert_eq!(u.get_secret_2fa(), Some(enrollment.secret));
+        assert_eq!(
+            UserRepository::get_user(repository.as_ref(), "email@email.test").unwrap(),
+            u
+        );
     }
 }
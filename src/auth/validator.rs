@@ -0,0 +1,221 @@
+/*!
+ * Pluggable validators run during the registration
+ *
+ * Deployments can implement `RegistrationValidator` to add their own checks
+ * (e.g. age confirmation, domain membership, invite codes) and chain them with
+ * a `ValidatorChain` without having to modify the registration itself.
+ *
+ * # Author
+ * Doran Kayoumi <doran.kayoumi@heig-vd.ch>
+ */
+
+use regex::Regex;
+
+use crate::errors::AuthError;
+
+pub trait RegistrationValidator {
+    /// Check if a registration is allowed
+    /// if it isn't, the error explaining why is returned
+    ///
+    /// # Arguments
+    ///
+    /// * `email` - email of the new user
+    ///
+    /// * `passwd` - password of the new user
+    ///
+    fn validate(&self, email: &str, passwd: &str) -> Result<(), AuthError>;
+}
+
+/// Chain of validators run one after the other
+/// The first validator rejecting the registration stops the chain
+#[derive(Default)]
+pub struct ValidatorChain {
+    validators: Vec<Box<dyn RegistrationValidator>>,
+}
+
+impl ValidatorChain {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a validator at the end of the chain
+    pub fn with(mut self, validator: impl RegistrationValidator + 'static) -> Self {
+        self.validators.push(Box::new(validator));
+        self
+    }
+}
+
+impl RegistrationValidator for ValidatorChain {
+    fn validate(&self, email: &str, passwd: &str) -> Result<(), AuthError> {
+        self.validators
+            .iter()
+            .try_for_each(|v| v.validate(email, passwd))
+    }
+}
+
+/// Only allows registrations with an e-mail address from one of the given domains
+pub struct EmailDomainValidator {
+    domains: Vec<String>,
+}
+
+impl EmailDomainValidator {
+    pub fn new(domains: &[&str]) -> Self {
+        Self {
+            domains: domains.iter().map(|d| d.to_lowercase()).collect(),
+        }
+    }
+}
+
+impl RegistrationValidator for EmailDomainValidator {
+    fn validate(&self, email: &str, _passwd: &str) -> Result<(), AuthError> {
+        let domain = email.rsplit('@').next().unwrap_or_default().to_lowercase();
+
+        if self.domains.contains(&domain) {
+            Ok(())
+        } else {
+            Err(AuthError::EmailDomainNotAllowed)
+        }
+    }
+}
+
+/// Refuses the registrations with a reserved e-mail (e.g. `admin@` or `security@` on
+/// the domains of the deployment), the patterns come from the `[registration]`
+/// section of the configuration
+/// The e-mails are compared without their case & sub-address (`admin+x@` is `admin@`)
+pub struct ReservedEmailValidator {
+    patterns: Vec<Regex>,
+}
+
+impl ReservedEmailValidator {
+    /// # Arguments
+    ///
+    /// * `patterns` - the reserved e-mails, `*` matches anything (e.g. `admin@*.example.com`)
+    ///
+    pub fn new(patterns: &[String]) -> Self {
+        Self {
+            patterns: patterns
+                .iter()
+                .filter_map(|p| {
+                    let pattern = regex::escape(&p.to_lowercase()).replace("\\*", ".*");
+                    Regex::new(&format!("^{}$", pattern)).ok()
+                })
+                .collect(),
+        }
+    }
+}
+
+impl RegistrationValidator for ReservedEmailValidator {
+    fn validate(&self, email: &str, _passwd: &str) -> Result<(), AuthError> {
+        let email = email.to_lowercase();
+        let (local, domain) = email.rsplit_once('@').unwrap_or((&email, ""));
+        let local = local.split('+').next().unwrap_or_default();
+        let email = format!("{}@{}", local, domain);
+
+        if self.patterns.iter().any(|p| p.is_match(&email)) {
+            Err(AuthError::ReservedEmail)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Requires the user to give her/his consent (e.g. confirm her/his age)
+/// The way the consent is asked is left to the deployment
+pub struct ConsentValidator<F: Fn(&str) -> bool> {
+    ask_consent: F,
+}
+
+impl<F: Fn(&str) -> bool> ConsentValidator<F> {
+    /// # Arguments
+    ///
+    /// * `ask_consent` - function asking the user with the given email for her/his consent
+    ///
+    pub fn new(ask_consent: F) -> Self {
+        Self { ask_consent }
+    }
+}
+
+impl<F: Fn(&str) -> bool> RegistrationValidator for ConsentValidator<F> {
+    fn validate(&self, email: &str, _passwd: &str) -> Result<(), AuthError> {
+        if (self.ask_consent)(email) {
+            Ok(())
+        } else {
+            Err(AuthError::ConsentRequired)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rstest::rstest;
+
+    #[rstest(
+        input,
+        expected,
+        case("doran.kayoumi@heig-vd.ch", Ok(())),
+        case("doran.kayoumi@HEIG-VD.ch", Ok(())),
+        case("dorankayoumi@gmail.com", Err(AuthError::EmailDomainNotAllowed)),
+        case("evil@heig-vd.ch.evil.lo", Err(AuthError::EmailDomainNotAllowed)),
+        ::trace
+    )]
+    fn test_email_domain_validator(input: &str, expected: Result<(), AuthError>) {
+        let validator = EmailDomainValidator::new(&["heig-vd.ch"]);
+
+        assert_eq!(validator.validate(input, "password"), expected);
+    }
+
+    #[rstest(
+        input,
+        expected,
+        case("admin@example.com", Err(AuthError::ReservedEmail)),
+        case("Admin@Example.com", Err(AuthError::ReservedEmail)),
+        case("admin+test@example.com", Err(AuthError::ReservedEmail)),
+        case("security@mail.example.com", Err(AuthError::ReservedEmail)),
+        case("security@example.com", Ok(())),
+        case("admin@gmail.com", Ok(())),
+        case("administrator@example.com", Ok(())),
+        case("security@example.com.evil.lo", Ok(())),
+        case("alice@example.com", Ok(())),
+        ::trace
+    )]
+    fn test_reserved_email_validator(input: &str, expected: Result<(), AuthError>) {
+        let validator = ReservedEmailValidator::new(&[
+            "admin@example.com".to_string(),
+            "security@*.example.com".to_string(),
+        ]);
+
+        assert_eq!(validator.validate(input, "password"), expected);
+    }
+
+    #[test]
+    fn test_consent_validator() {
+        let accept = ConsentValidator::new(|_| true);
+        let refuse = ConsentValidator::new(|_| false);
+
+        assert_eq!(accept.validate("email@email.test", "password"), Ok(()));
+        assert_eq!(
+            refuse.validate("email@email.test", "password"),
+            Err(AuthError::ConsentRequired)
+        );
+    }
+
+    #[test]
+    fn test_empty_chain_accepts_everything() {
+        let chain = ValidatorChain::new();
+
+        assert_eq!(chain.validate("email@email.test", "password"), Ok(()));
+    }
+
+    #[test]
+    fn test_chain_stops_at_first_rejection() {
+        let chain = ValidatorChain::new()
+            .with(EmailDomainValidator::new(&["heig-vd.ch"]))
+            .with(ConsentValidator::new(|_| panic!("shouldn't be asked")));
+
+        assert_eq!(
+            chain.validate("email@email.test", "password"),
+            Err(AuthError::EmailDomainNotAllowed)
+        );
+    }
+}
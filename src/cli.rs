@@ -0,0 +1,629 @@
+/*!
+ * Command line interface of the system
+ *
+ * Without any command, the interactive login screen is started.
+ *
+ * # Author
+ * Doran Kayoumi <doran.kayoumi@heig-vd.ch>
+ */
+
+use clap::{Parser, Subcommand};
+use secure_auth::audit::chain::Head;
+use secure_auth::db::seed::Profile;
+use std::path::PathBuf;
+
+#[derive(Parser, Debug, PartialEq)]
+#[command(name = "secure-auth", about = "A simple authentication system")]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// Disable the colors (setting the `NO_COLOR` environment variable does the same)
+    #[arg(long, global = true)]
+    pub no_color: bool,
+
+    /// Plain-text output for screen readers: no colors, spinners or decorations
+    #[arg(long, global = true)]
+    pub accessible: bool,
+}
+
+#[derive(Subcommand, Debug, PartialEq)]
+pub enum Command {
+    /// Set up the system: configuration, database & administrator account
+    Init,
+
+    /// Create an account, the password & answers are read from the standard input
+    Register {
+        /// The email of the account
+        email: String,
+    },
+
+    /// Check the credentials of an account (& its two factor authentication code)
+    Login {
+        /// The email of the account
+        email: String,
+    },
+
+    /// Enable the two factor authentication of an account
+    #[command(name = "2fa")]
+    TwoFa {
+        #[command(subcommand)]
+        command: TwoFaCommand,
+    },
+
+    /// Reset the password of an account
+    Reset {
+        #[command(subcommand)]
+        command: ResetCommand,
+    },
+
+    /// Database maintenance
+    Db {
+        #[command(subcommand)]
+        command: DbCommand,
+    },
+
+    /// Review the accounts on security hold
+    Hold {
+        #[command(subcommand)]
+        command: HoldCommand,
+    },
+
+    /// Manage the accounts used by other systems, authenticated by an API key or a client certificate
+    Service {
+        #[command(subcommand)]
+        command: ServiceCommand,
+    },
+
+    /// Check the login policies of the configuration
+    Policy {
+        #[command(subcommand)]
+        command: PolicyCommand,
+    },
+
+    /// Warn & disable the accounts inactive for too long (run it periodically, e.g. daily)
+    Inactivity {
+        /// Only show the accounts that would be warned & disabled, without changing anything
+        #[arg(long)]
+        dry_run: bool,
+    },
+
+    /// Show the distribution of the strength scores of the chosen passwords
+    PasswordReport,
+
+    /// Report a change you didn't make with the token of a security alert:
+    /// the account is locked & a reset token is sent
+    NotMe {
+        /// The token of the "this wasn't me" link
+        token: String,
+    },
+
+    /// Verify your email with the token sent at your registration
+    VerifyEmail {
+        /// The token of the verification link
+        token: String,
+    },
+
+    /// Unlock your account with the token sent after too many failed logins:
+    /// no CAPTCHA is asked anymore
+    Unlock {
+        /// The token of the unlock link
+        token: String,
+    },
+
+    /// Recover an account through its trusted contacts
+    Recovery {
+        #[command(subcommand)]
+        command: RecoveryCommand,
+    },
+
+    /// Erase an account & everything linked to it, on the request of its user,
+    /// & print the certificate of the erasure
+    Erase {
+        /// The email of the account
+        email: String,
+
+        /// Only show the certificate the erasure would have, without erasing anything
+        #[arg(long)]
+        dry_run: bool,
+    },
+
+    /// Pseudonymous ids of the users, to use in the metrics, webhooks & logs instead of the emails
+    Pseudonym {
+        #[command(subcommand)]
+        command: PseudonymCommand,
+    },
+
+    /// Show statistics about the users & their activity
+    Stats {
+        /// Print them as JSON (e.g. for a dashboard)
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// Check the security configuration (hashing cost, keys, database & mail server)
+    /// & explain how to fix the problems found
+    Check,
+
+    /// Verify the chain of the tamper-evident audit log
+    VerifyAudit {
+        /// Head printed by a previous verification (`<seq>:<hash>`), to detect a truncation
+        #[arg(long)]
+        expect: Option<Head>,
+    },
+}
+
+#[derive(Subcommand, Debug, PartialEq)]
+pub enum TwoFaCommand {
+    /// Generate a secret to add to an authentication app
+    Enable {
+        /// The email of the account
+        email: String,
+    },
+
+    /// Enable the two factor authentication with a code of the new secret
+    Confirm {
+        /// The email of the account
+        email: String,
+    },
+}
+
+#[derive(Subcommand, Debug, PartialEq)]
+pub enum ResetCommand {
+    /// Send a reset token to the email of an account
+    Request {
+        /// The email of the account
+        email: String,
+    },
+
+    /// Set a new password with the reset token received
+    Complete {
+        /// The email of the account
+        email: String,
+
+        /// The reset token
+        token: String,
+    },
+}
+
+#[derive(Subcommand, Debug, PartialEq)]
+pub enum HoldCommand {
+    /// List the accounts on security hold & why
+    List,
+
+    /// Release the security hold of an account once it was reviewed
+    Release {
+        /// The email of the account
+        email: String,
+    },
+}
+
+#[derive(Subcommand, Debug, PartialEq)]
+pub enum ServiceCommand {
+    /// Create a service account, its API key is shown once
+    Create {
+        /// The email identifying the account
+        email: String,
+
+        /// A scope granted to the account (e.g. `profile:read`), can be repeated
+        #[arg(long = "scope")]
+        scopes: Vec<String>,
+    },
+
+    /// Replace the API key of a service account
+    RotateKey {
+        /// The email identifying the account
+        email: String,
+    },
+
+    /// Register the client certificate of a service account (mTLS)
+    AddCertificate {
+        /// The email identifying the account
+        email: String,
+
+        /// The SHA-256 fingerprint of the certificate
+        fingerprint: String,
+    },
+}
+
+#[derive(Subcommand, Debug, PartialEq)]
+pub enum PolicyCommand {
+    /// Run a scenario of login attempts through the policies & show their decisions
+    Simulate {
+        /// TOML file describing the login attempts (see `auth/simulation.rs`)
+        #[arg(long)]
+        scenario: PathBuf,
+    },
+}
+
+#[derive(Subcommand, Debug, PartialEq)]
+pub enum PseudonymCommand {
+    /// Show the pseudonym of a user
+    Show {
+        /// The email of the account
+        email: String,
+    },
+
+    /// Find the user behind a pseudonym (recorded in the audit log)
+    Resolve {
+        /// The pseudonym, `psn_...`
+        pseudonym: String,
+    },
+}
+
+#[derive(Subcommand, Debug, PartialEq)]
+pub enum RecoveryCommand {
+    /// Ask the trusted contacts of an account to approve its recovery
+    Start {
+        /// The email of the account
+        email: String,
+    },
+
+    /// Approve a recovery with the token received by email
+    Approve {
+        /// The approval token
+        token: String,
+    },
+
+    /// Accept to be the trusted contact of an account with the token received by email
+    Confirm {
+        /// The confirmation token
+        token: String,
+    },
+
+    /// Replace a recovery of your account you didn't start with the token of the alert,
+    /// you get the secret of the new one
+    TakeOver {
+        /// The token of the link of the alert
+        token: String,
+    },
+
+    /// Set a new password once the recovery is approved & the waiting period over
+    Complete {
+        /// The email of the account
+        email: String,
+    },
+}
+
+#[derive(Subcommand, Debug, PartialEq)]
+pub enum DbCommand {
+    /// Create the database or apply its pending migrations
+    Migrate,
+
+    /// Check the database for problems and explain how to fix them
+    Doctor {
+        /// Apply the safe repairs
+        #[arg(long)]
+        repair: bool,
+    },
+
+    /// Create a known set of users for development (never use it in production!)
+    Seed {
+        /// The set of users to create
+        #[arg(long, default_value = "demo")]
+        profile: Profile,
+    },
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use clap::CommandFactory;
+
+    #[test]
+    fn test_cli_definition() {
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn test_parse_db_doctor() {
+        assert_eq!(Cli::parse_from(["secure-auth"]).command, None);
+        assert_eq!(
+            Cli::parse_from(["secure-auth", "db", "doctor", "--repair"]).command,
+            Some(Command::Db {
+                command: DbCommand::Doctor { repair: true }
+            })
+        );
+    }
+
+    #[test]
+    fn test_parse_no_color() {
+        assert!(!Cli::parse_from(["secure-auth"]).no_color);
+        assert!(Cli::parse_from(["secure-auth", "--no-color"]).no_color);
+        assert!(Cli::parse_from(["secure-auth", "db", "doctor", "--no-color"]).no_color);
+    }
+
+    #[test]
+    fn test_parse_accessible() {
+        assert!(!Cli::parse_from(["secure-auth"]).accessible);
+        assert!(Cli::parse_from(["secure-auth", "--accessible"]).accessible);
+        assert!(Cli::parse_from(["secure-auth", "init", "--accessible"]).accessible);
+    }
+
+    #[test]
+    fn test_parse_init() {
+        assert_eq!(
+            Cli::parse_from(["secure-auth", "init"]).command,
+            Some(Command::Init)
+        );
+        assert_eq!(
+            Cli::parse_from(["secure-auth", "check"]).command,
+            Some(Command::Check)
+        );
+    }
+
+    #[test]
+    fn test_parse_verify_audit() {
+        assert_eq!(
+            Cli::parse_from(["secure-auth", "verify-audit"]).command,
+            Some(Command::VerifyAudit { expect: None })
+        );
+
+        let head = format!("42:{}", "ab".repeat(32));
+        assert_eq!(
+            Cli::parse_from(["secure-auth", "verify-audit", "--expect", &head]).command,
+            Some(Command::VerifyAudit {
+                expect: head.parse().ok()
+            })
+        );
+        assert!(Cli::try_parse_from(["secure-auth", "verify-audit", "--expect", "42"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_account_commands() {
+        assert_eq!(
+            Cli::parse_from(["secure-auth", "register", "email@email.test"]).command,
+            Some(Command::Register {
+                email: "email@email.test".to_string()
+            })
+        );
+        assert_eq!(
+            Cli::parse_from(["secure-auth", "2fa", "confirm", "email@email.test"]).command,
+            Some(Command::TwoFa {
+                command: TwoFaCommand::Confirm {
+                    email: "email@email.test".to_string()
+                }
+            })
+        );
+        assert_eq!(
+            Cli::parse_from([
+                "secure-auth",
+                "reset",
+                "complete",
+                "email@email.test",
+                "token"
+            ])
+            .command,
+            Some(Command::Reset {
+                command: ResetCommand::Complete {
+                    email: "email@email.test".to_string(),
+                    token: "token".to_string()
+                }
+            })
+        );
+        assert_eq!(
+            Cli::parse_from(["secure-auth", "db", "migrate"]).command,
+            Some(Command::Db {
+                command: DbCommand::Migrate
+            })
+        );
+    }
+
+    #[test]
+    fn test_parse_inactivity() {
+        assert_eq!(
+            Cli::parse_from(["secure-auth", "inactivity"]).command,
+            Some(Command::Inactivity { dry_run: false })
+        );
+        assert_eq!(
+            Cli::parse_from(["secure-auth", "inactivity", "--dry-run"]).command,
+            Some(Command::Inactivity { dry_run: true })
+        );
+    }
+
+    #[test]
+    fn test_parse_password_report() {
+        assert_eq!(
+            Cli::parse_from(["secure-auth", "password-report"]).command,
+            Some(Command::PasswordReport)
+        );
+    }
+
+    #[test]
+    fn test_parse_stats() {
+        assert_eq!(
+            Cli::parse_from(["secure-auth", "stats"]).command,
+            Some(Command::Stats { json: false })
+        );
+        assert_eq!(
+            Cli::parse_from(["secure-auth", "stats", "--json"]).command,
+            Some(Command::Stats { json: true })
+        );
+    }
+
+    #[test]
+    fn test_parse_hold() {
+        assert_eq!(
+            Cli::parse_from(["secure-auth", "hold", "list"]).command,
+            Some(Command::Hold {
+                command: HoldCommand::List
+            })
+        );
+        assert_eq!(
+            Cli::parse_from(["secure-auth", "hold", "release", "email@email.test"]).command,
+            Some(Command::Hold {
+                command: HoldCommand::Release {
+                    email: "email@email.test".to_string()
+                }
+            })
+        );
+        assert!(Cli::try_parse_from(["secure-auth", "hold", "release"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_policy_simulate() {
+        assert_eq!(
+            Cli::parse_from(["secure-auth", "policy", "simulate", "--scenario", "s.toml"]).command,
+            Some(Command::Policy {
+                command: PolicyCommand::Simulate {
+                    scenario: PathBuf::from("s.toml")
+                }
+            })
+        );
+        assert!(Cli::try_parse_from(["secure-auth", "policy", "simulate"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_not_me() {
+        assert_eq!(
+            Cli::parse_from(["secure-auth", "not-me", "token"]).command,
+            Some(Command::NotMe {
+                token: "token".to_string()
+            })
+        );
+        assert!(Cli::try_parse_from(["secure-auth", "not-me"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_verify_email_and_unlock() {
+        assert_eq!(
+            Cli::parse_from(["secure-auth", "verify-email", "token"]).command,
+            Some(Command::VerifyEmail {
+                token: "token".to_string()
+            })
+        );
+        assert_eq!(
+            Cli::parse_from(["secure-auth", "unlock", "token"]).command,
+            Some(Command::Unlock {
+                token: "token".to_string()
+            })
+        );
+        assert!(Cli::try_parse_from(["secure-auth", "unlock"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_service() {
+        assert_eq!(
+            Cli::parse_from(["secure-auth", "service", "rotate-key", "ci@email.test"]).command,
+            Some(Command::Service {
+                command: ServiceCommand::RotateKey {
+                    email: "ci@email.test".to_string()
+                }
+            })
+        );
+        assert_eq!(
+            Cli::parse_from([
+                "secure-auth",
+                "service",
+                "create",
+                "ci@email.test",
+                "--scope",
+                "deploy:write",
+                "--scope",
+                "logs:read"
+            ])
+            .command,
+            Some(Command::Service {
+                command: ServiceCommand::Create {
+                    email: "ci@email.test".to_string(),
+                    scopes: vec!["deploy:write".to_string(), "logs:read".to_string()]
+                }
+            })
+        );
+        assert!(Cli::try_parse_from([
+            "secure-auth",
+            "service",
+            "add-certificate",
+            "ci@email.test"
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn test_parse_recovery() {
+        assert_eq!(
+            Cli::parse_from(["secure-auth", "recovery", "start", "email@email.test"]).command,
+            Some(Command::Recovery {
+                command: RecoveryCommand::Start {
+                    email: "email@email.test".to_string()
+                }
+            })
+        );
+        assert_eq!(
+            Cli::parse_from(["secure-auth", "recovery", "approve", "token"]).command,
+            Some(Command::Recovery {
+                command: RecoveryCommand::Approve {
+                    token: "token".to_string()
+                }
+            })
+        );
+        assert_eq!(
+            Cli::parse_from(["secure-auth", "recovery", "confirm", "token"]).command,
+            Some(Command::Recovery {
+                command: RecoveryCommand::Confirm {
+                    token: "token".to_string()
+                }
+            })
+        );
+        assert_eq!(
+            Cli::parse_from(["secure-auth", "recovery", "take-over", "token"]).command,
+            Some(Command::Recovery {
+                command: RecoveryCommand::TakeOver {
+                    token: "token".to_string()
+                }
+            })
+        );
+        assert!(Cli::try_parse_from(["secure-auth", "recovery", "complete"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_pseudonym() {
+        assert_eq!(
+            Cli::parse_from(["secure-auth", "pseudonym", "show", "email@email.test"]).command,
+            Some(Command::Pseudonym {
+                command: PseudonymCommand::Show {
+                    email: "email@email.test".to_string()
+                }
+            })
+        );
+        assert_eq!(
+            Cli::parse_from(["secure-auth", "pseudonym", "resolve", "psn_00"]).command,
+            Some(Command::Pseudonym {
+                command: PseudonymCommand::Resolve {
+                    pseudonym: "psn_00".to_string()
+                }
+            })
+        );
+    }
+
+    #[test]
+    fn test_parse_erase() {
+        assert_eq!(
+            Cli::parse_from(["secure-auth", "erase", "email@email.test"]).command,
+            Some(Command::Erase {
+                email: "email@email.test".to_string(),
+                dry_run: false
+            })
+        );
+        assert_eq!(
+            Cli::parse_from(["secure-auth", "erase", "email@email.test", "--dry-run"]).command,
+            Some(Command::Erase {
+                email: "email@email.test".to_string(),
+                dry_run: true
+            })
+        );
+    }
+
+    #[test]
+    fn test_parse_db_seed() {
+        assert_eq!(
+            Cli::parse_from(["secure-auth", "db", "seed"]).command,
+            Some(Command::Db {
+                command: DbCommand::Seed {
+                    profile: Profile::Demo
+                }
+            })
+        );
+        assert!(Cli::try_parse_from(["secure-auth", "db", "seed", "--profile", "prod"]).is_err());
+    }
+}
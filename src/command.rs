@@ -49,7 +49,61 @@ pub enum ProfileScreenCmd {
     )]
     Disable2FA,
 
-    #[strum(serialize = "Logout", serialize = "logout", serialize = "3")]
+    #[strum(
+        serialize = "Phrase",
+        serialize = "phrase",
+        serialize = "Set anti-phishing phrase",
+        serialize = "set anti-phishing phrase",
+        serialize = "3"
+    )]
+    SetAntiPhishingPhrase,
+
+    #[strum(
+        serialize = "Preferences",
+        serialize = "preferences",
+        serialize = "Set language & timezone",
+        serialize = "set language & timezone",
+        serialize = "4"
+    )]
+    SetPreferences,
+
+    #[strum(
+        serialize = "Rotate",
+        serialize = "rotate",
+        serialize = "Change two factor authentication secret",
+        serialize = "change two factor authentication secret",
+        serialize = "5"
+    )]
+    Rotate2FA,
+
+    #[strum(
+        serialize = "Status",
+        serialize = "status",
+        serialize = "Security status",
+        serialize = "security status",
+        serialize = "6"
+    )]
+    SecurityStatus,
+
+    #[strum(
+        serialize = "Contacts",
+        serialize = "contacts",
+        serialize = "Trusted contacts",
+        serialize = "trusted contacts",
+        serialize = "7"
+    )]
+    TrustedContacts,
+
+    #[strum(
+        serialize = "WhoAmI",
+        serialize = "whoami",
+        serialize = "Who am I",
+        serialize = "who am i",
+        serialize = "8"
+    )]
+    WhoAmI,
+
+    #[strum(serialize = "Logout", serialize = "logout", serialize = "9")]
     Logout,
 }
 
@@ -57,7 +111,6 @@ pub enum ProfileScreenCmd {
 mod test {
     use rstest::rstest;
     use std::str::FromStr;
-    use strum;
 
     use super::*;
 
@@ -102,11 +155,49 @@ mod test {
         case("Disable two factor authentication", Ok(ProfileScreenCmd::Disable2FA)),
         case("disable two factor authentication", Ok(ProfileScreenCmd::Disable2FA)),
         case("2", Ok(ProfileScreenCmd::Disable2FA)),
+        case("Phrase", Ok(ProfileScreenCmd::SetAntiPhishingPhrase)),
+        case("phrase", Ok(ProfileScreenCmd::SetAntiPhishingPhrase)),
+        case(
+            "Set anti-phishing phrase",
+            Ok(ProfileScreenCmd::SetAntiPhishingPhrase)
+        ),
+        case(
+            "set anti-phishing phrase",
+            Ok(ProfileScreenCmd::SetAntiPhishingPhrase)
+        ),
+        case("3", Ok(ProfileScreenCmd::SetAntiPhishingPhrase)),
+        case("Preferences", Ok(ProfileScreenCmd::SetPreferences)),
+        case("preferences", Ok(ProfileScreenCmd::SetPreferences)),
+        case("Set language & timezone", Ok(ProfileScreenCmd::SetPreferences)),
+        case("set language & timezone", Ok(ProfileScreenCmd::SetPreferences)),
+        case("4", Ok(ProfileScreenCmd::SetPreferences)),
+        case("Rotate", Ok(ProfileScreenCmd::Rotate2FA)),
+        case("rotate", Ok(ProfileScreenCmd::Rotate2FA)),
+        case(
+            "Change two factor authentication secret",
+            Ok(ProfileScreenCmd::Rotate2FA)
+        ),
+        case(
+            "change two factor authentication secret",
+            Ok(ProfileScreenCmd::Rotate2FA)
+        ),
+        case("5", Ok(ProfileScreenCmd::Rotate2FA)),
+        case("Status", Ok(ProfileScreenCmd::SecurityStatus)),
+        case("status", Ok(ProfileScreenCmd::SecurityStatus)),
+        case("Security status", Ok(ProfileScreenCmd::SecurityStatus)),
+        case("security status", Ok(ProfileScreenCmd::SecurityStatus)),
+        case("6", Ok(ProfileScreenCmd::SecurityStatus)),
+        case("Contacts", Ok(ProfileScreenCmd::TrustedContacts)),
+        case("trusted contacts", Ok(ProfileScreenCmd::TrustedContacts)),
+        case("7", Ok(ProfileScreenCmd::TrustedContacts)),
+        case("whoami", Ok(ProfileScreenCmd::WhoAmI)),
+        case("Who am I", Ok(ProfileScreenCmd::WhoAmI)),
+        case("8", Ok(ProfileScreenCmd::WhoAmI)),
         case("Logout", Ok(ProfileScreenCmd::Logout)),
         case("logout", Ok(ProfileScreenCmd::Logout)),
-        case("3", Ok(ProfileScreenCmd::Logout)),
+        case("9", Ok(ProfileScreenCmd::Logout)),
         case("UnknownCmd", Err(strum::ParseError::VariantNotFound)),
-        case("5", Err(strum::ParseError::VariantNotFound)),
+        case("10", Err(strum::ParseError::VariantNotFound)),
         ::trace
     )]
     fn test_user_profile_cmd_from_string(
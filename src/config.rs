@@ -0,0 +1,1124 @@
+/*!
+ * Configuration of the system
+ *
+ * The configuration is read from a TOML file whose path is set by the
+ * `AUTH_CONFIG` variable (in the environment or the `.env` file), `auth.toml`
+ * by default. Every missing value (or the whole file) falls back to its default.
+ * See `auth.toml.example` for all the available options.
+ *
+ * # Author
+ * Doran Kayoumi <doran.kayoumi@heig-vd.ch>
+ */
+
+use chrono::Weekday;
+use chrono_tz::Tz;
+use dotenv::dotenv;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use sodiumoxide::crypto::pwhash::argon2id13;
+use std::collections::BTreeMap;
+use std::time::Duration;
+use std::{env, fs};
+
+use crate::auth::binding::BindingStrictness;
+use crate::errors::ConfigError;
+use crate::i18n::Locale;
+
+const DEFAULT_PATH: &str = "auth.toml";
+
+#[derive(Deserialize, Serialize, Debug, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub database: DatabaseConfig,
+    pub cache: CacheConfig,
+    pub network: NetworkConfig,
+    pub hashing: HashingConfig,
+    pub locale: LocaleConfig,
+    pub risk: RiskConfig,
+    pub captcha: CaptchaConfig,
+    pub binding: BindingConfig,
+    pub availability: AvailabilityConfig,
+    pub timing: TimingConfig,
+    pub hold: HoldConfig,
+    pub contact_recovery: ContactRecoveryConfig,
+    pub access_hours: AccessHoursConfig,
+    pub inactivity: InactivityConfig,
+    pub registration: RegistrationConfig,
+    pub maintenance: MaintenanceConfig,
+    pub audit: AuditConfig,
+    pub jwt: JwtConfig,
+    pub banner: BannerConfig,
+    pub mail: MailConfig,
+    /// LDAP / Active Directory server of the directory logins (see `auth/ldap.rs`), none if not set
+    pub ldap: Option<LdapConfig>,
+    /// Encrypted file keeping the users instead of a database (see `db/repository/file.rs`),
+    /// none if not set
+    pub user_file: Option<UserFileConfig>,
+    /// Redis server keeping the state of the logins (see `db/ephemeral.rs`), in memory if not set
+    pub redis: Option<RedisConfig>,
+}
+
+/// SQLite tuning applied to every connection
+#[derive(Deserialize, Serialize, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+    pub journal_mode: JournalMode,
+    pub synchronous: Synchronous,
+    /// How long a connection waits for a lock before failing with "database is locked"
+    pub busy_timeout_ms: u32,
+    pub foreign_keys: bool,
+    /// How long an operation on the users may take before failing with a timeout (see `db/timeout.rs`), none if 0
+    pub operation_timeout_ms: u64,
+    /// Whether the stored password hashes & 2fa secrets are checked when the CLI starts (see `doctor::scan_credentials`)
+    pub startup_scan: bool,
+    /// The maximum number of users a search by email returns (see `UserRepository::search_users`)
+    pub search_limit: i64,
+}
+
+#[derive(Deserialize, Serialize, Debug, PartialEq, Clone, Copy, strum_macros::Display)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "UPPERCASE")]
+pub enum JournalMode {
+    Delete,
+    Truncate,
+    Persist,
+    Memory,
+    Wal,
+}
+
+#[derive(Deserialize, Serialize, Debug, PartialEq, Clone, Copy, strum_macros::Display)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "UPPERCASE")]
+pub enum Synchronous {
+    Off,
+    Normal,
+    Full,
+    Extra,
+}
+
+/// Cache in front of the user lookups (only used with the `cache` feature)
+#[derive(Deserialize, Serialize, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheConfig {
+    /// Maximum number of users kept in the cache
+    pub capacity: u64,
+    /// How long (in seconds) a user stays in the cache
+    pub ttl_secs: u64,
+}
+
+/// Outbound network integrations (see `network.rs`)
+#[derive(Deserialize, Serialize, Debug, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct NetworkConfig {
+    /// Disable every outbound integration, they fall back to their offline behaviour
+    pub offline: bool,
+}
+
+/// Cost of the argon2id password hashing (see `utils::calibrate_hashing`)
+/// Note: the cost is stored in each hash, changing it doesn't invalidate the existing ones
+#[derive(Deserialize, Serialize, Debug, PartialEq, Clone, Copy)]
+#[serde(default, deny_unknown_fields)]
+pub struct HashingConfig {
+    /// Number of passes over the memory
+    pub ops_limit: usize,
+    /// Memory used, in bytes
+    pub mem_limit: usize,
+}
+
+impl Default for HashingConfig {
+    fn default() -> Self {
+        Self {
+            ops_limit: argon2id13::OPSLIMIT_INTERACTIVE.0,
+            mem_limit: argon2id13::MEMLIMIT_INTERACTIVE.0,
+        }
+    }
+}
+
+impl HashingConfig {
+    /// Minimum number of passes accepted by libsodium
+    pub const MIN_OPS_LIMIT: usize = 1;
+    /// Minimum memory accepted by libsodium, in bytes
+    pub const MIN_MEM_LIMIT: usize = 8192;
+
+    /// Whether libsodium can hash with this cost
+    pub fn is_valid(&self) -> bool {
+        self.ops_limit >= Self::MIN_OPS_LIMIT && self.mem_limit >= Self::MIN_MEM_LIMIT
+    }
+}
+
+/// Locale & timezone used for the users who didn't set theirs (see `i18n.rs`)
+#[derive(Deserialize, Serialize, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct LocaleConfig {
+    pub locale: Locale,
+    pub timezone: Tz,
+}
+
+impl Default for LocaleConfig {
+    fn default() -> Self {
+        Self {
+            locale: Locale::En,
+            timezone: Tz::UTC,
+        }
+    }
+}
+
+/// Weights of the signals & thresholds of the risk-based authentication (see `auth/risk.rs`)
+#[derive(Deserialize, Serialize, Debug, PartialEq, Clone, Copy)]
+#[serde(default, deny_unknown_fields)]
+pub struct RiskConfig {
+    pub new_device_weight: u32,
+    /// Added for each failed attempt
+    pub failed_attempt_weight: u32,
+    pub geo_anomaly_weight: u32,
+    pub unusual_hour_weight: u32,
+    /// First unusual hour (0 to 23)
+    pub unusual_hours_start: u32,
+    /// First usual hour after the unusual ones, can be lower than the start to wrap around midnight
+    pub unusual_hours_end: u32,
+    /// Score from which a second factor is required
+    pub require_2fa_at: u32,
+    /// Score from which an email confirmation is required
+    pub require_email_confirmation_at: u32,
+    /// Score from which the login is blocked
+    pub block_at: u32,
+}
+
+impl Default for RiskConfig {
+    fn default() -> Self {
+        Self {
+            new_device_weight: 20,
+            failed_attempt_weight: 10,
+            geo_anomaly_weight: 40,
+            unusual_hour_weight: 10,
+            unusual_hours_start: 0,
+            unusual_hours_end: 6,
+            require_2fa_at: 20,
+            require_email_confirmation_at: 50,
+            block_at: 80,
+        }
+    }
+}
+
+/// Limits of the email availability checks (see `auth/availability.rs`)
+#[derive(Deserialize, Serialize, Debug, PartialEq, Clone, Copy)]
+#[serde(default, deny_unknown_fields)]
+pub struct AvailabilityConfig {
+    /// Checks allowed per source & per hour
+    pub checks_per_hour: u32,
+    /// Minimum duration of a check, so a taken email can't be told apart by the timing
+    pub min_duration_ms: u64,
+}
+
+impl Default for AvailabilityConfig {
+    fn default() -> Self {
+        Self {
+            checks_per_hour: 10,
+            min_duration_ms: 300,
+        }
+    }
+}
+
+/// Minimum response time of the login, reset & 2FA verification (see `auth/timing.rs`)
+#[derive(Deserialize, Serialize, Debug, PartialEq, Clone, Copy, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct TimingConfig {
+    /// Floor of the response time in milliseconds, 0 to disable it
+    pub min_response_ms: u64,
+}
+
+impl TimingConfig {
+    pub fn min_response(&self) -> Duration {
+        Duration::from_millis(self.min_response_ms)
+    }
+}
+
+/// Thresholds of failed logins from which a CAPTCHA is required (see `auth/throttle.rs`)
+#[derive(Deserialize, Serialize, Debug, PartialEq, Clone, Copy)]
+#[serde(default, deny_unknown_fields)]
+pub struct CaptchaConfig {
+    /// Failed logins of the same account
+    pub after_account_failures: u32,
+    /// Failed logins from the same source, whatever the account
+    pub after_source_failures: u32,
+}
+
+impl Default for CaptchaConfig {
+    fn default() -> Self {
+        Self {
+            after_account_failures: 3,
+            after_source_failures: 10,
+        }
+    }
+}
+
+/// Binding of the 2fa challenges to the client which started the login (see `auth/binding.rs`)
+#[derive(Deserialize, Serialize, Debug, PartialEq, Clone, Copy, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct BindingConfig {
+    pub strictness: BindingStrictness,
+}
+
+/// When an account is placed on security hold (see `auth/hold.rs`)
+/// Unlike the CAPTCHA, a hold only ends with an admin review or a recovery of the account
+#[derive(Deserialize, Serialize, Debug, PartialEq, Clone, Copy)]
+#[serde(default, deny_unknown_fields)]
+pub struct HoldConfig {
+    /// Place the account on hold when the risk-based policy blocks a login made with the right password
+    pub on_risk_block: bool,
+    /// Place the account on hold when the right password comes after this many failed logins
+    /// of the account, 0 disables it
+    pub after_account_failures: u32,
+    /// Place the account on hold when the user follows the "this wasn't me" link of an alert
+    pub on_not_me: bool,
+}
+
+impl Default for HoldConfig {
+    fn default() -> Self {
+        Self {
+            on_risk_block: false,
+            after_account_failures: 0,
+            on_not_me: true,
+        }
+    }
+}
+
+/// Recovery of the accounts through their trusted contacts (see `auth/contacts.rs`)
+#[derive(Deserialize, Serialize, Debug, PartialEq, Clone, Copy)]
+#[serde(default, deny_unknown_fields)]
+pub struct ContactRecoveryConfig {
+    /// Time (in hours) the owner of the account has to cancel a recovery, even once approved
+    pub waiting_period_hours: i64,
+    /// Time (in hours) after which a recovery that wasn't completed expires
+    pub validity_hours: i64,
+}
+
+impl Default for ContactRecoveryConfig {
+    fn default() -> Self {
+        Self {
+            waiting_period_hours: 72,
+            validity_hours: 168,
+        }
+    }
+}
+
+/// Hours in which the users are allowed to login (see `auth/schedule.rs`)
+/// The users without any window can login at any time
+#[derive(Deserialize, Serialize, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct AccessHoursConfig {
+    /// Timezone in which the hours of the windows are given
+    pub timezone: Tz,
+    /// Windows of the users with a given role (their `role` attribute)
+    pub roles: BTreeMap<String, Vec<AccessWindow>>,
+    /// Windows of given users (by email), they replace the ones of their role
+    pub users: BTreeMap<String, Vec<AccessWindow>>,
+}
+
+impl Default for AccessHoursConfig {
+    fn default() -> Self {
+        Self {
+            timezone: Tz::UTC,
+            roles: BTreeMap::new(),
+            users: BTreeMap::new(),
+        }
+    }
+}
+
+/// Hours of some days of the week in which a login is allowed
+#[derive(Deserialize, Serialize, Debug, PartialEq, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct AccessWindow {
+    /// Days of the week (e.g. `Mon`), every day if empty
+    #[serde(default)]
+    pub days: Vec<Weekday>,
+    /// First allowed hour (0 to 23)
+    pub start_hour: u32,
+    /// First hour after the allowed ones (0 to 24), can be lower than the start to wrap
+    /// around midnight
+    pub end_hour: u32,
+}
+
+impl AccessWindow {
+    /// Whether the hours make sense: the start is an hour of the day, the end one
+    /// too (or 24 for midnight), and they differ since the window would allow nothing
+    pub fn is_valid(&self) -> bool {
+        self.start_hour < 24 && self.end_hour <= 24 && self.start_hour != self.end_hour
+    }
+}
+
+impl AccessHoursConfig {
+    /// Whether the windows of every role & user are valid (see `AccessWindow::is_valid`)
+    pub fn is_valid(&self) -> bool {
+        self.roles
+            .values()
+            .chain(self.users.values())
+            .flatten()
+            .all(AccessWindow::is_valid)
+    }
+}
+
+/// Accounts disabled after a long inactivity (see `auth/inactivity.rs`)
+#[derive(Deserialize, Serialize, Debug, PartialEq, Clone, Copy)]
+#[serde(default, deny_unknown_fields)]
+pub struct InactivityConfig {
+    /// Days without any login after which a user is warned, 0 disables it
+    pub after_days: u32,
+    /// Days a warned user has to login before her/his account is disabled
+    pub grace_days: u32,
+}
+
+impl Default for InactivityConfig {
+    fn default() -> Self {
+        Self {
+            after_days: 0,
+            grace_days: 30,
+        }
+    }
+}
+
+/// Emails no one can register (see `auth/validator.rs`)
+#[derive(Deserialize, Serialize, Debug, PartialEq, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct RegistrationConfig {
+    /// Patterns of the reserved emails, `*` matches anything (e.g. `admin@*.example.com`)
+    pub reserved: Vec<String>,
+}
+
+/// Read-only mode, e.g. during a migration or a backup (see `auth/maintenance.rs`)
+#[derive(Deserialize, Serialize, Debug, PartialEq, Clone, Copy, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct MaintenanceConfig {
+    /// The logins & the credential checks continue, the changes (registrations, resets,
+    /// profiles...) are refused with `AuthError::MaintenanceMode`
+    pub read_only: bool,
+}
+
+/// Tamper-evident audit log (see `audit/chain.rs`)
+#[derive(Deserialize, Serialize, Debug, PartialEq, Clone, Copy)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuditConfig {
+    /// Chain every new entry to the previous one by its hash, turning it off afterwards
+    /// breaks the chain
+    pub tamper_evident: bool,
+    /// Entries between two signed checkpoints, 0 disables them
+    pub checkpoint_every: u32,
+}
+
+impl Default for AuditConfig {
+    fn default() -> Self {
+        Self {
+            tamper_evident: false,
+            checkpoint_every: 100,
+        }
+    }
+}
+
+/// Tokens issued after a login (see `auth/jwt.rs`)
+/// Note: the signing key is read from `JWT_SECRET`, never from the configuration
+#[derive(Deserialize, Serialize, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct JwtConfig {
+    /// How long a token is valid, in seconds
+    pub ttl_secs: i64,
+    /// Issuer of the tokens (`iss`), the tokens of another issuer are refused
+    pub issuer: String,
+}
+
+impl Default for JwtConfig {
+    fn default() -> Self {
+        Self {
+            ttl_secs: 900,
+            issuer: "secure-auth".to_string(),
+        }
+    }
+}
+
+/// Messages shown around the logins (see `auth/banner.rs`)
+#[derive(Deserialize, Serialize, Debug, PartialEq, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct BannerConfig {
+    /// Shown before the logins, e.g. the legal notice of the system, none if not set
+    pub pre_login: Option<String>,
+    /// Message of the day, shown once logged in (security notices, planned maintenance...),
+    /// none if not set
+    pub motd: Option<String>,
+}
+
+/// LDAP / Active Directory server checking the passwords of the directory accounts
+#[derive(Deserialize, Serialize, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct LdapConfig {
+    /// e.g. `ldaps://ldap.example.com`, the certificate is always verified
+    pub url: String,
+    /// DN the users bind with, `{email}` is replaced by the email of the user & `{user}`
+    /// by the part before the `@`, e.g. `uid={user},ou=people,dc=example,dc=com`
+    /// (`{email}` as is binds with the user principal name on Active Directory)
+    pub bind_dn: String,
+    /// Upgrade a plain `ldap://` connection to TLS, the upgrade is required
+    pub starttls: bool,
+    pub timeout_secs: u64,
+}
+
+impl Default for LdapConfig {
+    fn default() -> Self {
+        Self {
+            url: "ldaps://localhost".to_string(),
+            bind_dn: "{email}".to_string(),
+            starttls: false,
+            timeout_secs: 5,
+        }
+    }
+}
+
+/// Encrypted file of the users, for the small tools & the demos
+/// Note: the key is read from `USER_FILE_SECRET`, never from the configuration
+#[derive(Deserialize, Serialize, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct UserFileConfig {
+    pub path: String,
+}
+
+impl Default for UserFileConfig {
+    fn default() -> Self {
+        Self {
+            path: "users.json.enc".to_string(),
+        }
+    }
+}
+
+/// Redis server shared by the instances (only used with the `redis` feature)
+#[derive(Deserialize, Serialize, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct RedisConfig {
+    /// e.g. `redis://127.0.0.1/`, `rediss://` for TLS
+    pub url: String,
+    /// Prefix of the keys, so the server can be shared with other applications
+    pub prefix: String,
+}
+
+impl Default for RedisConfig {
+    fn default() -> Self {
+        Self {
+            url: "redis://127.0.0.1/".to_string(),
+            prefix: "secure-auth:".to_string(),
+        }
+    }
+}
+
+/// Customization of the emails sent by the system (see `mail/templates.rs`)
+#[derive(Deserialize, Serialize, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct MailConfig {
+    /// Address the emails are sent from
+    pub sender: String,
+    /// Identical security alerts sent within this window (in seconds) are collapsed into one
+    /// (see `mail/dedupe.rs`), 0 disables it
+    pub dedupe_window_secs: u64,
+    /// Variables usable in the emails as `{name}` (e.g. `product_name`, `support_url`, `logo_url`)
+    pub variables: BTreeMap<String, String>,
+    pub subjects: SubjectsConfig,
+    /// SMTP server sending the emails, they're printed in the terminal if it isn't set
+    pub smtp: Option<SmtpConfig>,
+    /// HTTP API of a provider sending the emails, used if no SMTP server is set
+    pub api: Option<MailApiConfig>,
+}
+
+impl Default for MailConfig {
+    fn default() -> Self {
+        Self {
+            sender: "lab02.auth@heig-vd.lo".to_string(),
+            dedupe_window_secs: 60,
+            variables: BTreeMap::new(),
+            subjects: SubjectsConfig::default(),
+            smtp: None,
+            api: None,
+        }
+    }
+}
+
+/// Providers whose HTTP API can send the emails, each one is behind its own feature
+#[derive(Deserialize, Serialize, Debug, PartialEq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum MailApiProvider {
+    SendGrid,
+    Mailgun,
+    Ses,
+}
+
+/// HTTP API sending the emails (see `mail/api.rs`)
+/// Note: the credentials are read from `MAIL_API_KEY` & `MAIL_API_SECRET`
+#[derive(Deserialize, Serialize, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct MailApiConfig {
+    pub provider: MailApiProvider,
+    /// Sending domain (Mailgun only)
+    pub domain: Option<String>,
+    /// Region of the provider (`eu` for Mailgun, e.g. `eu-west-1` for SES)
+    pub region: Option<String>,
+    #[serde(default = "default_api_timeout_secs")]
+    pub timeout_secs: u64,
+}
+
+fn default_api_timeout_secs() -> u64 {
+    10
+}
+
+/// How the connection to the SMTP server is secured, the certificate is always verified
+#[derive(Deserialize, Serialize, Debug, PartialEq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpSecurity {
+    /// TLS from the start (port 465 by default)
+    Tls,
+    /// Plain connection upgraded to TLS, the upgrade is required (port 587 by default)
+    StartTls,
+}
+
+/// SMTP server sending the emails (see `mail/smtp.rs`)
+/// Note: the password is read from `SMTP_PASSWORD`, so it isn't stored in the configuration
+#[derive(Deserialize, Serialize, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct SmtpConfig {
+    pub host: String,
+    /// Port of the server, the default one of the security used if not set
+    pub port: Option<u16>,
+    pub security: SmtpSecurity,
+    pub username: Option<String>,
+    /// Maximum number of connections kept open
+    pub pool_size: u32,
+    pub timeout_secs: u64,
+    /// How many times a transient failure is retried
+    pub max_retries: u32,
+    /// Delay (in ms) before the first retry, it doubles with each retry
+    pub backoff_ms: u64,
+}
+
+impl Default for SmtpConfig {
+    fn default() -> Self {
+        Self {
+            host: "localhost".to_string(),
+            port: None,
+            security: SmtpSecurity::StartTls,
+            username: None,
+            pool_size: 4,
+            timeout_secs: 10,
+            max_retries: 3,
+            backoff_ms: 200,
+        }
+    }
+}
+
+/// Subjects replacing the default ones, per type of email
+#[derive(Deserialize, Serialize, Debug, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct SubjectsConfig {
+    pub reset_token: Option<String>,
+    pub password_changed: Option<String>,
+    pub contact_approval: Option<String>,
+    pub contact_confirmation: Option<String>,
+    pub recovery_started: Option<String>,
+    pub inactivity_warning: Option<String>,
+    pub login_confirmation: Option<String>,
+    pub verify_email: Option<String>,
+    pub unlock_account: Option<String>,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            capacity: 10_000,
+            ttl_secs: 60,
+        }
+    }
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        Self {
+            journal_mode: JournalMode::Wal,
+            synchronous: Synchronous::Normal,
+            busy_timeout_ms: 5000,
+            foreign_keys: true,
+            operation_timeout_ms: 10000,
+            startup_scan: true,
+            search_limit: 50,
+        }
+    }
+}
+
+impl DatabaseConfig {
+    /// The PRAGMA statements to run on a newly opened connection
+    pub fn pragmas(&self) -> Vec<String> {
+        vec![
+            format!("PRAGMA journal_mode = {}", self.journal_mode),
+            format!("PRAGMA synchronous = {}", self.synchronous),
+            format!("PRAGMA busy_timeout = {}", self.busy_timeout_ms),
+            format!(
+                "PRAGMA foreign_keys = {}",
+                if self.foreign_keys { "ON" } else { "OFF" }
+            ),
+        ]
+    }
+}
+
+impl Config {
+    /// Parse a configuration from its TOML representation
+    ///
+    /// # Arguments
+    ///
+    /// * `s` - content of the configuration file
+    ///
+    pub fn from_toml(s: &str) -> Result<Self, ConfigError> {
+        let config: Self = toml::from_str(s).map_err(|_| ConfigError::ParseError)?;
+
+        // Note: checked here, so the hashing can't fail later on
+        if !config.hashing.is_valid() {
+            return Err(ConfigError::InvalidHashingCost);
+        }
+        // Note: a window allowing nothing would lock its users out without a word
+        if !config.access_hours.is_valid() {
+            return Err(ConfigError::InvalidAccessWindow);
+        }
+        // Note: SQLite reads a negative limit as no limit at all
+        if config.database.search_limit < 0 {
+            return Err(ConfigError::InvalidSearchLimit);
+        }
+
+        Ok(config)
+    }
+
+    /// Get the TOML representation of the configuration
+    pub fn to_toml(&self) -> Result<String, ConfigError> {
+        toml::to_string(self).map_err(|_| ConfigError::SerializeError)
+    }
+
+    /// Load the configuration from the file set by `AUTH_CONFIG`
+    /// if the file doesn't exist, the default configuration is used
+    pub fn load() -> Result<Self, ConfigError> {
+        match fs::read_to_string(path()) {
+            Ok(s) => Self::from_toml(&s),
+            Err(_) => Ok(Self::default()),
+        }
+    }
+}
+
+/// Get the path of the configuration file, set by `AUTH_CONFIG`
+pub fn path() -> String {
+    dotenv().ok();
+
+    env::var("AUTH_CONFIG").unwrap_or_else(|_| DEFAULT_PATH.to_string())
+}
+
+lazy_static! {
+    static ref CONFIG: Result<Config, ConfigError> = Config::load();
+}
+
+/// Get the configuration of the system, or why the configuration file is invalid
+/// It's loaded once, the first time it's needed
+/// Note: the applications call it at startup, so an invalid file is reported
+///       before any other function of the library runs (see `get`)
+pub fn try_get() -> Result<&'static Config, ConfigError> {
+    CONFIG.as_ref().map_err(|e| *e)
+}
+
+/// Get the configuration of the system
+/// It's loaded once, the first time it's needed
+///
+/// # Panics
+/// If the configuration file exists but is invalid, there's no sane way to continue.
+/// Check it with `try_get` at startup to get a `ConfigError` instead.
+pub fn get() -> &'static Config {
+    try_get().unwrap_or_else(|e| panic!("{}", e))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_empty_config_uses_defaults() {
+        assert_eq!(Config::from_toml(""), Ok(Config::default()));
+    }
+
+    #[test]
+    fn test_partial_database_config() {
+        let config = Config::from_toml(
+            r#"
+            [database]
+            journal_mode = "delete"
+            busy_timeout_ms = 100
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.database.journal_mode, JournalMode::Delete);
+        assert_eq!(config.database.busy_timeout_ms, 100);
+        assert_eq!(config.database.synchronous, Synchronous::Normal);
+        assert!(config.database.foreign_keys);
+        assert!(!config.network.offline);
+    }
+
+    #[test]
+    fn test_toml_round_trip() {
+        let mut config = Config::default();
+        config.network.offline = true;
+        config.hashing.ops_limit = 42;
+
+        assert_eq!(Config::from_toml(&config.to_toml().unwrap()), Ok(config));
+    }
+
+    #[test]
+    fn test_locale_config() {
+        let config =
+            Config::from_toml("[locale]\nlocale = \"fr\"\ntimezone = \"Europe/Zurich\"").unwrap();
+
+        assert_eq!(config.locale.locale, Locale::Fr);
+        assert_eq!(config.locale.timezone, Tz::Europe__Zurich);
+        assert_eq!(
+            Config::from_toml("[locale]\ntimezone = \"Mars/Olympus\""),
+            Err(ConfigError::ParseError)
+        );
+    }
+
+    #[test]
+    fn test_risk_config() {
+        let config = Config::from_toml("[risk]\nblock_at = 200").unwrap();
+
+        assert_eq!(config.risk.block_at, 200);
+        assert_eq!(
+            config.risk.require_2fa_at,
+            RiskConfig::default().require_2fa_at
+        );
+    }
+
+    #[test]
+    fn test_hold_config() {
+        let config = Config::from_toml("[hold]\non_risk_block = true").unwrap();
+
+        assert!(config.hold.on_risk_block);
+        assert_eq!(
+            config.hold.after_account_failures,
+            HoldConfig::default().after_account_failures
+        );
+    }
+
+    #[test]
+    fn test_contact_recovery_config() {
+        let config = Config::from_toml("[contact_recovery]\nwaiting_period_hours = 24").unwrap();
+
+        assert_eq!(config.contact_recovery.waiting_period_hours, 24);
+        assert_eq!(
+            config.contact_recovery.validity_hours,
+            ContactRecoveryConfig::default().validity_hours
+        );
+    }
+
+    #[test]
+    fn test_access_hours_config() {
+        let config = Config::from_toml(
+            r#"
+            [access_hours]
+            timezone = "Europe/Zurich"
+
+            [access_hours.roles]
+            contractor = [{ days = ["Mon", "Tue", "Wed", "Thu", "Fri"], start_hour = 8, end_hour = 18 }]
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.access_hours.timezone, Tz::Europe__Zurich);
+        assert_eq!(
+            config.access_hours.roles["contractor"],
+            vec![AccessWindow {
+                days: vec![
+                    Weekday::Mon,
+                    Weekday::Tue,
+                    Weekday::Wed,
+                    Weekday::Thu,
+                    Weekday::Fri
+                ],
+                start_hour: 8,
+                end_hour: 18,
+            }]
+        );
+        assert!(config.access_hours.users.is_empty());
+    }
+
+    #[test]
+    fn test_invalid_access_windows() {
+        let window = |start: u32, end: u32| {
+            format!(
+                "[access_hours.users]\n\"a@email.test\" = [{{ start_hour = {}, end_hour = {} }}]",
+                start, end
+            )
+        };
+
+        assert!(Config::from_toml(&window(22, 6)).is_ok());
+        assert!(Config::from_toml(&window(0, 24)).is_ok());
+        for (start, end) in &[(8, 8), (8, 25), (24, 6), (30, 40)] {
+            assert_eq!(
+                Config::from_toml(&window(*start, *end)),
+                Err(ConfigError::InvalidAccessWindow)
+            );
+        }
+    }
+
+    #[test]
+    fn test_invalid_search_limit() {
+        let config = Config::from_toml("[database]\nsearch_limit = 10").unwrap();
+        assert_eq!(config.database.search_limit, 10);
+        assert!(Config::from_toml("[database]\nsearch_limit = 0").is_ok());
+
+        assert_eq!(
+            Config::from_toml("[database]\nsearch_limit = -1"),
+            Err(ConfigError::InvalidSearchLimit)
+        );
+    }
+
+    #[test]
+    fn test_binding_config() {
+        assert_eq!(Config::default().binding.strictness, BindingStrictness::Off);
+
+        let config = Config::from_toml("[binding]\nstrictness = \"lenient\"").unwrap();
+        assert_eq!(config.binding.strictness, BindingStrictness::Lenient);
+        assert_eq!(
+            Config::from_toml("[binding]\nstrictness = \"paranoid\""),
+            Err(ConfigError::ParseError)
+        );
+    }
+
+    #[test]
+    fn test_timing_config() {
+        assert_eq!(
+            Config::default().timing.min_response(),
+            Duration::from_millis(0)
+        );
+
+        let config = Config::from_toml("[timing]\nmin_response_ms = 300").unwrap();
+        assert_eq!(config.timing.min_response(), Duration::from_millis(300));
+    }
+
+    #[test]
+    fn test_availability_config() {
+        let config = Config::from_toml("[availability]\nchecks_per_hour = 3").unwrap();
+
+        assert_eq!(config.availability.checks_per_hour, 3);
+        assert_eq!(
+            config.availability.min_duration_ms,
+            AvailabilityConfig::default().min_duration_ms
+        );
+    }
+
+    #[test]
+    fn test_registration_config() {
+        assert!(Config::default().registration.reserved.is_empty());
+
+        let config =
+            Config::from_toml("[registration]\nreserved = [\"admin@*\", \"security@example.com\"]")
+                .unwrap();
+        assert_eq!(
+            config.registration.reserved,
+            vec!["admin@*".to_string(), "security@example.com".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_maintenance_config() {
+        assert!(!Config::default().maintenance.read_only);
+        assert!(
+            Config::from_toml("[maintenance]\nread_only = true")
+                .unwrap()
+                .maintenance
+                .read_only
+        );
+    }
+
+    #[test]
+    fn test_audit_config() {
+        assert_eq!(Config::default().audit, AuditConfig::default());
+
+        let config =
+            Config::from_toml("[audit]\ntamper_evident = true\ncheckpoint_every = 10").unwrap();
+        assert_eq!(
+            config.audit,
+            AuditConfig {
+                tamper_evident: true,
+                checkpoint_every: 10,
+            }
+        );
+    }
+
+    #[test]
+    fn test_inactivity_config() {
+        let config = Config::from_toml("[inactivity]\nafter_days = 180").unwrap();
+
+        assert_eq!(config.inactivity.after_days, 180);
+        assert_eq!(
+            config.inactivity.grace_days,
+            InactivityConfig::default().grace_days
+        );
+        assert_eq!(Config::default().inactivity.after_days, 0);
+    }
+
+    #[test]
+    fn test_mail_config() {
+        let config = Config::from_toml(
+            r#"
+            [mail.variables]
+            support_url = "https://support.example.com"
+
+            [mail.subjects]
+            reset_token = "{product_name}: your reset token"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            config.mail.variables.get("support_url").map(String::as_str),
+            Some("https://support.example.com")
+        );
+        assert!(config.mail.subjects.password_changed.is_none());
+        assert!(config.mail.smtp.is_none());
+        assert_eq!(config.mail.dedupe_window_secs, 60);
+
+        let config =
+            Config::from_toml("[mail.smtp]\nhost = \"smtp.example.com\"\nsecurity = \"tls\"")
+                .unwrap()
+                .mail
+                .smtp
+                .unwrap();
+        assert_eq!(config.host, "smtp.example.com");
+        assert_eq!(config.security, SmtpSecurity::Tls);
+        assert_eq!(config.max_retries, SmtpConfig::default().max_retries);
+
+        let config =
+            Config::from_toml("[mail.api]\nprovider = \"mailgun\"\ndomain = \"mg.example.com\"")
+                .unwrap()
+                .mail
+                .api
+                .unwrap();
+        assert_eq!(config.provider, MailApiProvider::Mailgun);
+        assert_eq!(config.timeout_secs, 10);
+        assert_eq!(
+            Config::from_toml("[mail.api]\nprovider = \"postmark\""),
+            Err(ConfigError::ParseError)
+        );
+        assert_eq!(
+            Config::from_toml("[mail.subjects]\nwelcome = \"Hi\""),
+            Err(ConfigError::ParseError)
+        );
+    }
+
+    #[test]
+    fn test_ldap_config() {
+        assert!(Config::default().ldap.is_none());
+
+        let config = Config::from_toml(
+            "[ldap]\nurl = \"ldap://ldap.example.com\"\nbind_dn = \"uid={user},dc=example,dc=com\"\nstarttls = true",
+        )
+        .unwrap()
+        .ldap
+        .unwrap();
+        assert_eq!(config.url, "ldap://ldap.example.com");
+        assert_eq!(config.bind_dn, "uid={user},dc=example,dc=com");
+        assert!(config.starttls);
+        assert_eq!(config.timeout_secs, LdapConfig::default().timeout_secs);
+    }
+
+    #[test]
+    fn test_jwt_config() {
+        let config = Config::from_toml("[jwt]\nttl_secs = 60").unwrap().jwt;
+
+        assert_eq!(config.ttl_secs, 60);
+        assert_eq!(config.issuer, JwtConfig::default().issuer);
+    }
+
+    #[test]
+    fn test_banner_config() {
+        assert_eq!(Config::default().banner, BannerConfig::default());
+
+        let config = Config::from_toml("[banner]\nmotd = \"Maintenance on Sunday\"")
+            .unwrap()
+            .banner;
+        assert_eq!(config.pre_login, None);
+        assert_eq!(config.motd, Some("Maintenance on Sunday".to_string()));
+    }
+
+    #[test]
+    fn test_user_file_config() {
+        assert!(Config::default().user_file.is_none());
+
+        let config = Config::from_toml("[user_file]").unwrap().user_file.unwrap();
+        assert_eq!(config, UserFileConfig::default());
+
+        let config = Config::from_toml("[user_file]\npath = \"/var/lib/tool/users\"")
+            .unwrap()
+            .user_file
+            .unwrap();
+        assert_eq!(config.path, "/var/lib/tool/users");
+    }
+
+    #[test]
+    fn test_redis_config() {
+        assert!(Config::default().redis.is_none());
+
+        let config = Config::from_toml("[redis]\nurl = \"redis://redis.internal:6379/1\"")
+            .unwrap()
+            .redis
+            .unwrap();
+        assert_eq!(config.url, "redis://redis.internal:6379/1");
+        assert_eq!(config.prefix, RedisConfig::default().prefix);
+    }
+
+    #[test]
+    fn test_hashing_config() {
+        let config = Config::from_toml("[hashing]\nops_limit = 1\nmem_limit = 8192").unwrap();
+        assert_eq!(config.hashing.ops_limit, 1);
+
+        assert_eq!(
+            Config::from_toml("[hashing]\nops_limit = 0"),
+            Err(ConfigError::InvalidHashingCost)
+        );
+        assert_eq!(
+            Config::from_toml("[hashing]\nmem_limit = 1024"),
+            Err(ConfigError::InvalidHashingCost)
+        );
+    }
+
+    #[test]
+    fn test_offline_config() {
+        let config = Config::from_toml("[network]\noffline = true").unwrap();
+
+        assert!(config.network.offline);
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        assert_eq!(
+            Config::from_toml("[database]\njournal_mode = \"nope\""),
+            Err(ConfigError::ParseError)
+        );
+        assert_eq!(
+            Config::from_toml("[database]\nunknown = 1"),
+            Err(ConfigError::ParseError)
+        );
+    }
+
+    #[test]
+    fn test_pragmas() {
+        let db = DatabaseConfig {
+            foreign_keys: false,
+            ..DatabaseConfig::default()
+        };
+
+        assert_eq!(
+            db.pragmas(),
+            vec![
+                "PRAGMA journal_mode = WAL",
+                "PRAGMA synchronous = NORMAL",
+                "PRAGMA busy_timeout = 5000",
+                "PRAGMA foreign_keys = OFF",
+            ]
+        );
+    }
+}
@@ -5,19 +5,116 @@
  * Doran Kayoumi <doran.kayoumi@heig-vd.ch>
  */
 
+// the library must not panic, the failures are returned as typed errors
+#![cfg_attr(
+    not(test),
+    deny(clippy::unwrap_used, clippy::expect_used, clippy::panic)
+)]
+
+#[cfg(feature = "cache")]
+pub mod cache;
+pub mod doctor;
+pub mod ephemeral;
+#[cfg(any(test, feature = "test-utils"))]
+pub mod flaky;
+pub mod migrations;
 pub mod models;
 pub mod repository;
 pub mod schema;
+pub mod seed;
+pub mod timeout;
 
 use diesel::prelude::*;
 use dotenv::dotenv;
 use std::env;
 
-/// Establish a connection to a SQLite database with the url set in a `.env` file
-fn establish_connection() -> SqliteConnection {
+use crate::config;
+use crate::errors::SetupError;
+
+/// Version of the latest migration, i.e. the schema the code expects
+/// Note: must be bumped along with every new migration
+pub const SCHEMA_VERSION: &str = "20261017110000";
+
+/// Get the url of the SQLite database set in a `.env` file
+/// Note: empty if it isn't set, the connections to it then fail
+pub fn database_url() -> String {
+    try_database_url().unwrap_or_default()
+}
+
+/// Get the url of the SQLite database set in a `.env` file, if it's set
+pub fn try_database_url() -> Option<String> {
     dotenv().ok();
 
-    let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
-    SqliteConnection::establish(&database_url)
-        .unwrap_or_else(|_| panic!("Error connecting to {}", database_url))
+    env::var("DATABASE_URL").ok()
+}
+
+/// Establish a connection to a SQLite database
+/// The connection is tuned with the pragmas set in the configuration (see `config.rs`)
+///
+/// # Arguments
+///
+/// * `database_url` - url of the database to connect to
+///
+fn establish_connection(database_url: &str) -> Result<SqliteConnection, SetupError> {
+    // Note: SQLite would open a temporary database for an empty url
+    if database_url.is_empty() {
+        return Err(SetupError::MissingDatabaseUrl);
+    }
+
+    let conn =
+        SqliteConnection::establish(database_url).map_err(|_| SetupError::ConnectionError)?;
+
+    // Note: SQLite only enforces foreign keys (and their ON DELETE actions) when asked to
+    for pragma in config::get().database.pragmas() {
+        conn.execute(&pragma)
+            .map_err(|_| SetupError::ConnectionError)?;
+    }
+
+    Ok(conn)
+}
+
+/// Apply the pending migrations to a database (which is created if needed)
+/// See `migrations::migrate` for more info
+///
+/// # Arguments
+///
+/// * `database_url` - url of the database to migrate
+///
+pub fn run_migrations(database_url: &str) -> Result<(), SetupError> {
+    migrations::migrate(database_url).map(|_| ())
+}
+
+/// Create an empty database with all the migrations applied
+/// The database is deleted once the returned directory is dropped
+#[cfg(test)]
+pub(crate) fn test_database() -> (tempfile::TempDir, String) {
+    let dir = tempfile::tempdir().unwrap();
+    let url = dir.path().join("test.db").to_str().unwrap().to_string();
+
+    run_migrations(&url).unwrap();
+
+    (dir, url)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_schema_version_is_the_latest_migration() {
+        let latest = fs::read_dir(concat!(env!("CARGO_MANIFEST_DIR"), "/migrations"))
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .map(|name| {
+                name.chars()
+                    .take_while(|c| *c != '_')
+                    .filter(char::is_ascii_digit)
+                    .collect::<String>()
+            })
+            .max()
+            .unwrap();
+
+        assert_eq!(latest, SCHEMA_VERSION);
+    }
 }
@@ -0,0 +1,254 @@
+/*!
+ * Cache in front of the user lookups
+ *
+ * Meant for workloads looking up the same users over and over (e.g. token
+ * validation in a server). Every mutation going through the cached repository
+ * invalidates the concerned user so the cache never serves stale data written
+ * by this process.
+ *
+ * # Note
+ * Changes made by other processes are only seen once the cached entry expires.
+ *
+ * # Author
+ * Doran Kayoumi <doran.kayoumi@heig-vd.ch>
+ */
+
+use moka::sync::Cache;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use super::models::{User, UserChangeset};
+use super::repository::{UserFilter, UserRepository};
+use crate::config::CacheConfig;
+use crate::errors::UserDBError;
+
+/// Number of lookups served by the cache or by the underlying repository
+#[derive(Debug, Default, PartialEq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+/// `UserRepository` caching the users retrieved by `get_user`
+pub struct CachedUserRepository<R: UserRepository> {
+    inner: R,
+    cache: Cache<String, User>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl<R: UserRepository> CachedUserRepository<R> {
+    /// # Arguments
+    ///
+    /// * `inner` - the repository used on a cache miss and for the mutations
+    ///
+    /// * `config` - size & ttl of the cache
+    ///
+    pub fn new(inner: R, config: &CacheConfig) -> Self {
+        let cache = Cache::builder()
+            .max_capacity(config.capacity)
+            .time_to_live(Duration::from_secs(config.ttl_secs))
+            .support_invalidation_closures()
+            .build();
+
+        Self {
+            inner,
+            cache,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Remove a user from the cache, whatever email she/he was cached under
+    fn invalidate(&self, user: i32) {
+        // Note: only fails if the cache wasn't built with invalidation closures,
+        //       dropping every entry is then the safe fallback
+        if self
+            .cache
+            .invalidate_entries_if(move |_, u| u.get_id() == user)
+            .is_err()
+        {
+            self.cache.invalidate_all();
+        }
+    }
+}
+
+impl<R: UserRepository> UserRepository for CachedUserRepository<R> {
+    fn get_user(&self, e: &str) -> Result<User, UserDBError> {
+        if let Some(u) = self.cache.get(e) {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(u);
+        }
+
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        let u = self.inner.get_user(e)?;
+        self.cache.insert(e.to_string(), u.clone());
+
+        Ok(u)
+    }
+
+    fn create_user(&self, e: &str, passwd: &str) -> Result<(), UserDBError> {
+        self.inner.create_user(e, passwd)
+    }
+
+    fn update_user(&self, u: &User) -> Result<(), UserDBError> {
+        // invalidate even if the update fails, the storage may have been partially updated
+        let res = self.inner.update_user(u);
+        self.invalidate(u.get_id());
+        self.cache.invalidate(&u.get_email());
+
+        res
+    }
+
+    fn patch_user(&self, user: i32, changes: &UserChangeset) -> Result<(), UserDBError> {
+        let res = self.inner.patch_user(user, changes);
+        self.invalidate(user);
+
+        res
+    }
+
+    fn update_user_atomic(
+        &self,
+        expected: &User,
+        changes: &UserChangeset,
+    ) -> Result<bool, UserDBError> {
+        let res = self.inner.update_user_atomic(expected, changes);
+        self.invalidate(expected.get_id());
+
+        res
+    }
+
+    fn delete_user(&self, user: i32) -> Result<(), UserDBError> {
+        let res = self.inner.delete_user(user);
+        self.invalidate(user);
+
+        res
+    }
+
+    fn update_many(
+        &self,
+        filter: &UserFilter,
+        changes: &UserChangeset,
+    ) -> Result<usize, UserDBError> {
+        // the updated users aren't known, so nothing cached can be trusted anymore
+        let res = self.inner.update_many(filter, changes);
+        self.cache.invalidate_all();
+
+        res
+    }
+
+    fn list_users(&self, filter: &UserFilter) -> Result<Vec<User>, UserDBError> {
+        self.inner.list_users(filter)
+    }
+
+    fn iter_users(
+        &self,
+        filter: &UserFilter,
+    ) -> Box<dyn Iterator<Item = Result<User, UserDBError>>> {
+        self.inner.iter_users(filter)
+    }
+
+    fn search_users(&self, query: &str, limit: i64) -> Result<Vec<User>, UserDBError> {
+        self.inner.search_users(query, limit)
+    }
+
+    fn get_attributes(&self, user: i32) -> Result<HashMap<String, String>, UserDBError> {
+        self.inner.get_attributes(user)
+    }
+
+    fn set_attribute(&self, user: i32, attr: &str, val: &str) -> Result<(), UserDBError> {
+        self.inner.set_attribute(user, attr, val)
+    }
+
+    fn remove_attribute(&self, user: i32, attr: &str) -> Result<(), UserDBError> {
+        self.inner.remove_attribute(user, attr)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::db::repository::MockSQliteUserRepository;
+
+    fn config() -> CacheConfig {
+        CacheConfig {
+            capacity: 10,
+            ttl_secs: 60,
+        }
+    }
+
+    #[test]
+    fn test_get_user_is_cached() {
+        let mut mock = MockSQliteUserRepository::new();
+
+        mock.expect_get_user()
+            .times(1)
+            .returning(|e| Ok(User::new(e, "passwd_hash")));
+
+        let repository = CachedUserRepository::new(mock, &config());
+
+        assert!(repository.get_user("email@email.test").is_ok());
+        assert!(repository.get_user("email@email.test").is_ok());
+        assert_eq!(repository.stats(), CacheStats { hits: 1, misses: 1 });
+    }
+
+    #[test]
+    fn test_unknown_users_are_not_cached() {
+        let mut mock = MockSQliteUserRepository::new();
+
+        mock.expect_get_user()
+            .times(2)
+            .returning(|_| Err(UserDBError::GetUserError));
+
+        let repository = CachedUserRepository::new(mock, &config());
+
+        assert!(repository.get_user("email@email.test").is_err());
+        assert!(repository.get_user("email@email.test").is_err());
+        assert_eq!(repository.stats(), CacheStats { hits: 0, misses: 2 });
+    }
+
+    #[test]
+    fn test_update_user_invalidates() {
+        let mut mock = MockSQliteUserRepository::new();
+
+        mock.expect_get_user()
+            .times(2)
+            .returning(|e| Ok(User::new(e, "passwd_hash")));
+        mock.expect_update_user().returning(|_| Ok(()));
+
+        let repository = CachedUserRepository::new(mock, &config());
+
+        let mut u = repository.get_user("email@email.test").unwrap();
+        u.set_password("new_hash");
+        repository.update_user(&u).unwrap();
+
+        assert!(repository.get_user("email@email.test").is_ok());
+        assert_eq!(repository.stats(), CacheStats { hits: 0, misses: 2 });
+    }
+
+    #[test]
+    fn test_delete_user_invalidates() {
+        let mut mock = MockSQliteUserRepository::new();
+
+        mock.expect_get_user()
+            .times(2)
+            .returning(|e| Ok(User::new(e, "passwd_hash")));
+        mock.expect_delete_user().returning(|_| Ok(()));
+
+        let repository = CachedUserRepository::new(mock, &config());
+
+        let u = repository.get_user("email@email.test").unwrap();
+        repository.delete_user(u.get_id()).unwrap();
+
+        assert!(repository.get_user("email@email.test").is_ok());
+        assert_eq!(repository.stats(), CacheStats { hits: 0, misses: 2 });
+    }
+}
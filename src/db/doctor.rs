@@ -0,0 +1,448 @@
+/*!
+ * Health checks of the database
+ *
+ * Looks for the problems that creep in over time (outdated schema, missing
+ * indexes, orphaned rows, corrupted password hashes & 2fa secrets) and
+ * explains how to fix them. The problems that can be fixed without losing
+ * meaningful data can also be repaired automatically.
+ *
+ * The findings never hold the emails of the users concerned, only their ids
+ * (see `Finding::users`), the CLI shows their pseudonyms instead.
+ *
+ * The stored password hashes & 2fa secrets can also be checked on their own
+ * (`scan_credentials`), e.g. when a service starts, so a corruption is found
+ * before the users fail to login. `health` sums the findings up for a health
+ * endpoint, with the number of users concerned only.
+ *
+ * # Author
+ * Doran Kayoumi <doran.kayoumi@heig-vd.ch>
+ */
+
+use diesel::prelude::*;
+use diesel::sql_query;
+use diesel::sql_types::BigInt;
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde::Serialize;
+
+use super::migrations::current_version;
+use super::schema::users::dsl::{id, password, pending_secret_2fa, secret_2fa, users};
+use super::{establish_connection, SCHEMA_VERSION};
+use crate::errors::DoctorError;
+use crate::portable::otp;
+
+/// A problem found in the database
+#[derive(PartialEq, Debug)]
+pub struct Finding {
+    /// What is wrong
+    pub problem: String,
+    /// How to fix it
+    pub fix: String,
+    /// Whether `repair` takes care of it
+    pub repairable: bool,
+    /// Ids of the users concerned, none if the problem isn't about some users
+    pub users: Vec<i32>,
+}
+
+impl Finding {
+    fn new(problem: String, fix: &str, repairable: bool) -> Self {
+        Self {
+            problem,
+            fix: fix.to_string(),
+            repairable,
+            users: vec![],
+        }
+    }
+
+    fn with_users(mut self, concerned: Vec<i32>) -> Self {
+        self.users = concerned;
+        self
+    }
+}
+
+#[derive(QueryableByName)]
+struct Count {
+    #[sql_type = "BigInt"]
+    count: i64,
+}
+
+/// Rows selected by a table and a condition
+type Rows = (&'static str, &'static str);
+
+/// Attributes of users that don't exist anymore
+const ORPHANED_ATTRIBUTES: Rows = ("user_attributes", "user_id not in (select id from users)");
+
+/// Audit entries referencing users that don't exist anymore
+const ORPHANED_AUDIT_ENTRIES: Rows = (
+    "audit_log",
+    "user_id is not null and user_id not in (select id from users)",
+);
+
+fn count(conn: &SqliteConnection, (table, condition): Rows) -> Result<i64, DoctorError> {
+    sql_query(format!(
+        "select count(*) as count from {} where {}",
+        table, condition
+    ))
+    .get_result::<Count>(conn)
+    .map(|c| c.count)
+    .map_err(|_| DoctorError::InspectionError)
+}
+
+/// Check if a string looks like a hash generated by `utils::hash`
+///
+/// # Arguments
+///
+/// * `h` - the hash to check
+///
+// the pattern is a constant, the tests make sure it compiles
+#[allow(clippy::unwrap_used)]
+fn is_password_hash_valid(h: &str) -> bool {
+    lazy_static! {
+        // Note: sodiumoxide pads the hash with NUL bytes up to its fixed size
+        static ref RE: Regex = Regex::new(
+            r"^\$argon2id\$v=19\$m=\d+,t=\d+,p=\d+\$[A-Za-z0-9+/]+\$[A-Za-z0-9+/]+\x00*$"
+        )
+        .unwrap();
+    }
+
+    RE.is_match(h)
+}
+
+/// Check if a 2fa secret can be used to compute the codes, i.e. it's base32 encoded
+///
+/// # Arguments
+///
+/// * `secret` - the secret to check
+///
+fn is_2fa_secret_valid(secret: &str) -> bool {
+    otp::decode_secret(secret).is_some_and(|key| !key.is_empty())
+}
+
+/// Problem of a health report, see `Finding`
+#[derive(PartialEq, Debug, Serialize)]
+pub struct HealthProblem {
+    pub problem: String,
+    pub fix: String,
+    /// Number of users concerned
+    pub users: usize,
+}
+
+/// State of the database, e.g. for the health endpoint of a service
+#[derive(PartialEq, Debug, Serialize)]
+pub struct Health {
+    /// Whether no problem was found
+    pub healthy: bool,
+    pub schema_version: Option<String>,
+    pub problems: Vec<HealthProblem>,
+}
+
+/// Get the version of the last migration applied to a database (see `SCHEMA_VERSION`)
+///
+/// # Arguments
+///
+/// * `database_url` - url of the database to check
+///
+pub fn schema_version(database_url: &str) -> Result<Option<String>, DoctorError> {
+    let conn = establish_connection(database_url).map_err(|_| DoctorError::InspectionError)?;
+
+    Ok(current_version(&conn))
+}
+
+/// Check the database and list every problem found
+///
+/// # Arguments
+///
+/// * `database_url` - url of the database to check
+///
+pub fn diagnose(database_url: &str) -> Result<Vec<Finding>, DoctorError> {
+    let conn = establish_connection(database_url).map_err(|_| DoctorError::InspectionError)?;
+    let mut findings = Vec::new();
+
+    let version = current_version(&conn);
+    if version.as_deref() != Some(SCHEMA_VERSION) {
+        findings.push(Finding::new(
+            format!(
+                "The schema is at version {}, {} is expected.",
+                version.as_deref().unwrap_or("<none>"),
+                SCHEMA_VERSION
+            ),
+            "Apply the pending migrations with `secure-auth db migrate`.",
+            false,
+        ));
+
+        // the other checks rely on tables & columns that may not exist yet
+        return Ok(findings);
+    }
+
+    // unique index on the emails
+    let email_indexes = count(
+        &conn,
+        (
+            "pragma_index_list('users') as l",
+            "l.\"unique\" = 1 and (select group_concat(name) from pragma_index_info(l.name)) = 'email'",
+        ),
+    )?;
+    if email_indexes == 0 {
+        findings.push(Finding::new(
+            "There's no unique index on the users' emails.".to_string(),
+            "Remove the duplicated accounts, then run the pending migrations.",
+            false,
+        ));
+    }
+
+    // orphaned rows
+    let n = count(&conn, ORPHANED_ATTRIBUTES)?;
+    if n > 0 {
+        findings.push(Finding::new(
+            format!("{} attribute(s) belong to deleted users.", n),
+            "Delete them.",
+            true,
+        ));
+    }
+    let n = count(&conn, ORPHANED_AUDIT_ENTRIES)?;
+    if n > 0 {
+        findings.push(Finding::new(
+            format!("{} audit log entries reference deleted users.", n),
+            "Detach them from the users.",
+            true,
+        ));
+    }
+
+    findings.extend(credential_findings(&conn)?);
+
+    Ok(findings)
+}
+
+/// Check the format of the stored password hashes & 2fa secrets only, e.g. when a service starts
+/// Note: nothing is checked if the schema isn't the expected one, see `diagnose`
+///
+/// # Arguments
+///
+/// * `database_url` - url of the database to check
+///
+pub fn scan_credentials(database_url: &str) -> Result<Vec<Finding>, DoctorError> {
+    let conn = establish_connection(database_url).map_err(|_| DoctorError::InspectionError)?;
+    if current_version(&conn).as_deref() != Some(SCHEMA_VERSION) {
+        return Ok(vec![]);
+    }
+
+    credential_findings(&conn)
+}
+
+/// Check the database & sum the problems up, without the ids of the users concerned
+///
+/// # Arguments
+///
+/// * `database_url` - url of the database to check
+///
+pub fn health(database_url: &str) -> Result<Health, DoctorError> {
+    let problems: Vec<HealthProblem> = diagnose(database_url)?
+        .into_iter()
+        .map(|f| HealthProblem {
+            problem: f.problem,
+            fix: f.fix,
+            users: f.users.len(),
+        })
+        .collect();
+
+    Ok(Health {
+        healthy: problems.is_empty(),
+        schema_version: schema_version(database_url)?,
+        problems,
+    })
+}
+
+/// Look for the password hashes & the 2fa secrets that can't be used
+///
+/// # Arguments
+///
+/// * `conn` - connection to the database to check
+///
+fn credential_findings(conn: &SqliteConnection) -> Result<Vec<Finding>, DoctorError> {
+    let mut findings = Vec::new();
+
+    // password hashes
+    let invalid: Vec<i32> = users
+        .select((id, password))
+        .load::<(i32, String)>(conn)
+        .map_err(|_| DoctorError::InspectionError)?
+        .into_iter()
+        .filter(|(_, h)| !is_password_hash_valid(h))
+        .map(|(i, _)| i)
+        .collect();
+    if !invalid.is_empty() {
+        findings.push(
+            Finding::new(
+                format!("{} user(s) have an invalid password hash.", invalid.len()),
+                "They won't be able to login, they need to reset their password.",
+                false,
+            )
+            .with_users(invalid),
+        );
+    }
+
+    // 2fa secrets, enabled or waiting for their confirmation
+    let invalid: Vec<i32> = users
+        .select((id, secret_2fa, pending_secret_2fa))
+        .load::<(i32, Option<String>, Option<String>)>(conn)
+        .map_err(|_| DoctorError::InspectionError)?
+        .into_iter()
+        .filter(|(_, s, p)| s.iter().chain(p).any(|s| !is_2fa_secret_valid(s)))
+        .map(|(i, _, _)| i)
+        .collect();
+    if !invalid.is_empty() {
+        findings.push(
+            Finding::new(
+                format!("{} user(s) have an invalid 2FA secret.", invalid.len()),
+                "Their codes can't be checked, they need to recover their account & enable the 2FA again.",
+                false,
+            )
+            .with_users(invalid),
+        );
+    }
+
+    Ok(findings)
+}
+
+/// Apply the safe repairs (see `Finding::repairable`)
+/// Returns the number of rows repaired, if something goes wrong, an error is returned
+/// and nothing is changed
+///
+/// # Arguments
+///
+/// * `database_url` - url of the database to repair
+///
+pub fn repair(database_url: &str) -> Result<usize, DoctorError> {
+    let conn = establish_connection(database_url).map_err(|_| DoctorError::RepairError)?;
+
+    conn.transaction::<_, diesel::result::Error, _>(|| {
+        let statements = [
+            format!(
+                "delete from {} where {}",
+                ORPHANED_ATTRIBUTES.0, ORPHANED_ATTRIBUTES.1
+            ),
+            format!(
+                "update {} set user_id = null where {}",
+                ORPHANED_AUDIT_ENTRIES.0, ORPHANED_AUDIT_ENTRIES.1
+            ),
+        ];
+
+        let mut repaired = 0;
+        for statement in &statements {
+            repaired += sql_query(statement).execute(&conn)?;
+        }
+
+        Ok(repaired)
+    })
+    .map_err(|_| DoctorError::RepairError)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::db::test_database;
+
+    const HASH: &str =
+        "$argon2id$v=19$m=65536,t=2,p=1$c2FsdHNhbHRzYWx0$aGFzaGhhc2hoYXNoaGFzaA\0\0\0";
+
+    #[test]
+    fn test_password_hash_format() {
+        assert!(is_password_hash_valid(HASH));
+        assert!(is_password_hash_valid(HASH.trim_end_matches('\0')));
+        assert!(!is_password_hash_valid("passwd_hash"));
+        assert!(!is_password_hash_valid(
+            &HASH.replace("argon2id", "argon2i")
+        ));
+    }
+
+    #[test]
+    fn test_2fa_secret_format() {
+        assert!(is_2fa_secret_valid("I3VFM3JKMNDJCDH5BMBEEQAW6KJ6NOE3"));
+        assert!(is_2fa_secret_valid("i3vfm3jkmndjcdh5"));
+        assert!(!is_2fa_secret_valid("not base32!"));
+        assert!(!is_2fa_secret_valid(""));
+    }
+
+    #[test]
+    fn test_healthy_database() {
+        let (_dir, url) = test_database();
+
+        assert_eq!(diagnose(&url), Ok(vec![]));
+    }
+
+    #[test]
+    fn test_outdated_schema() {
+        let dir = tempfile::tempdir().unwrap();
+        let url = dir.path().join("empty.db").to_str().unwrap().to_string();
+
+        let findings = diagnose(&url).unwrap();
+
+        assert_eq!(findings.len(), 1);
+        assert!(findings[0].problem.contains("<none>"));
+    }
+
+    #[test]
+    fn test_diagnose_and_repair() {
+        let (_dir, url) = test_database();
+
+        // the foreign keys would prevent creating the orphans
+        let conn = SqliteConnection::establish(&url).unwrap();
+        for statement in &[
+            "PRAGMA foreign_keys = OFF",
+            "insert into users (id, email, password) values (1, 'corrupted@email.test', 'passwd_hash')",
+            "insert into user_attributes values (42, 'department', 'IT')",
+            "insert into audit_log (user_id, event, created_at) values (42, 'TosAccepted', '2021-04-28')",
+            "insert into users (id, email, password, secret_2fa) values (3, 'secret@email.test', 'HASH', 'I3VFM3JKMNDJCDH5')",
+            "insert into users (id, email, password, pending_secret_2fa) values (4, 'pending@email.test', 'HASH', '0189')",
+        ] {
+            conn.execute(&statement.replace("HASH", HASH.trim_end_matches('\0')))
+                .unwrap();
+        }
+
+        let findings = diagnose(&url).unwrap();
+        assert_eq!(findings.len(), 4);
+        assert_eq!(findings.iter().filter(|f| f.repairable).count(), 2);
+        assert_eq!(findings[2].users, vec![1]);
+        assert_eq!(findings[3].users, vec![4]);
+        assert!(findings[3].problem.starts_with("1 user(s)"));
+        // the emails aren't disclosed
+        assert!(findings.iter().all(|f| !f.problem.contains('@')));
+
+        assert_eq!(repair(&url), Ok(2));
+
+        let findings = diagnose(&url).unwrap();
+        assert_eq!(findings.len(), 2);
+        assert!(findings.iter().all(|f| !f.repairable));
+    }
+
+    #[test]
+    fn test_scan_credentials_and_health() {
+        let (_dir, url) = test_database();
+        assert_eq!(scan_credentials(&url), Ok(vec![]));
+        assert!(health(&url).unwrap().healthy);
+
+        let conn = SqliteConnection::establish(&url).unwrap();
+        conn.execute(
+            "insert into users (id, email, password) values (1, 'corrupted@email.test', 'passwd_hash')",
+        )
+        .unwrap();
+
+        let findings = scan_credentials(&url).unwrap();
+        assert_eq!(findings.len(), 1);
+        assert_eq!(findings[0].users, vec![1]);
+
+        let health = health(&url).unwrap();
+        assert!(!health.healthy);
+        assert_eq!(health.schema_version.as_deref(), Some(SCHEMA_VERSION));
+        assert_eq!(health.problems.len(), 1);
+        assert_eq!(health.problems[0].users, 1);
+    }
+
+    #[test]
+    fn test_scan_credentials_of_outdated_schema() {
+        let dir = tempfile::tempdir().unwrap();
+        let url = dir.path().join("empty.db").to_str().unwrap().to_string();
+
+        assert_eq!(scan_credentials(&url), Ok(vec![]));
+    }
+}
@@ -0,0 +1,458 @@
+/*!
+ * Storage of the short-lived state: reset tokens, rate-limit counters & sessions
+ *
+ * This state expires on its own, so it doesn't belong in the rows of the users.
+ * A `TokenStore` keeps values for a given time, the store drops them once it's
+ * over. `InMemoryTokenStore` keeps them in the process, `RedisTokenStore` (with
+ * the `redis` feature) in a Redis server, which expires them itself and
+ * shares them between the instances of an application.
+ *
+ * The flows keep their state in the store returned by `shared`: the failed
+ * logins, the CAPTCHAs & their passes (see `auth/throttle.rs`), the 2fa
+ * challenges (see `auth/login.rs`) and the checks of the email availability
+ * (see `auth/availability.rs`). It's in Redis if the `[redis]` section of the
+ * configuration is set, so the instances of an application behind a load
+ * balancer throttle the same logins & complete each other's challenges.
+ *
+ * The reset tokens must outlive the process (the CLI requests a reset & uses
+ * its token in two runs), they're kept in the store returned by `durable`:
+ * in Redis if it's set, in the database otherwise (`SQliteTokenStore`). Only
+ * their hash is kept, under `reset_token_key` (see `auth/reset.rs`).
+ *
+ * `InMemoryTokenStore` holds at most `MAX_ENTRIES` values, so a flood of keys
+ * (e.g. of sources) can't exhaust the memory: once it's full, the values
+ * expiring first make room.
+ *
+ * # Note
+ * The system has no sessions (see `auth/status.rs`), `session_key` is for the
+ * applications embedding it.
+ *
+ * # Author
+ * Doran Kayoumi <doran.kayoumi@heig-vd.ch>
+ */
+
+#[cfg(feature = "redis")]
+mod redis_store;
+mod sqlite_store;
+
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "redis")]
+use crate::config;
+use crate::errors::EphemeralStoreError;
+#[cfg(feature = "redis")]
+pub use redis_store::RedisTokenStore;
+pub use sqlite_store::SQliteTokenStore;
+
+/// Maximum number of values of an `InMemoryTokenStore`, unless another one is set
+pub const MAX_ENTRIES: usize = 100_000;
+
+/// A store shared by the flows & the threads
+pub type SharedTokenStore = Arc<dyn TokenStore + Send + Sync>;
+
+lazy_static! {
+    static ref SHARED: SharedTokenStore = open_shared();
+    static ref DURABLE: SharedTokenStore = open_durable();
+}
+
+/// Get the store of the state of the login flows, the same one on every call
+pub fn shared() -> SharedTokenStore {
+    Arc::clone(&SHARED)
+}
+
+/// Get the store of the state outliving the process (e.g. the reset tokens), the same
+/// one on every call
+pub fn durable() -> SharedTokenStore {
+    Arc::clone(&DURABLE)
+}
+
+/// Open the store set in the configuration, in memory without the `[redis]` section
+/// Note: a Redis server whose url is invalid refuses everything, rather than each
+///       instance keeping its own state unnoticed (see `selfcheck.rs`)
+#[cfg(feature = "redis")]
+fn open_shared() -> SharedTokenStore {
+    match config::get()
+        .redis
+        .as_ref()
+        .map(RedisTokenStore::from_config)
+    {
+        Some(Ok(store)) => Arc::new(store),
+        Some(Err(_)) => Arc::new(UnreachableTokenStore),
+        None => Arc::new(InMemoryTokenStore::new()),
+    }
+}
+
+/// Open the store set in the configuration, always in memory without Redis
+#[cfg(not(feature = "redis"))]
+fn open_shared() -> SharedTokenStore {
+    Arc::new(InMemoryTokenStore::new())
+}
+
+/// Open the store outliving the process, the shared one if it's in Redis, the database otherwise
+#[cfg(feature = "redis")]
+fn open_durable() -> SharedTokenStore {
+    match config::get().redis {
+        Some(_) => shared(),
+        None => Arc::new(SQliteTokenStore::new()),
+    }
+}
+
+/// Open the store outliving the process, always the database without Redis
+#[cfg(not(feature = "redis"))]
+fn open_durable() -> SharedTokenStore {
+    Arc::new(SQliteTokenStore::new())
+}
+
+/// Key of the hash of the reset token of an account
+///
+/// # Arguments
+///
+/// * `email` - the email of the account
+///
+pub fn reset_token_key(email: &str) -> String {
+    format!("reset_token:{}", email.to_lowercase())
+}
+
+/// Key of a rate-limit counter
+///
+/// # Arguments
+///
+/// * `scope` - what's limited (e.g. `login`)
+///
+/// * `subject` - who's limited (e.g. an email or an IP address)
+///
+pub fn rate_limit_key(scope: &str, subject: &str) -> String {
+    format!("rate_limit:{}:{}", scope, subject)
+}
+
+/// Key of the answer of a CAPTCHA that wasn't answered yet
+///
+/// # Arguments
+///
+/// * `scope` - what the CAPTCHA is for (e.g. `login`)
+///
+/// * `id` - the id of the CAPTCHA
+///
+pub fn captcha_key(scope: &str, id: &str) -> String {
+    format!("captcha:{}:{}", scope, id)
+}
+
+/// Key of a 2fa challenge that wasn't completed yet
+///
+/// # Arguments
+///
+/// * `id` - the id of the challenge
+///
+pub fn challenge_key(id: &str) -> String {
+    format!("challenge:{}", id)
+}
+
+/// Key of the data of a session
+///
+/// # Arguments
+///
+/// * `id` - the id of the session
+///
+pub fn session_key(id: &str) -> String {
+    format!("session:{}", id)
+}
+
+pub trait TokenStore {
+    /// Keep a value for a given time, replacing the previous one (if any)
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - the key of the value (e.g. `reset_token_key`)
+    ///
+    /// * `value` - the value to keep
+    ///
+    /// * `ttl` - how long it's kept
+    ///
+    fn put(&self, key: &str, value: &str, ttl: Duration) -> Result<(), EphemeralStoreError>;
+
+    /// Get a value, `None` if it doesn't exist or expired
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - the key of the value
+    ///
+    fn get(&self, key: &str) -> Result<Option<String>, EphemeralStoreError>;
+
+    /// Get a value & remove it at once, so only one caller gets it
+    /// (e.g. a reset token used twice at the same time)
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - the key of the value
+    ///
+    fn take(&self, key: &str) -> Result<Option<String>, EphemeralStoreError>;
+
+    /// Remove a value, if it exists
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - the key of the value
+    ///
+    fn remove(&self, key: &str) -> Result<(), EphemeralStoreError>;
+
+    /// Increment a counter & get its new value
+    /// Note: the counter expires `ttl` after its first increment (fixed window),
+    ///       the next increments don't extend it
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - the key of the counter (e.g. `rate_limit_key`)
+    ///
+    /// * `ttl` - how long the counter is kept
+    ///
+    fn increment(&self, key: &str, ttl: Duration) -> Result<u64, EphemeralStoreError>;
+}
+
+/// A value & when it expires
+struct Entry {
+    value: String,
+    expires_at: Instant,
+}
+
+impl Entry {
+    fn is_live(&self, now: Instant) -> bool {
+        now < self.expires_at
+    }
+}
+
+/// `TokenStore` keeping the values in memory
+/// Nothing is shared with the other processes, the values are lost with the store.
+/// It can be shared between threads, the values are behind a lock.
+pub struct InMemoryTokenStore {
+    entries: Mutex<HashMap<String, Entry>>,
+    max_entries: usize,
+}
+
+impl Default for InMemoryTokenStore {
+    fn default() -> Self {
+        Self::with_capacity(MAX_ENTRIES)
+    }
+}
+
+impl InMemoryTokenStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create a store holding at most a given number of values
+    ///
+    /// # Arguments
+    ///
+    /// * `max_entries` - maximum number of values held at once
+    ///
+    pub fn with_capacity(max_entries: usize) -> Self {
+        Self {
+            entries: Mutex::new(HashMap::new()),
+            max_entries: max_entries.max(1),
+        }
+    }
+
+    /// Number of values held, the expired ones aside
+    pub fn len(&self) -> usize {
+        self.lock().len()
+    }
+
+    /// Whether no value is held
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Make room for a new key once the store is full, by dropping the value expiring first
+    ///
+    /// # Arguments
+    ///
+    /// * `entries` - the values, locked
+    ///
+    /// * `key` - the key about to be inserted
+    ///
+    fn make_room(&self, entries: &mut HashMap<String, Entry>, key: &str) {
+        if entries.contains_key(key) || entries.len() < self.max_entries {
+            return;
+        }
+
+        let first = entries
+            .iter()
+            .min_by_key(|(_, e)| e.expires_at)
+            .map(|(k, _)| k.clone());
+        if let Some(k) = first {
+            entries.remove(&k);
+        }
+    }
+
+    /// Lock the values, dropping the expired ones
+    /// Note: a lock poisoned by a panic is used all the same, the values stay consistent
+    fn lock(&self) -> MutexGuard<'_, HashMap<String, Entry>> {
+        let mut entries = self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+        let now = Instant::now();
+        entries.retain(|_, e| e.is_live(now));
+        entries
+    }
+}
+
+impl TokenStore for InMemoryTokenStore {
+    fn put(&self, key: &str, value: &str, ttl: Duration) -> Result<(), EphemeralStoreError> {
+        let mut entries = self.lock();
+        self.make_room(&mut entries, key);
+        entries.insert(
+            key.to_string(),
+            Entry {
+                value: value.to_string(),
+                expires_at: Instant::now() + ttl,
+            },
+        );
+        Ok(())
+    }
+
+    fn get(&self, key: &str) -> Result<Option<String>, EphemeralStoreError> {
+        Ok(self.lock().get(key).map(|e| e.value.clone()))
+    }
+
+    fn take(&self, key: &str) -> Result<Option<String>, EphemeralStoreError> {
+        Ok(self.lock().remove(key).map(|e| e.value))
+    }
+
+    fn remove(&self, key: &str) -> Result<(), EphemeralStoreError> {
+        self.lock().remove(key);
+        Ok(())
+    }
+
+    fn increment(&self, key: &str, ttl: Duration) -> Result<u64, EphemeralStoreError> {
+        let mut entries = self.lock();
+        self.make_room(&mut entries, key);
+        let entry = entries.entry(key.to_string()).or_insert_with(|| Entry {
+            value: "0".to_string(),
+            expires_at: Instant::now() + ttl,
+        });
+        let count = entry
+            .value
+            .parse::<u64>()
+            .map_err(|_| EphemeralStoreError::ReadError)?
+            + 1;
+        entry.value = count.to_string();
+
+        Ok(count)
+    }
+}
+
+/// `TokenStore` of a Redis server that can't be opened, every call fails
+#[cfg(feature = "redis")]
+struct UnreachableTokenStore;
+
+#[cfg(feature = "redis")]
+impl TokenStore for UnreachableTokenStore {
+    fn put(&self, _: &str, _: &str, _: Duration) -> Result<(), EphemeralStoreError> {
+        Err(EphemeralStoreError::ConnectionError)
+    }
+
+    fn get(&self, _: &str) -> Result<Option<String>, EphemeralStoreError> {
+        Err(EphemeralStoreError::ConnectionError)
+    }
+
+    fn take(&self, _: &str) -> Result<Option<String>, EphemeralStoreError> {
+        Err(EphemeralStoreError::ConnectionError)
+    }
+
+    fn remove(&self, _: &str) -> Result<(), EphemeralStoreError> {
+        Err(EphemeralStoreError::ConnectionError)
+    }
+
+    fn increment(&self, _: &str, _: Duration) -> Result<u64, EphemeralStoreError> {
+        Err(EphemeralStoreError::ConnectionError)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const TTL: Duration = Duration::from_secs(60);
+
+    #[test]
+    fn test_put_get() {
+        let store = InMemoryTokenStore::new();
+        let key = reset_token_key("Alice@Email.test");
+
+        assert_eq!(store.get(&key), Ok(None));
+        store.put(&key, "first", TTL).unwrap();
+        store.put(&key, "second", TTL).unwrap();
+
+        assert_eq!(
+            store.get(&reset_token_key("alice@email.test")),
+            Ok(Some("second".to_string()))
+        );
+        store.remove(&key).unwrap();
+        assert_eq!(store.get(&key), Ok(None));
+    }
+
+    #[test]
+    fn test_take_only_once() {
+        let store = InMemoryTokenStore::new();
+        let key = reset_token_key("alice@email.test");
+        store.put(&key, "token", TTL).unwrap();
+
+        assert_eq!(store.take(&key), Ok(Some("token".to_string())));
+        assert_eq!(store.take(&key), Ok(None));
+    }
+
+    #[test]
+    fn test_expired_values() {
+        let store = InMemoryTokenStore::new();
+        let key = session_key("session");
+        store.put(&key, "data", Duration::ZERO).unwrap();
+
+        assert_eq!(store.get(&key), Ok(None));
+        assert_eq!(store.take(&key), Ok(None));
+    }
+
+    #[test]
+    fn test_increment() {
+        let store = InMemoryTokenStore::new();
+        let key = rate_limit_key("login", "alice@email.test");
+
+        assert_eq!(store.increment(&key, TTL), Ok(1));
+        assert_eq!(store.increment(&key, TTL), Ok(2));
+        // other counters aren't affected
+        assert_eq!(
+            store.increment(&rate_limit_key("login", "bob@email.test"), TTL),
+            Ok(1)
+        );
+
+        // once the window is over, the counter starts again
+        let key = rate_limit_key("availability", "127.0.0.1");
+        assert_eq!(store.increment(&key, Duration::ZERO), Ok(1));
+        assert_eq!(store.increment(&key, Duration::ZERO), Ok(1));
+    }
+
+    #[test]
+    fn test_capacity() {
+        let store = InMemoryTokenStore::with_capacity(2);
+
+        store.put("first", "1", TTL).unwrap();
+        store.put("second", "2", TTL * 2).unwrap();
+        assert_eq!(store.len(), 2);
+
+        // the value expiring first makes room
+        assert_eq!(store.increment("third", TTL * 3), Ok(1));
+        assert_eq!(store.len(), 2);
+        assert_eq!(store.get("first"), Ok(None));
+        assert_eq!(store.get("second"), Ok(Some("2".to_string())));
+
+        // replacing a value doesn't drop another one
+        store.put("second", "22", TTL).unwrap();
+        assert_eq!(store.increment("third", TTL), Ok(2));
+        assert_eq!(store.len(), 2);
+
+        // nor do the expired values, they're dropped first
+        store.put("second", "2", Duration::ZERO).unwrap();
+        store.put("fourth", "4", TTL).unwrap();
+        assert_eq!(store.get("third"), Ok(Some("2".to_string())));
+    }
+}
@@ -0,0 +1,159 @@
+/*!
+ * Implementation of the `TokenStore` with Redis as a storage
+ *
+ * The values are kept with an expiry (`SET ... PX`), so Redis drops them
+ * itself. The keys are prefixed, so the store can share a Redis server with
+ * other applications.
+ *
+ * # Author
+ * Doran Kayoumi <doran.kayoumi@heig-vd.ch>
+ */
+
+use redis::{Client, Connection, RedisResult, Script};
+use std::convert::TryFrom;
+use std::sync::{Mutex, PoisonError};
+use std::time::Duration;
+
+use super::TokenStore;
+use crate::config::RedisConfig;
+use crate::errors::EphemeralStoreError;
+
+/// Prefix of the keys, unless another one is set
+pub const DEFAULT_PREFIX: &str = "secure-auth:";
+
+/// Increment a counter, setting its expiry along with its first increment
+/// Note: a script, so the counter can't be left without an expiry
+const INCREMENT: &str = r#"
+local count = redis.call('INCR', KEYS[1])
+if count == 1 then
+    redis.call('PEXPIRE', KEYS[1], ARGV[1])
+end
+return count
+"#;
+
+/// `TokenStore` keeping the values in a Redis server
+pub struct RedisTokenStore {
+    client: Client,
+    prefix: String,
+    /// Note: kept between the calls, opened again once a call failed
+    connection: Mutex<Option<Connection>>,
+}
+
+impl RedisTokenStore {
+    /// # Arguments
+    ///
+    /// * `url` - the url of the server (e.g. `redis://127.0.0.1/`)
+    ///
+    pub fn new(url: &str) -> Result<Self, EphemeralStoreError> {
+        Ok(Self {
+            client: Client::open(url).map_err(|_| EphemeralStoreError::ConnectionError)?,
+            prefix: DEFAULT_PREFIX.to_string(),
+            connection: Mutex::new(None),
+        })
+    }
+
+    /// Store in the server set in the `[redis]` section of the configuration
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - the url of the server & the prefix of the keys
+    ///
+    pub fn from_config(config: &RedisConfig) -> Result<Self, EphemeralStoreError> {
+        Ok(Self::new(&config.url)?.with_prefix(&config.prefix))
+    }
+
+    /// Prefix the keys with a given prefix instead of `DEFAULT_PREFIX`
+    pub fn with_prefix(mut self, prefix: &str) -> Self {
+        self.prefix = prefix.to_string();
+        self
+    }
+
+    fn key(&self, key: &str) -> String {
+        format!("{}{}", self.prefix, key)
+    }
+
+    /// Run a command on the connection to the server
+    ///
+    /// # Arguments
+    ///
+    /// * `error` - the error returned if the command fails
+    ///
+    /// * `command` - the command to run
+    ///
+    fn run<T>(
+        &self,
+        error: EphemeralStoreError,
+        command: impl FnOnce(&mut Connection) -> RedisResult<T>,
+    ) -> Result<T, EphemeralStoreError> {
+        let mut connection = self
+            .connection
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if connection.is_none() {
+            *connection = Some(
+                self.client
+                    .get_connection()
+                    .map_err(|_| EphemeralStoreError::ConnectionError)?,
+            );
+        }
+
+        let result = match connection.as_mut() {
+            Some(c) => command(c),
+            None => return Err(EphemeralStoreError::ConnectionError),
+        };
+        result.map_err(|_| {
+            // the connection may be broken, a new one is opened by the next call
+            *connection = None;
+            error
+        })
+    }
+}
+
+/// Expiry of a value in milliseconds, at least one as Redis rejects a zero expiry
+fn millis(ttl: Duration) -> u64 {
+    u64::try_from(ttl.as_millis()).unwrap_or(u64::MAX).max(1)
+}
+
+impl TokenStore for RedisTokenStore {
+    fn put(&self, key: &str, value: &str, ttl: Duration) -> Result<(), EphemeralStoreError> {
+        self.run(EphemeralStoreError::WriteError, |c| {
+            redis::cmd("SET")
+                .arg(self.key(key))
+                .arg(value)
+                .arg("PX")
+                .arg(millis(ttl))
+                .query(c)
+        })
+    }
+
+    fn get(&self, key: &str) -> Result<Option<String>, EphemeralStoreError> {
+        self.run(EphemeralStoreError::ReadError, |c| {
+            redis::cmd("GET").arg(self.key(key)).query(c)
+        })
+    }
+
+    fn take(&self, key: &str) -> Result<Option<String>, EphemeralStoreError> {
+        let key = self.key(key);
+        // Note: `MULTI`/`EXEC` rather than `GETDEL`, which requires Redis 6.2
+        let (value, _): (Option<String>, u64) = self.run(EphemeralStoreError::WriteError, |c| {
+            redis::pipe().atomic().get(&key).del(&key).query(c)
+        })?;
+
+        Ok(value)
+    }
+
+    fn remove(&self, key: &str) -> Result<(), EphemeralStoreError> {
+        self.run(EphemeralStoreError::WriteError, |c| {
+            redis::cmd("DEL").arg(self.key(key)).query(c)
+        })
+    }
+
+    fn increment(&self, key: &str, ttl: Duration) -> Result<u64, EphemeralStoreError> {
+        self.run(EphemeralStoreError::WriteError, |c| {
+            Script::new(INCREMENT)
+                .key(self.key(key))
+                .arg(millis(ttl))
+                .invoke(c)
+        })
+    }
+}
@@ -0,0 +1,210 @@
+/*!
+ * Implementation of the `TokenStore` with SQLite as a storage
+ *
+ * The values outlive the process, so a state created by a run of the CLI can
+ * be used by the next one (e.g. a reset token requested, then used). The
+ * dates are all stored in UTC, the expired values are filtered out when read
+ * & dropped when the store is written to.
+ *
+ * # Author
+ * Doran Kayoumi <doran.kayoumi@heig-vd.ch>
+ */
+
+use chrono::{DateTime, Utc};
+use diesel::prelude::*;
+use diesel::{delete, replace_into, update};
+use std::time::Duration;
+
+use super::TokenStore;
+use crate::db::models::EphemeralValue;
+use crate::db::schema::ephemeral_values;
+use crate::db::{database_url, establish_connection};
+use crate::errors::EphemeralStoreError;
+
+/// `TokenStore` keeping the values in a table of the SQLite database
+pub struct SQliteTokenStore {
+    database_url: String,
+}
+
+impl SQliteTokenStore {
+    /// Store using the database set in the `.env` file
+    pub fn new() -> Self {
+        Self::with_database_url(&database_url())
+    }
+
+    /// Store using a specific database
+    ///
+    /// # Arguments
+    ///
+    /// * `url` - url of the SQLite database
+    ///
+    pub fn with_database_url(url: &str) -> Self {
+        Self {
+            database_url: url.to_string(),
+        }
+    }
+
+    fn connect(&self) -> Result<SqliteConnection, EphemeralStoreError> {
+        establish_connection(&self.database_url).map_err(|_| EphemeralStoreError::ConnectionError)
+    }
+}
+
+impl Default for SQliteTokenStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// When a value kept for a given time expires
+fn expiry(ttl: Duration) -> Result<String, EphemeralStoreError> {
+    let ttl = chrono::Duration::from_std(ttl).map_err(|_| EphemeralStoreError::WriteError)?;
+    Ok((Utc::now() + ttl).to_rfc3339())
+}
+
+/// Read a value that didn't expire yet
+///
+/// # Arguments
+///
+/// * `conn` - the connection to the database
+///
+/// * `key` - the key of the value
+///
+/// * `now` - the current date & time
+///
+fn live_value(
+    conn: &SqliteConnection,
+    key: &str,
+    now: DateTime<Utc>,
+) -> Result<Option<String>, diesel::result::Error> {
+    ephemeral_values::table
+        .filter(ephemeral_values::key.eq(key))
+        .filter(ephemeral_values::expires_at.gt(now.to_rfc3339()))
+        .select(ephemeral_values::value)
+        .first::<String>(conn)
+        .optional()
+}
+
+impl TokenStore for SQliteTokenStore {
+    fn put(&self, key: &str, value: &str, ttl: Duration) -> Result<(), EphemeralStoreError> {
+        let conn = self.connect()?;
+        let expires_at = expiry(ttl)?;
+
+        conn.immediate_transaction::<_, diesel::result::Error, _>(|| {
+            delete(
+                ephemeral_values::table
+                    .filter(ephemeral_values::expires_at.le(Utc::now().to_rfc3339())),
+            )
+            .execute(&conn)?;
+            replace_into(ephemeral_values::table)
+                .values(EphemeralValue {
+                    key,
+                    value,
+                    expires_at,
+                })
+                .execute(&conn)
+                .map(|_| ())
+        })
+        .map_err(|_| EphemeralStoreError::WriteError)
+    }
+
+    fn get(&self, key: &str) -> Result<Option<String>, EphemeralStoreError> {
+        let conn = self.connect()?;
+        live_value(&conn, key, Utc::now()).map_err(|_| EphemeralStoreError::ReadError)
+    }
+
+    fn take(&self, key: &str) -> Result<Option<String>, EphemeralStoreError> {
+        let conn = self.connect()?;
+
+        // Note: read & removed in the same transaction, so only one caller gets it
+        conn.immediate_transaction::<_, diesel::result::Error, _>(|| {
+            let value = live_value(&conn, key, Utc::now())?;
+            delete(ephemeral_values::table.filter(ephemeral_values::key.eq(key))).execute(&conn)?;
+            Ok(value)
+        })
+        .map_err(|_| EphemeralStoreError::WriteError)
+    }
+
+    fn remove(&self, key: &str) -> Result<(), EphemeralStoreError> {
+        let conn = self.connect()?;
+        delete(ephemeral_values::table.filter(ephemeral_values::key.eq(key)))
+            .execute(&conn)
+            .map(|_| ())
+            .map_err(|_| EphemeralStoreError::WriteError)
+    }
+
+    fn increment(&self, key: &str, ttl: Duration) -> Result<u64, EphemeralStoreError> {
+        let conn = self.connect()?;
+        let expires_at = expiry(ttl)?;
+
+        conn.immediate_transaction::<_, diesel::result::Error, _>(|| {
+            let count = match live_value(&conn, key, Utc::now())?.map(|v| v.parse::<u64>()) {
+                Some(Ok(count)) => {
+                    // Note: the expiry of the first increment is kept
+                    update(ephemeral_values::table.filter(ephemeral_values::key.eq(key)))
+                        .set(ephemeral_values::value.eq((count + 1).to_string()))
+                        .execute(&conn)?;
+                    count + 1
+                }
+                // Note: the value isn't a counter
+                Some(Err(_)) => return Err(diesel::result::Error::RollbackTransaction),
+                None => {
+                    replace_into(ephemeral_values::table)
+                        .values(EphemeralValue {
+                            key,
+                            value: "1",
+                            expires_at,
+                        })
+                        .execute(&conn)?;
+                    1
+                }
+            };
+
+            Ok(count)
+        })
+        .map_err(|_| EphemeralStoreError::WriteError)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::db::test_database;
+
+    #[test]
+    fn test_values_outlive_the_store() {
+        let (_dir, url) = test_database();
+        let ttl = Duration::from_secs(60);
+
+        SQliteTokenStore::with_database_url(&url)
+            .put("key", "value", ttl)
+            .unwrap();
+
+        let store = SQliteTokenStore::with_database_url(&url);
+        assert_eq!(store.get("key"), Ok(Some("value".to_string())));
+        assert_eq!(store.take("key"), Ok(Some("value".to_string())));
+        assert_eq!(store.take("key"), Ok(None));
+
+        assert_eq!(store.increment("counter", ttl), Ok(1));
+        assert_eq!(store.increment("counter", ttl), Ok(2));
+        store.remove("counter").unwrap();
+        assert_eq!(store.get("counter"), Ok(None));
+    }
+
+    #[test]
+    fn test_values_expire() {
+        let (_dir, url) = test_database();
+        let store = SQliteTokenStore::with_database_url(&url);
+
+        store.put("key", "value", Duration::from_secs(0)).unwrap();
+        assert_eq!(store.get("key"), Ok(None));
+        assert_eq!(store.take("key"), Ok(None));
+        assert_eq!(store.increment("key", Duration::from_secs(60)), Ok(1));
+    }
+
+    #[test]
+    fn test_unreachable_database() {
+        let store = SQliteTokenStore::with_database_url("");
+
+        assert_eq!(store.get("key"), Err(EphemeralStoreError::ConnectionError));
+    }
+}
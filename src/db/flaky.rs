@@ -0,0 +1,280 @@
+/*!
+ * Fault injection in front of a user repository
+ *
+ * Meant for the tests: the repository fails at a given rate & answers with a
+ * given latency, so the retries, timeouts & error paths of the code using it
+ * can be checked without breaking a real database. Only built with the
+ * `test-utils` feature (and for the tests of the library).
+ *
+ * # Author
+ * Doran Kayoumi <doran.kayoumi@heig-vd.ch>
+ */
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::HashMap;
+use std::iter;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, PoisonError};
+use std::thread;
+use std::time::Duration;
+
+use super::models::{User, UserChangeset};
+use super::repository::{UserFilter, UserRepository};
+use crate::errors::UserDBError;
+
+/// Faults injected by a `FlakyRepository`
+#[derive(PartialEq, Debug, Clone, Copy, Default)]
+pub struct Faults {
+    /// Probability (from 0 to 1) that an operation fails
+    pub failure_rate: f64,
+    /// Delay before each operation
+    pub latency: Duration,
+    /// Error of the failed operations, the one of the operation itself if not set
+    pub error: Option<UserDBError>,
+}
+
+/// `UserRepository` injecting faults in front of another one
+pub struct FlakyRepository<R: UserRepository> {
+    inner: R,
+    faults: Faults,
+    rng: Mutex<StdRng>,
+    injected: AtomicU64,
+}
+
+impl<R: UserRepository> FlakyRepository<R> {
+    /// # Arguments
+    ///
+    /// * `inner` - the repository used when no fault is injected
+    ///
+    /// * `faults` - the faults to inject
+    ///
+    pub fn new(inner: R, faults: Faults) -> Self {
+        Self::with_rng(inner, faults, StdRng::from_entropy())
+    }
+
+    /// Same as `new`, but the failures happen in the same order for the same seed
+    ///
+    /// # Arguments
+    ///
+    /// * `inner` - the repository used when no fault is injected
+    ///
+    /// * `faults` - the faults to inject
+    ///
+    /// * `seed` - seed of the random failures
+    ///
+    pub fn with_seed(inner: R, faults: Faults, seed: u64) -> Self {
+        Self::with_rng(inner, faults, StdRng::seed_from_u64(seed))
+    }
+
+    fn with_rng(inner: R, faults: Faults, rng: StdRng) -> Self {
+        Self {
+            inner,
+            faults,
+            rng: Mutex::new(rng),
+            injected: AtomicU64::new(0),
+        }
+    }
+
+    /// Number of failures injected so far
+    pub fn injected(&self) -> u64 {
+        self.injected.load(Ordering::Relaxed)
+    }
+
+    /// Wait for the latency & decide whether the operation fails
+    ///
+    /// # Arguments
+    ///
+    /// * `own` - the error of the operation
+    ///
+    fn fault(&self, own: UserDBError) -> Result<(), UserDBError> {
+        if !self.faults.latency.is_zero() {
+            thread::sleep(self.faults.latency);
+        }
+
+        // Note: compared rather than `gen_bool`, which panics for a rate outside [0, 1]
+        let draw: f64 = self
+            .rng
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .gen();
+        if draw < self.faults.failure_rate {
+            self.injected.fetch_add(1, Ordering::Relaxed);
+            return Err(self.faults.error.unwrap_or(own));
+        }
+
+        Ok(())
+    }
+}
+
+impl<R: UserRepository> UserRepository for FlakyRepository<R> {
+    fn get_user(&self, e: &str) -> Result<User, UserDBError> {
+        self.fault(UserDBError::GetUserError)?;
+        self.inner.get_user(e)
+    }
+
+    fn create_user(&self, e: &str, passwd: &str) -> Result<(), UserDBError> {
+        self.fault(UserDBError::CreateUserError)?;
+        self.inner.create_user(e, passwd)
+    }
+
+    fn update_user(&self, u: &User) -> Result<(), UserDBError> {
+        self.fault(UserDBError::UpdateUserError)?;
+        self.inner.update_user(u)
+    }
+
+    fn patch_user(&self, user: i32, changes: &UserChangeset) -> Result<(), UserDBError> {
+        self.fault(UserDBError::UpdateUserError)?;
+        self.inner.patch_user(user, changes)
+    }
+
+    fn update_user_atomic(
+        &self,
+        expected: &User,
+        changes: &UserChangeset,
+    ) -> Result<bool, UserDBError> {
+        self.fault(UserDBError::UpdateUserError)?;
+        self.inner.update_user_atomic(expected, changes)
+    }
+
+    fn delete_user(&self, user: i32) -> Result<(), UserDBError> {
+        self.fault(UserDBError::DeleteUserError)?;
+        self.inner.delete_user(user)
+    }
+
+    fn update_many(
+        &self,
+        filter: &UserFilter,
+        changes: &UserChangeset,
+    ) -> Result<usize, UserDBError> {
+        self.fault(UserDBError::UpdateUserError)?;
+        self.inner.update_many(filter, changes)
+    }
+
+    fn list_users(&self, filter: &UserFilter) -> Result<Vec<User>, UserDBError> {
+        self.fault(UserDBError::ListUsersError)?;
+        self.inner.list_users(filter)
+    }
+
+    fn iter_users(
+        &self,
+        filter: &UserFilter,
+    ) -> Box<dyn Iterator<Item = Result<User, UserDBError>>> {
+        match self.fault(UserDBError::ListUsersError) {
+            Ok(()) => self.inner.iter_users(filter),
+            Err(e) => Box::new(iter::once(Err(e))),
+        }
+    }
+
+    fn search_users(&self, query: &str, limit: i64) -> Result<Vec<User>, UserDBError> {
+        self.fault(UserDBError::ListUsersError)?;
+        self.inner.search_users(query, limit)
+    }
+
+    fn get_attributes(&self, user: i32) -> Result<HashMap<String, String>, UserDBError> {
+        self.fault(UserDBError::GetAttributesError)?;
+        self.inner.get_attributes(user)
+    }
+
+    fn set_attribute(&self, user: i32, attr: &str, val: &str) -> Result<(), UserDBError> {
+        self.fault(UserDBError::UpdateAttributesError)?;
+        self.inner.set_attribute(user, attr, val)
+    }
+
+    fn remove_attribute(&self, user: i32, attr: &str) -> Result<(), UserDBError> {
+        self.fault(UserDBError::UpdateAttributesError)?;
+        self.inner.remove_attribute(user, attr)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::db::repository::MockSQliteUserRepository;
+    use std::time::Instant;
+
+    fn repository() -> MockSQliteUserRepository {
+        let mut mock = MockSQliteUserRepository::new();
+        mock.expect_get_user()
+            .returning(|e| Ok(User::new(e, "passwd_hash")));
+        mock
+    }
+
+    #[test]
+    fn test_without_faults() {
+        let flaky = FlakyRepository::new(repository(), Faults::default());
+
+        for _ in 0..100 {
+            assert!(flaky.get_user("email@email.test").is_ok());
+        }
+        assert_eq!(flaky.injected(), 0);
+    }
+
+    #[test]
+    fn test_every_operation_fails() {
+        let faults = Faults {
+            failure_rate: 1.0,
+            ..Faults::default()
+        };
+        let flaky = FlakyRepository::new(MockSQliteUserRepository::new(), faults);
+
+        // the inner repository is never reached
+        assert_eq!(
+            flaky.get_user("email@email.test"),
+            Err(UserDBError::GetUserError)
+        );
+        assert_eq!(flaky.delete_user(1), Err(UserDBError::DeleteUserError));
+        assert_eq!(
+            flaky.iter_users(&UserFilter::new()).collect::<Vec<_>>(),
+            vec![Err(UserDBError::ListUsersError)]
+        );
+        assert_eq!(flaky.injected(), 3);
+    }
+
+    #[test]
+    fn test_failure_rate() {
+        let faults = Faults {
+            failure_rate: 0.3,
+            error: Some(UserDBError::Timeout),
+            ..Faults::default()
+        };
+        let flaky = FlakyRepository::with_seed(repository(), faults, 42);
+
+        let failures = (0..1000)
+            .filter(|_| flaky.get_user("email@email.test") == Err(UserDBError::Timeout))
+            .count();
+
+        assert!((250..350).contains(&failures));
+        assert_eq!(flaky.injected(), failures as u64);
+    }
+
+    #[test]
+    fn test_same_seed_same_failures() {
+        let faults = Faults {
+            failure_rate: 0.5,
+            ..Faults::default()
+        };
+        let run = || {
+            let flaky = FlakyRepository::with_seed(repository(), faults, 7);
+            (0..20)
+                .map(|_| flaky.get_user("email@email.test").is_ok())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(run(), run());
+    }
+
+    #[test]
+    fn test_latency() {
+        let faults = Faults {
+            latency: Duration::from_millis(20),
+            ..Faults::default()
+        };
+        let flaky = FlakyRepository::new(repository(), faults);
+
+        let start = Instant::now();
+        let _ = flaky.get_user("email@email.test");
+
+        assert!(start.elapsed() >= Duration::from_millis(20));
+    }
+}
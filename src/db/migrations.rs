@@ -0,0 +1,148 @@
+/*!
+ * Versioned migrations of the database schema
+ *
+ * Every change of the schema is a migration: a `migrations/<version>_<name>`
+ * directory (the version is the date & time it was written) holding the `up.sql`
+ * applying the change & the `down.sql` reverting it. They're embedded in the
+ * binary, so it migrates a database on its own (`secure-auth db migrate`).
+ *
+ * `migrate` applies the pending migrations in the order of their version, each
+ * one in its own transaction: the existing rows are kept (e.g. a new column of
+ * the users gets its default), and a migration failing leaves the database at
+ * the previous version instead of half-migrated, the next run resumes from it.
+ * A database migrated by a newer version of the system is left untouched, this
+ * one doesn't know its schema.
+ *
+ * Adding a migration:
+ * 1. create its directory, e.g. `migrations/2026-10-16-220000_add_audit_chain`
+ * 2. update `schema.rs` & the models to the new schema
+ * 3. bump `SCHEMA_VERSION` to its version
+ *
+ * # Author
+ * Doran Kayoumi <doran.kayoumi@heig-vd.ch>
+ */
+
+use diesel::prelude::*;
+use diesel::sql_query;
+use diesel::sql_types::{Nullable, Text};
+
+use super::{establish_connection, SCHEMA_VERSION};
+use crate::errors::SetupError;
+
+// the migrations are embedded in the binary so it can set up a database on its own
+embed_migrations!("migrations");
+
+#[derive(QueryableByName)]
+struct Version {
+    #[sql_type = "Nullable<Text>"]
+    version: Option<String>,
+}
+
+/// Get the version of the last migration applied
+/// Note: the table doesn't exist if the migrations were never run, there's no version then
+pub(super) fn current_version(conn: &SqliteConnection) -> Option<String> {
+    sql_query("select max(version) as version from __diesel_schema_migrations")
+        .get_result::<Version>(conn)
+        .ok()
+        .and_then(|v| v.version)
+}
+
+/// Apply the pending migrations to a database (which is created if needed)
+/// Returns the versions of the migrations applied, in the order they were
+///
+/// # Arguments
+///
+/// * `database_url` - url of the database to migrate
+///
+pub fn migrate(database_url: &str) -> Result<Vec<String>, SetupError> {
+    let conn = establish_connection(database_url)?;
+
+    // Note: the versions all have the same length, so they're compared as text
+    if current_version(&conn).is_some_and(|v| v.as_str() > SCHEMA_VERSION) {
+        return Err(SetupError::NewerSchemaError);
+    }
+
+    let mut output = Vec::new();
+    embedded_migrations::run_with_output(&conn, &mut output)
+        .map_err(|_| SetupError::MigrationError)?;
+
+    Ok(String::from_utf8_lossy(&output)
+        .lines()
+        .filter_map(|l| l.strip_prefix("Running migration "))
+        .map(str::to_string)
+        .collect())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::db::schema::users;
+    use diesel::connection::SimpleConnection;
+    use std::fs;
+
+    fn empty_database() -> (tempfile::TempDir, String) {
+        let dir = tempfile::tempdir().unwrap();
+        let url = dir.path().join("test.db").to_str().unwrap().to_string();
+
+        (dir, url)
+    }
+
+    /// Revert the latest migration with its `down.sql`
+    fn revert_latest(conn: &SqliteConnection) {
+        // Note: the directories are named after their date, the latest comes last
+        let latest = fs::read_dir(concat!(env!("CARGO_MANIFEST_DIR"), "/migrations"))
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .max()
+            .unwrap();
+
+        conn.batch_execute(&fs::read_to_string(latest.join("down.sql")).unwrap())
+            .unwrap();
+        sql_query(format!(
+            "delete from __diesel_schema_migrations where version = '{}'",
+            SCHEMA_VERSION
+        ))
+        .execute(conn)
+        .unwrap();
+    }
+
+    #[test]
+    fn test_migrate_new_database() {
+        let (_dir, url) = empty_database();
+
+        let applied = migrate(&url).unwrap();
+        assert_eq!(applied.first().unwrap(), "20210428090017");
+        assert_eq!(applied.last().unwrap(), SCHEMA_VERSION);
+
+        assert_eq!(migrate(&url), Ok(vec![]));
+    }
+
+    #[test]
+    fn test_migrate_keeps_the_rows() {
+        let (_dir, url) = empty_database();
+        migrate(&url).unwrap();
+
+        let conn = establish_connection(&url).unwrap();
+        sql_query("insert into users (email, password) values ('email@email.test', 'passwd_hash')")
+            .execute(&conn)
+            .unwrap();
+        revert_latest(&conn);
+        assert_ne!(current_version(&conn).as_deref(), Some(SCHEMA_VERSION));
+
+        assert_eq!(migrate(&url), Ok(vec![SCHEMA_VERSION.to_string()]));
+        assert_eq!(users::table.count().get_result::<i64>(&conn), Ok(1));
+    }
+
+    #[test]
+    fn test_newer_schema_is_left_untouched() {
+        let (_dir, url) = empty_database();
+        migrate(&url).unwrap();
+
+        let conn = establish_connection(&url).unwrap();
+        sql_query("insert into __diesel_schema_migrations (version) values ('99991231000000')")
+            .execute(&conn)
+            .unwrap();
+
+        assert_eq!(migrate(&url), Err(SetupError::NewerSchemaError));
+    }
+}
@@ -1,25 +1,299 @@
-use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
 
-use super::schema::users;
+use super::schema::{
+    audit_log, contact_recoveries, ephemeral_values, job_leases, notification_dedupe,
+    password_stats, recovery_codes, redeemed_action_links, trusted_contacts, user_attributes,
+    users,
+};
+use crate::utils::{redact, Redacted};
 
-#[derive(Queryable, Debug, AsChangeset, PartialEq)]
+/// Note: the debug output hides the password hash & the 2fa secrets
+#[derive(Queryable, Clone, AsChangeset, PartialEq)]
 #[changeset_options(treat_none_as_null = "true")]
 pub struct User {
     id: i32,
     email: String,
     password: String,
     secret_2fa: Option<String>,
-    reset_token: Option<String>,
-    reset_token_created_at: Option<String>,
+    anti_phishing_phrase: Option<String>,
+    accepted_tos_version: Option<i32>,
+    pending_secret_2fa: Option<String>,
+    kind: String,
 }
 
-#[derive(Insertable, Debug)]
+/// Every field of a user, as kept in the file of `FileUserRepository`
+/// Note: `User` itself can't be serialized, so its hashes & secrets can't end up
+///       in a response by mistake
+#[derive(Serialize, Deserialize)]
+pub(super) struct UserRecord {
+    id: i32,
+    email: String,
+    password: String,
+    secret_2fa: Option<String>,
+    anti_phishing_phrase: Option<String>,
+    accepted_tos_version: Option<i32>,
+    pending_secret_2fa: Option<String>,
+    kind: String,
+}
+
+impl From<&User> for UserRecord {
+    fn from(u: &User) -> Self {
+        let u = u.clone();
+        Self {
+            id: u.id,
+            email: u.email,
+            password: u.password,
+            secret_2fa: u.secret_2fa,
+            anti_phishing_phrase: u.anti_phishing_phrase,
+            accepted_tos_version: u.accepted_tos_version,
+            pending_secret_2fa: u.pending_secret_2fa,
+            kind: u.kind,
+        }
+    }
+}
+
+impl From<UserRecord> for User {
+    fn from(r: UserRecord) -> Self {
+        Self {
+            id: r.id,
+            email: r.email,
+            password: r.password,
+            secret_2fa: r.secret_2fa,
+            anti_phishing_phrase: r.anti_phishing_phrase,
+            accepted_tos_version: r.accepted_tos_version,
+            pending_secret_2fa: r.pending_secret_2fa,
+            kind: r.kind,
+        }
+    }
+}
+
+/// What an account is used by
+/// The service accounts (e.g. a CI job or another backend) have no usable password,
+/// they authenticate with an API key or a client certificate (see `auth/service.rs`)
+/// The directory accounts have no usable password either, their users authenticate
+/// with the password of the directory (see `auth/ldap.rs`)
+#[derive(PartialEq, Debug, Clone, Copy, strum_macros::Display, strum_macros::EnumString)]
+#[strum(serialize_all = "lowercase")]
+pub enum AccountKind {
+    Human,
+    Service,
+    Directory,
+}
+
+#[derive(Insertable, Clone, Copy)]
 #[table_name = "users"]
 pub struct NewUser<'a> {
     pub email: &'a str,
     pub password: &'a str,
 }
 
+/// Set of changes to apply to users
+/// Only the fields explicitly set are written, the others are left untouched
+/// Note: the fields are visible to the storage backends which can't use the derived changeset
+#[derive(AsChangeset, Default, Clone, PartialEq)]
+#[table_name = "users"]
+pub struct UserChangeset {
+    pub(super) password: Option<String>,
+    pub(super) secret_2fa: Option<Option<String>>,
+    pub(super) anti_phishing_phrase: Option<Option<String>>,
+    pub(super) accepted_tos_version: Option<Option<i32>>,
+    pub(super) pending_secret_2fa: Option<Option<String>>,
+    pub(super) kind: Option<String>,
+}
+
+#[derive(Queryable, Insertable, Debug, Clone, PartialEq)]
+#[table_name = "user_attributes"]
+pub struct UserAttribute {
+    pub user_id: i32,
+    pub name: String,
+    pub value: String,
+}
+
+#[derive(Insertable, Clone, Copy)]
+#[table_name = "recovery_codes"]
+pub struct NewRecoveryCode<'a> {
+    pub user_id: i32,
+    pub code_hash: &'a str,
+}
+
+#[derive(Insertable, Debug, Clone, Copy)]
+#[table_name = "trusted_contacts"]
+pub struct NewTrustedContact<'a> {
+    pub user_id: i32,
+    pub email: &'a str,
+}
+
+/// Approval of a recovery asked to a trusted contact
+/// Note: the hash of the approval token is hidden in the debug output
+#[derive(Queryable, Clone, PartialEq)]
+pub struct ContactRecovery {
+    pub id: i32,
+    pub user_id: i32,
+    pub contact_email: String,
+    pub token_hash: String,
+    pub requested_at: String,
+    pub approved_at: Option<String>,
+}
+
+#[derive(Insertable, Clone)]
+#[table_name = "contact_recoveries"]
+pub struct NewContactRecovery<'a> {
+    pub user_id: i32,
+    pub contact_email: &'a str,
+    pub token_hash: &'a str,
+    pub requested_at: String,
+}
+
+/// Latest sending of a notification to a recipient
+#[derive(Insertable, Debug, Clone)]
+#[table_name = "notification_dedupe"]
+pub struct NewNotification<'a> {
+    pub recipient: &'a str,
+    pub fingerprint: &'a str,
+    pub last_sent_at: String,
+    pub suppressed: i32,
+}
+
+/// Instance running a job, until the lease expires
+#[derive(Insertable, Debug, Clone)]
+#[table_name = "job_leases"]
+pub struct JobLease<'a> {
+    pub job: &'a str,
+    pub holder: &'a str,
+    pub expires_at: String,
+}
+
+/// Short-lived value that must outlive the process (see `ephemeral::SQliteTokenStore`)
+#[derive(Insertable, Debug, Clone)]
+#[table_name = "ephemeral_values"]
+pub struct EphemeralValue<'a> {
+    pub key: &'a str,
+    pub value: &'a str,
+    pub expires_at: String,
+}
+
+/// Action link already redeemed, kept until it expires so it can't be redeemed again
+#[derive(Insertable, Debug, Clone)]
+#[table_name = "redeemed_action_links"]
+pub struct RedeemedActionLink<'a> {
+    pub jti: &'a str,
+    pub expires_at: String,
+}
+
+/// Number of passwords of a strength score chosen in a context (e.g. at registration)
+/// Note: only the counts are kept, they can't be linked to a user
+#[derive(Queryable, Insertable, Debug, Clone, PartialEq)]
+#[table_name = "password_stats"]
+pub struct PasswordScoreCount {
+    pub context: String,
+    pub score: i32,
+    pub count: i32,
+}
+
+/// Entry of the audit log
+/// Note: the id is the sequence number of the event, it's increasing & never reused
+#[derive(Queryable, Debug, Clone, PartialEq)]
+pub struct AuditEntry {
+    pub id: i32,
+    pub user_id: Option<i32>,
+    pub event: String,
+    pub details: Option<String>,
+    pub created_at: String,
+    /// User concerned by a chained entry, kept once the user is deleted (see `audit::chain`)
+    pub subject_id: Option<i32>,
+    /// Hash chaining the entry to the previous one, unless the log wasn't tamper-evident yet
+    pub hash: Option<String>,
+}
+
+#[derive(Insertable, Debug, Clone)]
+#[table_name = "audit_log"]
+pub struct NewAuditEntry<'a> {
+    pub user_id: Option<i32>,
+    pub event: &'a str,
+    pub details: Option<&'a str>,
+    pub created_at: String,
+    pub subject_id: Option<i32>,
+    pub hash: Option<String>,
+}
+
+impl fmt::Debug for User {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("User")
+            .field("id", &self.id)
+            .field("email", &self.email)
+            .field("password", &Redacted)
+            .field("secret_2fa", &redact(&self.secret_2fa))
+            .field("anti_phishing_phrase", &redact(&self.anti_phishing_phrase))
+            .field("accepted_tos_version", &self.accepted_tos_version)
+            .field("pending_secret_2fa", &redact(&self.pending_secret_2fa))
+            .field("kind", &self.kind)
+            .finish()
+    }
+}
+
+impl fmt::Debug for NewUser<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("NewUser")
+            .field("email", &self.email)
+            .field("password", &Redacted)
+            .finish()
+    }
+}
+
+impl fmt::Debug for UserChangeset {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("UserChangeset")
+            .field("password", &redact(&self.password))
+            .field("secret_2fa", &self.secret_2fa.as_ref().map(redact))
+            .field(
+                "anti_phishing_phrase",
+                &self.anti_phishing_phrase.as_ref().map(redact),
+            )
+            .field("accepted_tos_version", &self.accepted_tos_version)
+            .field(
+                "pending_secret_2fa",
+                &self.pending_secret_2fa.as_ref().map(redact),
+            )
+            .field("kind", &self.kind)
+            .finish()
+    }
+}
+
+impl fmt::Debug for NewRecoveryCode<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("NewRecoveryCode")
+            .field("user_id", &self.user_id)
+            .field("code_hash", &Redacted)
+            .finish()
+    }
+}
+
+impl fmt::Debug for ContactRecovery {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ContactRecovery")
+            .field("id", &self.id)
+            .field("user_id", &self.user_id)
+            .field("contact_email", &self.contact_email)
+            .field("token_hash", &Redacted)
+            .field("requested_at", &self.requested_at)
+            .field("approved_at", &self.approved_at)
+            .finish()
+    }
+}
+
+impl fmt::Debug for NewContactRecovery<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("NewContactRecovery")
+            .field("user_id", &self.user_id)
+            .field("contact_email", &self.contact_email)
+            .field("token_hash", &Redacted)
+            .field("requested_at", &self.requested_at)
+            .finish()
+    }
+}
+
 impl User {
     /// Only exists for the unit tests
     pub fn new(email: &str, passwd: &str) -> Self {
@@ -28,13 +302,63 @@ impl User {
             email: email.to_string(),
             password: passwd.to_string(),
             secret_2fa: None,
-            reset_token: None,
-            reset_token_created_at: None,
+            anti_phishing_phrase: None,
+            accepted_tos_version: None,
+            pending_secret_2fa: None,
+            kind: AccountKind::Human.to_string(),
+        }
+    }
+
+    /// Same user with the id given by the storage (see `InMemoryUserRepository`)
+    pub(super) fn with_id(mut self, id: i32) -> Self {
+        self.id = id;
+        self
+    }
+
+    /// Apply the fields set in a changeset, like the databases do with it
+    pub(super) fn apply(&mut self, changes: &UserChangeset) {
+        let c = changes.clone();
+        if let Some(passwd) = c.password {
+            self.password = passwd;
+        }
+        if let Some(secret) = c.secret_2fa {
+            self.secret_2fa = secret;
+        }
+        if let Some(phrase) = c.anti_phishing_phrase {
+            self.anti_phishing_phrase = phrase;
+        }
+        if let Some(version) = c.accepted_tos_version {
+            self.accepted_tos_version = version;
+        }
+        if let Some(secret) = c.pending_secret_2fa {
+            self.pending_secret_2fa = secret;
+        }
+        if let Some(kind) = c.kind {
+            self.kind = kind;
         }
     }
 
     pub fn is_2fa_enabled(&self) -> bool {
-        self.secret_2fa != None
+        self.secret_2fa.is_some()
+    }
+
+    /// Note: an unknown kind is read as the default one of the column, i.e. `Human`
+    pub fn get_kind(&self) -> AccountKind {
+        AccountKind::from_str(&self.kind).unwrap_or(AccountKind::Human)
+    }
+
+    pub fn set_kind(&mut self, kind: AccountKind) {
+        self.kind = kind.to_string();
+    }
+
+    pub fn is_service(&self) -> bool {
+        self.get_kind() == AccountKind::Service
+    }
+
+    /// Tell if the password of the account is checked by the system, i.e. it can
+    /// login with it & reset it
+    pub fn has_local_password(&self) -> bool {
+        self.get_kind() == AccountKind::Human
     }
 
     // GETTERS & SETTERS
@@ -60,44 +384,84 @@ impl User {
     }
 
     pub fn get_secret_2fa(&self) -> Option<String> {
-        if let Some(s) = self.secret_2fa.clone() {
-            Some(s)
-        } else {
-            None
-        }
+        self.secret_2fa.clone()
     }
 
     pub fn set_secret_2fa(&mut self, secret: Option<String>) {
         self.secret_2fa = secret;
     }
 
-    pub fn get_reset_token(&self) -> Option<String> {
-        if let Some(s) = self.reset_token.clone() {
-            Some(s)
-        } else {
-            None
-        }
+    pub fn get_anti_phishing_phrase(&self) -> Option<String> {
+        self.anti_phishing_phrase.clone()
     }
 
-    pub fn set_reset_token(&mut self, token: &str) {
-        self.reset_token = Some(token.to_string());
-        self.reset_token_created_at = Some(Utc::now().to_rfc3339());
+    pub fn set_anti_phishing_phrase(&mut self, phrase: Option<String>) {
+        self.anti_phishing_phrase = phrase;
     }
 
-    /// Note: No setter was defined for `reset_token_created_at` because
-    /// it's only set when a new token is set.
-    pub fn get_reset_token_created_at(&self) -> Option<String> {
-        if let Some(s) = self.reset_token_created_at.clone() {
-            Some(s)
-        } else {
-            None
-        }
+    pub fn get_accepted_tos_version(&self) -> Option<i32> {
+        self.accepted_tos_version
+    }
+
+    pub fn set_accepted_tos_version(&mut self, version: Option<i32>) {
+        self.accepted_tos_version = version;
+    }
+
+    /// Note: the pending secret replaces the current one once the user confirmed
+    /// it works (see `twofa::confirm_rotation`)
+    pub fn get_pending_secret_2fa(&self) -> Option<String> {
+        self.pending_secret_2fa.clone()
+    }
+
+    pub fn set_pending_secret_2fa(&mut self, secret: Option<String>) {
+        self.pending_secret_2fa = secret;
+    }
+}
+
+impl UserChangeset {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Check if the changeset doesn't change anything
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    pub fn password(mut self, passwd: &str) -> Self {
+        self.password = Some(passwd.to_string());
+        self
+    }
+
+    pub fn secret_2fa(mut self, secret: Option<String>) -> Self {
+        self.secret_2fa = Some(secret);
+        self
+    }
+
+    pub fn anti_phishing_phrase(mut self, phrase: Option<String>) -> Self {
+        self.anti_phishing_phrase = Some(phrase);
+        self
+    }
+
+    pub fn accepted_tos_version(mut self, version: Option<i32>) -> Self {
+        self.accepted_tos_version = Some(version);
+        self
+    }
+
+    pub fn pending_secret_2fa(mut self, secret: Option<String>) -> Self {
+        self.pending_secret_2fa = Some(secret);
+        self
+    }
+
+    pub fn kind(mut self, kind: AccountKind) -> Self {
+        self.kind = Some(kind.to_string());
+        self
     }
 }
 
 #[cfg(test)]
 mod test {
-    use super::User;
+    use super::{AccountKind, User, UserChangeset};
 
     /**
      * Note: Only the "complicated" functions were tested.
@@ -112,33 +476,67 @@ mod test {
             email: "dummy@test.lo".to_string(),
             password: "hashedpasswd".to_string(),
             secret_2fa: Some("2fasecret".to_string()),
-            reset_token: None,
-            reset_token_created_at: None,
+            anti_phishing_phrase: None,
+            accepted_tos_version: None,
+            pending_secret_2fa: None,
+            kind: "human".to_string(),
         };
 
-        assert_eq!(dummy.is_2fa_enabled(), true);
+        assert!(dummy.is_2fa_enabled());
 
         dummy.set_secret_2fa(None);
-        assert_eq!(dummy.is_2fa_enabled(), false);
+        assert!(!dummy.is_2fa_enabled());
     }
 
     #[test]
-    fn test_set_reset_token() {
-        let mut dummy = User {
-            id: 1,
-            email: "dummy@test.lo".to_string(),
-            password: "hashedpasswd".to_string(),
-            secret_2fa: Some("2fasecret".to_string()),
-            reset_token: None,
-            reset_token_created_at: None,
-        };
+    fn test_debug_hides_secrets() {
+        let mut dummy = User::new("dummy@test.lo", "hashedpasswd");
+        dummy.set_secret_2fa(Some("2fasecret".to_string()));
+
+        let debug = format!("{:?}", dummy);
+        assert!(debug.contains("dummy@test.lo"));
+        assert!(debug.contains("secret_2fa: Some(<redacted>)"));
+        assert!(debug.contains("pending_secret_2fa: None"));
+        for secret in &["hashedpasswd", "2fasecret"] {
+            assert!(!debug.contains(secret));
+        }
+
+        let changes = UserChangeset::new()
+            .password("hashedpasswd")
+            .secret_2fa(None);
+        let debug = format!("{:?}", changes);
+        assert!(!debug.contains("hashedpasswd"));
+        assert!(debug.contains("secret_2fa: Some(None)"));
+    }
+
+    #[test]
+    fn test_changeset_is_empty() {
+        let empty = UserChangeset::new();
+        let cleared = UserChangeset::new().anti_phishing_phrase(None);
+
+        assert!(empty.is_empty());
+        assert!(!cleared.is_empty());
+        assert_eq!(cleared.anti_phishing_phrase, Some(None));
+    }
+
+    #[test]
+    fn test_kind() {
+        let mut dummy = User::new("dummy@test.lo", "hashedpasswd");
+        assert_eq!(dummy.get_kind(), AccountKind::Human);
+        assert!(!dummy.is_service());
+        assert!(dummy.has_local_password());
 
-        assert_eq!(dummy.get_reset_token(), None);
-        assert_eq!(dummy.get_reset_token_created_at(), None);
+        dummy.set_kind(AccountKind::Service);
+        assert_eq!(dummy.kind, "service");
+        assert!(dummy.is_service());
+        assert!(!dummy.has_local_password());
 
-        dummy.set_reset_token("token");
+        dummy.set_kind(AccountKind::Directory);
+        assert_eq!(dummy.kind, "directory");
+        assert!(!dummy.is_service());
+        assert!(!dummy.has_local_password());
 
-        assert_ne!(dummy.get_reset_token(), None);
-        assert_ne!(dummy.get_reset_token_created_at(), None);
+        dummy.kind = "robot".to_string();
+        assert_eq!(dummy.get_kind(), AccountKind::Human);
     }
 }
@@ -1,17 +1,61 @@
 /*!
  * All of the repositories to interact with the storage
  *
+ * Several processes (e.g. instances of an application) can share a database.
+ * The methods deciding between concurrent callers (`update_user_atomic`,
+ * `NotificationRepository::claim`, `ActionLinkRepository::redeem` &
+ * `LeaseRepository::acquire`) lock the rows they compare before writing
+ * (`BEGIN IMMEDIATE` on SQLite, `SELECT ... FOR UPDATE` on the servers), so
+ * exactly one of the callers wins, whatever process it runs in.
+ *
  * # Author
  * Doran Kayoumi <doran.kayoumi@heig-vd.ch>
  */
 
-use diesel::{insert_into, prelude::*, update};
+#[cfg(feature = "async")]
+mod asynchronous;
+mod file;
+mod memory;
+#[cfg(feature = "mysql")]
+mod mysql;
+#[cfg(feature = "postgres")]
+mod postgres;
+#[cfg(any(feature = "postgres", feature = "mysql"))]
+mod server;
+
+#[cfg(feature = "async")]
+pub(crate) use self::asynchronous::run_blocking;
+#[cfg(feature = "async")]
+pub use self::asynchronous::{AsyncUserRepository, SpawnBlocking};
+pub use self::file::FileUserRepository;
+pub use self::memory::InMemoryUserRepository;
+#[cfg(feature = "mysql")]
+pub use self::mysql::{MySqlOptions, MySqlUserRepository};
+#[cfg(feature = "postgres")]
+pub use self::postgres::PostgresUserRepository;
+
+use diesel::result::{DatabaseErrorKind, Error::DatabaseError};
+use diesel::sqlite::Sqlite;
+use diesel::{delete, insert_into, prelude::*, replace_into, update};
+use std::collections::{HashMap, VecDeque};
+
+use chrono::prelude::*;
 
-use super::establish_connection;
 use super::models::*;
-use super::schema::users::dsl::*;
+use super::schema::users as users_schema;
+use super::schema::users::dsl::{email, id, users};
+use super::schema::{
+    audit_log, contact_recoveries, job_leases, notification_dedupe, password_stats, recovery_codes,
+    redeemed_action_links, trusted_contacts, user_attributes,
+};
+use super::{database_url, establish_connection};
 
-use crate::errors::UserDBError;
+use crate::audit::{chain, AuditEvent};
+use crate::config::{self, AuditConfig};
+use crate::errors::{
+    ActionLinkDBError, AuditDBError, LeaseDBError, NotificationDBError, PasswordStatsDBError,
+    RecoveryCodeDBError, TrustedContactDBError, UserDBError,
+};
 
 pub trait UserRepository {
     /// Try and get a user from the storage
@@ -31,6 +75,9 @@ pub trait UserRepository {
     /// * `e` - email of the new user
     /// * `passwd` - password of the new user
     ///
+    /// # Note
+    /// If the email is already used, `UserDBError::EmailUsedError` is returned
+    ///
     fn create_user(&self, e: &str, passwd: &str) -> Result<(), UserDBError>;
 
     /// Try and update an existing user in the storage
@@ -40,10 +87,273 @@ pub trait UserRepository {
     ///
     /// * `u` - the user object containing all the information (changed or unchanged)
     ///
+    /// # Note
+    /// Every column is rewritten, which overwrites any change made concurrently by another flow.
+    /// Prefer `patch_user` to change specific fields.
+    ///
     fn update_user(&self, u: &User) -> Result<(), UserDBError>;
+
+    /// Try and apply changes to a single user, the fields not set in the changeset are left untouched
+    /// if something goes wrong, an error is returned
+    ///
+    /// # Arguments
+    ///
+    /// * `user` - id of the user to update
+    /// * `changes` - the changes to apply
+    ///
+    fn patch_user(&self, user: i32, changes: &UserChangeset) -> Result<(), UserDBError>;
+
+    /// Try and apply changes to a single user, only if she/he is still as she/he was read
+    /// The check & the changes happen in a single transaction, so a multi-step update
+    /// (e.g. consuming a reset token & changing the password) is never half applied
+    /// Returns whether the changes were applied, if something goes wrong, an error is returned
+    ///
+    /// # Arguments
+    ///
+    /// * `expected` - the user as she/he was read, the changes are dropped if the stored user differs
+    /// * `changes` - the changes to apply
+    ///
+    fn update_user_atomic(
+        &self,
+        expected: &User,
+        changes: &UserChangeset,
+    ) -> Result<bool, UserDBError>;
+
+    /// Try and delete a user and everything linked to her/him from the storage
+    /// if something goes wrong, an error is returned and nothing is deleted
+    ///
+    /// # Arguments
+    ///
+    /// * `user` - id of the user to delete
+    ///
+    fn delete_user(&self, user: i32) -> Result<(), UserDBError>;
+
+    /// Try and apply the same changes to every user matching a filter
+    /// The users are updated in chunks, each chunk in its own transaction
+    /// Returns the number of users updated, if something goes wrong, an error is returned
+    ///
+    /// # Arguments
+    ///
+    /// * `filter` - the criteria the users to update must match
+    /// * `changes` - the changes to apply
+    ///
+    fn update_many(
+        &self,
+        filter: &UserFilter,
+        changes: &UserChangeset,
+    ) -> Result<usize, UserDBError>;
+
+    /// Try and list the users matching a filter
+    /// if something goes wrong, an error is returned
+    ///
+    /// # Arguments
+    ///
+    /// * `filter` - the criteria the users must match
+    ///
+    fn list_users(&self, filter: &UserFilter) -> Result<Vec<User>, UserDBError>;
+
+    /// Iterate over the users matching a filter without loading them all in memory
+    /// if something goes wrong, an error is yielded and the iteration stops
+    ///
+    /// # Arguments
+    ///
+    /// * `filter` - the criteria the users must match
+    ///
+    fn iter_users(
+        &self,
+        filter: &UserFilter,
+    ) -> Box<dyn Iterator<Item = Result<User, UserDBError>>>;
+
+    /// Try and get the users whose email starts with a query, sorted by email (e.g. for type-ahead lookups)
+    /// The search is case sensitive, like the emails stored
+    /// if something goes wrong, an error is returned
+    ///
+    /// # Arguments
+    ///
+    /// * `query` - the beginning of the emails
+    /// * `limit` - the maximum number of users returned, capped by the configuration & can't be negative
+    ///
+    fn search_users(&self, query: &str, limit: i64) -> Result<Vec<User>, UserDBError>;
+
+    /// Try and get all the custom attributes of a user
+    /// if something goes wrong, an error is returned
+    ///
+    /// # Arguments
+    ///
+    /// * `user` - id of the user
+    ///
+    fn get_attributes(&self, user: i32) -> Result<HashMap<String, String>, UserDBError>;
+
+    /// Try and set (i.e. create or replace) a custom attribute of a user
+    /// if something goes wrong, an error is returned
+    ///
+    /// # Arguments
+    ///
+    /// * `user` - id of the user
+    /// * `attr` - name of the attribute (e.g. department)
+    /// * `val` - value of the attribute
+    ///
+    fn set_attribute(&self, user: i32, attr: &str, val: &str) -> Result<(), UserDBError>;
+
+    /// Try and remove a custom attribute of a user
+    /// if something goes wrong, an error is returned
+    ///
+    /// # Arguments
+    ///
+    /// * `user` - id of the user
+    /// * `attr` - name of the attribute to remove
+    ///
+    fn remove_attribute(&self, user: i32, attr: &str) -> Result<(), UserDBError>;
+}
+
+/// Criteria used to select users when listing them
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct UserFilter {
+    id: Option<i32>,
+    email: Option<String>,
+    attributes: Vec<(String, String)>,
 }
 
-pub struct SQliteUserRepository {}
+impl UserFilter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only keep the users having the given attribute set to the given value
+    pub fn with_attribute(mut self, attr: &str, val: &str) -> Self {
+        self.attributes.push((attr.to_string(), val.to_string()));
+        self
+    }
+
+    /// Only keep the user with the given id
+    pub fn with_id(mut self, user: i32) -> Self {
+        self.id = Some(user);
+        self
+    }
+
+    /// Only keep the user with the given email
+    pub fn with_email(mut self, e: &str) -> Self {
+        self.email = Some(e.to_string());
+        self
+    }
+}
+
+/// Number of users updated per transaction by `update_many`
+const UPDATE_CHUNK_SIZE: usize = 500;
+
+/// Number of users loaded at once by `iter_users`
+const ITER_PAGE_SIZE: i64 = 500;
+
+pub struct SQliteUserRepository {
+    database_url: String,
+}
+
+/// Build the query selecting the users matching a filter
+fn filtered_users(filter: &UserFilter) -> users_schema::BoxedQuery<'_, Sqlite> {
+    let mut query = users.into_boxed();
+    if let Some(user) = filter.id {
+        query = query.filter(id.eq(user));
+    }
+    if let Some(e) = &filter.email {
+        query = query.filter(email.eq(e));
+    }
+    for (attr, val) in &filter.attributes {
+        query = query.filter(
+            id.eq_any(
+                user_attributes::table
+                    .select(user_attributes::user_id)
+                    .filter(user_attributes::name.eq(attr))
+                    .filter(user_attributes::value.eq(val)),
+            ),
+        );
+    }
+
+    query
+}
+
+/// Map a failed query on the users
+/// Note: SQLite waits for a lock up to the busy timeout of the configuration (see `config.rs`),
+///       a database still locked after it is a timeout
+///
+/// # Arguments
+///
+/// * `e` - the error of the query
+///
+/// * `otherwise` - the error for the other failures
+///
+fn user_query_error(e: diesel::result::Error, otherwise: UserDBError) -> UserDBError {
+    if is_locked(&e) {
+        UserDBError::Timeout
+    } else {
+        otherwise
+    }
+}
+
+/// Map a failed query on the audit log, see `user_query_error`
+fn audit_query_error(e: diesel::result::Error, otherwise: AuditDBError) -> AuditDBError {
+    if is_locked(&e) {
+        AuditDBError::Timeout
+    } else {
+        otherwise
+    }
+}
+
+/// Map a failed query on the recovery codes, see `user_query_error`
+fn code_query_error(
+    e: diesel::result::Error,
+    otherwise: RecoveryCodeDBError,
+) -> RecoveryCodeDBError {
+    if is_locked(&e) {
+        RecoveryCodeDBError::Timeout
+    } else {
+        otherwise
+    }
+}
+
+/// Get the number of users a search may return, at most the `search_limit` of the configuration
+/// a negative limit is refused, the databases would read it as no limit at all
+///
+/// # Arguments
+///
+/// * `limit` - the limit asked by the caller
+///
+fn search_limit(limit: i64) -> Result<i64, UserDBError> {
+    if limit < 0 {
+        return Err(UserDBError::ListUsersError);
+    }
+
+    Ok(limit.min(config::get().database.search_limit))
+}
+
+/// Whether a query failed because the database was still locked after the busy timeout
+fn is_locked(e: &diesel::result::Error) -> bool {
+    matches!(e, DatabaseError(_, info) if info.message().contains("is locked"))
+}
+
+impl SQliteUserRepository {
+    /// Repository using the database set in the `.env` file
+    pub fn new() -> Self {
+        Self::with_database_url(&database_url())
+    }
+
+    /// Repository using a specific database
+    ///
+    /// # Arguments
+    ///
+    /// * `url` - url of the SQLite database
+    ///
+    pub fn with_database_url(url: &str) -> Self {
+        Self {
+            database_url: url.to_string(),
+        }
+    }
+}
+
+impl Default for SQliteUserRepository {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
 #[cfg(test)]
 use mockall::{automock, predicate::*};
@@ -52,14 +362,12 @@ use mockall::{automock, predicate::*};
 /// Implementation of the `UserRepository` with SQLite as a storage
 impl UserRepository for SQliteUserRepository {
     fn get_user(&self, e: &str) -> Result<User, UserDBError> {
-        let conn = establish_connection();
-        let res = users.filter(email.eq(e)).first::<User>(&conn);
-
-        if let Err(_) = res {
-            Err(UserDBError::GetUserError)
-        } else {
-            Ok(res.unwrap())
-        }
+        let conn =
+            establish_connection(&self.database_url).map_err(|_| UserDBError::GetUserError)?;
+        users
+            .filter(email.eq(e))
+            .first::<User>(&conn)
+            .map_err(|e| user_query_error(e, UserDBError::GetUserError))
     }
 
     fn create_user(&self, e: &str, passwd: &str) -> Result<(), UserDBError> {
@@ -68,23 +376,2310 @@ impl UserRepository for SQliteUserRepository {
             password: passwd,
         };
 
-        let conn = establish_connection();
-        if let Err(_) = insert_into(users).values(u).execute(&conn) {
-            return Err(UserDBError::CreateUserError);
+        let conn =
+            establish_connection(&self.database_url).map_err(|_| UserDBError::CreateUserError)?;
+        match insert_into(users).values(u).execute(&conn) {
+            Ok(_) => Ok(()),
+            // the emails are unique (see the `add_unique_email_index` migration)
+            Err(DatabaseError(DatabaseErrorKind::UniqueViolation, _)) => {
+                Err(UserDBError::EmailUsedError)
+            }
+            Err(e) => Err(user_query_error(e, UserDBError::CreateUserError)),
         }
-
-        Ok(())
     }
 
     fn update_user(&self, u: &User) -> Result<(), UserDBError> {
-        let conn = establish_connection();
-        if let Err(_) = update(users.filter(id.eq(u.get_id())))
+        let conn =
+            establish_connection(&self.database_url).map_err(|_| UserDBError::UpdateUserError)?;
+        update(users.filter(id.eq(u.get_id())))
             .set(u)
             .execute(&conn)
+            .map(|_| ())
+            .map_err(|e| user_query_error(e, UserDBError::UpdateUserError))
+    }
+
+    fn patch_user(&self, user: i32, changes: &UserChangeset) -> Result<(), UserDBError> {
+        // diesel refuses to run an update without any change
+        if changes.is_empty() {
+            return Ok(());
+        }
+
+        let conn =
+            establish_connection(&self.database_url).map_err(|_| UserDBError::UpdateUserError)?;
+        update(users.filter(id.eq(user)))
+            .set(changes)
+            .execute(&conn)
+            .map(|_| ())
+            .map_err(|e| user_query_error(e, UserDBError::UpdateUserError))
+    }
+
+    fn update_user_atomic(
+        &self,
+        expected: &User,
+        changes: &UserChangeset,
+    ) -> Result<bool, UserDBError> {
+        let conn =
+            establish_connection(&self.database_url).map_err(|_| UserDBError::UpdateUserError)?;
+
+        // Note: the write lock is taken before the read, so no other connection can
+        //       change the user in between
+        conn.immediate_transaction::<_, diesel::result::Error, _>(|| {
+            let stored = users
+                .filter(id.eq(expected.get_id()))
+                .first::<User>(&conn)
+                .optional()?;
+            if stored.as_ref() != Some(expected) {
+                return Ok(false);
+            }
+            // diesel refuses to run an update without any change
+            if !changes.is_empty() {
+                update(users.filter(id.eq(expected.get_id())))
+                    .set(changes)
+                    .execute(&conn)?;
+            }
+
+            Ok(true)
+        })
+        .map_err(|e| user_query_error(e, UserDBError::UpdateUserError))
+    }
+
+    fn delete_user(&self, user: i32) -> Result<(), UserDBError> {
+        let conn =
+            establish_connection(&self.database_url).map_err(|_| UserDBError::DeleteUserError)?;
+
+        // the foreign keys already take care of it, but the cleanup is done
+        // explicitly as well so it doesn't depend on the connection settings
+        conn.transaction::<_, diesel::result::Error, _>(|| {
+            delete(user_attributes::table.filter(user_attributes::user_id.eq(user)))
+                .execute(&conn)?;
+            delete(recovery_codes::table.filter(recovery_codes::user_id.eq(user)))
+                .execute(&conn)?;
+            update(audit_log::table.filter(audit_log::user_id.eq(user)))
+                .set(audit_log::user_id.eq(None::<i32>))
+                .execute(&conn)?;
+            delete(users.filter(id.eq(user))).execute(&conn)?;
+
+            Ok(())
+        })
+        .map_err(|_| UserDBError::DeleteUserError)
+    }
+
+    fn update_many(
+        &self,
+        filter: &UserFilter,
+        changes: &UserChangeset,
+    ) -> Result<usize, UserDBError> {
+        // diesel refuses to run an update without any change
+        if changes.is_empty() {
+            return Ok(0);
+        }
+
+        let conn =
+            establish_connection(&self.database_url).map_err(|_| UserDBError::UpdateUserError)?;
+        let ids = filtered_users(filter)
+            .select(id)
+            .order(id)
+            .load::<i32>(&conn)
+            .map_err(|_| UserDBError::UpdateUserError)?;
+
+        let mut updated = 0;
+        for chunk in ids.chunks(UPDATE_CHUNK_SIZE) {
+            updated += conn
+                .transaction::<_, diesel::result::Error, _>(|| {
+                    update(users.filter(id.eq_any(chunk)))
+                        .set(changes)
+                        .execute(&conn)
+                })
+                .map_err(|_| UserDBError::UpdateUserError)?;
+        }
+
+        Ok(updated)
+    }
+
+    fn list_users(&self, filter: &UserFilter) -> Result<Vec<User>, UserDBError> {
+        let conn =
+            establish_connection(&self.database_url).map_err(|_| UserDBError::ListUsersError)?;
+
+        filtered_users(filter)
+            .order(id)
+            .load::<User>(&conn)
+            .map_err(|e| user_query_error(e, UserDBError::ListUsersError))
+    }
+
+    fn iter_users(
+        &self,
+        filter: &UserFilter,
+    ) -> Box<dyn Iterator<Item = Result<User, UserDBError>>> {
+        Box::new(UserIter::new(&self.database_url, filter, ITER_PAGE_SIZE))
+    }
+
+    fn search_users(&self, query: &str, limit: i64) -> Result<Vec<User>, UserDBError> {
+        let limit = search_limit(limit)?;
+        let conn =
+            establish_connection(&self.database_url).map_err(|_| UserDBError::ListUsersError)?;
+
+        // Note: a range rather than `like`, which is case insensitive & can't use the unique email index
+        users
+            .filter(email.ge(query))
+            .filter(email.lt(format!("{}{}", query, char::MAX)))
+            .order(email)
+            .limit(limit)
+            .load::<User>(&conn)
+            .map_err(|e| user_query_error(e, UserDBError::ListUsersError))
+    }
+
+    fn get_attributes(&self, user: i32) -> Result<HashMap<String, String>, UserDBError> {
+        let conn = establish_connection(&self.database_url)
+            .map_err(|_| UserDBError::GetAttributesError)?;
+        let attrs = user_attributes::table
+            .filter(user_attributes::user_id.eq(user))
+            .load::<UserAttribute>(&conn)
+            .map_err(|e| user_query_error(e, UserDBError::GetAttributesError))?;
+
+        Ok(attrs.into_iter().map(|a| (a.name, a.value)).collect())
+    }
+
+    fn set_attribute(&self, user: i32, attr: &str, val: &str) -> Result<(), UserDBError> {
+        let a = UserAttribute {
+            user_id: user,
+            name: attr.to_string(),
+            value: val.to_string(),
+        };
+
+        let conn = establish_connection(&self.database_url)
+            .map_err(|_| UserDBError::UpdateAttributesError)?;
+        if replace_into(user_attributes::table)
+            .values(&a)
+            .execute(&conn)
+            .is_err()
+        {
+            return Err(UserDBError::UpdateAttributesError);
+        }
+
+        Ok(())
+    }
+
+    fn remove_attribute(&self, user: i32, attr: &str) -> Result<(), UserDBError> {
+        let conn = establish_connection(&self.database_url)
+            .map_err(|_| UserDBError::UpdateAttributesError)?;
+        if delete(
+            user_attributes::table
+                .filter(user_attributes::user_id.eq(user))
+                .filter(user_attributes::name.eq(attr)),
+        )
+        .execute(&conn)
+        .is_err()
         {
-            return Err(UserDBError::UpdateUserError);
+            return Err(UserDBError::UpdateAttributesError);
+        }
+
+        Ok(())
+    }
+}
+
+/// Iterator over the users of a SQLite database
+/// The users are loaded page by page, ordered by id
+struct UserIter {
+    /// `None` if the database couldn't be reached, the iteration then yields the error
+    conn: Option<SqliteConnection>,
+    filter: UserFilter,
+    page_size: i64,
+    page: VecDeque<User>,
+    last_id: i32,
+    done: bool,
+}
+
+impl UserIter {
+    fn new(database_url: &str, filter: &UserFilter, page_size: i64) -> Self {
+        Self {
+            conn: establish_connection(database_url).ok(),
+            filter: filter.clone(),
+            page_size,
+            page: VecDeque::new(),
+            last_id: 0,
+            done: false,
         }
+    }
+
+    /// Load the users following the last one returned
+    fn load_next_page(&mut self) -> Result<(), UserDBError> {
+        let conn = self.conn.as_ref().ok_or(UserDBError::ListUsersError)?;
+        let page = filtered_users(&self.filter)
+            .filter(id.gt(self.last_id))
+            .order(id)
+            .limit(self.page_size)
+            .load::<User>(conn)
+            .map_err(|_| UserDBError::ListUsersError)?;
+
+        // a partial page means there's nothing left after it
+        self.done = (page.len() as i64) < self.page_size;
+        self.page.extend(page);
 
         Ok(())
     }
 }
+
+impl Iterator for UserIter {
+    type Item = Result<User, UserDBError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.page.is_empty() && !self.done {
+            if let Err(e) = self.load_next_page() {
+                self.done = true;
+                return Some(Err(e));
+            }
+        }
+
+        let u = self.page.pop_front()?;
+        self.last_id = u.get_id();
+
+        Some(Ok(u))
+    }
+}
+
+/// Wrapper around a `UserRepository` that only allows lookups
+/// Every mutation is rejected, which is useful when pointing query-heavy
+/// components (e.g. exports) at a replica or a snapshot of the storage
+pub struct ReadOnlyUserRepository<R: UserRepository> {
+    inner: R,
+}
+
+impl<R: UserRepository> ReadOnlyUserRepository<R> {
+    /// # Arguments
+    ///
+    /// * `inner` - the repository used for the lookups
+    ///
+    pub fn new(inner: R) -> Self {
+        Self { inner }
+    }
+}
+
+impl<R: UserRepository> UserRepository for ReadOnlyUserRepository<R> {
+    fn get_user(&self, e: &str) -> Result<User, UserDBError> {
+        self.inner.get_user(e)
+    }
+
+    fn create_user(&self, _e: &str, _passwd: &str) -> Result<(), UserDBError> {
+        Err(UserDBError::ReadOnlyError)
+    }
+
+    fn update_user(&self, _u: &User) -> Result<(), UserDBError> {
+        Err(UserDBError::ReadOnlyError)
+    }
+
+    fn patch_user(&self, _user: i32, _changes: &UserChangeset) -> Result<(), UserDBError> {
+        Err(UserDBError::ReadOnlyError)
+    }
+
+    fn update_user_atomic(
+        &self,
+        _expected: &User,
+        _changes: &UserChangeset,
+    ) -> Result<bool, UserDBError> {
+        Err(UserDBError::ReadOnlyError)
+    }
+
+    fn delete_user(&self, _user: i32) -> Result<(), UserDBError> {
+        Err(UserDBError::ReadOnlyError)
+    }
+
+    fn update_many(
+        &self,
+        _filter: &UserFilter,
+        _changes: &UserChangeset,
+    ) -> Result<usize, UserDBError> {
+        Err(UserDBError::ReadOnlyError)
+    }
+
+    fn list_users(&self, filter: &UserFilter) -> Result<Vec<User>, UserDBError> {
+        self.inner.list_users(filter)
+    }
+
+    fn iter_users(
+        &self,
+        filter: &UserFilter,
+    ) -> Box<dyn Iterator<Item = Result<User, UserDBError>>> {
+        self.inner.iter_users(filter)
+    }
+
+    fn search_users(&self, query: &str, limit: i64) -> Result<Vec<User>, UserDBError> {
+        self.inner.search_users(query, limit)
+    }
+
+    fn get_attributes(&self, user: i32) -> Result<HashMap<String, String>, UserDBError> {
+        self.inner.get_attributes(user)
+    }
+
+    fn set_attribute(&self, _user: i32, _attr: &str, _val: &str) -> Result<(), UserDBError> {
+        Err(UserDBError::ReadOnlyError)
+    }
+
+    fn remove_attribute(&self, _user: i32, _attr: &str) -> Result<(), UserDBError> {
+        Err(UserDBError::ReadOnlyError)
+    }
+}
+
+pub trait AuditRepository {
+    /// Try and add a new entry to the audit log
+    /// if something goes wrong, an error is returned
+    ///
+    /// # Arguments
+    ///
+    /// * `user` - id of the user concerned by the event (if any)
+    /// * `event` - name of the event
+    /// * `details` - additional information about the event
+    ///
+    fn create_entry(
+        &self,
+        user: Option<i32>,
+        event: &str,
+        details: Option<String>,
+    ) -> Result<(), AuditDBError>;
+
+    /// Try and get the date of the latest occurrence of an event for a user
+    /// `None` is returned if the event never happened to the user
+    /// if something goes wrong, an error is returned
+    ///
+    /// # Arguments
+    ///
+    /// * `user` - id of the user concerned by the event
+    /// * `event` - name of the event
+    ///
+    fn last_occurrence(&self, user: i32, event: &str) -> Result<Option<String>, AuditDBError>;
+
+    /// Try and get the dates of the occurrences of an event since a date, for all the users
+    /// if something goes wrong, an error is returned
+    ///
+    /// # Arguments
+    ///
+    /// * `event` - name of the event
+    /// * `since` - the date from which the occurrences are kept
+    ///
+    fn occurrences_since(
+        &self,
+        event: &str,
+        since: DateTime<Utc>,
+    ) -> Result<Vec<String>, AuditDBError>;
+
+    /// Try and get the entries following a sequence number, in the order they were added
+    /// if something goes wrong, an error is returned
+    ///
+    /// # Arguments
+    ///
+    /// * `seq` - sequence number of the last entry already read (0 to start from the beginning)
+    /// * `limit` - maximum number of entries returned
+    ///
+    fn entries_after(&self, seq: i32, limit: i64) -> Result<Vec<AuditEntry>, AuditDBError>;
+}
+
+pub struct SQliteAuditRepository {
+    database_url: String,
+    /// Whether the entries are chained & how often they're signed (see `audit::chain`)
+    audit: AuditConfig,
+    /// Key signing the checkpoints, they're skipped without it
+    checkpoint_secret: Option<String>,
+}
+
+impl SQliteAuditRepository {
+    /// Repository using the database set in the `.env` file, tamper-evident if configured
+    pub fn new() -> Self {
+        Self::with_database_url(&database_url()).with_config(config::get().audit, chain::secret())
+    }
+
+    /// Repository using a specific database
+    ///
+    /// # Arguments
+    ///
+    /// * `url` - url of the SQLite database
+    ///
+    pub fn with_database_url(url: &str) -> Self {
+        Self {
+            database_url: url.to_string(),
+            audit: AuditConfig::default(),
+            checkpoint_secret: None,
+        }
+    }
+
+    /// Chain the new entries or not (see `audit::chain`)
+    ///
+    /// # Arguments
+    ///
+    /// * `audit` - the `[audit]` section of the configuration
+    ///
+    /// * `checkpoint_secret` - the key signing the checkpoints (if any)
+    ///
+    pub fn with_config(mut self, audit: AuditConfig, checkpoint_secret: Option<String>) -> Self {
+        self.audit = audit;
+        self.checkpoint_secret = checkpoint_secret;
+        self
+    }
+}
+
+/// Append an entry chained to the last one of the audit log (see `audit::chain`)
+/// Returns the sequence number & the hash of the entry
+///
+/// # Arguments
+///
+/// * `conn` - connection to the database, in a transaction locking it
+/// * `user` - id of the user concerned by the event (if any)
+/// * `event` - name of the event
+/// * `details` - additional information about the event
+///
+fn append_chained(
+    conn: &SqliteConnection,
+    user: Option<i32>,
+    event: &str,
+    details: Option<&str>,
+) -> Result<(i32, String), diesel::result::Error> {
+    // Note: the chain restarts if the last entry isn't chained, the verification reports it
+    let previous = audit_log::table
+        .order(audit_log::id.desc())
+        .select(audit_log::hash)
+        .first::<Option<String>>(conn)
+        .optional()?
+        .flatten();
+    let created_at = Utc::now().to_rfc3339();
+    let hash = chain::entry_hash(
+        previous.as_deref().unwrap_or(chain::GENESIS),
+        user,
+        event,
+        details,
+        &created_at,
+    );
+
+    insert_into(audit_log::table)
+        .values(NewAuditEntry {
+            user_id: user,
+            event,
+            details,
+            created_at,
+            subject_id: user,
+            hash: Some(hash.clone()),
+        })
+        .execute(conn)?;
+    let seq = audit_log::table
+        .order(audit_log::id.desc())
+        .select(audit_log::id)
+        .first::<i32>(conn)?;
+
+    Ok((seq, hash))
+}
+
+/// Check if a checkpoint is due, i.e. enough entries were chained since the last one
+///
+/// # Arguments
+///
+/// * `conn` - connection to the database
+/// * `every` - entries between two checkpoints, 0 if there are none
+///
+fn is_checkpoint_due(conn: &SqliteConnection, every: u32) -> Result<bool, diesel::result::Error> {
+    if every == 0 {
+        return Ok(false);
+    }
+
+    let last = audit_log::table
+        .filter(audit_log::event.eq(AuditEvent::AuditCheckpoint.to_string()))
+        .order(audit_log::id.desc())
+        .select(audit_log::id)
+        .first::<i32>(conn)
+        .optional()?
+        .unwrap_or(0);
+    let chained = audit_log::table
+        .filter(audit_log::id.gt(last))
+        .filter(audit_log::hash.is_not_null())
+        .count()
+        .get_result::<i64>(conn)?;
+
+    Ok(chained >= i64::from(every))
+}
+
+impl Default for SQliteAuditRepository {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg_attr(test, automock)]
+/// Implementation of the `AuditRepository` with SQLite as a storage
+impl AuditRepository for SQliteAuditRepository {
+    fn create_entry(
+        &self,
+        user: Option<i32>,
+        event: &str,
+        details: Option<String>,
+    ) -> Result<(), AuditDBError> {
+        let conn =
+            establish_connection(&self.database_url).map_err(|_| AuditDBError::CreateEntryError)?;
+
+        if !self.audit.tamper_evident {
+            let entry = NewAuditEntry {
+                user_id: user,
+                event,
+                details: details.as_deref(),
+                created_at: Utc::now().to_rfc3339(),
+                subject_id: None,
+                hash: None,
+            };
+            return insert_into(audit_log::table)
+                .values(entry)
+                .execute(&conn)
+                .map(|_| ())
+                .map_err(|e| audit_query_error(e, AuditDBError::CreateEntryError));
+        }
+
+        // Note: the database is locked right away, so two entries can't follow the same one
+        conn.immediate_transaction::<_, diesel::result::Error, _>(|| {
+            let (seq, hash) = append_chained(&conn, user, event, details.as_deref())?;
+            if !is_checkpoint_due(&conn, self.audit.checkpoint_every)? {
+                return Ok(());
+            }
+            // Note: the checkpoints are skipped until the key is set
+            let signature = self
+                .checkpoint_secret
+                .as_deref()
+                .and_then(|secret| chain::sign_checkpoint(seq, &hash, secret));
+            if let Some(signature) = signature {
+                let checkpoint = AuditEvent::AuditCheckpoint.to_string();
+                append_chained(&conn, None, &checkpoint, Some(&signature))?;
+            }
+
+            Ok(())
+        })
+        .map_err(|e| audit_query_error(e, AuditDBError::CreateEntryError))
+    }
+
+    fn last_occurrence(&self, user: i32, event: &str) -> Result<Option<String>, AuditDBError> {
+        let conn =
+            establish_connection(&self.database_url).map_err(|_| AuditDBError::ReadEntryError)?;
+
+        audit_log::table
+            .filter(audit_log::user_id.eq(user))
+            .filter(audit_log::event.eq(event))
+            .order(audit_log::id.desc())
+            .select(audit_log::created_at)
+            .first::<String>(&conn)
+            .optional()
+            .map_err(|e| audit_query_error(e, AuditDBError::ReadEntryError))
+    }
+
+    fn occurrences_since(
+        &self,
+        event: &str,
+        since: DateTime<Utc>,
+    ) -> Result<Vec<String>, AuditDBError> {
+        let conn =
+            establish_connection(&self.database_url).map_err(|_| AuditDBError::ReadEntryError)?;
+
+        // Note: the dates are all stored in UTC, so they can be compared as text
+        audit_log::table
+            .filter(audit_log::event.eq(event))
+            .filter(audit_log::created_at.ge(since.to_rfc3339()))
+            .order(audit_log::id)
+            .select(audit_log::created_at)
+            .load::<String>(&conn)
+            .map_err(|e| audit_query_error(e, AuditDBError::ReadEntryError))
+    }
+
+    fn entries_after(&self, seq: i32, limit: i64) -> Result<Vec<AuditEntry>, AuditDBError> {
+        let conn =
+            establish_connection(&self.database_url).map_err(|_| AuditDBError::ReadEntryError)?;
+
+        audit_log::table
+            .filter(audit_log::id.gt(seq))
+            .order(audit_log::id)
+            .limit(limit)
+            .load::<AuditEntry>(&conn)
+            .map_err(|e| audit_query_error(e, AuditDBError::ReadEntryError))
+    }
+}
+
+pub trait RecoveryCodeRepository {
+    /// Try and replace all the recovery codes of a user at once, either all of
+    /// the old codes are removed & the new ones added or nothing changes
+    /// if something goes wrong, an error is returned
+    ///
+    /// # Arguments
+    ///
+    /// * `user` - id of the user owning the codes
+    /// * `hashes` - hashes of the new codes
+    ///
+    fn replace_codes(&self, user: i32, hashes: &[String]) -> Result<(), RecoveryCodeDBError>;
+
+    /// Try and mark an unused recovery code as used
+    /// Returns whether an unused code matched
+    /// if something goes wrong, an error is returned
+    ///
+    /// # Arguments
+    ///
+    /// * `user` - id of the user owning the code
+    /// * `hash` - hash of the code
+    ///
+    fn use_code(&self, user: i32, hash: &str) -> Result<bool, RecoveryCodeDBError>;
+
+    /// Try and count the unused recovery codes of a user
+    /// if something goes wrong, an error is returned
+    ///
+    /// # Arguments
+    ///
+    /// * `user` - id of the user owning the codes
+    ///
+    fn count_unused_codes(&self, user: i32) -> Result<i64, RecoveryCodeDBError>;
+}
+
+pub struct SQliteRecoveryCodeRepository {
+    database_url: String,
+}
+
+impl SQliteRecoveryCodeRepository {
+    /// Repository using the database set in the `.env` file
+    pub fn new() -> Self {
+        Self::with_database_url(&database_url())
+    }
+
+    /// Repository using a specific database
+    ///
+    /// # Arguments
+    ///
+    /// * `url` - url of the SQLite database
+    ///
+    pub fn with_database_url(url: &str) -> Self {
+        Self {
+            database_url: url.to_string(),
+        }
+    }
+}
+
+impl Default for SQliteRecoveryCodeRepository {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg_attr(test, automock)]
+/// Implementation of the `RecoveryCodeRepository` with SQLite as a storage
+impl RecoveryCodeRepository for SQliteRecoveryCodeRepository {
+    fn replace_codes(&self, user: i32, hashes: &[String]) -> Result<(), RecoveryCodeDBError> {
+        let conn = establish_connection(&self.database_url)
+            .map_err(|_| RecoveryCodeDBError::ReplaceCodesError)?;
+        let codes: Vec<NewRecoveryCode> = hashes
+            .iter()
+            .map(|h| NewRecoveryCode {
+                user_id: user,
+                code_hash: h,
+            })
+            .collect();
+
+        conn.transaction::<_, diesel::result::Error, _>(|| {
+            delete(recovery_codes::table.filter(recovery_codes::user_id.eq(user)))
+                .execute(&conn)?;
+            if !codes.is_empty() {
+                insert_into(recovery_codes::table)
+                    .values(&codes)
+                    .execute(&conn)?;
+            }
+
+            Ok(())
+        })
+        .map_err(|e| code_query_error(e, RecoveryCodeDBError::ReplaceCodesError))
+    }
+
+    fn use_code(&self, user: i32, hash: &str) -> Result<bool, RecoveryCodeDBError> {
+        let conn = establish_connection(&self.database_url)
+            .map_err(|_| RecoveryCodeDBError::UseCodeError)?;
+
+        // Note: a single statement, so the same code can't be used twice concurrently
+        update(
+            recovery_codes::table
+                .filter(recovery_codes::user_id.eq(user))
+                .filter(recovery_codes::code_hash.eq(hash))
+                .filter(recovery_codes::used_at.is_null()),
+        )
+        .set(recovery_codes::used_at.eq(Some(Utc::now().to_rfc3339())))
+        .execute(&conn)
+        .map(|n| n > 0)
+        .map_err(|e| code_query_error(e, RecoveryCodeDBError::UseCodeError))
+    }
+
+    fn count_unused_codes(&self, user: i32) -> Result<i64, RecoveryCodeDBError> {
+        let conn = establish_connection(&self.database_url)
+            .map_err(|_| RecoveryCodeDBError::CountCodesError)?;
+
+        recovery_codes::table
+            .filter(recovery_codes::user_id.eq(user))
+            .filter(recovery_codes::used_at.is_null())
+            .count()
+            .get_result(&conn)
+            .map_err(|e| code_query_error(e, RecoveryCodeDBError::CountCodesError))
+    }
+}
+
+pub trait TrustedContactRepository {
+    /// Try and get the emails of the trusted contacts of a user
+    /// if something goes wrong, an error is returned
+    ///
+    /// # Arguments
+    ///
+    /// * `user` - id of the user
+    ///
+    fn list_contacts(&self, user: i32) -> Result<Vec<String>, TrustedContactDBError>;
+
+    /// Try and add a trusted contact to a user, adding an existing one does nothing
+    /// if something goes wrong, an error is returned
+    ///
+    /// # Arguments
+    ///
+    /// * `user` - id of the user
+    /// * `e` - email of the contact
+    ///
+    fn add_contact(&self, user: i32, e: &str) -> Result<(), TrustedContactDBError>;
+
+    /// Try and remove a trusted contact of a user
+    /// if something goes wrong, an error is returned
+    ///
+    /// # Arguments
+    ///
+    /// * `user` - id of the user
+    /// * `e` - email of the contact
+    ///
+    fn remove_contact(&self, user: i32, e: &str) -> Result<(), TrustedContactDBError>;
+
+    /// Try and get the emails of the trusted contacts who confirmed they accept the role
+    /// if something goes wrong, an error is returned
+    ///
+    /// # Arguments
+    ///
+    /// * `user` - id of the user
+    ///
+    fn list_confirmed_contacts(&self, user: i32) -> Result<Vec<String>, TrustedContactDBError>;
+
+    /// Try and set the hash of the token a trusted contact confirms the role with, it
+    /// replaces the one sent before. Returns whether an unconfirmed contact matched
+    /// if something goes wrong, an error is returned
+    ///
+    /// # Arguments
+    ///
+    /// * `user` - id of the user
+    /// * `e` - email of the contact
+    /// * `hash` - hash of the confirmation token
+    ///
+    fn request_confirmation(
+        &self,
+        user: i32,
+        e: &str,
+        hash: &str,
+    ) -> Result<bool, TrustedContactDBError>;
+
+    /// Try and confirm a trusted contact with the hash of a confirmation token
+    /// Returns whether an unconfirmed contact matched
+    /// if something goes wrong, an error is returned
+    ///
+    /// # Arguments
+    ///
+    /// * `hash` - hash of the confirmation token
+    /// * `now` - when the contact confirmed
+    ///
+    fn confirm_contact(
+        &self,
+        hash: &str,
+        now: DateTime<Utc>,
+    ) -> Result<bool, TrustedContactDBError>;
+
+    /// Try and start a recovery, it replaces the one the user started before (if any)
+    /// if something goes wrong, an error is returned
+    ///
+    /// # Arguments
+    ///
+    /// * `user` - id of the user
+    /// * `approvals` - email of each contact & hash of her/his approval token
+    /// * `requested_at` - when the recovery was started
+    ///
+    fn start_recovery(
+        &self,
+        user: i32,
+        approvals: &[(String, String)],
+        requested_at: DateTime<Utc>,
+    ) -> Result<(), TrustedContactDBError>;
+
+    /// Try and approve a recovery with the hash of an approval token
+    /// Returns whether a pending approval matched
+    /// if something goes wrong, an error is returned
+    ///
+    /// # Arguments
+    ///
+    /// * `hash` - hash of the approval token
+    /// * `now` - when the approval is given
+    ///
+    fn approve(&self, hash: &str, now: DateTime<Utc>) -> Result<bool, TrustedContactDBError>;
+
+    /// Try and get the approvals of the recovery a user started
+    /// if something goes wrong, an error is returned
+    ///
+    /// # Arguments
+    ///
+    /// * `user` - id of the user
+    ///
+    fn get_recovery(&self, user: i32) -> Result<Vec<ContactRecovery>, TrustedContactDBError>;
+
+    /// Try and forget the recovery a user started (e.g. it was completed or cancelled)
+    /// if something goes wrong, an error is returned
+    ///
+    /// # Arguments
+    ///
+    /// * `user` - id of the user
+    ///
+    fn clear_recovery(&self, user: i32) -> Result<(), TrustedContactDBError>;
+}
+
+pub struct SQliteTrustedContactRepository {
+    database_url: String,
+}
+
+impl SQliteTrustedContactRepository {
+    /// Repository using the database set in the `.env` file
+    pub fn new() -> Self {
+        Self::with_database_url(&database_url())
+    }
+
+    /// Repository using a specific database
+    ///
+    /// # Arguments
+    ///
+    /// * `url` - url of the SQLite database
+    ///
+    pub fn with_database_url(url: &str) -> Self {
+        Self {
+            database_url: url.to_string(),
+        }
+    }
+}
+
+impl Default for SQliteTrustedContactRepository {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg_attr(test, automock)]
+/// Implementation of the `TrustedContactRepository` with SQLite as a storage
+impl TrustedContactRepository for SQliteTrustedContactRepository {
+    fn list_contacts(&self, user: i32) -> Result<Vec<String>, TrustedContactDBError> {
+        let conn = establish_connection(&self.database_url)
+            .map_err(|_| TrustedContactDBError::ReadContactsError)?;
+
+        trusted_contacts::table
+            .filter(trusted_contacts::user_id.eq(user))
+            .order(trusted_contacts::id)
+            .select(trusted_contacts::email)
+            .load::<String>(&conn)
+            .map_err(|_| TrustedContactDBError::ReadContactsError)
+    }
+
+    fn add_contact(&self, user: i32, e: &str) -> Result<(), TrustedContactDBError> {
+        let conn = establish_connection(&self.database_url)
+            .map_err(|_| TrustedContactDBError::UpdateContactsError)?;
+
+        diesel::insert_or_ignore_into(trusted_contacts::table)
+            .values(NewTrustedContact {
+                user_id: user,
+                email: e,
+            })
+            .execute(&conn)
+            .map(|_| ())
+            .map_err(|_| TrustedContactDBError::UpdateContactsError)
+    }
+
+    fn remove_contact(&self, user: i32, e: &str) -> Result<(), TrustedContactDBError> {
+        let conn = establish_connection(&self.database_url)
+            .map_err(|_| TrustedContactDBError::UpdateContactsError)?;
+
+        delete(
+            trusted_contacts::table
+                .filter(trusted_contacts::user_id.eq(user))
+                .filter(trusted_contacts::email.eq(e)),
+        )
+        .execute(&conn)
+        .map(|_| ())
+        .map_err(|_| TrustedContactDBError::UpdateContactsError)
+    }
+
+    fn list_confirmed_contacts(&self, user: i32) -> Result<Vec<String>, TrustedContactDBError> {
+        let conn = establish_connection(&self.database_url)
+            .map_err(|_| TrustedContactDBError::ReadContactsError)?;
+
+        trusted_contacts::table
+            .filter(trusted_contacts::user_id.eq(user))
+            .filter(trusted_contacts::confirmed_at.is_not_null())
+            .order(trusted_contacts::id)
+            .select(trusted_contacts::email)
+            .load::<String>(&conn)
+            .map_err(|_| TrustedContactDBError::ReadContactsError)
+    }
+
+    fn request_confirmation(
+        &self,
+        user: i32,
+        e: &str,
+        hash: &str,
+    ) -> Result<bool, TrustedContactDBError> {
+        let conn = establish_connection(&self.database_url)
+            .map_err(|_| TrustedContactDBError::UpdateContactsError)?;
+
+        update(
+            trusted_contacts::table
+                .filter(trusted_contacts::user_id.eq(user))
+                .filter(trusted_contacts::email.eq(e))
+                .filter(trusted_contacts::confirmed_at.is_null()),
+        )
+        .set(trusted_contacts::confirmation_hash.eq(Some(hash)))
+        .execute(&conn)
+        .map(|n| n > 0)
+        .map_err(|_| TrustedContactDBError::UpdateContactsError)
+    }
+
+    fn confirm_contact(
+        &self,
+        hash: &str,
+        now: DateTime<Utc>,
+    ) -> Result<bool, TrustedContactDBError> {
+        let conn = establish_connection(&self.database_url)
+            .map_err(|_| TrustedContactDBError::UpdateContactsError)?;
+
+        // Note: a single statement, so the same token can't be used twice concurrently
+        update(
+            trusted_contacts::table
+                .filter(trusted_contacts::confirmation_hash.eq(hash))
+                .filter(trusted_contacts::confirmed_at.is_null()),
+        )
+        .set((
+            trusted_contacts::confirmation_hash.eq(None::<String>),
+            trusted_contacts::confirmed_at.eq(Some(now.to_rfc3339())),
+        ))
+        .execute(&conn)
+        .map(|n| n > 0)
+        .map_err(|_| TrustedContactDBError::UpdateContactsError)
+    }
+
+    fn start_recovery(
+        &self,
+        user: i32,
+        approvals: &[(String, String)],
+        requested_at: DateTime<Utc>,
+    ) -> Result<(), TrustedContactDBError> {
+        let conn = establish_connection(&self.database_url)
+            .map_err(|_| TrustedContactDBError::UpdateRecoveryError)?;
+        let rows: Vec<NewContactRecovery> = approvals
+            .iter()
+            .map(|(e, hash)| NewContactRecovery {
+                user_id: user,
+                contact_email: e,
+                token_hash: hash,
+                requested_at: requested_at.to_rfc3339(),
+            })
+            .collect();
+
+        conn.transaction::<_, diesel::result::Error, _>(|| {
+            delete(contact_recoveries::table.filter(contact_recoveries::user_id.eq(user)))
+                .execute(&conn)?;
+            insert_into(contact_recoveries::table)
+                .values(&rows)
+                .execute(&conn)?;
+
+            Ok(())
+        })
+        .map_err(|_| TrustedContactDBError::UpdateRecoveryError)
+    }
+
+    fn approve(&self, hash: &str, now: DateTime<Utc>) -> Result<bool, TrustedContactDBError> {
+        let conn = establish_connection(&self.database_url)
+            .map_err(|_| TrustedContactDBError::UpdateRecoveryError)?;
+
+        // Note: a single statement, so the same token can't be used twice concurrently
+        update(
+            contact_recoveries::table
+                .filter(contact_recoveries::token_hash.eq(hash))
+                .filter(contact_recoveries::approved_at.is_null()),
+        )
+        .set(contact_recoveries::approved_at.eq(Some(now.to_rfc3339())))
+        .execute(&conn)
+        .map(|n| n > 0)
+        .map_err(|_| TrustedContactDBError::UpdateRecoveryError)
+    }
+
+    fn get_recovery(&self, user: i32) -> Result<Vec<ContactRecovery>, TrustedContactDBError> {
+        let conn = establish_connection(&self.database_url)
+            .map_err(|_| TrustedContactDBError::ReadRecoveryError)?;
+
+        contact_recoveries::table
+            .filter(contact_recoveries::user_id.eq(user))
+            .order(contact_recoveries::id)
+            .load::<ContactRecovery>(&conn)
+            .map_err(|_| TrustedContactDBError::ReadRecoveryError)
+    }
+
+    fn clear_recovery(&self, user: i32) -> Result<(), TrustedContactDBError> {
+        let conn = establish_connection(&self.database_url)
+            .map_err(|_| TrustedContactDBError::UpdateRecoveryError)?;
+
+        delete(contact_recoveries::table.filter(contact_recoveries::user_id.eq(user)))
+            .execute(&conn)
+            .map(|_| ())
+            .map_err(|_| TrustedContactDBError::UpdateRecoveryError)
+    }
+}
+
+pub trait NotificationRepository {
+    /// Try and claim the sending of a notification to a recipient
+    /// Returns `false` (& counts the notification as suppressed) if the same
+    /// notification was already sent to the recipient within the window
+    /// if something goes wrong, an error is returned
+    ///
+    /// # Arguments
+    ///
+    /// * `recipient` - who the notification is sent to
+    /// * `fingerprint` - identifies the content of the notification
+    /// * `now` - when the notification is sent
+    /// * `window` - how long identical notifications are collapsed
+    ///
+    fn claim(
+        &self,
+        recipient: &str,
+        fingerprint: &str,
+        now: DateTime<Utc>,
+        window: chrono::Duration,
+    ) -> Result<bool, NotificationDBError>;
+
+    /// Try and forget the latest sending of a notification (e.g. it couldn't be sent after all)
+    /// if something goes wrong, an error is returned
+    ///
+    /// # Arguments
+    ///
+    /// * `recipient` - who the notification was sent to
+    /// * `fingerprint` - identifies the content of the notification
+    ///
+    fn release(&self, recipient: &str, fingerprint: &str) -> Result<(), NotificationDBError>;
+
+    /// Try and forget every notification sent to a recipient (e.g. her/his account was erased)
+    /// if something goes wrong, an error is returned
+    ///
+    /// # Arguments
+    ///
+    /// * `recipient` - who the notifications were sent to
+    ///
+    fn forget_recipient(&self, recipient: &str) -> Result<(), NotificationDBError>;
+}
+
+pub struct SQliteNotificationRepository {
+    database_url: String,
+}
+
+impl SQliteNotificationRepository {
+    /// Repository using the database set in the `.env` file
+    pub fn new() -> Self {
+        Self::with_database_url(&database_url())
+    }
+
+    /// Repository using a specific database
+    ///
+    /// # Arguments
+    ///
+    /// * `url` - url of the SQLite database
+    ///
+    pub fn with_database_url(url: &str) -> Self {
+        Self {
+            database_url: url.to_string(),
+        }
+    }
+}
+
+impl Default for SQliteNotificationRepository {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg_attr(test, automock)]
+/// Implementation of the `NotificationRepository` with SQLite as a storage
+impl NotificationRepository for SQliteNotificationRepository {
+    fn claim(
+        &self,
+        recipient: &str,
+        fingerprint: &str,
+        now: DateTime<Utc>,
+        window: chrono::Duration,
+    ) -> Result<bool, NotificationDBError> {
+        let conn = establish_connection(&self.database_url)
+            .map_err(|_| NotificationDBError::ClaimError)?;
+        let key = (recipient, fingerprint);
+
+        // Note: the database is locked right away, so two identical notifications can't both be claimed
+        conn.immediate_transaction::<_, diesel::result::Error, _>(|| {
+            let last_sent_at = notification_dedupe::table
+                .find(key)
+                .select(notification_dedupe::last_sent_at)
+                .first::<String>(&conn)
+                .optional()?;
+            let is_recent = last_sent_at
+                .and_then(|d| DateTime::parse_from_rfc3339(&d).ok())
+                .is_some_and(|d| now.signed_duration_since(d) < window);
+
+            if is_recent {
+                update(notification_dedupe::table.find(key))
+                    .set(notification_dedupe::suppressed.eq(notification_dedupe::suppressed + 1))
+                    .execute(&conn)?;
+            } else {
+                replace_into(notification_dedupe::table)
+                    .values(NewNotification {
+                        recipient,
+                        fingerprint,
+                        last_sent_at: now.to_rfc3339(),
+                        suppressed: 0,
+                    })
+                    .execute(&conn)?;
+            }
+
+            Ok(!is_recent)
+        })
+        .map_err(|_| NotificationDBError::ClaimError)
+    }
+
+    fn release(&self, recipient: &str, fingerprint: &str) -> Result<(), NotificationDBError> {
+        let conn = establish_connection(&self.database_url)
+            .map_err(|_| NotificationDBError::ReleaseError)?;
+
+        delete(notification_dedupe::table.find((recipient, fingerprint)))
+            .execute(&conn)
+            .map(|_| ())
+            .map_err(|_| NotificationDBError::ReleaseError)
+    }
+
+    fn forget_recipient(&self, recipient: &str) -> Result<(), NotificationDBError> {
+        let conn = establish_connection(&self.database_url)
+            .map_err(|_| NotificationDBError::ReleaseError)?;
+
+        delete(notification_dedupe::table.filter(notification_dedupe::recipient.eq(recipient)))
+            .execute(&conn)
+            .map(|_| ())
+            .map_err(|_| NotificationDBError::ReleaseError)
+    }
+}
+
+pub trait ActionLinkRepository {
+    /// Try and redeem an action link, the links that expired are forgotten on the way
+    /// Returns `false` if the link was already redeemed
+    /// if something goes wrong, an error is returned
+    ///
+    /// # Arguments
+    ///
+    /// * `jti` - the unique identifier of the link
+    /// * `expires_at` - when the link expires, it's kept until then
+    /// * `now` - when the link is redeemed
+    ///
+    fn redeem(
+        &self,
+        jti: &str,
+        expires_at: DateTime<Utc>,
+        now: DateTime<Utc>,
+    ) -> Result<bool, ActionLinkDBError>;
+}
+
+pub struct SQliteActionLinkRepository {
+    database_url: String,
+}
+
+impl SQliteActionLinkRepository {
+    /// Repository using the database set in the `.env` file
+    pub fn new() -> Self {
+        Self::with_database_url(&database_url())
+    }
+
+    /// Repository using a specific database
+    ///
+    /// # Arguments
+    ///
+    /// * `url` - url of the SQLite database
+    ///
+    pub fn with_database_url(url: &str) -> Self {
+        Self {
+            database_url: url.to_string(),
+        }
+    }
+}
+
+impl Default for SQliteActionLinkRepository {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg_attr(test, automock)]
+/// Implementation of the `ActionLinkRepository` with SQLite as a storage
+impl ActionLinkRepository for SQliteActionLinkRepository {
+    fn redeem(
+        &self,
+        jti: &str,
+        expires_at: DateTime<Utc>,
+        now: DateTime<Utc>,
+    ) -> Result<bool, ActionLinkDBError> {
+        let conn =
+            establish_connection(&self.database_url).map_err(|_| ActionLinkDBError::RedeemError)?;
+
+        // Note: the dates are all stored in UTC, so they can be compared as text
+        conn.immediate_transaction::<_, diesel::result::Error, _>(|| {
+            delete(
+                redeemed_action_links::table
+                    .filter(redeemed_action_links::expires_at.le(now.to_rfc3339())),
+            )
+            .execute(&conn)?;
+            let inserted = diesel::insert_or_ignore_into(redeemed_action_links::table)
+                .values(RedeemedActionLink {
+                    jti,
+                    expires_at: expires_at.to_rfc3339(),
+                })
+                .execute(&conn)?;
+
+            Ok(inserted == 1)
+        })
+        .map_err(|_| ActionLinkDBError::RedeemError)
+    }
+}
+
+pub trait LeaseRepository {
+    /// Try and acquire the lease of a job, i.e. the right to run it until the lease expires
+    /// Returns `false` if another holder has a lease that didn't expire yet, the holder of
+    /// the lease renews it
+    /// if something goes wrong, an error is returned
+    ///
+    /// # Arguments
+    ///
+    /// * `job` - the name of the job
+    /// * `holder` - who runs the job, unique to each run
+    /// * `now` - when the lease is acquired
+    /// * `ttl` - how long the lease lasts
+    ///
+    fn acquire(
+        &self,
+        job: &str,
+        holder: &str,
+        now: DateTime<Utc>,
+        ttl: chrono::Duration,
+    ) -> Result<bool, LeaseDBError>;
+
+    /// Try and release the lease of a job, nothing happens if it's someone else's
+    /// if something goes wrong, an error is returned
+    ///
+    /// # Arguments
+    ///
+    /// * `job` - the name of the job
+    /// * `holder` - who ran the job
+    ///
+    fn release(&self, job: &str, holder: &str) -> Result<(), LeaseDBError>;
+}
+
+pub struct SQliteLeaseRepository {
+    database_url: String,
+}
+
+impl SQliteLeaseRepository {
+    /// Repository using the database set in the `.env` file
+    pub fn new() -> Self {
+        Self::with_database_url(&database_url())
+    }
+
+    /// Repository using a specific database
+    ///
+    /// # Arguments
+    ///
+    /// * `url` - url of the SQLite database
+    ///
+    pub fn with_database_url(url: &str) -> Self {
+        Self {
+            database_url: url.to_string(),
+        }
+    }
+}
+
+impl Default for SQliteLeaseRepository {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg_attr(test, automock)]
+/// Implementation of the `LeaseRepository` with SQLite as a storage
+impl LeaseRepository for SQliteLeaseRepository {
+    fn acquire(
+        &self,
+        job: &str,
+        holder: &str,
+        now: DateTime<Utc>,
+        ttl: chrono::Duration,
+    ) -> Result<bool, LeaseDBError> {
+        let conn =
+            establish_connection(&self.database_url).map_err(|_| LeaseDBError::AcquireError)?;
+
+        // Note: the database is locked right away, so two instances can't both acquire the lease
+        conn.immediate_transaction::<_, diesel::result::Error, _>(|| {
+            let current = job_leases::table
+                .find(job)
+                .select((job_leases::holder, job_leases::expires_at))
+                .first::<(String, String)>(&conn)
+                .optional()?;
+            let is_free = current.is_none_or(|(h, expires_at)| {
+                h == holder || DateTime::parse_from_rfc3339(&expires_at).map_or(true, |d| d <= now)
+            });
+
+            if is_free {
+                replace_into(job_leases::table)
+                    .values(JobLease {
+                        job,
+                        holder,
+                        expires_at: (now + ttl).to_rfc3339(),
+                    })
+                    .execute(&conn)?;
+            }
+
+            Ok(is_free)
+        })
+        .map_err(|_| LeaseDBError::AcquireError)
+    }
+
+    fn release(&self, job: &str, holder: &str) -> Result<(), LeaseDBError> {
+        let conn =
+            establish_connection(&self.database_url).map_err(|_| LeaseDBError::ReleaseError)?;
+
+        delete(
+            job_leases::table
+                .filter(job_leases::job.eq(job))
+                .filter(job_leases::holder.eq(holder)),
+        )
+        .execute(&conn)
+        .map(|_| ())
+        .map_err(|_| LeaseDBError::ReleaseError)
+    }
+}
+
+pub trait PasswordStatsRepository {
+    /// Try and count a password of a strength score chosen in a context
+    /// if something goes wrong, an error is returned
+    ///
+    /// # Arguments
+    ///
+    /// * `context` - where the password was chosen (e.g. at registration)
+    /// * `score` - the strength score of the password
+    ///
+    fn record(&self, context: &str, score: i32) -> Result<(), PasswordStatsDBError>;
+
+    /// Try and get the number of passwords of each context & strength score
+    /// if something goes wrong, an error is returned
+    ///
+    fn counts(&self) -> Result<Vec<PasswordScoreCount>, PasswordStatsDBError>;
+}
+
+pub struct SQlitePasswordStatsRepository {
+    database_url: String,
+}
+
+impl SQlitePasswordStatsRepository {
+    /// Repository using the database set in the `.env` file
+    pub fn new() -> Self {
+        Self::with_database_url(&database_url())
+    }
+
+    /// Repository using a specific database
+    ///
+    /// # Arguments
+    ///
+    /// * `url` - url of the SQLite database
+    ///
+    pub fn with_database_url(url: &str) -> Self {
+        Self {
+            database_url: url.to_string(),
+        }
+    }
+}
+
+impl Default for SQlitePasswordStatsRepository {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg_attr(test, automock)]
+/// Implementation of the `PasswordStatsRepository` with SQLite as a storage
+impl PasswordStatsRepository for SQlitePasswordStatsRepository {
+    fn record(&self, context: &str, score: i32) -> Result<(), PasswordStatsDBError> {
+        let conn = establish_connection(&self.database_url)
+            .map_err(|_| PasswordStatsDBError::RecordError)?;
+
+        conn.immediate_transaction::<_, diesel::result::Error, _>(|| {
+            diesel::insert_or_ignore_into(password_stats::table)
+                .values(PasswordScoreCount {
+                    context: context.to_string(),
+                    score,
+                    count: 0,
+                })
+                .execute(&conn)?;
+            update(password_stats::table.find((context, score)))
+                .set(password_stats::count.eq(password_stats::count + 1))
+                .execute(&conn)
+                .map(|_| ())
+        })
+        .map_err(|_| PasswordStatsDBError::RecordError)
+    }
+
+    fn counts(&self) -> Result<Vec<PasswordScoreCount>, PasswordStatsDBError> {
+        let conn = establish_connection(&self.database_url)
+            .map_err(|_| PasswordStatsDBError::ReadError)?;
+
+        password_stats::table
+            .order((password_stats::context, password_stats::score))
+            .load::<PasswordScoreCount>(&conn)
+            .map_err(|_| PasswordStatsDBError::ReadError)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::db::test_database;
+
+    #[test]
+    fn test_locked_database_is_a_timeout() {
+        let locked = DatabaseError(
+            DatabaseErrorKind::__Unknown,
+            Box::new("database is locked".to_string()),
+        );
+        let other = DatabaseError(
+            DatabaseErrorKind::__Unknown,
+            Box::new("no such table: users".to_string()),
+        );
+
+        assert_eq!(
+            user_query_error(locked, UserDBError::GetUserError),
+            UserDBError::Timeout
+        );
+        assert_eq!(
+            user_query_error(other, UserDBError::GetUserError),
+            UserDBError::GetUserError
+        );
+        assert_eq!(
+            user_query_error(diesel::result::Error::NotFound, UserDBError::GetUserError),
+            UserDBError::GetUserError
+        );
+    }
+
+    #[test]
+    fn test_locked_audit_log_and_codes_are_a_timeout() {
+        use crate::errors::AuthError;
+
+        let locked = || {
+            DatabaseError(
+                DatabaseErrorKind::__Unknown,
+                Box::new("database is locked".to_string()),
+            )
+        };
+
+        assert_eq!(
+            audit_query_error(locked(), AuditDBError::ReadEntryError),
+            AuditDBError::Timeout
+        );
+        assert_eq!(
+            audit_query_error(
+                diesel::result::Error::NotFound,
+                AuditDBError::ReadEntryError
+            ),
+            AuditDBError::ReadEntryError
+        );
+        assert_eq!(
+            code_query_error(locked(), RecoveryCodeDBError::UseCodeError),
+            RecoveryCodeDBError::Timeout
+        );
+        assert_eq!(
+            RecoveryCodeDBError::Timeout.to_auth_error(AuthError::RecoveryCodesError),
+            AuthError::Timeout
+        );
+    }
+
+    /// Create a user with an attribute and an audit entry
+    fn setup_user(
+        repository: &SQliteUserRepository,
+        audit_repository: &SQliteAuditRepository,
+        e: &str,
+    ) -> i32 {
+        repository.create_user(e, "passwd_hash").unwrap();
+        let u = repository.get_user(e).unwrap();
+
+        repository
+            .set_attribute(u.get_id(), "department", "IT")
+            .unwrap();
+        audit_repository
+            .create_entry(Some(u.get_id()), "TosAccepted", None)
+            .unwrap();
+
+        u.get_id()
+    }
+
+    fn count_attributes(url: &str, user: i32) -> i64 {
+        user_attributes::table
+            .filter(user_attributes::user_id.eq(user))
+            .count()
+            .get_result(&establish_connection(url).unwrap())
+            .unwrap()
+    }
+
+    fn audit_entries_of(url: &str, user: i32) -> i64 {
+        audit_log::table
+            .filter(audit_log::user_id.eq(user))
+            .count()
+            .get_result(&establish_connection(url).unwrap())
+            .unwrap()
+    }
+
+    fn count_audit_entries(url: &str) -> i64 {
+        audit_log::table
+            .count()
+            .get_result(&establish_connection(url).unwrap())
+            .unwrap()
+    }
+
+    #[test]
+    fn test_last_occurrence() {
+        let (_dir, url) = test_database();
+        let repository = SQliteUserRepository::with_database_url(&url);
+        let audit_repository = SQliteAuditRepository::with_database_url(&url);
+        let user = setup_user(&repository, &audit_repository, "email@email.test");
+
+        assert_eq!(
+            audit_repository.last_occurrence(user, "TwoFaEnabled"),
+            Ok(None)
+        );
+
+        audit_repository
+            .create_entry(Some(user), "TwoFaEnabled", Some("first".to_string()))
+            .unwrap();
+        let first = audit_repository
+            .last_occurrence(user, "TwoFaEnabled")
+            .unwrap()
+            .unwrap();
+        audit_repository
+            .create_entry(Some(user), "TwoFaEnabled", Some("second".to_string()))
+            .unwrap();
+        let second = audit_repository
+            .last_occurrence(user, "TwoFaEnabled")
+            .unwrap()
+            .unwrap();
+
+        assert!(DateTime::parse_from_rfc3339(&first).is_ok());
+        assert!(second >= first);
+        assert_eq!(
+            audit_repository.last_occurrence(user + 1, "TwoFaEnabled"),
+            Ok(None)
+        );
+    }
+
+    #[test]
+    fn test_occurrences_since() {
+        let (_dir, url) = test_database();
+        let audit_repository = SQliteAuditRepository::with_database_url(&url);
+        let before = Utc::now() - chrono::Duration::seconds(1);
+
+        audit_repository
+            .create_entry(None, "LoginSucceeded", None)
+            .unwrap();
+        audit_repository
+            .create_entry(None, "LoginSucceeded", None)
+            .unwrap();
+        audit_repository
+            .create_entry(None, "ResetRequested", None)
+            .unwrap();
+
+        assert_eq!(
+            audit_repository
+                .occurrences_since("LoginSucceeded", before)
+                .map(|o| o.len()),
+            Ok(2)
+        );
+        assert_eq!(
+            audit_repository
+                .occurrences_since("LoginSucceeded", Utc::now() + chrono::Duration::hours(1)),
+            Ok(vec![])
+        );
+    }
+
+    #[test]
+    fn test_entries_after() {
+        let (_dir, url) = test_database();
+        let audit_repository = SQliteAuditRepository::with_database_url(&url);
+
+        for event in &["LoginSucceeded", "ResetRequested", "TosAccepted"] {
+            audit_repository.create_entry(None, event, None).unwrap();
+        }
+
+        let all = audit_repository.entries_after(0, 10).unwrap();
+        assert_eq!(
+            all.iter().map(|e| e.event.as_str()).collect::<Vec<_>>(),
+            vec!["LoginSucceeded", "ResetRequested", "TosAccepted"]
+        );
+        assert_eq!(
+            audit_repository.entries_after(all[0].id, 1),
+            Ok(vec![all[1].clone()])
+        );
+        assert_eq!(audit_repository.entries_after(all[2].id, 10), Ok(vec![]));
+
+        // the sequence number of a removed entry isn't given to the next one
+        let conn = establish_connection(&url).unwrap();
+        delete(audit_log::table.filter(audit_log::id.eq(all[2].id)))
+            .execute(&conn)
+            .unwrap();
+        audit_repository
+            .create_entry(None, "LoginSucceeded", None)
+            .unwrap();
+        let next = audit_repository.entries_after(all[1].id, 10).unwrap();
+        assert!(next[0].id > all[2].id);
+    }
+
+    #[test]
+    fn test_recovery_codes() {
+        let (_dir, url) = test_database();
+        let repository = SQliteUserRepository::with_database_url(&url);
+        let codes_repository = SQliteRecoveryCodeRepository::with_database_url(&url);
+        repository
+            .create_user("email@email.test", "passwd_hash")
+            .unwrap();
+        let user = repository.get_user("email@email.test").unwrap().get_id();
+
+        let hashes: Vec<String> = vec!["a".to_string(), "b".to_string(), "c".to_string()];
+        codes_repository.replace_codes(user, &hashes).unwrap();
+        assert_eq!(codes_repository.count_unused_codes(user), Ok(3));
+
+        // a code can only be used once
+        assert_eq!(codes_repository.use_code(user, "a"), Ok(true));
+        assert_eq!(codes_repository.use_code(user, "a"), Ok(false));
+        assert_eq!(codes_repository.use_code(user, "unknown"), Ok(false));
+        assert_eq!(codes_repository.use_code(user + 1, "b"), Ok(false));
+        assert_eq!(codes_repository.count_unused_codes(user), Ok(2));
+
+        // the old codes stop working as soon as the new ones are set
+        codes_repository
+            .replace_codes(user, &["d".to_string()])
+            .unwrap();
+        assert_eq!(codes_repository.use_code(user, "b"), Ok(false));
+        assert_eq!(codes_repository.count_unused_codes(user), Ok(1));
+
+        codes_repository.replace_codes(user, &[]).unwrap();
+        assert_eq!(codes_repository.count_unused_codes(user), Ok(0));
+    }
+
+    #[test]
+    fn test_trusted_contacts() {
+        let (_dir, url) = test_database();
+        let repository = SQliteUserRepository::with_database_url(&url);
+        let contacts_repository = SQliteTrustedContactRepository::with_database_url(&url);
+        repository
+            .create_user("email@email.test", "passwd_hash")
+            .unwrap();
+        let user = repository.get_user("email@email.test").unwrap().get_id();
+
+        contacts_repository
+            .add_contact(user, "a@email.test")
+            .unwrap();
+        contacts_repository
+            .add_contact(user, "b@email.test")
+            .unwrap();
+        contacts_repository
+            .add_contact(user, "a@email.test")
+            .unwrap();
+        assert_eq!(
+            contacts_repository.list_contacts(user),
+            Ok(vec!["a@email.test".to_string(), "b@email.test".to_string()])
+        );
+
+        contacts_repository
+            .remove_contact(user, "a@email.test")
+            .unwrap();
+        assert_eq!(
+            contacts_repository.list_contacts(user),
+            Ok(vec!["b@email.test".to_string()])
+        );
+        assert_eq!(contacts_repository.list_contacts(user + 1), Ok(vec![]));
+    }
+
+    #[test]
+    fn test_trusted_contact_confirmation() {
+        let (_dir, url) = test_database();
+        let repository = SQliteUserRepository::with_database_url(&url);
+        let contacts_repository = SQliteTrustedContactRepository::with_database_url(&url);
+        repository
+            .create_user("email@email.test", "passwd_hash")
+            .unwrap();
+        let user = repository.get_user("email@email.test").unwrap().get_id();
+        let now = Utc::now();
+
+        contacts_repository
+            .add_contact(user, "a@email.test")
+            .unwrap();
+        contacts_repository
+            .add_contact(user, "b@email.test")
+            .unwrap();
+        assert_eq!(
+            contacts_repository.list_confirmed_contacts(user),
+            Ok(vec![])
+        );
+        assert_eq!(
+            contacts_repository.request_confirmation(user, "unknown@email.test", "x"),
+            Ok(false)
+        );
+
+        // a new token replaces the one sent before
+        assert_eq!(
+            contacts_repository.request_confirmation(user, "a@email.test", "a"),
+            Ok(true)
+        );
+        assert_eq!(
+            contacts_repository.request_confirmation(user, "a@email.test", "c"),
+            Ok(true)
+        );
+        assert_eq!(contacts_repository.confirm_contact("a", now), Ok(false));
+        // a confirmation token can only be used once
+        assert_eq!(contacts_repository.confirm_contact("c", now), Ok(true));
+        assert_eq!(contacts_repository.confirm_contact("c", now), Ok(false));
+        assert_eq!(
+            contacts_repository.list_confirmed_contacts(user),
+            Ok(vec!["a@email.test".to_string()])
+        );
+
+        // a confirmed contact isn't asked again
+        assert_eq!(
+            contacts_repository.request_confirmation(user, "a@email.test", "d"),
+            Ok(false)
+        );
+    }
+
+    #[test]
+    fn test_contact_recovery() {
+        let (_dir, url) = test_database();
+        let repository = SQliteUserRepository::with_database_url(&url);
+        let contacts_repository = SQliteTrustedContactRepository::with_database_url(&url);
+        repository
+            .create_user("email@email.test", "passwd_hash")
+            .unwrap();
+        let user = repository.get_user("email@email.test").unwrap().get_id();
+        let now = Utc::now();
+        let approvals = |hashes: &[&str]| -> Vec<(String, String)> {
+            hashes
+                .iter()
+                .map(|h| (format!("{}@email.test", h), h.to_string()))
+                .collect()
+        };
+
+        contacts_repository
+            .start_recovery(user, &approvals(&["a", "b"]), now)
+            .unwrap();
+        // an approval token can only be used once
+        assert_eq!(contacts_repository.approve("a", now), Ok(true));
+        assert_eq!(contacts_repository.approve("a", now), Ok(false));
+        assert_eq!(contacts_repository.approve("unknown", now), Ok(false));
+
+        let recovery = contacts_repository.get_recovery(user).unwrap();
+        assert_eq!(recovery.len(), 2);
+        assert!(recovery[0].approved_at.is_some());
+        assert!(recovery[1].approved_at.is_none());
+
+        // a new recovery replaces the previous one & its approvals
+        contacts_repository
+            .start_recovery(user, &approvals(&["c"]), now)
+            .unwrap();
+        assert_eq!(contacts_repository.approve("b", now), Ok(false));
+        assert_eq!(contacts_repository.get_recovery(user).unwrap().len(), 1);
+
+        contacts_repository.clear_recovery(user).unwrap();
+        assert_eq!(contacts_repository.get_recovery(user), Ok(vec![]));
+    }
+
+    #[test]
+    fn test_password_stats() {
+        let (_dir, url) = test_database();
+        let repository = SQlitePasswordStatsRepository::with_database_url(&url);
+
+        repository.record("registration", 3).unwrap();
+        repository.record("registration", 3).unwrap();
+        repository.record("change", 1).unwrap();
+
+        assert_eq!(
+            repository.counts(),
+            Ok(vec![
+                PasswordScoreCount {
+                    context: "change".to_string(),
+                    score: 1,
+                    count: 1,
+                },
+                PasswordScoreCount {
+                    context: "registration".to_string(),
+                    score: 3,
+                    count: 2,
+                },
+            ])
+        );
+    }
+
+    #[test]
+    fn test_job_leases() {
+        let (_dir, url) = test_database();
+        let repository = SQliteLeaseRepository::with_database_url(&url);
+        let now = Utc::now();
+        let ttl = chrono::Duration::minutes(10);
+
+        assert_eq!(repository.acquire("job", "a", now, ttl), Ok(true));
+        assert_eq!(repository.acquire("job", "b", now, ttl), Ok(false));
+        // the holder renews its lease, the other jobs aren't affected
+        assert_eq!(repository.acquire("job", "a", now, ttl), Ok(true));
+        assert_eq!(repository.acquire("other", "b", now, ttl), Ok(true));
+
+        // only the holder releases its lease
+        repository.release("job", "b").unwrap();
+        assert_eq!(repository.acquire("job", "b", now, ttl), Ok(false));
+        repository.release("job", "a").unwrap();
+        assert_eq!(repository.acquire("job", "b", now, ttl), Ok(true));
+
+        // once expired, the lease is free again (e.g. its holder crashed)
+        assert_eq!(repository.acquire("job", "c", now + ttl, ttl), Ok(true));
+    }
+
+    #[test]
+    fn test_action_links_are_redeemed_once() {
+        let (_dir, url) = test_database();
+        let repository = SQliteActionLinkRepository::with_database_url(&url);
+        let now = Utc::now();
+        let expires_at = now + chrono::Duration::hours(1);
+
+        assert_eq!(repository.redeem("jti", expires_at, now), Ok(true));
+        assert_eq!(repository.redeem("jti", expires_at, now), Ok(false));
+        assert_eq!(repository.redeem("other", expires_at, now), Ok(true));
+
+        // once expired, the links are forgotten
+        let later = expires_at + chrono::Duration::seconds(1);
+        assert_eq!(
+            redeemed_action_links::table
+                .count()
+                .get_result::<i64>(&establish_connection(&url).unwrap()),
+            Ok(2)
+        );
+        assert_eq!(
+            repository.redeem("new", expires_at + chrono::Duration::hours(1), later),
+            Ok(true)
+        );
+        assert_eq!(
+            redeemed_action_links::table
+                .count()
+                .get_result::<i64>(&establish_connection(&url).unwrap()),
+            Ok(1)
+        );
+    }
+
+    #[test]
+    fn test_notification_dedupe() {
+        let (_dir, url) = test_database();
+        let repository = SQliteNotificationRepository::with_database_url(&url);
+        let window = chrono::Duration::seconds(60);
+        let now = Utc.ymd(2021, 4, 28).and_hms(14, 0, 0);
+        let suppressed = |r: &str| {
+            notification_dedupe::table
+                .find((r, "alert"))
+                .select(notification_dedupe::suppressed)
+                .first::<i32>(&establish_connection(&url).unwrap())
+        };
+
+        assert_eq!(
+            repository.claim("a@email.test", "alert", now, window),
+            Ok(true)
+        );
+        for _ in 0..4 {
+            assert_eq!(
+                repository.claim(
+                    "a@email.test",
+                    "alert",
+                    now + chrono::Duration::seconds(10),
+                    window
+                ),
+                Ok(false)
+            );
+        }
+        assert_eq!(suppressed("a@email.test"), Ok(4));
+
+        // other recipients & other notifications aren't affected
+        assert_eq!(
+            repository.claim("b@email.test", "alert", now, window),
+            Ok(true)
+        );
+        assert_eq!(
+            repository.claim("a@email.test", "other", now, window),
+            Ok(true)
+        );
+
+        // once the window is over, the notification is sent again
+        assert_eq!(
+            repository.claim("a@email.test", "alert", now + window, window),
+            Ok(true)
+        );
+        assert_eq!(suppressed("a@email.test"), Ok(0));
+
+        repository.release("a@email.test", "alert").unwrap();
+        assert_eq!(
+            repository.claim("a@email.test", "alert", now + window, window),
+            Ok(true)
+        );
+
+        // forgetting a recipient forgets all her/his notifications, only hers/his
+        repository.forget_recipient("a@email.test").unwrap();
+        assert_eq!(
+            repository.claim("a@email.test", "other", now, window),
+            Ok(true)
+        );
+        assert_eq!(
+            repository.claim("b@email.test", "alert", now, window),
+            Ok(false)
+        );
+    }
+
+    #[test]
+    fn test_delete_user_leaves_no_orphans() {
+        let (_dir, url) = test_database();
+        let repository = SQliteUserRepository::with_database_url(&url);
+        let audit_repository = SQliteAuditRepository::with_database_url(&url);
+
+        let deleted = setup_user(&repository, &audit_repository, "deleted@email.test");
+        let kept = setup_user(&repository, &audit_repository, "kept@email.test");
+
+        repository.delete_user(deleted).unwrap();
+
+        assert_eq!(
+            repository.get_user("deleted@email.test"),
+            Err(UserDBError::GetUserError)
+        );
+        assert_eq!(count_attributes(&url, deleted), 0);
+        assert_eq!(audit_entries_of(&url, deleted), 0);
+
+        // the audit history is kept, only detached from the account
+        assert_eq!(count_audit_entries(&url), 2);
+
+        // the other users aren't affected
+        assert_eq!(count_attributes(&url, kept), 1);
+        assert_eq!(audit_entries_of(&url, kept), 1);
+    }
+
+    #[test]
+    fn test_foreign_keys_cascade_on_raw_delete() {
+        let (_dir, url) = test_database();
+        let repository = SQliteUserRepository::with_database_url(&url);
+        let audit_repository = SQliteAuditRepository::with_database_url(&url);
+
+        let user = setup_user(&repository, &audit_repository, "email@email.test");
+
+        delete(users.filter(id.eq(user)))
+            .execute(&establish_connection(&url).unwrap())
+            .unwrap();
+
+        assert_eq!(count_attributes(&url, user), 0);
+        assert_eq!(audit_entries_of(&url, user), 0);
+        assert_eq!(count_audit_entries(&url), 1);
+    }
+
+    #[test]
+    fn test_read_only_repository_allows_lookups() {
+        let mut mock = MockSQliteUserRepository::new();
+
+        mock.expect_get_user()
+            .returning(|e| Ok(User::new(e, "passwd_hash")));
+        mock.expect_list_users().returning(|_| Ok(vec![]));
+
+        let repository = ReadOnlyUserRepository::new(mock);
+
+        assert!(repository.get_user("email@email.test").is_ok());
+        assert_eq!(repository.list_users(&UserFilter::new()), Ok(vec![]));
+    }
+
+    #[test]
+    fn test_read_only_repository_rejects_mutations() {
+        let mut mock = MockSQliteUserRepository::new();
+
+        mock.expect_create_user().times(0);
+        mock.expect_update_user().times(0);
+        mock.expect_patch_user().times(0);
+        mock.expect_delete_user().times(0);
+        mock.expect_update_many().times(0);
+        mock.expect_set_attribute().times(0);
+        mock.expect_remove_attribute().times(0);
+
+        let repository = ReadOnlyUserRepository::new(mock);
+        let u = User::new("email@email.test", "passwd_hash");
+
+        assert_eq!(
+            repository.create_user("email@email.test", "passwd_hash"),
+            Err(UserDBError::ReadOnlyError)
+        );
+        assert_eq!(repository.update_user(&u), Err(UserDBError::ReadOnlyError));
+        assert_eq!(
+            repository.patch_user(1, &UserChangeset::new()),
+            Err(UserDBError::ReadOnlyError)
+        );
+        assert_eq!(repository.delete_user(1), Err(UserDBError::ReadOnlyError));
+        assert_eq!(
+            repository.update_many(&UserFilter::new(), &UserChangeset::new()),
+            Err(UserDBError::ReadOnlyError)
+        );
+        assert_eq!(
+            repository.set_attribute(1, "department", "IT"),
+            Err(UserDBError::ReadOnlyError)
+        );
+        assert_eq!(
+            repository.remove_attribute(1, "department"),
+            Err(UserDBError::ReadOnlyError)
+        );
+    }
+
+    #[test]
+    fn test_list_users_with_attribute_filter() {
+        let (_dir, url) = test_database();
+        let repository = SQliteUserRepository::with_database_url(&url);
+        let audit_repository = SQliteAuditRepository::with_database_url(&url);
+
+        let it = setup_user(&repository, &audit_repository, "it@email.test");
+        let hr = setup_user(&repository, &audit_repository, "hr@email.test");
+        repository.set_attribute(hr, "department", "HR").unwrap();
+
+        let all = repository.list_users(&UserFilter::new()).unwrap();
+        let filtered = repository
+            .list_users(&UserFilter::new().with_attribute("department", "IT"))
+            .unwrap();
+
+        assert_eq!(all.len(), 2);
+        assert_eq!(filtered.len(), 1);
+        assert_eq!(filtered[0].get_id(), it);
+        assert_eq!(
+            repository
+                .list_users(&UserFilter::new().with_email("hr@email.test"))
+                .unwrap()[0]
+                .get_id(),
+            hr
+        );
+        assert!(repository
+            .list_users(&UserFilter::new().with_email("unknown@email.test"))
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            repository
+                .list_users(&UserFilter::new().with_id(it))
+                .unwrap()[0]
+                .get_email(),
+            "it@email.test"
+        );
+    }
+
+    #[test]
+    fn test_update_many() {
+        let (_dir, url) = test_database();
+        let repository = SQliteUserRepository::with_database_url(&url);
+        let audit_repository = SQliteAuditRepository::with_database_url(&url);
+
+        let it = setup_user(&repository, &audit_repository, "it@email.test");
+        let hr = setup_user(&repository, &audit_repository, "hr@email.test");
+        repository.set_attribute(hr, "department", "HR").unwrap();
+
+        let mut u = repository.get_user("it@email.test").unwrap();
+        u.set_anti_phishing_phrase(Some("purple elephant".to_string()));
+        repository.update_user(&u).unwrap();
+
+        let changes = UserChangeset::new().accepted_tos_version(Some(3));
+        let updated = repository
+            .update_many(
+                &UserFilter::new().with_attribute("department", "IT"),
+                &changes,
+            )
+            .unwrap();
+
+        let u_it = repository.get_user("it@email.test").unwrap();
+        let u_hr = repository.get_user("hr@email.test").unwrap();
+
+        assert_eq!(updated, 1);
+        assert_eq!(u_it.get_id(), it);
+        assert_eq!(u_it.get_accepted_tos_version(), Some(3));
+        // the fields not in the changeset are untouched
+        assert_eq!(u_it.get_password(), "passwd_hash");
+        assert_eq!(
+            u_it.get_anti_phishing_phrase(),
+            Some("purple elephant".to_string())
+        );
+        assert_eq!(u_hr.get_accepted_tos_version(), None);
+    }
+
+    #[test]
+    fn test_update_many_with_empty_changeset() {
+        let (_dir, url) = test_database();
+        let repository = SQliteUserRepository::with_database_url(&url);
+
+        repository
+            .create_user("email@email.test", "passwd_hash")
+            .unwrap();
+
+        assert_eq!(
+            repository.update_many(&UserFilter::new(), &UserChangeset::new()),
+            Ok(0)
+        );
+    }
+
+    #[test]
+    fn test_search_users_by_email_prefix() {
+        let (_dir, url) = test_database();
+        let repository = SQliteUserRepository::with_database_url(&url);
+
+        for e in &[
+            "bob@email.test",
+            "alice@email.test",
+            "alicia@email.test",
+            "Alex@email.test",
+        ] {
+            repository.create_user(e, "passwd_hash").unwrap();
+        }
+
+        let emails = |query: &str, limit: i64| -> Vec<String> {
+            repository
+                .search_users(query, limit)
+                .unwrap()
+                .iter()
+                .map(|u| u.get_email())
+                .collect()
+        };
+
+        assert_eq!(
+            emails("ali", 10),
+            vec!["alice@email.test", "alicia@email.test"]
+        );
+        assert_eq!(emails("ali", 1), vec!["alice@email.test"]);
+        assert_eq!(
+            emails("al", 10),
+            vec!["alice@email.test", "alicia@email.test"]
+        );
+        assert_eq!(emails("Al", 10), vec!["Alex@email.test"]);
+        assert_eq!(emails("", 10).len(), 4);
+        assert!(emails("carol", 10).is_empty());
+        assert!(emails("ali", 0).is_empty());
+        assert_eq!(
+            repository.search_users("ali", -1).map(|found| found.len()),
+            Err(UserDBError::ListUsersError)
+        );
+    }
+
+    #[test]
+    fn test_iter_users_over_several_pages() {
+        let (_dir, url) = test_database();
+        let repository = SQliteUserRepository::with_database_url(&url);
+
+        for i in 0..5 {
+            let e = format!("user{}@email.test", i);
+            repository.create_user(&e, "passwd_hash").unwrap();
+
+            let u = repository.get_user(&e).unwrap();
+            let department = if i % 2 == 0 { "IT" } else { "HR" };
+            repository
+                .set_attribute(u.get_id(), "department", department)
+                .unwrap();
+        }
+
+        let all: Vec<User> = UserIter::new(&url, &UserFilter::new(), 2)
+            .collect::<Result<_, _>>()
+            .unwrap();
+        let it: Vec<String> = UserIter::new(
+            &url,
+            &UserFilter::new().with_attribute("department", "IT"),
+            2,
+        )
+        .map(|u| u.unwrap().get_email())
+        .collect();
+
+        assert_eq!(all.len(), 5);
+        assert!(all.windows(2).all(|w| w[0].get_id() < w[1].get_id()));
+        assert_eq!(
+            it,
+            vec!["user0@email.test", "user2@email.test", "user4@email.test"]
+        );
+        assert_eq!(repository.iter_users(&UserFilter::new()).count(), all.len());
+    }
+
+    #[test]
+    fn test_patch_user_only_touches_given_fields() {
+        let (_dir, url) = test_database();
+        let repository = SQliteUserRepository::with_database_url(&url);
+        repository
+            .create_user("email@email.test", "passwd_hash")
+            .unwrap();
+
+        let mut u = repository.get_user("email@email.test").unwrap();
+        u.set_secret_2fa(Some("secret".to_string()));
+        repository.update_user(&u).unwrap();
+
+        // a stale copy of the user changing another field mustn't bring back the secret
+        let stale = u.clone();
+        repository
+            .patch_user(u.get_id(), &UserChangeset::new().secret_2fa(None))
+            .unwrap();
+        repository
+            .patch_user(
+                stale.get_id(),
+                &UserChangeset::new().password("new_passwd_hash"),
+            )
+            .unwrap();
+
+        let u = repository.get_user("email@email.test").unwrap();
+        assert_eq!(u.get_secret_2fa(), None);
+        assert_eq!(u.get_password(), "new_passwd_hash");
+        assert_eq!(
+            repository.patch_user(u.get_id(), &UserChangeset::new()),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn test_update_user_atomic_with_stale_user() {
+        let (_dir, url) = test_database();
+        let repository = SQliteUserRepository::with_database_url(&url);
+        repository
+            .create_user("email@email.test", "passwd_hash")
+            .unwrap();
+        let u = repository.get_user("email@email.test").unwrap();
+        repository
+            .patch_user(
+                u.get_id(),
+                &UserChangeset::new().accepted_tos_version(Some(1)),
+            )
+            .unwrap();
+        let read = repository.get_user("email@email.test").unwrap();
+
+        let changes = UserChangeset::new()
+            .password("new_passwd_hash")
+            .accepted_tos_version(None);
+        assert_eq!(repository.update_user_atomic(&read, &changes), Ok(true));
+        // the user changed since she/he was read, nothing is applied
+        let changes = UserChangeset::new().password("other_passwd_hash");
+        assert_eq!(repository.update_user_atomic(&read, &changes), Ok(false));
+        assert_eq!(repository.update_user_atomic(&u, &changes), Ok(false));
+
+        let u = repository.get_user("email@email.test").unwrap();
+        assert_eq!(u.get_password(), "new_passwd_hash");
+        assert_eq!(u.get_accepted_tos_version(), None);
+    }
+
+    #[test]
+    fn test_create_user_with_used_email() {
+        let (_dir, url) = test_database();
+        let repository = SQliteUserRepository::with_database_url(&url);
+
+        repository
+            .create_user("email@email.test", "passwd_hash")
+            .unwrap();
+
+        assert_eq!(
+            repository.create_user("email@email.test", "other_passwd_hash"),
+            Err(UserDBError::EmailUsedError)
+        );
+    }
+}
@@ -0,0 +1,418 @@
+/*!
+ * Async version of the `UserRepository`, for the applications running on tokio
+ *
+ * The storages are blocking (diesel), so `SpawnBlocking` runs them on the
+ * blocking threads of tokio rather than on the threads serving the requests.
+ * The async flows (e.g. `login::login_async`) run the same way: the password
+ * hashing, the response padding & the storage calls all happen on a blocking
+ * thread, see `run_blocking`.
+ *
+ * # Author
+ * Doran Kayoumi <doran.kayoumi@heig-vd.ch>
+ */
+
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::runtime::Handle;
+use tokio::task;
+
+use super::{InMemoryUserRepository, UserFilter, UserRepository};
+use crate::db::models::{User, UserChangeset};
+use crate::errors::UserDBError;
+
+/// Same operations as `UserRepository`, see it for their description
+/// Note: there's no `iter_users`, the users are listed with `list_users`
+#[async_trait]
+pub trait AsyncUserRepository: Send + Sync {
+    async fn get_user(&self, e: &str) -> Result<User, UserDBError>;
+
+    async fn create_user(&self, e: &str, passwd: &str) -> Result<(), UserDBError>;
+
+    async fn update_user(&self, u: &User) -> Result<(), UserDBError>;
+
+    async fn patch_user(&self, user: i32, changes: &UserChangeset) -> Result<(), UserDBError>;
+
+    async fn update_user_atomic(
+        &self,
+        expected: &User,
+        changes: &UserChangeset,
+    ) -> Result<bool, UserDBError>;
+
+    async fn delete_user(&self, user: i32) -> Result<(), UserDBError>;
+
+    async fn update_many(
+        &self,
+        filter: &UserFilter,
+        changes: &UserChangeset,
+    ) -> Result<usize, UserDBError>;
+
+    async fn list_users(&self, filter: &UserFilter) -> Result<Vec<User>, UserDBError>;
+
+    async fn search_users(&self, query: &str, limit: i64) -> Result<Vec<User>, UserDBError>;
+
+    async fn get_attributes(&self, user: i32) -> Result<HashMap<String, String>, UserDBError>;
+
+    async fn set_attribute(&self, user: i32, attr: &str, val: &str) -> Result<(), UserDBError>;
+
+    async fn remove_attribute(&self, user: i32, attr: &str) -> Result<(), UserDBError>;
+}
+
+/// `AsyncUserRepository` running the calls of a blocking repository (e.g. `SQliteUserRepository`)
+/// on the blocking threads of tokio
+pub struct SpawnBlocking<R> {
+    inner: Arc<R>,
+}
+
+impl<R: UserRepository + Send + Sync + 'static> SpawnBlocking<R> {
+    /// # Arguments
+    ///
+    /// * `inner` - the blocking repository
+    ///
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner: Arc::new(inner),
+        }
+    }
+
+    /// Run a call of the blocking repository on a blocking thread
+    ///
+    /// # Arguments
+    ///
+    /// * `failed` - the error if the call couldn't run (e.g. the runtime is shutting down)
+    ///
+    /// * `call` - the call to run
+    ///
+    async fn spawn<T, F>(&self, failed: UserDBError, call: F) -> Result<T, UserDBError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&R) -> Result<T, UserDBError> + Send + 'static,
+    {
+        let inner = Arc::clone(&self.inner);
+        task::spawn_blocking(move || call(&inner))
+            .await
+            .unwrap_or(Err(failed))
+    }
+}
+
+#[async_trait]
+impl<R: UserRepository + Send + Sync + 'static> AsyncUserRepository for SpawnBlocking<R> {
+    async fn get_user(&self, e: &str) -> Result<User, UserDBError> {
+        let e = e.to_string();
+        self.spawn(UserDBError::GetUserError, move |r| r.get_user(&e))
+            .await
+    }
+
+    async fn create_user(&self, e: &str, passwd: &str) -> Result<(), UserDBError> {
+        let (e, passwd) = (e.to_string(), passwd.to_string());
+        self.spawn(UserDBError::CreateUserError, move |r| {
+            r.create_user(&e, &passwd)
+        })
+        .await
+    }
+
+    async fn update_user(&self, u: &User) -> Result<(), UserDBError> {
+        let u = u.clone();
+        self.spawn(UserDBError::UpdateUserError, move |r| r.update_user(&u))
+            .await
+    }
+
+    async fn patch_user(&self, user: i32, changes: &UserChangeset) -> Result<(), UserDBError> {
+        let changes = changes.clone();
+        self.spawn(UserDBError::UpdateUserError, move |r| {
+            r.patch_user(user, &changes)
+        })
+        .await
+    }
+
+    async fn update_user_atomic(
+        &self,
+        expected: &User,
+        changes: &UserChangeset,
+    ) -> Result<bool, UserDBError> {
+        let (expected, changes) = (expected.clone(), changes.clone());
+        self.spawn(UserDBError::UpdateUserError, move |r| {
+            r.update_user_atomic(&expected, &changes)
+        })
+        .await
+    }
+
+    async fn delete_user(&self, user: i32) -> Result<(), UserDBError> {
+        self.spawn(UserDBError::DeleteUserError, move |r| r.delete_user(user))
+            .await
+    }
+
+    async fn update_many(
+        &self,
+        filter: &UserFilter,
+        changes: &UserChangeset,
+    ) -> Result<usize, UserDBError> {
+        let (filter, changes) = (filter.clone(), changes.clone());
+        self.spawn(UserDBError::UpdateUserError, move |r| {
+            r.update_many(&filter, &changes)
+        })
+        .await
+    }
+
+    async fn list_users(&self, filter: &UserFilter) -> Result<Vec<User>, UserDBError> {
+        let filter = filter.clone();
+        self.spawn(UserDBError::ListUsersError, move |r| r.list_users(&filter))
+            .await
+    }
+
+    async fn search_users(&self, query: &str, limit: i64) -> Result<Vec<User>, UserDBError> {
+        let query = query.to_string();
+        self.spawn(UserDBError::ListUsersError, move |r| {
+            r.search_users(&query, limit)
+        })
+        .await
+    }
+
+    async fn get_attributes(&self, user: i32) -> Result<HashMap<String, String>, UserDBError> {
+        self.spawn(UserDBError::GetAttributesError, move |r| {
+            r.get_attributes(user)
+        })
+        .await
+    }
+
+    async fn set_attribute(&self, user: i32, attr: &str, val: &str) -> Result<(), UserDBError> {
+        let (attr, val) = (attr.to_string(), val.to_string());
+        self.spawn(UserDBError::UpdateAttributesError, move |r| {
+            r.set_attribute(user, &attr, &val)
+        })
+        .await
+    }
+
+    async fn remove_attribute(&self, user: i32, attr: &str) -> Result<(), UserDBError> {
+        let attr = attr.to_string();
+        self.spawn(UserDBError::UpdateAttributesError, move |r| {
+            r.remove_attribute(user, &attr)
+        })
+        .await
+    }
+}
+
+/// The users are in memory, the calls don't block long enough to need a blocking thread
+#[async_trait]
+impl AsyncUserRepository for InMemoryUserRepository {
+    async fn get_user(&self, e: &str) -> Result<User, UserDBError> {
+        UserRepository::get_user(self, e)
+    }
+
+    async fn create_user(&self, e: &str, passwd: &str) -> Result<(), UserDBError> {
+        UserRepository::create_user(self, e, passwd)
+    }
+
+    async fn update_user(&self, u: &User) -> Result<(), UserDBError> {
+        UserRepository::update_user(self, u)
+    }
+
+    async fn patch_user(&self, user: i32, changes: &UserChangeset) -> Result<(), UserDBError> {
+        UserRepository::patch_user(self, user, changes)
+    }
+
+    async fn update_user_atomic(
+        &self,
+        expected: &User,
+        changes: &UserChangeset,
+    ) -> Result<bool, UserDBError> {
+        UserRepository::update_user_atomic(self, expected, changes)
+    }
+
+    async fn delete_user(&self, user: i32) -> Result<(), UserDBError> {
+        UserRepository::delete_user(self, user)
+    }
+
+    async fn update_many(
+        &self,
+        filter: &UserFilter,
+        changes: &UserChangeset,
+    ) -> Result<usize, UserDBError> {
+        UserRepository::update_many(self, filter, changes)
+    }
+
+    async fn list_users(&self, filter: &UserFilter) -> Result<Vec<User>, UserDBError> {
+        UserRepository::list_users(self, filter)
+    }
+
+    async fn search_users(&self, query: &str, limit: i64) -> Result<Vec<User>, UserDBError> {
+        UserRepository::search_users(self, query, limit)
+    }
+
+    async fn get_attributes(&self, user: i32) -> Result<HashMap<String, String>, UserDBError> {
+        UserRepository::get_attributes(self, user)
+    }
+
+    async fn set_attribute(&self, user: i32, attr: &str, val: &str) -> Result<(), UserDBError> {
+        UserRepository::set_attribute(self, user, attr, val)
+    }
+
+    async fn remove_attribute(&self, user: i32, attr: &str) -> Result<(), UserDBError> {
+        UserRepository::remove_attribute(self, user, attr)
+    }
+}
+
+/// Blocking `UserRepository` over an async one, for the flows running on a blocking thread
+/// Note: it must only be used outside of the async tasks (see `run_blocking`),
+///       waiting for a call from a task would block its runtime thread
+struct BlockOn {
+    inner: Arc<dyn AsyncUserRepository>,
+    runtime: Handle,
+}
+
+impl UserRepository for BlockOn {
+    fn get_user(&self, e: &str) -> Result<User, UserDBError> {
+        self.runtime.block_on(self.inner.get_user(e))
+    }
+
+    fn create_user(&self, e: &str, passwd: &str) -> Result<(), UserDBError> {
+        self.runtime.block_on(self.inner.create_user(e, passwd))
+    }
+
+    fn update_user(&self, u: &User) -> Result<(), UserDBError> {
+        self.runtime.block_on(self.inner.update_user(u))
+    }
+
+    fn patch_user(&self, user: i32, changes: &UserChangeset) -> Result<(), UserDBError> {
+        self.runtime.block_on(self.inner.patch_user(user, changes))
+    }
+
+    fn update_user_atomic(
+        &self,
+        expected: &User,
+        changes: &UserChangeset,
+    ) -> Result<bool, UserDBError> {
+        self.runtime
+            .block_on(self.inner.update_user_atomic(expected, changes))
+    }
+
+    fn delete_user(&self, user: i32) -> Result<(), UserDBError> {
+        self.runtime.block_on(self.inner.delete_user(user))
+    }
+
+    fn update_many(
+        &self,
+        filter: &UserFilter,
+        changes: &UserChangeset,
+    ) -> Result<usize, UserDBError> {
+        self.runtime
+            .block_on(self.inner.update_many(filter, changes))
+    }
+
+    fn list_users(&self, filter: &UserFilter) -> Result<Vec<User>, UserDBError> {
+        self.runtime.block_on(self.inner.list_users(filter))
+    }
+
+    fn iter_users(
+        &self,
+        filter: &UserFilter,
+    ) -> Box<dyn Iterator<Item = Result<User, UserDBError>>> {
+        match self.list_users(filter) {
+            Ok(users) => Box::new(users.into_iter().map(Ok)),
+            Err(e) => Box::new(std::iter::once(Err(e))),
+        }
+    }
+
+    fn search_users(&self, query: &str, limit: i64) -> Result<Vec<User>, UserDBError> {
+        self.runtime.block_on(self.inner.search_users(query, limit))
+    }
+
+    fn get_attributes(&self, user: i32) -> Result<HashMap<String, String>, UserDBError> {
+        self.runtime.block_on(self.inner.get_attributes(user))
+    }
+
+    fn set_attribute(&self, user: i32, attr: &str, val: &str) -> Result<(), UserDBError> {
+        self.runtime
+            .block_on(self.inner.set_attribute(user, attr, val))
+    }
+
+    fn remove_attribute(&self, user: i32, attr: &str) -> Result<(), UserDBError> {
+        self.runtime
+            .block_on(self.inner.remove_attribute(user, attr))
+    }
+}
+
+/// Run a blocking flow (e.g. `login_with_repository`) on a blocking thread of tokio
+/// The flow gets the async repository as a blocking one
+///
+/// # Arguments
+///
+/// * `repository` - the storage of the users
+///
+/// * `failed` - the error if the flow couldn't run (e.g. it isn't called from a tokio runtime)
+///
+/// * `flow` - the flow to run
+///
+pub(crate) async fn run_blocking<T, E, F>(
+    repository: Arc<dyn AsyncUserRepository>,
+    failed: E,
+    flow: F,
+) -> Result<T, E>
+where
+    T: Send + 'static,
+    E: Send + 'static,
+    F: FnOnce(&dyn UserRepository) -> Result<T, E> + Send + 'static,
+{
+    let runtime = match Handle::try_current() {
+        Ok(runtime) => runtime,
+        Err(_) => return Err(failed),
+    };
+    let blocking = BlockOn {
+        inner: repository,
+        runtime,
+    };
+
+    task::spawn_blocking(move || flow(&blocking))
+        .await
+        .unwrap_or(Err(failed))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::errors::AuthError;
+
+    #[tokio::test]
+    async fn test_spawn_blocking_repository() {
+        let repository = SpawnBlocking::new(InMemoryUserRepository::new());
+
+        repository
+            .create_user("alice@email.test", "passwd_hash")
+            .await
+            .unwrap();
+        assert_eq!(
+            repository
+                .create_user("alice@email.test", "passwd_hash")
+                .await,
+            Err(UserDBError::EmailUsedError)
+        );
+
+        let alice = repository.get_user("alice@email.test").await.unwrap();
+        repository
+            .set_attribute(alice.get_id(), "role", "admin")
+            .await
+            .unwrap();
+        let admins = repository
+            .list_users(&UserFilter::new().with_attribute("role", "admin"))
+            .await
+            .unwrap();
+        assert_eq!(admins, vec![alice]);
+    }
+
+    #[tokio::test]
+    async fn test_run_blocking() {
+        let repository: Arc<dyn AsyncUserRepository> = Arc::new(InMemoryUserRepository::new());
+        repository
+            .create_user("alice@email.test", "passwd_hash")
+            .await
+            .unwrap();
+
+        let found = run_blocking(repository, AuthError::LoginError, |r| {
+            r.get_user("alice@email.test")
+                .map_err(|_| AuthError::LoginError)
+        })
+        .await
+        .unwrap();
+        assert_eq!(found.get_email(), "alice@email.test");
+    }
+}
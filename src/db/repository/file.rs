@@ -0,0 +1,421 @@
+/*!
+ * Implementation of the `UserRepository` keeping the users in an encrypted file
+ *
+ * For the small tools & the demos, which shouldn't need a database. The users
+ * & their attributes are kept as JSON, encrypted with a key derived from
+ * `USER_FILE_SECRET` (XSalsa20-Poly1305, a nonce per write), so the hashes &
+ * the 2fa secrets aren't readable from the file.
+ *
+ * Every operation loads the whole file under a lock (`<path>.lock`), shared to
+ * read & exclusive to write, so several processes can use the same file. A
+ * write replaces the file at once (written aside, then renamed), a crash never
+ * leaves half of it.
+ *
+ * # Note
+ * The whole file is read & written by every operation, it's meant for a few
+ * hundred users at most.
+ *
+ * # Author
+ * Doran Kayoumi <doran.kayoumi@heig-vd.ch>
+ */
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sodiumoxide::crypto::secretbox;
+use std::collections::HashMap;
+use std::env;
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use super::{InMemoryUserRepository, UserFilter, UserRepository};
+use crate::config::UserFileConfig;
+use crate::db::models::{User, UserChangeset, UserRecord};
+use crate::errors::UserDBError;
+
+/// Variable holding the key of the file
+pub const SECRET_VARIABLE: &str = "USER_FILE_SECRET";
+/// Minimum length of the key
+pub const MIN_SECRET_LEN: usize = 32;
+
+/// Contents of the file, once decrypted
+#[derive(Serialize, Deserialize, Default)]
+struct Contents {
+    users: Vec<UserRecord>,
+    attributes: HashMap<i32, HashMap<String, String>>,
+    last_id: i32,
+}
+
+pub struct FileUserRepository {
+    path: PathBuf,
+    key: secretbox::Key,
+}
+
+impl FileUserRepository {
+    /// Repository of the users kept in a given file, created by the first write
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - the path of the file
+    ///
+    /// * `secret` - the key of the file, at least `MIN_SECRET_LEN` characters
+    ///
+    pub fn new(path: impl AsRef<Path>, secret: &str) -> Result<Self, UserDBError> {
+        if secret.len() < MIN_SECRET_LEN {
+            return Err(UserDBError::KeyError);
+        }
+        sodiumoxide::init().map_err(|_| UserDBError::KeyError)?;
+
+        let digest = Sha256::digest(secret.as_bytes());
+        Ok(Self {
+            path: path.as_ref().to_path_buf(),
+            key: secretbox::Key::from_slice(&digest).ok_or(UserDBError::KeyError)?,
+        })
+    }
+
+    /// Repository of the file set in the configuration, with the key of `USER_FILE_SECRET`
+    /// (in the environment or the `.env` file)
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - the `[user_file]` section of the configuration
+    ///
+    pub fn from_config(config: &UserFileConfig) -> Result<Self, UserDBError> {
+        dotenv::dotenv().ok();
+        let secret = env::var(SECRET_VARIABLE).map_err(|_| UserDBError::KeyError)?;
+
+        Self::new(&config.path, &secret)
+    }
+
+    /// Path next to the file, e.g. its lock
+    fn sibling(&self, suffix: &str) -> PathBuf {
+        let mut path = OsString::from(self.path.as_os_str());
+        path.push(suffix);
+        path.into()
+    }
+
+    /// Lock the file, until the returned lock is dropped
+    ///
+    /// # Arguments
+    ///
+    /// * `exclusive` - lock it to write, otherwise only the other writers wait
+    ///
+    fn lock(&self, exclusive: bool) -> io::Result<File> {
+        let lock = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(self.sibling(".lock"))?;
+        if exclusive {
+            lock.lock()?;
+        } else {
+            lock.lock_shared()?;
+        }
+
+        Ok(lock)
+    }
+
+    /// Read & decrypt the file, a missing file holds no users
+    /// Note: must be called with the lock held
+    fn load(&self) -> Option<InMemoryUserRepository> {
+        let sealed = match fs::read(&self.path) {
+            Ok(sealed) => sealed,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Some(Default::default()),
+            Err(_) => return None,
+        };
+        if sealed.len() < secretbox::NONCEBYTES {
+            return None;
+        }
+
+        let (nonce, ciphertext) = sealed.split_at(secretbox::NONCEBYTES);
+        let json =
+            secretbox::open(ciphertext, &secretbox::Nonce::from_slice(nonce)?, &self.key).ok()?;
+        let contents: Contents = serde_json::from_slice(&json).ok()?;
+
+        Some(InMemoryUserRepository::from_parts(
+            contents.users.into_iter().map(User::from).collect(),
+            contents.attributes,
+            contents.last_id,
+        ))
+    }
+
+    /// Encrypt & write the users, replacing the file
+    /// Note: must be called with the exclusive lock held
+    fn save(&self, repository: InMemoryUserRepository) -> io::Result<()> {
+        let (users, attributes, last_id) = repository.into_parts();
+        let contents = Contents {
+            users: users.iter().map(UserRecord::from).collect(),
+            attributes,
+            last_id,
+        };
+        let json = serde_json::to_vec(&contents)?;
+
+        let nonce = secretbox::gen_nonce();
+        let mut sealed = nonce.0.to_vec();
+        sealed.extend(secretbox::seal(&json, &nonce, &self.key));
+
+        let tmp = self.sibling(".tmp");
+        let mut options = OpenOptions::new();
+        options.create(true).truncate(true).write(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = options.open(&tmp)?;
+        file.write_all(&sealed)?;
+        file.sync_all()?;
+
+        fs::rename(tmp, &self.path)
+    }
+
+    /// Run an operation reading the users
+    ///
+    /// # Arguments
+    ///
+    /// * `error` - the error returned if the file can't be read
+    ///
+    /// * `op` - the operation, on the users of the file
+    ///
+    fn read<T>(
+        &self,
+        error: UserDBError,
+        op: impl FnOnce(&InMemoryUserRepository) -> Result<T, UserDBError>,
+    ) -> Result<T, UserDBError> {
+        let _lock = self.lock(false).map_err(|_| error)?;
+        let repository = self.load().ok_or(error)?;
+
+        op(&repository)
+    }
+
+    /// Run an operation changing the users, they're saved if it succeeds
+    ///
+    /// # Arguments
+    ///
+    /// * `error` - the error returned if the file can't be read or written
+    ///
+    /// * `op` - the operation, on the users of the file
+    ///
+    fn write<T>(
+        &self,
+        error: UserDBError,
+        op: impl FnOnce(&InMemoryUserRepository) -> Result<T, UserDBError>,
+    ) -> Result<T, UserDBError> {
+        let _lock = self.lock(true).map_err(|_| error)?;
+        let repository = self.load().ok_or(error)?;
+
+        let result = op(&repository)?;
+        self.save(repository).map_err(|_| error)?;
+
+        Ok(result)
+    }
+}
+
+impl UserRepository for FileUserRepository {
+    fn get_user(&self, e: &str) -> Result<User, UserDBError> {
+        self.read(UserDBError::GetUserError, |r| r.get_user(e))
+    }
+
+    fn create_user(&self, e: &str, passwd: &str) -> Result<(), UserDBError> {
+        self.write(UserDBError::CreateUserError, |r| r.create_user(e, passwd))
+    }
+
+    fn update_user(&self, u: &User) -> Result<(), UserDBError> {
+        self.write(UserDBError::UpdateUserError, |r| r.update_user(u))
+    }
+
+    fn patch_user(&self, user: i32, changes: &UserChangeset) -> Result<(), UserDBError> {
+        self.write(UserDBError::UpdateUserError, |r| {
+            r.patch_user(user, changes)
+        })
+    }
+
+    fn update_user_atomic(
+        &self,
+        expected: &User,
+        changes: &UserChangeset,
+    ) -> Result<bool, UserDBError> {
+        // Note: the exclusive lock is held from the check to the changes
+        self.write(UserDBError::UpdateUserError, |r| {
+            r.update_user_atomic(expected, changes)
+        })
+    }
+
+    fn delete_user(&self, user: i32) -> Result<(), UserDBError> {
+        self.write(UserDBError::DeleteUserError, |r| r.delete_user(user))
+    }
+
+    fn update_many(
+        &self,
+        filter: &UserFilter,
+        changes: &UserChangeset,
+    ) -> Result<usize, UserDBError> {
+        self.write(UserDBError::UpdateUserError, |r| {
+            r.update_many(filter, changes)
+        })
+    }
+
+    fn list_users(&self, filter: &UserFilter) -> Result<Vec<User>, UserDBError> {
+        self.read(UserDBError::ListUsersError, |r| r.list_users(filter))
+    }
+
+    fn iter_users(
+        &self,
+        filter: &UserFilter,
+    ) -> Box<dyn Iterator<Item = Result<User, UserDBError>>> {
+        // Note: the whole file is loaded anyway, there are no pages to load
+        match self.list_users(filter) {
+            Ok(users) => Box::new(users.into_iter().map(Ok)),
+            Err(e) => Box::new(std::iter::once(Err(e))),
+        }
+    }
+
+    fn search_users(&self, query: &str, limit: i64) -> Result<Vec<User>, UserDBError> {
+        self.read(UserDBError::ListUsersError, |r| {
+            r.search_users(query, limit)
+        })
+    }
+
+    fn get_attributes(&self, user: i32) -> Result<HashMap<String, String>, UserDBError> {
+        self.read(UserDBError::GetAttributesError, |r| r.get_attributes(user))
+    }
+
+    fn set_attribute(&self, user: i32, attr: &str, val: &str) -> Result<(), UserDBError> {
+        self.write(UserDBError::UpdateAttributesError, |r| {
+            r.set_attribute(user, attr, val)
+        })
+    }
+
+    fn remove_attribute(&self, user: i32, attr: &str) -> Result<(), UserDBError> {
+        self.write(UserDBError::UpdateAttributesError, |r| {
+            r.remove_attribute(user, attr)
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::thread;
+    use tempfile::TempDir;
+
+    const SECRET: &str = "a secret of at least thirty-two characters";
+
+    fn repository(dir: &TempDir) -> FileUserRepository {
+        FileUserRepository::new(dir.path().join("users.json.enc"), SECRET).unwrap()
+    }
+
+    #[test]
+    fn test_short_secret() {
+        let dir = TempDir::new().unwrap();
+
+        assert!(matches!(
+            FileUserRepository::new(dir.path().join("users.json.enc"), "too short"),
+            Err(UserDBError::KeyError)
+        ));
+    }
+
+    #[test]
+    fn test_persisted_between_repositories() {
+        let dir = TempDir::new().unwrap();
+        let first = repository(&dir);
+        assert_eq!(
+            first.get_user("alice@email.test"),
+            Err(UserDBError::GetUserError)
+        );
+        assert!(first.list_users(&UserFilter::new()).unwrap().is_empty());
+
+        first
+            .create_user("alice@email.test", "passwd_hash")
+            .unwrap();
+        let mut alice = first.get_user("alice@email.test").unwrap();
+        alice.set_secret_2fa(Some("secret".to_string()));
+        first.update_user(&alice).unwrap();
+        first
+            .set_attribute(alice.get_id(), "role", "admin")
+            .unwrap();
+        first.delete_user(alice.get_id()).unwrap();
+        first
+            .create_user("alice@email.test", "passwd_hash")
+            .unwrap();
+        let alice = first.get_user("alice@email.test").unwrap();
+        first.set_attribute(alice.get_id(), "role", "user").unwrap();
+
+        let second = repository(&dir);
+        assert_eq!(second.get_user("alice@email.test"), Ok(alice.clone()));
+        assert_eq!(
+            second.get_attributes(alice.get_id()).unwrap().get("role"),
+            Some(&"user".to_string())
+        );
+        // the ids given before aren't given again
+        second.create_user("bob@email.test", "passwd_hash").unwrap();
+        assert_eq!(
+            second.get_user("bob@email.test").unwrap().get_id(),
+            alice.get_id() + 1
+        );
+        // a failed operation leaves the file as it was
+        assert_eq!(
+            second.create_user("bob@email.test", "passwd_hash"),
+            Err(UserDBError::EmailUsedError)
+        );
+        assert_eq!(first.list_users(&UserFilter::new()).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_encrypted_file() {
+        let dir = TempDir::new().unwrap();
+        let repository = repository(&dir);
+        repository
+            .create_user("alice@email.test", "passwd_hash")
+            .unwrap();
+
+        let sealed = fs::read(dir.path().join("users.json.enc")).unwrap();
+        let sealed = String::from_utf8_lossy(&sealed);
+        assert!(!sealed.contains("alice@email.test"));
+        assert!(!sealed.contains("passwd_hash"));
+
+        let other_key = FileUserRepository::new(
+            dir.path().join("users.json.enc"),
+            "another secret of at least thirty-two characters",
+        )
+        .unwrap();
+        assert_eq!(
+            other_key.get_user("alice@email.test"),
+            Err(UserDBError::GetUserError)
+        );
+        assert_eq!(
+            other_key.create_user("bob@email.test", "passwd_hash"),
+            Err(UserDBError::CreateUserError)
+        );
+        assert!(matches!(
+            other_key.iter_users(&UserFilter::new()).next(),
+            Some(Err(UserDBError::ListUsersError))
+        ));
+        // the users are still there for the right key
+        assert!(repository.get_user("alice@email.test").is_ok());
+    }
+
+    #[test]
+    fn test_shared_between_processes() {
+        let dir = TempDir::new().unwrap();
+
+        // Note: a repository per thread, like separate processes using the same file
+        let handles: Vec<_> = (0..8)
+            .map(|i| {
+                let path = dir.path().join("users.json.enc");
+                thread::spawn(move || {
+                    FileUserRepository::new(path, SECRET)
+                        .unwrap()
+                        .create_user(&format!("user{}@email.test", i), "passwd_hash")
+                        .unwrap()
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        let users = repository(&dir).list_users(&UserFilter::new()).unwrap();
+        let mut ids: Vec<i32> = users.iter().map(User::get_id).collect();
+        ids.dedup();
+        assert_eq!(ids.len(), 8);
+    }
+}
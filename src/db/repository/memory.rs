@@ -0,0 +1,409 @@
+/*!
+ * Implementation of the `UserRepository` keeping the users in memory
+ *
+ * Nothing is persisted, the users are lost with the repository. It lets an
+ * application (or its tests) use the login, registration & reset flows without
+ * any database, e.g. through `register_with_repository`. The repository can be
+ * shared between threads, the users are behind a lock.
+ *
+ * # Author
+ * Doran Kayoumi <doran.kayoumi@heig-vd.ch>
+ */
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Mutex, MutexGuard};
+
+use super::{search_limit, UserFilter, UserRepository};
+use crate::db::models::{User, UserChangeset};
+use crate::errors::UserDBError;
+
+/// Users & attributes of the repository
+#[derive(Default)]
+struct Storage {
+    /// Users by id, so they're listed in the order of their creation like in the databases
+    users: BTreeMap<i32, User>,
+    attributes: HashMap<i32, HashMap<String, String>>,
+    last_id: i32,
+}
+
+impl Storage {
+    /// Check if a user matches a filter
+    fn matches(&self, u: &User, filter: &UserFilter) -> bool {
+        let attrs = self.attributes.get(&u.get_id());
+
+        filter.id.is_none_or(|user| u.get_id() == user)
+            && filter.email.as_ref().is_none_or(|e| &u.get_email() == e)
+            && filter
+                .attributes
+                .iter()
+                .all(|(attr, val)| attrs.and_then(|a| a.get(attr)) == Some(val))
+    }
+
+    fn filtered(&self, filter: &UserFilter) -> Vec<User> {
+        self.users
+            .values()
+            .filter(|u| self.matches(u, filter))
+            .cloned()
+            .collect()
+    }
+}
+
+#[derive(Default)]
+pub struct InMemoryUserRepository {
+    storage: Mutex<Storage>,
+}
+
+impl InMemoryUserRepository {
+    /// Empty repository
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Lock the storage
+    /// Note: a thread panicking while holding the lock leaves the users as they were
+    ///       before the operation, so they're fine to use afterwards
+    fn storage(&self) -> MutexGuard<'_, Storage> {
+        self.storage
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Repository holding given users & attributes (see `FileUserRepository`)
+    ///
+    /// # Arguments
+    ///
+    /// * `users` - the users, with their ids
+    ///
+    /// * `attributes` - the attributes of the users, by id
+    ///
+    /// * `last_id` - the id given to the last user created
+    ///
+    pub(super) fn from_parts(
+        users: Vec<User>,
+        attributes: HashMap<i32, HashMap<String, String>>,
+        last_id: i32,
+    ) -> Self {
+        Self {
+            storage: Mutex::new(Storage {
+                users: users.into_iter().map(|u| (u.get_id(), u)).collect(),
+                attributes,
+                last_id,
+            }),
+        }
+    }
+
+    /// Get the users, their attributes & the id given to the last user created
+    /// (see `from_parts`)
+    pub(super) fn into_parts(self) -> (Vec<User>, HashMap<i32, HashMap<String, String>>, i32) {
+        let storage = self
+            .storage
+            .into_inner()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+
+        (
+            storage.users.into_values().collect(),
+            storage.attributes,
+            storage.last_id,
+        )
+    }
+}
+
+impl UserRepository for InMemoryUserRepository {
+    fn get_user(&self, e: &str) -> Result<User, UserDBError> {
+        self.storage()
+            .users
+            .values()
+            .find(|u| u.get_email() == e)
+            .cloned()
+            .ok_or(UserDBError::GetUserError)
+    }
+
+    fn create_user(&self, e: &str, passwd: &str) -> Result<(), UserDBError> {
+        let mut storage = self.storage();
+        if storage.users.values().any(|u| u.get_email() == e) {
+            return Err(UserDBError::EmailUsedError);
+        }
+
+        storage.last_id += 1;
+        let user = storage.last_id;
+        storage
+            .users
+            .insert(user, User::new(e, passwd).with_id(user));
+
+        Ok(())
+    }
+
+    fn update_user(&self, u: &User) -> Result<(), UserDBError> {
+        let mut storage = self.storage();
+        let email_used = storage
+            .users
+            .values()
+            .any(|other| other.get_id() != u.get_id() && other.get_email() == u.get_email());
+        if email_used {
+            return Err(UserDBError::UpdateUserError);
+        }
+
+        // Note: like an update query, a missing user is left missing
+        if let Some(stored) = storage.users.get_mut(&u.get_id()) {
+            *stored = u.clone();
+        }
+
+        Ok(())
+    }
+
+    fn patch_user(&self, user: i32, changes: &UserChangeset) -> Result<(), UserDBError> {
+        if let Some(stored) = self.storage().users.get_mut(&user) {
+            stored.apply(changes);
+        }
+
+        Ok(())
+    }
+
+    fn update_user_atomic(
+        &self,
+        expected: &User,
+        changes: &UserChangeset,
+    ) -> Result<bool, UserDBError> {
+        // Note: the lock is held from the check to the changes
+        match self.storage().users.get_mut(&expected.get_id()) {
+            Some(stored) if stored == expected => {
+                stored.apply(changes);
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    fn delete_user(&self, user: i32) -> Result<(), UserDBError> {
+        let mut storage = self.storage();
+        storage.users.remove(&user);
+        storage.attributes.remove(&user);
+
+        Ok(())
+    }
+
+    fn update_many(
+        &self,
+        filter: &UserFilter,
+        changes: &UserChangeset,
+    ) -> Result<usize, UserDBError> {
+        if changes.is_empty() {
+            return Ok(0);
+        }
+
+        let mut storage = self.storage();
+        let ids: Vec<i32> = storage.filtered(filter).iter().map(User::get_id).collect();
+        for user in &ids {
+            if let Some(stored) = storage.users.get_mut(user) {
+                stored.apply(changes);
+            }
+        }
+
+        Ok(ids.len())
+    }
+
+    fn list_users(&self, filter: &UserFilter) -> Result<Vec<User>, UserDBError> {
+        Ok(self.storage().filtered(filter))
+    }
+
+    fn iter_users(
+        &self,
+        filter: &UserFilter,
+    ) -> Box<dyn Iterator<Item = Result<User, UserDBError>>> {
+        // Note: the users are already in memory, there are no pages to load
+        Box::new(self.storage().filtered(filter).into_iter().map(Ok))
+    }
+
+    fn search_users(&self, query: &str, limit: i64) -> Result<Vec<User>, UserDBError> {
+        let limit = search_limit(limit)?;
+        let mut found: Vec<User> = self
+            .storage()
+            .users
+            .values()
+            .filter(|u| u.get_email().starts_with(query))
+            .cloned()
+            .collect();
+        found.sort_by_key(User::get_email);
+        found.truncate(limit as usize);
+
+        Ok(found)
+    }
+
+    fn get_attributes(&self, user: i32) -> Result<HashMap<String, String>, UserDBError> {
+        Ok(self
+            .storage()
+            .attributes
+            .get(&user)
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    fn set_attribute(&self, user: i32, attr: &str, val: &str) -> Result<(), UserDBError> {
+        let mut storage = self.storage();
+        // Note: like the foreign key of the databases, the user must exist
+        if !storage.users.contains_key(&user) {
+            return Err(UserDBError::UpdateAttributesError);
+        }
+
+        storage
+            .attributes
+            .entry(user)
+            .or_default()
+            .insert(attr.to_string(), val.to_string());
+
+        Ok(())
+    }
+
+    fn remove_attribute(&self, user: i32, attr: &str) -> Result<(), UserDBError> {
+        if let Some(attrs) = self.storage().attributes.get_mut(&user) {
+            attrs.remove(attr);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn test_create_get_and_update_user() {
+        let repository = InMemoryUserRepository::new();
+
+        repository
+            .create_user("alice@email.test", "passwd_hash")
+            .unwrap();
+        repository
+            .create_user("bob@email.test", "passwd_hash")
+            .unwrap();
+        assert_eq!(
+            repository.create_user("alice@email.test", "passwd_hash"),
+            Err(UserDBError::EmailUsedError)
+        );
+        assert_eq!(
+            repository.get_user("carol@email.test"),
+            Err(UserDBError::GetUserError)
+        );
+
+        let mut alice = repository.get_user("alice@email.test").unwrap();
+        let bob = repository.get_user("bob@email.test").unwrap();
+        assert_ne!(alice.get_id(), bob.get_id());
+
+        alice.set_secret_2fa(Some("secret".to_string()));
+        repository.update_user(&alice).unwrap();
+        assert_eq!(repository.get_user("alice@email.test").unwrap(), alice);
+
+        alice.set_email("bob@email.test");
+        assert_eq!(
+            repository.update_user(&alice),
+            Err(UserDBError::UpdateUserError)
+        );
+
+        repository
+            .patch_user(
+                bob.get_id(),
+                &UserChangeset::new()
+                    .secret_2fa(Some("secret".to_string()))
+                    .accepted_tos_version(Some(2)),
+            )
+            .unwrap();
+        let patched = repository.get_user("bob@email.test").unwrap();
+        assert_eq!(patched.get_secret_2fa(), Some("secret".to_string()));
+        assert_eq!(patched.get_accepted_tos_version(), Some(2));
+        assert_eq!(patched.get_password(), "passwd_hash");
+    }
+
+    #[test]
+    fn test_attributes_and_filters() {
+        let repository = InMemoryUserRepository::new();
+        for e in &["bob@email.test", "alice@email.test", "alicia@email.test"] {
+            repository.create_user(e, "passwd_hash").unwrap();
+        }
+        let bob = repository.get_user("bob@email.test").unwrap().get_id();
+        let alice = repository.get_user("alice@email.test").unwrap().get_id();
+
+        repository.set_attribute(alice, "role", "user").unwrap();
+        repository.set_attribute(alice, "role", "admin").unwrap();
+        repository.set_attribute(bob, "role", "user").unwrap();
+        assert_eq!(
+            repository.set_attribute(42, "role", "user"),
+            Err(UserDBError::UpdateAttributesError)
+        );
+
+        let admins = repository
+            .list_users(&UserFilter::new().with_attribute("role", "admin"))
+            .unwrap();
+        assert_eq!(admins.len(), 1);
+        assert_eq!(admins[0].get_id(), alice);
+
+        let updated = repository
+            .update_many(
+                &UserFilter::new().with_attribute("role", "user"),
+                &UserChangeset::new().accepted_tos_version(Some(3)),
+            )
+            .unwrap();
+        assert_eq!(updated, 1);
+        assert_eq!(
+            repository
+                .get_user("bob@email.test")
+                .unwrap()
+                .get_accepted_tos_version(),
+            Some(3)
+        );
+
+        let emails: Vec<String> = repository
+            .iter_users(&UserFilter::new())
+            .map(|u| u.unwrap().get_email())
+            .collect();
+        assert_eq!(
+            emails,
+            vec!["bob@email.test", "alice@email.test", "alicia@email.test"]
+        );
+
+        let found: Vec<String> = repository
+            .search_users("ali", 10)
+            .unwrap()
+            .iter()
+            .map(|u| u.get_email())
+            .collect();
+        assert_eq!(found, vec!["alice@email.test", "alicia@email.test"]);
+        assert_eq!(repository.search_users("ali", 1).unwrap().len(), 1);
+
+        repository.remove_attribute(bob, "role").unwrap();
+        assert!(repository.get_attributes(bob).unwrap().is_empty());
+
+        repository.delete_user(alice).unwrap();
+        assert!(repository.get_attributes(alice).unwrap().is_empty());
+        assert_eq!(
+            repository.get_user("alice@email.test"),
+            Err(UserDBError::GetUserError)
+        );
+    }
+
+    #[test]
+    fn test_shared_between_threads() {
+        let repository = Arc::new(InMemoryUserRepository::new());
+
+        let handles: Vec<_> = (0..8)
+            .map(|i| {
+                let repository = Arc::clone(&repository);
+                thread::spawn(move || {
+                    repository
+                        .create_user(&format!("user{}@email.test", i), "passwd_hash")
+                        .unwrap()
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        let users = repository.list_users(&UserFilter::new()).unwrap();
+        let mut ids: Vec<i32> = users.iter().map(User::get_id).collect();
+        ids.dedup();
+        assert_eq!(ids.len(), 8);
+    }
+}
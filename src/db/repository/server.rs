@@ -0,0 +1,82 @@
+/*!
+ * Tables & changesets shared by the storages running on a database server
+ * (PostgreSQL & MySQL, see `postgres.rs` & `mysql.rs`)
+ *
+ * # Author
+ * Doran Kayoumi <doran.kayoumi@heig-vd.ch>
+ */
+
+use crate::db::models::{User, UserChangeset};
+
+/// Tables of the users & their attributes
+/// Note: unlike the SQLite schema, the dates are declared as text (RFC 3339, like the other dates)
+pub(super) mod schema {
+    table! {
+        users (id) {
+            id -> Integer,
+            email -> Text,
+            password -> Text,
+            secret_2fa -> Nullable<Text>,
+            anti_phishing_phrase -> Nullable<Text>,
+            accepted_tos_version -> Nullable<Integer>,
+            pending_secret_2fa -> Nullable<Text>,
+            kind -> Text,
+        }
+    }
+
+    table! {
+        user_attributes (user_id, name) {
+            user_id -> Integer,
+            name -> Text,
+            value -> Text,
+        }
+    }
+
+    joinable!(user_attributes -> users (user_id));
+
+    allow_tables_to_appear_in_same_query!(user_attributes, users);
+}
+
+use self::schema::users as users_schema;
+
+/// Changes to apply to users, see `UserChangeset`
+#[derive(AsChangeset)]
+#[table_name = "users_schema"]
+pub(super) struct ServerUserChangeset {
+    email: Option<String>,
+    password: Option<String>,
+    secret_2fa: Option<Option<String>>,
+    anti_phishing_phrase: Option<Option<String>>,
+    accepted_tos_version: Option<Option<i32>>,
+    pending_secret_2fa: Option<Option<String>>,
+    kind: Option<String>,
+}
+
+impl From<&UserChangeset> for ServerUserChangeset {
+    fn from(c: &UserChangeset) -> Self {
+        Self {
+            email: None,
+            password: c.password.clone(),
+            secret_2fa: c.secret_2fa.clone(),
+            anti_phishing_phrase: c.anti_phishing_phrase.clone(),
+            accepted_tos_version: c.accepted_tos_version,
+            pending_secret_2fa: c.pending_secret_2fa.clone(),
+            kind: c.kind.clone(),
+        }
+    }
+}
+
+/// Every column is rewritten, like the changeset of `User`
+impl From<&User> for ServerUserChangeset {
+    fn from(u: &User) -> Self {
+        Self {
+            email: Some(u.get_email()),
+            password: Some(u.get_password()),
+            secret_2fa: Some(u.get_secret_2fa()),
+            anti_phishing_phrase: Some(u.get_anti_phishing_phrase()),
+            accepted_tos_version: Some(u.get_accepted_tos_version()),
+            pending_secret_2fa: Some(u.get_pending_secret_2fa()),
+            kind: Some(u.get_kind().to_string()),
+        }
+    }
+}
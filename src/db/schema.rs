@@ -1,10 +1,121 @@
+table! {
+    audit_log (id) {
+        id -> Integer,
+        user_id -> Nullable<Integer>,
+        event -> Text,
+        details -> Nullable<Text>,
+        created_at -> Timestamp,
+        subject_id -> Nullable<Integer>,
+        hash -> Nullable<Text>,
+    }
+}
+
+table! {
+    contact_recoveries (id) {
+        id -> Integer,
+        user_id -> Integer,
+        contact_email -> Text,
+        token_hash -> Text,
+        requested_at -> Timestamp,
+        approved_at -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    ephemeral_values (key) {
+        key -> Text,
+        value -> Text,
+        expires_at -> Timestamp,
+    }
+}
+
+table! {
+    job_leases (job) {
+        job -> Text,
+        holder -> Text,
+        expires_at -> Timestamp,
+    }
+}
+
+table! {
+    notification_dedupe (recipient, fingerprint) {
+        recipient -> Text,
+        fingerprint -> Text,
+        last_sent_at -> Timestamp,
+        suppressed -> Integer,
+    }
+}
+
+table! {
+    password_stats (context, score) {
+        context -> Text,
+        score -> Integer,
+        count -> Integer,
+    }
+}
+
+table! {
+    redeemed_action_links (jti) {
+        jti -> Text,
+        expires_at -> Timestamp,
+    }
+}
+
+table! {
+    recovery_codes (id) {
+        id -> Integer,
+        user_id -> Integer,
+        code_hash -> Text,
+        used_at -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    trusted_contacts (id) {
+        id -> Integer,
+        user_id -> Integer,
+        email -> Text,
+        confirmation_hash -> Nullable<Text>,
+        confirmed_at -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    user_attributes (user_id, name) {
+        user_id -> Integer,
+        name -> Text,
+        value -> Text,
+    }
+}
+
 table! {
     users (id) {
         id -> Integer,
         email -> Text,
         password -> Text,
         secret_2fa -> Nullable<Text>,
-        reset_token -> Nullable<Text>,
-        reset_token_created_at -> Nullable<Timestamp>,
+        anti_phishing_phrase -> Nullable<Text>,
+        accepted_tos_version -> Nullable<Integer>,
+        pending_secret_2fa -> Nullable<Text>,
+        kind -> Text,
     }
 }
+
+joinable!(contact_recoveries -> users (user_id));
+joinable!(recovery_codes -> users (user_id));
+joinable!(trusted_contacts -> users (user_id));
+joinable!(user_attributes -> users (user_id));
+
+allow_tables_to_appear_in_same_query!(
+    audit_log,
+    contact_recoveries,
+    ephemeral_values,
+    job_leases,
+    notification_dedupe,
+    password_stats,
+    recovery_codes,
+    redeemed_action_links,
+    trusted_contacts,
+    user_attributes,
+    users,
+);
@@ -0,0 +1,178 @@
+/*!
+ * Deterministic seeding of development databases
+ *
+ * Creates a known set of users so nobody needs to go through the registration
+ * by hand to get a usable environment. Their passwords (& 2fa secrets) are
+ * generated for each database & only shown once, by `seed`: no credentials are
+ * shared between the environments. Never seed a production database all the same!
+ *
+ * # Note
+ * The system doesn't handle roles, locked accounts or email verification yet,
+ * those states are stored as user attributes (see `SeedUser::attributes`).
+ *
+ * # Author
+ * Doran Kayoumi <doran.kayoumi@heig-vd.ch>
+ */
+
+use google_authenticator::GoogleAuthenticator;
+
+use super::models::UserChangeset;
+use super::repository::UserRepository;
+use crate::errors::UserDBError;
+use crate::utils;
+
+/// A user created by the seeding
+#[derive(PartialEq, Debug)]
+pub struct SeedUser {
+    pub email: &'static str,
+    /// What the user is meant to showcase
+    pub description: &'static str,
+    /// Whether a 2fa secret is generated for the user
+    pub with_2fa: bool,
+    pub attributes: &'static [(&'static str, &'static str)],
+}
+
+/// Credentials of a user created by the seeding, generated for this database
+#[derive(PartialEq, Debug)]
+pub struct Credentials {
+    pub email: &'static str,
+    pub description: &'static str,
+    pub password: String,
+    pub secret_2fa: Option<String>,
+}
+
+#[derive(PartialEq, Debug, Clone, Copy, strum_macros::EnumString, strum_macros::Display)]
+#[strum(serialize_all = "lowercase")]
+pub enum Profile {
+    /// One user of each kind, for frontend development & demos
+    Demo,
+}
+
+const DEMO_USERS: &[SeedUser] = &[
+    SeedUser {
+        email: "admin@secure-auth.test",
+        description: "administrator",
+        with_2fa: false,
+        attributes: &[("role", "admin")],
+    },
+    SeedUser {
+        email: "2fa@secure-auth.test",
+        description: "two-factor authentication enabled",
+        with_2fa: true,
+        attributes: &[],
+    },
+    SeedUser {
+        email: "locked@secure-auth.test",
+        description: "locked account",
+        with_2fa: false,
+        attributes: &[("locked", "true")],
+    },
+    SeedUser {
+        email: "unverified@secure-auth.test",
+        description: "email not verified",
+        with_2fa: false,
+        attributes: &[("email_verified", "false")],
+    },
+];
+
+impl Profile {
+    /// The users created by the profile
+    pub fn users(&self) -> &'static [SeedUser] {
+        match self {
+            Profile::Demo => DEMO_USERS,
+        }
+    }
+}
+
+/// Create the users of a profile
+/// The users that already exist are left untouched, so seeding twice is harmless
+/// Returns the credentials of the users created, if something goes wrong, an error is returned
+///
+/// # Arguments
+///
+/// * `profile` - the set of users to create
+///
+/// * `repository` - the user repository to interact with
+///
+pub fn seed(
+    profile: Profile,
+    repository: &dyn UserRepository,
+) -> Result<Vec<Credentials>, UserDBError> {
+    let mut created = Vec::new();
+
+    for s in profile.users() {
+        let credentials = Credentials {
+            email: s.email,
+            description: s.description,
+            password: utils::gen_token(),
+            secret_2fa: if s.with_2fa {
+                Some(GoogleAuthenticator::new().create_secret(32))
+            } else {
+                None
+            },
+        };
+        let pwh = utils::hash(&credentials.password).ok_or(UserDBError::CreateUserError)?;
+        match repository.create_user(s.email, &pwh) {
+            Ok(()) => {}
+            Err(UserDBError::EmailUsedError) => continue,
+            Err(e) => return Err(e),
+        }
+
+        let u = repository.get_user(s.email)?;
+        if let Some(secret) = &credentials.secret_2fa {
+            let changes = UserChangeset::new().secret_2fa(Some(secret.clone()));
+            repository.patch_user(u.get_id(), &changes)?;
+        }
+        for (attr, val) in s.attributes {
+            repository.set_attribute(u.get_id(), attr, val)?;
+        }
+        created.push(credentials);
+    }
+
+    Ok(created)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::db::repository::SQliteUserRepository;
+    use crate::db::test_database;
+    use crate::validation::{is_email_valid, is_password_valid};
+    use std::str::FromStr;
+
+    #[test]
+    fn test_profile_from_str() {
+        assert_eq!(Profile::from_str("demo"), Ok(Profile::Demo));
+        assert!(Profile::from_str("prod").is_err());
+    }
+
+    #[test]
+    fn test_demo_users_are_valid() {
+        for s in Profile::Demo.users() {
+            assert!(is_email_valid(s.email));
+        }
+    }
+
+    #[test]
+    fn test_seed_twice() {
+        let (_dir, url) = test_database();
+        let repository = SQliteUserRepository::with_database_url(&url);
+
+        let created = seed(Profile::Demo, &repository).unwrap();
+        assert_eq!(created.len(), 4);
+        assert_eq!(seed(Profile::Demo, &repository), Ok(vec![]));
+
+        for c in &created {
+            assert!(is_password_valid(&c.password));
+            let u = repository.get_user(c.email).unwrap();
+            assert!(utils::verify_hash(&c.password, &u.get_password()));
+        }
+        let u = repository.get_user("2fa@secure-auth.test").unwrap();
+        assert!(u.is_2fa_enabled());
+        assert_eq!(u.get_secret_2fa(), created[1].secret_2fa);
+
+        let u = repository.get_user("admin@secure-auth.test").unwrap();
+        let attributes = repository.get_attributes(u.get_id()).unwrap();
+        assert_eq!(attributes.get("role").map(String::as_str), Some("admin"));
+    }
+}
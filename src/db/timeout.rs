@@ -0,0 +1,251 @@
+/*!
+ * Time limit on each operation of a user repository
+ *
+ * SQLite only waits for a lock up to its busy timeout, but a storage can hang
+ * in other ways (e.g. a remote server not answering or a disk stalled), which
+ * would hang the login or the reset along with it. Each operation going
+ * through a `TimeoutRepository` runs on its own thread & fails with
+ * `UserDBError::Timeout` (so `AuthError::Timeout` in the flows) once the limit
+ * of the `[database]` section of the configuration (`operation_timeout_ms`) is
+ * reached.
+ *
+ * # Note
+ * An operation timing out isn't cancelled, it finishes in the background & its
+ * result is dropped. `iter_users` isn't limited, the batches are read lazily.
+ * The mailers have their own timeouts (see the `[mail]` section).
+ *
+ * # Author
+ * Doran Kayoumi <doran.kayoumi@heig-vd.ch>
+ */
+
+use std::collections::HashMap;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use super::models::{User, UserChangeset};
+use super::repository::{UserFilter, UserRepository};
+use crate::config::DatabaseConfig;
+use crate::errors::UserDBError;
+
+/// `UserRepository` failing the operations of another one which take too long
+pub struct TimeoutRepository<R: UserRepository + Send + Sync + 'static> {
+    inner: Arc<R>,
+    limit: Duration,
+}
+
+impl<R: UserRepository + Send + Sync + 'static> TimeoutRepository<R> {
+    /// # Arguments
+    ///
+    /// * `inner` - the repository running the operations
+    ///
+    /// * `limit` - how long an operation may take, none if zero
+    ///
+    pub fn new(inner: R, limit: Duration) -> Self {
+        Self {
+            inner: Arc::new(inner),
+            limit,
+        }
+    }
+
+    /// Same as `new`, with the limit of the configuration
+    ///
+    /// # Arguments
+    ///
+    /// * `inner` - the repository running the operations
+    ///
+    /// * `config` - the database section of the configuration
+    ///
+    pub fn from_config(inner: R, config: &DatabaseConfig) -> Self {
+        Self::new(inner, Duration::from_millis(config.operation_timeout_ms))
+    }
+
+    pub fn inner(&self) -> &R {
+        &self.inner
+    }
+
+    /// Run an operation on the inner repository, within the limit
+    ///
+    /// # Arguments
+    ///
+    /// * `own` - the error of the operation, if its thread panicked
+    ///
+    /// * `op` - the operation
+    ///
+    fn run<T, F>(&self, own: UserDBError, op: F) -> Result<T, UserDBError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&R) -> Result<T, UserDBError> + Send + 'static,
+    {
+        if self.limit.is_zero() {
+            return op(&self.inner);
+        }
+
+        let inner = Arc::clone(&self.inner);
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            // Note: the receiver is gone if the operation timed out
+            let _ = sender.send(op(&inner));
+        });
+
+        match receiver.recv_timeout(self.limit) {
+            Ok(res) => res,
+            Err(RecvTimeoutError::Timeout) => Err(UserDBError::Timeout),
+            Err(RecvTimeoutError::Disconnected) => Err(own),
+        }
+    }
+}
+
+impl<R: UserRepository + Send + Sync + 'static> UserRepository for TimeoutRepository<R> {
+    fn get_user(&self, e: &str) -> Result<User, UserDBError> {
+        let e = e.to_string();
+        self.run(UserDBError::GetUserError, move |r| r.get_user(&e))
+    }
+
+    fn create_user(&self, e: &str, passwd: &str) -> Result<(), UserDBError> {
+        let (e, passwd) = (e.to_string(), passwd.to_string());
+        self.run(UserDBError::CreateUserError, move |r| {
+            r.create_user(&e, &passwd)
+        })
+    }
+
+    fn update_user(&self, u: &User) -> Result<(), UserDBError> {
+        let u = u.clone();
+        self.run(UserDBError::UpdateUserError, move |r| r.update_user(&u))
+    }
+
+    fn patch_user(&self, user: i32, changes: &UserChangeset) -> Result<(), UserDBError> {
+        let changes = changes.clone();
+        self.run(UserDBError::UpdateUserError, move |r| {
+            r.patch_user(user, &changes)
+        })
+    }
+
+    fn update_user_atomic(
+        &self,
+        expected: &User,
+        changes: &UserChangeset,
+    ) -> Result<bool, UserDBError> {
+        let (expected, changes) = (expected.clone(), changes.clone());
+        self.run(UserDBError::UpdateUserError, move |r| {
+            r.update_user_atomic(&expected, &changes)
+        })
+    }
+
+    fn delete_user(&self, user: i32) -> Result<(), UserDBError> {
+        self.run(UserDBError::DeleteUserError, move |r| r.delete_user(user))
+    }
+
+    fn update_many(
+        &self,
+        filter: &UserFilter,
+        changes: &UserChangeset,
+    ) -> Result<usize, UserDBError> {
+        let (filter, changes) = (filter.clone(), changes.clone());
+        self.run(UserDBError::UpdateUserError, move |r| {
+            r.update_many(&filter, &changes)
+        })
+    }
+
+    fn list_users(&self, filter: &UserFilter) -> Result<Vec<User>, UserDBError> {
+        let filter = filter.clone();
+        self.run(UserDBError::ListUsersError, move |r| r.list_users(&filter))
+    }
+
+    fn iter_users(
+        &self,
+        filter: &UserFilter,
+    ) -> Box<dyn Iterator<Item = Result<User, UserDBError>>> {
+        self.inner.iter_users(filter)
+    }
+
+    fn search_users(&self, query: &str, limit: i64) -> Result<Vec<User>, UserDBError> {
+        let query = query.to_string();
+        self.run(UserDBError::ListUsersError, move |r| {
+            r.search_users(&query, limit)
+        })
+    }
+
+    fn get_attributes(&self, user: i32) -> Result<HashMap<String, String>, UserDBError> {
+        self.run(UserDBError::GetAttributesError, move |r| {
+            r.get_attributes(user)
+        })
+    }
+
+    fn set_attribute(&self, user: i32, attr: &str, val: &str) -> Result<(), UserDBError> {
+        let (attr, val) = (attr.to_string(), val.to_string());
+        self.run(UserDBError::UpdateAttributesError, move |r| {
+            r.set_attribute(user, &attr, &val)
+        })
+    }
+
+    fn remove_attribute(&self, user: i32, attr: &str) -> Result<(), UserDBError> {
+        let attr = attr.to_string();
+        self.run(UserDBError::UpdateAttributesError, move |r| {
+            r.remove_attribute(user, &attr)
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::db::flaky::{Faults, FlakyRepository};
+    use crate::db::repository::InMemoryUserRepository;
+    use std::time::Instant;
+
+    fn repository(latency: Duration) -> FlakyRepository<InMemoryUserRepository> {
+        let inner = InMemoryUserRepository::new();
+        inner
+            .create_user("email@email.test", "passwd_hash")
+            .unwrap();
+        FlakyRepository::new(
+            inner,
+            Faults {
+                latency,
+                ..Faults::default()
+            },
+        )
+    }
+
+    #[test]
+    fn test_slow_operation_times_out() {
+        let timeout = TimeoutRepository::new(
+            repository(Duration::from_millis(500)),
+            Duration::from_millis(50),
+        );
+
+        let start = Instant::now();
+        assert_eq!(
+            timeout.get_user("email@email.test").map(|u| u.get_email()),
+            Err(UserDBError::Timeout)
+        );
+        assert!(start.elapsed() < Duration::from_millis(400));
+    }
+
+    #[test]
+    fn test_operation_within_the_limit() {
+        let timeout =
+            TimeoutRepository::new(repository(Duration::from_millis(0)), Duration::from_secs(5));
+
+        assert_eq!(
+            timeout.get_user("email@email.test").map(|u| u.get_email()),
+            Ok("email@email.test".to_string())
+        );
+        assert_eq!(
+            timeout.get_user("other@email.test").map(|u| u.get_email()),
+            Err(UserDBError::GetUserError)
+        );
+    }
+
+    #[test]
+    fn test_no_limit() {
+        let timeout = TimeoutRepository::new(
+            repository(Duration::from_millis(20)),
+            Duration::from_millis(0),
+        );
+
+        assert!(timeout.get_user("email@email.test").is_ok());
+    }
+}
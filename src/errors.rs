@@ -5,16 +5,28 @@
  * To simplify the definition of messages n' stuff, the crates `strum` & `strum_macros`
  * were used (thank you SEC Midterm :D)
  *
+ * Every error has a stable code & a suggested HTTP status, see `catalog.rs`.
+ *
  * # Author
  * Doran Kayoumi <doran.kayoumi@heig-vd.ch>
  */
 
+pub mod catalog;
+
 use std::error;
 use std::fmt;
 use strum::EnumMessage;
-use strum_macros;
 
-#[derive(PartialEq, Debug, strum_macros::EnumMessage)]
+#[derive(
+    PartialEq,
+    Debug,
+    Clone,
+    Copy,
+    strum_macros::EnumMessage,
+    strum_macros::EnumIter,
+    strum_macros::IntoStaticStr,
+)]
+#[strum(serialize_all = "snake_case")]
 pub enum AuthError {
     #[strum(message = "Your login details are incorrect.")]
     LoginError,
@@ -31,14 +43,216 @@ pub enum AuthError {
     #[strum(message = "Your password must be between 8 and 64 characters long.")]
     InvalidPassword,
 
+    #[strum(message = "The value you entered is too long.")]
+    InputTooLong,
+
     #[strum(message = "This e-mail address is already used for another account.")]
     EmailUsed,
 
+    #[strum(message = "Registrations with this e-mail domain aren't allowed.")]
+    EmailDomainNotAllowed,
+
+    #[strum(message = "This e-mail is reserved, please choose another one.")]
+    ReservedEmail,
+
+    #[strum(message = "Your consent is required to register.")]
+    ConsentRequired,
+
+    #[strum(message = "Your registration was rejected.")]
+    RegistrationRejected,
+
     #[strum(message = "Reset token is expired.")]
     ExpiredToken,
 
     #[strum(message = "You've entered an ivalid token.")]
     TokenMismatch,
+
+    #[strum(message = "You must accept the terms of service to continue.")]
+    TosNotAccepted,
+
+    #[strum(message = "Something went wrong while accepting the terms of service.")]
+    TosAcceptanceError,
+
+    #[strum(message = "Two-factor authentication isn't enabled.")]
+    TwoFaNotEnabled,
+
+    #[strum(message = "Two-factor authentication is already enabled.")]
+    TwoFaAlreadyEnabled,
+
+    #[strum(message = "There's no new two-factor authentication secret to activate.")]
+    NoPendingSecret,
+
+    #[strum(message = "Incorrect authentication code.")]
+    InvalidAuthenticationCode,
+
+    #[strum(message = "Something went wrong while changing the two-factor authentication secret.")]
+    SecretRotationError,
+
+    #[strum(message = "Incorrect recovery code.")]
+    InvalidRecoveryCode,
+
+    #[strum(message = "Something went wrong with the recovery codes.")]
+    RecoveryCodesError,
+
+    #[strum(message = "Your login attempt expired, please login again.")]
+    ChallengeExpired,
+
+    #[strum(message = "Your login attempt is no longer valid, please login again.")]
+    InvalidChallenge,
+
+    #[strum(message = "Your login attempt was started from another client, please login again.")]
+    ClientMismatch,
+
+    #[strum(message = "This second factor isn't available.")]
+    UnknownFactor,
+
+    #[strum(message = "Incorrect password.")]
+    IncorrectPassword,
+
+    #[strum(message = "A code of your second factor is required to confirm this action.")]
+    StepUpRequired,
+
+    #[strum(
+        message = "This login looks unusual, please confirm it with the token sent to your email address."
+    )]
+    EmailConfirmationRequired,
+
+    #[strum(message = "This login looks too risky and was blocked, please try again later.")]
+    LoginBlocked,
+
+    #[strum(message = "Too many failed attempts, please prove you're human first.")]
+    CaptchaRequired,
+
+    #[strum(message = "Wrong answer, please try again.")]
+    InvalidCaptcha,
+
+    #[strum(
+        message = "This account is on security hold, contact an administrator or recover it with your reset token & second factor."
+    )]
+    AccountOnHold,
+
+    #[strum(message = "This account isn't on security hold.")]
+    NotOnHold,
+
+    #[strum(message = "Unable to update the security hold of the account.")]
+    HoldError,
+
+    #[strum(message = "A user can't have more than two trusted contacts.")]
+    TooManyContacts,
+
+    #[strum(message = "You can't be your own trusted contact.")]
+    InvalidContact,
+
+    #[strum(message = "This approval link is invalid or was already used.")]
+    InvalidApproval,
+
+    #[strum(message = "This confirmation link is invalid or was already used.")]
+    InvalidContactConfirmation,
+
+    #[strum(message = "No recovery was started for this account.")]
+    NoRecovery,
+
+    #[strum(message = "Your trusted contacts haven't all approved the recovery yet.")]
+    RecoveryNotApproved,
+
+    #[strum(message = "The waiting period of the recovery isn't over yet.")]
+    RecoveryPending,
+
+    #[strum(message = "The recovery secret is invalid.")]
+    InvalidRecoverySecret,
+
+    #[strum(message = "The recovery expired, please start a new one.")]
+    RecoveryExpired,
+
+    #[strum(message = "Unable to recover the account with the trusted contacts.")]
+    ContactRecoveryError,
+
+    #[strum(message = "You aren't allowed to login at this time.")]
+    OutsideAllowedHours,
+
+    #[strum(message = "Unable to check the allowed login hours.")]
+    AccessHoursError,
+
+    #[strum(message = "Unable to check the inactivity of the accounts.")]
+    InactivityError,
+
+    #[strum(message = "Unable to compute the statistics.")]
+    StatsError,
+
+    #[strum(message = "Too many checks, please try again later.")]
+    TooManyChecks,
+
+    #[strum(message = "Unable to check the availability of the email.")]
+    AvailabilityError,
+
+    #[strum(message = "Your API credentials are incorrect.")]
+    InvalidApiKey,
+
+    #[strum(message = "This account isn't a service account.")]
+    NotAServiceAccount,
+
+    #[strum(message = "This isn't the SHA-256 fingerprint of a certificate.")]
+    InvalidCertificate,
+
+    #[strum(message = "Something went wrong with the service account.")]
+    ServiceAccountError,
+
+    #[strum(message = "This isn't a valid scope.")]
+    InvalidScope,
+
+    #[strum(message = "Your API credentials don't grant this permission.")]
+    MissingScope,
+
+    #[strum(message = "This link is invalid.")]
+    InvalidActionLink,
+
+    #[strum(message = "This link has expired.")]
+    ActionLinkExpired,
+
+    #[strum(message = "This link was already used.")]
+    ActionLinkUsed,
+
+    #[strum(message = "Unable to handle the link.")]
+    ActionLinkError,
+
+    #[strum(message = "Unable to handle the pseudonym.")]
+    PseudonymError,
+
+    #[strum(message = "Your session token is invalid.")]
+    InvalidJwt,
+
+    #[strum(message = "Your session token has expired, please login again.")]
+    JwtExpired,
+
+    #[strum(message = "Unable to handle the session token.")]
+    JwtError,
+
+    #[strum(message = "The login isn't complete, enter the code of your second factor first.")]
+    LoginIncomplete,
+
+    #[strum(message = "Unable to erase the account.")]
+    ErasureError,
+
+    #[strum(message = "The directory can't be reached, please try again later.")]
+    DirectoryUnavailable,
+
+    #[strum(message = "Unable to set up the account of the directory user.")]
+    DirectoryProvisioningError,
+
+    #[strum(message = "The job is already running on another instance.")]
+    JobAlreadyRunning,
+
+    #[strum(message = "Unable to coordinate the job with the other instances.")]
+    JobLeaseError,
+
+    #[strum(message = "The service is busy, please try again later.")]
+    Timeout,
+
+    #[strum(message = "The service is under maintenance, changes are unavailable for now.")]
+    MaintenanceMode,
+
+    #[strum(message = "Unable to reach the state of the logins, please try again later.")]
+    StateStoreError,
 }
 
 impl fmt::Display for AuthError {
@@ -53,16 +267,66 @@ impl error::Error for AuthError {
     }
 }
 
-#[derive(PartialEq, Debug, strum_macros::EnumMessage)]
+#[allow(clippy::enum_variant_names)]
+#[derive(
+    PartialEq,
+    Debug,
+    Clone,
+    Copy,
+    strum_macros::EnumMessage,
+    strum_macros::EnumIter,
+    strum_macros::IntoStaticStr,
+)]
+#[strum(serialize_all = "snake_case")]
 pub enum UserDBError {
     #[strum(message = "Unable to create the user.")]
     CreateUserError,
 
+    #[strum(message = "A user with this email already exists.")]
+    EmailUsedError,
+
     #[strum(message = "Unable to update the user.")]
     UpdateUserError,
 
     #[strum(message = "Unable to get the user.")]
     GetUserError,
+
+    #[strum(message = "Unable to delete the user.")]
+    DeleteUserError,
+
+    #[strum(message = "Unable to list the users.")]
+    ListUsersError,
+
+    #[strum(message = "Unable to get the user attributes.")]
+    GetAttributesError,
+
+    #[strum(message = "Unable to update the user attributes.")]
+    UpdateAttributesError,
+
+    #[strum(message = "The storage is read-only.")]
+    ReadOnlyError,
+
+    #[strum(message = "The database is still locked, the operation timed out.")]
+    Timeout,
+
+    #[strum(message = "The key of the file of the users is missing or too short.")]
+    KeyError,
+}
+
+impl UserDBError {
+    /// Get the error of a flow failing because of this error
+    /// Note: the timeouts are kept apart, trying again later may work
+    ///
+    /// # Arguments
+    ///
+    /// * `otherwise` - the error of the flow for the other failures
+    ///
+    pub fn to_auth_error(self, otherwise: AuthError) -> AuthError {
+        match self {
+            UserDBError::Timeout => AuthError::Timeout,
+            _ => otherwise,
+        }
+    }
 }
 
 impl fmt::Display for UserDBError {
@@ -76,3 +340,507 @@ impl error::Error for UserDBError {
         self.get_message().unwrap()
     }
 }
+
+#[allow(clippy::enum_variant_names)]
+#[derive(
+    PartialEq,
+    Debug,
+    Clone,
+    Copy,
+    strum_macros::EnumMessage,
+    strum_macros::EnumIter,
+    strum_macros::IntoStaticStr,
+)]
+#[strum(serialize_all = "snake_case")]
+pub enum RecoveryCodeDBError {
+    #[strum(message = "Unable to replace the recovery codes.")]
+    ReplaceCodesError,
+
+    #[strum(message = "Unable to use the recovery code.")]
+    UseCodeError,
+
+    #[strum(message = "Unable to count the recovery codes.")]
+    CountCodesError,
+
+    #[strum(message = "The database is still locked, the operation timed out.")]
+    Timeout,
+}
+
+impl RecoveryCodeDBError {
+    /// Get the error of a flow failing because of this error
+    /// Note: the timeouts are kept apart, trying again later may work
+    ///
+    /// # Arguments
+    ///
+    /// * `otherwise` - the error of the flow for the other failures
+    ///
+    pub fn to_auth_error(self, otherwise: AuthError) -> AuthError {
+        match self {
+            RecoveryCodeDBError::Timeout => AuthError::Timeout,
+            _ => otherwise,
+        }
+    }
+}
+
+impl fmt::Display for RecoveryCodeDBError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.get_message().unwrap())
+    }
+}
+
+impl error::Error for RecoveryCodeDBError {
+    fn description(&self) -> &str {
+        self.get_message().unwrap()
+    }
+}
+
+#[allow(clippy::enum_variant_names)]
+#[derive(
+    PartialEq,
+    Debug,
+    Clone,
+    Copy,
+    strum_macros::EnumMessage,
+    strum_macros::EnumIter,
+    strum_macros::IntoStaticStr,
+)]
+#[strum(serialize_all = "snake_case")]
+pub enum TrustedContactDBError {
+    #[strum(message = "Unable to read the trusted contacts.")]
+    ReadContactsError,
+
+    #[strum(message = "Unable to update the trusted contacts.")]
+    UpdateContactsError,
+
+    #[strum(message = "Unable to read the recovery.")]
+    ReadRecoveryError,
+
+    #[strum(message = "Unable to update the recovery.")]
+    UpdateRecoveryError,
+}
+
+impl fmt::Display for TrustedContactDBError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.get_message().unwrap())
+    }
+}
+
+impl error::Error for TrustedContactDBError {
+    fn description(&self) -> &str {
+        self.get_message().unwrap()
+    }
+}
+
+#[derive(
+    PartialEq,
+    Debug,
+    Clone,
+    Copy,
+    strum_macros::EnumMessage,
+    strum_macros::EnumIter,
+    strum_macros::IntoStaticStr,
+)]
+#[strum(serialize_all = "snake_case")]
+pub enum PasswordStatsDBError {
+    #[strum(message = "Unable to record the password statistics.")]
+    RecordError,
+
+    #[strum(message = "Unable to read the password statistics.")]
+    ReadError,
+}
+
+impl fmt::Display for PasswordStatsDBError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.get_message().unwrap())
+    }
+}
+
+impl error::Error for PasswordStatsDBError {
+    fn description(&self) -> &str {
+        self.get_message().unwrap()
+    }
+}
+
+#[derive(
+    PartialEq,
+    Debug,
+    Clone,
+    Copy,
+    strum_macros::EnumMessage,
+    strum_macros::EnumIter,
+    strum_macros::IntoStaticStr,
+)]
+#[strum(serialize_all = "snake_case")]
+pub enum ActionLinkDBError {
+    #[strum(message = "Unable to redeem the link.")]
+    RedeemError,
+}
+
+impl fmt::Display for ActionLinkDBError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.get_message().unwrap())
+    }
+}
+
+impl error::Error for ActionLinkDBError {
+    fn description(&self) -> &str {
+        self.get_message().unwrap()
+    }
+}
+
+#[derive(
+    PartialEq,
+    Debug,
+    Clone,
+    Copy,
+    strum_macros::EnumMessage,
+    strum_macros::EnumIter,
+    strum_macros::IntoStaticStr,
+)]
+#[strum(serialize_all = "snake_case")]
+pub enum EphemeralStoreError {
+    #[strum(message = "Unable to reach the store of the short-lived state.")]
+    ConnectionError,
+
+    #[strum(message = "Unable to read the short-lived state.")]
+    ReadError,
+
+    #[strum(message = "Unable to write the short-lived state.")]
+    WriteError,
+}
+
+impl fmt::Display for EphemeralStoreError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.get_message().unwrap())
+    }
+}
+
+impl error::Error for EphemeralStoreError {
+    fn description(&self) -> &str {
+        self.get_message().unwrap()
+    }
+}
+
+#[allow(clippy::enum_variant_names)]
+#[derive(
+    PartialEq,
+    Debug,
+    Clone,
+    Copy,
+    strum_macros::EnumMessage,
+    strum_macros::EnumIter,
+    strum_macros::IntoStaticStr,
+)]
+#[strum(serialize_all = "snake_case")]
+pub enum LeaseDBError {
+    #[strum(message = "Unable to acquire the lease of the job.")]
+    AcquireError,
+
+    #[strum(message = "Unable to release the lease of the job.")]
+    ReleaseError,
+}
+
+impl fmt::Display for LeaseDBError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.get_message().unwrap())
+    }
+}
+
+impl error::Error for LeaseDBError {
+    fn description(&self) -> &str {
+        self.get_message().unwrap()
+    }
+}
+
+#[allow(clippy::enum_variant_names)]
+#[derive(
+    PartialEq,
+    Debug,
+    Clone,
+    Copy,
+    strum_macros::EnumMessage,
+    strum_macros::EnumIter,
+    strum_macros::IntoStaticStr,
+)]
+#[strum(serialize_all = "snake_case")]
+pub enum NotificationDBError {
+    #[strum(message = "Unable to record the notification.")]
+    ClaimError,
+
+    #[strum(message = "Unable to release the notification.")]
+    ReleaseError,
+}
+
+impl fmt::Display for NotificationDBError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.get_message().unwrap())
+    }
+}
+
+impl error::Error for NotificationDBError {
+    fn description(&self) -> &str {
+        self.get_message().unwrap()
+    }
+}
+
+#[derive(
+    PartialEq,
+    Debug,
+    Clone,
+    Copy,
+    strum_macros::EnumMessage,
+    strum_macros::EnumIter,
+    strum_macros::IntoStaticStr,
+)]
+#[strum(serialize_all = "snake_case")]
+pub enum AuditDBError {
+    #[strum(message = "Unable to add the entry to the audit log.")]
+    CreateEntryError,
+
+    #[strum(message = "Unable to read the audit log.")]
+    ReadEntryError,
+
+    #[strum(message = "The database is still locked, the operation timed out.")]
+    Timeout,
+}
+
+impl AuditDBError {
+    /// Get the error of a flow failing because of this error
+    /// Note: the timeouts are kept apart, trying again later may work
+    ///
+    /// # Arguments
+    ///
+    /// * `otherwise` - the error of the flow for the other failures
+    ///
+    pub fn to_auth_error(self, otherwise: AuthError) -> AuthError {
+        match self {
+            AuditDBError::Timeout => AuthError::Timeout,
+            _ => otherwise,
+        }
+    }
+}
+
+impl fmt::Display for AuditDBError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.get_message().unwrap())
+    }
+}
+
+impl error::Error for AuditDBError {
+    fn description(&self) -> &str {
+        self.get_message().unwrap()
+    }
+}
+
+#[derive(
+    PartialEq,
+    Debug,
+    Clone,
+    Copy,
+    strum_macros::EnumMessage,
+    strum_macros::EnumIter,
+    strum_macros::IntoStaticStr,
+)]
+#[strum(serialize_all = "snake_case")]
+pub enum ConfigError {
+    #[strum(message = "The configuration file is invalid.")]
+    ParseError,
+
+    #[strum(message = "The hashing cost is below the minimum supported.")]
+    InvalidHashingCost,
+
+    #[strum(message = "An access window has invalid hours or allows no hour at all.")]
+    InvalidAccessWindow,
+
+    #[strum(message = "The search limit can't be negative.")]
+    InvalidSearchLimit,
+
+    #[strum(message = "The scenario file is invalid.")]
+    InvalidScenario,
+
+    #[strum(message = "The configuration can't be written as TOML.")]
+    SerializeError,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.get_message().unwrap())
+    }
+}
+
+impl error::Error for ConfigError {
+    fn description(&self) -> &str {
+        self.get_message().unwrap()
+    }
+}
+
+#[derive(
+    PartialEq,
+    Debug,
+    Clone,
+    Copy,
+    strum_macros::EnumMessage,
+    strum_macros::EnumIter,
+    strum_macros::IntoStaticStr,
+)]
+#[strum(serialize_all = "snake_case")]
+pub enum MailError {
+    #[strum(message = "Unable to send the email.")]
+    SendError,
+
+    #[strum(message = "Unable to set up the mailer.")]
+    SetupError,
+
+    #[strum(message = "This email provider isn't supported by this build.")]
+    UnsupportedProvider,
+
+    #[strum(message = "The mail server didn't answer in time.")]
+    Timeout,
+}
+
+impl fmt::Display for MailError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.get_message().unwrap())
+    }
+}
+
+impl error::Error for MailError {
+    fn description(&self) -> &str {
+        self.get_message().unwrap()
+    }
+}
+
+#[derive(
+    PartialEq,
+    Debug,
+    Clone,
+    Copy,
+    strum_macros::EnumMessage,
+    strum_macros::EnumIter,
+    strum_macros::IntoStaticStr,
+)]
+#[strum(serialize_all = "snake_case")]
+pub enum DoctorError {
+    #[strum(message = "Unable to inspect the database.")]
+    InspectionError,
+
+    #[strum(message = "Unable to repair the database.")]
+    RepairError,
+}
+
+impl fmt::Display for DoctorError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.get_message().unwrap())
+    }
+}
+
+impl error::Error for DoctorError {
+    fn description(&self) -> &str {
+        self.get_message().unwrap()
+    }
+}
+
+#[derive(
+    PartialEq,
+    Debug,
+    Clone,
+    Copy,
+    strum_macros::EnumMessage,
+    strum_macros::EnumIter,
+    strum_macros::IntoStaticStr,
+)]
+#[strum(serialize_all = "snake_case")]
+pub enum SetupError {
+    #[strum(message = "Unable to write the configuration file.")]
+    WriteConfigError,
+
+    #[strum(message = "Unable to save the database url.")]
+    SaveDatabaseUrlError,
+
+    #[strum(message = "Unable to save the generated keys.")]
+    SaveSecretError,
+
+    #[strum(message = "Unable to migrate the database.")]
+    MigrationError,
+
+    #[strum(message = "The database was migrated by a newer version of the system.")]
+    NewerSchemaError,
+
+    #[strum(message = "Unable to create the administrator account.")]
+    AdminCreationError,
+
+    #[strum(message = "DATABASE_URL must be set.")]
+    MissingDatabaseUrl,
+
+    #[strum(message = "Unable to connect to the database.")]
+    ConnectionError,
+}
+
+impl fmt::Display for SetupError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.get_message().unwrap())
+    }
+}
+
+impl error::Error for SetupError {
+    fn description(&self) -> &str {
+        self.get_message().unwrap()
+    }
+}
+
+/// Problem with a side effect of a flow (e.g. an email), while the flow itself succeeded
+#[derive(
+    PartialEq,
+    Debug,
+    Clone,
+    Copy,
+    strum_macros::EnumMessage,
+    strum_macros::EnumIter,
+    strum_macros::IntoStaticStr,
+)]
+#[strum(serialize_all = "snake_case")]
+pub enum Warning {
+    #[strum(message = "The notification email couldn't be sent.")]
+    NotificationNotSent,
+
+    #[strum(message = "The erasure couldn't be recorded in the audit log.")]
+    ErasureNotRecorded,
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.get_message().unwrap())
+    }
+}
+
+/// Result of a flow which succeeded, with the problems of its side effects (if any)
+#[derive(PartialEq, Debug, Clone, Default)]
+pub enum Completion {
+    #[default]
+    Completed,
+    CompletedWithWarnings(Vec<Warning>),
+}
+
+impl Completion {
+    /// Build the result of a flow from the problems of its side effects
+    ///
+    /// # Arguments
+    ///
+    /// * `warnings` - the problems, `Completed` if there's none
+    ///
+    pub fn with_warnings(warnings: Vec<Warning>) -> Self {
+        if warnings.is_empty() {
+            Self::Completed
+        } else {
+            Self::CompletedWithWarnings(warnings)
+        }
+    }
+
+    /// Get the problems of the side effects of the flow
+    pub fn warnings(&self) -> &[Warning] {
+        match self {
+            Self::Completed => &[],
+            Self::CompletedWithWarnings(warnings) => warnings,
+        }
+    }
+}
@@ -0,0 +1,483 @@
+/*!
+ * Catalog of the errors of the system
+ *
+ * Every error has a stable code (e.g. `auth.login_error`), a message key for
+ * the frontends translating the messages themselves & a suggested HTTP status,
+ * so the HTTP/gRPC layers & the third-party frontends stay consistent.
+ * The errors are sent as problem details (RFC 7807), see `problem_json`.
+ *
+ * # Author
+ * Doran Kayoumi <doran.kayoumi@heig-vd.ch>
+ */
+
+use http::StatusCode;
+use serde::Serialize;
+use serde_json::{json, Value};
+use strum::{EnumMessage, IntoEnumIterator};
+
+use crate::auth::availability::RateLimit;
+
+use super::{
+    ActionLinkDBError, AuditDBError, AuthError, Completion, ConfigError, DoctorError,
+    EphemeralStoreError, LeaseDBError, MailError, NotificationDBError, PasswordStatsDBError,
+    RecoveryCodeDBError, SetupError, TrustedContactDBError, UserDBError, Warning,
+};
+
+/// Content type of the problem details
+pub const PROBLEM_JSON_CONTENT_TYPE: &str = "application/problem+json";
+
+/// Prefix of the `type` of the problem details
+const PROBLEM_TYPE_PREFIX: &str = "urn:secure-auth:error:";
+
+pub trait Catalogued: EnumMessage + IntoEnumIterator
+where
+    for<'a> &'a Self: Into<&'static str>,
+{
+    /// Domain of the errors, prefix of their code
+    const DOMAIN: &'static str;
+
+    /// HTTP status suggested for the error
+    fn status(&self) -> StatusCode;
+
+    /// Stable identifier of the error
+    fn code(&self) -> String {
+        let name: &'static str = self.into();
+        format!("{}.{}", Self::DOMAIN, name)
+    }
+
+    /// Key of the message of the error
+    fn message_key(&self) -> String {
+        format!("errors.{}", self.code())
+    }
+}
+
+/// An error of the catalog
+#[derive(PartialEq, Debug, Serialize)]
+pub struct CatalogEntry {
+    pub code: String,
+    pub message_key: String,
+    /// Message in English
+    pub message: String,
+    pub status: u16,
+}
+
+impl CatalogEntry {
+    fn new<E: Catalogued>(e: &E) -> Self
+    where
+        for<'a> &'a E: Into<&'static str>,
+    {
+        Self {
+            code: e.code(),
+            message_key: e.message_key(),
+            message: e.get_message().unwrap_or_default().to_string(),
+            status: e.status().as_u16(),
+        }
+    }
+}
+
+fn entries<E: Catalogued>() -> impl Iterator<Item = CatalogEntry>
+where
+    for<'a> &'a E: Into<&'static str>,
+{
+    E::iter().map(|e| CatalogEntry::new(&e))
+}
+
+/// Every error (& warning) the system can return
+pub fn catalog() -> Vec<CatalogEntry> {
+    entries::<AuthError>()
+        .chain(entries::<UserDBError>())
+        .chain(entries::<RecoveryCodeDBError>())
+        .chain(entries::<AuditDBError>())
+        .chain(entries::<NotificationDBError>())
+        .chain(entries::<LeaseDBError>())
+        .chain(entries::<TrustedContactDBError>())
+        .chain(entries::<PasswordStatsDBError>())
+        .chain(entries::<ActionLinkDBError>())
+        .chain(entries::<EphemeralStoreError>())
+        .chain(entries::<ConfigError>())
+        .chain(entries::<MailError>())
+        .chain(entries::<DoctorError>())
+        .chain(entries::<SetupError>())
+        .chain(entries::<Warning>())
+        .collect()
+}
+
+/// Build the problem details (RFC 7807) of an error
+///
+/// # Arguments
+///
+/// * `e` - the error
+///
+/// * `instance` - the URI of the request that failed (if any)
+///
+pub fn problem_json<E: Catalogued>(e: &E, instance: Option<&str>) -> Value
+where
+    for<'a> &'a E: Into<&'static str>,
+{
+    let entry = CatalogEntry::new(e);
+    let mut problem = json!({
+        "type": format!("{}{}", PROBLEM_TYPE_PREFIX, entry.code),
+        "title": entry.message,
+        "status": entry.status,
+        "code": entry.code,
+        "message_key": entry.message_key,
+    });
+    if let Some(instance) = instance {
+        problem["instance"] = json!(instance);
+    }
+
+    problem
+}
+
+/// Build the problem details of a request rejected by a rate limit (429)
+/// The state of the limit is added to them, so the client knows when to try again
+/// Note: the response also carries the `Retry-After` header (see `RateLimit::retry_after`)
+///
+/// # Arguments
+///
+/// * `limit` - the state of the limit of the client (e.g. `availability::rate_limit`)
+///
+/// * `instance` - the URI of the request that failed (if any)
+///
+pub fn rate_limited_json(limit: &RateLimit, instance: Option<&str>) -> Value {
+    let mut problem = problem_json(&AuthError::TooManyChecks, instance);
+    problem["limit"] = json!(limit.limit);
+    problem["remaining"] = json!(limit.remaining);
+    problem["reset"] = json!(limit.reset);
+
+    problem
+}
+
+/// Build the body of a flow which succeeded, listing the problems of its side effects
+///
+/// # Arguments
+///
+/// * `completion` - the result of the flow
+///
+pub fn completion_json(completion: &Completion) -> Value {
+    let warnings: Vec<Value> = completion
+        .warnings()
+        .iter()
+        .map(|w| {
+            json!({
+                "code": w.code(),
+                "message_key": w.message_key(),
+                "message": w.to_string(),
+            })
+        })
+        .collect();
+    let status = match completion {
+        Completion::Completed => "completed",
+        Completion::CompletedWithWarnings(_) => "completed_with_warnings",
+    };
+
+    json!({ "status": status, "warnings": warnings })
+}
+
+impl From<AuthError> for StatusCode {
+    fn from(e: AuthError) -> Self {
+        e.status()
+    }
+}
+
+impl Catalogued for AuthError {
+    const DOMAIN: &'static str = "auth";
+
+    fn status(&self) -> StatusCode {
+        match self {
+            AuthError::LoginError
+            | AuthError::InvalidAuthenticationCode
+            | AuthError::InvalidRecoveryCode
+            | AuthError::ChallengeExpired
+            | AuthError::InvalidChallenge
+            | AuthError::ClientMismatch
+            | AuthError::InvalidApiKey
+            | AuthError::IncorrectPassword
+            | AuthError::StepUpRequired
+            | AuthError::InvalidJwt
+            | AuthError::JwtExpired
+            | AuthError::LoginIncomplete => StatusCode::UNAUTHORIZED,
+            AuthError::InvalidEmail
+            | AuthError::InvalidPassword
+            | AuthError::InputTooLong
+            | AuthError::ConsentRequired
+            | AuthError::InvalidCaptcha
+            | AuthError::InvalidContact
+            | AuthError::InvalidCertificate
+            | AuthError::InvalidScope => StatusCode::UNPROCESSABLE_ENTITY,
+            AuthError::ExpiredToken
+            | AuthError::TokenMismatch
+            | AuthError::UnknownFactor
+            | AuthError::InvalidActionLink
+            | AuthError::InvalidRecoverySecret => StatusCode::BAD_REQUEST,
+            AuthError::EmailUsed
+            | AuthError::TwoFaNotEnabled
+            | AuthError::TwoFaAlreadyEnabled
+            | AuthError::NoPendingSecret
+            | AuthError::NotOnHold
+            | AuthError::TooManyContacts
+            | AuthError::RecoveryNotApproved
+            | AuthError::RecoveryPending
+            | AuthError::NotAServiceAccount
+            | AuthError::JobAlreadyRunning => StatusCode::CONFLICT,
+            AuthError::NoRecovery => StatusCode::NOT_FOUND,
+            AuthError::InvalidApproval
+            | AuthError::InvalidContactConfirmation
+            | AuthError::RecoveryExpired
+            | AuthError::ActionLinkExpired
+            | AuthError::ActionLinkUsed => StatusCode::GONE,
+            AuthError::AccountOnHold => StatusCode::LOCKED,
+            AuthError::EmailDomainNotAllowed
+            | AuthError::ReservedEmail
+            | AuthError::RegistrationRejected
+            | AuthError::TosNotAccepted
+            | AuthError::EmailConfirmationRequired
+            | AuthError::LoginBlocked
+            | AuthError::OutsideAllowedHours
+            | AuthError::MissingScope => StatusCode::FORBIDDEN,
+            AuthError::CaptchaRequired => StatusCode::PRECONDITION_REQUIRED,
+            AuthError::TooManyChecks => StatusCode::TOO_MANY_REQUESTS,
+            AuthError::Timeout
+            | AuthError::MaintenanceMode
+            | AuthError::DirectoryUnavailable
+            | AuthError::StateStoreError => StatusCode::SERVICE_UNAVAILABLE,
+            AuthError::RegistrationError
+            | AuthError::JobLeaseError
+            | AuthError::DirectoryProvisioningError
+            | AuthError::ResetError
+            | AuthError::TosAcceptanceError
+            | AuthError::SecretRotationError
+            | AuthError::RecoveryCodesError
+            | AuthError::HoldError
+            | AuthError::ContactRecoveryError
+            | AuthError::AccessHoursError
+            | AuthError::InactivityError
+            | AuthError::StatsError
+            | AuthError::AvailabilityError
+            | AuthError::ServiceAccountError
+            | AuthError::ActionLinkError
+            | AuthError::PseudonymError
+            | AuthError::JwtError
+            | AuthError::ErasureError => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+impl Catalogued for UserDBError {
+    const DOMAIN: &'static str = "user_db";
+
+    fn status(&self) -> StatusCode {
+        match self {
+            UserDBError::EmailUsedError => StatusCode::CONFLICT,
+            UserDBError::ReadOnlyError | UserDBError::Timeout => StatusCode::SERVICE_UNAVAILABLE,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+impl Catalogued for RecoveryCodeDBError {
+    const DOMAIN: &'static str = "recovery_code_db";
+
+    fn status(&self) -> StatusCode {
+        match self {
+            RecoveryCodeDBError::Timeout => StatusCode::SERVICE_UNAVAILABLE,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+impl Catalogued for AuditDBError {
+    const DOMAIN: &'static str = "audit_db";
+
+    fn status(&self) -> StatusCode {
+        match self {
+            AuditDBError::Timeout => StatusCode::SERVICE_UNAVAILABLE,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+impl Catalogued for TrustedContactDBError {
+    const DOMAIN: &'static str = "trusted_contact_db";
+
+    fn status(&self) -> StatusCode {
+        StatusCode::INTERNAL_SERVER_ERROR
+    }
+}
+
+impl Catalogued for PasswordStatsDBError {
+    const DOMAIN: &'static str = "password_stats_db";
+
+    fn status(&self) -> StatusCode {
+        StatusCode::INTERNAL_SERVER_ERROR
+    }
+}
+
+impl Catalogued for ActionLinkDBError {
+    const DOMAIN: &'static str = "action_link_db";
+
+    fn status(&self) -> StatusCode {
+        StatusCode::INTERNAL_SERVER_ERROR
+    }
+}
+
+impl Catalogued for EphemeralStoreError {
+    const DOMAIN: &'static str = "ephemeral_store";
+
+    fn status(&self) -> StatusCode {
+        StatusCode::INTERNAL_SERVER_ERROR
+    }
+}
+
+impl Catalogued for NotificationDBError {
+    const DOMAIN: &'static str = "notification_db";
+
+    fn status(&self) -> StatusCode {
+        StatusCode::INTERNAL_SERVER_ERROR
+    }
+}
+
+impl Catalogued for LeaseDBError {
+    const DOMAIN: &'static str = "lease_db";
+
+    fn status(&self) -> StatusCode {
+        StatusCode::INTERNAL_SERVER_ERROR
+    }
+}
+
+impl Catalogued for ConfigError {
+    const DOMAIN: &'static str = "config";
+
+    fn status(&self) -> StatusCode {
+        StatusCode::INTERNAL_SERVER_ERROR
+    }
+}
+
+impl Catalogued for MailError {
+    const DOMAIN: &'static str = "mail";
+
+    fn status(&self) -> StatusCode {
+        match self {
+            MailError::Timeout => StatusCode::GATEWAY_TIMEOUT,
+            _ => StatusCode::BAD_GATEWAY,
+        }
+    }
+}
+
+impl Catalogued for DoctorError {
+    const DOMAIN: &'static str = "doctor";
+
+    fn status(&self) -> StatusCode {
+        StatusCode::INTERNAL_SERVER_ERROR
+    }
+}
+
+impl Catalogued for SetupError {
+    const DOMAIN: &'static str = "setup";
+
+    fn status(&self) -> StatusCode {
+        StatusCode::INTERNAL_SERVER_ERROR
+    }
+}
+
+impl Catalogued for Warning {
+    const DOMAIN: &'static str = "warning";
+
+    // Note: the flow succeeded, only a side effect failed
+    fn status(&self) -> StatusCode {
+        StatusCode::OK
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn test_codes_are_unique() {
+        let catalog = catalog();
+        let codes: HashSet<&str> = catalog.iter().map(|e| e.code.as_str()).collect();
+
+        assert_eq!(codes.len(), catalog.len());
+        assert!(catalog.iter().all(|e| !e.message.is_empty()));
+    }
+
+    #[test]
+    fn test_auth_error_to_status() {
+        assert_eq!(
+            StatusCode::from(AuthError::LoginError),
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            StatusCode::from(AuthError::CaptchaRequired),
+            StatusCode::PRECONDITION_REQUIRED
+        );
+        assert_eq!(AuthError::LoginError.code(), "auth.login_error");
+    }
+
+    #[test]
+    fn test_problem_json() {
+        let problem = problem_json(&AuthError::EmailUsed, Some("/register"));
+
+        assert_eq!(
+            problem,
+            json!({
+                "type": "urn:secure-auth:error:auth.email_used",
+                "title": "This e-mail address is already used for another account.",
+                "status": 409,
+                "code": "auth.email_used",
+                "message_key": "errors.auth.email_used",
+                "instance": "/register",
+            })
+        );
+        assert!(problem_json(&MailError::SendError, None)
+            .get("instance")
+            .is_none());
+    }
+
+    #[test]
+    fn test_rate_limited_json() {
+        let limit = RateLimit {
+            limit: 10,
+            remaining: 0,
+            reset: 1800,
+        };
+
+        assert_eq!(
+            rate_limited_json(&limit, Some("/availability")),
+            json!({
+                "type": "urn:secure-auth:error:auth.too_many_checks",
+                "title": "Too many checks, please try again later.",
+                "status": 429,
+                "code": "auth.too_many_checks",
+                "message_key": "errors.auth.too_many_checks",
+                "instance": "/availability",
+                "limit": 10,
+                "remaining": 0,
+                "reset": 1800,
+            })
+        );
+    }
+
+    #[test]
+    fn test_completion_json() {
+        assert_eq!(
+            completion_json(&Completion::with_warnings(vec![])),
+            json!({ "status": "completed", "warnings": [] })
+        );
+        assert_eq!(
+            completion_json(&Completion::with_warnings(vec![
+                Warning::NotificationNotSent
+            ])),
+            json!({
+                "status": "completed_with_warnings",
+                "warnings": [{
+                    "code": "warning.notification_not_sent",
+                    "message_key": "errors.warning.notification_not_sent",
+                    "message": "The notification email couldn't be sent.",
+                }],
+            })
+        );
+    }
+}
@@ -0,0 +1,350 @@
+/*!
+ * Localization of the texts & dates shown to the users
+ *
+ * Every user can set her/his locale & timezone in her/his profile (stored as
+ * the `locale` & `timezone` attributes), the ones set in the configuration
+ * are used otherwise (e.g. before the login).
+ *
+ * # Author
+ * Doran Kayoumi <doran.kayoumi@heig-vd.ch>
+ */
+
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::{PoisonError, RwLock};
+
+use crate::config;
+use crate::db::repository::UserRepository;
+
+/// Name of the user attribute holding her/his locale
+pub const LOCALE_ATTRIBUTE: &str = "locale";
+/// Name of the user attribute holding her/his timezone
+pub const TIMEZONE_ATTRIBUTE: &str = "timezone";
+
+#[derive(
+    Deserialize,
+    Serialize,
+    Debug,
+    PartialEq,
+    Clone,
+    Copy,
+    strum_macros::EnumString,
+    strum_macros::Display,
+)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum Locale {
+    En,
+    Fr,
+}
+
+/// Locale & timezone in which the texts & dates are shown to a user
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Preferences {
+    pub locale: Locale,
+    pub timezone: Tz,
+}
+
+impl Default for Preferences {
+    /// The preferences set in the configuration
+    fn default() -> Self {
+        let config = &config::get().locale;
+
+        Self {
+            locale: config.locale,
+            timezone: config.timezone,
+        }
+    }
+}
+
+impl Preferences {
+    /// Get the preferences stored in the attributes of a user
+    /// The missing (or invalid) ones are taken from the default preferences
+    ///
+    /// # Arguments
+    ///
+    /// * `attributes` - the attributes of the user
+    ///
+    /// * `default` - the preferences used when the user didn't set any
+    ///
+    pub fn from_attributes(attributes: &HashMap<String, String>, default: &Self) -> Self {
+        Self {
+            locale: attributes
+                .get(LOCALE_ATTRIBUTE)
+                .and_then(|l| Locale::from_str(l).ok())
+                .unwrap_or(default.locale),
+            timezone: attributes
+                .get(TIMEZONE_ATTRIBUTE)
+                .and_then(|tz| tz.parse().ok())
+                .unwrap_or(default.timezone),
+        }
+    }
+
+    /// Render a date & time in the locale & timezone of the preferences
+    ///
+    /// # Arguments
+    ///
+    /// * `dt` - the date & time to render
+    ///
+    pub fn format_datetime(&self, dt: &DateTime<Utc>) -> String {
+        let format = match self.locale {
+            Locale::En => "%b %-d, %Y %-I:%M %p (%Z)",
+            Locale::Fr => "%d.%m.%Y %H:%M (%Z)",
+        };
+
+        dt.with_timezone(&self.timezone).format(format).to_string()
+    }
+}
+
+/// Get the preferences a user set in her/his profile
+/// If they can't be retrieved, the default preferences are used
+///
+/// # Arguments
+///
+/// * `user` - id of the user
+///
+/// * `repository` - the user repository to interact with
+///
+pub fn user_preferences(user: i32, repository: &dyn UserRepository) -> Preferences {
+    repository
+        .get_attributes(user)
+        .map(|attributes| Preferences::from_attributes(&attributes, &Preferences::default()))
+        .unwrap_or_default()
+}
+
+lazy_static! {
+    static ref CURRENT: RwLock<Option<Preferences>> = RwLock::new(None);
+}
+
+/// Get the preferences of the current user, the default ones if nobody is logged in
+pub fn current() -> Preferences {
+    CURRENT
+        .read()
+        .unwrap_or_else(PoisonError::into_inner)
+        .unwrap_or_default()
+}
+
+/// Set the preferences of the current user
+///
+/// # Arguments
+///
+/// * `preferences` - the preferences of the user, `None` to go back to the default ones
+///
+pub fn set_current(preferences: Option<Preferences>) {
+    *CURRENT.write().unwrap_or_else(PoisonError::into_inner) = preferences;
+}
+
+/// Texts shown to the users
+/// Note: `{}` is a placeholder, see `tr_with`
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum Text {
+    EmailPrompt,
+    InvalidEmail,
+    PasswordPrompt,
+    InvalidPassword,
+    AuthenticationCodeHint,
+    AuthenticationCodePrompt,
+    CommandPrompt,
+    UnknownCommand,
+    ResetTokenPrompt,
+    ConfirmationTokenPrompt,
+    RecoverySecretPrompt,
+    TokenExpiry,
+    TosNotice,
+    TosQuestion,
+    AgeQuestion,
+    YesNoAnswer,
+    AntiPhishingPhrasePrompt,
+    InvalidAntiPhishingPhrase,
+    LocalePrompt,
+    InvalidLocale,
+    TimezonePrompt,
+    InvalidTimezone,
+    OverwriteQuestion,
+    OfflineQuestion,
+    DatabasePathPrompt,
+    InvalidDatabasePath,
+    RecoveryCodeHint,
+    RegenerateRecoveryCodesQuestion,
+    ContactPrompt,
+    CancelRecoveryQuestion,
+}
+
+impl Text {
+    /// Get the text in a given locale
+    ///
+    /// # Arguments
+    ///
+    /// * `locale` - the locale of the text
+    ///
+    pub fn localized(self, locale: Locale) -> &'static str {
+        match locale {
+            Locale::En => self.en(),
+            Locale::Fr => self.fr(),
+        }
+    }
+
+    /// Get the text in a given locale, with its placeholder replaced
+    ///
+    /// # Arguments
+    ///
+    /// * `locale` - the locale of the text
+    ///
+    /// * `arg` - the value replacing the placeholder
+    ///
+    pub fn localized_with(self, locale: Locale, arg: &str) -> String {
+        self.localized(locale).replacen("{}", arg, 1)
+    }
+
+    fn en(self) -> &'static str {
+        match self {
+            Text::EmailPrompt => "Email : ",
+            Text::InvalidEmail => "Invalid mail address, please try again",
+            Text::PasswordPrompt => "Password : ",
+            Text::InvalidPassword => "Password length must be between 8 and 64, please try again",
+            Text::AuthenticationCodeHint => "Open the two-factor authentication app on your device to view your authentication code and verify your identity.",
+            Text::AuthenticationCodePrompt => "Authentication code: ",
+            Text::CommandPrompt => "What do you want to do? ",
+            Text::UnknownCommand => "Unknown command",
+            Text::ResetTokenPrompt => "Reset token : ",
+            Text::ConfirmationTokenPrompt => "Confirmation token (sent to your email address) : ",
+            Text::RecoverySecretPrompt => "Recovery secret (given when the recovery was started) : ",
+            Text::TokenExpiry => "The reset token expires on {}.",
+            Text::TosNotice => "Please read our terms of service & privacy policy (version {}).",
+            Text::TosQuestion => "Do you accept them?",
+            Text::AgeQuestion => "Do you confirm that you are at least 16 years old?",
+            Text::YesNoAnswer => "Please answer with y or n",
+            Text::AntiPhishingPhrasePrompt => "Anti-phishing phrase : ",
+            Text::InvalidAntiPhishingPhrase => "Phrase length must be between 4 and 32, please try again",
+            Text::LocalePrompt => "Language (en, fr) : ",
+            Text::InvalidLocale => "Unknown language, please try again",
+            Text::TimezonePrompt => "Timezone (e.g. Europe/Zurich) : ",
+            Text::InvalidTimezone => "Unknown timezone, please try again",
+            Text::OverwriteQuestion => "`{}` already exists, do you want to replace it?",
+            Text::OfflineQuestion => "Should the system run offline (no outbound network access)?",
+            Text::DatabasePathPrompt => "Database path : ",
+            Text::InvalidDatabasePath => "Please enter a path",
+            Text::RecoveryCodeHint => "Lost your device? Enter one of your recovery codes instead.",
+            Text::RegenerateRecoveryCodesQuestion => "Only {} recovery code(s) left, do you want to generate a new set? The remaining ones will stop working.",
+            Text::ContactPrompt => "Email of the contact to add or remove (leave empty to go back) : ",
+            Text::CancelRecoveryQuestion => "A recovery of your account was started, if it wasn't you, cancel it. Do you want to cancel it?",
+        }
+    }
+
+    fn fr(self) -> &'static str {
+        match self {
+            Text::EmailPrompt => "E-mail : ",
+            Text::InvalidEmail => "Adresse e-mail invalide, veuillez réessayer",
+            Text::PasswordPrompt => "Mot de passe : ",
+            Text::InvalidPassword => "Le mot de passe doit contenir entre 8 et 64 caractères, veuillez réessayer",
+            Text::AuthenticationCodeHint => "Ouvrez l'application d'authentification à deux facteurs de votre appareil pour afficher votre code d'authentification et confirmer votre identité.",
+            Text::AuthenticationCodePrompt => "Code d'authentification : ",
+            Text::CommandPrompt => "Que voulez-vous faire ? ",
+            Text::UnknownCommand => "Commande inconnue",
+            Text::ResetTokenPrompt => "Jeton de réinitialisation : ",
+            Text::ConfirmationTokenPrompt => "Jeton de confirmation (envoyé à votre adresse e-mail) : ",
+            Text::RecoverySecretPrompt => "Secret de récupération (donné au début de la récupération) : ",
+            Text::TokenExpiry => "Le jeton de réinitialisation expire le {}.",
+            Text::TosNotice => "Veuillez lire nos conditions d'utilisation et notre politique de confidentialité (version {}).",
+            Text::TosQuestion => "Les acceptez-vous ?",
+            Text::AgeQuestion => "Confirmez-vous avoir au moins 16 ans ?",
+            Text::YesNoAnswer => "Veuillez répondre par y ou n",
+            Text::AntiPhishingPhrasePrompt => "Phrase anti-hameçonnage : ",
+            Text::InvalidAntiPhishingPhrase => "La phrase doit contenir entre 4 et 32 caractères, veuillez réessayer",
+            Text::LocalePrompt => "Langue (en, fr) : ",
+            Text::InvalidLocale => "Langue inconnue, veuillez réessayer",
+            Text::TimezonePrompt => "Fuseau horaire (p. ex. Europe/Zurich) : ",
+            Text::InvalidTimezone => "Fuseau horaire inconnu, veuillez réessayer",
+            Text::OverwriteQuestion => "`{}` existe déjà, voulez-vous le remplacer ?",
+            Text::OfflineQuestion => "Le système doit-il fonctionner hors ligne (sans accès réseau sortant) ?",
+            Text::DatabasePathPrompt => "Chemin de la base de données : ",
+            Text::InvalidDatabasePath => "Veuillez entrer un chemin",
+            Text::RecoveryCodeHint => "Appareil perdu ? Entrez plutôt l'un de vos codes de récupération.",
+            Text::RegenerateRecoveryCodesQuestion => "Il ne reste que {} code(s) de récupération, voulez-vous en générer de nouveaux ? Les codes restants ne fonctionneront plus.",
+            Text::ContactPrompt => "E-mail du contact à ajouter ou retirer (laisser vide pour revenir) : ",
+            Text::CancelRecoveryQuestion => "Une récupération de votre compte a été lancée, si ce n'est pas vous, annulez-la. Voulez-vous l'annuler ?",
+        }
+    }
+}
+
+/// Get a text in the locale of the current user
+///
+/// # Arguments
+///
+/// * `text` - the text to get
+///
+pub fn tr(text: Text) -> &'static str {
+    text.localized(current().locale)
+}
+
+/// Get a text in the locale of the current user, with its placeholder replaced
+///
+/// # Arguments
+///
+/// * `text` - the text to get
+///
+/// * `arg` - the value replacing the placeholder
+///
+pub fn tr_with(text: Text, arg: &str) -> String {
+    text.localized_with(current().locale, arg)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn preferences(locale: Locale, timezone: Tz) -> Preferences {
+        Preferences { locale, timezone }
+    }
+
+    #[test]
+    fn test_from_attributes() {
+        let default = preferences(Locale::En, Tz::UTC);
+        let mut attributes = HashMap::new();
+
+        assert_eq!(Preferences::from_attributes(&attributes, &default), default);
+
+        attributes.insert(LOCALE_ATTRIBUTE.to_string(), "fr".to_string());
+        attributes.insert(TIMEZONE_ATTRIBUTE.to_string(), "Europe/Zurich".to_string());
+        assert_eq!(
+            Preferences::from_attributes(&attributes, &default),
+            preferences(Locale::Fr, Tz::Europe__Zurich)
+        );
+
+        attributes.insert(LOCALE_ATTRIBUTE.to_string(), "klingon".to_string());
+        attributes.insert(TIMEZONE_ATTRIBUTE.to_string(), "Mars/Olympus".to_string());
+        assert_eq!(Preferences::from_attributes(&attributes, &default), default);
+    }
+
+    #[test]
+    fn test_format_datetime() {
+        let dt = Utc.ymd(2021, 4, 28).and_hms(14, 5, 0);
+
+        assert_eq!(
+            preferences(Locale::En, Tz::UTC).format_datetime(&dt),
+            "Apr 28, 2021 2:05 PM (UTC)"
+        );
+        assert_eq!(
+            preferences(Locale::Fr, Tz::Europe__Zurich).format_datetime(&dt),
+            "28.04.2021 16:05 (CEST)"
+        );
+    }
+
+    #[test]
+    fn test_placeholders_match_across_locales() {
+        for text in &[
+            Text::TokenExpiry,
+            Text::TosNotice,
+            Text::OverwriteQuestion,
+            Text::RegenerateRecoveryCodesQuestion,
+        ] {
+            assert_eq!(text.en().matches("{}").count(), 1);
+            assert_eq!(text.fr().matches("{}").count(), 1);
+        }
+    }
+}
@@ -0,0 +1,56 @@
+/*!
+ * Library part of the authentication system.
+ *
+ * Everything needed to authenticate users lays here so it can be reused
+ * outside of the CLI (see `main.rs`), e.g. to inject custom registration
+ * validators.
+ *
+ * # Author
+ * Doran Kayoumi <doran.kayoumi@heig-vd.ch>
+ */
+
+// diesel 1.4's derives and `table!` expand to impls nested in named consts
+#![allow(non_local_definitions)]
+
+#[cfg(feature = "native")]
+#[macro_use]
+extern crate diesel;
+#[cfg(feature = "native")]
+#[macro_use]
+extern crate diesel_migrations;
+#[cfg(feature = "native")]
+extern crate dotenv;
+
+// Note: only `portable` & `validation` build without the `native` feature (e.g. for wasm32)
+#[cfg(feature = "native")]
+pub mod admin;
+#[cfg(feature = "native")]
+pub mod audit;
+#[cfg(feature = "native")]
+pub mod auth;
+#[cfg(feature = "native")]
+pub mod config;
+#[cfg(feature = "native")]
+pub mod db;
+#[cfg(feature = "native")]
+pub mod errors;
+#[cfg(feature = "native")]
+pub mod i18n;
+#[cfg(feature = "native")]
+pub mod mail;
+#[cfg(feature = "native")]
+pub mod network;
+#[cfg(feature = "native")]
+pub mod output;
+pub mod portable;
+#[cfg(feature = "native")]
+pub mod prelude;
+#[cfg(feature = "native")]
+pub mod selfcheck;
+#[cfg(feature = "native")]
+pub mod setup;
+#[cfg(feature = "native")]
+pub mod stats;
+#[cfg(feature = "native")]
+pub mod utils;
+pub mod validation;
@@ -0,0 +1,210 @@
+/*!
+ * Everything related to the emails sent by the system
+ *
+ * # Note
+ * For the purpose of the labratory, no real email is sent by default. The
+ * `ConsoleMailer` simply prints the email in the terminal. The emails are sent
+ * through an SMTP server once it's set in the configuration (see `smtp.rs`),
+ * or through the HTTP API of a provider (see `api.rs`). The tests capture them
+ * in memory instead (see `capture.rs`).
+ *
+ * # Author
+ * Doran Kayoumi <doran.kayoumi@heig-vd.ch>
+ */
+
+#[cfg(any(feature = "sendgrid", feature = "mailgun", feature = "ses"))]
+pub mod api;
+#[cfg(any(test, feature = "test-utils"))]
+pub mod capture;
+pub mod dedupe;
+pub mod smtp;
+pub mod templates;
+
+use lazy_static::lazy_static;
+use std::fmt;
+
+use crate::config;
+use crate::db::repository::SQliteNotificationRepository;
+use crate::errors::MailError;
+use crate::utils::Redacted;
+use dedupe::DedupingMailer;
+use smtp::{MailMetrics, SmtpMailer};
+
+/// An email ready to be sent
+/// Note: the body is hidden in the debug output, it can hold a token (e.g. a reset token)
+#[derive(PartialEq, Clone)]
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+impl fmt::Debug for Email {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Email")
+            .field("to", &self.to)
+            .field("subject", &self.subject)
+            .field("body", &Redacted)
+            .finish()
+    }
+}
+
+pub trait Mailer {
+    /// Try and send an email
+    /// if something goes wrong, an error is returned
+    ///
+    /// # Arguments
+    ///
+    /// * `email` - the email to send
+    ///
+    fn send(&self, email: &Email) -> Result<(), MailError>;
+}
+
+pub struct ConsoleMailer {}
+
+#[cfg(test)]
+use mockall::automock;
+
+#[cfg_attr(test, automock)]
+/// Implementation of the `Mailer` that "sends" the emails to the standard output
+impl Mailer for ConsoleMailer {
+    fn send(&self, email: &Email) -> Result<(), MailError> {
+        println!();
+        println!("from: {}", config::get().mail.sender);
+        println!("to: {}", email.to);
+        println!("subject: {}", email.subject);
+        println!("message:");
+        println!("{}", email.body);
+        println!();
+
+        Ok(())
+    }
+}
+
+/// Stands for a mailer that couldn't be set up (e.g. the SMTP password is missing),
+/// every email fails with the error of its setup
+pub struct UnavailableMailer(MailError);
+
+impl Mailer for UnavailableMailer {
+    fn send(&self, _: &Email) -> Result<(), MailError> {
+        Err(self.0)
+    }
+}
+
+lazy_static! {
+    /// Note: kept for the whole run, so its connections are reused
+    static ref SMTP: Option<Result<SmtpMailer, UnavailableMailer>> = {
+        let mail = &config::get().mail;
+        mail.smtp
+            .as_ref()
+            .map(|c| SmtpMailer::new(c, &mail.sender).map_err(UnavailableMailer))
+    };
+
+    static ref API: Option<Box<dyn Mailer + Send + Sync>> = {
+        let mail = &config::get().mail;
+        mail.api
+            .as_ref()
+            .map(|c| {
+                api_mailer(c, &mail.sender)
+                    .unwrap_or_else(|e| Box::new(UnavailableMailer(e)))
+            })
+    };
+}
+
+#[cfg(any(feature = "sendgrid", feature = "mailgun", feature = "ses"))]
+pub(crate) fn api_mailer(
+    c: &config::MailApiConfig,
+    sender: &str,
+) -> Result<Box<dyn Mailer + Send + Sync>, MailError> {
+    api::from_config(c, sender)
+}
+
+#[cfg(not(any(feature = "sendgrid", feature = "mailgun", feature = "ses")))]
+pub(crate) fn api_mailer(
+    _: &config::MailApiConfig,
+    _: &str,
+) -> Result<Box<dyn Mailer + Send + Sync>, MailError> {
+    Err(MailError::UnsupportedProvider)
+}
+
+/// Get the mailer set up in the configuration, the SMTP one if a server is set,
+/// then the API one if a provider is set, the `ConsoleMailer` otherwise
+/// If the SMTP server or the provider is set but can't be set up (e.g. the provider
+/// isn't enabled in this build), every email fails with the error of the setup
+/// (see `secure-auth check` to find out why)
+pub fn default_mailer() -> &'static dyn Mailer {
+    match SMTP.as_ref() {
+        Some(Ok(mailer)) => return mailer,
+        Some(Err(unavailable)) => return unavailable,
+        None => {}
+    }
+
+    match API.as_ref() {
+        Some(mailer) => mailer.as_ref(),
+        None => &ConsoleMailer {},
+    }
+}
+
+/// Get the mailer of the security alerts, the default one skipping the alerts
+/// identical to one sent within the window set in the configuration
+pub fn alert_mailer() -> DedupingMailer<'static> {
+    DedupingMailer::new(
+        default_mailer(),
+        Box::new(SQliteNotificationRepository::new()),
+        config::get().mail.dedupe_window_secs,
+    )
+}
+
+/// Get the metrics of the emails sent through the SMTP server (if one is set)
+pub fn metrics() -> Option<MailMetrics> {
+    match SMTP.as_ref() {
+        Some(Ok(mailer)) => Some(mailer.metrics()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::db::models::User;
+    use templates::Template;
+
+    #[test]
+    fn test_debug_hides_body() {
+        let u = User::new("email@email.test", "passwd_hash");
+        let email = templates::render(
+            &Template::ResetToken {
+                token: "s3cr3t-token".to_string(),
+            },
+            &u,
+        );
+
+        let debug = format!("{:?}", email);
+        assert!(debug.contains("email@email.test"));
+        assert!(!debug.contains("s3cr3t-token"));
+    }
+
+    #[test]
+    fn test_unavailable_mailer() {
+        // Note: Mailgun can't be set up without its domain
+        let api = config::MailApiConfig {
+            provider: config::MailApiProvider::Mailgun,
+            domain: None,
+            region: None,
+            timeout_secs: 10,
+        };
+        let mailer =
+            api_mailer(&api, "auth@email.test").unwrap_or_else(|e| Box::new(UnavailableMailer(e)));
+        let email = Email {
+            to: "email@email.test".to_string(),
+            subject: "subject".to_string(),
+            body: "body".to_string(),
+        };
+
+        assert!(mailer.send(&email).is_err());
+        assert_eq!(
+            UnavailableMailer(MailError::UnsupportedProvider).send(&email),
+            Err(MailError::UnsupportedProvider)
+        );
+    }
+}
@@ -0,0 +1,436 @@
+/*!
+ * Mailers sending the emails through the HTTP API of a provider
+ *
+ * Many deployments can't speak SMTP from their network, the emails can then be
+ * sent through SendGrid, Mailgun or Amazon SES instead. Each provider is behind
+ * its own feature (`sendgrid`, `mailgun`, `ses`) & is selected in the
+ * `[mail.api]` section of the configuration.
+ *
+ * The credentials are read from `MAIL_API_KEY` (& `MAIL_API_SECRET` for SES),
+ * in the environment or the `.env` file, so they aren't stored in the configuration.
+ *
+ * # Author
+ * Doran Kayoumi <doran.kayoumi@heig-vd.ch>
+ */
+
+#[cfg(feature = "ses")]
+use chrono::{DateTime, Utc};
+use std::env;
+use std::error::Error;
+use std::io;
+use std::time::Duration;
+use ureq::{Agent, AgentBuilder};
+
+use super::{Email, Mailer};
+use crate::config::{MailApiConfig, MailApiProvider};
+use crate::errors::MailError;
+
+/// Name of the variable holding the API key (the access key id for SES)
+pub const KEY_VARIABLE: &str = "MAIL_API_KEY";
+/// Name of the variable holding the secret access key of SES
+pub const SECRET_VARIABLE: &str = "MAIL_API_SECRET";
+
+/// A request to the API of a provider
+#[derive(PartialEq, Debug)]
+struct ApiRequest {
+    url: String,
+    headers: Vec<(&'static str, String)>,
+    body: String,
+}
+
+/// Send a request, any status other than 2xx is a failure
+fn send(agent: &Agent, request: ApiRequest) -> Result<(), MailError> {
+    let mut req = agent.post(&request.url);
+    for (name, value) in &request.headers {
+        req = req.set(name, value);
+    }
+
+    req.send_string(&request.body).map(|_| ()).map_err(|e| {
+        if is_timeout(&e) {
+            MailError::Timeout
+        } else {
+            MailError::SendError
+        }
+    })
+}
+
+/// Whether a request failed because the provider didn't answer in time
+/// (see `timeout_secs` in the configuration)
+fn is_timeout(e: &ureq::Error) -> bool {
+    match e {
+        ureq::Error::Transport(t) => t
+            .source()
+            .and_then(|s| s.downcast_ref::<io::Error>())
+            .is_some_and(|e| {
+                matches!(
+                    e.kind(),
+                    io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock
+                )
+            }),
+        ureq::Error::Status(..) => false,
+    }
+}
+
+fn variable(name: &str) -> Result<String, MailError> {
+    dotenv::dotenv().ok();
+    env::var(name).map_err(|_| MailError::SetupError)
+}
+
+/// Create the mailer of the provider set in the configuration
+/// if its feature isn't enabled or its credentials are missing, an error is returned
+///
+/// # Arguments
+///
+/// * `config` - the provider to use
+///
+/// * `sender` - the address the emails are sent from
+///
+pub fn from_config(
+    config: &MailApiConfig,
+    sender: &str,
+) -> Result<Box<dyn Mailer + Send + Sync>, MailError> {
+    let sender = sender.to_string();
+    let agent = AgentBuilder::new()
+        .timeout(Duration::from_secs(config.timeout_secs))
+        .build();
+
+    match config.provider {
+        #[cfg(feature = "sendgrid")]
+        MailApiProvider::SendGrid => Ok(Box::new(SendGridMailer {
+            agent,
+            sender,
+            api_key: variable(KEY_VARIABLE)?,
+        })),
+        #[cfg(feature = "mailgun")]
+        MailApiProvider::Mailgun => Ok(Box::new(MailgunMailer {
+            agent,
+            sender,
+            api_key: variable(KEY_VARIABLE)?,
+            domain: config.domain.clone().ok_or(MailError::SetupError)?,
+            eu: config.region.as_deref() == Some("eu"),
+        })),
+        #[cfg(feature = "ses")]
+        MailApiProvider::Ses => Ok(Box::new(SesMailer {
+            agent,
+            sender,
+            access_key_id: variable(KEY_VARIABLE)?,
+            secret_access_key: variable(SECRET_VARIABLE)?,
+            region: config.region.clone().ok_or(MailError::SetupError)?,
+        })),
+        #[allow(unreachable_patterns)]
+        _ => {
+            let _ = agent;
+            Err(MailError::UnsupportedProvider)
+        }
+    }
+}
+
+#[cfg(feature = "sendgrid")]
+pub struct SendGridMailer {
+    agent: Agent,
+    sender: String,
+    api_key: String,
+}
+
+#[cfg(feature = "sendgrid")]
+impl SendGridMailer {
+    fn request(&self, email: &Email) -> ApiRequest {
+        let body = serde_json::json!({
+            "personalizations": [{ "to": [{ "email": email.to }] }],
+            "from": { "email": self.sender },
+            "subject": email.subject,
+            "content": [{ "type": "text/plain", "value": email.body }],
+        });
+
+        ApiRequest {
+            url: "https://api.sendgrid.com/v3/mail/send".to_string(),
+            headers: vec![
+                ("Authorization", format!("Bearer {}", self.api_key)),
+                ("Content-Type", "application/json".to_string()),
+            ],
+            body: body.to_string(),
+        }
+    }
+}
+
+#[cfg(feature = "sendgrid")]
+impl Mailer for SendGridMailer {
+    fn send(&self, email: &Email) -> Result<(), MailError> {
+        send(&self.agent, self.request(email))
+    }
+}
+
+#[cfg(feature = "mailgun")]
+pub struct MailgunMailer {
+    agent: Agent,
+    sender: String,
+    api_key: String,
+    domain: String,
+    /// The domain is hosted in the EU region of Mailgun
+    eu: bool,
+}
+
+#[cfg(feature = "mailgun")]
+impl MailgunMailer {
+    fn request(&self, email: &Email) -> ApiRequest {
+        use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+        use sodiumoxide::base64::{self, Variant};
+
+        let body = [
+            ("from", self.sender.as_str()),
+            ("to", &email.to),
+            ("subject", &email.subject),
+            ("text", &email.body),
+        ]
+        .iter()
+        .map(|(name, value)| format!("{}={}", name, utf8_percent_encode(value, NON_ALPHANUMERIC)))
+        .collect::<Vec<_>>()
+        .join("&");
+        let credentials = base64::encode(format!("api:{}", self.api_key), Variant::Original);
+
+        ApiRequest {
+            url: format!(
+                "https://api.{}mailgun.net/v3/{}/messages",
+                if self.eu { "eu." } else { "" },
+                self.domain
+            ),
+            headers: vec![
+                ("Authorization", format!("Basic {}", credentials)),
+                (
+                    "Content-Type",
+                    "application/x-www-form-urlencoded".to_string(),
+                ),
+            ],
+            body,
+        }
+    }
+}
+
+#[cfg(feature = "mailgun")]
+impl Mailer for MailgunMailer {
+    fn send(&self, email: &Email) -> Result<(), MailError> {
+        send(&self.agent, self.request(email))
+    }
+}
+
+#[cfg(feature = "ses")]
+pub struct SesMailer {
+    agent: Agent,
+    sender: String,
+    access_key_id: String,
+    secret_access_key: String,
+    region: String,
+}
+
+#[cfg(feature = "ses")]
+fn hmac(key: &[u8], data: &str) -> Vec<u8> {
+    use sodiumoxide::crypto::auth::hmacsha256;
+
+    let mut state = hmacsha256::State::init(key);
+    state.update(data.as_bytes());
+    state.finalize().as_ref().to_vec()
+}
+
+#[cfg(feature = "ses")]
+fn sha256_hex(data: &str) -> String {
+    sodiumoxide::hex::encode(sodiumoxide::crypto::hash::sha256::hash(data.as_bytes()))
+}
+
+/// Derive the key signing the requests of a day (AWS Signature Version 4)
+///
+/// # Arguments
+///
+/// * `secret` - the secret access key
+///
+/// * `date` - the day of the request, as `YYYYMMDD`
+///
+/// * `region` - the region of the service
+///
+/// * `service` - the name of the service
+///
+#[cfg(feature = "ses")]
+fn signing_key(secret: &str, date: &str, region: &str, service: &str) -> Vec<u8> {
+    let key = hmac(format!("AWS4{}", secret).as_bytes(), date);
+    let key = hmac(&key, region);
+    let key = hmac(&key, service);
+    hmac(&key, "aws4_request")
+}
+
+#[cfg(feature = "ses")]
+impl SesMailer {
+    fn request(&self, email: &Email, now: DateTime<Utc>) -> ApiRequest {
+        const PATH: &str = "/v2/email/outbound-emails";
+        const SIGNED_HEADERS: &str = "content-type;host;x-amz-date";
+
+        let body = serde_json::json!({
+            "FromEmailAddress": self.sender,
+            "Destination": { "ToAddresses": [email.to] },
+            "Content": { "Simple": {
+                "Subject": { "Data": email.subject },
+                "Body": { "Text": { "Data": email.body } },
+            } },
+        })
+        .to_string();
+
+        let host = format!("email.{}.amazonaws.com", self.region);
+        let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let date = now.format("%Y%m%d").to_string();
+        let scope = format!("{}/{}/ses/aws4_request", date, self.region);
+
+        let canonical_request = format!(
+            "POST\n{}\n\ncontent-type:application/json\nhost:{}\nx-amz-date:{}\n\n{}\n{}",
+            PATH,
+            host,
+            amz_date,
+            SIGNED_HEADERS,
+            sha256_hex(&body)
+        );
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            amz_date,
+            scope,
+            sha256_hex(&canonical_request)
+        );
+        let key = signing_key(&self.secret_access_key, &date, &self.region, "ses");
+        let signature = sodiumoxide::hex::encode(hmac(&key, &string_to_sign));
+
+        ApiRequest {
+            url: format!("https://{}{}", host, PATH),
+            headers: vec![
+                (
+                    "Authorization",
+                    format!(
+                        "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+                        self.access_key_id, scope, SIGNED_HEADERS, signature
+                    ),
+                ),
+                ("Content-Type", "application/json".to_string()),
+                ("X-Amz-Date", amz_date),
+            ],
+            body,
+        }
+    }
+}
+
+#[cfg(feature = "ses")]
+impl Mailer for SesMailer {
+    fn send(&self, email: &Email) -> Result<(), MailError> {
+        send(&self.agent, self.request(email, Utc::now()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[allow(dead_code)]
+    fn email() -> Email {
+        Email {
+            to: "email@email.test".to_string(),
+            subject: "Hello & welcome".to_string(),
+            body: "Kind regards".to_string(),
+        }
+    }
+
+    #[cfg(feature = "sendgrid")]
+    #[test]
+    fn test_sendgrid_request() {
+        let mailer = SendGridMailer {
+            agent: Agent::new(),
+            sender: "auth@email.test".to_string(),
+            api_key: "key".to_string(),
+        };
+
+        let request = mailer.request(&email());
+
+        assert_eq!(request.url, "https://api.sendgrid.com/v3/mail/send");
+        assert!(request
+            .headers
+            .contains(&("Authorization", "Bearer key".to_string())));
+        let body: serde_json::Value = serde_json::from_str(&request.body).unwrap();
+        assert_eq!(
+            body["personalizations"][0]["to"][0]["email"],
+            "email@email.test"
+        );
+    }
+
+    #[cfg(feature = "mailgun")]
+    #[test]
+    fn test_mailgun_request() {
+        let mailer = MailgunMailer {
+            agent: Agent::new(),
+            sender: "auth@email.test".to_string(),
+            api_key: "key".to_string(),
+            domain: "mg.example.com".to_string(),
+            eu: true,
+        };
+
+        let request = mailer.request(&email());
+
+        assert_eq!(
+            request.url,
+            "https://api.eu.mailgun.net/v3/mg.example.com/messages"
+        );
+        // base64 of `api:key`
+        assert!(request
+            .headers
+            .contains(&("Authorization", "Basic YXBpOmtleQ==".to_string())));
+        assert!(request.body.contains("&subject=Hello%20%26%20welcome&"));
+    }
+
+    #[cfg(feature = "ses")]
+    #[test]
+    fn test_hmac_vector() {
+        // RFC 4231, test case 2
+        assert_eq!(
+            sodiumoxide::hex::encode(hmac(b"Jefe", "what do ya want for nothing?")),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[cfg(feature = "ses")]
+    #[test]
+    fn test_signing_key() {
+        // example from the AWS documentation
+        let key = signing_key(
+            "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY",
+            "20120215",
+            "us-east-1",
+            "iam",
+        );
+
+        assert_eq!(
+            sodiumoxide::hex::encode(key),
+            "f4780e2d9f65fa895f9c67b32ce1baf0b0d8a43505a000a1a9e090d414db404d"
+        );
+    }
+
+    #[cfg(feature = "ses")]
+    #[test]
+    fn test_ses_request() {
+        use chrono::TimeZone;
+
+        let mailer = SesMailer {
+            agent: Agent::new(),
+            sender: "auth@email.test".to_string(),
+            access_key_id: "AKID".to_string(),
+            secret_access_key: "secret".to_string(),
+            region: "eu-west-1".to_string(),
+        };
+
+        let request = mailer.request(&email(), Utc.ymd(2021, 4, 28).and_hms(14, 5, 0));
+
+        assert_eq!(
+            request.url,
+            "https://email.eu-west-1.amazonaws.com/v2/email/outbound-emails"
+        );
+        assert!(request.headers.iter().any(|(n, v)| *n == "Authorization"
+            && v.starts_with(
+                "AWS4-HMAC-SHA256 Credential=AKID/20210428/eu-west-1/ses/aws4_request, "
+            )));
+        assert!(request
+            .headers
+            .contains(&("X-Amz-Date", "20210428T140500Z".to_string())));
+    }
+}
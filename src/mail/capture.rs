@@ -0,0 +1,170 @@
+/*!
+ * Mailbox keeping the emails in memory
+ *
+ * Meant for the tests: the emails "sent" are captured instead of printed, so
+ * a test can read the token a flow sent (reset, trusted contact approval,
+ * "this wasn't me" link...) & carry on the flow with it. Only built with the
+ * `test-utils` feature (and for the tests of the library).
+ *
+ * # Author
+ * Doran Kayoumi <doran.kayoumi@heig-vd.ch>
+ */
+
+use std::sync::{Arc, Mutex, PoisonError};
+
+use super::{Email, Mailer};
+use crate::errors::MailError;
+
+/// What precedes a token in the bodies of the emails (see `templates.rs`)
+const TOKEN_MARKERS: [&str; 8] = [
+    "token: ",
+    "?token=",
+    "secure-auth recovery approve ",
+    "secure-auth recovery confirm ",
+    "secure-auth recovery take-over ",
+    "secure-auth not-me ",
+    "secure-auth verify-email ",
+    "secure-auth unlock ",
+];
+
+/// `Mailer` keeping the emails in memory
+/// Note: its clones share the same mailbox, so a clone can be handed to a flow
+/// (e.g. `AuthService::with_mailer`) & the emails read from the original
+#[derive(Clone, Default)]
+pub struct CapturingMailer {
+    mailbox: Arc<Mutex<Vec<Email>>>,
+}
+
+impl CapturingMailer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// All the emails sent so far, the oldest first
+    pub fn emails(&self) -> Vec<Email> {
+        self.mailbox
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    /// Emails sent to a recipient so far, the oldest first
+    ///
+    /// # Arguments
+    ///
+    /// * `to` - the email of the recipient
+    ///
+    pub fn emails_to(&self, to: &str) -> Vec<Email> {
+        self.emails().into_iter().filter(|e| e.to == to).collect()
+    }
+
+    /// Get the token of the latest email sent to a recipient with one
+    ///
+    /// # Arguments
+    ///
+    /// * `to` - the email of the recipient
+    ///
+    pub fn last_token_for(&self, to: &str) -> Option<String> {
+        self.emails_to(to)
+            .iter()
+            .rev()
+            .find_map(|e| token_in(&e.body))
+    }
+
+    /// Forget the emails sent so far
+    pub fn clear(&self) {
+        self.mailbox
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clear();
+    }
+}
+
+impl Mailer for CapturingMailer {
+    fn send(&self, email: &Email) -> Result<(), MailError> {
+        self.mailbox
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(email.clone());
+
+        Ok(())
+    }
+}
+
+/// Find a token in the body of an email
+///
+/// # Arguments
+///
+/// * `body` - the body of the email
+///
+fn token_in(body: &str) -> Option<String> {
+    TOKEN_MARKERS.iter().find_map(|marker| {
+        let (_, rest) = body.split_once(marker)?;
+        let token: String = rest
+            .chars()
+            .take_while(|c| !c.is_whitespace() && *c != '&')
+            .collect();
+        (!token.is_empty()).then_some(token)
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn email(to: &str, body: &str) -> Email {
+        Email {
+            to: to.to_string(),
+            subject: "subject".to_string(),
+            body: body.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_token_in() {
+        assert_eq!(
+            token_in("Here is your reset token: abc123\n"),
+            Some("abc123".to_string())
+        );
+        assert_eq!(
+            token_in("approve with:\nhttps://auth.test/approve?token=abc123\n"),
+            Some("abc123".to_string())
+        );
+        assert_eq!(
+            token_in("approve with:\nsecure-auth recovery approve abc123"),
+            Some("abc123".to_string())
+        );
+        assert_eq!(token_in("You'll receive a reset token."), None);
+    }
+
+    #[test]
+    fn test_last_token_for() {
+        let mailer = CapturingMailer::new();
+        // the clones share the mailbox
+        let sender = mailer.clone();
+
+        sender
+            .send(&email("alice@email.test", "reset token: first"))
+            .unwrap();
+        sender
+            .send(&email("alice@email.test", "reset token: second"))
+            .unwrap();
+        sender
+            .send(&email("alice@email.test", "Your password was changed."))
+            .unwrap();
+        sender
+            .send(&email("bob@email.test", "reset token: bob"))
+            .unwrap();
+
+        assert_eq!(mailer.emails().len(), 4);
+        assert_eq!(mailer.emails_to("alice@email.test").len(), 3);
+        assert_eq!(
+            mailer.last_token_for("alice@email.test"),
+            Some("second".to_string())
+        );
+        assert_eq!(mailer.last_token_for("carol@email.test"), None);
+
+        mailer.clear();
+        assert_eq!(mailer.last_token_for("bob@email.test"), None);
+    }
+}
@@ -0,0 +1,192 @@
+/*!
+ * Deduplication of the security alerts
+ *
+ * A burst of events (e.g. five failed logins in one minute) shouldn't flood
+ * the mailbox of the user. Identical alerts sent to the same recipient within
+ * a window (see `dedupe_window_secs` in the `[mail]` section of the configuration)
+ * are collapsed into the first one, the others are only counted.
+ *
+ * The alerts sent are kept in the `notification_dedupe` table, so the
+ * deduplication works across the processes.
+ *
+ * # Author
+ * Doran Kayoumi <doran.kayoumi@heig-vd.ch>
+ */
+
+use chrono::{Duration, Utc};
+use sodiumoxide::crypto::hash::sha256;
+
+use super::{Email, Mailer};
+use crate::db::repository::NotificationRepository;
+use crate::errors::MailError;
+
+/// Identify the content of an email, two identical alerts have the same fingerprint
+fn fingerprint(email: &Email) -> String {
+    let content = format!("{}\n{}", email.subject, email.body);
+
+    sodiumoxide::hex::encode(sha256::hash(content.as_bytes()))
+}
+
+/// Mailer skipping the emails identical to one sent within the window
+pub struct DedupingMailer<'a> {
+    mailer: &'a dyn Mailer,
+    repository: Box<dyn NotificationRepository>,
+    window: Duration,
+}
+
+impl<'a> DedupingMailer<'a> {
+    /// # Arguments
+    ///
+    /// * `mailer` - the mailer actually sending the emails
+    ///
+    /// * `repository` - where the emails sent are kept
+    ///
+    /// * `window_secs` - how long (in seconds) identical emails are collapsed, 0 disables it
+    ///
+    pub fn new(
+        mailer: &'a dyn Mailer,
+        repository: Box<dyn NotificationRepository>,
+        window_secs: u64,
+    ) -> Self {
+        Self {
+            mailer,
+            repository,
+            window: Duration::seconds(window_secs as i64),
+        }
+    }
+}
+
+impl Mailer for DedupingMailer<'_> {
+    fn send(&self, email: &Email) -> Result<(), MailError> {
+        if self.window.is_zero() {
+            return self.mailer.send(email);
+        }
+
+        let fingerprint = fingerprint(email);
+        // Note: if the table can't be used, the alert is sent anyway, a duplicate is better than nothing
+        if let Ok(false) = self
+            .repository
+            .claim(&email.to, &fingerprint, Utc::now(), self.window)
+        {
+            return Ok(());
+        }
+
+        // the alert wasn't sent after all, so the next identical one mustn't be skipped
+        self.mailer.send(email).inspect_err(|_| {
+            self.repository.release(&email.to, &fingerprint).ok();
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::db::repository::MockSQliteNotificationRepository;
+    use crate::errors::NotificationDBError;
+    use crate::mail::MockConsoleMailer;
+    use mockall::predicate::*;
+
+    fn alert() -> Email {
+        Email {
+            to: "email@email.test".to_string(),
+            subject: "Your password was changed".to_string(),
+            body: "The password of your account was just changed.".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_identical_alerts_are_collapsed() {
+        let mut mailer = MockConsoleMailer::new();
+        let mut repository = MockSQliteNotificationRepository::new();
+
+        repository
+            .expect_claim()
+            .with(
+                eq("email@email.test"),
+                eq(fingerprint(&alert())),
+                always(),
+                eq(Duration::seconds(60)),
+            )
+            .times(1)
+            .returning(|_, _, _, _| Ok(false));
+        mailer.expect_send().times(0);
+
+        let mailer = DedupingMailer::new(&mailer, Box::new(repository), 60);
+        assert_eq!(mailer.send(&alert()), Ok(()));
+    }
+
+    #[test]
+    fn test_first_alert_is_sent() {
+        let mut mailer = MockConsoleMailer::new();
+        let mut repository = MockSQliteNotificationRepository::new();
+
+        repository
+            .expect_claim()
+            .times(1)
+            .returning(|_, _, _, _| Ok(true));
+        repository.expect_release().times(0);
+        mailer.expect_send().times(1).returning(|_| Ok(()));
+
+        let mailer = DedupingMailer::new(&mailer, Box::new(repository), 60);
+        assert_eq!(mailer.send(&alert()), Ok(()));
+    }
+
+    #[test]
+    fn test_failed_alert_is_released() {
+        let mut mailer = MockConsoleMailer::new();
+        let mut repository = MockSQliteNotificationRepository::new();
+
+        repository
+            .expect_claim()
+            .times(1)
+            .returning(|_, _, _, _| Ok(true));
+        repository
+            .expect_release()
+            .with(eq("email@email.test"), eq(fingerprint(&alert())))
+            .times(1)
+            .returning(|_, _| Ok(()));
+        mailer
+            .expect_send()
+            .times(1)
+            .returning(|_| Err(MailError::SendError));
+
+        let mailer = DedupingMailer::new(&mailer, Box::new(repository), 60);
+        assert_eq!(mailer.send(&alert()), Err(MailError::SendError));
+    }
+
+    #[test]
+    fn test_alert_is_sent_if_the_table_is_unavailable() {
+        let mut mailer = MockConsoleMailer::new();
+        let mut repository = MockSQliteNotificationRepository::new();
+
+        repository
+            .expect_claim()
+            .returning(|_, _, _, _| Err(NotificationDBError::ClaimError));
+        mailer.expect_send().times(1).returning(|_| Ok(()));
+
+        let mailer = DedupingMailer::new(&mailer, Box::new(repository), 60);
+        assert_eq!(mailer.send(&alert()), Ok(()));
+    }
+
+    #[test]
+    fn test_zero_window_disables_dedupe() {
+        let mut mailer = MockConsoleMailer::new();
+        let mut repository = MockSQliteNotificationRepository::new();
+
+        repository.expect_claim().times(0);
+        mailer.expect_send().times(2).returning(|_| Ok(()));
+
+        let mailer = DedupingMailer::new(&mailer, Box::new(repository), 0);
+        assert_eq!(mailer.send(&alert()), Ok(()));
+        assert_eq!(mailer.send(&alert()), Ok(()));
+    }
+
+    #[test]
+    fn test_fingerprint() {
+        let mut other = alert();
+        other.body.push('!');
+
+        assert_eq!(fingerprint(&alert()), fingerprint(&alert()));
+        assert_ne!(fingerprint(&alert()), fingerprint(&other));
+    }
+}
@@ -0,0 +1,340 @@
+/*!
+ * Mailer sending the emails through an SMTP server
+ *
+ * The connections are pooled & always use TLS with a verified certificate
+ * (implicit TLS or STARTTLS, see `SmtpSecurity`). The transient failures
+ * (e.g. 4xx replies, dropped connections) are retried with a jittered
+ * exponential backoff, the permanent ones are reported right away.
+ * The number of emails sent, failed & retried are kept, see `SmtpMailer::metrics`.
+ *
+ * # Author
+ * Doran Kayoumi <doran.kayoumi@heig-vd.ch>
+ */
+
+use lettre::message::Mailbox;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::transport::smtp::PoolConfig;
+use lettre::{Message, SmtpTransport, Transport};
+use rand::{thread_rng, Rng};
+use std::env;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::{Email, Mailer};
+use crate::config::{SmtpConfig, SmtpSecurity};
+use crate::errors::MailError;
+
+/// Name of the variable (in the environment or the `.env` file) holding the SMTP password
+pub const PASSWORD_VARIABLE: &str = "SMTP_PASSWORD";
+
+/// What went wrong while trying to send an email
+#[derive(PartialEq, Debug, Clone, Copy)]
+enum Failure {
+    /// Trying again later may work (e.g. the server is busy)
+    Transient,
+    /// Trying again won't help (e.g. the recipient doesn't exist)
+    Permanent,
+    /// The server didn't answer in time (see `timeout_secs` in the configuration),
+    /// it's retried like a transient failure
+    Timeout,
+}
+
+impl From<&lettre::transport::smtp::Error> for Failure {
+    fn from(e: &lettre::transport::smtp::Error) -> Self {
+        // Note: a TLS error is most likely an invalid certificate, retrying won't fix it
+        if e.is_timeout() {
+            Failure::Timeout
+        } else if e.is_permanent() || e.is_client() || e.is_tls() || e.is_response() {
+            Failure::Permanent
+        } else {
+            Failure::Transient
+        }
+    }
+}
+
+/// Counters of the emails sent
+#[derive(Default)]
+struct Counters {
+    sent: AtomicU64,
+    failed: AtomicU64,
+    retries: AtomicU64,
+    latency_ms: AtomicU64,
+}
+
+/// Metrics of the emails sent since the mailer was created
+#[derive(PartialEq, Debug, Clone, Copy, Default)]
+pub struct MailMetrics {
+    pub sent: u64,
+    /// Emails that couldn't be sent, even after the retries
+    pub failed: u64,
+    pub retries: u64,
+    /// Average time (in ms) it took to send an email, retries included
+    pub average_latency_ms: u64,
+}
+
+/// Delay before a retry, it doubles with each attempt & half of it is random,
+/// so the clients failing together don't retry together
+///
+/// # Arguments
+///
+/// * `attempt` - the number of the attempt that failed (starting at 0)
+///
+/// * `base` - the delay before the first retry
+///
+fn backoff(attempt: u32, base: Duration) -> Duration {
+    let delay = base.saturating_mul(2u32.saturating_pow(attempt));
+    let half = delay / 2;
+
+    half + half.mul_f64(thread_rng().gen::<f64>())
+}
+
+/// Try to send an email until it works, a permanent failure occurs or there's no retry left
+///
+/// # Arguments
+///
+/// * `max_retries` - how many times a transient failure is retried
+///
+/// * `base` - the delay before the first retry
+///
+/// * `counters` - where the retries are counted
+///
+/// * `sleep` - waits between the attempts
+///
+/// * `attempt` - tries to send the email once
+///
+fn send_with_retry(
+    max_retries: u32,
+    base: Duration,
+    counters: &Counters,
+    sleep: impl Fn(Duration),
+    mut attempt: impl FnMut() -> Result<(), Failure>,
+) -> Result<(), MailError> {
+    let mut retries = 0;
+
+    loop {
+        match attempt() {
+            Ok(()) => return Ok(()),
+            Err(Failure::Transient) | Err(Failure::Timeout) if retries < max_retries => {
+                sleep(backoff(retries, base));
+                retries += 1;
+                counters.retries.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(Failure::Timeout) => return Err(MailError::Timeout),
+            Err(_) => return Err(MailError::SendError),
+        }
+    }
+}
+
+pub struct SmtpMailer {
+    transport: SmtpTransport,
+    sender: Mailbox,
+    max_retries: u32,
+    backoff: Duration,
+    counters: Counters,
+}
+
+impl SmtpMailer {
+    /// Set up the connection pool to the SMTP server, no connection is opened yet
+    /// The password is read from `SMTP_PASSWORD`
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - the SMTP server to use
+    ///
+    /// * `sender` - the address the emails are sent from
+    ///
+    pub fn new(config: &SmtpConfig, sender: &str) -> Result<Self, MailError> {
+        let sender = sender.parse().map_err(|_| MailError::SetupError)?;
+        let builder = match config.security {
+            SmtpSecurity::Tls => SmtpTransport::relay(&config.host),
+            SmtpSecurity::StartTls => SmtpTransport::starttls_relay(&config.host),
+        }
+        .map_err(|_| MailError::SetupError)?;
+
+        let mut builder = builder
+            .timeout(Some(Duration::from_secs(config.timeout_secs)))
+            .pool_config(PoolConfig::new().max_size(config.pool_size));
+        if let Some(port) = config.port {
+            builder = builder.port(port);
+        }
+        if let Some(username) = &config.username {
+            dotenv::dotenv().ok();
+            let password = env::var(PASSWORD_VARIABLE).map_err(|_| MailError::SetupError)?;
+            builder = builder.credentials(Credentials::new(username.clone(), password));
+        }
+
+        Ok(Self {
+            transport: builder.build(),
+            sender,
+            max_retries: config.max_retries,
+            backoff: Duration::from_millis(config.backoff_ms),
+            counters: Counters::default(),
+        })
+    }
+
+    /// Check that the SMTP server answers & accepts the connection (see `secure-auth check`)
+    /// Note: the credentials are only checked once an email is sent
+    pub fn test_connection(&self) -> Result<(), MailError> {
+        match self.transport.test_connection() {
+            Ok(true) => Ok(()),
+            Err(e) if Failure::from(&e) == Failure::Timeout => Err(MailError::Timeout),
+            _ => Err(MailError::SendError),
+        }
+    }
+
+    /// Metrics of the emails sent so far
+    pub fn metrics(&self) -> MailMetrics {
+        let sent = self.counters.sent.load(Ordering::Relaxed);
+        let failed = self.counters.failed.load(Ordering::Relaxed);
+        let latency_ms = self.counters.latency_ms.load(Ordering::Relaxed);
+
+        MailMetrics {
+            sent,
+            failed,
+            retries: self.counters.retries.load(Ordering::Relaxed),
+            average_latency_ms: latency_ms.checked_div(sent + failed).unwrap_or(0),
+        }
+    }
+}
+
+impl Mailer for SmtpMailer {
+    fn send(&self, email: &Email) -> Result<(), MailError> {
+        let message = Message::builder()
+            .from(self.sender.clone())
+            .to(email.to.parse().map_err(|_| MailError::SendError)?)
+            .subject(email.subject.as_str())
+            .body(email.body.clone())
+            .map_err(|_| MailError::SendError)?;
+
+        let start = Instant::now();
+        let res = send_with_retry(
+            self.max_retries,
+            self.backoff,
+            &self.counters,
+            thread::sleep,
+            || {
+                self.transport
+                    .send(&message)
+                    .map(|_| ())
+                    .map_err(|e| Failure::from(&e))
+            },
+        );
+
+        let counter = if res.is_ok() {
+            &self.counters.sent
+        } else {
+            &self.counters.failed
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        self.counters
+            .latency_ms
+            .fetch_add(start.elapsed().as_millis() as u64, Ordering::Relaxed);
+
+        res
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::cell::RefCell;
+
+    /// Attempts failing with the given failures, then succeeding
+    fn attempts(failures: Vec<Failure>) -> impl FnMut() -> Result<(), Failure> {
+        let mut failures = failures.into_iter();
+        move || failures.next().map_or(Ok(()), Err)
+    }
+
+    #[test]
+    fn test_backoff_is_jittered_exponential() {
+        let base = Duration::from_millis(100);
+
+        for attempt in 0..4 {
+            let delay = backoff(attempt, base);
+            let max = base * 2u32.pow(attempt);
+
+            assert!(delay >= max / 2 && delay <= max);
+        }
+    }
+
+    #[test]
+    fn test_transient_failures_are_retried() {
+        let counters = Counters::default();
+        let delays = RefCell::new(vec![]);
+
+        let res = send_with_retry(
+            3,
+            Duration::from_millis(100),
+            &counters,
+            |d| delays.borrow_mut().push(d),
+            attempts(vec![Failure::Transient, Failure::Transient]),
+        );
+
+        assert_eq!(res, Ok(()));
+        assert_eq!(counters.retries.load(Ordering::Relaxed), 2);
+        assert_eq!(delays.borrow().len(), 2);
+    }
+
+    #[test]
+    fn test_retries_are_limited() {
+        let counters = Counters::default();
+
+        let res = send_with_retry(
+            2,
+            Duration::from_millis(0),
+            &counters,
+            |_| {},
+            attempts(vec![Failure::Transient; 5]),
+        );
+
+        assert_eq!(res, Err(MailError::SendError));
+        assert_eq!(counters.retries.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn test_timeouts_are_retried() {
+        let counters = Counters::default();
+
+        let res = send_with_retry(
+            1,
+            Duration::from_millis(0),
+            &counters,
+            |_| {},
+            attempts(vec![Failure::Timeout; 2]),
+        );
+
+        assert_eq!(res, Err(MailError::Timeout));
+        assert_eq!(counters.retries.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn test_permanent_failures_are_not_retried() {
+        let counters = Counters::default();
+
+        let res = send_with_retry(
+            3,
+            Duration::from_millis(0),
+            &counters,
+            |_| panic!("a permanent failure mustn't be retried"),
+            attempts(vec![Failure::Permanent]),
+        );
+
+        assert_eq!(res, Err(MailError::SendError));
+    }
+
+    #[test]
+    fn test_new_metrics_are_empty() {
+        let mailer = SmtpMailer::new(&SmtpConfig::default(), "auth@email.test").unwrap();
+
+        assert_eq!(mailer.metrics(), MailMetrics::default());
+    }
+
+    #[test]
+    fn test_invalid_sender() {
+        assert!(matches!(
+            SmtpMailer::new(&SmtpConfig::default(), "not an address"),
+            Err(MailError::SetupError)
+        ));
+    }
+}
@@ -0,0 +1,618 @@
+/*!
+ * Templates of all the emails sent by the system
+ *
+ * Every email is rendered for a specific user so the user's anti-phishing
+ * phrase (if she/he has set one) can be injected in it. This way, the user
+ * can tell apart a real email from a phishing attempt.
+ *
+ * The integrators can set variables (e.g. `product_name`, `support_url`) &
+ * replace the subjects in the `[mail]` section of the configuration. The
+ * variables are used as `{name}` in the subjects & the bodies, `{email}` is
+ * the recipient.
+ *
+ * # Author
+ * Doran Kayoumi <doran.kayoumi@heig-vd.ch>
+ */
+
+use std::fmt;
+
+use super::Email;
+use crate::config::{self, MailConfig};
+use crate::db::models::User;
+use crate::utils::{redact, Redacted};
+
+/// Note: the reset, approval, take over, "this wasn't me", confirmation, verification & unlock
+/// tokens are hidden in the debug output
+#[derive(PartialEq, Clone)]
+pub enum Template {
+    /// Email containing the token to reset a password
+    ResetToken { token: String },
+
+    /// Security alert sent once the password of an account was changed, with
+    /// the token of its "this wasn't me" link (see `auth/not_me.rs`) if any
+    PasswordChanged { not_me: Option<String> },
+
+    /// Request sent to a trusted contact to approve the recovery of an account
+    ContactApproval { account: String, token: String },
+
+    /// Request sent to a new trusted contact to confirm she/he accepts the role
+    ContactConfirmation { account: String, token: String },
+
+    /// Security alert sent once a recovery through the trusted contacts was started, with
+    /// the token of the link taking it over (see `auth/contacts.rs`) if any
+    RecoveryStarted {
+        waiting_hours: i64,
+        takeover: Option<String>,
+    },
+
+    /// Warning sent to the users who didn't login for too long
+    InactivityWarning { inactive_days: i64, grace_days: u32 },
+
+    /// Token confirming a login the risk-based policy found unusual (see `auth/login.rs`)
+    LoginConfirmation { token: String },
+
+    /// Link verifying the email of a new account (see `auth/register.rs`)
+    VerifyEmail { token: String },
+
+    /// Link lifting the CAPTCHA asked after too many failed logins (see `auth/login.rs`)
+    UnlockAccount { token: String },
+}
+
+impl fmt::Debug for Template {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Template::ResetToken { .. } => f
+                .debug_struct("ResetToken")
+                .field("token", &Redacted)
+                .finish(),
+            Template::PasswordChanged { not_me } => f
+                .debug_struct("PasswordChanged")
+                .field("not_me", &redact(not_me))
+                .finish(),
+            Template::ContactApproval { account, .. } => f
+                .debug_struct("ContactApproval")
+                .field("account", account)
+                .field("token", &Redacted)
+                .finish(),
+            Template::ContactConfirmation { account, .. } => f
+                .debug_struct("ContactConfirmation")
+                .field("account", account)
+                .field("token", &Redacted)
+                .finish(),
+            Template::RecoveryStarted {
+                waiting_hours,
+                takeover,
+            } => f
+                .debug_struct("RecoveryStarted")
+                .field("waiting_hours", waiting_hours)
+                .field("takeover", &redact(takeover))
+                .finish(),
+            Template::InactivityWarning {
+                inactive_days,
+                grace_days,
+            } => f
+                .debug_struct("InactivityWarning")
+                .field("inactive_days", inactive_days)
+                .field("grace_days", grace_days)
+                .finish(),
+            Template::LoginConfirmation { .. } => f
+                .debug_struct("LoginConfirmation")
+                .field("token", &Redacted)
+                .finish(),
+            Template::VerifyEmail { .. } => f
+                .debug_struct("VerifyEmail")
+                .field("token", &Redacted)
+                .finish(),
+            Template::UnlockAccount { .. } => f
+                .debug_struct("UnlockAccount")
+                .field("token", &Redacted)
+                .finish(),
+        }
+    }
+}
+
+impl Template {
+    /// The subject of the email, the one set in the configuration (if any) or the default one
+    fn subject<'a>(&self, config: &'a MailConfig) -> &'a str {
+        let (custom, default) = match self {
+            Template::ResetToken { .. } => {
+                (&config.subjects.reset_token, "{product_name} Reset token")
+            }
+            Template::PasswordChanged { .. } => (
+                &config.subjects.password_changed,
+                "{product_name} Your password was changed",
+            ),
+            Template::ContactApproval { .. } => (
+                &config.subjects.contact_approval,
+                "{product_name} Approve the recovery of an account",
+            ),
+            Template::ContactConfirmation { .. } => (
+                &config.subjects.contact_confirmation,
+                "{product_name} Become a trusted contact",
+            ),
+            Template::RecoveryStarted { .. } => (
+                &config.subjects.recovery_started,
+                "{product_name} A recovery of your account was started",
+            ),
+            Template::InactivityWarning { .. } => (
+                &config.subjects.inactivity_warning,
+                "{product_name} Your account will be disabled",
+            ),
+            Template::LoginConfirmation { .. } => (
+                &config.subjects.login_confirmation,
+                "{product_name} Confirm your login",
+            ),
+            Template::VerifyEmail { .. } => (
+                &config.subjects.verify_email,
+                "{product_name} Verify your email",
+            ),
+            Template::UnlockAccount { .. } => (
+                &config.subjects.unlock_account,
+                "{product_name} Too many failed logins to your account",
+            ),
+        };
+
+        custom.as_deref().unwrap_or(default)
+    }
+
+    fn message(&self, config: &MailConfig) -> String {
+        match self {
+            Template::ResetToken { token } => format!("Here is your reset token: {}", token),
+            Template::PasswordChanged { not_me: None } => {
+                "The password of your account was just changed.\nIf you didn't do it, reset your password immediately."
+                    .to_string()
+            }
+            Template::PasswordChanged {
+                not_me: Some(token),
+            } => format!(
+                "The password of your account was just changed.\nIf you didn't do it, secure your account with:\n{}\nIt will be locked & you'll receive a reset token.",
+                not_me_link(token, config)
+            ),
+            Template::ContactApproval { account, token } => format!(
+                "{} asked you to help recover her/his account.\nIf she/he really asked you (e.g. by phone), approve the recovery with:\n{}\nOtherwise, ignore this email.",
+                account,
+                approval_link(token, config)
+            ),
+            Template::ContactConfirmation { account, token } => format!(
+                "{} chose you as a trusted contact: if she/he loses access to her/his account, you'll be asked to approve its recovery.\nIf you accept, confirm it with:\n{}\nOtherwise, ignore this email.",
+                account,
+                contact_confirmation_link(token, config)
+            ),
+            Template::RecoveryStarted {
+                waiting_hours,
+                takeover: None,
+            } => format!(
+                "A recovery of your account through your trusted contacts was just started.\nIf you didn't do it, login & cancel it within {} hours.",
+                waiting_hours
+            ),
+            Template::RecoveryStarted {
+                waiting_hours,
+                takeover: Some(token),
+            } => format!(
+                "A recovery of your account through your trusted contacts was just started.\nIf you didn't do it, login & cancel it within {} hours, or take it over with:\n{}\nIt will be replaced by a new one & you'll get its secret.",
+                waiting_hours,
+                takeover_link(token, config)
+            ),
+            Template::InactivityWarning {
+                inactive_days,
+                grace_days,
+            } => format!(
+                "You haven't logged in for {} days.\nLogin within {} days to keep your account, it will be disabled otherwise.",
+                inactive_days, grace_days
+            ),
+            Template::LoginConfirmation { token } => format!(
+                "Someone just logged in to your account in an unusual way.\nIf it's you, confirm the login with:\n{}\nOtherwise, ignore this email & change your password.",
+                confirmation_link(token, config)
+            ),
+            Template::VerifyEmail { token } => format!(
+                "Welcome! Please verify your email with:\n{}\nIf you didn't create an account, ignore this email.",
+                verification_link(token, config)
+            ),
+            Template::UnlockAccount { token } => format!(
+                "There were too many failed logins to your account, a CAPTCHA is now asked before each one.\nIf it was you, unlock your account with:\n{}\nOtherwise, change your password.",
+                unlock_link(token, config)
+            ),
+        }
+    }
+}
+
+/// Where a trusted contact approves a recovery, the `approval_url` variable if
+/// it's set, the command to run otherwise
+fn approval_link(token: &str, config: &MailConfig) -> String {
+    match config.variables.get("approval_url") {
+        Some(url) => format!("{}?token={}", url, token),
+        None => format!("secure-auth recovery approve {}", token),
+    }
+}
+
+/// Where a new trusted contact confirms she/he accepts the role, the
+/// `contact_confirmation_url` variable if it's set, the command to run otherwise
+fn contact_confirmation_link(token: &str, config: &MailConfig) -> String {
+    match config.variables.get("contact_confirmation_url") {
+        Some(url) => format!("{}?token={}", url, token),
+        None => format!("secure-auth recovery confirm {}", token),
+    }
+}
+
+/// Where a user takes over a recovery she/he didn't start, the `takeover_url`
+/// variable if it's set, the command to run otherwise
+fn takeover_link(token: &str, config: &MailConfig) -> String {
+    match config.variables.get("takeover_url") {
+        Some(url) => format!("{}?token={}", url, token),
+        None => format!("secure-auth recovery take-over {}", token),
+    }
+}
+
+/// Where a user reports she/he didn't make a change, the `not_me_url` variable
+/// if it's set, the command to run otherwise
+fn not_me_link(token: &str, config: &MailConfig) -> String {
+    match config.variables.get("not_me_url") {
+        Some(url) => format!("{}?token={}", url, token),
+        None => format!("secure-auth not-me {}", token),
+    }
+}
+
+/// Where a user confirms an unusual login, the `confirmation_url` variable if
+/// it's set, the token to enter in the login otherwise
+fn confirmation_link(token: &str, config: &MailConfig) -> String {
+    match config.variables.get("confirmation_url") {
+        Some(url) => format!("{}?token={}", url, token),
+        None => token.to_string(),
+    }
+}
+
+/// Where a new user verifies her/his email, the `verification_url` variable if
+/// it's set, the command to run otherwise
+fn verification_link(token: &str, config: &MailConfig) -> String {
+    match config.variables.get("verification_url") {
+        Some(url) => format!("{}?token={}", url, token),
+        None => format!("secure-auth verify-email {}", token),
+    }
+}
+
+/// Where a user unlocks her/his account, the `unlock_url` variable if it's
+/// set, the command to run otherwise
+fn unlock_link(token: &str, config: &MailConfig) -> String {
+    match config.variables.get("unlock_url") {
+        Some(url) => format!("{}?token={}", url, token),
+        None => format!("secure-auth unlock {}", token),
+    }
+}
+
+/// Variables used when the configuration doesn't set them
+const DEFAULT_VARIABLES: &[(&str, &str)] = &[("product_name", "Lab 02 - Auth")];
+
+/// Replace the `{name}` placeholders by the value of the variables
+/// The unknown placeholders are left as is, the first value of a variable wins
+///
+/// # Arguments
+///
+/// * `text` - the text containing the placeholders
+///
+/// * `variables` - the name & value of the variables
+///
+fn substitute(text: &str, variables: &[(&str, &str)]) -> String {
+    variables
+        .iter()
+        .fold(text.to_string(), |text, (name, value)| {
+            text.replace(&format!("{{{}}}", name), value)
+        })
+}
+
+/// Render an email template for a given user, customized by the configuration
+///
+/// # Arguments
+///
+/// * `template` - the template to render
+///
+/// * `u` - the user that will recieve the email
+///
+pub fn render(template: &Template, u: &User) -> Email {
+    render_with(template, u, &config::get().mail)
+}
+
+/// Render an email template for someone who may not be a user (e.g. a trusted contact)
+/// Note: no anti-phishing phrase can be injected
+///
+/// # Arguments
+///
+/// * `template` - the template to render
+///
+/// * `to` - the email address of the recipient
+///
+pub fn render_to(template: &Template, to: &str) -> Email {
+    render_email(template, to, None, &config::get().mail)
+}
+
+/// Render an email template for a given user
+///
+/// # Arguments
+///
+/// * `template` - the template to render
+///
+/// * `u` - the user that will recieve the email
+///
+/// * `config` - the variables & subjects set by the integrator
+///
+pub fn render_with(template: &Template, u: &User, config: &MailConfig) -> Email {
+    render_email(
+        template,
+        &u.get_email(),
+        u.get_anti_phishing_phrase(),
+        config,
+    )
+}
+
+fn render_email(
+    template: &Template,
+    email: &str,
+    anti_phishing_phrase: Option<String>,
+    config: &MailConfig,
+) -> Email {
+    let mut variables: Vec<(&str, &str)> = vec![("email", email)];
+    variables.extend(
+        config
+            .variables
+            .iter()
+            .map(|(n, v)| (n.as_str(), v.as_str())),
+    );
+    variables.extend(DEFAULT_VARIABLES);
+
+    let mut body = String::new();
+
+    if let Some(phrase) = anti_phishing_phrase {
+        body.push_str(&format!("Your anti-phishing phrase: {}\n\n", phrase));
+    }
+
+    body.push_str(&template.message(config));
+
+    // Note: only the texts of the system are substituted, not what the users entered
+    let mut signature = "\nKind regards,\n{product_name}".to_string();
+    if config.variables.contains_key("support_url") {
+        signature.push_str("\n\nNeed help? {support_url}");
+    }
+    body.push_str(&substitute(&signature, &variables));
+
+    Email {
+        to: email.to_string(),
+        subject: substitute(template.subject(config), &variables),
+        body,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_render_without_anti_phishing_phrase() {
+        let u = User::new("email@email.test", "passwd_hash");
+        let template = Template::ResetToken {
+            token: "token".to_string(),
+        };
+
+        let email = render(&template, &u);
+
+        assert_eq!(email.to, "email@email.test");
+        assert_eq!(email.subject, "Lab 02 - Auth Reset token");
+        assert!(email.body.contains("token"));
+        assert!(!email.body.contains("anti-phishing"));
+    }
+
+    #[test]
+    fn test_render_with_anti_phishing_phrase() {
+        let mut u = User::new("email@email.test", "passwd_hash");
+        u.set_anti_phishing_phrase(Some("purple elephant".to_string()));
+
+        let reset = render(
+            &Template::ResetToken {
+                token: "token".to_string(),
+            },
+            &u,
+        );
+        let alert = render(&Template::PasswordChanged { not_me: None }, &u);
+
+        assert!(reset
+            .body
+            .starts_with("Your anti-phishing phrase: purple elephant"));
+        assert!(alert
+            .body
+            .starts_with("Your anti-phishing phrase: purple elephant"));
+    }
+
+    #[test]
+    fn test_render_with_integrator_config() {
+        let mut u = User::new("email@email.test", "passwd_hash");
+        u.set_anti_phishing_phrase(Some("{support_url}".to_string()));
+        let mut config = MailConfig::default();
+        config.variables.insert(
+            "support_url".to_string(),
+            "https://support.example.com".to_string(),
+        );
+        config.subjects.password_changed = Some("[{product_name}] Alert for {email}".to_string());
+
+        let alert = render_with(&Template::PasswordChanged { not_me: None }, &u, &config);
+        let reset = render_with(
+            &Template::ResetToken {
+                token: "token".to_string(),
+            },
+            &u,
+            &config,
+        );
+
+        assert_eq!(alert.subject, "[Lab 02 - Auth] Alert for email@email.test");
+        assert_eq!(reset.subject, "Lab 02 - Auth Reset token");
+        assert!(alert
+            .body
+            .ends_with("Kind regards,\nLab 02 - Auth\n\nNeed help? https://support.example.com"));
+        // what the user entered is left as is
+        assert!(alert
+            .body
+            .starts_with("Your anti-phishing phrase: {support_url}"));
+    }
+
+    #[test]
+    fn test_render_contact_approval() {
+        let template = Template::ContactApproval {
+            account: "email@email.test".to_string(),
+            token: "token".to_string(),
+        };
+        let mut config = MailConfig::default();
+
+        let email = render_email(&template, "contact@email.test", None, &config);
+        assert_eq!(email.to, "contact@email.test");
+        assert!(email.body.contains("secure-auth recovery approve token"));
+
+        config.variables.insert(
+            "approval_url".to_string(),
+            "https://auth.example.com/approve".to_string(),
+        );
+        let email = render_email(&template, "contact@email.test", None, &config);
+        assert!(email
+            .body
+            .contains("https://auth.example.com/approve?token=token"));
+        assert!(!format!("{:?}", template).contains("token\""));
+    }
+
+    #[test]
+    fn test_render_contact_confirmation() {
+        let template = Template::ContactConfirmation {
+            account: "email@email.test".to_string(),
+            token: "token".to_string(),
+        };
+        let mut config = MailConfig::default();
+
+        let email = render_email(&template, "contact@email.test", None, &config);
+        assert_eq!(email.to, "contact@email.test");
+        assert!(email.body.starts_with("email@email.test chose you"));
+        assert!(email.body.contains("secure-auth recovery confirm token"));
+
+        config.variables.insert(
+            "contact_confirmation_url".to_string(),
+            "https://auth.example.com/confirm".to_string(),
+        );
+        let email = render_email(&template, "contact@email.test", None, &config);
+        assert!(email
+            .body
+            .contains("https://auth.example.com/confirm?token=token"));
+        assert!(!format!("{:?}", template).contains("token\""));
+    }
+
+    #[test]
+    fn test_render_recovery_takeover_link() {
+        let u = User::new("email@email.test", "passwd_hash");
+        let mut template = Template::RecoveryStarted {
+            waiting_hours: 48,
+            takeover: Some("token".to_string()),
+        };
+        let mut config = MailConfig::default();
+
+        let email = render_with(&template, &u, &config);
+        assert!(email.body.contains("within 48 hours"));
+        assert!(email.body.contains("secure-auth recovery take-over token"));
+        assert!(!format!("{:?}", template).contains("token\""));
+
+        config.variables.insert(
+            "takeover_url".to_string(),
+            "https://auth.example.com/take-over".to_string(),
+        );
+        let email = render_with(&template, &u, &config);
+        assert!(email
+            .body
+            .contains("https://auth.example.com/take-over?token=token"));
+
+        // without the action links, the owner can only cancel it
+        template = Template::RecoveryStarted {
+            waiting_hours: 48,
+            takeover: None,
+        };
+        let email = render_with(&template, &u, &config);
+        assert!(email.body.contains("login & cancel it within 48 hours."));
+        assert!(!email.body.contains("take-over"));
+    }
+
+    #[test]
+    fn test_render_not_me_link() {
+        let u = User::new("email@email.test", "passwd_hash");
+        let template = Template::PasswordChanged {
+            not_me: Some("token".to_string()),
+        };
+        let mut config = MailConfig::default();
+
+        let email = render_with(&template, &u, &config);
+        assert!(email.body.contains("secure-auth not-me token"));
+        assert!(
+            !render_with(&Template::PasswordChanged { not_me: None }, &u, &config)
+                .body
+                .contains("not-me")
+        );
+
+        config.variables.insert(
+            "not_me_url".to_string(),
+            "https://auth.example.com/not-me".to_string(),
+        );
+        let email = render_with(&template, &u, &config);
+        assert!(email
+            .body
+            .contains("https://auth.example.com/not-me?token=token"));
+        assert!(!format!("{:?}", template).contains("token\""));
+    }
+
+    #[test]
+    fn test_render_login_confirmation() {
+        let u = User::new("email@email.test", "passwd_hash");
+        let template = Template::LoginConfirmation {
+            token: "token".to_string(),
+        };
+        let mut config = MailConfig::default();
+
+        let email = render_with(&template, &u, &config);
+        assert_eq!(email.subject, "Lab 02 - Auth Confirm your login");
+        assert!(email.body.contains("\ntoken\n"));
+
+        config.variables.insert(
+            "confirmation_url".to_string(),
+            "https://auth.example.com/confirm".to_string(),
+        );
+        let email = render_with(&template, &u, &config);
+        assert!(email
+            .body
+            .contains("https://auth.example.com/confirm?token=token"));
+        assert!(!format!("{:?}", template).contains("token\""));
+    }
+
+    #[test]
+    fn test_render_verification_and_unlock_links() {
+        let u = User::new("email@email.test", "passwd_hash");
+        let verify = Template::VerifyEmail {
+            token: "token".to_string(),
+        };
+        let unlock = Template::UnlockAccount {
+            token: "token".to_string(),
+        };
+        let mut config = MailConfig::default();
+
+        let email = render_with(&verify, &u, &config);
+        assert_eq!(email.subject, "Lab 02 - Auth Verify your email");
+        assert!(email.body.contains("secure-auth verify-email token"));
+        assert!(render_with(&unlock, &u, &config)
+            .body
+            .contains("secure-auth unlock token"));
+
+        config.variables.insert(
+            "verification_url".to_string(),
+            "https://auth.example.com/verify".to_string(),
+        );
+        config.variables.insert(
+            "unlock_url".to_string(),
+            "https://auth.example.com/unlock".to_string(),
+        );
+        assert!(render_with(&verify, &u, &config)
+            .body
+            .contains("https://auth.example.com/verify?token=token"));
+        assert!(render_with(&unlock, &u, &config)
+            .body
+            .contains("https://auth.example.com/unlock?token=token"));
+        assert!(!format!("{:?} {:?}", verify, unlock).contains("token\""));
+    }
+}
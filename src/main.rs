@@ -18,25 +18,26 @@
  * Details on how they would be tested can be found in the README.md
  */
 
-#[macro_use]
-extern crate diesel;
-extern crate dotenv;
-
-mod auth;
+mod cli;
 mod command;
-mod db;
-mod errors;
+mod maintenance;
 mod process;
 mod user_input;
-mod utils;
-mod validation;
 
-use db::models::User;
+use clap::Parser;
+use secure_auth::config;
+use secure_auth::db::models::User;
+use secure_auth::output;
+use std::process::exit;
+
+use cli::{
+    Cli, Command, DbCommand, HoldCommand, PolicyCommand, PseudonymCommand, RecoveryCommand,
+    ResetCommand, ServiceCommand, TwoFaCommand,
+};
 
 fn login_screen() {
-    println!();
-    println!("Login screen");
-    println!("---------");
+    output::title("Login screen");
+    output::separator();
     println!("1. Login");
     println!("2. Register");
     println!("3. Reset password");
@@ -44,29 +45,122 @@ fn login_screen() {
 }
 
 fn user_profile_screen(user_email: &str) {
-    println!();
-    println!("{}' profile", user_email);
-    println!("---------");
+    output::title(&format!("{}' profile", user_email));
+    output::separator();
     println!("1. Enable two factor authentication");
     println!("2. Disable two factor authentication");
-    println!("3. Logout");
+    println!("3. Set anti-phishing phrase");
+    println!("4. Set language & timezone");
+    println!("5. Change two factor authentication secret");
+    println!("6. Security status");
+    println!("7. Trusted contacts");
+    println!("8. Who am I");
+    println!("9. Logout");
 }
 
 fn main() {
+    let cli = Cli::parse();
+    output::init(cli.no_color, cli.accessible);
+
+    // Note: `init` writes the configuration & `check` reports what's wrong with it
+    if !matches!(cli.command, Some(Command::Init) | Some(Command::Check)) {
+        if let Err(e) = config::try_get() {
+            output::error(&format!("{}: {}", config::path(), e));
+            exit(1);
+        }
+    }
+    // Note: the database commands look at (or fix) the database themselves
+    if !matches!(
+        cli.command,
+        Some(Command::Init) | Some(Command::Check) | Some(Command::Db { .. })
+    ) {
+        maintenance::startup_scan();
+    }
+
+    let success = match cli.command {
+        Some(Command::Init) => maintenance::init_process(),
+        Some(Command::Register { email }) => process::register_account_process(&email),
+        Some(Command::Login { email }) => process::check_login_process(&email),
+        Some(Command::TwoFa { command }) => match command {
+            TwoFaCommand::Enable { email } => process::start_2fa_process(&email),
+            TwoFaCommand::Confirm { email } => process::confirm_2fa_process(&email),
+        },
+        Some(Command::Reset { command }) => match command {
+            ResetCommand::Request { email } => process::request_reset_process(&email),
+            ResetCommand::Complete { email, token } => {
+                process::complete_reset_process(&email, &token)
+            }
+        },
+        Some(Command::Db { command }) => match command {
+            DbCommand::Migrate => maintenance::migrate_process(),
+            DbCommand::Doctor { repair } => maintenance::doctor_process(repair),
+            DbCommand::Seed { profile } => maintenance::seed_process(profile),
+        },
+        Some(Command::Hold { command }) => match command {
+            HoldCommand::List => maintenance::list_holds_process(),
+            HoldCommand::Release { email } => maintenance::release_hold_process(&email),
+        },
+        Some(Command::Service { command }) => match command {
+            ServiceCommand::Create { email, scopes } => {
+                maintenance::create_service_account_process(&email, &scopes)
+            }
+            ServiceCommand::RotateKey { email } => maintenance::rotate_api_key_process(&email),
+            ServiceCommand::AddCertificate { email, fingerprint } => {
+                maintenance::register_certificate_process(&email, &fingerprint)
+            }
+        },
+        Some(Command::Policy { command }) => match command {
+            PolicyCommand::Simulate { scenario } => maintenance::simulate_policy_process(&scenario),
+        },
+        Some(Command::Inactivity { dry_run }) => maintenance::inactivity_process(dry_run),
+        Some(Command::PasswordReport) => maintenance::password_report_process(),
+        Some(Command::NotMe { token }) => process::not_me_process(&token),
+        Some(Command::VerifyEmail { token }) => process::verify_email_process(&token),
+        Some(Command::Unlock { token }) => process::unlock_process(&token),
+        Some(Command::Recovery { command }) => match command {
+            RecoveryCommand::Start { email } => process::start_recovery_process(&email),
+            RecoveryCommand::Approve { token } => process::approve_recovery_process(&token),
+            RecoveryCommand::Confirm { token } => process::confirm_contact_process(&token),
+            RecoveryCommand::TakeOver { token } => process::take_over_recovery_process(&token),
+            RecoveryCommand::Complete { email } => process::complete_recovery_process(&email),
+        },
+        Some(Command::Erase { email, dry_run }) => maintenance::erase_process(&email, dry_run),
+        Some(Command::Pseudonym { command }) => match command {
+            PseudonymCommand::Show { email } => maintenance::show_pseudonym_process(&email),
+            PseudonymCommand::Resolve { pseudonym } => {
+                maintenance::resolve_pseudonym_process(&pseudonym)
+            }
+        },
+        Some(Command::Stats { json }) => maintenance::stats_process(json),
+        Some(Command::Check) => maintenance::check_process(),
+        Some(Command::VerifyAudit { expect }) => maintenance::verify_audit_process(expect.as_ref()),
+        None => return interactive(),
+    };
+
+    if !success {
+        exit(1);
+    }
+}
+
+fn interactive() {
     // Login screen
     let mut authenticated_user: User;
     loop {
         login_screen();
         match user_input::ask_for_login_screen_cmd() {
             command::LoginScreenCmd::Login => {
-                authenticated_user = process::login_process();
-                break;
+                if let Some(u) = process::login_process() {
+                    authenticated_user = u;
+                    break;
+                }
             }
             command::LoginScreenCmd::Register => {
                 process::registration_process();
                 // change?
-                authenticated_user = process::login_process();
-                break;
+                if let Some(u) = process::login_process() {
+                    authenticated_user = u;
+                    break;
+                }
             }
             command::LoginScreenCmd::Reset => process::reset_password_process(),
             command::LoginScreenCmd::Quit => return,
@@ -83,6 +177,22 @@ fn main() {
             command::ProfileScreenCmd::Disable2FA => {
                 process::disable_2fa_process(&mut authenticated_user)
             }
+            command::ProfileScreenCmd::SetAntiPhishingPhrase => {
+                process::set_anti_phishing_phrase_process(&mut authenticated_user)
+            }
+            command::ProfileScreenCmd::SetPreferences => {
+                process::set_preferences_process(&authenticated_user)
+            }
+            command::ProfileScreenCmd::Rotate2FA => {
+                process::rotate_2fa_process(&mut authenticated_user)
+            }
+            command::ProfileScreenCmd::SecurityStatus => {
+                process::security_status_process(&authenticated_user)
+            }
+            command::ProfileScreenCmd::TrustedContacts => {
+                process::trusted_contacts_process(&authenticated_user)
+            }
+            command::ProfileScreenCmd::WhoAmI => process::whoami_process(&authenticated_user),
             command::ProfileScreenCmd::Logout => break,
         }
     }
@@ -0,0 +1,709 @@
+/*!
+ * Maintenance commands run from the command line
+ *
+ * # Author
+ * Doran Kayoumi <doran.kayoumi@heig-vd.ch>
+ */
+
+use std::path::Path;
+use std::time::Duration;
+
+use secure_auth::audit::chain::{self, Head};
+use secure_auth::auth::simulation::{self, Scenario};
+use secure_auth::auth::{erasure, hold, inactivity, pseudonym, service};
+use secure_auth::config::{self, Config};
+use secure_auth::db::doctor::{self, Finding};
+use secure_auth::db::repository::{SQliteUserRepository, UserRepository};
+use secure_auth::db::seed::{self, Profile};
+use secure_auth::db::{self, migrations};
+use secure_auth::errors::{ConfigError, SetupError};
+use secure_auth::selfcheck::{self, Status};
+use secure_auth::{admin, output, setup, stats, utils};
+
+use crate::user_input;
+
+/// How long hashing a password should take once the hashing is calibrated
+const HASHING_TARGET: Duration = Duration::from_millis(500);
+
+/// First-run setup
+/// Creates the configuration file, migrates the database and creates the first administrator
+/// Returns whether the setup succeeded
+pub fn init_process() -> bool {
+    match _init_process() {
+        Ok(()) => {
+            println!("\nThe system is ready, run `secure-auth` to start it.");
+            true
+        }
+        Err(e) => {
+            output::error(&e.to_string());
+            false
+        }
+    }
+}
+
+fn _init_process() -> Result<(), SetupError> {
+    // Note: the answers are only saved once the administrator is created (see `setup.rs`)
+    println!("\nConfiguration");
+    let path = config::path();
+    let config = if !Path::new(&path).exists() || user_input::ask_for_overwrite_confirmation(&path)
+    {
+        let mut config = Config::default();
+        config.network.offline = user_input::ask_for_offline_mode();
+
+        println!("Calibrating the password hashing, this may take a few seconds...");
+        config.hashing = output::with_spinner("Calibrating the password hashing...", || {
+            utils::calibrate_hashing(HASHING_TARGET)
+        });
+        Some(config)
+    } else {
+        None
+    };
+    let hashing = config.as_ref().map_or(config::get().hashing, |c| c.hashing);
+
+    println!("\nDatabase");
+    let (url, new_url) = match db::try_database_url() {
+        Some(url) => (url, false),
+        None => (user_input::ask_for_database_path(), true),
+    };
+    db::run_migrations(&url)?;
+    println!("Database `{}` is up to date", url);
+
+    println!("\nAdministrator account");
+    let email = user_input::ask_for_email();
+    let passwd = user_input::ask_for_password_with_policy_check();
+    output::with_spinner("Creating the administrator...", || {
+        setup::create_admin(&url, &email, passwd.as_str(), &hashing)
+    })?;
+    println!("Administrator `{}` created", email);
+
+    if new_url {
+        setup::save_database_url(&url)?;
+    }
+    for variable in setup::generate_secrets()? {
+        println!("`{}` generated & saved in `.env`", variable);
+    }
+    if let Some(config) = config {
+        setup::write_config(&path, &config)?;
+        println!("Configuration written to `{}`", path);
+    }
+
+    Ok(())
+}
+
+/// Creates the database set by `DATABASE_URL` or applies its pending migrations
+/// Returns whether the database is up to date
+pub fn migrate_process() -> bool {
+    let url = match db::try_database_url() {
+        Some(url) => url,
+        None => {
+            output::error("DATABASE_URL must be set.");
+            return false;
+        }
+    };
+
+    match migrations::migrate(&url) {
+        Ok(applied) => {
+            for version in &applied {
+                println!("  applied migration {}", version);
+            }
+            println!("Database `{}` is up to date", url);
+            true
+        }
+        Err(e) => {
+            output::error(&e.to_string());
+            false
+        }
+    }
+}
+
+/// Self-test of the security configuration
+/// Prints the result of every check & how to fix the failed ones
+/// Returns whether no check failed (the warnings don't count)
+pub fn check_process() -> bool {
+    let checks = selfcheck::run();
+
+    for c in &checks {
+        let line = format!("{}: {}", c.name, c.detail);
+        match c.status {
+            Status::Pass => output::success(&format!("[ok] {}", line)),
+            Status::Warn => output::warning(&format!("[warn] {}", line)),
+            Status::Fail => output::error(&format!("[fail] {}", line)),
+        }
+        if let Some(fix) = &c.fix {
+            println!("    fix: {}", fix);
+        }
+    }
+
+    let failed = checks.iter().filter(|c| c.status == Status::Fail).count();
+    println!();
+    if failed == 0 {
+        output::success("The configuration is ready.");
+        true
+    } else {
+        println!("{} check(s) failed.", failed);
+        false
+    }
+}
+
+/// Verifies the chain of the tamper-evident audit log & prints every sign of tampering
+/// Returns whether the log is intact
+///
+/// # Arguments
+///
+/// * `expected` - the head printed by a previous verification (if any)
+///
+pub fn verify_audit_process(expected: Option<&Head>) -> bool {
+    let report = match chain::verify(expected) {
+        Ok(report) => report,
+        Err(e) => {
+            output::error(&e.to_string());
+            return false;
+        }
+    };
+
+    let head = match &report.head {
+        Some(head) => head,
+        None => {
+            output::warning(
+                "Nothing is chained yet, set `tamper_evident = true` in the `[audit]` section.",
+            );
+            return true;
+        }
+    };
+    if chain::secret().is_none() {
+        output::warning(&format!(
+            "{} isn't set, the checkpoints weren't checked.",
+            chain::SECRET_VARIABLE
+        ));
+    }
+    println!(
+        "{} chained entries, {} checkpoint(s) checked.",
+        report.entries, report.checkpoints
+    );
+
+    for t in &report.tampering {
+        output::error(&format!("[tampered] {}", t));
+    }
+    if !report.tampering.is_empty() {
+        return false;
+    }
+
+    output::success("The audit log is intact.");
+    // Note: only a head kept out of reach of the database reveals a later truncation
+    println!(
+        "Head: {} (keep it to check the next time with `--expect`)",
+        head
+    );
+
+    true
+}
+
+/// Checks the stored password hashes & 2FA secrets when the CLI starts (see `startup_scan`
+/// in the `[database]` section), the problems found are only reported
+pub fn startup_scan() {
+    if !config::get().database.startup_scan {
+        return;
+    }
+
+    // Note: a database that can't be read is reported by the command itself
+    if let Ok(findings) = doctor::scan_credentials(&db::database_url()) {
+        for f in &findings {
+            output::warning(&format!(
+                "[!] {} Run `secure-auth db doctor` for the details.",
+                f.problem
+            ));
+        }
+    }
+}
+
+/// Database doctor
+/// Prints every problem found in the database and, if asked, repairs the safe ones
+/// Returns whether the database is healthy
+///
+/// # Arguments
+///
+/// * `repair` - whether the safe repairs must be applied
+///
+pub fn doctor_process(repair: bool) -> bool {
+    let url = db::database_url();
+    println!("Checking {}", url);
+
+    if repair {
+        match doctor::repair(&url) {
+            Ok(n) => println!("{} row(s) repaired", n),
+            Err(e) => output::error(&e.to_string()),
+        }
+    }
+
+    let findings = match doctor::diagnose(&url) {
+        Ok(findings) => findings,
+        Err(e) => {
+            output::error(&e.to_string());
+            return false;
+        }
+    };
+
+    if findings.is_empty() {
+        output::success("No problem found.");
+        return true;
+    }
+
+    for f in &findings {
+        println!();
+        output::warning(&format!("[!] {}", f.problem));
+        if !f.users.is_empty() {
+            println!("    users: {}", users_of(f));
+        }
+        println!("    fix: {}", f.fix);
+    }
+
+    let repairable = findings.iter().filter(|f| f.repairable).count();
+    println!();
+    println!("{} problem(s) found.", findings.len());
+    if repairable > 0 {
+        println!(
+            "{} can be repaired with `secure-auth db doctor --repair`.",
+            repairable
+        );
+    }
+
+    false
+}
+
+/// Pseudonyms of the users concerned by a finding, their emails are never shown
+/// (`secure-auth pseudonym resolve` finds a user back, the resolution is audited)
+///
+/// # Arguments
+///
+/// * `finding` - the finding of the doctor
+///
+fn users_of(finding: &Finding) -> String {
+    let pseudonyms: Result<Vec<String>, _> = finding
+        .users
+        .iter()
+        .map(|u| pseudonym::pseudonym_of(*u))
+        .collect();
+
+    match pseudonyms {
+        Ok(p) => p.join(", "),
+        Err(_) => format!(
+            "set {} to list their pseudonyms",
+            pseudonym::SECRET_VARIABLE
+        ),
+    }
+}
+
+/// Database seeding
+/// Creates the users of a profile and prints their credentials
+/// Returns whether the seeding succeeded
+///
+/// # Arguments
+///
+/// * `profile` - the set of users to create
+///
+pub fn seed_process(profile: Profile) -> bool {
+    let repository = SQliteUserRepository::new();
+
+    let created = match output::with_spinner("Seeding the database...", || {
+        seed::seed(profile, &repository)
+    }) {
+        Ok(created) => created,
+        Err(e) => {
+            output::error(&e.to_string());
+            return false;
+        }
+    };
+    println!(
+        "{} user(s) created, the others already existed.",
+        created.len()
+    );
+    if created.is_empty() {
+        return true;
+    }
+
+    // Note: the credentials are generated for this database & aren't stored anywhere else
+    println!();
+    println!(
+        "Users of the `{}` profile (their credentials won't be shown again):",
+        profile
+    );
+    for c in &created {
+        println!("  {} / {} ({})", c.email, c.password, c.description);
+        if let Some(secret) = &c.secret_2fa {
+            println!("    2FA secret: {}", secret);
+        }
+    }
+
+    true
+}
+
+/// Warns & disables the accounts inactive for too long, or only lists them on a dry run
+/// Returns whether all the accounts could be checked
+pub fn inactivity_process(dry_run: bool) -> bool {
+    let report = match inactivity::disable_inactive_accounts(dry_run) {
+        Ok(report) => report,
+        Err(e) => {
+            output::error(&e.to_string());
+            return false;
+        }
+    };
+
+    let (warned, disabled) = if dry_run {
+        ("would be warned", "would be disabled")
+    } else {
+        ("warned", "disabled")
+    };
+    for email in &report.warned {
+        println!("  {} {}", email, warned);
+    }
+    for email in &report.disabled {
+        println!("  {} {} (placed on security hold)", email, disabled);
+    }
+    output::success(&format!(
+        "{} account(s) {}, {} {}.",
+        report.warned.len(),
+        warned,
+        report.disabled.len(),
+        disabled
+    ));
+    if dry_run {
+        println!("Dry run, nothing was changed.");
+    }
+
+    true
+}
+
+/// Shows the distribution of the strength scores of the chosen passwords
+/// Returns whether the statistics could be read
+pub fn password_report_process() -> bool {
+    let report = match stats::password_report() {
+        Ok(report) => report,
+        Err(e) => {
+            output::error(&e.to_string());
+            return false;
+        }
+    };
+
+    if report.is_empty() {
+        output::success("No password was chosen yet.");
+        return true;
+    }
+
+    for d in &report {
+        println!("Passwords chosen at {} ({}):", d.context, d.total());
+        for score in 0..=stats::MAX_SCORE {
+            println!(
+                "  score {}: {:>6} ({:.1}%)",
+                score,
+                d.counts[score],
+                d.share(score)
+            );
+        }
+    }
+    println!();
+    println!(
+        "Scores below 3 are considered too weak, many of them may call for a stricter policy."
+    );
+
+    true
+}
+
+/// Shows the statistics of the users & their activity, as a table or as JSON
+/// Returns whether the statistics could be gathered
+pub fn stats_process(json: bool) -> bool {
+    let stats = match admin::stats() {
+        Ok(stats) => stats,
+        Err(e) => {
+            output::error(&e.to_string());
+            return false;
+        }
+    };
+
+    if json {
+        match serde_json::to_string_pretty(&stats) {
+            Ok(json) => println!("{}", json),
+            Err(e) => {
+                output::error(&e.to_string());
+                return false;
+            }
+        }
+        return true;
+    }
+
+    println!("{:<20} {:>8}", "Users", stats.total_users);
+    println!(
+        "{:<20} {:>8}",
+        "Verified users",
+        stats
+            .verified_users
+            .map_or_else(|| "n/a".to_string(), |v| v.to_string())
+    );
+    println!(
+        "{:<20} {:>8} ({:.1}%)",
+        "Users with 2fa", stats.users_with_2fa, stats.twofa_adoption
+    );
+    println!("{:<20} {:>8}", "Service accounts", stats.service_accounts);
+    println!("{:<20} {:>8}", "Accounts on hold", stats.locked_accounts);
+    println!();
+    println!("{:<12} {:>8} {:>8}", "Day", "Logins", "Resets");
+    for (day, logins) in &stats.logins_per_day {
+        let resets = stats.reset_requests_per_day.get(day).copied().unwrap_or(0);
+        println!("{:<12} {:>8} {:>8}", day, logins, resets);
+    }
+
+    true
+}
+
+/// Lists the accounts on security hold
+/// Returns whether the accounts could be listed
+pub fn list_holds_process() -> bool {
+    let holds = match hold::list_holds() {
+        Ok(holds) => holds,
+        Err(e) => {
+            output::error(&e.to_string());
+            return false;
+        }
+    };
+
+    if holds.is_empty() {
+        output::success("No account on security hold.");
+        return true;
+    }
+
+    for h in &holds {
+        println!(
+            "  {} ({})",
+            h.email,
+            h.reason.as_deref().unwrap_or("unknown reason")
+        );
+    }
+    println!();
+    println!("{} account(s) on security hold.", holds.len());
+    println!("Once reviewed, release them with `secure-auth hold release <email>`.");
+
+    true
+}
+
+/// Releases the security hold of an account after an admin review
+/// Returns whether the hold was released
+///
+/// # Arguments
+///
+/// * `email` - the email of the account
+///
+pub fn release_hold_process(email: &str) -> bool {
+    match hold::release_hold(email) {
+        Ok(()) => {
+            output::success(&format!("The security hold of `{}` was released.", email));
+            true
+        }
+        Err(e) => {
+            output::error(&e.to_string());
+            false
+        }
+    }
+}
+
+/// Shows the API key of a service account, it can't be shown again
+fn show_api_key(email: &str, key: &str) {
+    println!("API key of `{}`: {}", email, key);
+    println!("Keep it somewhere safe, it can't be shown again.");
+}
+
+/// Creates a service account & shows its API key
+/// Returns whether the account was created
+///
+/// # Arguments
+///
+/// * `email` - the email identifying the account
+///
+/// * `scopes` - the scopes granted to the account
+///
+pub fn create_service_account_process(email: &str, scopes: &[String]) -> bool {
+    match service::create_service_account(email, scopes) {
+        Ok(key) => {
+            output::success(&format!("The service account `{}` was created.", email));
+            show_api_key(email, &key);
+            true
+        }
+        Err(e) => {
+            output::error(&e.to_string());
+            false
+        }
+    }
+}
+
+/// Replaces the API key of a service account & shows the new one
+/// Returns whether the key was replaced
+///
+/// # Arguments
+///
+/// * `email` - the email identifying the account
+///
+pub fn rotate_api_key_process(email: &str) -> bool {
+    match service::rotate_api_key(email) {
+        Ok(key) => {
+            output::success("The previous API key no longer works.");
+            show_api_key(email, &key);
+            true
+        }
+        Err(e) => {
+            output::error(&e.to_string());
+            false
+        }
+    }
+}
+
+/// Registers the client certificate of a service account
+/// Returns whether the certificate was registered
+///
+/// # Arguments
+///
+/// * `email` - the email identifying the account
+///
+/// * `fingerprint` - the SHA-256 fingerprint of the certificate
+///
+pub fn register_certificate_process(email: &str, fingerprint: &str) -> bool {
+    match service::register_certificate(email, fingerprint) {
+        Ok(()) => {
+            output::success(&format!(
+                "The certificate of `{}` was registered, it replaces the previous one.",
+                email
+            ));
+            true
+        }
+        Err(e) => {
+            output::error(&e.to_string());
+            false
+        }
+    }
+}
+
+/// Erases an account & prints the certificate of the erasure as JSON
+/// Returns whether the account could be erased
+///
+/// # Arguments
+///
+/// * `email` - the email of the account
+///
+/// * `dry_run` - only print the certificate, without erasing anything
+///
+pub fn erase_process(email: &str, dry_run: bool) -> bool {
+    let (certificate, completion) = match erasure::erase(email, dry_run) {
+        Ok(erased) => erased,
+        Err(e) => {
+            output::error(&e.to_string());
+            return false;
+        }
+    };
+
+    match serde_json::to_string_pretty(&certificate) {
+        Ok(json) => println!("{}", json),
+        Err(e) => output::error(&e.to_string()),
+    }
+    for warning in completion.warnings() {
+        output::warning(&warning.to_string());
+    }
+    if dry_run {
+        println!("Dry run, nothing was changed.");
+    } else {
+        output::success("The account has been erased.");
+    }
+
+    true
+}
+
+/// Shows the pseudonym of a user
+/// Returns whether it could be computed
+///
+/// # Arguments
+///
+/// * `email` - the email of the account
+///
+pub fn show_pseudonym_process(email: &str) -> bool {
+    let u = match SQliteUserRepository::new().get_user(email) {
+        Ok(u) => u,
+        Err(_) => {
+            output::error(&format!("There's no account `{}`.", email));
+            return false;
+        }
+    };
+
+    match pseudonym::pseudonym(&u) {
+        Ok(p) => {
+            println!("{}", p);
+            true
+        }
+        Err(e) => {
+            output::error(&format!(
+                "{} ({} must be set, at least {} characters)",
+                e,
+                pseudonym::SECRET_VARIABLE,
+                pseudonym::MIN_SECRET_LEN
+            ));
+            false
+        }
+    }
+}
+
+/// Shows the user behind a pseudonym
+/// Returns whether it's someone's
+///
+/// # Arguments
+///
+/// * `p` - the pseudonym to resolve
+///
+pub fn resolve_pseudonym_process(p: &str) -> bool {
+    match pseudonym::resolve(p) {
+        Ok(Some(u)) => {
+            println!("{}", u.get_email());
+            true
+        }
+        Ok(None) => {
+            output::error(&format!(
+                "`{}` isn't the pseudonym of any user (with the current key).",
+                p
+            ));
+            false
+        }
+        Err(e) => {
+            output::error(&e.to_string());
+            false
+        }
+    }
+}
+
+/// Runs a scenario of login attempts through the policies of the configuration & shows their decisions
+/// Returns whether the scenario could be run
+///
+/// # Arguments
+///
+/// * `scenario` - the TOML file describing the login attempts
+///
+pub fn simulate_policy_process(scenario: &Path) -> bool {
+    let scenario = match std::fs::read_to_string(scenario)
+        .map_err(|_| ConfigError::InvalidScenario)
+        .and_then(|s| Scenario::from_toml(&s))
+    {
+        Ok(scenario) => scenario,
+        Err(e) => {
+            output::error(&e.to_string());
+            return false;
+        }
+    };
+
+    println!("Policies of `{}`:", config::path());
+    let outcomes = simulation::simulate(&scenario, config::get());
+    for outcome in &outcomes {
+        println!("  {}", outcome);
+    }
+    println!();
+    println!(
+        "{} attempt(s), {} refused, {} account(s) placed on hold.",
+        outcomes.len(),
+        outcomes.iter().filter(|o| o.result.is_err()).count(),
+        outcomes.iter().filter(|o| o.hold.is_some()).count()
+    );
+
+    true
+}
@@ -0,0 +1,63 @@
+/*!
+ * Single entry point of every outbound network integration
+ *
+ * The system doesn't send any telemetry. The only traffic it generates comes
+ * from the integrations going through `outbound`, so setting `offline = true`
+ * in the `[network]` section of the configuration is enough to guarantee that
+ * nothing leaves the machine (e.g. for air-gapped deployments).
+ *
+ * # Author
+ * Doran Kayoumi <doran.kayoumi@heig-vd.ch>
+ */
+
+use crate::config::{self, NetworkConfig};
+
+/// Public function for running an outbound integration
+/// See `_outbound` for more info
+///
+pub fn outbound<T>(fallback: T, integration: impl FnOnce() -> T) -> T {
+    _outbound(&config::get().network, fallback, integration)
+}
+
+/// Run an outbound integration, unless the system is offline
+/// in which case the fallback is returned instead
+///
+/// # Arguments
+///
+/// * `config` - the network configuration
+///
+/// * `fallback` - what the integration "returns" when offline
+///
+/// * `integration` - the function reaching the network
+///
+fn _outbound<T>(config: &NetworkConfig, fallback: T, integration: impl FnOnce() -> T) -> T {
+    if config.offline {
+        return fallback;
+    }
+
+    integration()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_outbound_when_online() {
+        let config = NetworkConfig { offline: false };
+
+        assert_eq!(_outbound(&config, "fallback", || "online"), "online");
+    }
+
+    #[test]
+    fn test_outbound_when_offline() {
+        let config = NetworkConfig { offline: true };
+
+        assert_eq!(
+            _outbound(&config, "fallback", || panic!(
+                "shouldn't reach the network"
+            )),
+            "fallback"
+        );
+    }
+}
@@ -0,0 +1,242 @@
+/*!
+ * Presentation of the messages shown in the terminal
+ *
+ * Messages are styled according to their severity so the important ones
+ * (e.g. security alerts) stand out. The colors can be disabled with the
+ * `--no-color` flag or the `NO_COLOR` environment variable (see https://no-color.org),
+ * and the styles can be changed with `set_theme`.
+ *
+ * The slow tasks (e.g. hashing a password, sending an email) can be wrapped
+ * with `with_spinner` so the user knows something is happening.
+ *
+ * The accessible mode (`--accessible` flag) is meant for screen readers: no
+ * colors, no spinners, no decorations and the severity of the messages is
+ * spelled out.
+ *
+ * # Author
+ * Doran Kayoumi <doran.kayoumi@heig-vd.ch>
+ */
+
+use indicatif::ProgressBar;
+use lazy_static::lazy_static;
+use owo_colors::{OwoColorize, Style};
+use std::env;
+use std::io::{self, IsTerminal};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{PoisonError, RwLock};
+use std::time::Duration;
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum Severity {
+    /// Title of a screen or a process
+    Title,
+    Success,
+    Warning,
+    Error,
+}
+
+/// Style of the messages of each severity
+#[derive(Debug, Clone, Copy)]
+pub struct Theme {
+    pub title: Style,
+    pub success: Style,
+    pub warning: Style,
+    pub error: Style,
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Self {
+            title: Style::new().bold(),
+            success: Style::new().green(),
+            warning: Style::new().yellow(),
+            error: Style::new().red().bold(),
+        }
+    }
+}
+
+impl Severity {
+    /// Severity spelled out, used in the accessible mode
+    fn label(self) -> Option<&'static str> {
+        match self {
+            Severity::Title => None,
+            Severity::Success => Some("Success"),
+            Severity::Warning => Some("Warning"),
+            Severity::Error => Some("Error"),
+        }
+    }
+}
+
+impl Theme {
+    fn for_severity(&self, severity: Severity) -> Style {
+        match severity {
+            Severity::Title => self.title,
+            Severity::Success => self.success,
+            Severity::Warning => self.warning,
+            Severity::Error => self.error,
+        }
+    }
+}
+
+static COLORS: AtomicBool = AtomicBool::new(true);
+static ACCESSIBLE: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    static ref THEME: RwLock<Theme> = RwLock::new(Theme::default());
+}
+
+/// Set up the output, must be called before anything is printed
+///
+/// # Arguments
+///
+/// * `no_color` - whether the colors were disabled from the command line
+///
+/// * `accessible` - whether the accessible mode was enabled from the command line
+///
+pub fn init(no_color: bool, accessible: bool) {
+    // any non-empty value disables the colors
+    let no_color_env = env::var("NO_COLOR").is_ok_and(|v| !v.is_empty());
+
+    COLORS.store(!no_color && !no_color_env && !accessible, Ordering::Relaxed);
+    ACCESSIBLE.store(accessible, Ordering::Relaxed);
+}
+
+/// Whether the output is meant for screen readers
+pub fn is_accessible() -> bool {
+    ACCESSIBLE.load(Ordering::Relaxed)
+}
+
+/// Replace the styles used for the messages
+pub fn set_theme(theme: Theme) {
+    *THEME.write().unwrap_or_else(PoisonError::into_inner) = theme;
+}
+
+/// Render a message according to its severity
+///
+/// # Arguments
+///
+/// * `severity` - the severity of the message
+///
+/// * `msg` - the message to render
+///
+/// * `colors` - whether the message can be colored
+///
+/// * `accessible` - whether the severity must be spelled out
+///
+fn render(severity: Severity, msg: &str, colors: bool, accessible: bool) -> String {
+    if accessible {
+        if let Some(label) = severity.label() {
+            return format!("{}: {}", label, msg);
+        }
+    }
+
+    if !colors {
+        return msg.to_string();
+    }
+
+    msg.style(
+        THEME
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .for_severity(severity),
+    )
+    .to_string()
+}
+
+fn print(severity: Severity, msg: &str) {
+    println!(
+        "{}",
+        render(
+            severity,
+            msg,
+            COLORS.load(Ordering::Relaxed),
+            is_accessible()
+        )
+    );
+}
+
+/// Print the title of a screen or a process, preceded by an empty line
+pub fn title(msg: &str) {
+    println!();
+    print(Severity::Title, msg);
+}
+
+/// Print a line separating a title from what follows
+/// Nothing is printed in the accessible mode, screen readers would read it out
+pub fn separator() {
+    if !is_accessible() {
+        println!("---------");
+    }
+}
+
+pub fn success(msg: &str) {
+    print(Severity::Success, msg);
+}
+
+pub fn warning(msg: &str) {
+    print(Severity::Warning, msg);
+}
+
+pub fn error(msg: &str) {
+    print(Severity::Error, msg);
+}
+
+/// Run a slow task while a spinner is shown
+/// The spinner is only shown in interactive terminals, so piped or redirected
+/// output isn't cluttered with it, and never in the accessible mode
+///
+/// # Arguments
+///
+/// * `msg` - what is being done
+///
+/// * `task` - the task to run, it must not ask anything to the user
+///
+pub fn with_spinner<T>(msg: &str, task: impl FnOnce() -> T) -> T {
+    if is_accessible() || !io::stderr().is_terminal() {
+        return task();
+    }
+
+    let spinner = ProgressBar::new_spinner().with_message(msg.to_string());
+    spinner.enable_steady_tick(Duration::from_millis(100));
+    let res = task();
+    spinner.finish_and_clear();
+
+    res
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_render_without_colors() {
+        assert_eq!(render(Severity::Error, "error", false, false), "error");
+    }
+
+    #[test]
+    fn test_render_accessible() {
+        assert_eq!(
+            render(Severity::Error, "failed", true, true),
+            "Error: failed"
+        );
+        assert_eq!(
+            render(Severity::Warning, "careful", false, true),
+            "Warning: careful"
+        );
+        assert_eq!(render(Severity::Title, "Login", false, true), "Login");
+    }
+
+    #[test]
+    fn test_render_with_colors() {
+        let rendered = render(Severity::Error, "error", true, false);
+
+        assert!(rendered.contains("error"));
+        assert!(rendered.starts_with("\u{1b}["));
+        assert_ne!(rendered, render(Severity::Success, "error", true, false));
+    }
+
+    #[test]
+    fn test_with_spinner_returns_the_result() {
+        assert_eq!(with_spinner("working", || 42), 42);
+    }
+}
@@ -0,0 +1,16 @@
+/*!
+ * The pure logic of the authentication system: TOTP math, password hashes &
+ * token formats
+ *
+ * Nothing here touches the storage, the mailers or the configuration, so it
+ * builds without the `native` feature (e.g. for wasm32) & browser or edge code
+ * can reuse the exact same verification logic. The rest of the library relies
+ * on it, along with `validation` which is portable too.
+ *
+ * # Author
+ * Doran Kayoumi <doran.kayoumi@heig-vd.ch>
+ */
+
+pub mod otp;
+pub mod password;
+pub mod token;
@@ -0,0 +1,161 @@
+/*!
+ * HOTP (RFC 4226) & TOTP (RFC 6238) codes, with HMAC-SHA1 like the authenticator apps
+ *
+ * # Author
+ * Doran Kayoumi <doran.kayoumi@heig-vd.ch>
+ */
+
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+
+/// Decodes a base32 secret (as shown to the users), the case & padding don't matter
+///
+/// # Arguments
+///
+/// * `secret` - the secret to decode
+///
+pub fn decode_secret(secret: &str) -> Option<Vec<u8>> {
+    let secret = secret.trim_end_matches('=').to_ascii_uppercase();
+    base32::decode(base32::Alphabet::RFC4648 { padding: false }, &secret)
+}
+
+/// Computes the HOTP code of a counter, zero-padded to the number of digits
+///
+/// # Arguments
+///
+/// * `key` - the decoded secret
+///
+/// * `counter` - the counter (for TOTP, the number of periods since the epoch)
+///
+/// * `digits` - the number of digits of the code
+///
+pub fn hotp(key: &[u8], counter: u64, digits: u32) -> Option<String> {
+    let mut mac = Hmac::<Sha1>::new_from_slice(key).ok()?;
+    mac.update(&counter.to_be_bytes());
+    let digest = mac.finalize().into_bytes();
+
+    // dynamic truncation, see RFC 4226 section 5.3
+    let offset = (digest[digest.len() - 1] & 0xf) as usize;
+    let bin = u32::from_be_bytes([
+        digest[offset] & 0x7f,
+        digest[offset + 1],
+        digest[offset + 2],
+        digest[offset + 3],
+    ]);
+    let code = bin % 10u32.checked_pow(digits)?;
+
+    Some(format!("{:0width$}", code, width = digits as usize))
+}
+
+/// Checks that a TOTP code is valid at a given time
+/// Every period of the tolerance is checked, whether the code matched early or not
+///
+/// # Arguments
+///
+/// * `secret` - the base32 secret under which the code was generated
+///
+/// * `code` - the code to check
+///
+/// * `at` - the unix timestamp at which the code is checked
+///
+/// * `period` - the number of seconds during which a code is valid
+///
+/// * `digits` - the number of digits of the codes
+///
+/// * `tolerance` - the number of periods before & after the current one in which a code is still accepted
+///
+pub fn verify_totp(
+    secret: &str,
+    code: &str,
+    at: u64,
+    period: u64,
+    digits: u32,
+    tolerance: u64,
+) -> bool {
+    let key = match decode_secret(secret) {
+        Some(key) if period > 0 => key,
+        _ => return false,
+    };
+    let current = at / period;
+
+    (current.saturating_sub(tolerance)..=current.saturating_add(tolerance))
+        .fold(false, |matched, counter| {
+            matched | (hotp(&key, counter, digits).as_deref() == Some(code))
+        })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rstest::rstest;
+
+    /// Secret of the test vectors of the RFCs, "12345678901234567890" in base32
+    const RFC_SECRET: &str = "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ";
+
+    #[test]
+    fn test_decode_secret() {
+        let key = b"12345678901234567890".to_vec();
+
+        assert_eq!(decode_secret(RFC_SECRET), Some(key.clone()));
+        assert_eq!(decode_secret(&RFC_SECRET.to_lowercase()), Some(key));
+        assert_eq!(decode_secret("not base32!"), None);
+    }
+
+    // RFC 4226, appendix D
+    #[rstest(
+        counter,
+        expected,
+        case(0, "755224"),
+        case(1, "287082"),
+        case(2, "359152"),
+        case(3, "969429"),
+        case(4, "338314"),
+        case(5, "254676"),
+        case(6, "287922"),
+        case(7, "162583"),
+        case(8, "399871"),
+        case(9, "520489"),
+        ::trace
+    )]
+    fn test_hotp_vectors(counter: u64, expected: &str) {
+        let key = decode_secret(RFC_SECRET).unwrap();
+
+        assert_eq!(hotp(&key, counter, 6).unwrap(), expected);
+    }
+
+    // RFC 6238, appendix B (SHA-1)
+    #[rstest(
+        at,
+        expected,
+        case(59, "94287082"),
+        case(1111111109, "07081804"),
+        case(1111111111, "14050471"),
+        case(1234567890, "89005924"),
+        case(2000000000, "69279037"),
+        case(20000000000, "65353130"),
+        ::trace
+    )]
+    fn test_totp_vectors(at: u64, expected: &str) {
+        assert!(verify_totp(RFC_SECRET, expected, at, 30, 8, 0));
+        // the 6 digits codes are the last digits of the 8 digits ones
+        assert!(verify_totp(RFC_SECRET, &expected[2..], at, 30, 6, 0));
+        assert!(!verify_totp(RFC_SECRET, "00000000", at, 30, 8, 0));
+    }
+
+    #[test]
+    fn test_totp_tolerance() {
+        let at = 1234567890;
+
+        assert!(verify_totp(RFC_SECRET, "005924", at + 60, 30, 6, 2));
+        assert!(!verify_totp(RFC_SECRET, "005924", at + 90, 30, 6, 2));
+        assert!(verify_totp(RFC_SECRET, "005924", at - 60, 30, 6, 2));
+        assert!(!verify_totp(RFC_SECRET, "005924", at - 90, 30, 6, 2));
+    }
+
+    #[test]
+    fn test_invalid_parameters() {
+        assert!(!verify_totp("not base32!", "000000", 59, 30, 6, 1));
+        assert!(!verify_totp(RFC_SECRET, "287082", 59, 0, 6, 1));
+        assert_eq!(hotp(b"key", 0, 12), None);
+    }
+}
@@ -0,0 +1,97 @@
+/*!
+ * argon2id password hashes, in the PHC format libsodium stores them in
+ *
+ * libsodium hands the hashes out padded with NULs to 128 bytes, the padding is
+ * ignored so the hashes can be checked whether they were trimmed or not.
+ *
+ * # Author
+ * Doran Kayoumi <doran.kayoumi@heig-vd.ch>
+ */
+
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::{Algorithm, Argon2, Params, Version};
+
+/// Verify that a password matches a stored hash
+/// The variant, version & cost are the ones of the hash
+///
+/// # Arguments
+///
+/// * `passwd` - the password that needs to match
+///
+/// * `stored` - the hash that the password needs to match
+///
+pub fn verify_password(passwd: &str, stored: &str) -> bool {
+    match PasswordHash::new(stored.trim_end_matches('\0')) {
+        Ok(hash) => Argon2::default()
+            .verify_password(passwd.as_bytes(), &hash)
+            .is_ok(),
+        Err(_) => false,
+    }
+}
+
+/// Hash a password using argon2id, returns `None` if the cost or salt are invalid
+/// Note: the salt is given since there's no portable source of randomness, it must be random
+///
+/// # Arguments
+///
+/// * `passwd` - the password to hash
+///
+/// * `salt` - the salt, at least 8 bytes
+///
+/// * `passes` - the number of passes over the memory
+///
+/// * `mem_kib` - the memory used, in KiB
+///
+pub fn hash_password(passwd: &str, salt: &[u8], passes: u32, mem_kib: u32) -> Option<String> {
+    let params = Params::new(mem_kib, passes, 1, None).ok()?;
+    let salt = SaltString::encode_b64(salt).ok()?;
+
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password(passwd.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Hash stored by the system (see `utils.rs`), made by libsodium
+    const STORED_HASH: &str =
+        "$argon2id$v=19$m=8,t=1,p=1$8OM7NwMU/MQlVtMm96ve8w$ERkqYI4/ut20Wwf1HK1XTn8awLMM7oxxTm098pOzbco";
+
+    #[test]
+    fn test_verify_stored_hash() {
+        let padded = format!("{:\0<128}", STORED_HASH);
+
+        assert!(verify_password("correct horse battery staple", STORED_HASH));
+        assert!(verify_password("correct horse battery staple", &padded));
+        assert!(!verify_password("correct horse battery stapler", &padded));
+        assert!(!verify_password(
+            "correct horse battery staple",
+            "not a hash"
+        ));
+    }
+
+    #[test]
+    fn test_hash_matches_libsodium() {
+        let mut salt = [0u8; 16];
+        PasswordHash::new(STORED_HASH)
+            .unwrap()
+            .salt
+            .unwrap()
+            .decode_b64(&mut salt)
+            .unwrap();
+
+        assert_eq!(
+            hash_password("correct horse battery staple", &salt, 1, 8).as_deref(),
+            Some(STORED_HASH)
+        );
+    }
+
+    #[test]
+    fn test_invalid_cost() {
+        assert_eq!(hash_password("passwd", &[0; 16], 0, 8), None);
+        assert_eq!(hash_password("passwd", &[0; 4], 1, 8), None);
+    }
+}
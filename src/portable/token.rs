@@ -0,0 +1,166 @@
+/*!
+ * Formats of the random tokens & recovery codes, and how they're hashed for storage
+ *
+ * # Author
+ * Doran Kayoumi <doran.kayoumi@heig-vd.ch>
+ */
+
+use sha2::{Digest, Sha256};
+
+/// Number of characters of the random tokens (e.g. the reset tokens)
+pub const TOKEN_LEN: usize = 30;
+/// Characters of the recovery codes, the ambiguous ones (e.g. 0 & O) were left out
+pub const CODE_ALPHABET: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+/// Number of characters of a recovery code (without the separator)
+pub const CODE_LEN: usize = 10;
+
+/// Check if a random token has the right format, i.e. `TOKEN_LEN` alphanumeric characters
+///
+/// # Arguments
+///
+/// * `token` - the token to check
+///
+pub fn is_token_well_formed(token: &str) -> bool {
+    token.len() == TOKEN_LEN && token.chars().all(|c| c.is_ascii_alphanumeric())
+}
+
+/// Splits the characters of a recovery code in two groups, e.g. `ABCDE-FGHJK`
+///
+/// # Arguments
+///
+/// * `chars` - the `CODE_LEN` characters of the code
+///
+pub fn format_code(chars: &str) -> String {
+    let mid = chars
+        .char_indices()
+        .nth(chars.chars().count() / 2)
+        .map_or(chars.len(), |(i, _)| i);
+    format!("{}-{}", &chars[..mid], &chars[mid..])
+}
+
+/// Normalizes a recovery code as entered by the user (the case & separators don't matter)
+///
+/// # Arguments
+///
+/// * `code` - the code to normalize
+///
+pub fn normalize_code(code: &str) -> String {
+    code.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_uppercase())
+        .collect()
+}
+
+/// Check if a recovery code, once normalized, could have been generated
+///
+/// # Arguments
+///
+/// * `code` - the code to check
+///
+pub fn is_code_well_formed(code: &str) -> bool {
+    let normalized = normalize_code(code);
+    normalized.len() == CODE_LEN && normalized.bytes().all(|c| CODE_ALPHABET.contains(&c))
+}
+
+/// Hashes a token for storage, in hex (SHA-256)
+/// Note: the tokens are random & long enough, a fast hash is sufficient
+///
+/// # Arguments
+///
+/// * `token` - the token to hash
+///
+pub fn hash(token: &str) -> String {
+    Sha256::digest(token.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Compare two hashes in a time that doesn't depend on where they differ
+/// Note: a comparison stopping at the first difference tells how much of a guess is right
+///
+/// # Arguments
+///
+/// * `a` - the first hash
+///
+/// * `b` - the second hash
+///
+pub fn hashes_match(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |diff, (x, y)| diff | (x ^ y))
+            == 0
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rstest::rstest;
+
+    #[rstest(
+        token,
+        expected,
+        case("aB3dE6gH9jK2mN5pQ8sT1vW4yZ7bC0", true),
+        case("aB3dE6gH9jK2mN5pQ8sT1vW4yZ7bC", false),
+        case("aB3dE6gH9jK2mN5pQ8sT1vW4yZ7bC0d", false),
+        case("aB3dE6gH9jK2mN5pQ8sT1vW4yZ7b-0", false),
+        case("", false),
+        ::trace
+    )]
+    fn test_is_token_well_formed(token: &str, expected: bool) {
+        assert_eq!(is_token_well_formed(token), expected);
+    }
+
+    #[rstest(
+        code,
+        expected,
+        case("ABCDE-FGHJK", true),
+        case("abcde fghjk", true),
+        case("ABCDEFGHJK", true),
+        // ambiguous characters
+        case("ABCDE-FGH0K", false),
+        case("ABCDE-FGHJ", false),
+        case("", false),
+        ::trace
+    )]
+    fn test_is_code_well_formed(code: &str, expected: bool) {
+        assert_eq!(is_code_well_formed(code), expected);
+    }
+
+    #[test]
+    fn test_format_code() {
+        assert_eq!(format_code("ABCDEFGHJK"), "ABCDE-FGHJK");
+        assert_eq!(format_code("ÄBCDÉ"), "ÄB-CDÉ");
+        assert_eq!(format_code(""), "-");
+    }
+
+    // FIPS 180-2, appendix B.1 & the empty message
+    #[rstest(
+        token,
+        expected,
+        case(
+            "abc",
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        ),
+        case("", "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"),
+        ::trace
+    )]
+    fn test_hash(token: &str, expected: &str) {
+        assert_eq!(hash(token), expected);
+    }
+
+    #[rstest(
+        a,
+        b,
+        expected,
+        case("ba7816bf", "ba7816bf", true),
+        case("ba7816bf", "ba7816be", false),
+        case("ba7816bf", "ba7816", false),
+        case("", "", true),
+        ::trace
+    )]
+    fn test_hashes_match(a: &str, b: &str, expected: bool) {
+        assert_eq!(hashes_match(a, b), expected);
+    }
+}
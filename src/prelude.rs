@@ -0,0 +1,53 @@
+/*!
+ * The items most applications embedding the library need, in one import
+ *
+ * ```no_run
+ * use secure_auth::prelude::*;
+ *
+ * let email = Email::parse("alice@example.com").ok_or(AuthError::InvalidEmail)?;
+ * let passwd = Password::parse("password").ok_or(AuthError::LoginError)?;
+ *
+ * match begin_login(&email, &passwd) {
+ *     Ok(LoginOutcome::Authenticated(login)) => println!("welcome {}", login.get_user().get_email()),
+ *     Ok(LoginOutcome::TwoFactorRequired(_)) => println!("enter your code"),
+ *     Err(e) => println!("{}", e),
+ * }
+ * # Ok::<(), AuthError>(())
+ * ```
+ *
+ * See `examples/` for complete integrations.
+ *
+ * # Author
+ * Doran Kayoumi <doran.kayoumi@heig-vd.ch>
+ */
+
+pub use crate::auth::auth_service::AuthService;
+pub use crate::auth::binding::ClientInfo;
+pub use crate::auth::factor::{FactorRegistry, SecondFactor};
+#[cfg(feature = "async")]
+pub use crate::auth::login::login_async;
+pub use crate::auth::login::{
+    begin_login, begin_login_with, complete_2fa, complete_2fa_with, find_challenge,
+    AuthenticatedLogin, CompletedLogin, LoginOutcome, TwoFactorChallenge,
+};
+#[cfg(feature = "async")]
+pub use crate::auth::register::register_async;
+pub use crate::auth::register::{register, register_with_repository};
+pub use crate::auth::reset::{
+    change_password, change_password_with_repository, check_token, check_token_with_repository,
+    generate_reset_token, generate_reset_token_with_repository,
+};
+#[cfg(feature = "async")]
+pub use crate::auth::reset::{
+    change_password_async, check_token_async, generate_reset_token_async,
+};
+pub use crate::auth::risk::Signals;
+pub use crate::auth::throttle::CaptchaProvider;
+pub use crate::auth::validator::{RegistrationValidator, ValidatorChain};
+pub use crate::config::Config;
+pub use crate::db::models::User;
+#[cfg(feature = "async")]
+pub use crate::db::repository::{AsyncUserRepository, SpawnBlocking};
+pub use crate::db::repository::{InMemoryUserRepository, UserRepository};
+pub use crate::errors::AuthError;
+pub use crate::validation::{Email, Password};
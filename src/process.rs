@@ -5,77 +5,215 @@
  * Doran Kayoumi <doran.kayoumi@heig-vd.ch>
  */
 
-use crate::auth::{login, register, reset, twofa};
-use crate::db::models::User;
-use crate::db::repository::{SQliteUserRepository, UserRepository};
-use crate::errors::AuthError;
+use chrono::{Duration, Utc};
+use secure_auth::audit::{self, AuditEvent};
+use secure_auth::auth::auth_service::AuthService;
+use secure_auth::auth::login::{LoginOutcome, TwoFactorChallenge};
+use secure_auth::auth::validator::{ConsentValidator, ReservedEmailValidator, ValidatorChain};
+use secure_auth::auth::{
+    banner, contacts, factor, hold, login, maintenance, not_me, recovery, register, reset, status,
+    step_up, tos, twofa,
+};
+use secure_auth::db::models::{User, UserChangeset};
+use secure_auth::db::repository::{
+    AuditRepository, SQliteAuditRepository, SQliteUserRepository, UserRepository,
+};
+use secure_auth::db::timeout::TimeoutRepository;
+use secure_auth::errors::{AuthError, Completion};
+use secure_auth::i18n::{self, tr, Text, LOCALE_ATTRIBUTE, TIMEZONE_ATTRIBUTE};
+use secure_auth::validation::{Email, Password};
+use secure_auth::{config, network, output};
+
 use crate::user_input;
-use crate::utils;
+
+/// Name of the system shown in the authentication apps
+const ISSUER: &str = "Lab 02 - Authentication";
 
 /// Login process
+/// No user is returned if she/he refuses the terms of service
 ///
-pub fn login_process() -> User {
-    println!("\nLogin:");
+pub fn login_process() -> Option<User> {
+    output::title("Login:");
+    if let Some(banner) = banner::pre_login_banner() {
+        println!("{}", banner);
+        output::separator();
+    }
     loop {
         let email = user_input::ask_for_email();
         let passwd = user_input::ask_for_password();
 
-        let u = login::login(&email, &passwd);
-        if let Err(e) = u {
-            println!("{}", e);
-            continue;
+        let outcome = output::with_spinner("Logging in...", || {
+            login_password(&passwd).and_then(|passwd| login::begin_login(&email, &passwd))
+        });
+        // an unusual login goes on once the user confirms it from her/his mailbox
+        let outcome = match outcome {
+            Err(AuthError::EmailConfirmationRequired) => {
+                output::warning(&AuthError::EmailConfirmationRequired.to_string());
+                let token = user_input::ask_for_confirmation_token();
+                output::with_spinner("Logging in...", || login::confirm_login(&token))
+            }
+            outcome => outcome,
+        };
+        let mut u = match outcome {
+            Ok(LoginOutcome::Authenticated(login)) => login.into_user(),
+            Ok(LoginOutcome::TwoFactorRequired(challenge)) => {
+                match confirm_second_factor(&challenge) {
+                    Some(u) => u,
+                    None => continue,
+                }
+            }
+            Err(AuthError::CaptchaRequired) => {
+                output::warning(&AuthError::CaptchaRequired.to_string());
+                solve_captcha(&email);
+                continue;
+            }
+            Err(e) => {
+                output::error(&e.to_string());
+                continue;
+            }
+        };
+
+        // the terms of service changed since the user last accepted them
+        if tos::requires_acceptance(&u) && !confirm_tos_acceptance(&mut u) {
+            return None;
         }
 
-        let u = u.unwrap();
-        if u.is_2fa_enabled() {
-            let secret = u.get_secret_2fa().unwrap();
-            confirm_2fa_code(&secret);
+        // from now on, talk to the user in her/his language
+        let repository = SQliteUserRepository::new();
+        i18n::set_current(Some(i18n::user_preferences(u.get_id(), &repository)));
+
+        if let Some(motd) = banner::motd() {
+            output::separator();
+            println!("{}", motd);
         }
 
-        return u;
+        return Some(u);
     }
 }
 
-/// Registration process
+/// The flows over the users of the database, each operation limited to the
+/// `operation_timeout_ms` of the configuration (see `db/timeout.rs`)
+fn service() -> AuthService<TimeoutRepository<SQliteUserRepository>> {
+    AuthService::new(TimeoutRepository::from_config(
+        SQliteUserRepository::new(),
+        &config::get().database,
+    ))
+}
+
+/// Public function for the registration process
+/// See `_registration_process` for more info
 ///
 pub fn registration_process() {
-    println!("\nRegistration:");
-    loop {
-        let email = user_input::ask_for_email();
-        let passwd = user_input::ask_for_password_with_policy_check();
-
-        let u = register::register(&email, &passwd);
-        if let Err(e) = u {
-            println!("{}", e);
-            continue;
-        }
-
-        break;
+    if !check_writable() {
+        return;
     }
+    let service = service();
+    _registration_process(&service)
 }
 
 /// Public function for the password reset process
 /// See `_reset_password_process` for more info
 ///
 pub fn reset_password_process() {
-    let repository = SQliteUserRepository {};
-    _reset_password_process(&repository)
+    if !check_writable() {
+        return;
+    }
+    let service = service();
+    _reset_password_process(&service)
 }
 
 /// Public function for the 2FA enable process
 /// See `enable_2fa_process` for more info
 ///
 pub fn enable_2fa_process(u: &mut User) {
-    let repository = SQliteUserRepository {};
-    _enable_2fa_process(u, &repository)
+    if !check_writable() {
+        return;
+    }
+    let repository = SQliteUserRepository::new();
+    let audit_repository = SQliteAuditRepository::new();
+    _enable_2fa_process(u, &repository, &audit_repository)
 }
 
 /// Public function for the 2FA disable process
 /// See `disable_2fa_process` for more info
 ///
 pub fn disable_2fa_process(u: &mut User) {
-    let repository = SQliteUserRepository {};
-    _disable_2fa_process(u, &repository)
+    if !check_writable() {
+        return;
+    }
+    let repository = SQliteUserRepository::new();
+    let audit_repository = SQliteAuditRepository::new();
+    _disable_2fa_process(u, &repository, &audit_repository)
+}
+
+/// Public function for the anti-phishing phrase process
+/// See `_set_anti_phishing_phrase_process` for more info
+///
+pub fn set_anti_phishing_phrase_process(u: &mut User) {
+    if !check_writable() {
+        return;
+    }
+    let repository = SQliteUserRepository::new();
+    _set_anti_phishing_phrase_process(u, &repository)
+}
+
+/// Checks the registrations must pass: the reserved emails & the age confirmation
+///
+/// # Arguments
+///
+/// * `adult` - whether the user confirmed her/his age
+///
+fn registration_validators(adult: bool) -> ValidatorChain {
+    ValidatorChain::new()
+        .with(ReservedEmailValidator::new(
+            &config::get().registration.reserved,
+        ))
+        .with(ConsentValidator::new(move |_| adult))
+}
+
+/// Registration process
+///
+/// # Note
+/// Since this function requires to interact with the db via a `UserRepository` the implementation was
+/// made private so we don't need to worry about it when calling the function
+///
+/// # Arguments
+///
+/// * `service` - the flows over the user repository to interact with
+///
+fn _registration_process<R: UserRepository>(service: &AuthService<R>) {
+    output::title("Registration:");
+    let email = loop {
+        let email = user_input::ask_for_email();
+        let passwd = user_input::ask_for_password_with_policy_check();
+
+        if !user_input::ask_for_tos_acceptance(tos::TOS_VERSION) {
+            output::error(&AuthError::TosNotAccepted.to_string());
+            return;
+        }
+
+        // Note: asked beforehand, the spinner can't be shown while asking something
+        let adult = user_input::ask_for_age_confirmation();
+        let validators = registration_validators(adult);
+
+        let u = output::with_spinner("Creating your account...", || {
+            service.register(&email, &passwd, &validators)
+        });
+        if let Err(e) = u {
+            output::error(&e.to_string());
+            continue;
+        }
+
+        break email;
+    };
+
+    // record the version of the terms the user accepted while registering
+    // Note: if this fails, the user will be asked to accept them on her/his first login
+    if let Ok(mut u) = service.repository().get_user(&email) {
+        if let Err(e) = tos::accept_terms(&mut u) {
+            output::error(&e.to_string());
+        }
+    }
 }
 
 /// Password reset process
@@ -86,63 +224,85 @@ pub fn disable_2fa_process(u: &mut User) {
 ///
 /// # Arguments
 ///
-/// * `repository` - the user repository to interact with
+/// * `service` - the flows over the user repository to interact with
 ///
-fn _reset_password_process(repository: &dyn UserRepository) {
-    println!("\nPassword reset:");
+fn _reset_password_process<R: UserRepository>(service: &AuthService<R>) {
+    output::title("Password reset:");
     let email = user_input::ask_for_email();
 
     println!("In case a user with that data exists in our database, you'll recieve the token to reset your password");
 
-    // try and generate a reset token for the given email
-    if let Err(_) = reset::generate_reset_token(&email) {
+    // try and generate & send a reset token for the given email
+    if output::with_spinner("Sending the reset token...", || {
+        service.generate_reset_token(&email)
+    })
+    .is_err()
+    {
         // exit the process without informing the user to avoid any forms of attacks
         return;
     }
 
-    reset::send_reset_token(&email);
+    // Note: the user exists, otherwise no token would have been sent
+    let preferences = service
+        .repository()
+        .get_user(&email)
+        .map(|u| i18n::user_preferences(u.get_id(), service.repository()))
+        .unwrap_or_default();
+    let expiry = Utc::now() + Duration::minutes(reset::CODE_VALIDITY_MIN);
+    println!(
+        "{}",
+        Text::TokenExpiry.localized_with(preferences.locale, &preferences.format_datetime(&expiry))
+    );
 
     // ideally all of the following would be handeled somewhere else
-    // and the `send_reset_token` would send an email with a url that hte user needs to click to follow th reset instructions
+    // and the `generate_reset_token` would send an email with a url that hte user needs to click to follow th reset instructions
 
-    loop {
+    let token = loop {
         let input_token = user_input::ask_for_reset_token();
 
-        if let Err(e) = reset::check_token(&email, &input_token) {
-            println!("{}", e);
+        if let Err(e) = service.check_token(&email, &input_token) {
+            output::error(&e.to_string());
 
             match e {
-                AuthError::ExpiredToken => return,
                 AuthError::TokenMismatch => continue,
-                AuthError::ResetError => return,
-                _ => panic!("Unexpected return value."),
+                _ => return,
             }
         }
 
-        break;
-    }
+        break input_token;
+    };
 
     // get the user from the db
-    let u = repository.get_user(&email);
-    if let Err(e) = u {
-        // something bad happened (e.g. the db is down)
-        // Note: The problem can't come from the non existance of the user
-        //       because `generate_reset_token` generates a token only if the user exists.
-        //       hence the panic.
-        panic!(e);
-    }
-    let u = u.unwrap();
+    // Note: The problem can't come from the non existance of the user
+    //       because `generate_reset_token` generates a token only if the user exists,
+    //       something bad happened (e.g. the db is down)
+    let u = match service.repository().get_user(&email) {
+        Ok(u) => u,
+        Err(e) => {
+            output::error(&e.to_string());
+            return;
+        }
+    };
+    let on_hold = hold::is_on_hold(u.get_id(), service.repository()).unwrap_or(false);
 
-    if u.is_2fa_enabled() {
+    if let Some(secret) = u.get_secret_2fa() {
         println!("Confirm your identity:");
-        // we can safely get the users 2FA secret
-        let secret = u.get_secret_2fa().unwrap();
-        confirm_2fa_code(&secret);
+        if on_hold {
+            // the reset token & the 2FA code are enough to recover the account
+            release_hold_with_recovery(&email, &token);
+        } else {
+            confirm_2fa_code(&secret);
+        }
+    } else if on_hold {
+        output::warning(&AuthError::AccountOnHold.to_string());
     }
 
     let passwd = user_input::ask_for_password_with_policy_check();
-    if let Err(e) = reset::change_password(&email, &passwd) {
-        println!("{}", e);
+    match output::with_spinner("Changing your password...", || {
+        service.change_password(&email, &token, &passwd)
+    }) {
+        Ok(completion) => show_completion("Your password was changed.", &completion),
+        Err(e) => output::error(&e.to_string()),
     }
 }
 
@@ -155,42 +315,54 @@ fn _reset_password_process(repository: &dyn UserRepository) {
 ///
 /// * `repository` - the user repository to interact with
 ///
-fn _enable_2fa_process(u: &mut User, repository: &dyn UserRepository) {
-    println!("\nEnabling Two-factor authentication");
+/// * `audit_repository` - the audit repository where the activation is logged
+///
+fn _enable_2fa_process(
+    u: &mut User,
+    repository: &dyn UserRepository,
+    audit_repository: &dyn AuditRepository,
+) {
+    output::title("Enabling Two-factor authentication");
     // quick check that the user doesn't already have 2fa activated
     // you never know...
     if u.is_2fa_enabled() {
-        println!("Two-factor authentication already enabled");
+        output::warning("Two-factor authentication already enabled");
         return;
     }
 
     // Before adding the 2FA, confirm the users identity
-    // by asking for hes/his password
     println!("Confirm your identity:");
-    confirm_identity_with_password(&u.get_password());
+    confirm_step_up(u);
 
     // generate the 2FA secret & the QR code so the user can add the secret
     // to her/his 2FA authentication app
-    let secret = twofa::generate_secret();
-    let qr_url = twofa::generate_qr(&secret, &u.get_email(), "Lab 02 - Authentication");
-    println!(
-        "Scan the following QR code with your favorite Authentication app: {}\n",
-        qr_url
-    );
+    // Note: the QR code is rendered by an online service, which isn't available offline
+    let enrollment = twofa::enroll(&u.get_email(), ISSUER);
+    show_enrollment(&enrollment);
 
     // Ask the user to input a authentication code
     // to confirm she/he correctly setup the 2FA
     println!("Confirm 2FA setup:");
-    confirm_2fa_code(&secret);
+    confirm_2fa_code(&enrollment.secret);
 
     // update the database with the new secret
-    u.set_secret_2fa(Some(secret));
-    if let Err(_) = repository.update_user(&u) {
-        println!("Two-factor authentication failed.");
-
-        // just to be safe, revert changes
-        u.set_secret_2fa(None);
+    let changes = UserChangeset::new().secret_2fa(Some(enrollment.secret.clone()));
+    if repository.patch_user(u.get_id(), &changes).is_err() {
+        output::error("Two-factor authentication failed.");
+        return;
     }
+
+    u.set_secret_2fa(Some(enrollment.secret));
+    // Note: the 2FA is enabled either way, only its date is missing from the security status
+    let _ = audit::record(
+        audit_repository,
+        Some(u.get_id()),
+        AuditEvent::TwoFaEnabled,
+        None,
+    );
+    output::success("Two-factor authentication enabled.");
+
+    regenerate_recovery_codes(u);
 }
 
 /// 2FA diable process
@@ -202,37 +374,896 @@ fn _enable_2fa_process(u: &mut User, repository: &dyn UserRepository) {
 ///
 /// * `repository` - the user repository to interact with
 ///
-fn _disable_2fa_process(u: &mut User, repository: &dyn UserRepository) {
-    println!("\nDisabling Two-factor authentication");
+/// * `audit_repository` - the audit repository where the deactivation is logged
+///
+fn _disable_2fa_process(
+    u: &mut User,
+    repository: &dyn UserRepository,
+    audit_repository: &dyn AuditRepository,
+) {
+    output::title("Disabling Two-factor authentication");
     // quick check that the user doesn't already have 2fa activated
     // you never know...
     if !u.is_2fa_enabled() {
-        println!("Two-factor authentication is already disabled");
+        output::warning("Two-factor authentication is already disabled");
         return;
     }
 
     // Before touching the 2FA, confirm the users identity
-    // by asking for hers/his password
+    // Note: the password & a code of the current device, since the 2FA is enabled
     println!("Confirm your identity:");
-    confirm_identity_with_password(&u.get_password());
+    confirm_step_up(u);
 
-    // Ask the user to input a authentication code
-    // to confirm she/he correctly setup the 2FA
-    let secret = u.get_secret_2fa().unwrap(); // we can safely get the users 2FA secret
-    confirm_2fa_code(&secret);
-
-    // NOTE: For some reason this doesn't remove the secret from the DB
-    // TODO: Fix
     // update the database with the changes
+    // Note: a secret waiting to replace the current one is dropped as well
+    let changes = UserChangeset::new()
+        .secret_2fa(None)
+        .pending_secret_2fa(None);
+    if repository.patch_user(u.get_id(), &changes).is_err() {
+        output::error("Two-factor authentication failed.");
+        return;
+    }
+
     u.set_secret_2fa(None);
-    if let Err(_) = repository.update_user(&u) {
-        println!("Two-factor authentication failed.");
+    u.set_pending_secret_2fa(None);
+    let _ = audit::record(
+        audit_repository,
+        Some(u.get_id()),
+        AuditEvent::TwoFaDisabled,
+        None,
+    );
+    // Note: without 2FA, the codes are useless anyway
+    let _ = recovery::revoke_codes(u);
+    output::success("Two-factor authentication disabled.");
+}
+
+/// 2FA secret rotation process
+/// Replaces the 2FA secret without disabling the 2FA, the current secret stays
+/// valid until the new one is confirmed
+///
+/// # Arguments
+///
+/// * `u` - the user changing her/his secret
+///
+pub fn rotate_2fa_process(u: &mut User) {
+    if !check_writable() {
+        return;
+    }
+    output::title("Changing the two-factor authentication secret");
+    if !u.is_2fa_enabled() {
+        output::warning("Two-factor authentication isn't enabled");
+        return;
+    }
+
+    // Before touching the 2FA, confirm the users identity
+    // Note: the password & a code of the current device, since the 2FA is enabled
+    println!("Confirm your identity:");
+    confirm_step_up(u);
+
+    let enrollment = match twofa::start_rotation(u, ISSUER) {
+        Ok(enrollment) => enrollment,
+        Err(e) => {
+            output::error(&e.to_string());
+            return;
+        }
+    };
+    show_enrollment(&enrollment);
+
+    // the new secret is only activated once the new device generates valid codes
+    println!("Confirm the new 2FA setup:");
+    loop {
+        let auth_code = user_input::ask_for_authentication_code();
+        match twofa::confirm_rotation(u, &auth_code) {
+            Ok(()) => break,
+            Err(AuthError::InvalidAuthenticationCode) => {
+                output::error(&AuthError::InvalidAuthenticationCode.to_string());
+                continue;
+            }
+            Err(e) => {
+                output::error(&e.to_string());
+                return;
+            }
+        }
+    }
+
+    output::success("Two-factor authentication secret changed.");
+}
+
+/// Anti-phishing phrase process
+/// # Note
+/// Since this function requires to interact with the db via a `UserRepository` the implementation was
+/// made private so we don't need to worry about it when calling the function
+///
+/// # Arguments
+///
+/// * `repository` - the user repository to interact with
+///
+fn _set_anti_phishing_phrase_process(u: &mut User, repository: &dyn UserRepository) {
+    output::title("Setting the anti-phishing phrase");
+    println!(
+        "This phrase will be shown in every email we send you, emails without it aren't from us."
+    );
+
+    // Before touching the phrase, confirm the users identity
+    println!("Confirm your identity:");
+    confirm_step_up(u);
+
+    let phrase = user_input::ask_for_anti_phishing_phrase();
+
+    let changes = UserChangeset::new().anti_phishing_phrase(Some(phrase.clone()));
+    if repository.patch_user(u.get_id(), &changes).is_err() {
+        output::error("Unable to set the anti-phishing phrase.");
+        return;
+    }
+
+    u.set_anti_phishing_phrase(Some(phrase));
+    output::success("Anti-phishing phrase set.");
+}
+
+/// Public function for the preferences process
+/// See `_set_preferences_process` for more info
+///
+pub fn set_preferences_process(u: &User) {
+    if !check_writable() {
+        return;
+    }
+    let repository = SQliteUserRepository::new();
+    _set_preferences_process(u, &repository)
+}
+
+/// Preferences process
+/// Sets the language & timezone in which the system talks to the user
+/// # Note
+/// Since this function requires to interact with the db via a `UserRepository` the implementation was
+/// made private so we don't need to worry about it when calling the function
+///
+/// # Arguments
+///
+/// * `repository` - the user repository to interact with
+///
+fn _set_preferences_process(u: &User, repository: &dyn UserRepository) {
+    output::title("Setting the language & timezone");
+
+    let locale = user_input::ask_for_locale();
+    let timezone = user_input::ask_for_timezone();
+
+    if repository
+        .set_attribute(u.get_id(), LOCALE_ATTRIBUTE, &locale.to_string())
+        .and_then(|_| repository.set_attribute(u.get_id(), TIMEZONE_ATTRIBUTE, timezone.name()))
+        .is_err()
+    {
+        output::error("Unable to set the language & timezone.");
+        return;
+    }
+
+    i18n::set_current(Some(i18n::Preferences { locale, timezone }));
+    output::success("Language & timezone set.");
+}
+
+/// Security status process
+/// Shows an overview of the security settings of the user
+///
+/// # Arguments
+///
+/// * `u` - the user to show the overview of
+///
+pub fn security_status_process(u: &User) {
+    output::title("Security status");
+    let status = status::security_status(u);
+
+    if !status.twofa_enabled {
+        output::warning("Two-factor authentication: disabled");
+        return;
+    }
+
+    match status.twofa_enabled_at {
+        Some(dt) => println!(
+            "Two-factor authentication: enabled since {}",
+            i18n::current().format_datetime(&dt)
+        ),
+        None => println!("Two-factor authentication: enabled"),
+    }
+    if status.twofa_rotation_pending {
+        output::warning(
+            "A new two-factor authentication secret waits to be confirmed, the current one is still in use",
+        );
+    }
 
-        // just to be safe, revert changes
-        u.set_secret_2fa(Some(secret));
+    if let Some(left) = status.recovery_codes_left {
+        println!("Unused recovery codes: {}", left);
+        if left < recovery::LOW_CODE_THRESHOLD
+            && user_input::ask_for_recovery_codes_regeneration(left)
+        {
+            regenerate_recovery_codes(u);
+        }
     }
 }
 
+/// Shows what the system knows about the user, e.g. to check how the policies apply to her/him
+///
+/// # Arguments
+///
+/// * `u` - the user logged in
+///
+pub fn whoami_process(u: &User) {
+    output::title("Who am I");
+    let identity = status::identity(u);
+    let yes_no = |b: bool| if b { "yes" } else { "no" };
+
+    println!("Id: {}", identity.id);
+    println!("Email: {}", identity.email);
+    println!("Account: {}", identity.kind);
+    println!("Role: {}", identity.role.as_deref().unwrap_or("none"));
+    println!("Email verified: {}", yes_no(identity.email_verified));
+    println!(
+        "Two-factor authentication: {}",
+        if identity.twofa_enabled {
+            "enabled"
+        } else {
+            "disabled"
+        }
+    );
+    println!(
+        "Restricted login hours: {}",
+        yes_no(identity.restricted_hours)
+    );
+    if let Some(reason) = &identity.hold {
+        output::warning(&format!("Security hold: {}", reason));
+    }
+    if let Some(dt) = identity.last_login_at {
+        println!("Last login: {}", i18n::current().format_datetime(&dt));
+    }
+    // Note: there's nothing else to show, see `status::Identity`
+    println!("Session: none, the login lasts until you logout");
+    println!("Permissions: none are managed by the system");
+}
+
+/// Generates a new set of recovery codes & shows them to the user
+///
+/// # Arguments
+///
+/// * `u` - the user owning the codes
+///
+fn regenerate_recovery_codes(u: &User) {
+    let codes = match recovery::regenerate_codes(u) {
+        Ok(codes) => codes,
+        Err(e) => {
+            output::error(&e.to_string());
+            return;
+        }
+    };
+
+    println!("Your recovery codes, each of them can be used once to login without your device:");
+    for code in &codes {
+        println!("    {}", code);
+    }
+    output::warning("Keep them somewhere safe, they won't be shown again.");
+}
+
+/// Shows the trusted contacts of the user & lets her/him add or remove them
+/// If a recovery of the account was started, the user can cancel it
+///
+/// # Arguments
+///
+/// * `u` - the user owning the contacts
+///
+pub fn trusted_contacts_process(u: &User) {
+    output::title("Trusted contacts");
+
+    if let Ok(progress) = contacts::recovery_status(u) {
+        println!(
+            "Recovery approved by {}/{} contact(s), possible from {}",
+            progress.approved,
+            progress.required,
+            i18n::current().format_datetime(&progress.ready_at)
+        );
+        if user_input::ask_for_recovery_cancellation() {
+            match contacts::cancel_recovery(u) {
+                Ok(()) => output::success("The recovery was cancelled."),
+                Err(e) => output::error(&e.to_string()),
+            }
+        }
+    }
+
+    loop {
+        let list = match contacts::list_contacts(u) {
+            Ok(list) => list,
+            Err(e) => {
+                output::error(&e.to_string());
+                return;
+            }
+        };
+        let confirmed = contacts::list_confirmed_contacts(u).unwrap_or_default();
+        if list.is_empty() {
+            println!("No trusted contact yet.");
+        }
+        for c in &list {
+            if confirmed.contains(c) {
+                println!("    {}", c);
+            } else {
+                println!("    {} (not confirmed yet)", c);
+            }
+        }
+        println!(
+            "Up to {} trusted contacts can approve the recovery of your account if you lose access to it, once they confirmed they accept the role.",
+            contacts::MAX_CONTACTS
+        );
+
+        let contact = match user_input::ask_for_contact() {
+            Some(contact) => contact,
+            None => return,
+        };
+        let res = if list.contains(&contact) {
+            contacts::remove_contact(u, &contact)
+        } else {
+            contacts::add_contact(u, &contact)
+        };
+        if let Err(e) = res {
+            output::error(&e.to_string());
+        }
+    }
+}
+
+/// Starts the recovery of an account through its trusted contacts
+/// Returns whether the recovery could be started
+///
+/// # Arguments
+///
+/// * `email` - the email of the account
+///
+pub fn start_recovery_process(email: &str) -> bool {
+    if !check_writable() {
+        return false;
+    }
+    match contacts::start_recovery(email) {
+        Ok(secret) => {
+            // Note: the same message is shown whether the account exists or not
+            output::success(
+                "If the account has trusted contacts, they were asked to approve its recovery.",
+            );
+            println!(
+                "Keep this secret, it's asked to complete the recovery: {}",
+                secret
+            );
+            true
+        }
+        Err(e) => {
+            output::error(&e.to_string());
+            false
+        }
+    }
+}
+
+/// Locks the account whose owner reported a change she/he didn't make & sends her/him a reset token
+/// Returns whether the account was secured
+///
+/// # Arguments
+///
+/// * `token` - the token of the "this wasn't me" link
+///
+pub fn not_me_process(token: &str) -> bool {
+    match not_me::report(token) {
+        Ok(()) => {
+            output::success("Your account is secured, check your emails for a reset token.");
+            true
+        }
+        Err(e) => {
+            output::error(&e.to_string());
+            false
+        }
+    }
+}
+
+/// Verifies the email of a user with the token sent at her/his registration
+/// Returns whether the email was verified
+///
+/// # Arguments
+///
+/// * `token` - the token of the verification link
+///
+pub fn verify_email_process(token: &str) -> bool {
+    match register::verify_email(token) {
+        Ok(()) => {
+            output::success("Thank you, your email is verified.");
+            true
+        }
+        Err(e) => {
+            output::error(&e.to_string());
+            false
+        }
+    }
+}
+
+/// Unlocks the account of a user with the token sent after too many failed logins
+/// Returns whether the account was unlocked
+///
+/// # Arguments
+///
+/// * `token` - the token of the unlock link
+///
+pub fn unlock_process(token: &str) -> bool {
+    match login::unlock(token) {
+        Ok(()) => {
+            output::success("Your account is unlocked, you can login without a CAPTCHA.");
+            true
+        }
+        Err(e) => {
+            output::error(&e.to_string());
+            false
+        }
+    }
+}
+
+/// Approves a recovery with the token a trusted contact received
+/// Returns whether the recovery was approved
+///
+/// # Arguments
+///
+/// * `token` - the approval token
+///
+pub fn approve_recovery_process(token: &str) -> bool {
+    if !check_writable() {
+        return false;
+    }
+    match contacts::approve_recovery(token) {
+        Ok(()) => {
+            output::success("Thank you, the recovery was approved.");
+            true
+        }
+        Err(e) => {
+            output::error(&e.to_string());
+            false
+        }
+    }
+}
+
+/// Confirms a trusted contact with the token she/he received
+/// Returns whether the contact was confirmed
+///
+/// # Arguments
+///
+/// * `token` - the confirmation token
+///
+pub fn confirm_contact_process(token: &str) -> bool {
+    if !check_writable() {
+        return false;
+    }
+    match contacts::confirm_contact(token) {
+        Ok(()) => {
+            output::success("Thank you, you're now a trusted contact of the account.");
+            true
+        }
+        Err(e) => {
+            output::error(&e.to_string());
+            false
+        }
+    }
+}
+
+/// Replaces a recovery the owner of the account didn't start with a new one
+/// Returns whether the recovery was taken over
+///
+/// # Arguments
+///
+/// * `token` - the token of the link of the alert
+///
+pub fn take_over_recovery_process(token: &str) -> bool {
+    if !check_writable() {
+        return false;
+    }
+    match contacts::take_over_recovery(token) {
+        Ok(secret) => {
+            output::success(
+                "The recovery was replaced, your trusted contacts were asked to approve the new one.",
+            );
+            println!(
+                "Keep this secret, it's asked to complete the recovery: {}",
+                secret
+            );
+            true
+        }
+        Err(e) => {
+            output::error(&e.to_string());
+            false
+        }
+    }
+}
+
+/// Completes the recovery of an account by asking for a new password
+/// Returns whether the account was recovered
+///
+/// # Arguments
+///
+/// * `email` - the email of the account
+///
+pub fn complete_recovery_process(email: &str) -> bool {
+    if !check_writable() {
+        return false;
+    }
+    let secret = user_input::ask_for_recovery_secret();
+    let passwd = user_input::ask_for_password_with_policy_check();
+    match contacts::complete_recovery(email, &secret, &passwd) {
+        Ok(()) => {
+            output::success("Your account was recovered, two factor authentication is disabled.");
+            true
+        }
+        Err(e) => {
+            output::error(&e.to_string());
+            false
+        }
+    }
+}
+
+/// Creates an account without going through the menus (see `secure-auth register`)
+/// Returns whether the account was created
+///
+/// # Arguments
+///
+/// * `email` - the email of the account
+///
+pub fn register_account_process(email: &str) -> bool {
+    if !check_writable() {
+        return false;
+    }
+    let email = match parse_email(email) {
+        Some(email) => email,
+        None => return false,
+    };
+    let passwd = user_input::ask_for_password_with_policy_check();
+    if !user_input::ask_for_tos_acceptance(tos::TOS_VERSION) {
+        output::error(&AuthError::TosNotAccepted.to_string());
+        return false;
+    }
+    let adult = user_input::ask_for_age_confirmation();
+    let validators = registration_validators(adult);
+
+    if let Err(e) = output::with_spinner("Creating your account...", || {
+        register::register(&email, &passwd, &validators)
+    }) {
+        output::error(&e.to_string());
+        return false;
+    }
+
+    // Note: if this fails, the user will be asked to accept the terms on her/his first login
+    if let Ok(mut u) = SQliteUserRepository::new().get_user(&email) {
+        if let Err(e) = tos::accept_terms(&mut u) {
+            output::error(&e.to_string());
+        }
+    }
+    output::success("Your account was created.");
+
+    true
+}
+
+/// Checks the credentials of an account, & its second factor if it's set,
+/// without going through the menus (see `secure-auth login`)
+/// Returns whether the login succeeded
+///
+/// # Arguments
+///
+/// * `email` - the email of the account
+///
+pub fn check_login_process(email: &str) -> bool {
+    let email = match parse_email(email) {
+        Some(email) => email,
+        None => return false,
+    };
+    let passwd = user_input::ask_for_password();
+
+    let outcome = output::with_spinner("Logging in...", || {
+        login_password(&passwd).and_then(|passwd| login::begin_login(&email, &passwd))
+    });
+    let u = match outcome {
+        Ok(LoginOutcome::Authenticated(login)) => login.into_user(),
+        Ok(LoginOutcome::TwoFactorRequired(challenge)) => {
+            let code = user_input::ask_for_authentication_code();
+            let chosen = chosen_factor(&challenge, &code);
+            match login::complete_2fa(&challenge, chosen, &code) {
+                Ok(completed) => completed.into_user(),
+                Err(e) => {
+                    output::error(&e.to_string());
+                    return false;
+                }
+            }
+        }
+        Err(e) => {
+            output::error(&e.to_string());
+            return false;
+        }
+    };
+    output::success(&format!("Logged in as {}.", u.get_email()));
+
+    true
+}
+
+/// Checks the password of a user before a change of her/his 2FA
+///
+/// # Arguments
+///
+/// * `email` - the email of the user
+///
+fn authenticate_with_password(email: &str) -> Option<User> {
+    let email = parse_email(email)?;
+    let passwd = user_input::ask_for_password();
+
+    match output::with_spinner("Checking your password...", || {
+        login_password(&passwd).and_then(|passwd| login::login(&email, &passwd))
+    }) {
+        Ok(u) => Some(u),
+        Err(e) => {
+            output::error(&e.to_string());
+            None
+        }
+    }
+}
+
+/// Starts enabling the 2FA of an account without going through the menus (see `secure-auth 2fa enable`)
+/// The new secret stays pending until it's confirmed with `secure-auth 2fa confirm`
+/// Returns whether the secret was generated
+///
+/// # Arguments
+///
+/// * `email` - the email of the account
+///
+pub fn start_2fa_process(email: &str) -> bool {
+    if !check_writable() {
+        return false;
+    }
+    let mut u = match authenticate_with_password(email) {
+        Some(u) => u,
+        None => return false,
+    };
+
+    match twofa::start_enrollment(&mut u, ISSUER) {
+        Ok(enrollment) => {
+            show_enrollment(&enrollment);
+            println!("Then confirm it with `secure-auth 2fa confirm {}`.", email);
+            true
+        }
+        Err(e) => {
+            output::error(&e.to_string());
+            false
+        }
+    }
+}
+
+/// Enables the 2FA of an account once a code of its pending secret is entered (see `secure-auth 2fa confirm`)
+/// Returns whether the 2FA was enabled
+///
+/// # Arguments
+///
+/// * `email` - the email of the account
+///
+pub fn confirm_2fa_process(email: &str) -> bool {
+    let mut u = match authenticate_with_password(email) {
+        Some(u) => u,
+        None => return false,
+    };
+    let was_enabled = u.is_2fa_enabled();
+
+    let code = user_input::ask_for_authentication_code();
+    if let Err(e) = twofa::confirm_rotation(&mut u, &code) {
+        output::error(&e.to_string());
+        return false;
+    }
+
+    if was_enabled {
+        output::success("Two-factor authentication secret changed.");
+        return true;
+    }
+    // Note: the 2FA is enabled either way, only its date is missing from the security status
+    let _ = audit::record(
+        &SQliteAuditRepository::new(),
+        Some(u.get_id()),
+        AuditEvent::TwoFaEnabled,
+        None,
+    );
+    output::success("Two-factor authentication enabled.");
+    regenerate_recovery_codes(&u);
+
+    true
+}
+
+/// Sends a reset token without going through the menus (see `secure-auth reset request`)
+/// Always succeeds, so whether the account exists isn't leaked
+///
+/// # Arguments
+///
+/// * `email` - the email of the account
+///
+pub fn request_reset_process(email: &str) -> bool {
+    if !check_writable() {
+        return false;
+    }
+    let email = match parse_email(email) {
+        Some(email) => email,
+        None => return false,
+    };
+    println!("In case a user with that data exists in our database, you'll recieve the token to reset your password");
+
+    let service = service();
+    let _ = output::with_spinner("Sending the reset token...", || {
+        service.generate_reset_token(&email)
+    });
+
+    true
+}
+
+/// Changes the password of an account with its reset token, & its 2FA code if
+/// it's set, without going through the menus (see `secure-auth reset complete`)
+/// Returns whether the password was changed
+///
+/// # Arguments
+///
+/// * `email` - the email of the account
+///
+/// * `token` - the reset token received by email
+///
+pub fn complete_reset_process(email: &str, token: &str) -> bool {
+    if !check_writable() {
+        return false;
+    }
+    let email = match parse_email(email) {
+        Some(email) => email,
+        None => return false,
+    };
+    let service = service();
+    if let Err(e) = service.check_token(&email, token) {
+        output::error(&e.to_string());
+        return false;
+    }
+
+    let u = match service.repository().get_user(&email) {
+        Ok(u) => u,
+        Err(e) => {
+            output::error(&e.to_string());
+            return false;
+        }
+    };
+    let on_hold = hold::is_on_hold(u.get_id(), service.repository()).unwrap_or(false);
+
+    if let Some(secret) = u.get_secret_2fa() {
+        let code = user_input::ask_for_authentication_code();
+        let confirmed = if on_hold {
+            // the reset token & the 2FA code are enough to recover the account
+            hold::release_with_recovery(&email, token, factor::TOTP, &code)
+        } else if twofa::check_code(&secret, &code) {
+            Ok(())
+        } else {
+            Err(AuthError::InvalidAuthenticationCode)
+        };
+        if let Err(e) = confirmed {
+            output::error(&e.to_string());
+            return false;
+        }
+    } else if on_hold {
+        output::warning(&AuthError::AccountOnHold.to_string());
+    }
+
+    let passwd = user_input::ask_for_password_with_policy_check();
+    match output::with_spinner("Changing your password...", || {
+        service.change_password(&email, token, &passwd)
+    }) {
+        Ok(completion) => {
+            show_completion("Your password was changed.", &completion);
+            true
+        }
+        Err(e) => {
+            output::error(&e.to_string());
+            false
+        }
+    }
+}
+
+/// Shows that a flow succeeded, along with the problems of its side effects (if any)
+///
+/// # Arguments
+///
+/// * `msg` - what succeeded
+///
+/// * `completion` - the result of the flow
+///
+fn show_completion(msg: &str, completion: &Completion) {
+    output::success(msg);
+    for warning in completion.warnings() {
+        output::warning(&warning.to_string());
+    }
+}
+
+/// Checks that changes are allowed before asking for anything, the error is shown
+/// if the system is in maintenance mode
+fn check_writable() -> bool {
+    match maintenance::check_writable() {
+        Ok(()) => true,
+        Err(e) => {
+            output::error(&e.to_string());
+            false
+        }
+    }
+}
+
+/// Checks the email given on the command line, the error is shown if its format is incorrect
+///
+/// # Arguments
+///
+/// * `email` - the email to check
+///
+fn parse_email(email: &str) -> Option<Email> {
+    let parsed = Email::parse(email);
+    if parsed.is_none() {
+        output::error(&AuthError::InvalidEmail.to_string());
+    }
+
+    parsed
+}
+
+/// Checks the password entered to login
+/// Note: a password outside of the policy can't be the one of an account, so it's
+///       refused like a wrong one
+///
+/// # Arguments
+///
+/// * `passwd` - the password entered
+///
+fn login_password(passwd: &str) -> Result<Password, AuthError> {
+    Password::parse(passwd).ok_or(AuthError::LoginError)
+}
+
+/// Shows everything the user needs to add a 2FA secret to her/his authentication app
+///
+/// # Arguments
+///
+/// * `enrollment` - the secret to add & its settings
+///
+fn show_enrollment(enrollment: &twofa::Enrollment) {
+    // Note: the QR code is rendered by an online service, which isn't available offline
+    let qr_url = network::outbound(None, || {
+        Some(twofa::generate_qr(
+            &enrollment.secret,
+            &enrollment.account,
+            &enrollment.issuer,
+        ))
+    });
+    match qr_url {
+        Some(qr_url) => {
+            println!(
+                "Scan the following QR code with your favorite Authentication app: {}",
+                qr_url
+            );
+            println!("Can't scan it? Enter the following secret instead:");
+        }
+        None => println!("Add the following secret to your favorite Authentication app:"),
+    }
+    // the secret is always offered, not everybody can scan a QR code (e.g. screen reader users)
+    println!("    {}", enrollment.formatted_secret());
+    println!(
+        "    (time based, {} digits, new code every {} seconds)\n",
+        enrollment.digits, enrollment.period_secs
+    );
+}
+
+/// Asks the user to accept the current terms of service and records it
+/// Returns whether the user accepted them
+///
+/// # Arguments
+///
+/// * `u` - the user that needs to accept the terms
+///
+fn confirm_tos_acceptance(u: &mut User) -> bool {
+    output::warning("Our terms of service changed.");
+    if !user_input::ask_for_tos_acceptance(tos::TOS_VERSION) {
+        output::error(&AuthError::TosNotAccepted.to_string());
+        return false;
+    }
+
+    if let Err(e) = tos::accept_terms(u) {
+        output::error(&e.to_string());
+        return false;
+    }
+
+    true
+}
+
 /// Asks the user for her/his 2FA code and validates it
 ///
 /// # Arguments
@@ -243,26 +1274,150 @@ fn confirm_2fa_code(secret: &str) {
     loop {
         let auth_code = user_input::ask_for_authentication_code();
         if !twofa::check_code(secret, &auth_code) {
-            println!("Incorrect authentication code.");
+            output::error("Incorrect authentication code.");
             continue;
         }
         break;
     }
 }
 
-/// Confirms the users identity by askign for her/his password
+/// Asks the user for her/his 2FA code until the security hold of her/his account is released
+///
+/// # Arguments
+///
+/// * `email` - the email of the user
+///
+/// * `token` - the reset token the user entered
+///
+fn release_hold_with_recovery(email: &str, token: &str) {
+    loop {
+        let auth_code = user_input::ask_for_authentication_code();
+        match hold::release_with_recovery(email, token, factor::TOTP, &auth_code) {
+            Ok(()) => {
+                output::success("The security hold of your account was released.");
+                return;
+            }
+            Err(AuthError::InvalidAuthenticationCode) => {
+                output::error(&AuthError::InvalidAuthenticationCode.to_string());
+            }
+            Err(e) => {
+                output::error(&e.to_string());
+                return;
+            }
+        }
+    }
+}
+
+/// Tells which second factor a code entered by the user is for
 ///
 /// # Arguments
 ///
-/// * `user_passwd_hash` - the users password hash stored in the db
+/// * `challenge` - the challenge returned by the first phase of the login
+///
+/// * `code` - the code entered by the user
 ///
-fn confirm_identity_with_password(user_passwd_hash: &str) {
+fn chosen_factor(challenge: &TwoFactorChallenge, code: &str) -> &'static str {
+    // the authentication codes are only made of digits, the recovery codes aren't
+    if challenge.get_factors().contains(&factor::RECOVERY_CODE)
+        && !code.chars().all(|c| c.is_ascii_digit())
+    {
+        factor::RECOVERY_CODE
+    } else {
+        factor::TOTP
+    }
+}
+
+/// Asks the user for her/his 2FA code, or one of her/his recovery codes if she/he
+/// lost her/his device
+/// When only a few recovery codes are left, the user is offered to generate new ones
+/// No user is returned if the login must be started over (e.g. the challenge expired)
+///
+/// # Arguments
+///
+/// * `challenge` - the challenge returned by the first phase of the login
+///
+fn confirm_second_factor(challenge: &TwoFactorChallenge) -> Option<User> {
+    let recovery_allowed = challenge.get_factors().contains(&factor::RECOVERY_CODE);
+    if recovery_allowed {
+        println!("{}", tr(Text::RecoveryCodeHint));
+    }
+
+    loop {
+        let code = user_input::ask_for_authentication_code();
+        let chosen = chosen_factor(challenge, &code);
+
+        let completed = match login::complete_2fa(challenge, chosen, &code) {
+            Ok(completed) => completed,
+            Err(e @ AuthError::InvalidAuthenticationCode)
+            | Err(e @ AuthError::InvalidRecoveryCode) => {
+                output::error(&e.to_string());
+                continue;
+            }
+            Err(e) => {
+                output::error(&e.to_string());
+                return None;
+            }
+        };
+
+        if let Some(consumption) = completed.get_recovery() {
+            output::warning(&format!(
+                "Recovery code used, {} left.",
+                consumption.remaining
+            ));
+            if consumption.should_regenerate
+                && user_input::ask_for_recovery_codes_regeneration(consumption.remaining)
+            {
+                regenerate_recovery_codes(completed.get_user());
+            }
+        }
+
+        return Some(completed.into_user());
+    }
+}
+
+/// Asks the user to solve CAPTCHAs until she/he gets one right, which grants
+/// her/him one more login attempt
+///
+/// # Arguments
+///
+/// * `email` - the email of the account the user tries to login to
+///
+fn solve_captcha(email: &str) {
+    loop {
+        let captcha = login::new_captcha();
+        let answer = user_input::ask_for_captcha_answer(captcha.get_question());
+
+        match login::solve_captcha(&captcha, &answer, email) {
+            Ok(()) => return,
+            Err(e) => output::error(&e.to_string()),
+        }
+    }
+}
+
+/// Confirms the users identity before a sensitive action, by asking for her/his
+/// password & a code of her/his strongest second factor (if any, see `step_up.rs`)
+///
+/// # Arguments
+///
+/// * `u` - the user confirming the action
+///
+fn confirm_step_up(u: &User) {
+    let step_up = step_up::step_up(u);
     loop {
         let passwd = user_input::ask_for_password();
-        if !utils::verify_hash(&passwd, user_passwd_hash) {
-            println!("Incorrect password.");
-            continue;
+        let code = step_up
+            .factor
+            .map(|_| user_input::ask_for_authentication_code());
+
+        match output::with_spinner("Checking your identity...", || {
+            step_up::require_step_up(u, &passwd, code.as_deref())
+        }) {
+            Ok(()) => return,
+            Err(AuthError::CaptchaRequired) => {
+                output::warning(&AuthError::CaptchaRequired.to_string());
+                solve_captcha(&u.get_email());
+            }
+            Err(e) => output::error(&e.to_string()),
         }
-        return;
     }
 }
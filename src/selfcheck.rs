@@ -0,0 +1,439 @@
+/*!
+ * Self-test of the security configuration (see the `check` command)
+ *
+ * Every check passes, warns about a setting that weakens or disables a feature,
+ * or fails on a setting the system can't run safely with, and explains how to
+ * fix it. The configuration file is checked first, the other checks need it.
+ *
+ * The passwords aren't peppered: the hashes are argon2id with a random salt, no
+ * secret key is mixed in, so there's no pepper to check.
+ *
+ * # Author
+ * Doran Kayoumi <doran.kayoumi@heig-vd.ch>
+ */
+
+use std::env;
+
+use crate::audit::chain;
+use crate::auth::action;
+use crate::config::{self, AuditConfig, Config, HashingConfig, MailConfig, RedisConfig};
+#[cfg(feature = "redis")]
+use crate::db::ephemeral::{RedisTokenStore, TokenStore};
+use crate::db::{self, doctor, SCHEMA_VERSION};
+use crate::errors::ConfigError;
+use crate::mail::{self, smtp::SmtpMailer};
+
+/// Outcome of a check
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum Status {
+    Pass,
+    /// The system runs, but a feature is weakened or disabled
+    Warn,
+    /// The system can't run (safely) like this
+    Fail,
+}
+
+/// Result of a single check
+#[derive(PartialEq, Debug)]
+pub struct Check {
+    /// What was checked
+    pub name: &'static str,
+    pub status: Status,
+    /// What was found
+    pub detail: String,
+    /// How to fix it, unless the check passed
+    pub fix: Option<String>,
+}
+
+impl Check {
+    fn pass(name: &'static str, detail: String) -> Self {
+        Self {
+            name,
+            status: Status::Pass,
+            detail,
+            fix: None,
+        }
+    }
+
+    fn warn(name: &'static str, detail: String, fix: &str) -> Self {
+        Self {
+            name,
+            status: Status::Warn,
+            detail,
+            fix: Some(fix.to_string()),
+        }
+    }
+
+    fn fail(name: &'static str, detail: String, fix: &str) -> Self {
+        Self {
+            name,
+            status: Status::Fail,
+            detail,
+            fix: Some(fix.to_string()),
+        }
+    }
+}
+
+/// Run every check of the security configuration
+/// Note: only the configuration is checked if it can't be loaded
+pub fn run() -> Vec<Check> {
+    // the keys may be set in the `.env` file
+    dotenv::dotenv().ok();
+
+    let config = check_config(Config::load());
+    if config.status == Status::Fail {
+        return vec![config];
+    }
+
+    // Note: the configuration was loaded above, so reading it again can't fail
+    let settings = config::get();
+    vec![
+        config,
+        check_hashing(&settings.hashing),
+        check_action_secret(env::var(action::SECRET_VARIABLE).ok()),
+        check_database(db::try_database_url()),
+        check_state_store(settings.redis.as_ref()),
+        check_mail(&settings.mail),
+        check_audit(&settings.audit, env::var(chain::SECRET_VARIABLE).ok()),
+    ]
+}
+
+/// Check that the configuration file can be loaded
+///
+/// # Arguments
+///
+/// * `loaded` - the configuration read from the file
+///
+fn check_config(loaded: Result<Config, ConfigError>) -> Check {
+    match loaded {
+        Ok(_) => Check::pass("Configuration", format!("{} loaded", config::path())),
+        Err(e) => Check::fail(
+            "Configuration",
+            format!("{}: {}", config::path(), e),
+            "Fix the file, see `auth.toml.example` for all the available options.",
+        ),
+    }
+}
+
+/// Check that the password hashing isn't cheaper than the default cost
+/// (libsodium's interactive cost, `calibrate_hashing` never goes below it either)
+///
+/// # Arguments
+///
+/// * `hashing` - the cost set in the configuration
+///
+fn check_hashing(hashing: &HashingConfig) -> Check {
+    let minimum = HashingConfig::default();
+    let detail = format!(
+        "argon2id with {} pass(es) over {} KiB",
+        hashing.ops_limit,
+        hashing.mem_limit / 1024
+    );
+
+    if hashing.ops_limit >= minimum.ops_limit && hashing.mem_limit >= minimum.mem_limit {
+        Check::pass("Password hashing", detail)
+    } else {
+        Check::fail(
+            "Password hashing",
+            format!(
+                "{}, at least {} pass(es) over {} KiB are required",
+                detail,
+                minimum.ops_limit,
+                minimum.mem_limit / 1024
+            ),
+            "Raise `ops_limit` & `mem_limit` in the `[hashing]` section, or remove them to use the default cost.",
+        )
+    }
+}
+
+/// Check the signing key of the action links
+/// Without it, the action links & the "this wasn't me" link of the alerts are disabled
+///
+/// # Arguments
+///
+/// * `secret` - the value of `ACTION_LINK_SECRET`, if it's set
+///
+fn check_action_secret(secret: Option<String>) -> Check {
+    const NAME: &str = "Action links key";
+
+    match secret {
+        None => Check::warn(
+            NAME,
+            format!(
+                "{} isn't set, the action links are disabled",
+                action::SECRET_VARIABLE
+            ),
+            "Generate one, e.g. `echo \"ACTION_LINK_SECRET=$(openssl rand -hex 32)\" >> .env`.",
+        ),
+        Some(s) if s.len() < action::MIN_SECRET_LEN => Check::fail(
+            NAME,
+            format!(
+                "{} is {} characters long, at least {} are required",
+                action::SECRET_VARIABLE,
+                s.len(),
+                action::MIN_SECRET_LEN
+            ),
+            "Replace it, e.g. with `openssl rand -hex 32`.",
+        ),
+        Some(s) => Check::pass(NAME, format!("{} characters", s.len())),
+    }
+}
+
+/// Check that the database can be reached & its migrations are up to date
+///
+/// # Arguments
+///
+/// * `database_url` - the url set by `DATABASE_URL`, if it's set
+///
+fn check_database(database_url: Option<String>) -> Check {
+    const NAME: &str = "Database";
+
+    let url = match database_url {
+        Some(url) if !url.is_empty() => url,
+        _ => {
+            return Check::fail(
+                NAME,
+                "DATABASE_URL isn't set".to_string(),
+                "Set it in the `.env` file, or run `secure-auth init`.",
+            )
+        }
+    };
+
+    match doctor::schema_version(&url) {
+        Err(e) => Check::fail(
+            NAME,
+            format!("{}: {}", url, e),
+            "Check the url & the permissions of the database file.",
+        ),
+        Ok(Some(version)) if version == SCHEMA_VERSION => {
+            Check::pass(NAME, format!("{} at version {}", url, version))
+        }
+        Ok(version) => Check::fail(
+            NAME,
+            format!(
+                "{} is at version {}, {} is expected",
+                url,
+                version.as_deref().unwrap_or("<none>"),
+                SCHEMA_VERSION
+            ),
+            "Apply the pending migrations with `secure-auth db migrate`.",
+        ),
+    }
+}
+
+/// Check that the emails can be sent: the SMTP server answers, or the provider
+/// is supported by this build
+///
+/// # Arguments
+///
+/// * `mail` - the mail section of the configuration
+///
+fn check_mail(mail: &MailConfig) -> Check {
+    const NAME: &str = "Mail";
+
+    if let Some(smtp) = &mail.smtp {
+        let reached =
+            SmtpMailer::new(smtp, &mail.sender).and_then(|mailer| mailer.test_connection());
+        return match reached {
+            Ok(()) => Check::pass(NAME, format!("SMTP server {} reachable", smtp.host)),
+            Err(e) => Check::fail(
+                NAME,
+                format!("SMTP server {}: {}", smtp.host, e),
+                "Check the `[mail.smtp]` section (host, port & security), & `SMTP_PASSWORD` if a username is set.",
+            ),
+        };
+    }
+
+    if let Some(api) = &mail.api {
+        // Note: the provider isn't contacted, that would take sending an email
+        return match mail::api_mailer(api, &mail.sender) {
+            Ok(_) => Check::pass(NAME, format!("{:?} API set up", api.provider)),
+            Err(e) => Check::fail(
+                NAME,
+                format!("{:?} API: {}", api.provider, e),
+                "Build with the feature of the provider (e.g. `--features sendgrid`) & set its key.",
+            ),
+        };
+    }
+
+    Check::warn(
+        NAME,
+        "no mail server set, the emails are printed on the console".to_string(),
+        "Set the `[mail.smtp]` or `[mail.api]` section.",
+    )
+}
+
+/// Check the signing key of the checkpoints once the audit log is tamper-evident
+///
+/// # Arguments
+///
+/// * `audit` - the audit section of the configuration
+///
+/// * `secret` - the value of `AUDIT_CHECKPOINT_SECRET`, if it's set
+///
+fn check_audit(audit: &AuditConfig, secret: Option<String>) -> Check {
+    const NAME: &str = "Audit log";
+
+    if !audit.tamper_evident {
+        return Check::pass(NAME, "not tamper-evident".to_string());
+    }
+    if audit.checkpoint_every == 0 {
+        return Check::pass(NAME, "chained, without checkpoints".to_string());
+    }
+
+    match secret {
+        None => Check::warn(
+            NAME,
+            format!(
+                "{} isn't set, the checkpoints are skipped",
+                chain::SECRET_VARIABLE
+            ),
+            "Generate one, e.g. `echo \"AUDIT_CHECKPOINT_SECRET=$(openssl rand -hex 32)\" >> .env`.",
+        ),
+        Some(s) if s.len() < chain::MIN_SECRET_LEN => Check::fail(
+            NAME,
+            format!(
+                "{} is {} characters long, at least {} are required",
+                chain::SECRET_VARIABLE,
+                s.len(),
+                chain::MIN_SECRET_LEN
+            ),
+            "Replace it, e.g. with `openssl rand -hex 32`.",
+        ),
+        Some(_) => Check::pass(
+            NAME,
+            format!(
+                "chained, signed every {} entries",
+                audit.checkpoint_every
+            ),
+        ),
+    }
+}
+
+/// Check where the state of the logins is kept (see `db/ephemeral.rs`), a Redis server must be
+/// reachable
+/// Note: the url isn't shown, it may hold the password of the server
+///
+/// # Arguments
+///
+/// * `redis` - the Redis server set in the configuration, if any
+///
+fn check_state_store(redis: Option<&RedisConfig>) -> Check {
+    const NAME: &str = "Login state";
+
+    match redis {
+        None => Check::pass(NAME, "in memory, each instance has its own".to_string()),
+        #[cfg(feature = "redis")]
+        Some(config) => {
+            let reachable = RedisTokenStore::from_config(config).and_then(|s| s.get("selfcheck"));
+            match reachable {
+                Ok(_) => Check::pass(NAME, "in Redis, shared by the instances".to_string()),
+                Err(e) => Check::fail(
+                    NAME,
+                    format!("Redis: {}", e),
+                    "Check the url of the `[redis]` section & that the server is running.",
+                ),
+            }
+        }
+        #[cfg(not(feature = "redis"))]
+        Some(_) => Check::fail(
+            NAME,
+            "the `[redis]` section is set, but Redis isn't enabled in this build".to_string(),
+            "Build with the `redis` feature, or remove the section.",
+        ),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::db::test_database;
+
+    #[test]
+    fn test_check_hashing() {
+        assert_eq!(
+            check_hashing(&HashingConfig::default()).status,
+            Status::Pass
+        );
+
+        let cheap = HashingConfig {
+            mem_limit: HashingConfig::MIN_MEM_LIMIT,
+            ..HashingConfig::default()
+        };
+        let check = check_hashing(&cheap);
+        assert_eq!(check.status, Status::Fail);
+        assert!(check.fix.is_some());
+    }
+
+    #[test]
+    fn test_check_action_secret() {
+        assert_eq!(check_action_secret(None).status, Status::Warn);
+        assert_eq!(
+            check_action_secret(Some("short".to_string())).status,
+            Status::Fail
+        );
+        assert_eq!(
+            check_action_secret(Some("0123456789abcdef0123456789abcdef".to_string())),
+            Check::pass("Action links key", "32 characters".to_string())
+        );
+    }
+
+    #[test]
+    fn test_check_database() {
+        assert_eq!(check_database(None).status, Status::Fail);
+
+        let (_dir, url) = test_database();
+        assert_eq!(check_database(Some(url)).status, Status::Pass);
+
+        let dir = tempfile::tempdir().unwrap();
+        let empty = dir.path().join("empty.db").to_str().unwrap().to_string();
+        let check = check_database(Some(empty));
+        assert_eq!(check.status, Status::Fail);
+        assert!(check.detail.contains("<none>"));
+    }
+
+    #[test]
+    fn test_check_state_store() {
+        assert_eq!(check_state_store(None).status, Status::Pass);
+
+        // nothing listens on the port (or Redis isn't enabled in this build)
+        let unreachable = RedisConfig {
+            url: "redis://127.0.0.1:1/".to_string(),
+            ..RedisConfig::default()
+        };
+        let check = check_state_store(Some(&unreachable));
+        assert_eq!(check.status, Status::Fail);
+        assert!(check.fix.is_some());
+    }
+
+    #[test]
+    fn test_check_audit() {
+        let chained = AuditConfig {
+            tamper_evident: true,
+            ..AuditConfig::default()
+        };
+
+        assert_eq!(
+            check_audit(&AuditConfig::default(), None).status,
+            Status::Pass
+        );
+        assert_eq!(check_audit(&chained, None).status, Status::Warn);
+        assert_eq!(
+            check_audit(&chained, Some("short".to_string())).status,
+            Status::Fail
+        );
+        assert_eq!(
+            check_audit(
+                &chained,
+                Some("0123456789abcdef0123456789abcdef".to_string())
+            )
+            .status,
+            Status::Pass
+        );
+    }
+
+    #[test]
+    fn test_check_mail_without_server() {
+        assert_eq!(check_mail(&MailConfig::default()).status, Status::Warn);
+    }
+}
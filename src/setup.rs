@@ -0,0 +1,204 @@
+/*!
+ * First-run setup of the system (see the `init` command)
+ *
+ * Nothing is saved before the first administrator is created: the database
+ * url, the generated keys & the configuration file are only written once she/he
+ * is, so a failed setup doesn't leave a half-configured install behind.
+ *
+ * # Author
+ * Doran Kayoumi <doran.kayoumi@heig-vd.ch>
+ */
+
+use rand::{thread_rng, Rng};
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+
+use crate::auth::{action, jwt};
+use crate::config::{Config, HashingConfig};
+use crate::db::repository::{SQliteUserRepository, UserRepository};
+use crate::errors::SetupError;
+use crate::utils;
+
+/// File where the database url & the generated keys are saved
+const ENV_FILE: &str = ".env";
+
+/// Keys generated by the setup if they aren't set yet
+pub const GENERATED_SECRETS: &[&str] = &[action::SECRET_VARIABLE, jwt::SECRET_VARIABLE];
+
+/// Write a configuration file, replacing the existing one if any
+///
+/// # Arguments
+///
+/// * `path` - where to write the configuration
+///
+/// * `config` - the configuration to write
+///
+pub fn write_config(path: &str, config: &Config) -> Result<(), SetupError> {
+    let toml = config.to_toml().map_err(|_| SetupError::WriteConfigError)?;
+
+    // Note: written aside then moved, so an existing file is never left half written
+    let tmp = format!("{}.tmp", path);
+    fs::write(&tmp, toml)
+        .and_then(|_| fs::rename(&tmp, path))
+        .map_err(|_| SetupError::WriteConfigError)
+}
+
+/// Append a variable to an env file
+///
+/// # Arguments
+///
+/// * `env_file` - the file to append to
+///
+/// * `variable` - the name of the variable
+///
+/// * `value` - the value of the variable
+///
+fn append_env(env_file: &str, variable: &str, value: &str) -> std::io::Result<()> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(env_file)
+        .and_then(|mut f| writeln!(f, "{}={}", variable, value))
+}
+
+/// Save the url of the database in the `.env` file (see `db::database_url`)
+///
+/// # Arguments
+///
+/// * `database_url` - url of the database
+///
+pub fn save_database_url(database_url: &str) -> Result<(), SetupError> {
+    append_env(ENV_FILE, "DATABASE_URL", database_url).map_err(|_| SetupError::SaveDatabaseUrlError)
+}
+
+/// Public function for the generation of the keys
+/// See `_generate_secrets` for more info
+///
+pub fn generate_secrets() -> Result<Vec<&'static str>, SetupError> {
+    dotenv::dotenv().ok();
+    _generate_secrets(ENV_FILE, |variable| env::var(variable).is_ok())
+}
+
+/// Generate the keys of `GENERATED_SECRETS` that aren't set yet & save them in an env file
+/// Returns the variables generated, the keys themselves are never shown
+///
+/// # Arguments
+///
+/// * `env_file` - where the keys are saved
+///
+/// * `is_set` - whether a variable is already set
+///
+fn _generate_secrets(
+    env_file: &str,
+    is_set: impl Fn(&str) -> bool,
+) -> Result<Vec<&'static str>, SetupError> {
+    let mut generated = Vec::new();
+
+    for variable in GENERATED_SECRETS.iter().filter(|v| !is_set(v)) {
+        // 32 random bytes, hex encoded like `openssl rand -hex 32`
+        let key: String = (0..32)
+            .map(|_| format!("{:02x}", thread_rng().gen::<u8>()))
+            .collect();
+        append_env(env_file, variable, &key).map_err(|_| SetupError::SaveSecretError)?;
+        generated.push(*variable);
+    }
+
+    Ok(generated)
+}
+
+/// Public function for the creation of the administrator
+/// See `_create_admin` for more info
+///
+pub fn create_admin(
+    database_url: &str,
+    email: &str,
+    passwd: &str,
+    cost: &HashingConfig,
+) -> Result<(), SetupError> {
+    // Note: the url isn't saved yet, it can't be read from the environment
+    let repository = SQliteUserRepository::with_database_url(database_url);
+    _create_admin(email, passwd, cost, &repository)
+}
+
+/// Create the first administrator account
+/// Note: the system doesn't handle roles yet, the user is marked with the `role=admin` attribute
+///
+/// # Arguments
+///
+/// * `email` - email of the administrator
+///
+/// * `passwd` - password of the administrator
+///
+/// * `cost` - the cost of the hashing of the new configuration, it isn't written yet
+///
+/// * `repository` - the user repository to interact with
+///
+fn _create_admin(
+    email: &str,
+    passwd: &str,
+    cost: &HashingConfig,
+    repository: &dyn UserRepository,
+) -> Result<(), SetupError> {
+    let pwh = utils::hash_with_cost(passwd, cost).ok_or(SetupError::AdminCreationError)?;
+    repository
+        .create_user(email, &pwh)
+        .and_then(|_| repository.get_user(email))
+        .and_then(|u| repository.set_attribute(u.get_id(), "role", "admin"))
+        .map_err(|_| SetupError::AdminCreationError)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::db::test_database;
+
+    #[test]
+    fn test_write_config() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("auth.toml").to_str().unwrap().to_string();
+        let mut config = Config::default();
+        config.network.offline = true;
+
+        write_config(&path, &config).unwrap();
+
+        let written = fs::read_to_string(&path).unwrap();
+        assert_eq!(Config::from_toml(&written), Ok(config));
+    }
+
+    #[test]
+    fn test_generate_secrets() {
+        let dir = tempfile::tempdir().unwrap();
+        let env_file = dir.path().join(".env").to_str().unwrap().to_string();
+
+        let generated = _generate_secrets(&env_file, |v| v == action::SECRET_VARIABLE).unwrap();
+        assert_eq!(generated, vec![jwt::SECRET_VARIABLE]);
+
+        let written = fs::read_to_string(&env_file).unwrap();
+        let key = written
+            .trim_end()
+            .strip_prefix(&format!("{}=", jwt::SECRET_VARIABLE))
+            .unwrap();
+        assert_eq!(key.len(), 64);
+        assert!(key.len() >= jwt::MIN_SECRET_LEN);
+
+        assert_eq!(_generate_secrets(&env_file, |_| true), Ok(vec![]));
+    }
+
+    #[test]
+    fn test_create_admin() {
+        let (_dir, url) = test_database();
+        let repository = SQliteUserRepository::with_database_url(&url);
+
+        let cost = HashingConfig::default();
+        _create_admin("admin@email.test", "password", &cost, &repository).unwrap();
+
+        let u = repository.get_user("admin@email.test").unwrap();
+        let attributes = repository.get_attributes(u.get_id()).unwrap();
+        assert_eq!(attributes.get("role").map(String::as_str), Some("admin"));
+        assert_eq!(
+            _create_admin("admin@email.test", "password", &cost, &repository),
+            Err(SetupError::AdminCreationError)
+        );
+    }
+}
@@ -0,0 +1,213 @@
+/*!
+ * Statistics about the strength of the passwords chosen by the users
+ *
+ * When a password is chosen (at registration or when it's changed), only its
+ * zxcvbn score (0 to 4) is counted, the password itself is never stored. The
+ * counts aren't linked to the users or dated, so they don't tell anything about
+ * a given account. The distribution helps the operators decide whether the
+ * password policy must be tightened (see `secure-auth password-report`).
+ *
+ * # Author
+ * Doran Kayoumi <doran.kayoumi@heig-vd.ch>
+ */
+
+use std::convert::TryFrom;
+use strum_macros::Display;
+
+use crate::db::repository::{PasswordStatsRepository, SQlitePasswordStatsRepository};
+use crate::errors::PasswordStatsDBError;
+
+/// Highest zxcvbn score, for the passwords very unlikely to be guessed
+pub const MAX_SCORE: usize = 4;
+
+/// Where a password was chosen
+#[derive(PartialEq, Debug, Clone, Copy, Display)]
+#[strum(serialize_all = "snake_case")]
+pub enum PasswordContext {
+    Registration,
+    Change,
+}
+
+/// Number of passwords of each score chosen in a context
+#[derive(PartialEq, Debug, Clone)]
+pub struct ScoreDistribution {
+    pub context: String,
+    /// Indexed by the score
+    pub counts: [u64; MAX_SCORE + 1],
+}
+
+impl ScoreDistribution {
+    pub fn total(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// Share (in percent) of the passwords with a given score
+    ///
+    /// # Arguments
+    ///
+    /// * `score` - the score
+    ///
+    pub fn share(&self, score: usize) -> f64 {
+        match self.total() {
+            0 => 0.0,
+            total => self.counts[score] as f64 * 100.0 / total as f64,
+        }
+    }
+}
+
+/// Get the zxcvbn score of a password
+///
+/// # Arguments
+///
+/// * `passwd` - the password
+///
+/// * `user_inputs` - what the user entered besides the password (e.g. her/his email),
+///   a password built from them is weaker
+///
+pub fn password_score(passwd: &str, user_inputs: &[&str]) -> u8 {
+    zxcvbn::zxcvbn(passwd, user_inputs)
+        .map(|entropy| entropy.score())
+        .unwrap_or(0)
+}
+
+/// Public function for counting the score of a chosen password
+/// See `_record_password_score` for more info
+///
+/// # Note
+/// The password was chosen anyway, failing to count it doesn't change anything
+///
+pub fn record_password_score(context: PasswordContext, passwd: &str, email: &str) {
+    let repository = SQlitePasswordStatsRepository::new();
+    let _ = _record_password_score(context, passwd, email, &repository);
+}
+
+/// Public function for the distribution of the scores
+/// See `_password_report` for more info
+///
+pub fn password_report() -> Result<Vec<ScoreDistribution>, PasswordStatsDBError> {
+    let repository = SQlitePasswordStatsRepository::new();
+    _password_report(&repository)
+}
+
+/// Count the score of a chosen password
+///
+/// # Arguments
+///
+/// * `context` - where the password was chosen
+///
+/// * `passwd` - the password, only its score is kept
+///
+/// * `email` - the email of the user, for the score
+///
+/// * `repository` - the password statistics repository to write in
+///
+fn _record_password_score(
+    context: PasswordContext,
+    passwd: &str,
+    email: &str,
+    repository: &dyn PasswordStatsRepository,
+) -> Result<(), PasswordStatsDBError> {
+    let score = password_score(passwd, &[email]);
+    repository.record(&context.to_string(), i32::from(score))
+}
+
+/// Get the distribution of the scores of each context
+///
+/// # Arguments
+///
+/// * `repository` - the password statistics repository to read from
+///
+fn _password_report(
+    repository: &dyn PasswordStatsRepository,
+) -> Result<Vec<ScoreDistribution>, PasswordStatsDBError> {
+    let mut report: Vec<ScoreDistribution> = Vec::new();
+
+    for c in repository.counts()? {
+        let score = match usize::try_from(c.score) {
+            Ok(score) if score <= MAX_SCORE => score,
+            _ => continue,
+        };
+        let i = match report.iter().position(|d| d.context == c.context) {
+            Some(i) => i,
+            None => {
+                report.push(ScoreDistribution {
+                    context: c.context.clone(),
+                    counts: [0; MAX_SCORE + 1],
+                });
+                report.len() - 1
+            }
+        };
+        report[i].counts[score] += c.count.max(0) as u64;
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::db::models::PasswordScoreCount;
+    use crate::db::repository::MockSQlitePasswordStatsRepository;
+
+    #[test]
+    fn test_password_score() {
+        assert_eq!(password_score("password", &[]), 0);
+        assert_eq!(password_score("correct horse battery staple", &[]), 4);
+        // built from the email of the user
+        assert!(
+            password_score("doran.kayoumi@heig-vd.ch", &["doran.kayoumi@heig-vd.ch"])
+                < password_score("doran.kayoumi@heig-vd.ch", &[])
+        );
+    }
+
+    #[test]
+    fn test_only_the_score_is_recorded() {
+        let mut mock = MockSQlitePasswordStatsRepository::new();
+        mock.expect_record()
+            .withf(|c, s| c == "registration" && *s == 0)
+            .times(1)
+            .returning(|_, _| Ok(()));
+
+        assert_eq!(
+            _record_password_score(
+                PasswordContext::Registration,
+                "password",
+                "email@email.test",
+                &mock
+            ),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn test_password_report() {
+        let mut mock = MockSQlitePasswordStatsRepository::new();
+        mock.expect_counts().returning(|| {
+            Ok(vec![
+                PasswordScoreCount {
+                    context: "change".to_string(),
+                    score: 4,
+                    count: 1,
+                },
+                PasswordScoreCount {
+                    context: "registration".to_string(),
+                    score: 1,
+                    count: 3,
+                },
+                PasswordScoreCount {
+                    context: "registration".to_string(),
+                    score: 3,
+                    count: 1,
+                },
+            ])
+        });
+
+        let report = _password_report(&mock).unwrap();
+
+        assert_eq!(report.len(), 2);
+        assert_eq!(report[0].counts, [0, 0, 0, 0, 1]);
+        assert_eq!(report[1].counts, [0, 3, 0, 1, 0]);
+        assert_eq!(report[1].total(), 4);
+        assert_eq!(report[1].share(1), 75.0);
+    }
+}
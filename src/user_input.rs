@@ -9,52 +9,60 @@ use read_input::prelude::*;
 use regex::{self, Regex};
 use std::str::FromStr;
 
+use chrono_tz::Tz;
+use secure_auth::i18n::{tr, tr_with, Locale, Text};
+use secure_auth::validation::{self, Email, Password};
+
 use crate::command;
-use crate::validation;
 
 /// Ask the user to enter an email address
-pub fn ask_for_email() -> String {
-    input()
-        .repeat_msg("Email : ")
-        .add_err_test(
-            move |m: &String| validation::is_email_valid(m),
-            "Invalid mail address, please try again",
-        )
-        .get()
+pub fn ask_for_email() -> Email {
+    loop {
+        let email: String = input().msg(tr(Text::EmailPrompt)).get();
+        match Email::parse(&email) {
+            Some(email) => return email,
+            None => println!("{}", tr(Text::InvalidEmail)),
+        }
+    }
 }
 
 /// Ask the user for a password without checking the policy
 pub fn ask_for_password() -> String {
-    input().msg("Password : ").get()
+    input().msg(tr(Text::PasswordPrompt)).get()
 }
 
 /// Ask for a password with policy check
-pub fn ask_for_password_with_policy_check() -> String {
-    input()
-        .repeat_msg("Password : ")
-        .add_err_test(
-            move |m: &String| validation::is_password_valid(m),
-            "Password length must be between 8 and 64, please try again",
-        )
-        .get()
+pub fn ask_for_password_with_policy_check() -> Password {
+    loop {
+        let passwd: String = input().msg(tr(Text::PasswordPrompt)).get();
+        match Password::parse(&passwd) {
+            Some(passwd) => return passwd,
+            None => println!("{}", tr(Text::InvalidPassword)),
+        }
+    }
 }
 
 /// Ask for the 2FA code
 pub fn ask_for_authentication_code() -> String {
-    println!("Open the two-factor authentication app on your device to view your authentication code and verify your identity.");
-    input().msg("Authentication code: ").get()
+    println!("{}", tr(Text::AuthenticationCodeHint));
+    input().msg(tr(Text::AuthenticationCodePrompt)).get()
+}
+
+/// Ask the user to answer a CAPTCHA
+pub fn ask_for_captcha_answer(question: &str) -> String {
+    input().msg(format!("{} ", question)).get()
 }
 
 /// Ask for login screen command (see command.rs#LoginScreenCmd for options)
 pub fn ask_for_login_screen_cmd() -> command::LoginScreenCmd {
-    let err_msg = "Unknown command";
+    let err_msg = tr(Text::UnknownCommand);
     loop {
         let input: String = input()
-            .msg("What do you want to do? ")
-            .add_err_test(move |x: &String| check_cmd_syntax(&x), err_msg)
+            .msg(tr(Text::CommandPrompt))
+            .add_err_test(move |x: &String| check_cmd_syntax(x), err_msg)
             .get();
 
-        if let Err(_) = command::LoginScreenCmd::from_str(&input) {
+        if command::LoginScreenCmd::from_str(&input).is_err() {
             println!("{}", err_msg);
             continue;
         }
@@ -65,14 +73,14 @@ pub fn ask_for_login_screen_cmd() -> command::LoginScreenCmd {
 
 /// Ask for user profile screen command (see command.rs#ProfileScreenCmd for options)
 pub fn ask_for_user_profile_cmd() -> command::ProfileScreenCmd {
-    let err_msg = "Unknown command";
+    let err_msg = tr(Text::UnknownCommand);
     loop {
         let input: String = input()
-            .msg("What do you want to do? ")
-            .add_err_test(move |x: &String| check_cmd_syntax(&x), err_msg)
+            .msg(tr(Text::CommandPrompt))
+            .add_err_test(move |x: &String| check_cmd_syntax(x), err_msg)
             .get();
 
-        if let Err(_) = command::ProfileScreenCmd::from_str(&input) {
+        if command::ProfileScreenCmd::from_str(&input).is_err() {
             println!("{}", err_msg);
             continue;
         }
@@ -83,14 +91,116 @@ pub fn ask_for_user_profile_cmd() -> command::ProfileScreenCmd {
 
 /// Ask the user for a reset token he recieved by "email"
 pub fn ask_for_reset_token() -> String {
-    input().msg("Reset token : ").get()
+    input().msg(tr(Text::ResetTokenPrompt)).get()
+}
+
+/// Ask the user for the token confirming an unusual login, sent to her/his mailbox
+pub fn ask_for_confirmation_token() -> String {
+    input().msg(tr(Text::ConfirmationTokenPrompt)).get()
+}
+
+/// Ask the user for the secret given when she/he started the recovery of her/his account
+pub fn ask_for_recovery_secret() -> String {
+    input().msg(tr(Text::RecoverySecretPrompt)).get()
+}
+
+/// Ask the user to accept a version of the terms of service & privacy policy
+pub fn ask_for_tos_acceptance(version: i32) -> bool {
+    println!("{}", tr_with(Text::TosNotice, &version.to_string()));
+    ask_for_confirmation(tr(Text::TosQuestion))
+}
+
+/// Ask the user to confirm she/he is old enough to register
+pub fn ask_for_age_confirmation() -> bool {
+    ask_for_confirmation(tr(Text::AgeQuestion))
+}
+
+/// Ask the user if she/he wants new recovery codes
+pub fn ask_for_recovery_codes_regeneration(remaining: i64) -> bool {
+    ask_for_confirmation(&tr_with(
+        Text::RegenerateRecoveryCodesQuestion,
+        &remaining.to_string(),
+    ))
+}
+
+/// Ask the user if an existing file can be replaced
+pub fn ask_for_overwrite_confirmation(path: &str) -> bool {
+    ask_for_confirmation(&tr_with(Text::OverwriteQuestion, path))
+}
+
+/// Ask the user if the system must run without any outbound network access
+pub fn ask_for_offline_mode() -> bool {
+    ask_for_confirmation(tr(Text::OfflineQuestion))
+}
+
+/// Ask the user where the database must be created
+pub fn ask_for_database_path() -> String {
+    input()
+        .repeat_msg(tr(Text::DatabasePathPrompt))
+        .add_err_test(|p: &String| !p.is_empty(), tr(Text::InvalidDatabasePath))
+        .get()
+}
+
+/// Ask the user for the language in which the system talks to her/him
+pub fn ask_for_locale() -> Locale {
+    input()
+        .repeat_msg(tr(Text::LocalePrompt))
+        .err(tr(Text::InvalidLocale))
+        .get()
+}
+
+/// Ask the user for the timezone in which the dates are shown to her/him
+pub fn ask_for_timezone() -> Tz {
+    input()
+        .repeat_msg(tr(Text::TimezonePrompt))
+        .err(tr(Text::InvalidTimezone))
+        .get()
+}
+
+/// Ask the user a yes/no question
+fn ask_for_confirmation(question: &str) -> bool {
+    let answer: String = input()
+        .repeat_msg(format!("{} [y/n] ", question))
+        .add_err_test(|a: &String| a == "y" || a == "n", tr(Text::YesNoAnswer))
+        .get();
+
+    answer == "y"
+}
+
+/// Ask the user for the anti-phishing phrase shown in the emails she/he recieves
+pub fn ask_for_anti_phishing_phrase() -> String {
+    input()
+        .repeat_msg(tr(Text::AntiPhishingPhrasePrompt))
+        .add_err_test(
+            move |m: &String| validation::is_anti_phishing_phrase_valid(m),
+            tr(Text::InvalidAntiPhishingPhrase),
+        )
+        .get()
+}
+
+/// Ask the user for a trusted contact to add or remove, nothing is returned if she/he wants to go back
+pub fn ask_for_contact() -> Option<String> {
+    let contact: String = input()
+        .repeat_msg(tr(Text::ContactPrompt))
+        .add_err_test(
+            |m: &String| m.is_empty() || validation::is_email_valid(m),
+            tr(Text::InvalidEmail),
+        )
+        .get();
+
+    Some(contact).filter(|c| !c.is_empty())
+}
+
+/// Ask the user if the recovery of her/his account must be cancelled
+pub fn ask_for_recovery_cancellation() -> bool {
+    ask_for_confirmation(tr(Text::CancelRecoveryQuestion))
 }
 
 /// Check if a user inputed a valid command
 fn check_cmd_syntax(s: &str) -> bool {
     let re: Regex = Regex::new(r"^([A-Za-z]+)$|^(\d+)$").unwrap();
 
-    re.is_match(&s)
+    re.is_match(s)
 }
 
 #[cfg(test)]
@@ -111,4 +221,4 @@ mod test {
     fn test_check_cmd_syntax(input: &str, expected: bool) {
         assert_eq!(check_cmd_syntax(input), expected);
     }
-}
\ No newline at end of file
+}
@@ -6,71 +6,262 @@
  * Doran Kayoumi <doran.kayoumi@heig-vd.ch>
  */
 
+// the library must not panic, the failures are returned as typed errors
+#![cfg_attr(
+    not(test),
+    deny(clippy::unwrap_used, clippy::expect_used, clippy::panic)
+)]
+
 use rand::distributions::Alphanumeric;
 use rand::{thread_rng, Rng};
 
 use sodiumoxide::crypto::pwhash::argon2id13;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use crate::config::{self, HashingConfig};
+use crate::errors::AuthError;
+use crate::portable::{password, token};
+use crate::validation::{self, MAX_PASSWORD_BYTES};
+
+/// Hash a password (or any other String) using argon2id13
+/// The cost of the hashing is set in the configuration
+/// See `_hash` for more info
+///
+pub fn hash(passwd: &str) -> Option<String> {
+    _hash(passwd, &config::get().hashing)
+}
 
 /// Hash a password (or any other String) using argon2id13
+/// Returns `None` if libsodium fails (e.g. it can't allocate the memory) or if the
+/// password is longer than `MAX_PASSWORD_BYTES`
 ///
 /// # Arguments
 ///
 /// * `passwd` - The password/string to hash
 ///
-pub fn hash(passwd: &str) -> String {
-    sodiumoxide::init().unwrap();
+/// * `cost` - The cost of the hashing
+///
+fn _hash(passwd: &str, cost: &HashingConfig) -> Option<String> {
+    if !validation::is_within_cap(passwd, MAX_PASSWORD_BYTES) {
+        return None;
+    }
+    sodiumoxide::init().ok()?;
 
     let pwh = argon2id13::pwhash(
         passwd.as_bytes(),
-        argon2id13::OPSLIMIT_INTERACTIVE,
-        argon2id13::MEMLIMIT_INTERACTIVE,
+        argon2id13::OpsLimit(cost.ops_limit),
+        argon2id13::MemLimit(cost.mem_limit),
     )
-    .unwrap();
+    .ok()?;
+
+    std::str::from_utf8(&pwh.0).ok().map(str::to_string)
+}
+
+/// Same as `hash`, with a given cost (e.g. calibrated by `init` before the configuration
+/// is written)
+pub fn hash_with_cost(passwd: &str, cost: &HashingConfig) -> Option<String> {
+    _hash(passwd, cost)
+}
+
+/// Find the hashing cost matching a target duration on this machine
+/// The memory used stays the default one, only the number of passes is increased
+/// Note: the cost never goes below the default one, however fast the target is
+///
+/// # Arguments
+///
+/// * `target` - how long hashing a password should take
+///
+pub fn calibrate_hashing(target: Duration) -> HashingConfig {
+    let mut cost = HashingConfig::default();
+
+    let start = Instant::now();
+    let _ = _hash("calibration", &cost);
+    let elapsed = start.elapsed().as_secs_f64();
 
-    std::str::from_utf8(&pwh.0).unwrap().to_string()
+    // the duration grows linearly with the number of passes
+    let ops_limit = cost.ops_limit as f64 * target.as_secs_f64() / elapsed;
+    cost.ops_limit = cost.ops_limit.max(ops_limit as usize);
+
+    cost
 }
 
 /// Verify that a passwords matches a hash
+/// See `portable::password::verify_password` for more info
+/// Note: a password longer than `MAX_PASSWORD_BYTES` never matches, it isn't hashed
 ///
 /// # Arguments
 ///
-/// * `og_hash` - the hash that the passwords needs to match
 /// * `passwd` - the password that needs to match
+/// * `hash` - the hash that the passwords needs to match
 ///
-pub fn verify_hash(og_hash: &str, passwd: &str) -> bool {
-    sodiumoxide::init().unwrap();
+pub fn verify_hash(passwd: &str, hash: &str) -> bool {
+    validation::is_within_cap(passwd, MAX_PASSWORD_BYTES) && password::verify_password(passwd, hash)
+}
 
-    let hp = argon2id13::HashedPassword::from_slice(passwd.as_bytes()).unwrap();
-    argon2id13::pwhash_verify(&hp, og_hash.as_bytes())
+/// Refuse an input longer than a cap, before anything is computed from it
+/// (see `validation::is_within_cap`)
+///
+/// # Arguments
+///
+/// * `input` - the input received (e.g. a password, a token or a code)
+///
+/// * `max_bytes` - the cap, e.g. `MAX_PASSWORD_BYTES` or `MAX_TOKEN_BYTES`
+///
+pub fn check_length(input: &str, max_bytes: usize) -> Result<(), AuthError> {
+    if validation::is_within_cap(input, max_bytes) {
+        Ok(())
+    } else {
+        Err(AuthError::InputTooLong)
+    }
 }
 
 /// Generate a random token (i.e. string)
 pub fn gen_token() -> String {
     thread_rng()
         .sample_iter(&Alphanumeric)
-        .take(30)
+        .take(token::TOKEN_LEN)
         .map(char::from)
         .collect()
 }
 
+/// Stands for a secret (e.g. a password hash) in the debug output, so the
+/// structs holding secrets can be logged safely
+#[derive(Clone, Copy)]
+pub struct Redacted;
+
+impl fmt::Debug for Redacted {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "<redacted>")
+    }
+}
+
+/// Hide an optional secret in the debug output, only whether it's set is shown
+///
+/// # Arguments
+///
+/// * `secret` - the secret to hide
+///
+pub fn redact<T>(secret: &Option<T>) -> Option<Redacted> {
+    secret.as_ref().map(|_| Redacted)
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
+    use rstest::rstest;
+
+    /// Hash stored by the system (argon2id, t=1 & m=8 KiB to keep the test fast),
+    /// it must keep verifying whatever changes in the hashing
+    const STORED_HASH: &str =
+        "$argon2id$v=19$m=8,t=1,p=1$8OM7NwMU/MQlVtMm96ve8w$ERkqYI4/ut20Wwf1HK1XTn8awLMM7oxxTm098pOzbco";
+
+    /// The hashes are stored as libsodium hands them out, padded with NULs to 128 bytes
+    fn stored(phc: &str) -> String {
+        format!("{:\0<128}", phc)
+    }
 
     #[test]
     fn test_hash() {
         let pw1 = "passwd";
         let pw2 = "verySecurePassword";
 
-        let pwh1 = hash(pw1);
-        let pwh2 = hash(pw2);
+        let pwh1 = hash(pw1).unwrap();
+        let pwh2 = hash(pw2).unwrap();
 
-        let pwh11 = hash(pw1);
-        let pwh22 = hash(pw2);
+        let pwh11 = hash(pw1).unwrap();
+        let pwh22 = hash(pw2).unwrap();
 
         assert_ne!(pwh1, pwh11);
         assert_ne!(pwh2, pwh22);
 
         assert_ne!(pwh1, pwh2);
     }
+
+    #[test]
+    fn test_hash_uses_the_cost() {
+        let cost = HashingConfig {
+            ops_limit: 3,
+            ..HashingConfig::default()
+        };
+
+        assert!(_hash("passwd", &cost).unwrap().contains(",t=3,"));
+    }
+
+    #[test]
+    fn test_oversized_passwords_are_not_hashed() {
+        let huge = "a".repeat(1 << 20);
+
+        assert_eq!(_hash(&huge, &HashingConfig::default()), None);
+        assert!(!verify_hash(&huge, &stored(STORED_HASH)));
+        assert_eq!(
+            check_length(&huge, MAX_PASSWORD_BYTES),
+            Err(AuthError::InputTooLong)
+        );
+        assert_eq!(check_length("passwd", MAX_PASSWORD_BYTES), Ok(()));
+    }
+
+    #[test]
+    fn test_hash_format() {
+        let pwh = _hash("passwd", &HashingConfig::default()).unwrap();
+        let fields: Vec<&str> = pwh.trim_end_matches('\0').split('$').collect();
+
+        assert_eq!(pwh.len(), 128);
+        assert_eq!(fields[..4], ["", "argon2id", "v=19", "m=65536,t=2,p=1"]);
+        // 16 bytes of salt & 32 bytes of hash, in base64 without padding
+        assert_eq!(fields[4].len(), 22);
+        assert_eq!(fields[5].len(), 43);
+    }
+
+    #[test]
+    fn test_verify_stored_hash() {
+        assert!(verify_hash(
+            "correct horse battery staple",
+            &stored(STORED_HASH)
+        ));
+        assert!(!verify_hash(
+            "correct horse battery stapler",
+            &stored(STORED_HASH)
+        ));
+    }
+
+    #[rstest(
+        phc,
+        // hash changed
+        case("$argon2id$v=19$m=8,t=1,p=1$8OM7NwMU/MQlVtMm96ve8w$ERkrYI4/ut20Wwf1HK1XTn8awLMM7oxxTm098pOzbco"),
+        // salt changed
+        case("$argon2id$v=19$m=8,t=1,p=1$8OM7NwMU/MQlVtMm96ve8x$ERkqYI4/ut20Wwf1HK1XTn8awLMM7oxxTm098pOzbco"),
+        // cost changed
+        case("$argon2id$v=19$m=8,t=2,p=1$8OM7NwMU/MQlVtMm96ve8w$ERkqYI4/ut20Wwf1HK1XTn8awLMM7oxxTm098pOzbco"),
+        // other variant
+        case("$argon2i$v=19$m=8,t=1,p=1$8OM7NwMU/MQlVtMm96ve8w$ERkqYI4/ut20Wwf1HK1XTn8awLMM7oxxTm098pOzbco"),
+        // other version
+        case("$argon2id$v=16$m=8,t=1,p=1$8OM7NwMU/MQlVtMm96ve8w$ERkqYI4/ut20Wwf1HK1XTn8awLMM7oxxTm098pOzbco"),
+        // salt missing
+        case("$argon2id$v=19$m=8,t=1,p=1$ERkqYI4/ut20Wwf1HK1XTn8awLMM7oxxTm098pOzbco"),
+        case("not a hash"),
+        case(""),
+        ::trace
+    )]
+    fn test_verify_altered_hash(phc: &str) {
+        assert!(!verify_hash("correct horse battery staple", &stored(phc)));
+    }
+
+    #[test]
+    fn test_hash_with_invalid_cost() {
+        let cost = HashingConfig {
+            ops_limit: 0,
+            ..HashingConfig::default()
+        };
+
+        assert_eq!(_hash("passwd", &cost), None);
+    }
+
+    #[test]
+    fn test_calibration_never_goes_below_default() {
+        assert_eq!(
+            calibrate_hashing(Duration::from_millis(0)),
+            HashingConfig::default()
+        );
+    }
 }
@@ -8,6 +8,8 @@
 
 use lazy_static::lazy_static;
 use regex::{self, Regex};
+use std::fmt;
+use std::ops::Deref;
 
 /// Check if a given email has the correct format (i.e. correct syntax)
 /// i.e. something@somthing.something
@@ -26,6 +28,13 @@ pub fn is_email_valid(email: &str) -> bool {
     RE.is_match(email)
 }
 
+/// Longest password accepted (in bytes), the longer ones are refused before being hashed
+pub const MAX_PASSWORD_BYTES: usize = 64;
+
+/// Longest token, code or key accepted (in bytes), e.g. a reset token or a 2fa code
+/// Note: the tokens of the system are far shorter, the longest are the action links
+pub const MAX_TOKEN_BYTES: usize = 256;
+
 /// Check if a given password respects the apps password policy
 /// i.e. it's at least 8 characters long and shorter than 64
 ///
@@ -34,7 +43,272 @@ pub fn is_email_valid(email: &str) -> bool {
 /// * `passwd` - password to check if it respects the policy
 ///
 pub fn is_password_valid(passwd: &str) -> bool {
-    (8..65).contains(&passwd.len())
+    (8..=MAX_PASSWORD_BYTES).contains(&passwd.len())
+}
+
+/// Check if an input fits in a cap, so a huge one (e.g. a megabyte "password")
+/// is refused before anything is computed from it
+///
+/// # Arguments
+///
+/// * `input` - the input to check
+///
+/// * `max_bytes` - the cap, e.g. `MAX_PASSWORD_BYTES` or `MAX_TOKEN_BYTES`
+///
+pub fn is_within_cap(input: &str, max_bytes: usize) -> bool {
+    input.len() <= max_bytes
+}
+
+/// Email address with a correct format (see `is_email_valid`)
+/// It's checked once, when it's created, so the functions taking one don't check it again
+#[derive(PartialEq, Eq, Hash, Debug, Clone)]
+pub struct Email(String);
+
+impl Email {
+    /// Check & normalize an email address, nothing is returned if its format is incorrect
+    /// Note: only the surrounding whitespace is removed, the case is kept since the
+    ///       existing accounts are looked up as they were registered
+    ///
+    /// # Arguments
+    ///
+    /// * `email` - the email address to check
+    ///
+    pub fn parse(email: &str) -> Option<Self> {
+        let email = email.trim();
+        if is_email_valid(email) {
+            Some(Self(email.to_string()))
+        } else {
+            None
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Deref for Email {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for Email {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// Password respecting the password policy (see `is_password_valid`)
+/// It's checked once, when it's created, so the functions taking one don't check it again
+/// Note: the debug output hides it
+#[derive(PartialEq, Eq, Clone)]
+pub struct Password(String);
+
+impl Password {
+    /// Check a password against the policy, nothing is returned if it doesn't respect it
+    /// Note: the password is kept as is, the whitespace is part of it
+    ///
+    /// # Arguments
+    ///
+    /// * `passwd` - the password to check
+    ///
+    pub fn parse(passwd: &str) -> Option<Self> {
+        if is_password_valid(passwd) {
+            Some(Self(passwd.to_string()))
+        } else {
+            None
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Debug for Password {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Password(<redacted>)")
+    }
+}
+
+/// Check if a given anti-phishing phrase can be used
+/// i.e. it's between 4 and 32 characters long and doesn't contain control characters
+///
+/// # Arguments
+///
+/// * `phrase` - the phrase to check
+///
+pub fn is_anti_phishing_phrase_valid(phrase: &str) -> bool {
+    (4..33).contains(&phrase.chars().count()) && !phrase.chars().any(char::is_control)
+}
+
+/// Names of the staff a display name can't impersonate
+pub const PROTECTED_NAMES: &[&str] = &[
+    "admin",
+    "administrator",
+    "moderator",
+    "root",
+    "security",
+    "staff",
+    "support",
+    "system",
+];
+
+/// Why a display name can't be used
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum DisplayNameIssue {
+    /// Shorter than 2 or longer than 32 characters
+    Length,
+    /// Control or invisible characters (e.g. zero-width spaces, bidi overrides)
+    HiddenCharacters,
+    /// Latin letters mixed with Cyrillic or Greek ones, e.g. a Cyrillic `а` in `аdmin`
+    MixedScripts,
+    /// Looks like one of the `PROTECTED_NAMES`
+    Impersonation,
+    /// Looks like a word of the blocklist
+    Blocked,
+}
+
+/// Script of a letter, only the ones whose letters can pass for each other are told apart
+#[derive(PartialEq, Clone, Copy)]
+enum Script {
+    Latin,
+    Greek,
+    Cyrillic,
+}
+
+fn script(c: char) -> Option<Script> {
+    match c {
+        'a'..='z' | 'A'..='Z' | '\u{c0}'..='\u{24f}' => Some(Script::Latin),
+        '\u{370}'..='\u{3ff}' => Some(Script::Greek),
+        '\u{400}'..='\u{4ff}' => Some(Script::Cyrillic),
+        _ => None,
+    }
+}
+
+/// Characters which aren't shown, or which change how the rest of the name is shown
+fn is_hidden(c: char) -> bool {
+    c.is_control()
+        || matches!(
+            c,
+            '\u{ad}'
+                | '\u{200b}'..='\u{200f}'
+                | '\u{202a}'..='\u{202e}'
+                | '\u{2060}'..='\u{2064}'
+                | '\u{2066}'..='\u{2069}'
+                | '\u{feff}'
+        )
+}
+
+/// The letter a character can pass for, e.g. `а` (Cyrillic), `α` or `4` for `a`
+/// Note: only the common confusables are known, the other characters are kept
+fn unconfuse(c: char) -> Option<char> {
+    let c = match c {
+        // fullwidth forms, e.g. `ａ`
+        '\u{ff01}'..='\u{ff5e}' => std::char::from_u32(c as u32 - 0xfee0)?,
+        _ => c,
+    };
+    let c = c.to_lowercase().next()?;
+
+    Some(match c {
+        // combining marks, e.g. the accent of `é` written as `e` + `´`
+        '\u{300}'..='\u{36f}' => return None,
+        'à'..='å' | 'а' | 'α' | '4' | '@' => 'a',
+        'в' | 'β' | '8' => 'b',
+        'ç' | 'с' => 'c',
+        'ԁ' => 'd',
+        'è'..='ë' | 'е' | 'ε' | '3' => 'e',
+        'һ' | 'н' => 'h',
+        'ì'..='ï' | 'і' | 'ι' | 'l' | '1' | '|' | '!' => 'i',
+        'ј' => 'j',
+        'к' | 'κ' => 'k',
+        'м' => 'm',
+        'ñ' => 'n',
+        'ò'..='ö' | 'ø' | 'о' | 'ο' | '0' => 'o',
+        'р' | 'ρ' => 'p',
+        'ԛ' => 'q',
+        'ѕ' | '5' | '$' => 's',
+        'т' | 'τ' | '7' => 't',
+        'ù'..='ü' | 'υ' => 'u',
+        'ν' => 'v',
+        'ԝ' | 'ω' => 'w',
+        'х' | 'χ' => 'x',
+        'ý' | 'ÿ' | 'у' => 'y',
+        c => c,
+    })
+}
+
+/// What a text looks like once its confusable characters are replaced, two
+/// texts with the same skeleton can pass for each other
+/// e.g. `Аdm1n`, `ADMIN` & `aḋmin` all have the skeleton `admin`
+///
+/// # Arguments
+///
+/// * `text` - the text to get the skeleton of
+///
+pub fn skeleton(text: &str) -> String {
+    text.chars()
+        .filter_map(unconfuse)
+        .collect::<String>()
+        .replace("rn", "m")
+        .replace("vv", "w")
+}
+
+/// Check if a display name can be used, i.e. it's between 2 & 32 characters long,
+/// it doesn't hide characters, it doesn't mix scripts & it doesn't look like the
+/// name of the staff or like a word of the blocklist (whatever its case,
+/// confusable characters & separators)
+///
+/// # Arguments
+///
+/// * `name` - the display name to check
+///
+/// * `blocklist` - words that can't be used (e.g. profanities), may be empty
+///
+pub fn check_display_name(name: &str, blocklist: &[&str]) -> Result<(), DisplayNameIssue> {
+    let name = name.trim();
+    if !(2..33).contains(&name.chars().count()) {
+        return Err(DisplayNameIssue::Length);
+    }
+    if name.chars().any(is_hidden) {
+        return Err(DisplayNameIssue::HiddenCharacters);
+    }
+
+    let mut scripts = name.chars().filter_map(script);
+    if let Some(first) = scripts.next() {
+        if scripts.any(|s| s != first) {
+            return Err(DisplayNameIssue::MixedScripts);
+        }
+    }
+
+    // each word on its own, & all of them together for the names split to evade the check (e.g. `a.d.m.i.n`)
+    let mut skeletons: Vec<String> = name
+        .split(|c: char| !c.is_alphanumeric() && unconfuse(c) == Some(c))
+        .map(skeleton)
+        .filter(|s| !s.is_empty())
+        .collect();
+    skeletons.push(skeleton(
+        &name
+            .chars()
+            .filter(|c| c.is_alphanumeric() || unconfuse(*c) != Some(*c))
+            .collect::<String>(),
+    ));
+    let looks_like = |words: &[&str]| {
+        words
+            .iter()
+            .any(|w| skeletons.iter().any(|s| *s == skeleton(w)))
+    };
+
+    if looks_like(PROTECTED_NAMES) {
+        Err(DisplayNameIssue::Impersonation)
+    } else if looks_like(blocklist) {
+        Err(DisplayNameIssue::Blocked)
+    } else {
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -80,4 +354,92 @@ mod test {
     fn test_if_password_respects_policy(input: &str, expected: bool) {
         assert_eq!(is_password_valid(input), expected);
     }
+
+    #[test]
+    fn test_input_cap() {
+        assert!(is_within_cap("", MAX_TOKEN_BYTES));
+        assert!(is_within_cap(&"a".repeat(MAX_TOKEN_BYTES), MAX_TOKEN_BYTES));
+        assert!(!is_within_cap(
+            &"a".repeat(MAX_TOKEN_BYTES + 1),
+            MAX_TOKEN_BYTES
+        ));
+        // the cap is in bytes, not in characters
+        assert!(!is_within_cap(
+            &"é".repeat(MAX_PASSWORD_BYTES),
+            MAX_PASSWORD_BYTES
+        ));
+    }
+
+    #[test]
+    fn test_email_parse() {
+        assert_eq!(
+            Email::parse("  alice@example.com\n").map(|e| e.to_string()),
+            Some("alice@example.com".to_string())
+        );
+        assert_eq!(
+            Email::parse("Alice@Example.com").as_deref(),
+            Some("Alice@Example.com")
+        );
+        assert_eq!(Email::parse("invalidemail"), None);
+    }
+
+    #[test]
+    fn test_password_parse() {
+        let passwd = Password::parse(" verySecurePassword ").unwrap();
+
+        assert_eq!(passwd.as_str(), " verySecurePassword ");
+        assert_eq!(format!("{:?}", passwd), "Password(<redacted>)");
+        assert_eq!(Password::parse("badpwd"), None);
+    }
+
+    #[rstest(
+        input,
+        expected,
+        case("purple elephant", true),
+        case("Bl4ck C@t", true),
+        case("柔道 柔道", true),
+        case("abc", false),
+        case("this phrase is way too long to be used", false),
+        case("line\nbreak", false),
+        ::trace
+    )]
+    fn test_anti_phishing_phrase_validity(input: &str, expected: bool) {
+        assert_eq!(is_anti_phishing_phrase_valid(input), expected);
+    }
+
+    #[rstest(
+        input,
+        expected,
+        case("Alice", Ok(())),
+        case("Jean-François Müller", Ok(())),
+        case("Ψυχή", Ok(())),
+        case("Дмитрий", Ok(())),
+        case("柔道", Ok(())),
+        case("Rootbeer lover", Ok(())),
+        case("A", Err(DisplayNameIssue::Length)),
+        case("zero\u{200b}width", Err(DisplayNameIssue::HiddenCharacters)),
+        case("evil\u{202e}nimda", Err(DisplayNameIssue::HiddenCharacters)),
+        case("\u{430}dmin", Err(DisplayNameIssue::MixedScripts)),
+        case("Admin", Err(DisplayNameIssue::Impersonation)),
+        case("ADM1N", Err(DisplayNameIssue::Impersonation)),
+        case("a.d.m.i.n", Err(DisplayNameIssue::Impersonation)),
+        case("ａｄｍｉｎ", Err(DisplayNameIssue::Impersonation)),
+        case("Support Team", Err(DisplayNameIssue::Impersonation)),
+        case("SECUR1TY", Err(DisplayNameIssue::Impersonation)),
+        case("syst3m", Err(DisplayNameIssue::Impersonation)),
+        case("Big Jerk", Err(DisplayNameIssue::Blocked)),
+        case("j3rk", Err(DisplayNameIssue::Blocked)),
+        ::trace
+    )]
+    fn test_display_name(input: &str, expected: Result<(), DisplayNameIssue>) {
+        assert_eq!(check_display_name(input, &["jerk"]), expected);
+    }
+
+    #[test]
+    fn test_skeleton() {
+        assert_eq!(skeleton("Аdm1n"), "admin");
+        assert_eq!(skeleton("mod\u{0435}rator"), "moderator");
+        assert_eq!(skeleton("rnoderator"), skeleton("moderator"));
+        assert_ne!(skeleton("alice"), skeleton("bob"));
+    }
 }
@@ -0,0 +1,379 @@
+/*!
+ * End-to-end tests driving the `secure-auth` binary through its subcommands
+ *
+ * Each test runs the real binary against its own SQLite database & configuration
+ * (in a temporary directory), the answers to the prompts are piped on the
+ * standard input. Unlike the unit tests, nothing is mocked.
+ *
+ * # Note
+ * The emails aren't verified by the system, so there's no verification step in the flows.
+ *
+ * # Author
+ * Doran Kayoumi <doran.kayoumi@heig-vd.ch>
+ */
+
+use assert_cmd::assert::Assert;
+use assert_cmd::Command;
+use google_authenticator::GoogleAuthenticator;
+use std::fs;
+use tempfile::TempDir;
+
+const EMAIL: &str = "alice@email.test";
+const PASSWD: &str = "correct horse battery staple";
+const NEW_PASSWD: &str = "another horse battery staple";
+
+/// Offline (the QR code isn't rendered by the online service) & cheap hashing to keep the tests fast
+const CONFIG: &str = r#"
+[network]
+offline = true
+
+[hashing]
+ops_limit = 1
+mem_limit = 8192
+"#;
+
+/// A database & a configuration of their own
+struct Sandbox {
+    dir: TempDir,
+}
+
+impl Sandbox {
+    fn new() -> Self {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("auth.toml"), CONFIG).unwrap();
+
+        let sandbox = Self { dir };
+        sandbox.run(&["db", "migrate"], "").success();
+        sandbox
+    }
+
+    /// Run the binary with some arguments, the input is piped on the standard input
+    fn run(&self, args: &[&str], input: &str) -> Assert {
+        Command::cargo_bin("secure-auth")
+            .unwrap()
+            // Note: the `.env` of the working directory would be loaded otherwise
+            .current_dir(self.dir.path())
+            .env("DATABASE_URL", self.dir.path().join("test.db"))
+            .env("AUTH_CONFIG", self.dir.path().join("auth.toml"))
+            .env("ACTION_LINK_SECRET", "0123456789abcdef0123456789abcdef")
+            .arg("--accessible")
+            .args(args)
+            .write_stdin(input)
+            .assert()
+    }
+
+    fn register(&self, email: &str, passwd: &str) -> Assert {
+        // the password, then accepting the terms of service & confirming the age
+        self.run(&["register", email], &format!("{}\ny\ny\n", passwd))
+    }
+
+    fn login(&self, email: &str, passwd: &str, code: Option<&str>) -> Assert {
+        let input = match code {
+            Some(code) => format!("{}\n{}\n", passwd, code),
+            None => format!("{}\n", passwd),
+        };
+        self.run(&["login", email], &input)
+    }
+
+    /// Enable the 2FA of an account, returns its secret
+    fn enable_2fa(&self, email: &str, passwd: &str) -> String {
+        let out = stdout(
+            self.run(&["2fa", "enable", email], &format!("{}\n", passwd))
+                .success(),
+        );
+        let secret = out
+            .lines()
+            .skip_while(|l| !l.contains("following secret"))
+            .nth(1)
+            .unwrap()
+            .replace(' ', "");
+
+        self.run(
+            &["2fa", "confirm", email],
+            &format!("{}\n{}\n", passwd, code(&secret)),
+        )
+        .success();
+
+        secret
+    }
+
+    /// Ask for a reset token, returns the token sent by email
+    fn request_reset(&self, email: &str) -> String {
+        let out = stdout(self.run(&["reset", "request", email], "").success());
+
+        out.lines()
+            .find_map(|l| l.strip_prefix("Here is your reset token: "))
+            .unwrap()
+            .trim()
+            .to_string()
+    }
+}
+
+fn stdout(assert: Assert) -> String {
+    String::from_utf8(assert.get_output().stdout.clone()).unwrap()
+}
+
+/// The current code of a 2FA secret, as an authentication app would compute it
+fn code(secret: &str) -> String {
+    GoogleAuthenticator::new().get_code(secret, 0).unwrap()
+}
+
+#[test]
+fn test_register_and_login() {
+    let sandbox = Sandbox::new();
+
+    sandbox.register(EMAIL, PASSWD).success();
+    sandbox.login(EMAIL, PASSWD, None).success();
+
+    sandbox.login(EMAIL, "wrong password", None).failure();
+    sandbox.login("bob@email.test", PASSWD, None).failure();
+    // the email is already used
+    sandbox.register(EMAIL, PASSWD).failure();
+}
+
+#[test]
+fn test_registration_requires_the_terms() {
+    let sandbox = Sandbox::new();
+
+    sandbox
+        .run(&["register", EMAIL], &format!("{}\nn\n", PASSWD))
+        .failure();
+    sandbox.login(EMAIL, PASSWD, None).failure();
+}
+
+#[test]
+fn test_login_with_2fa() {
+    let sandbox = Sandbox::new();
+    sandbox.register(EMAIL, PASSWD).success();
+
+    let secret = sandbox.enable_2fa(EMAIL, PASSWD);
+
+    sandbox.login(EMAIL, PASSWD, Some(&code(&secret))).success();
+    sandbox.login(EMAIL, PASSWD, Some("000000")).failure();
+    // already enabled
+    sandbox
+        .run(&["2fa", "enable", EMAIL], &format!("{}\n", PASSWD))
+        .failure();
+}
+
+#[test]
+fn test_2fa_needs_a_code_of_the_new_secret() {
+    let sandbox = Sandbox::new();
+    sandbox.register(EMAIL, PASSWD).success();
+
+    sandbox
+        .run(&["2fa", "enable", EMAIL], &format!("{}\n", PASSWD))
+        .success();
+    sandbox
+        .run(&["2fa", "confirm", EMAIL], &format!("{}\n000000\n", PASSWD))
+        .failure();
+
+    // still not enabled
+    sandbox.login(EMAIL, PASSWD, None).success();
+}
+
+#[test]
+fn test_reset_password() {
+    let sandbox = Sandbox::new();
+    sandbox.register(EMAIL, PASSWD).success();
+
+    let token = sandbox.request_reset(EMAIL);
+    sandbox
+        .run(&["reset", "complete", EMAIL, "wrong token"], "")
+        .failure();
+    sandbox
+        .run(
+            &["reset", "complete", EMAIL, &token],
+            &format!("{}\n", NEW_PASSWD),
+        )
+        .success();
+
+    sandbox.login(EMAIL, NEW_PASSWD, None).success();
+    sandbox.login(EMAIL, PASSWD, None).failure();
+}
+
+#[test]
+fn test_reset_password_with_2fa() {
+    let sandbox = Sandbox::new();
+    sandbox.register(EMAIL, PASSWD).success();
+    let secret = sandbox.enable_2fa(EMAIL, PASSWD);
+
+    let token = sandbox.request_reset(EMAIL);
+    sandbox
+        .run(
+            &["reset", "complete", EMAIL, &token],
+            &format!("000000\n{}\n", NEW_PASSWD),
+        )
+        .failure();
+    sandbox
+        .run(
+            &["reset", "complete", EMAIL, &token],
+            &format!("{}\n{}\n", code(&secret), NEW_PASSWD),
+        )
+        .success();
+
+    sandbox
+        .login(EMAIL, NEW_PASSWD, Some(&code(&secret)))
+        .success();
+}
+
+#[test]
+fn test_reset_of_unknown_account() {
+    let sandbox = Sandbox::new();
+
+    let out = stdout(
+        sandbox
+            .run(&["reset", "request", "bob@email.test"], "")
+            .success(),
+    );
+
+    assert!(!out.contains("reset token:"));
+}
+
+#[test]
+fn test_service_account() {
+    let sandbox = Sandbox::new();
+
+    let out = stdout(
+        sandbox
+            .run(
+                &[
+                    "service",
+                    "create",
+                    "ci@email.test",
+                    "--scope",
+                    "deploy:write",
+                ],
+                "",
+            )
+            .success(),
+    );
+    assert!(out.contains("API key of `ci@email.test`: "));
+
+    // no password login, reset or second account with the same email
+    sandbox.login("ci@email.test", "", None).failure();
+    let out = stdout(
+        sandbox
+            .run(&["reset", "request", "ci@email.test"], "")
+            .success(),
+    );
+    assert!(!out.contains("reset token:"));
+    sandbox.register("ci@email.test", PASSWD).failure();
+
+    sandbox
+        .run(&["service", "rotate-key", "ci@email.test"], "")
+        .success();
+    sandbox.register(EMAIL, PASSWD).success();
+    sandbox.run(&["service", "rotate-key", EMAIL], "").failure();
+    sandbox
+        .run(
+            &["service", "create", "bot@email.test", "--scope", "Deploy"],
+            "",
+        )
+        .failure();
+}
+
+#[test]
+fn test_policy_simulation() {
+    let sandbox = Sandbox::new();
+    let scenario = sandbox.dir.path().join("scenario.toml");
+    let attempt = "[[attempts]]\nat = \"2021-04-28T09:00:00Z\"\nemail = \"alice@email.test\"\n";
+    fs::write(
+        &scenario,
+        format!(
+            "{0}password = \"wrong\"\n{0}password = \"wrong\"\n{0}password = \"wrong\"\n{0}",
+            attempt
+        ),
+    )
+    .unwrap();
+
+    let out = stdout(
+        sandbox
+            .run(
+                &[
+                    "policy",
+                    "simulate",
+                    "--scenario",
+                    scenario.to_str().unwrap(),
+                ],
+                "",
+            )
+            .success(),
+    );
+    assert!(out.contains("[CAPTCHA required from now on]"));
+    assert!(out.contains("4 attempt(s), 4 refused, 0 account(s) placed on hold."));
+
+    fs::write(&scenario, "[[attempts]]\nemail = 1").unwrap();
+    sandbox
+        .run(
+            &[
+                "policy",
+                "simulate",
+                "--scenario",
+                scenario.to_str().unwrap(),
+            ],
+            "",
+        )
+        .failure();
+}
+
+#[test]
+fn test_check() {
+    let sandbox = Sandbox::new();
+
+    // the hashing of the tests is far too cheap for a real deployment
+    let out = stdout(sandbox.run(&["check"], "").failure());
+    assert!(out.contains("[fail] Password hashing"));
+    assert!(out.contains("[ok] Database"));
+    assert!(out.contains("1 check(s) failed."));
+
+    fs::write(
+        sandbox.dir.path().join("auth.toml"),
+        "[network]\noffline = true\n",
+    )
+    .unwrap();
+    let out = stdout(sandbox.run(&["check"], "").success());
+    assert!(out.contains("[ok] Password hashing"));
+    assert!(out.contains("The configuration is ready."));
+}
+
+#[test]
+fn test_verify_audit() {
+    let sandbox = Sandbox::new();
+
+    let out = stdout(sandbox.run(&["verify-audit"], "").success());
+    assert!(out.contains("Nothing is chained yet"));
+
+    fs::write(
+        sandbox.dir.path().join("auth.toml"),
+        format!("{}\n[audit]\ntamper_evident = true\n", CONFIG),
+    )
+    .unwrap();
+    sandbox.register(EMAIL, PASSWD).success();
+    let out = stdout(sandbox.run(&["verify-audit"], "").success());
+    assert!(out.contains("The audit log is intact."));
+
+    let head = out
+        .lines()
+        .find_map(|l| l.strip_prefix("Head: "))
+        .and_then(|l| l.split(' ').next())
+        .unwrap()
+        .to_string();
+    sandbox.login(EMAIL, PASSWD, None).success();
+    sandbox
+        .run(&["verify-audit", "--expect", &head], "")
+        .success();
+}
+
+#[test]
+fn test_migrate() {
+    let sandbox = Sandbox::new();
+    fs::remove_file(sandbox.dir.path().join("test.db")).unwrap();
+
+    let out = stdout(sandbox.run(&["db", "migrate"], "").success());
+    assert!(out.contains("applied migration 20210428090017"));
+
+    let out = stdout(sandbox.run(&["db", "migrate"], "").success());
+    assert!(!out.contains("applied migration"));
+    assert!(out.contains("is up to date"));
+}
@@ -0,0 +1,91 @@
+/*!
+ * Fuzzing of the library entry points, none of them may panic whatever the input
+ *
+ * The failures must come back as typed errors (or `false`/`None`), so nothing
+ * here expects a panic. Along with the `clippy::unwrap_used`, `clippy::expect_used`
+ * & `clippy::panic` lints denied in `auth`, `db` & `utils`, it keeps the library
+ * usable from a binary built with `panic = "abort"` (see the `smoke` profile).
+ *
+ * # Author
+ * Doran Kayoumi <doran.kayoumi@heig-vd.ch>
+ */
+
+use proptest::prelude::*;
+use secure_auth::auth::twofa::{self, Enrollment};
+use secure_auth::config::Config;
+use secure_auth::db::doctor;
+use secure_auth::db::repository::{SQliteUserRepository, UserFilter, UserRepository};
+use secure_auth::portable::{otp, password, token};
+use secure_auth::{utils, validation};
+
+proptest! {
+    #[test]
+    fn fuzz_totp(
+        secret in ".{0,64}",
+        code in ".{0,10}",
+        at in any::<u64>(),
+        period in any::<u64>(),
+        digits in any::<u32>(),
+        tolerance in 0u64..4,
+    ) {
+        let _ = otp::verify_totp(&secret, &code, at, period, digits, tolerance);
+        let _ = twofa::check_code(&secret, &code);
+    }
+
+    #[test]
+    fn fuzz_hotp(key in prop::collection::vec(any::<u8>(), 0..128), counter in any::<u64>(), digits in any::<u32>()) {
+        let _ = otp::hotp(&key, counter, digits);
+    }
+
+    #[test]
+    fn fuzz_password_verification(passwd in ".{0,64}", stored in ".{0,160}") {
+        prop_assert!(!password::verify_password(&passwd, &stored));
+        prop_assert!(!utils::verify_hash(&passwd, &stored));
+    }
+
+    #[test]
+    fn fuzz_tokens(input in ".{0,40}") {
+        let _ = token::format_code(&input);
+        let _ = token::is_code_well_formed(&input);
+        let _ = token::is_token_well_formed(&input);
+        prop_assert_eq!(token::hash(&input).len(), 64);
+    }
+
+    #[test]
+    fn fuzz_validation(input in ".{0,80}") {
+        let _ = validation::is_email_valid(&input);
+        let _ = validation::is_password_valid(&input);
+        let _ = validation::is_anti_phishing_phrase_valid(&input);
+        let _ = validation::check_display_name(&input, &["jerk"]);
+    }
+
+    #[test]
+    fn fuzz_config(input in ".{0,200}") {
+        let _ = Config::from_toml(&input);
+    }
+
+    #[test]
+    fn fuzz_enrollment(account in ".{0,3000}", issuer in ".{0,100}") {
+        let enrollment = Enrollment { account, issuer, ..twofa::enroll("", "") };
+
+        let _ = enrollment.uri();
+        let _ = enrollment.formatted_secret();
+        let _ = twofa::enrollment_qr_svg(&enrollment);
+    }
+}
+
+#[test]
+fn test_unreachable_database() {
+    for url in &["", "/nonexistent/directory/auth.db"] {
+        let repository = SQliteUserRepository::with_database_url(url);
+
+        assert!(repository.get_user("alice@email.test").is_err());
+        assert!(repository.list_users(&UserFilter::new()).is_err());
+        assert!(matches!(
+            repository.iter_users(&UserFilter::new()).next(),
+            Some(Err(_))
+        ));
+        assert!(doctor::diagnose(url).is_err());
+        assert!(doctor::repair(url).is_err());
+    }
+}